@@ -285,24 +285,30 @@ fn main() {
                                     panic!("Got the same result_token twice!");
                                 };
                             }
-                            crust::Event::BootstrapConnect(peer_id, addr) => {
-                                println!("\nBootstrapConnect with peer {:?} (address: <{:?}>)",
+                            crust::Event::BootstrapConnect(peer_id, addr, identity) => {
+                                println!("\nBootstrapConnect with peer {:?} (address: <{:?}>, \
+                                          identity: {})",
                                          peer_id,
-                                         addr);
+                                         addr,
+                                         identity);
                                 let peer_index = handle_new_peer(&unwrap!(service.lock()),
                                                                  network2.clone(),
                                                                  peer_id);
                                 let _ = bs_sender.send(peer_index);
                             }
-                            crust::Event::BootstrapAccept(peer_id, _) => {
-                                println!("\nBootstrapAccept with peer {:?}", peer_id);
+                            crust::Event::BootstrapAccept(peer_id, _, identity) => {
+                                println!("\nBootstrapAccept with peer {:?} (identity: {})",
+                                         peer_id,
+                                         identity);
                                 let peer_index = handle_new_peer(&unwrap!(service.lock()),
                                                                  network2.clone(),
                                                                  peer_id);
                                 let _ = bs_sender.send(peer_index);
                             }
-                            crust::Event::ConnectSuccess(peer_id) => {
-                                println!("\nConnected to peer {:?}", peer_id);
+                            crust::Event::ConnectSuccess(peer_id, identity) => {
+                                println!("\nConnected to peer {:?} (identity: {})",
+                                         peer_id,
+                                         identity);
                                 let _ = handle_new_peer(&unwrap!(service.lock()),
                                                         network2.clone(),
                                                         peer_id);
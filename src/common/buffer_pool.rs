@@ -0,0 +1,150 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Capacities of the buffers handed out, smallest first.
+const SIZE_CLASSES: [usize; 3] = [4 * 1024, 64 * 1024, 1024 * 1024];
+// Most free buffers kept per size class, so an idle pool holds at most about 36 MiB.
+const MAX_FREE_PER_CLASS: usize = 32;
+
+/// How well the receive buffer pool has been reusing buffers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    /// Buffers handed out from the pool.
+    pub hits: u64,
+    /// Buffers that had to be allocated because the pool had none of the size needed.
+    pub misses: u64,
+    /// Buffers returned and kept for reuse.
+    pub returned: u64,
+    /// Buffers returned but freed, being too small, too large or surplus.
+    pub discarded: u64,
+}
+
+impl BufferPoolStats {
+    /// Fraction of buffers handed out that did not need allocating, `0.0` if none were.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+struct Inner {
+    free: Vec<Vec<Vec<u8>>>,
+    stats: BufferPoolStats,
+}
+
+/// Size-classed pool of receive buffers, owned by `Core` and shared by the sockets on its event
+/// loop. A socket checks a buffer out when data arrives and returns it once every message in it
+/// has been delivered, so idle connections hold no receive buffer at all.
+#[derive(Clone)]
+pub struct BufferPool {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        BufferPool {
+            inner: Rc::new(RefCell::new(Inner {
+                                            free: SIZE_CLASSES.iter().map(|_| Vec::new()).collect(),
+                                            stats: Default::default(),
+                                        })),
+        }
+    }
+
+    /// Take an empty buffer with room for at least `min_capacity` bytes.
+    pub fn checkout(&self, min_capacity: usize) -> Vec<u8> {
+        let mut inner = self.inner.borrow_mut();
+        let class = match SIZE_CLASSES.iter().position(|&size| size >= min_capacity) {
+            Some(class) => class,
+            None => {
+                inner.stats.misses += 1;
+                return Vec::with_capacity(min_capacity);
+            }
+        };
+        if let Some(buffer) = inner.free[class].pop() {
+            inner.stats.hits += 1;
+            buffer
+        } else {
+            inner.stats.misses += 1;
+            Vec::with_capacity(SIZE_CLASSES[class])
+        }
+    }
+
+    /// Give a buffer back for reuse.
+    pub fn checkin(&self, mut buffer: Vec<u8>) {
+        let mut inner = self.inner.borrow_mut();
+        let capacity = buffer.capacity();
+        // Buffers grown far past the largest class would pin too much memory.
+        let class = if capacity > 2 * SIZE_CLASSES[SIZE_CLASSES.len() - 1] {
+            None
+        } else {
+            SIZE_CLASSES.iter().rposition(|&size| size <= capacity)
+        };
+        match class {
+            Some(class) if inner.free[class].len() < MAX_FREE_PER_CLASS => {
+                buffer.clear();
+                inner.free[class].push(buffer);
+                inner.stats.returned += 1;
+            }
+            _ => inner.stats.discarded += 1,
+        }
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        self.inner.borrow().stats
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        BufferPool::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse_by_size_class() {
+        let pool = BufferPool::new();
+
+        let small = pool.checkout(100);
+        assert_eq!(small.capacity(), 4 * 1024);
+        let large = pool.checkout(100 * 1024);
+        assert!(large.capacity() >= 1024 * 1024);
+        pool.checkin(small);
+        pool.checkin(large);
+
+        assert_eq!(pool.checkout(10).capacity(), 4 * 1024);
+        assert!(pool.checkout(5000).capacity() < 1024 * 1024);
+        pool.checkin(Vec::with_capacity(10));
+
+        let stats = pool.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 3);
+        assert_eq!(stats.returned, 2);
+        assert_eq!(stats.discarded, 1);
+        assert_eq!(stats.hit_rate(), 0.25);
+    }
+}
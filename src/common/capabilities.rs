@@ -0,0 +1,131 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{Compression, MAX_PAYLOAD_SIZE, SUPPORTED_COMPRESSIONS};
+
+/// Version of the wire protocol we speak, raised whenever messages change. Peers tell each other
+/// theirs in the handshake.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest version of the wire protocol we still speak. Peers on an older one are turned away in
+/// the handshake.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// Substreams of a connection, see `Service::open_stream`.
+pub const FEATURE_STREAMS: u32 = 1;
+/// Moving relayed connections onto a direct path, see `Config::migrate_relayed`. The bit
+/// `1 << 1` stood for it before the peer dialing the direct path had to prove its identity, and
+/// is left unused.
+pub const FEATURE_MIGRATION: u32 = 1 << 6;
+/// Replaying unacknowledged messages on reconnecting, see `Config::reconnect_replay_buffer`.
+pub const FEATURE_REPLAY: u32 = 1 << 2;
+/// Receiving messages over `max_message_size` in parts, see
+/// `Config::stream_oversized_messages`.
+pub const FEATURE_OVERSIZED: u32 = 1 << 3;
+/// Telling peers whenever we start or stop serving as a traversal helper, see
+/// `Config::act_as_helper`.
+pub const FEATURE_HELPERS: u32 = 1 << 4;
+/// Telling peers the address they are seen as over their connection, see
+/// `Service::query_ext_addr`.
+pub const FEATURE_EXT_ADDR: u32 = 1 << 5;
+/// Telling peers how often we send heartbeats, so that they allow for it before giving up on us,
+/// see `Config::heartbeat_misses`.
+pub const FEATURE_KEEP_ALIVE: u32 = 1 << 7;
+/// Answering pings, which measure the round trip time, see `ConnectionStats::rtt`.
+pub const FEATURE_PING: u32 = 1 << 9;
+
+/// What a peer told us it supports on connecting, see `Config::negotiate_capabilities`.
+///
+/// Features are bits rather than an enum so that peers can tell us about features we do not know
+/// of, which we then just ignore.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// Version of the wire protocol it speaks.
+    pub version: u32,
+    /// Names of the transports it dials and listens over, e.g. `"tcp"`.
+    pub transports: Vec<String>,
+    /// Codecs it can decompress.
+    pub compressions: Vec<Compression>,
+    /// Largest message in bytes it accepts whole.
+    pub max_message_size: u64,
+    /// The `FEATURE_*` bits of the optional features it supports.
+    pub features: u32,
+}
+
+impl Capabilities {
+    /// Ours, accepting messages of up to `max_message_size` bytes whole and any larger ones in
+    /// parts if `stream_oversized`.
+    pub fn new(max_message_size: Option<usize>, stream_oversized: bool) -> Self {
+        let mut features = FEATURE_STREAMS | FEATURE_MIGRATION | FEATURE_REPLAY | FEATURE_HELPERS |
+                           FEATURE_EXT_ADDR | FEATURE_KEEP_ALIVE | FEATURE_PING;
+        if stream_oversized {
+            features |= FEATURE_OVERSIZED;
+        }
+        Capabilities {
+            version: PROTOCOL_VERSION,
+            transports: Vec::new(),
+            compressions: SUPPORTED_COMPRESSIONS.to_vec(),
+            max_message_size: max_message_size.unwrap_or(MAX_PAYLOAD_SIZE) as u64,
+            features: features,
+        }
+    }
+
+    /// Whether all the `FEATURE_*` bits of `features` are supported.
+    pub fn supports(&self, features: u32) -> bool {
+        self.features & features == features
+    }
+
+    /// Whether a message of `len` bytes can be sent, whole or in parts.
+    pub fn accepts(&self, len: usize) -> bool {
+        len as u64 <= self.max_message_size || self.supports(FEATURE_OVERSIZED)
+    }
+}
+
+/// Whether a peer telling us it speaks `version` can be talked to.
+pub fn is_compatible_version(version: u32) -> bool {
+    version >= MIN_PROTOCOL_VERSION
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities::new(None, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maidsafe_utilities::serialisation::{deserialise, serialise};
+
+    #[test]
+    fn compatible_versions() {
+        assert!(is_compatible_version(PROTOCOL_VERSION));
+        assert!(is_compatible_version(PROTOCOL_VERSION + 1));
+        assert!(!is_compatible_version(MIN_PROTOCOL_VERSION - 1));
+    }
+
+    #[test]
+    fn unknown_features() {
+        let mut theirs = Capabilities::new(Some(100), false);
+        theirs.features |= 1 << 31;
+        let theirs: Capabilities = unwrap!(deserialise(&unwrap!(serialise(&theirs))));
+        assert!(theirs.supports(FEATURE_STREAMS | FEATURE_MIGRATION));
+        assert!(!theirs.supports(FEATURE_OVERSIZED));
+        assert!(theirs.accepts(100));
+        assert!(!theirs.accepts(101));
+        assert!(Capabilities::new(Some(100), true).accepts(101));
+    }
+}
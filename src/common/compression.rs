@@ -0,0 +1,97 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{CommonError, Result};
+use lz4;
+use std::io::{Read, Write};
+use zstd;
+
+/// Codecs we can decompress, advertised to peers when a connection is established.
+pub const SUPPORTED_COMPRESSIONS: &'static [Compression] = &[Compression::Lz4, Compression::Zstd];
+
+const LZ4_LEVEL: u32 = 1;
+const ZSTD_LEVEL: i32 = 3;
+
+/// Codec to compress large messages with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Compression {
+    /// Fast, with moderate savings.
+    Lz4,
+    /// Slower, with better savings.
+    Zstd,
+}
+
+impl Compression {
+    /// Whether compressing with it takes long enough that it is done off the event loop.
+    pub fn is_slow(&self) -> bool {
+        *self == Compression::Zstd
+    }
+}
+
+pub fn compress(codec: Compression, data: &[u8]) -> Result<Vec<u8>> {
+    match codec {
+        Compression::Lz4 => {
+            let mut encoder = lz4::EncoderBuilder::new()
+                .level(LZ4_LEVEL)
+                .build(Vec::with_capacity(data.len()))?;
+            encoder.write_all(data)?;
+            let (compressed, res) = encoder.finish();
+            res?;
+            Ok(compressed)
+        }
+        Compression::Zstd => {
+            let mut encoder = zstd::Encoder::new(Vec::with_capacity(data.len()), ZSTD_LEVEL)?;
+            encoder.write_all(data)?;
+            Ok(encoder.finish()?)
+        }
+    }
+}
+
+// Refuses to inflate beyond `max_size`, so a peer can't make us allocate more for a compressed
+// message than for a plain one.
+pub fn decompress(codec: Compression, data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+    let mut decompressed = Vec::with_capacity(data.len() * 2);
+    let limit = max_size as u64 + 1;
+    let _ = match codec {
+        Compression::Lz4 => lz4::Decoder::new(data)?.take(limit).read_to_end(&mut decompressed)?,
+        Compression::Zstd => {
+            zstd::Decoder::new(data)?
+                .take(limit)
+                .read_to_end(&mut decompressed)?
+        }
+    };
+    if decompressed.len() > max_size {
+        return Err(CommonError::PayloadSizeProhibitive);
+    }
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::MAX_PAYLOAD_SIZE;
+
+    #[test]
+    fn round_trip() {
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 7) as u8).collect();
+        for &codec in SUPPORTED_COMPRESSIONS {
+            let compressed = unwrap!(compress(codec, &data));
+            assert!(compressed.len() < data.len() / 10);
+            assert_eq!(unwrap!(decompress(codec, &compressed, MAX_PAYLOAD_SIZE)), data);
+        }
+    }
+}
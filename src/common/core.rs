@@ -17,25 +17,49 @@
 
 // Defines `Core`, the mio handler and the core of the event loop.
 
-use common::{Result, State};
+use common::{BufferPool, Capabilities, CommonError, LogSubscriber, Result, Shards, State, Tcp,
+             Timeout, TimerWheel, TraceEvent, TraceState, TraceSubscriber, Transport,
+             TraversalHelpers};
 use maidsafe_utilities::thread::{self, Joiner};
+use main::PeerId;
 use mio::{Event, Events, Poll, PollOpt, Ready, Token};
 use mio::channel::{self, Receiver, Sender};
-use mio::timer::{Timeout, Timer};
+use mio::timer::{self, Timer};
+use rust_sodium::crypto::box_::SecretKey;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
 use std::rc::Rc;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::TryRecvError;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 const EVENT_CAPACITY: usize = 1024;
 
 const CHANNEL_TOKEN_OFFSET: usize = 0;
 const TIMER_TOKEN_OFFSET: usize = CHANNEL_TOKEN_OFFSET + 1;
-const USER_TOKEN_OFFSET: usize = TIMER_TOKEN_OFFSET + 1;
+const DATA_CHANNEL_TOKEN_OFFSET: usize = TIMER_TOKEN_OFFSET + 1;
+const USER_TOKEN_OFFSET: usize = DATA_CHANNEL_TOKEN_OFFSET + 1;
+
+// Most bytes of data messages waiting on an event loop before senders are turned away.
+const DATA_CHANNEL_CAPACITY: usize = 32 * 1024 * 1024;
+
+// Precision of the timeouts set by states.
+const TIMER_TICK_MS: u64 = 10;
+
+// How long the token of a removed state is kept out of use, so that readiness still meant for
+// that state is ignored rather than reaching whichever state gets the token next. Tokens with
+// timeouts still set, or that readiness arrives for while quarantined, are not reused at all.
+const TOKEN_QUARANTINE_SECS: u64 = 300;
 
 pub struct EventLoop {
     tx: Sender<CoreMessage>,
+    data_tx: Sender<CoreMessage>,
+    // Bytes of the data messages yet to be run
+    data_queued: Arc<AtomicUsize>,
     _joiner: Joiner,
 }
 
@@ -43,6 +67,33 @@ impl EventLoop {
     pub fn send(&self, msg: CoreMessage) -> Result<()> {
         Ok(self.tx.send(msg)?)
     }
+
+    /// Queue `msg`, carrying `len` bytes of data, behind any control messages, failing with
+    /// `CommonError::EventLoopBusy` rather than waiting should too much data be queued already.
+    /// Data messages run in the order they are queued.
+    pub fn try_send_data(&self, mut msg: CoreMessage, len: usize) -> Result<()> {
+        let queued = self.data_queued.fetch_add(len, Ordering::SeqCst);
+        // However large, a message gets through once nothing else is queued
+        if queued > 0 && queued.saturating_add(len) > DATA_CHANNEL_CAPACITY {
+            let _ = self.data_queued.fetch_sub(len, Ordering::SeqCst);
+            return Err(CommonError::EventLoopBusy);
+        }
+        let data_queued = self.data_queued.clone();
+        let msg = CoreMessage::new(move |core, poll| {
+            let _ = data_queued.fetch_sub(len, Ordering::SeqCst);
+            if let Some(mut f) = msg.0.take() {
+                f(core, poll);
+            }
+        });
+        self.data_tx.send(msg).map_err(|e| {
+            let _ = self.data_queued.fetch_sub(len, Ordering::SeqCst);
+            CommonError::CoreMsgTx(e)
+        })
+    }
+
+    pub fn sender(&self) -> Sender<CoreMessage> {
+        self.tx.clone()
+    }
 }
 
 impl Drop for EventLoop {
@@ -60,7 +111,10 @@ pub fn spawn_event_loop(token_counter_start: usize,
                         -> Result<EventLoop> {
     let poll = Poll::new()?;
     let (tx, rx) = channel::channel();
-    let timer = Timer::default();
+    let (data_tx, data_rx) = channel::channel();
+    let timer = timer::Builder::default()
+        .tick_duration(Duration::from_millis(TIMER_TICK_MS))
+        .build();
 
     poll.register(&rx,
                   Token(token_counter_start + CHANNEL_TOKEN_OFFSET),
@@ -70,6 +124,10 @@ pub fn spawn_event_loop(token_counter_start: usize,
                   Token(token_counter_start + TIMER_TOKEN_OFFSET),
                   Ready::readable() | Ready::error() | Ready::hup(),
                   PollOpt::edge())?;
+    poll.register(&data_rx,
+                  Token(token_counter_start + DATA_CHANNEL_TOKEN_OFFSET),
+                  Ready::readable() | Ready::error() | Ready::hup(),
+                  PollOpt::edge())?;
 
     let mut name = "CRUST-Event-Loop".to_string();
     if let Some(id) = event_loop_id {
@@ -80,7 +138,7 @@ pub fn spawn_event_loop(token_counter_start: usize,
     let tx_clone = tx.clone();
     let joiner = thread::named(name, move || {
         let core = Core::new(token_counter_start + USER_TOKEN_OFFSET, tx_clone, timer);
-        match event_loop_impl(token_counter_start, &poll, &rx, core) {
+        match event_loop_impl(token_counter_start, &poll, &rx, &data_rx, core) {
             Ok(()) => trace!("Graceful event loop exit."),
             Err(e) => error!("Event loop killed due to {:?}", e),
         }
@@ -88,6 +146,8 @@ pub fn spawn_event_loop(token_counter_start: usize,
 
     Ok(EventLoop {
            tx: tx,
+           data_tx: data_tx,
+           data_queued: Arc::new(AtomicUsize::new(0)),
            _joiner: joiner,
        })
 }
@@ -95,6 +155,7 @@ pub fn spawn_event_loop(token_counter_start: usize,
 fn event_loop_impl(token_counter_start: usize,
                    poll: &Poll,
                    rx: &Receiver<CoreMessage>,
+                   data_rx: &Receiver<CoreMessage>,
                    mut core: Core)
                    -> Result<()> {
     let mut events = Events::with_capacity(EVENT_CAPACITY);
@@ -110,16 +171,24 @@ fn event_loop_impl(token_counter_start: usize,
                               event);
                         continue;
                     }
-
+                    if run_messages(rx, &mut core, poll) {
+                        break 'event_loop;
+                    }
+                }
+                Token(t) if t == token_counter_start + DATA_CHANNEL_TOKEN_OFFSET => {
+                    if !event.kind().is_readable() {
+                        warn!("Data channel to event loop errored out: {:?}", event);
+                        continue;
+                    }
+                    // Control messages go first, even those sent while data is being handled.
                     loop {
-                        let msg = match rx.try_recv() {
-                            Ok(msg) => msg,
-                            Err(TryRecvError::Empty) => break,
-                            Err(TryRecvError::Disconnected) => break 'event_loop,
-                        };
-                        match msg.0 {
-                            Some(mut f) => f(&mut core, poll),
-                            None => break 'event_loop,
+                        if run_messages(rx, &mut core, poll) {
+                            break 'event_loop;
+                        }
+                        match data_rx.try_recv() {
+                            Ok(CoreMessage(Some(mut f))) => f(&mut core, poll),
+                            Ok(CoreMessage(None)) => break 'event_loop,
+                            Err(_) => break,
                         }
                     }
                 }
@@ -134,6 +203,60 @@ fn event_loop_impl(token_counter_start: usize,
     Ok(())
 }
 
+// Run the messages waiting on `rx`, returning whether the event loop is to exit.
+fn run_messages(rx: &Receiver<CoreMessage>, core: &mut Core, poll: &Poll) -> bool {
+    loop {
+        let msg = match rx.try_recv() {
+            Ok(msg) => msg,
+            Err(TryRecvError::Empty) => return false,
+            Err(TryRecvError::Disconnected) => return true,
+        };
+        match msg.0 {
+            Some(mut f) => f(core, poll),
+            None => return true,
+        }
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+/// A state alive on an event loop, as listed by `Core::debug_snapshot`.
+#[derive(Clone, Debug)]
+pub struct StateSnapshot {
+    /// The token of the state.
+    pub token: usize,
+    /// The kind of state, e.g. `"MappedTcpSocket"`.
+    pub name: &'static str,
+    /// How long since the state was inserted.
+    pub age: Duration,
+    /// The timeouts set for the state, by timer id, along with how long until each is due.
+    pub timeouts: Vec<(u8, Duration)>,
+}
+
+/// A state that panicked in one of its callbacks and was terminated, the rest of its event loop
+/// carrying on.
+#[derive(Clone, Debug)]
+pub struct StateCrash {
+    /// The token of the state.
+    pub token: usize,
+    /// The kind of state, e.g. `"ActiveConnection"`.
+    pub name: &'static str,
+    /// The peer the state dealt with, if it was known.
+    pub peer_id: Option<PeerId>,
+    /// The callback that panicked, i.e. `"ready"` or `"timeout"`.
+    pub callback: &'static str,
+    /// What the panic was raised with, if it was a string.
+    pub message: String,
+}
+
+/// Locks `mutex`, recovering it should a state have panicked while holding it. Only that state was
+/// terminated, so the rest of the service carries on with what the mutex guards.
+pub fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 pub struct CoreMessage(Option<Box<FnMut(&mut Core, &Poll) + Send>>);
 
 #[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Copy, Clone, Debug)]
@@ -144,18 +267,73 @@ pub struct CoreTimer {
 
 pub struct Core {
     tx: Sender<CoreMessage>,
-    timer: Timer<CoreTimer>,
+    // Wakes the event loop when the timer wheel next has something to do
+    timer: Timer<()>,
+    wakeup: Option<(Instant, timer::Timeout)>,
+    timer_wheel: TimerWheel<CoreTimer>,
     token_counter: usize,
     states: HashMap<Token, Rc<RefCell<State>>>,
+    // When each state was inserted
+    inserted_at: HashMap<Token, Instant>,
+    buffer_pool: BufferPool,
+    trace_subscriber: Box<TraceSubscriber>,
+    crash_handler: Option<Box<Fn(StateCrash)>>,
+    transport: Rc<Transport>,
+    // Transports to also dial peers over, in turn, when they are not reached over the ones
+    // before in time.
+    fallback_transports: Vec<(Rc<Transport>, Duration)>,
+    // Transports listened over alongside `transport`.
+    listen_transports: Vec<Rc<Transport>>,
+    shards: Option<Shards>,
+    capabilities: Capabilities,
+    // Whether connections tell peers our capabilities unprompted.
+    negotiate_capabilities: bool,
+    // Our long-term secret key, which connections agree on session keys with.
+    secret_key: Option<SecretKey>,
+    helpers: TraversalHelpers,
+    // Tokens of removed states in the order they were freed, along with when.
+    free_tokens: VecDeque<(Instant, Token)>,
+    // When each token awaiting reuse was freed. Tokens taken up again by a state, as when one
+    // state hands its token on to the next, are dropped from here.
+    freed_at: HashMap<Token, Instant>,
+    token_quarantine: Duration,
+    // How many timeouts are set for each token, however long ago its state was removed.
+    pending_timeouts: HashMap<Token, usize>,
+    // Keep-alives are put off until the next multiple of this since `batch_epoch`, if set.
+    keep_alive_batch: Option<Duration>,
+    batch_epoch: Instant,
+    // Whether the service has been asked to stay off the network.
+    paused: bool,
 }
 
 impl Core {
-    fn new(token_counter_start: usize, tx: Sender<CoreMessage>, timer: Timer<CoreTimer>) -> Self {
+    fn new(token_counter_start: usize, tx: Sender<CoreMessage>, timer: Timer<()>) -> Self {
         Core {
             tx: tx,
             timer: timer,
+            wakeup: None,
+            timer_wheel: TimerWheel::new(Duration::from_millis(TIMER_TICK_MS)),
             token_counter: token_counter_start,
             states: HashMap::new(),
+            inserted_at: HashMap::new(),
+            buffer_pool: BufferPool::new(),
+            trace_subscriber: Box::new(LogSubscriber),
+            crash_handler: None,
+            transport: Rc::new(Tcp),
+            fallback_transports: Vec::new(),
+            listen_transports: Vec::new(),
+            shards: None,
+            capabilities: Capabilities::default(),
+            negotiate_capabilities: false,
+            secret_key: None,
+            helpers: TraversalHelpers::default(),
+            free_tokens: VecDeque::new(),
+            freed_at: HashMap::new(),
+            token_quarantine: Duration::from_secs(TOKEN_QUARANTINE_SECS),
+            pending_timeouts: HashMap::new(),
+            keep_alive_batch: None,
+            batch_epoch: Instant::now(),
+            paused: false,
         }
     }
 
@@ -164,14 +342,230 @@ impl Core {
     }
 
     pub fn set_timeout(&mut self, interval: Duration, core_timer: CoreTimer) -> Result<Timeout> {
-        Ok(self.timer.set_timeout(interval, core_timer)?)
+        let timeout = self.timer_wheel.set_timeout(interval, core_timer);
+        let due = Instant::now() + self.timer_wheel.until(&timeout);
+        if let Err(e) = self.wake_by(due) {
+            let _ = self.timer_wheel.cancel_timeout(&timeout);
+            return Err(e);
+        }
+        *self.pending_timeouts.entry(core_timer.state_id).or_insert(0) += 1;
+        Ok(timeout)
     }
 
     pub fn cancel_timeout(&mut self, timeout: &Timeout) -> Option<CoreTimer> {
-        self.timer.cancel_timeout(timeout)
+        let core_timer = self.timer_wheel.cancel_timeout(timeout);
+        if let Some(core_timer) = core_timer {
+            self.timeout_done(core_timer.state_id);
+        }
+        core_timer
+    }
+
+    fn timeout_done(&mut self, token: Token) {
+        let none_left = match self.pending_timeouts.get_mut(&token) {
+            Some(pending) => {
+                *pending -= 1;
+                *pending == 0
+            }
+            None => false,
+        };
+        if none_left {
+            let _ = self.pending_timeouts.remove(&token);
+        }
+    }
+
+    // Have the event loop woken no later than `at`.
+    fn wake_by(&mut self, at: Instant) -> Result<()> {
+        if let Some(&(wakeup, _)) = self.wakeup.as_ref() {
+            if wakeup <= at {
+                return Ok(());
+            }
+        }
+        if let Some((_, timeout)) = self.wakeup.take() {
+            let _ = self.timer.cancel_timeout(&timeout);
+        }
+        let now = Instant::now();
+        let after = if at > now {
+            at - now
+        } else {
+            Duration::from_secs(0)
+        };
+        self.wakeup = Some((at, self.timer.set_timeout(after, ())?));
+        Ok(())
+    }
+
+    /// Receive buffers shared by the sockets on this event loop.
+    pub fn buffer_pool(&self) -> &BufferPool {
+        &self.buffer_pool
+    }
+
+    /// The transport to dial and listen for peers with, TCP unless set otherwise.
+    pub fn transport(&self) -> Rc<Transport> {
+        self.transport.clone()
+    }
+
+    /// Dial and listen for peers with `transport` from now on.
+    pub fn set_transport(&mut self, transport: Rc<Transport>) {
+        self.transport = transport;
+    }
+
+    /// The `nth` transport to dial peers with again, counting from 0, should dialing them over
+    /// `transport` and the fallbacks before it fail, or not connect within the delay given with
+    /// it.
+    pub fn fallback_transport(&self, nth: usize) -> Option<(Rc<Transport>, Duration)> {
+        self.fallback_transports.get(nth).cloned()
+    }
+
+    /// Fall back on `transport` after the fallbacks already added, when dialing over those fails
+    /// or takes longer than `delay`.
+    pub fn add_fallback_transport(&mut self, transport: Rc<Transport>, delay: Duration) {
+        self.fallback_transports.push((transport, delay));
+    }
+
+    /// The transports listened over alongside `transport`.
+    pub fn listen_transports(&self) -> Vec<Rc<Transport>> {
+        self.listen_transports.clone()
+    }
+
+    /// Listen over `transport` as well from now on.
+    pub fn add_listen_transport(&mut self, transport: Rc<Transport>) {
+        self.listen_transports.push(transport);
+    }
+
+    /// The event loops connections are spread over, if there is more than this one.
+    pub fn shards(&self) -> Option<&Shards> {
+        self.shards.as_ref()
+    }
+
+    pub fn set_shards(&mut self, shards: Shards) {
+        self.shards = Some(shards);
+    }
+
+    /// What connections on this event loop tell peers they support, along with the transports
+    /// in use.
+    pub fn capabilities(&self) -> Capabilities {
+        let mut capabilities = self.capabilities.clone();
+        let names = Some(self.transport.name())
+            .into_iter()
+            .chain(self.fallback_transports.iter().map(|&(ref transport, _)| transport.name()))
+            .chain(self.listen_transports.iter().map(|transport| transport.name()));
+        for name in names {
+            if !capabilities.transports.iter().any(|known| known == name) {
+                capabilities.transports.push(name.to_owned());
+            }
+        }
+        capabilities
+    }
+
+    /// Whether connections tell peers our capabilities as soon as they are established, rather
+    /// than only in answer to theirs.
+    pub fn negotiates_capabilities(&self) -> bool {
+        self.negotiate_capabilities
+    }
+
+    /// Have connections tell peers `capabilities`, unprompted if `negotiate`.
+    pub fn set_capabilities(&mut self, capabilities: Capabilities, negotiate: bool) {
+        self.capabilities = capabilities;
+        self.negotiate_capabilities = negotiate;
+    }
+
+    /// Our long-term secret key, if the service has set it.
+    pub fn secret_key(&self) -> Option<&SecretKey> {
+        self.secret_key.as_ref()
+    }
+
+    pub fn set_secret_key(&mut self, secret_key: SecretKey) {
+        self.secret_key = Some(secret_key);
+    }
+
+    /// Whether we serve peers as a traversal helper, and where the helpers they serve as go.
+    pub fn helpers(&self) -> &TraversalHelpers {
+        &self.helpers
+    }
+
+    pub fn set_helpers(&mut self, helpers: TraversalHelpers) {
+        self.helpers = helpers;
     }
 
+    /// `delay` stretched to run out on the next keep-alive batch boundary, so that the radio of a
+    /// mobile device is woken once for the keep-alives of every connection rather than once for
+    /// each. Unchanged unless batching.
+    pub fn batched(&self, delay: Duration) -> Duration {
+        let batch_ms = match self.keep_alive_batch {
+            Some(batch) => cmp::max(millis(batch), 1),
+            None => return delay,
+        };
+        let due_ms = millis(self.batch_epoch.elapsed() + delay);
+        let boundary_ms = (due_ms + batch_ms - 1) / batch_ms * batch_ms;
+        delay + Duration::from_millis(boundary_ms - due_ms)
+    }
+
+    /// Batch keep-alives on boundaries `batch` apart from now on, or not at all if `None`.
+    pub fn set_keep_alive_batch(&mut self, batch: Option<Duration>) {
+        self.keep_alive_batch = batch;
+    }
+
+    /// Whether the service has been paused, in which case states hold off on any traffic of their
+    /// own such as keep-alives and lease renewals.
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// Have `handler` told of each state that panics from now on.
+    pub fn set_crash_handler(&mut self, handler: Box<Fn(StateCrash)>) {
+        self.crash_handler = Some(handler);
+    }
+
+    /// Have `subscriber` receive the `TraceEvent`s of this event loop from now on.
+    pub fn set_trace_subscriber(&mut self, subscriber: Box<TraceSubscriber>) {
+        self.trace_subscriber = subscriber;
+    }
+
+    /// Tell the trace subscriber that `name` happened to the connection of `conn` in `state`.
+    pub fn trace(&self,
+                 conn: Token,
+                 state: TraceState,
+                 name: &'static str,
+                 fields: &[(&'static str, &fmt::Display)]) {
+        self.trace_subscriber
+            .on_event(&TraceEvent {
+                           conn: conn.0,
+                           state: state,
+                           name: name,
+                           fields: fields,
+                       });
+    }
+
+    /// A token no state has, recycling those of states removed long enough ago.
     pub fn get_new_token(&mut self) -> Token {
+        for _ in 0..self.free_tokens.len() {
+            let (freed, token) = match self.free_tokens.front() {
+                Some(&entry) => entry,
+                None => break,
+            };
+            // Taken up again since, freed again later on, or retired
+            if self.freed_at.get(&token) != Some(&freed) || self.states.contains_key(&token) {
+                let _ = self.free_tokens.pop_front();
+                continue;
+            }
+            if freed.elapsed() < self.token_quarantine {
+                break;
+            }
+            let _ = self.free_tokens.pop_front();
+            // A timeout still set would reach the next state, so wait for it another quarantine
+            if self.pending_timeouts.contains_key(&token) {
+                let now = Instant::now();
+                self.free_tokens.push_back((now, token));
+                let _ = self.freed_at.insert(token, now);
+                continue;
+            }
+            let _ = self.freed_at.remove(&token);
+            return token;
+        }
+
         let token = Token(self.token_counter);
         self.token_counter += 1;
         token
@@ -181,20 +575,134 @@ impl Core {
                         token: Token,
                         state: Rc<RefCell<State>>)
                         -> Option<Rc<RefCell<State>>> {
+        let _ = self.freed_at.remove(&token);
+        let _ = self.inserted_at.insert(token, Instant::now());
         self.states.insert(token, state)
     }
 
     pub fn remove_state(&mut self, token: Token) -> Option<Rc<RefCell<State>>> {
-        self.states.remove(&token)
+        let state = self.states.remove(&token);
+        let _ = self.inserted_at.remove(&token);
+        if state.is_some() {
+            let now = Instant::now();
+            self.free_tokens.push_back((now, token));
+            let _ = self.freed_at.insert(token, now);
+        }
+        state
     }
 
     pub fn get_state(&self, key: Token) -> Option<Rc<RefCell<State>>> {
         self.states.get(&key).cloned()
     }
 
+    /// The states alive on this event loop, by token, for tracking down those that linger.
+    pub fn debug_snapshot(&self) -> Vec<StateSnapshot> {
+        let mut timeouts = HashMap::new();
+        for (core_timer, due_in) in self.timer_wheel.pending() {
+            timeouts
+                .entry(core_timer.state_id)
+                .or_insert_with(Vec::new)
+                .push((core_timer.timer_id, due_in));
+        }
+
+        let mut snapshot: Vec<_> = self.states
+            .iter()
+            .map(|(&token, state)| {
+                // A state busy with a callback is the one asking
+                let name = state.try_borrow().map(|state| state.name()).unwrap_or("(busy)");
+                let mut timers = timeouts.remove(&token).unwrap_or_else(Vec::new);
+                timers.sort();
+                StateSnapshot {
+                    token: token.0,
+                    name: name,
+                    age: self.inserted_at
+                        .get(&token)
+                        .map_or(Duration::from_secs(0), |at| at.elapsed()),
+                    timeouts: timers,
+                }
+            })
+            .collect();
+        snapshot.sort_by_key(|state| state.token);
+        snapshot
+    }
+
+    /// Terminate every state, along with any started by another as it terminates.
+    pub fn terminate_all(&mut self, poll: &Poll) {
+        loop {
+            let token = match self.states.keys().next() {
+                Some(&token) => token,
+                None => break,
+            };
+            if let Some(state) = self.get_state(token) {
+                state.borrow_mut().terminate(self, poll);
+            }
+            // In case the state left itself behind. Its token goes through the quarantine all
+            // the same, as its socket may still be registered.
+            let _ = self.remove_state(token);
+        }
+    }
+
     fn handle_event(&mut self, poll: &Poll, event: Event) {
-        if let Some(state) = self.get_state(event.token()) {
-            state.borrow_mut().ready(self, poll, event.kind());
+        let kind = event.kind();
+        // A socket of a removed state is still registered, and would keep reaching the state of
+        // any token handed out again, so this one never is
+        if self.freed_at.remove(&event.token()).is_some() {
+            debug!("{:?} for {:?} after its state was removed", kind, event.token());
+        }
+        self.dispatch(poll,
+                      event.token(),
+                      "ready",
+                      |state, core| state.ready(core, poll, kind));
+    }
+
+    // Run `f` on the state of `token`, should it panic terminating that state alone and reporting
+    // it to the crash handler.
+    fn dispatch<F>(&mut self, poll: &Poll, token: Token, callback: &'static str, f: F)
+        where F: FnOnce(&mut State, &mut Core)
+    {
+        let state = match self.get_state(token) {
+            Some(state) => state,
+            None => return,
+        };
+        let res = panic::catch_unwind(AssertUnwindSafe(|| f(&mut *state.borrow_mut(), self)));
+        let payload = match res {
+            Ok(()) => return,
+            Err(payload) => payload,
+        };
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(String::new);
+        let (name, peer_id) = state
+            .try_borrow()
+            .map(|state| (state.name(), state.peer_id()))
+            .unwrap_or(("(busy)", None));
+        error!("{} of {:?} for {:?} panicked in `{}`: {}",
+               name,
+               token,
+               peer_id,
+               callback,
+               message);
+
+        if panic::catch_unwind(AssertUnwindSafe(|| state.borrow_mut().terminate(self, poll)))
+               .is_err() {
+            error!("State of {:?} panicked again while terminating", token);
+        }
+        let left_behind = self.get_state(token)
+            .map_or(false, |current| Rc::ptr_eq(&current, &state));
+        if left_behind {
+            let _ = self.remove_state(token);
+        }
+
+        if let Some(ref handler) = self.crash_handler {
+            handler(StateCrash {
+                        token: token.0,
+                        name: name,
+                        peer_id: peer_id,
+                        callback: callback,
+                        message: message,
+                    });
         }
     }
 
@@ -203,11 +711,21 @@ impl Core {
             warn!("Timer errored out: {:?}", kind);
             return;
         }
-        while let Some(core_timer) = self.timer.poll() {
-            if let Some(state) = self.get_state(core_timer.state_id) {
-                state
-                    .borrow_mut()
-                    .timeout(self, poll, core_timer.timer_id);
+        while self.timer.poll().is_some() {
+            self.wakeup = None;
+        }
+
+        for core_timer in self.timer_wheel.poll() {
+            self.timeout_done(core_timer.state_id);
+            self.dispatch(poll,
+                          core_timer.state_id,
+                          "timeout",
+                          |state, core| state.timeout(core, poll, core_timer.timer_id));
+        }
+
+        if let Some(after) = self.timer_wheel.next_wakeup() {
+            if let Err(e) = self.wake_by(Instant::now() + after) {
+                warn!("Could not set a timeout to wake the event loop: {:?}", e);
             }
         }
     }
@@ -230,3 +748,196 @@ impl CoreTimer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::any::Any;
+    use std::cell::Cell;
+    use std::sync::mpsc;
+
+    struct Idle;
+
+    impl State for Idle {
+        fn name(&self) -> &'static str {
+            "Idle"
+        }
+
+        fn as_any(&mut self) -> &mut Any {
+            self
+        }
+    }
+
+    struct Faulty(Rc<Cell<bool>>);
+
+    impl State for Faulty {
+        fn name(&self) -> &'static str {
+            "Faulty"
+        }
+
+        fn as_any(&mut self) -> &mut Any {
+            self
+        }
+
+        fn timeout(&mut self, _core: &mut Core, _poll: &Poll, _timer_id: u8) {
+            panic!("Faulty timeout");
+        }
+
+        fn terminate(&mut self, _core: &mut Core, _poll: &Poll) {
+            self.0.set(true);
+        }
+    }
+
+    #[test]
+    fn token_recycling() {
+        let (tx, _rx) = channel::channel();
+        let mut core = Core::new(0, tx, Timer::default());
+
+        let token = core.get_new_token();
+        let _ = core.insert_state(token, Rc::new(RefCell::new(Idle)));
+        let _ = core.remove_state(token);
+        assert_ne!(core.get_new_token(), token);
+
+        core.token_quarantine = Duration::from_secs(0);
+        assert_eq!(core.get_new_token(), token);
+
+        // Handed on to the next state rather than freed
+        let next = core.get_new_token();
+        let _ = core.insert_state(next, Rc::new(RefCell::new(Idle)));
+        let _ = core.remove_state(next);
+        let _ = core.insert_state(next, Rc::new(RefCell::new(Idle)));
+        assert_ne!(core.get_new_token(), next);
+
+        // Not while a timeout is still set for it
+        let timed = core.get_new_token();
+        let _ = core.insert_state(timed, Rc::new(RefCell::new(Idle)));
+        let timeout = unwrap!(core.set_timeout(Duration::from_secs(60), CoreTimer::new(timed, 0)));
+        let _ = core.remove_state(timed);
+        assert_ne!(core.get_new_token(), timed);
+        let _ = core.cancel_timeout(&timeout);
+        assert_eq!(core.get_new_token(), timed);
+
+        // Nor when terminated along with everything else, by a state that leaves itself behind
+        let left = core.get_new_token();
+        let _ = core.insert_state(left, Rc::new(RefCell::new(Idle)));
+        core.terminate_all(&unwrap!(Poll::new()));
+        assert!(core.get_state(left).is_none());
+        assert!(core.freed_at.contains_key(&left));
+    }
+
+    #[test]
+    fn keep_alive_batch() {
+        let (tx, _rx) = channel::channel();
+        let mut core = Core::new(0, tx, Timer::default());
+        let delay = Duration::from_millis(1500);
+        assert_eq!(core.batched(delay), delay);
+
+        core.set_keep_alive_batch(Some(Duration::from_secs(1)));
+        let batched = core.batched(delay);
+        assert!(batched >= delay && batched <= Duration::from_secs(2));
+        let due = millis(core.batch_epoch.elapsed() + batched);
+        assert!(due % 1000 < 100);
+    }
+
+    #[test]
+    fn data_backpressure() {
+        const MSG_LEN: usize = 1024 * 1024;
+
+        // Holds the event loop up until told to go on
+        fn blocked_event_loop() -> (EventLoop, mpsc::Sender<()>) {
+            let el = unwrap!(spawn_event_loop(0, None));
+            let (release_tx, release_rx) = mpsc::channel::<()>();
+            unwrap!(el.send(CoreMessage::new(move |_, _| { let _ = release_rx.recv(); })));
+            (el, release_tx)
+        }
+
+        let (el, release_tx) = blocked_event_loop();
+        let mut sent = 0;
+        loop {
+            match el.try_send_data(CoreMessage::new(|_, _| ()), MSG_LEN) {
+                Ok(()) => sent += 1,
+                Err(CommonError::EventLoopBusy) => break,
+                Err(e) => panic!("Unexpected error: {:?}", e),
+            }
+        }
+        assert_eq!(sent, DATA_CHANNEL_CAPACITY / MSG_LEN);
+        unwrap!(release_tx.send(()));
+
+        // However large, a message gets through once nothing else is queued
+        let (el, release_tx) = blocked_event_loop();
+        unwrap!(el.try_send_data(CoreMessage::new(|_, _| ()), 2 * DATA_CHANNEL_CAPACITY));
+        match el.try_send_data(CoreMessage::new(|_, _| ()), 1) {
+            Err(CommonError::EventLoopBusy) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        unwrap!(release_tx.send(()));
+    }
+
+    #[test]
+    fn state_panic() {
+        let (tx, _rx) = channel::channel();
+        let mut core = Core::new(0, tx, Timer::default());
+        let poll = unwrap!(Poll::new());
+        let crashes = Rc::new(RefCell::new(Vec::new()));
+        let crashes_0 = crashes.clone();
+        core.set_crash_handler(Box::new(move |crash: StateCrash| {
+                                            crashes_0.borrow_mut().push(crash)
+                                        }));
+
+        let terminated = Rc::new(Cell::new(false));
+        let token = core.get_new_token();
+        let _ = core.insert_state(token, Rc::new(RefCell::new(Faulty(terminated.clone()))));
+        core.dispatch(&poll,
+                      token,
+                      "timeout",
+                      |state, core| state.timeout(core, &poll, 0));
+
+        assert!(terminated.get());
+        assert!(core.get_state(token).is_none());
+        let crashes = crashes.borrow();
+        assert_eq!(crashes.len(), 1);
+        assert_eq!(crashes[0].name, "Faulty");
+        assert_eq!(crashes[0].peer_id, None);
+        assert_eq!(crashes[0].callback, "timeout");
+        assert_eq!(crashes[0].message, "Faulty timeout");
+    }
+
+    #[test]
+    fn lock_poisoned_by_state_panic() {
+        let (tx, _rx) = channel::channel();
+        let mut core = Core::new(0, tx, Timer::default());
+        let poll = unwrap!(Poll::new());
+        let shared = Arc::new(Mutex::new(0));
+        let shared_0 = shared.clone();
+
+        let token = core.get_new_token();
+        let _ = core.insert_state(token, Rc::new(RefCell::new(Idle)));
+        core.dispatch(&poll, token, "ready", move |_, _| {
+            let mut value = lock(&shared_0);
+            *value += 1;
+            panic!("Panicked holding the lock");
+        });
+
+        assert!(shared.lock().is_err());
+        assert_eq!(*lock(&shared), 1);
+    }
+
+    #[test]
+    fn debug_snapshot() {
+        let (tx, _rx) = channel::channel();
+        let mut core = Core::new(0, tx, Timer::default());
+        let token = core.get_new_token();
+        let _ = core.insert_state(token, Rc::new(RefCell::new(Idle)));
+        let _ = unwrap!(core.set_timeout(Duration::from_secs(10), CoreTimer::new(token, 3)));
+
+        let snapshot = core.debug_snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].token, token.0);
+        assert_eq!(snapshot[0].name, "Idle");
+        assert_eq!(snapshot[0].timeouts.len(), 1);
+        assert_eq!(snapshot[0].timeouts[0].0, 3);
+        // Timeouts fire on the first tick of the timer wheel past when they are due
+        let tick = Duration::from_millis(TIMER_TICK_MS);
+        assert!(snapshot[0].timeouts[0].1 <= Duration::from_secs(10) + tick);
+    }
+}
@@ -0,0 +1,105 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use std::fmt;
+use std::io;
+use std::str;
+
+/// Longest interface name the OS takes, along with its terminating NUL.
+pub const IFNAMSIZ: usize = 16;
+
+/// The name of a network interface to pin sockets to, e.g. `"eth1"`, kept inline so that
+/// `SocketConfig` stays `Copy`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Device {
+    name: [u8; IFNAMSIZ],
+    len: usize,
+}
+
+impl Device {
+    /// The interface called `name`, or `None` if that cannot be the name of one.
+    pub fn new(name: &str) -> Option<Self> {
+        if name.is_empty() || name.len() >= IFNAMSIZ || name.contains('\0') {
+            return None;
+        }
+        let mut device = Device {
+            name: [0; IFNAMSIZ],
+            len: name.len(),
+        };
+        device.name[..name.len()].copy_from_slice(name.as_bytes());
+        Some(device)
+    }
+
+    /// The name of the interface.
+    pub fn name(&self) -> &str {
+        unwrap!(str::from_utf8(&self.name[..self.len]))
+    }
+}
+
+impl fmt::Debug for Device {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "Device({:?})", self.name())
+    }
+}
+
+/// Have `socket` send and receive through `device` alone (`SO_BINDTODEVICE`), whatever the
+/// routing table says. Listening sockets only accept connections arriving on it. Must be done
+/// before a tcp socket is connected.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[allow(unsafe_code)]
+pub fn bind_to_device<S: ::std::os::unix::io::AsRawFd>(socket: &S,
+                                                       device: &Device)
+                                                       -> io::Result<()> {
+    use libc;
+
+    let name_ptr: *const u8 = device.name.as_ptr();
+    let res = unsafe {
+        libc::setsockopt(socket.as_raw_fd(),
+                         libc::SOL_SOCKET,
+                         libc::SO_BINDTODEVICE,
+                         name_ptr as *const libc::c_void,
+                         device.len as libc::socklen_t)
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Have `socket` send and receive through `device` alone, which cannot be done here.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn bind_to_device<S>(_socket: &S, device: &Device) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other,
+                       format!("Cannot bind to {}: binding to a device is not supported on this \
+                                platform",
+                               device.name())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(unwrap!(Device::new("eth1")).name(), "eth1");
+        assert_eq!(unwrap!(Device::new("abcdefghijklmno")).name(), "abcdefghijklmno");
+        assert!(Device::new("").is_none());
+        assert!(Device::new("abcdefghijklmnop").is_none());
+        assert!(Device::new("eth\01").is_none());
+    }
+}
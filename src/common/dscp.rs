@@ -0,0 +1,170 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::Priority;
+use std::cmp;
+use std::io;
+
+/// Highest DSCP codepoint, which takes the upper six bits of the TOS byte.
+pub const MAX_DSCP: u8 = 63;
+/// Most priorities `DscpLanes` tells apart, higher ones sharing the last lane.
+pub const MAX_DSCP_LANES: usize = 8;
+
+/// The DSCP codepoint to mark the messages of each priority with, the first for priority 0 and
+/// the last also for every priority after it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DscpLanes {
+    codepoints: [u8; MAX_DSCP_LANES],
+    len: usize,
+}
+
+impl DscpLanes {
+    /// Lanes of `codepoints`, of which those past `MAX_DSCP_LANES` are left out.
+    pub fn new(codepoints: &[u8]) -> Self {
+        let mut lanes = DscpLanes::default();
+        for (lane, dscp) in lanes.codepoints.iter_mut().zip(codepoints) {
+            *lane = cmp::min(*dscp, MAX_DSCP);
+            lanes.len += 1;
+        }
+        lanes
+    }
+
+    /// The codepoint for messages of `priority`, if there are any lanes.
+    pub fn of(&self, priority: Priority) -> Option<u8> {
+        if self.len == 0 {
+            None
+        } else {
+            Some(self.codepoints[cmp::min(priority as usize, self.len - 1)])
+        }
+    }
+}
+
+/// What can be marked: sockets, wherever the OS lets us at their options.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios"))]
+pub trait Markable: ::std::os::unix::io::AsRawFd {}
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios"))]
+impl<T: ::std::os::unix::io::AsRawFd> Markable for T {}
+
+/// What can be marked: nothing, as the OS does not let us.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos",
+              target_os = "ios")))]
+pub trait Markable {}
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos",
+              target_os = "ios")))]
+impl<T> Markable for T {}
+
+/// Mark what `socket` sends with the DSCP codepoint `dscp`, by its traffic class if `ipv6` and
+/// by its TOS byte otherwise. Dual-stack sockets get both, so that IPv4 traffic is marked too.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios"))]
+#[allow(unsafe_code)]
+pub fn set_dscp<S: Markable>(socket: &S, ipv6: bool, dscp: u8) -> io::Result<()> {
+    use libc;
+    use std::mem;
+
+    let tos = (cmp::min(dscp, MAX_DSCP) << 2) as libc::c_int;
+    let tos_ptr: *const libc::c_int = &tos;
+    let set = |level, name| {
+        let res = unsafe {
+            libc::setsockopt(socket.as_raw_fd(),
+                             level,
+                             name,
+                             tos_ptr as *const libc::c_void,
+                             mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if res == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    };
+    if ipv6 {
+        set(libc::IPPROTO_IPV6, libc::IPV6_TCLASS)?;
+        // Only means something to the IPv4 traffic of a dual-stack socket
+        let _ = set(libc::IPPROTO_IP, libc::IP_TOS);
+        Ok(())
+    } else {
+        set(libc::IPPROTO_IP, libc::IP_TOS)
+    }
+}
+
+/// Mark what `socket` sends with the DSCP codepoint `dscp`, which cannot be done here.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos",
+              target_os = "ios")))]
+pub fn set_dscp<S: Markable>(_socket: &S, _ipv6: bool, _dscp: u8) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "DSCP marking is not supported on this platform"))
+}
+
+/// `set_dscp`, only logging should it fail, the marking being no more than a hint to the network.
+/// Returns whether the socket was marked.
+pub fn mark_dscp<S: Markable>(socket: &S, ipv6: bool, dscp: u8) -> bool {
+    match set_dscp(socket, ipv6, dscp) {
+        Ok(()) => true,
+        Err(e) => {
+            debug!("Could not mark socket with DSCP {}: {}", dscp, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+
+    #[test]
+    fn lanes() {
+        assert_eq!(DscpLanes::default().of(0), None);
+        let lanes = DscpLanes::new(&[46, 34, 100]);
+        assert_eq!(lanes.of(0), Some(46));
+        assert_eq!(lanes.of(1), Some(34));
+        assert_eq!(lanes.of(2), Some(MAX_DSCP));
+        assert_eq!(lanes.of(200), Some(MAX_DSCP));
+    }
+
+    #[test]
+    fn marks_or_degrades() {
+        // Whether the OS lets us, marking must not take the socket down with it
+        let socket = unwrap!(UdpSocket::bind("127.0.0.1:0"));
+        let _ = mark_dscp(&socket, false, 46);
+        unwrap!(socket.send_to(b"marked", unwrap!(socket.local_addr())));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[allow(unsafe_code)]
+    fn marks_tos() {
+        use libc;
+        use std::mem;
+        use std::os::unix::io::AsRawFd;
+
+        let socket = unwrap!(UdpSocket::bind("127.0.0.1:0"));
+        assert!(mark_dscp(&socket, false, 46));
+
+        let mut tos: libc::c_int = 0;
+        let tos_ptr: *mut libc::c_int = &mut tos;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(socket.as_raw_fd(),
+                             libc::IPPROTO_IP,
+                             libc::IP_TOS,
+                             tos_ptr as *mut libc::c_void,
+                             &mut len)
+        };
+        assert_eq!(res, 0);
+        assert_eq!(tos, 46 << 2);
+    }
+}
@@ -41,6 +41,11 @@ quick_error! {
         PayloadSizeProhibitive {
             description("Payload is too large")
         }
+        /// A message sent with its payload apart does not end in the empty payload to stand in for
+        /// it
+        NoPayloadForBody {
+            description("Message has no empty payload at its end for the body")
+        }
         /// Serialisation error
         Serialisation(e: SerialisationError) {
             description(e.description())
@@ -59,6 +64,19 @@ quick_error! {
         ZeroByteRead {
             description("Read zero bytes from the socket - indicates EOF")
         }
+        /// A peer sent something that is not a valid message
+        InvalidMessage(reason: &'static str) {
+            description("Invalid message")
+            display("Invalid message: {}", reason)
+        }
+        /// A droppable message was refused as its backlog is full
+        BacklogFull {
+            description("Backlog of droppable messages is full")
+        }
+        /// Too many data messages are already queued on the event loop
+        EventLoopBusy {
+            description("Event loop is busy")
+        }
         /// CoreMessage send error
         CoreMsgTx(e: mio::channel::SendError<CoreMessage>) {
             description(e.description())
@@ -25,6 +25,8 @@ use std::net::IpAddr;
 pub struct Interface {
     /// The name of the interface.
     pub name: String,
+    /// The index of the interface, e.g. to join a multicast group on, or 0 if unknown.
+    pub index: u32,
     /// The address details of the interface.
     pub addr: IfAddr,
 }
@@ -112,6 +114,7 @@ mod getifaddrs_posix {
     use libc::{AF_INET, AF_INET6};
     use libc::freeifaddrs as posix_freeifaddrs;
     use libc::getifaddrs as posix_getifaddrs;
+    use libc::if_nametoindex as posix_if_nametoindex;
     use libc::ifaddrs as posix_ifaddrs;
     use libc::sockaddr as posix_sockaddr;
     use libc::sockaddr_in as posix_sockaddr_in;
@@ -232,8 +235,10 @@ mod getifaddrs_posix {
                                })
                 }
             };
+            let index = unsafe { posix_if_nametoindex(ifaddr.ifa_name) };
             ret.push(Interface {
                          name: name,
+                         index: index,
                          addr: addr,
                      });
         }
@@ -304,7 +309,7 @@ mod getifaddrs_windows {
         mtu: DWORD,
         if_type: DWORD,
         oper_status: c_int,
-        ipv6_if_index: DWORD,
+        pub ipv6_if_index: DWORD,
         zone_indices: [DWORD; 16],
         // Loads more follows, but I'm not bothering to map these for now
         pub first_prefix: *const IpAdapterPrefix,
@@ -513,8 +518,13 @@ mod getifaddrs_windows {
                                    })
                     }
                 };
+                let index = match addr {
+                    IfAddr::V4(..) => ifaddr.if_index,
+                    IfAddr::V6(..) => ifaddr.ipv6_if_index,
+                };
                 ret.push(Interface {
                              name: name,
+                             index: index,
                              addr: addr,
                          });
             }
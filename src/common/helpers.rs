@@ -0,0 +1,124 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::lock;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// Whether we serve our peers as a traversal helper - echoing their external addresses and
+/// brokering their rendezvous - and the helpers they serve as in turn, shared by the event loops
+/// of a service.
+///
+/// Listeners stand for election once they are found reachable from outside our network and
+/// withdraw once they are not, or stop. Peers are told whenever that changes, and the helpers they
+/// tell us of are handed on to wherever the service keeps its pool of helpers.
+#[derive(Clone)]
+pub struct TraversalHelpers {
+    enabled: bool,
+    // The external address we serve on, by the local address of the listener it reaches.
+    ours: Arc<Mutex<BTreeMap<SocketAddr, SocketAddr>>>,
+    on_advertised: Arc<Fn(SocketAddr, bool) + Send + Sync>,
+}
+
+impl TraversalHelpers {
+    /// Stand for election only if `enabled`. `on_advertised` is told of each helper a peer starts
+    /// (`true`) or stops (`false`) serving as.
+    pub fn new<F>(enabled: bool, on_advertised: F) -> Self
+        where F: Fn(SocketAddr, bool) + Send + Sync + 'static
+    {
+        TraversalHelpers {
+            enabled: enabled,
+            ours: Arc::new(Mutex::new(BTreeMap::new())),
+            on_advertised: Arc::new(on_advertised),
+        }
+    }
+
+    /// Serve as a helper on `addr` for the listener bound to `local_addr`, or withdraw that
+    /// listener if `None`.
+    pub fn elect(&self, local_addr: SocketAddr, addr: Option<SocketAddr>) {
+        let mut ours = lock(&self.ours);
+        match addr {
+            Some(addr) if self.enabled => {
+                let _ = ours.insert(local_addr, addr);
+            }
+            _ => {
+                let _ = ours.remove(&local_addr);
+            }
+        }
+    }
+
+    /// The address we serve as a helper on, if we do.
+    pub fn ours(&self) -> Option<SocketAddr> {
+        lock(&self.ours).values().next().cloned()
+    }
+
+    /// A peer started serving as a helper on `addr`, or stopped if not `serving`.
+    pub fn advertised(&self, addr: SocketAddr, serving: bool) {
+        (self.on_advertised)(addr, serving)
+    }
+}
+
+impl Default for TraversalHelpers {
+    fn default() -> Self {
+        TraversalHelpers::new(false, |_, _| ())
+    }
+}
+
+impl fmt::Debug for TraversalHelpers {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter,
+               "TraversalHelpers {{ enabled: {}, ours: {:?} }}",
+               self.enabled,
+               self.ours())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn election() {
+        let local_0 = unwrap!("192.168.0.2:5483".parse());
+        let local_1 = unwrap!("192.168.0.2:5484".parse());
+        let ext_0 = unwrap!("8.8.8.8:5483".parse());
+        let ext_1 = unwrap!("8.8.8.8:5484".parse());
+
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let helpers = TraversalHelpers::new(true, move |addr, serving| {
+            let _ = unwrap!(tx.lock()).send((addr, serving));
+        });
+        helpers.elect(local_0, Some(ext_0));
+        helpers.clone().elect(local_1, Some(ext_1));
+        assert_eq!(helpers.ours(), Some(ext_0));
+        helpers.elect(local_0, None);
+        assert_eq!(helpers.ours(), Some(ext_1));
+        helpers.elect(local_1, None);
+        assert_eq!(helpers.ours(), None);
+
+        helpers.advertised(ext_0, true);
+        assert_eq!(unwrap!(rx.try_recv()), (ext_0, true));
+
+        let helpers = TraversalHelpers::default();
+        helpers.elect(local_0, Some(ext_0));
+        assert_eq!(helpers.ours(), None);
+    }
+}
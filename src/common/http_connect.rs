@@ -0,0 +1,176 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{Handshake, Listener, Reply, Stream, Transport, TunnelStream};
+use mio::tcp::TcpListener;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::str;
+
+// Longest response head to wait for from the proxy.
+const MAX_RESPONSE_HEAD: usize = 8 * 1024;
+
+/// Dials peers through an HTTP proxy with `CONNECT` tunnels, for networks that let nothing else
+/// out, and listens directly. Hole punching cannot pass through a proxy, so peers the proxy cannot
+/// reach directly are only reached through the relay.
+#[derive(Clone, Debug)]
+pub struct HttpConnect {
+    proxy: SocketAddr,
+    auth: Option<(String, String)>,
+}
+
+impl HttpConnect {
+    /// Tunnel through the proxy at `proxy`, which has to let us through without authenticating.
+    pub fn new(proxy: SocketAddr) -> Self {
+        HttpConnect {
+            proxy: proxy,
+            auth: None,
+        }
+    }
+
+    /// Authenticate to the proxy with `username` and `password` (`Basic` authentication).
+    pub fn with_auth(mut self, username: String, password: String) -> Self {
+        self.auth = Some((username, password));
+        self
+    }
+}
+
+impl Transport for HttpConnect {
+    fn name(&self) -> &'static str {
+        "http_connect"
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Box<Stream>> {
+        let handshake = HttpConnectHandshake {
+            target: *addr,
+            auth: self.auth.clone(),
+        };
+        Ok(Box::new(TunnelStream::connect(&self.proxy, addr, self.name(), handshake)?))
+    }
+
+    fn listen(&self, addr: &SocketAddr) -> io::Result<Box<Listener>> {
+        Ok(Box::new(TcpListener::bind(addr)?))
+    }
+}
+
+struct HttpConnectHandshake {
+    target: SocketAddr,
+    auth: Option<(String, String)>,
+}
+
+impl Handshake for HttpConnectHandshake {
+    fn start(&mut self) -> Vec<u8> {
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", self.target);
+        if let Some((ref username, ref password)) = self.auth {
+            let credentials = base64(format!("{}:{}", username, password).as_bytes());
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+        }
+        request.push_str("\r\n");
+        request.into_bytes()
+    }
+
+    fn reply(&mut self, incoming: &[u8]) -> io::Result<Reply> {
+        let head_len = match incoming.windows(4).position(|end| end == b"\r\n\r\n") {
+            Some(pos) => pos + 4,
+            None if incoming.len() > MAX_RESPONSE_HEAD => {
+                return Err(io::Error::new(ErrorKind::InvalidData,
+                                          "HTTP proxy response head too long"))
+            }
+            None => return Ok(Reply::Incomplete),
+        };
+
+        let status_line = incoming
+            .split(|&b| b == b'\r')
+            .next()
+            .and_then(|line| str::from_utf8(line).ok())
+            .unwrap_or("");
+        let status = status_line
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok());
+        match status {
+            Some(200...299) if status_line.starts_with("HTTP/1.") => Ok(Reply::Done(head_len)),
+            Some(407) => {
+                Err(io::Error::new(ErrorKind::PermissionDenied,
+                                   "HTTP proxy requires authentication"))
+            }
+            Some(_) => {
+                Err(io::Error::new(ErrorKind::ConnectionRefused,
+                                   format!("HTTP proxy refused to connect: {}", status_line)))
+            }
+            None => {
+                Err(io::Error::new(ErrorKind::InvalidData, "Invalid response from HTTP proxy"))
+            }
+        }
+    }
+}
+
+/// `bytes` in Base64, as HTTP headers carry binary values.
+pub fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+                                      abcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |group, (i, &b)| group | ((b as u32) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[((group >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake() {
+        assert_eq!(base64(b"user:pass"), "dXNlcjpwYXNz");
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b"a"), "YQ==");
+
+        let mut handshake = HttpConnectHandshake {
+            target: unwrap!("10.1.2.3:4567".parse()),
+            auth: Some(("user".to_owned(), "pass".to_owned())),
+        };
+        assert_eq!(unwrap!(String::from_utf8(handshake.start())),
+                   "CONNECT 10.1.2.3:4567 HTTP/1.1\r\nHost: 10.1.2.3:4567\r\nProxy-Authorization: \
+                    Basic dXNlcjpwYXNz\r\n\r\n");
+
+        let response = b"HTTP/1.1 200 Connection established\r\n\r\nhello";
+        match unwrap!(handshake.reply(&response[..20])) {
+            Reply::Incomplete => (),
+            _ => panic!("Reply taken before its end"),
+        }
+        match unwrap!(handshake.reply(response)) {
+            Reply::Done(len) => assert_eq!(&response[len..], b"hello"),
+            _ => panic!("Tunnel not opened"),
+        }
+
+        let refused = b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n";
+        let err = handshake.reply(refused).err().map(|e| e.kind());
+        assert_eq!(err, Some(ErrorKind::PermissionDenied));
+    }
+}
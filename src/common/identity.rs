@@ -0,0 +1,389 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use rust_sodium::crypto::box_;
+use rust_sodium::crypto::sign::{self, PublicKey, SecretKey, Signature};
+use rust_sodium::randombytes;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+// Keeps proofs made for crust handshakes from being good for anything else signed with the key.
+const PROOF_CONTEXT: &'static [u8] = b"crust-identity-proof";
+// Keeps proofs made for moving a connection onto a new path apart from those made in handshakes.
+const MIGRATION_CONTEXT: &'static [u8] = b"crust-migration-proof";
+// Keeps proofs of holding a `PeerId` key apart from anything else boxed with it.
+const KEY_PROOF_CONTEXT: &'static [u8] = b"crust-key-proof";
+/// Length of the random challenge each side of a handshake sends the other.
+pub const CHALLENGE_BYTES: usize = 32;
+
+/// A peer's long-term public signing key. Unlike its `PeerId`, which is new each time crust
+/// starts, it stays the same across runs when `Config::identity_file` is set, and the peer has
+/// proven it holds the secret key to every connection that reports it.
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Serialize, Deserialize)]
+pub struct Identity(pub PublicKey);
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter,
+               "Identity({:02x}{:02x}{:02x}{:02x}..)",
+               (self.0).0[0],
+               (self.0).0[1],
+               (self.0).0[2],
+               (self.0).0[3])
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter,
+               "{:02x}{:02x}{:02x}{:02x}..",
+               (self.0).0[0],
+               (self.0).0[1],
+               (self.0).0[2],
+               (self.0).0[3])
+    }
+}
+
+/// Random bytes sent in the handshake for the peer to sign over in proving its identity to us.
+/// Both sides pick a new one for every handshake, so no proof can be replayed to either of them.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Challenge(pub [u8; CHALLENGE_BYTES]);
+
+impl Challenge {
+    /// A fresh challenge, for one handshake only.
+    pub fn new() -> Self {
+        let mut bytes = [0; CHALLENGE_BYTES];
+        randombytes::randombytes_into(&mut bytes);
+        Challenge(bytes)
+    }
+}
+
+/// Our signing key pair.
+#[derive(Clone)]
+pub struct IdentityKeys {
+    public: PublicKey,
+    secret: SecretKey,
+}
+
+impl fmt::Debug for IdentityKeys {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "IdentityKeys({:?})", self.identity())
+    }
+}
+
+impl IdentityKeys {
+    /// A new identity, to last this run only.
+    pub fn generate() -> Self {
+        let (public, secret) = sign::gen_keypair();
+        IdentityKeys {
+            public: public,
+            secret: secret,
+        }
+    }
+
+    /// Read the secret key from `path`, or if there is no such file generate one and write it
+    /// there, readable by the owner only.
+    pub fn load_or_create(path: &Path) -> io::Result<Self> {
+        match File::open(path) {
+            Ok(mut file) => {
+                let mut bytes = Vec::with_capacity(sign::SECRETKEYBYTES);
+                let _ = file.read_to_end(&mut bytes)?;
+                IdentityKeys::from_secret_bytes(&bytes)
+                    .ok_or_else(|| {
+                                    io::Error::new(io::ErrorKind::InvalidData,
+                                                   "Identity file does not hold a secret key")
+                                })
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                let keys = IdentityKeys::generate();
+                let mut file = create_private(path)?;
+                file.write_all(&keys.secret.0)?;
+                file.sync_all()?;
+                Ok(keys)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // The public key is the second half of an ed25519 secret key.
+    fn from_secret_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != sign::SECRETKEYBYTES {
+            return None;
+        }
+        let secret = match SecretKey::from_slice(bytes) {
+            Some(secret) => secret,
+            None => return None,
+        };
+        PublicKey::from_slice(&bytes[sign::SECRETKEYBYTES - sign::PUBLICKEYBYTES..])
+            .map(|public| {
+                     IdentityKeys {
+                         public: public,
+                         secret: secret,
+                     }
+                 })
+    }
+
+    pub fn identity(&self) -> Identity {
+        Identity(self.public)
+    }
+
+    /// Sign `data` with our identity key.
+    pub fn sign(&self, data: &[u8]) -> Signature {
+        sign::sign_detached(data, &self.secret)
+    }
+
+    /// Prove to the peer whose `PeerId` key is `their_pk` that we, with `our_pk`, hold our
+    /// identity. The proof is good for this pair of keys and this pair of challenges only, so for
+    /// this one handshake.
+    pub fn prove(&self,
+                 our_pk: &box_::PublicKey,
+                 their_pk: &box_::PublicKey,
+                 our_challenge: &Challenge,
+                 their_challenge: &Challenge)
+                 -> IdentityProof {
+        let data = proof_data(our_pk, their_pk, our_challenge, their_challenge);
+        IdentityProof {
+            key: self.public,
+            sig: self.sign(&data),
+        }
+    }
+
+    /// Prove to the peer whose `PeerId` key is `their_pk`, over a new path for our connection to
+    /// it, that we still hold the identity we proved on connecting, by answering the `challenge`
+    /// it sent us over the old path.
+    pub fn prove_migration(&self,
+                           our_pk: &box_::PublicKey,
+                           their_pk: &box_::PublicKey,
+                           challenge: &Challenge)
+                           -> IdentityProof {
+        IdentityProof {
+            key: self.public,
+            sig: self.sign(&migration_data(our_pk, their_pk, challenge)),
+        }
+    }
+}
+
+/// What a peer sends in the handshake to prove its identity.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct IdentityProof {
+    key: PublicKey,
+    sig: Signature,
+}
+
+impl IdentityProof {
+    /// The identity proven to us, with `our_pk`, by the peer with `their_pk` in the handshake
+    /// where each of us sent the other the given challenge, unless the proof is bad.
+    pub fn verify(&self,
+                  their_pk: &box_::PublicKey,
+                  our_pk: &box_::PublicKey,
+                  their_challenge: &Challenge,
+                  our_challenge: &Challenge)
+                  -> Option<Identity> {
+        let data = proof_data(their_pk, our_pk, their_challenge, our_challenge);
+        if sign::verify_detached(&self.sig, &data, &self.key) {
+            Some(Identity(self.key))
+        } else {
+            None
+        }
+    }
+
+    /// The identity proven to us, with `our_pk`, by the peer with `their_pk` in answer to the
+    /// `challenge` we sent it for moving our connection onto a new path, unless the proof is bad.
+    pub fn verify_migration(&self,
+                            their_pk: &box_::PublicKey,
+                            our_pk: &box_::PublicKey,
+                            challenge: &Challenge)
+                            -> Option<Identity> {
+        let data = migration_data(their_pk, our_pk, challenge);
+        if sign::verify_detached(&self.sig, &data, &self.key) {
+            Some(Identity(self.key))
+        } else {
+            None
+        }
+    }
+}
+
+/// What a peer sends to prove it holds the secret key to its `PeerId`, e.g. to a relay it asks to
+/// be paired up with another peer as itself. Boxed from that key to the one it is sent to, it is
+/// good for the challenge it answers and the request it is made for only.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct KeyProof {
+    nonce: box_::Nonce,
+    sealed: Vec<u8>,
+}
+
+impl KeyProof {
+    /// Prove to the holder of `their_pk`, who sent us `challenge`, that we hold `our_sk` in
+    /// making `request` of it.
+    pub fn new(our_sk: &box_::SecretKey,
+               their_pk: &box_::PublicKey,
+               challenge: &Challenge,
+               request: &[u8])
+               -> Self {
+        let nonce = box_::gen_nonce();
+        let data = key_proof_data(challenge, request);
+        KeyProof {
+            nonce: nonce,
+            sealed: box_::seal(&data, &nonce, their_pk, our_sk),
+        }
+    }
+
+    /// Whether the holder of `their_pk` proved to us, with `our_sk`, that it made `request` in
+    /// answer to our `challenge`.
+    pub fn verify(&self,
+                  their_pk: &box_::PublicKey,
+                  our_sk: &box_::SecretKey,
+                  challenge: &Challenge,
+                  request: &[u8])
+                  -> bool {
+        box_::open(&self.sealed, &self.nonce, their_pk, our_sk)
+            .map_or(false, |data| data == key_proof_data(challenge, request))
+    }
+}
+
+fn proof_data(prover: &box_::PublicKey,
+              verifier: &box_::PublicKey,
+              prover_challenge: &Challenge,
+              verifier_challenge: &Challenge)
+              -> Vec<u8> {
+    let mut data = Vec::with_capacity(PROOF_CONTEXT.len() + 2 * box_::PUBLICKEYBYTES +
+                                      2 * CHALLENGE_BYTES);
+    data.extend_from_slice(PROOF_CONTEXT);
+    data.extend_from_slice(&prover.0);
+    data.extend_from_slice(&verifier.0);
+    data.extend_from_slice(&prover_challenge.0);
+    data.extend_from_slice(&verifier_challenge.0);
+    data
+}
+
+fn migration_data(prover: &box_::PublicKey,
+                  verifier: &box_::PublicKey,
+                  challenge: &Challenge)
+                  -> Vec<u8> {
+    let mut data = Vec::with_capacity(MIGRATION_CONTEXT.len() + 2 * box_::PUBLICKEYBYTES +
+                                      CHALLENGE_BYTES);
+    data.extend_from_slice(MIGRATION_CONTEXT);
+    data.extend_from_slice(&prover.0);
+    data.extend_from_slice(&verifier.0);
+    data.extend_from_slice(&challenge.0);
+    data
+}
+
+fn key_proof_data(challenge: &Challenge, request: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(KEY_PROOF_CONTEXT.len() + CHALLENGE_BYTES + request.len());
+    data.extend_from_slice(KEY_PROOF_CONTEXT);
+    data.extend_from_slice(&challenge.0);
+    data.extend_from_slice(request);
+    data
+}
+
+#[cfg(unix)]
+fn create_private(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_sodium::crypto::box_;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn prove_and_verify() {
+        let keys = IdentityKeys::generate();
+        let (our_pk, _) = box_::gen_keypair();
+        let (their_pk, _) = box_::gen_keypair();
+        let (other_pk, _) = box_::gen_keypair();
+        let our_challenge = Challenge::new();
+        let their_challenge = Challenge::new();
+        let other_challenge = Challenge::new();
+
+        let proof = keys.prove(&our_pk, &their_pk, &our_challenge, &their_challenge);
+        assert_eq!(proof.verify(&our_pk, &their_pk, &our_challenge, &their_challenge),
+                   Some(keys.identity()));
+        // Replayed to someone else, or passed off as someone else's
+        assert_eq!(proof.verify(&our_pk, &other_pk, &our_challenge, &their_challenge),
+                   None);
+        assert_eq!(proof.verify(&other_pk, &their_pk, &our_challenge, &their_challenge),
+                   None);
+        // Replayed in another handshake, even between the same peers
+        assert_eq!(proof.verify(&our_pk, &their_pk, &our_challenge, &other_challenge),
+                   None);
+        assert_eq!(proof.verify(&our_pk, &their_pk, &other_challenge, &their_challenge),
+                   None);
+    }
+
+    #[test]
+    fn prove_and_verify_migration() {
+        let keys = IdentityKeys::generate();
+        let (our_pk, _) = box_::gen_keypair();
+        let (their_pk, _) = box_::gen_keypair();
+        let challenge = Challenge::new();
+
+        let proof = keys.prove_migration(&our_pk, &their_pk, &challenge);
+        assert_eq!(proof.verify_migration(&our_pk, &their_pk, &challenge),
+                   Some(keys.identity()));
+        assert_eq!(proof.verify_migration(&our_pk, &their_pk, &Challenge::new()),
+                   None);
+        assert_eq!(proof.verify_migration(&their_pk, &our_pk, &challenge), None);
+
+        // Neither kind of proof passes for the other
+        let handshake_proof = keys.prove(&our_pk, &their_pk, &challenge, &challenge);
+        assert_eq!(handshake_proof.verify_migration(&our_pk, &their_pk, &challenge),
+                   None);
+        assert_eq!(proof.verify(&our_pk, &their_pk, &challenge, &challenge), None);
+    }
+
+    #[test]
+    fn key_proof() {
+        let (our_pk, our_sk) = box_::gen_keypair();
+        let (their_pk, their_sk) = box_::gen_keypair();
+        let (other_pk, other_sk) = box_::gen_keypair();
+        let challenge = Challenge::new();
+
+        let proof = KeyProof::new(&our_sk, &their_pk, &challenge, b"request");
+        assert!(proof.verify(&our_pk, &their_sk, &challenge, b"request"));
+        // Passed off as someone else's, replayed to someone else or in answer to another
+        // challenge, or for another request
+        assert!(!proof.verify(&other_pk, &their_sk, &challenge, b"request"));
+        assert!(!proof.verify(&our_pk, &other_sk, &challenge, b"request"));
+        assert!(!proof.verify(&our_pk, &their_sk, &Challenge::new(), b"request"));
+        assert!(!proof.verify(&our_pk, &their_sk, &challenge, b"other request"));
+    }
+
+    #[test]
+    fn persist() {
+        let path = env::temp_dir().join(format!("crust-identity-{}", ::rand::random::<u64>()));
+        let created = unwrap!(IdentityKeys::load_or_create(&path));
+        let loaded = unwrap!(IdentityKeys::load_or_create(&path));
+        assert_eq!(created.identity(), loaded.identity());
+        unwrap!(fs::remove_file(&path));
+    }
+}
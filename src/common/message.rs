@@ -16,9 +16,25 @@
 // relating to use of the SAFE Network Software.
 
 
-use common::{self, ExternalReachability, NameHash};
-use rust_sodium::crypto::box_::PublicKey;
+use byteorder::{ByteOrder, LittleEndian};
+use common::{self, Capabilities, Challenge, CommonError, Compression, ExternalReachability,
+             IdentityProof, KeyProof, NameHash, Result};
+use maidsafe_utilities::serialisation::deserialise;
+use rust_sodium::crypto::box_::{Nonce, PublicKey};
+use std::mem;
 
+// Index of `Message::Sequenced`, which is how its variant is encoded ahead of its fields.
+const SEQUENCED_TAG: u32 = 19;
+// Only user messages are ever sequenced, never an already sequenced one.
+const MAX_SEQUENCED_NESTING: usize = 1;
+
+/// Something a peer sends us, which may hold anything at all.
+pub trait Decode: Sized {
+    /// Decode `bytes`, failing rather than panicking or running out of stack whatever they hold.
+    fn decode(bytes: &[u8]) -> Result<Self>;
+}
+
+// A variant's index is its tag on the wire, so new ones go at the end.
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum Message {
     Heartbeat,
@@ -30,10 +46,103 @@ pub enum Message {
     ChooseConnection,
     Connect(PublicKey, NameHash),
     Data(Vec<u8>),
+    StreamOpen(u32),
+    StreamData(u32, Vec<u8>),
+    StreamWindow(u32, u32),
+    StreamClose(u32),
+    Compressions(Vec<Compression>),
+    CompressedData(Compression, Vec<u8>),
+    MigrateReq,
+    MigrateToken(u64),
+    Migrate(PublicKey, u64),
+    MigrateDone,
+    Sequenced(u64, Box<Message>),
+    Ack(Vec<u64>),
+    Identify(IdentityProof),
+    Ping(u64),
+    Pong(u64),
+    Capabilities(Capabilities),
+    Helper(Option<common::SocketAddr>),
+    // The last field of each is the `PROTOCOL_VERSION` of its sender.
+    IdentifiedBootstrapRequest(PublicKey, NameHash, ExternalReachability, Challenge, u32),
+    IdentifiedBootstrapGranted(PublicKey, Challenge, IdentityProof, u32),
+    IdentifiedConnect(PublicKey, NameHash, Challenge, Option<u64>, u32),
+    MigrateChallenge(Challenge),
+    MigrateProven(PublicKey, IdentityProof),
+    // How often in milliseconds the sender sends heartbeats.
+    KeepAlive(u64),
+    EchoAddrReqFromOtherPort,
+    EchoAddrReqTo(common::SocketAddr),
+    RelayConnect(PublicKey, PublicKey, NameHash),
+    ReachabilityReq(common::SocketAddr),
+    ReachabilityResp(bool),
+    RendezvousReq(Vec<u8>, Vec<u8>),
+    RendezvousResp(common::SocketAddr, Vec<u8>),
+    // Our offer in the key exchange that starts every connection, see `KeyExchange`.
+    SessionKey(Nonce, Vec<u8>),
+    // What follows the key exchange on the wire, sealed a record at a time, see `Session`.
+    Sealed(Vec<u8>),
+    // A relay's `PeerId` key and what a peer asking it for relaying is to answer to prove its own.
+    RelayChallenge(PublicKey, Challenge),
+    RelayProof(KeyProof),
 }
 
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum BootstrapDenyReason {
     InvalidNameHash,
     FailedExternalReachability,
+    NotRelaying,
+    IncompatibleVersion,
+}
+
+impl Decode for Message {
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        // Deserialisation recurses into every `Sequenced`, so a peer nesting enough of them would
+        // overflow our stack. Each is its tag and sequence number ahead of the message it wraps.
+        let header = mem::size_of::<u32>() + mem::size_of::<u64>();
+        let mut pos = 0;
+        let mut nesting = 0;
+        while bytes.len() >= pos + header &&
+              LittleEndian::read_u32(&bytes[pos..]) == SEQUENCED_TAG {
+            nesting += 1;
+            if nesting > MAX_SEQUENCED_NESTING {
+                return Err(CommonError::InvalidMessage("sequenced messages nested too deeply"));
+            }
+            pos += header;
+        }
+
+        Ok(deserialise(bytes)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maidsafe_utilities::serialisation::serialise;
+
+    #[test]
+    fn sequenced_nesting() {
+        let once = Message::Sequenced(1, Box::new(Message::Data(vec![SEQUENCED_TAG as u8; 16])));
+        let bytes = unwrap!(serialise(&once));
+        assert_eq!(LittleEndian::read_u32(&bytes), SEQUENCED_TAG);
+        assert_eq!(unwrap!(Message::decode(&bytes)), once);
+
+        let twice = Message::Sequenced(2, Box::new(once));
+        match Message::decode(&unwrap!(serialise(&twice))) {
+            Err(CommonError::InvalidMessage(_)) => (),
+            res => panic!("Unexpected {:?}", res),
+        }
+
+        assert!(Message::decode(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn wire_tags() {
+        // Peers from before any of the later variants still tell what they are sent
+        let tag = |msg| LittleEndian::read_u32(&unwrap!(serialise(&msg)));
+        assert_eq!(tag(Message::Heartbeat), 0);
+        assert_eq!(tag(Message::EchoAddrReq), 4);
+        assert_eq!(tag(Message::ChooseConnection), 6);
+        assert_eq!(tag(Message::Data(Vec::new())), 8);
+    }
 }
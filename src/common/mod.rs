@@ -15,11 +15,35 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-pub use self::core::{Core, CoreMessage, CoreTimer, EventLoop, spawn_event_loop};
+pub use self::buffer_pool::{BufferPool, BufferPoolStats};
+pub use self::capabilities::{Capabilities, FEATURE_EXT_ADDR, FEATURE_HELPERS, FEATURE_KEEP_ALIVE,
+                             FEATURE_MIGRATION, FEATURE_OVERSIZED, FEATURE_PING, FEATURE_REPLAY,
+                             FEATURE_STREAMS, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+                             is_compatible_version};
+pub use self::compression::{Compression, SUPPORTED_COMPRESSIONS, compress, decompress};
+pub use self::core::{Core, CoreMessage, CoreTimer, EventLoop, StateCrash, StateSnapshot, lock,
+                     spawn_event_loop};
+pub use self::device::{Device, IFNAMSIZ, bind_to_device};
+pub use self::dscp::{DscpLanes, MAX_DSCP, MAX_DSCP_LANES, Markable, mark_dscp, set_dscp};
 pub use self::error::CommonError;
-pub use self::message::{BootstrapDenyReason, Message};
-pub use self::socket::Socket;
+pub use self::helpers::TraversalHelpers;
+pub use self::http_connect::HttpConnect;
+pub use self::identity::{Challenge, Identity, IdentityKeys, IdentityProof, KeyProof};
+pub use self::message::{BootstrapDenyReason, Decode, Message};
+pub use self::rate_limit::{BandwidthLimits, PeerQuota, QuotaPolicy, RateLimit};
+pub use self::session::{KeyExchange, MAX_RECORD, MAX_SEGMENT, Session};
+pub use self::shard::{MAX_SHARDS, Shards, shard_of, shard_token_start};
+pub use self::socket::{Detached, DropPolicy, QueueFullPolicy, QueueLimits, Received, Socket,
+                       SocketConfig, set_hop_limit, set_recv_buffer_size};
+pub use self::socks5::Socks5;
 pub use self::state::State;
+pub use self::timer_wheel::{Timeout, TimerWheel};
+pub use self::trace::{LogSubscriber, TRACE_TARGET, TraceEvent, TraceState, TraceSubscriber};
+pub use self::transport::{Listener, Stream, Tcp, Transport, TunedTcp};
+pub use self::tunnel::{Handshake, Reply, TunnelStream};
+pub use self::udp::{Udp, UdpListener, UdpStream};
+pub use self::utp::{Utp, UtpListener, UtpStream};
+pub use self::websocket::{WebSocket, WsListener, WsStream};
 use rust_sodium::crypto::hash::sha256;
 use std::net::SocketAddr;
 
@@ -52,11 +76,33 @@ pub enum CrustUser {
 pub enum ExternalReachability {
     NotRequired,
     Required { direct_listeners: Vec<SocketAddr> },
+    // A node to be reached through the peer it bootstraps off, which must be a relay, should
+    // none of its listeners be reached.
+    Relayed { direct_listeners: Vec<SocketAddr> },
 }
 
 pub mod get_if_addrs;
+mod buffer_pool;
+mod capabilities;
+mod compression;
 mod core;
+mod device;
+mod dscp;
+mod helpers;
 mod error;
+mod http_connect;
+mod identity;
 mod message;
+mod rate_limit;
+mod session;
+mod shard;
 mod socket;
+mod socks5;
 mod state;
+mod timer_wheel;
+mod trace;
+mod transport;
+mod tunnel;
+mod udp;
+mod utp;
+mod websocket;
@@ -0,0 +1,401 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{QueueFullPolicy, QueueLimits, lock};
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Least number of bytes worth waiting for once a limit has been reached, so that a throttled
+/// socket is not woken up for every few bytes.
+const MIN_BURST: u64 = 4 * 1024;
+
+/// A token bucket refilled with `rate` bytes per second and holding at most a second's worth.
+/// Taking more than it holds leaves it in debt, which is paid off before it fills up again.
+/// Clones share the same bucket.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    inner: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: u64,
+    tokens: u64,
+    debt: u64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> TokenBucket {
+        let rate = cmp::max(rate, 1);
+        TokenBucket {
+            inner: Arc::new(Mutex::new(Bucket {
+                                           rate: rate,
+                                           tokens: rate,
+                                           debt: 0,
+                                           refilled_at: Instant::now(),
+                                       })),
+        }
+    }
+
+    fn available(&self, now: Instant) -> u64 {
+        let mut bucket = lock(&self.inner);
+        bucket.refill(now);
+        bucket.tokens
+    }
+
+    fn consume(&self, n: u64) {
+        let mut bucket = lock(&self.inner);
+        if n > bucket.tokens {
+            bucket.debt = bucket.debt.saturating_add(n - bucket.tokens);
+            bucket.tokens = 0;
+        } else {
+            bucket.tokens -= n;
+        }
+    }
+
+    fn wait(&self, now: Instant) -> Duration {
+        self.wait_for(MIN_BURST, now)
+    }
+
+    // How long from `now` until the debt is paid off and there are `wanted` tokens, or as many as
+    // the bucket holds if fewer.
+    fn wait_for(&self, wanted: u64, now: Instant) -> Duration {
+        let mut bucket = lock(&self.inner);
+        bucket.refill(now);
+        let wanted = cmp::min(wanted, bucket.rate);
+        if bucket.tokens >= wanted {
+            return Duration::from_millis(0);
+        }
+        let missing = (wanted - bucket.tokens).saturating_add(bucket.debt);
+        Duration::from_millis((missing * 1000 + bucket.rate - 1) / bucket.rate)
+    }
+
+    // Refill the bucket as though `by` had passed, rather than waiting for it to.
+    #[cfg(test)]
+    fn age(&self, by: Duration) {
+        lock(&self.inner).refilled_at -= by;
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        if now <= self.refilled_at {
+            return;
+        }
+        let elapsed = now - self.refilled_at;
+        let elapsed_ms = elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64;
+        let new_tokens = self.rate.saturating_mul(elapsed_ms) / 1000;
+        if new_tokens == 0 {
+            return;
+        }
+        let paid = cmp::min(new_tokens, self.debt);
+        self.debt -= paid;
+        self.tokens = cmp::min(self.tokens.saturating_add(new_tokens - paid), self.rate);
+        self.refilled_at = now;
+    }
+}
+
+/// The buckets which the traffic of one socket is drawn from, e.g. its own and one shared by all
+/// connections. A socket without buckets is not limited.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimit {
+    up: Vec<TokenBucket>,
+    down: Vec<TokenBucket>,
+}
+
+impl RateLimit {
+    pub fn new(up: Vec<TokenBucket>, down: Vec<TokenBucket>) -> RateLimit {
+        RateLimit { up: up, down: down }
+    }
+
+    /// How many of `max` bytes may be sent now.
+    pub fn up_allowance(&self, max: usize) -> usize {
+        allowance(&self.up, max, Instant::now())
+    }
+
+    /// How many of `max` bytes may be received now.
+    pub fn down_allowance(&self, max: usize) -> usize {
+        allowance(&self.down, max, Instant::now())
+    }
+
+    pub fn consume_up(&self, n: usize) {
+        for bucket in &self.up {
+            bucket.consume(n as u64);
+        }
+    }
+
+    pub fn consume_down(&self, n: usize) {
+        for bucket in &self.down {
+            bucket.consume(n as u64);
+        }
+    }
+
+    /// How long until sending is worth trying again.
+    pub fn up_wait(&self) -> Duration {
+        wait(&self.up, Instant::now())
+    }
+
+    /// How long until receiving is worth trying again.
+    pub fn down_wait(&self) -> Duration {
+        wait(&self.down, Instant::now())
+    }
+
+    #[cfg(test)]
+    fn age(&self, by: Duration) {
+        for bucket in self.up.iter().chain(&self.down) {
+            bucket.age(by);
+        }
+    }
+}
+
+fn allowance(buckets: &[TokenBucket], max: usize, now: Instant) -> usize {
+    buckets
+        .iter()
+        .map(|bucket| bucket.available(now))
+        .fold(max as u64, cmp::min) as usize
+}
+
+fn wait(buckets: &[TokenBucket], now: Instant) -> Duration {
+    buckets
+        .iter()
+        .map(|bucket| bucket.wait(now))
+        .fold(Duration::from_millis(0), cmp::max)
+}
+
+/// What to do about a peer that sends us more than its quota.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QuotaPolicy {
+    /// Stop reading from the peer until it is within its quota again.
+    Throttle,
+    /// Drop the connection.
+    Disconnect,
+}
+
+impl Default for QuotaPolicy {
+    fn default() -> QuotaPolicy {
+        QuotaPolicy::Throttle
+    }
+}
+
+/// How many messages, and bytes of them, one peer may send us each second.
+#[derive(Clone, Debug)]
+pub struct PeerQuota {
+    msgs: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    pub policy: QuotaPolicy,
+}
+
+impl PeerQuota {
+    /// How long until the peer is within its quota again, if it is over it now.
+    pub fn exceeded_for(&self) -> Option<Duration> {
+        self.exceeded_at(Instant::now())
+    }
+
+    fn exceeded_at(&self, now: Instant) -> Option<Duration> {
+        let msgs = self.msgs.iter().filter(|bucket| bucket.available(now) == 0);
+        let bytes = self.bytes.iter().filter(|bucket| bucket.available(now) == 0);
+        msgs.map(|bucket| bucket.wait_for(1, now))
+            .chain(bytes.map(|bucket| bucket.wait_for(MIN_BURST, now)))
+            .max()
+    }
+
+    /// Count a message of `bytes` received from the peer.
+    pub fn consume(&self, bytes: usize) {
+        for bucket in &self.msgs {
+            bucket.consume(1);
+        }
+        for bucket in &self.bytes {
+            bucket.consume(bytes as u64);
+        }
+    }
+}
+
+/// Bandwidth limits in bytes per second, each optional: per connection and for all connections
+/// together, separately for sending and receiving. Clones share the limits for all connections.
+/// Along with them go the quotas on what each peer may send us, and the caps on what we queue
+/// for sending.
+#[derive(Clone, Debug, Default)]
+pub struct BandwidthLimits {
+    conn_up: Option<u64>,
+    conn_down: Option<u64>,
+    total_up: Option<TokenBucket>,
+    total_down: Option<TokenBucket>,
+    peer_msgs: Option<u64>,
+    peer_bytes: Option<u64>,
+    quota_policy: QuotaPolicy,
+    queue_limits: QueueLimits,
+}
+
+impl BandwidthLimits {
+    pub fn new(conn_up: Option<u64>,
+               conn_down: Option<u64>,
+               total_up: Option<u64>,
+               total_down: Option<u64>)
+               -> BandwidthLimits {
+        BandwidthLimits {
+            conn_up: conn_up,
+            conn_down: conn_down,
+            total_up: total_up.map(TokenBucket::new),
+            total_down: total_down.map(TokenBucket::new),
+            peer_msgs: None,
+            peer_bytes: None,
+            quota_policy: QuotaPolicy::default(),
+            queue_limits: QueueLimits::default(),
+        }
+    }
+
+    /// Limit each peer to sending us `msgs` messages and `bytes` bytes a second, dealing with
+    /// those that exceed either as `policy` says.
+    pub fn with_peer_quota(mut self,
+                           msgs: Option<u64>,
+                           bytes: Option<u64>,
+                           policy: QuotaPolicy)
+                           -> BandwidthLimits {
+        self.peer_msgs = msgs;
+        self.peer_bytes = bytes;
+        self.quota_policy = policy;
+        self
+    }
+
+    /// Cap the bytes queued for sending on each connection at `per_connection` and on all of them
+    /// together at `total`, dealing with messages that would go over as `policy` says.
+    pub fn with_queue_limits(mut self,
+                             per_connection: Option<usize>,
+                             total: Option<usize>,
+                             policy: QueueFullPolicy)
+                             -> BandwidthLimits {
+        self.queue_limits = QueueLimits::new(per_connection, total, policy);
+        self
+    }
+
+    /// Consider peers slow once data has waited `after` to be sent to them, disconnecting them
+    /// if `disconnect`.
+    pub fn with_slow_peers(mut self,
+                           after: Option<Duration>,
+                           disconnect: bool)
+                           -> BandwidthLimits {
+        self.queue_limits = self.queue_limits.with_slow_peers(after, disconnect);
+        self
+    }
+
+    /// The caps on queued bytes, shared by all connections.
+    pub fn queue_limits(&self) -> &QueueLimits {
+        &self.queue_limits
+    }
+
+    /// The quota for a new connection, if there is one.
+    pub fn quota_for_connection(&self) -> Option<PeerQuota> {
+        if self.peer_msgs.is_none() && self.peer_bytes.is_none() {
+            return None;
+        }
+        Some(PeerQuota {
+                 msgs: self.peer_msgs.map(TokenBucket::new),
+                 bytes: self.peer_bytes.map(TokenBucket::new),
+                 policy: self.quota_policy,
+             })
+    }
+
+    /// The rate limit for a new connection.
+    pub fn for_connection(&self) -> RateLimit {
+        let up = self.conn_up
+            .map(TokenBucket::new)
+            .into_iter()
+            .chain(self.total_up.clone())
+            .collect();
+        let down = self.conn_down
+            .map(TokenBucket::new)
+            .into_iter()
+            .chain(self.total_down.clone())
+            .collect();
+        RateLimit::new(up, down)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn shared_and_own_buckets() {
+        let limits = BandwidthLimits::new(Some(100_000), None, Some(150_000), None);
+        let first = limits.for_connection();
+        let second = limits.for_connection();
+
+        // Receiving is unlimited
+        assert_eq!(first.down_allowance(1 << 20), 1 << 20);
+        assert_eq!(first.down_wait(), Duration::from_millis(0));
+
+        // Each connection gets its own 100k, but both together only 150k
+        assert_eq!(first.up_allowance(1 << 20), 100_000);
+        first.consume_up(100_000);
+        assert_eq!(first.up_allowance(1 << 20), 0);
+        assert!(first.up_wait() > Duration::from_millis(0));
+        assert_eq!(second.up_allowance(1 << 20), 50_000);
+        second.consume_up(50_000);
+
+        // A tenth of a second refills the shared bucket by 15k, and the own one by more
+        second.age(Duration::from_millis(100));
+        let allowance = second.up_allowance(1 << 20);
+        assert!(allowance >= 15_000 && allowance < 30_000, "{}", allowance);
+    }
+
+    #[test]
+    fn peer_quota() {
+        let limits = BandwidthLimits::default();
+        assert!(limits.quota_for_connection().is_none());
+
+        let limits = limits.with_peer_quota(Some(2), Some(100), QuotaPolicy::Disconnect);
+        let quota = unwrap!(limits.quota_for_connection());
+        assert_eq!(quota.policy, QuotaPolicy::Disconnect);
+        assert_eq!(quota.exceeded_for(), None);
+        quota.consume(40);
+        assert_eq!(quota.exceeded_for(), None);
+        quota.consume(40);
+        assert!(quota.exceeded_for().is_some());
+
+        // Too many bytes, in few enough messages
+        let quota = unwrap!(limits.quota_for_connection());
+        quota.consume(100);
+        assert!(quota.exceeded_for().is_some());
+    }
+
+    #[test]
+    fn peer_quota_carries_debt() {
+        let limits = BandwidthLimits::default().with_peer_quota(None,
+                                                                 Some(100_000),
+                                                                 QuotaPolicy::Throttle);
+        let quota = unwrap!(limits.quota_for_connection());
+        let start = Instant::now();
+        // Twenty seconds' worth in one message
+        quota.consume(2_100_000);
+        let wait = unwrap!(quota.exceeded_at(start));
+        assert!(wait >= Duration::from_secs(19) && wait <= Duration::from_secs(21),
+                "{:?}",
+                wait);
+
+        // Paying off the debt does not fill the bucket
+        let later = start + Duration::from_millis(100);
+        assert!(quota.exceeded_at(later).is_some());
+        let later = start + Duration::from_secs(19);
+        assert!(quota.exceeded_at(later).is_some());
+        let later = start + Duration::from_secs(21);
+        assert_eq!(quota.exceeded_at(later), None);
+    }
+}
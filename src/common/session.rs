@@ -0,0 +1,226 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use byteorder::{ByteOrder, LittleEndian};
+use rust_sodium::crypto::box_::{self, Nonce, PublicKey, SecretKey};
+use rust_sodium::crypto::hash::sha256;
+use rust_sodium::crypto::secretbox;
+
+// Keeps session keys apart from anything else derived from the same key pairs.
+const SESSION_CONTEXT: &'static [u8] = b"crust-session-key";
+/// Most bytes sealed into one record, so that a large message is opened, and can be streamed,
+/// a record at a time rather than only once all of it is in.
+pub const MAX_SEGMENT: usize = 16 * 1024;
+/// Most bytes a record of `MAX_SEGMENT` bytes comes to sealed, with room for its envelope.
+pub const MAX_RECORD: usize = MAX_SEGMENT + secretbox::MACBYTES + 64;
+
+/// Our half of the key exchange that starts every connection. Each side boxes the public half of
+/// a key pair made for the connection from its `PeerId` key to the peer's, which proves to the
+/// peer that it holds the secret half of its `PeerId`. The session key is derived from both the
+/// key pairs made for the connection and both `PeerId` keys, so only the two peers can work it
+/// out, and traffic of the connection stays private should the `PeerId` keys later leak.
+pub struct KeyExchange {
+    our_pk: PublicKey,
+    our_sk: SecretKey,
+}
+
+impl KeyExchange {
+    /// A fresh key pair, for one connection only.
+    pub fn new() -> Self {
+        let (our_pk, our_sk) = box_::gen_keypair();
+        KeyExchange {
+            our_pk: our_pk,
+            our_sk: our_sk,
+        }
+    }
+
+    /// What to send the peer with `PeerId` key `their_id`, from us with `our_id` and `our_sk`.
+    pub fn offer(&self,
+                 our_id: &PublicKey,
+                 our_sk: &SecretKey,
+                 their_id: &PublicKey)
+                 -> (Nonce, Vec<u8>) {
+        let mut plain = Vec::with_capacity(2 * box_::PUBLICKEYBYTES);
+        plain.extend_from_slice(&self.our_pk.0);
+        plain.extend_from_slice(&our_id.0);
+        let nonce = box_::gen_nonce();
+        let sealed = box_::seal(&plain, &nonce, their_id, our_sk);
+        (nonce, sealed)
+    }
+
+    /// The session agreed, with `our_sk`, with the peer with `PeerId` key `their_id` from what it
+    /// sent us, unless that was not boxed by the holder of the key or is our own offer played back
+    /// to us.
+    pub fn finish(self,
+                  our_sk: &SecretKey,
+                  their_id: &PublicKey,
+                  nonce: &Nonce,
+                  sealed: &[u8])
+                  -> Option<Session> {
+        let plain = match box_::open(sealed, nonce, their_id, our_sk) {
+            Ok(plain) => plain,
+            Err(()) => return None,
+        };
+        if plain.len() != 2 * box_::PUBLICKEYBYTES ||
+           &plain[box_::PUBLICKEYBYTES..] != &their_id.0[..] {
+            return None;
+        }
+        let their_pk = match PublicKey::from_slice(&plain[..box_::PUBLICKEYBYTES]) {
+            Some(their_pk) if their_pk != self.our_pk => their_pk,
+            _ => return None,
+        };
+
+        let ephemeral = box_::precompute(&their_pk, &self.our_sk);
+        let long_term = box_::precompute(their_id, our_sk);
+        let (first, second) = if self.our_pk < their_pk {
+            (&self.our_pk, &their_pk)
+        } else {
+            (&their_pk, &self.our_pk)
+        };
+        let mut data = Vec::with_capacity(SESSION_CONTEXT.len() + 4 * box_::PUBLICKEYBYTES);
+        data.extend_from_slice(SESSION_CONTEXT);
+        data.extend_from_slice(&ephemeral.0);
+        data.extend_from_slice(&long_term.0);
+        data.extend_from_slice(&first.0);
+        data.extend_from_slice(&second.0);
+        let key = secretbox::Key(sha256::hash(&data).0);
+
+        Some(Session {
+                 key: key,
+                 first: self.our_pk < their_pk,
+                 path: 0,
+                 sent: 0,
+                 received: 0,
+             })
+    }
+}
+
+/// The key agreed for a connection, along with how many records went each way on its path. Each
+/// record is sealed with the next nonce in its direction, so records dropped, replayed, reordered
+/// or sent back the way they came fail to open.
+#[derive(Clone)]
+pub struct Session {
+    key: secretbox::Key,
+    // Whether our nonces are those marked as the first side's.
+    first: bool,
+    path: u32,
+    sent: u64,
+    received: u64,
+}
+
+impl Session {
+    /// The session for the `path`th path of the connection, e.g. one it migrates onto, whose
+    /// nonces are apart from those of every other path. Both sides have to count paths alike.
+    pub fn for_path(&self, path: u32) -> Session {
+        Session {
+            key: self.key.clone(),
+            first: self.first,
+            path: path,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    /// Seal the next record to the peer.
+    pub fn seal(&mut self, plain: &[u8]) -> Vec<u8> {
+        let nonce = self.nonce(self.first, self.sent);
+        self.sent += 1;
+        secretbox::seal(plain, &nonce, &self.key)
+    }
+
+    /// Open the next record from the peer, unless it is not the one due.
+    pub fn open(&mut self, sealed: &[u8]) -> Option<Vec<u8>> {
+        let nonce = self.nonce(!self.first, self.received);
+        let plain = secretbox::open(sealed, &nonce, &self.key).ok()?;
+        self.received += 1;
+        Some(plain)
+    }
+
+    fn nonce(&self, first: bool, count: u64) -> secretbox::Nonce {
+        let mut nonce = [0; secretbox::NONCEBYTES];
+        nonce[0] = if first { 0 } else { 1 };
+        LittleEndian::write_u32(&mut nonce[4..8], self.path);
+        LittleEndian::write_u64(&mut nonce[8..16], count);
+        secretbox::Nonce(nonce)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_sodium::crypto::box_;
+
+    // Each side's half of the exchange between `ours` and `theirs`, finished.
+    fn exchange(ours: &(PublicKey, SecretKey),
+                theirs: &(PublicKey, SecretKey))
+                -> (Option<Session>, Option<Session>) {
+        let our_half = KeyExchange::new();
+        let their_half = KeyExchange::new();
+        let (our_nonce, our_offer) = our_half.offer(&ours.0, &ours.1, &theirs.0);
+        let (their_nonce, their_offer) = their_half.offer(&theirs.0, &theirs.1, &ours.0);
+        (our_half.finish(&ours.1, &theirs.0, &their_nonce, &their_offer),
+         their_half.finish(&theirs.1, &ours.0, &our_nonce, &our_offer))
+    }
+
+    #[test]
+    fn key_exchange() {
+        let ours = box_::gen_keypair();
+        let theirs = box_::gen_keypair();
+        let (ours_done, theirs_done) = exchange(&ours, &theirs);
+        let mut our_session = unwrap!(ours_done);
+        let mut their_session = unwrap!(theirs_done);
+
+        let first = our_session.seal(b"first");
+        let second = our_session.seal(b"second");
+        // Out of order, or back the way it came
+        assert!(their_session.open(&second).is_none());
+        assert!(our_session.for_path(0).open(&first).is_none());
+        assert_eq!(unwrap!(their_session.open(&first)), b"first");
+        assert_eq!(unwrap!(their_session.open(&second)), b"second");
+        // Replayed
+        assert!(their_session.open(&second).is_none());
+
+        // Onto another path
+        let sealed = our_session.for_path(1).seal(b"migrated");
+        assert!(their_session.for_path(0).open(&sealed).is_none());
+        assert_eq!(unwrap!(their_session.for_path(1).open(&sealed)), b"migrated");
+        let reply = their_session.seal(b"reply");
+        assert_eq!(unwrap!(our_session.open(&reply)), b"reply");
+    }
+
+    #[test]
+    fn impostors() {
+        let ours = box_::gen_keypair();
+        let theirs = box_::gen_keypair();
+        let other = box_::gen_keypair();
+
+        // Someone without the key they claim to hold
+        let half = KeyExchange::new();
+        let (nonce, offer) = KeyExchange::new().offer(&theirs.0, &other.1, &ours.0);
+        assert!(half.finish(&ours.1, &theirs.0, &nonce, &offer).is_none());
+
+        // Our own offer played back to us
+        let half = KeyExchange::new();
+        let (nonce, offer) = half.offer(&ours.0, &ours.1, &theirs.0);
+        assert!(half.finish(&ours.1, &theirs.0, &nonce, &offer).is_none());
+
+        // An offer made to someone else
+        let half = KeyExchange::new();
+        let (nonce, offer) = KeyExchange::new().offer(&theirs.0, &theirs.1, &other.0);
+        assert!(half.finish(&ours.1, &theirs.0, &nonce, &offer).is_none());
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright 2017 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+// Spreading connections over several event loops, each a shard with tokens of its own.
+
+use common::{CoreMessage, Result};
+use mio::Token;
+use mio::channel::Sender;
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// The top bits of a token, short of the very top one which mio reserves, name its shard.
+const SHARD_BITS: usize = 7;
+
+/// Most event loops connections can be spread over.
+pub const MAX_SHARDS: usize = 1 << SHARD_BITS;
+
+fn shard_shift() -> usize {
+    mem::size_of::<usize>() * 8 - SHARD_BITS - 1
+}
+
+/// The first token of the shard at `index`, to start its event loop at.
+pub fn shard_token_start(index: usize) -> usize {
+    index << shard_shift()
+}
+
+/// The index of the shard whose event loop `token` belongs to.
+pub fn shard_of(token: Token) -> usize {
+    token.0 >> shard_shift()
+}
+
+/// The event loops of a service, as seen from one of them, along with how many connections each
+/// holds.
+#[derive(Clone)]
+pub struct Shards {
+    index: usize,
+    txs: Vec<Sender<CoreMessage>>,
+    loads: Arc<Vec<AtomicUsize>>,
+}
+
+impl Shards {
+    /// A view of the event loops behind `txs` for each of them in turn.
+    pub fn new(txs: Vec<Sender<CoreMessage>>) -> Vec<Shards> {
+        let loads: Arc<Vec<_>> = Arc::new((0..txs.len()).map(|_| AtomicUsize::new(0)).collect());
+        (0..txs.len())
+            .map(|index| {
+                     Shards {
+                         index: index,
+                         txs: txs.clone(),
+                         loads: loads.clone(),
+                     }
+                 })
+            .collect()
+    }
+
+    /// Which shard this is.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// How many shards there are, this one included.
+    pub fn count(&self) -> usize {
+        self.txs.len()
+    }
+
+    /// The other shard holding the fewest connections, so long as that is no more than this one
+    /// holds.
+    pub fn least_loaded(&self) -> Option<usize> {
+        let load = |index: usize| self.loads[index].load(Ordering::Relaxed);
+        (0..self.txs.len())
+            .filter(|&index| index != self.index)
+            .min_by_key(|&index| load(index))
+            .and_then(|least| if load(least) <= load(self.index) {
+                          Some(least)
+                      } else {
+                          None
+                      })
+    }
+
+    /// Run `msg` on the event loop of the shard at `index`.
+    pub fn send(&self, index: usize, msg: CoreMessage) -> Result<()> {
+        Ok(self.txs[index].send(msg)?)
+    }
+
+    /// Count a connection opened on this shard.
+    pub fn opened(&self) {
+        let _ = self.loads[self.index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a connection closed on this shard.
+    pub fn closed(&self) {
+        let _ = self.loads[self.index].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio::channel;
+
+    #[test]
+    fn least_loaded() {
+        let txs = (0..3).map(|_| channel::channel().0).collect();
+        let shards = Shards::new(txs);
+        assert_eq!(shards[0].least_loaded(), Some(1));
+
+        shards[0].opened();
+        shards[1].opened();
+        assert_eq!(shards[0].least_loaded(), Some(2));
+        assert_eq!(shards[2].least_loaded(), None);
+
+        shards[1].closed();
+        assert_eq!(shards[0].least_loaded(), Some(1));
+        assert_eq!(shard_of(Token(shard_token_start(2) + 5)), 2);
+        assert_eq!(shard_of(Token(3)), 0);
+    }
+}
@@ -16,20 +16,274 @@
 // relating to use of the SAFE Network Software.
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use common::{CommonError, MAX_PAYLOAD_SIZE, MSG_DROP_PRIORITY, Priority, Result};
-use maidsafe_utilities::serialisation::{deserialise_from, serialise_into};
+use common::{BufferPool, CommonError, Decode, Device, DscpLanes, MAX_PAYLOAD_SIZE, MAX_RECORD,
+             MAX_SEGMENT, MSG_DROP_PRIORITY, Markable, Message, Priority, RateLimit, Result,
+             Session, Stream, Transport, mark_dscp};
+use maidsafe_utilities::serialisation::{serialise, serialise_into};
 use mio::{Evented, Poll, PollOpt, Ready, Token};
 use mio::tcp::TcpStream;
-use serde::de::Deserialize;
 use serde::ser::Serialize;
+use std::cmp;
 use std::collections::{BTreeMap, VecDeque};
-use std::io::{self, Cursor, ErrorKind, Read, Write};
+use std::io::{self, Cursor, ErrorKind};
 use std::mem;
 use std::net::SocketAddr;
-use std::time::Instant;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-/// Maximum age of a message waiting to be sent. If a message is older, the queue is dropped.
-const MAX_MSG_AGE_SECS: u64 = 60;
+/// Default maximum age of a droppable message waiting to be sent. If a message is older, its
+/// queue is dropped.
+pub const MAX_MSG_AGE_SECS: u64 = 60;
+
+/// How a socket sheds its backlog of droppable messages (those with a priority of at least
+/// `MSG_DROP_PRIORITY`) when bandwidth is insufficient.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DropPolicy {
+    /// Once the oldest message of a priority has waited this many seconds, the queues of that and
+    /// all lower priorities are dropped.
+    pub max_age_secs: u64,
+    /// Most messages to queue per priority, if limited.
+    pub max_backlog: Option<usize>,
+    /// Whether a full queue refuses new messages rather than making room by dropping its oldest.
+    pub drop_newest: bool,
+}
+
+impl Default for DropPolicy {
+    fn default() -> DropPolicy {
+        DropPolicy {
+            max_age_secs: MAX_MSG_AGE_SECS,
+            max_backlog: None,
+            drop_newest: false,
+        }
+    }
+}
+
+/// What to do about a message that would take the data queued for sending over a cap, see
+/// `Config::max_queued_bytes_per_peer` and `Config::max_queued_bytes`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QueueFullPolicy {
+    /// Refuse the message. While all connections together are over their cap, `Service::send`
+    /// refuses messages too, so that senders back off.
+    Refuse,
+    /// Make room by dropping queued messages of lower priority than it, as long as those are
+    /// droppable (have a priority of at least `MSG_DROP_PRIORITY`), refusing it if that is not
+    /// enough.
+    DropLowerPriority,
+    /// Drop the connection.
+    Disconnect,
+    /// Have `Service::send` and `Service::send_tracked` wait while all connections together are
+    /// over their cap, failing only if the queues do not drain within a while. Callers which must
+    /// not block, such as those of `AsyncService`, should use another policy. A message over the
+    /// cap of its own connection is refused, as under `Refuse`.
+    Block,
+}
+
+impl Default for QueueFullPolicy {
+    fn default() -> QueueFullPolicy {
+        QueueFullPolicy::Refuse
+    }
+}
+
+/// Caps on the bytes queued for sending, per connection and for all connections together, and
+/// what to do about messages over them. Along with them goes how long data may wait without any
+/// of it being sent before the peer is considered too slow a reader. Clones share the count for
+/// all connections.
+#[derive(Clone, Debug, Default)]
+pub struct QueueLimits {
+    pub per_connection: Option<usize>,
+    pub total: Option<usize>,
+    pub policy: QueueFullPolicy,
+    pub slow_after: Option<Duration>,
+    pub disconnect_slow: bool,
+    queued: Arc<AtomicUsize>,
+}
+
+impl QueueLimits {
+    pub fn new(per_connection: Option<usize>,
+               total: Option<usize>,
+               policy: QueueFullPolicy)
+               -> QueueLimits {
+        QueueLimits {
+            per_connection: per_connection,
+            total: total,
+            policy: policy,
+            slow_after: None,
+            disconnect_slow: false,
+            queued: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Consider peers slow once data has waited `after` without any of it being sent to them,
+    /// disconnecting them if `disconnect`.
+    pub fn with_slow_peers(mut self, after: Option<Duration>, disconnect: bool) -> QueueLimits {
+        self.slow_after = after;
+        self.disconnect_slow = disconnect;
+        self
+    }
+
+    /// Bytes queued over all connections together.
+    pub fn total_queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Whether all connections together are at their cap.
+    pub fn is_total_full(&self) -> bool {
+        self.total
+            .map_or(false, |total| self.total_queued() >= total)
+    }
+
+    fn fits(&self, queued: usize, len: usize) -> bool {
+        self.per_connection
+            .map_or(true, |max| queued + len <= max) &&
+        self.total
+            .map_or(true, |max| self.total_queued() + len <= max)
+    }
+}
+
+/// Options for sockets, each left at the OS (or, for the message options, crust) default if
+/// `None`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SocketConfig {
+    /// Disable Nagle's algorithm (`TCP_NODELAY`), trading bandwidth for latency.
+    pub nodelay: Option<bool>,
+    /// Idle time in milliseconds before the OS starts probing whether the peer is still there
+    /// (`SO_KEEPALIVE`), `0` disabling the probes.
+    pub keepalive_ms: Option<u32>,
+    /// Size of the send buffer (`SO_SNDBUF`) in bytes.
+    pub send_buffer_size: Option<usize>,
+    /// Size of the receive buffer (`SO_RCVBUF`) in bytes. Set before connecting or listening
+    /// where the OS lets us, as the window scale offered to the peer is picked by it.
+    pub recv_buffer_size: Option<usize>,
+    /// Time to live of outgoing packets (`IP_TTL`, or `IPV6_UNICAST_HOPS` over IPv6).
+    pub ttl: Option<u32>,
+    /// Largest message in bytes to accept from the peer, `MAX_PAYLOAD_SIZE` by default.
+    pub max_message_size: Option<usize>,
+    /// Pass larger messages on in chunks rather than refusing them, where the reader supports it.
+    pub stream_oversized: Option<bool>,
+    /// DSCP codepoint to mark outgoing packets with, for the network to classify them by.
+    pub dscp: Option<u8>,
+    /// Codepoints to mark the messages of each priority with instead, the stream being marked
+    /// anew whenever a message of another lane is written.
+    pub dscp_lanes: DscpLanes,
+    /// Network interface to pin sockets to, for both listening and dialing. Unlike the other
+    /// options this is set before a socket is bound, by whoever creates it.
+    pub device: Option<Device>,
+}
+
+impl SocketConfig {
+    /// Apply the options to a connected or accepted stream.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        if let Some(nodelay) = self.nodelay {
+            stream.set_nodelay(nodelay)?;
+        }
+        if let Some(keepalive_ms) = self.keepalive_ms {
+            let keepalive_ms = if keepalive_ms == 0 {
+                None
+            } else {
+                Some(keepalive_ms)
+            };
+            stream.set_keepalive_ms(keepalive_ms)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            stream.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            stream.set_recv_buffer_size(size)?;
+        }
+        if let Some(ttl) = self.ttl {
+            if stream.local_addr()?.is_ipv6() {
+                // Not every OS lets us at it, and packets then go with its default
+                if let Err(e) = set_hop_limit(stream, ttl) {
+                    debug!("Could not set the hop limit to {}: {}", ttl, e);
+                }
+                // Only means something to the IPv4 traffic of a dual-stack socket
+                let _ = stream.set_ttl(ttl);
+            } else {
+                stream.set_ttl(ttl)?;
+            }
+        }
+        // The network is free to ignore the marking anyway, so the stream goes without should
+        // the OS refuse it
+        if let Some(dscp) = self.dscp {
+            let _ = mark_dscp(stream, stream.local_addr()?.is_ipv6(), dscp);
+        }
+        Ok(())
+    }
+}
+
+/// Set the receive buffer of `socket` to `size` bytes. Unlike setting it once connected, this
+/// allows for it in the window scale offered to the peer, which is only done on connecting, and
+/// for a listening socket on accepting.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios"))]
+pub fn set_recv_buffer_size<S: Markable>(socket: &S, size: usize) -> io::Result<()> {
+    use libc;
+    let size = cmp::min(size, libc::c_int::max_value() as usize) as libc::c_int;
+    set_int_option(socket, libc::SOL_SOCKET, libc::SO_RCVBUF, size)
+}
+
+/// Set the receive buffer of `socket` to `size` bytes, which cannot be done here before it is
+/// connected.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos",
+              target_os = "ios")))]
+pub fn set_recv_buffer_size<S: Markable>(_socket: &S, _size: usize) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other,
+                       "Setting the receive buffer of an unconnected socket is not supported on \
+                        this platform"))
+}
+
+/// Set the hop limit of the IPv6 packets `socket` sends, their counterpart of the time to live.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios"))]
+pub fn set_hop_limit<S: Markable>(socket: &S, hops: u32) -> io::Result<()> {
+    use libc;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const IPV6_UNICAST_HOPS: libc::c_int = 16;
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    const IPV6_UNICAST_HOPS: libc::c_int = 4;
+
+    let hops = cmp::min(hops, libc::c_int::max_value() as u32) as libc::c_int;
+    set_int_option(socket, libc::IPPROTO_IPV6, IPV6_UNICAST_HOPS, hops)
+}
+
+/// Set the hop limit of the IPv6 packets `socket` sends, which cannot be done here.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos",
+              target_os = "ios")))]
+pub fn set_hop_limit<S: Markable>(_socket: &S, _hops: u32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other,
+                       "Setting the IPv6 hop limit is not supported on this platform"))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios"))]
+#[allow(unsafe_code)]
+fn set_int_option<S: Markable>(socket: &S,
+                               level: ::libc::c_int,
+                               name: ::libc::c_int,
+                               value: ::libc::c_int)
+                               -> io::Result<()> {
+    use libc;
+
+    let value_ptr: *const libc::c_int = &value;
+    let res = unsafe {
+        libc::setsockopt(socket.as_raw_fd(),
+                         level,
+                         name,
+                         value_ptr as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// What `Socket::read_chunked` took off the wire.
+pub enum Received<T> {
+    Message(T),
+    /// Part of the payload of a message too large to buffer whole, and whether it is the last.
+    Chunk(Vec<u8>, bool),
+}
 
 pub struct Socket {
     inner: Option<SockInner>,
@@ -41,18 +295,179 @@ impl Socket {
         Ok(Self::wrap(stream))
     }
 
+    /// Dial `addr` over `transport` rather than plain TCP.
+    pub fn dial(transport: &Transport, addr: &SocketAddr) -> Result<Self> {
+        Ok(Self::from_stream(transport.connect(addr)?))
+    }
+
     pub fn wrap(stream: TcpStream) -> Self {
+        Self::from_stream(Box::new(stream))
+    }
+
+    pub fn from_stream(stream: Box<Stream>) -> Self {
         Socket {
             inner: Some(SockInner {
                             stream: stream,
                             read_buffer: Vec::new(),
+                            read_pos: 0,
                             read_len: 0,
+                            buffer_pool: None,
+                            max_message_size: MAX_PAYLOAD_SIZE,
+                            stream_oversized: false,
+                            stream_prefix: None,
+                            streaming: None,
                             write_queue: BTreeMap::new(),
                             current_write: None,
+                            drained_at: Instant::now(),
+                            receipts: Vec::new(),
+                            body_bytes_written: 0,
+                            queue_limits: Default::default(),
+                            queued_bytes: 0,
+                            drop_policy: Default::default(),
+                            rate_limit: Default::default(),
+                            read_throttled: false,
+                            write_throttled: false,
+                            read_progress: false,
+                            dscp_lanes: Default::default(),
+                            dscp: None,
+                            dscp_refused: false,
+                            sealing: Sealing::Off,
+                            sealed_read: Vec::new(),
                         }),
         }
     }
 
+    /// Take the socket off `poll` to carry it to another event loop, which can only be done
+    /// between messages and with nothing waiting to be written. Otherwise it is handed back.
+    pub fn detach(mut self, poll: &Poll) -> ::std::result::Result<Detached, Socket> {
+        let idle = self.inner
+            .as_ref()
+            .map_or(false, |inner| {
+                inner.read_len == 0 && inner.streaming.is_none() &&
+                inner.current_write.is_none() && inner.write_queue.is_empty()
+            });
+        if !idle || poll.deregister(&self).is_err() {
+            return Err(self);
+        }
+
+        let mut inner = unwrap!(self.inner.take());
+        let unread = inner.read_buffer[inner.read_pos..].to_vec();
+        inner.checkin_read_buffer();
+        Ok(Detached {
+               stream: inner.stream,
+               unread: unread,
+               max_message_size: inner.max_message_size,
+               stream_oversized: inner.stream_oversized,
+               stream_prefix: inner.stream_prefix,
+               dscp_lanes: inner.dscp_lanes,
+               dscp: inner.dscp,
+               dscp_refused: inner.dscp_refused,
+               sealing: inner.sealing,
+               sealed_read: inner.sealed_read,
+           })
+    }
+
+    /// Resume a socket detached from another event loop. It still has to be registered.
+    pub fn attach(detached: Detached) -> Self {
+        let mut socket = Self::from_stream(detached.stream);
+        if let Some(inner) = socket.inner.as_mut() {
+            inner.read_buffer = detached.unread;
+            inner.max_message_size = detached.max_message_size;
+            inner.stream_oversized = detached.stream_oversized;
+            inner.stream_prefix = detached.stream_prefix;
+            inner.dscp_lanes = detached.dscp_lanes;
+            inner.dscp = detached.dscp;
+            inner.dscp_refused = detached.dscp_refused;
+            inner.sealing = detached.sealing;
+            inner.sealed_read = detached.sealed_read;
+        }
+        socket
+    }
+
+    pub fn configure(&mut self, config: &SocketConfig) -> Result<()> {
+        let inner = self.inner
+            .as_mut()
+            .ok_or(CommonError::UninitialisedSocket)?;
+        inner.max_message_size = config.max_message_size.unwrap_or(MAX_PAYLOAD_SIZE);
+        inner.stream_oversized = config.stream_oversized.unwrap_or(false);
+        inner.dscp_lanes = config.dscp_lanes;
+        inner.dscp = config.dscp;
+        Ok(inner.stream.configure(config)?)
+    }
+
+    /// Name of the transport the socket runs over.
+    pub fn transport(&self) -> Option<&'static str> {
+        self.inner.as_ref().map(|inner| inner.stream.transport())
+    }
+
+    /// Whether anything queued has yet to be written.
+    pub fn has_pending_writes(&self) -> bool {
+        self.inner
+            .as_ref()
+            .map_or(false, |inner| {
+                inner.current_write.is_some() || !inner.write_queue.is_empty()
+            })
+    }
+
+    pub fn max_message_size(&self) -> usize {
+        self.inner
+            .as_ref()
+            .map_or(MAX_PAYLOAD_SIZE, |inner| inner.max_message_size)
+    }
+
+    // Let `read_chunked` pass messages over the maximum size on in chunks if the socket is
+    // configured to, provided they serialise like `empty` but for the contents of its trailing
+    // `Vec<u8>`. Others are still refused.
+    pub fn stream_oversized<T: Serialize>(&mut self, empty: &T) -> Result<()> {
+        let inner = self.inner
+            .as_mut()
+            .ok_or(CommonError::UninitialisedSocket)?;
+        let mut prefix = serialise(empty)?;
+        let empty_len = serialise(&0u64)?;
+        if !prefix.ends_with(&empty_len) {
+            return Err(CommonError::PayloadSizeProhibitive);
+        }
+        let prefix_len = prefix.len() - empty_len.len();
+        prefix.truncate(prefix_len);
+        inner.stream_prefix = Some(prefix);
+        Ok(())
+    }
+
+    // Seal every frame queued from now on with the session `set_session` is to be given, holding
+    // them back until then. Frames queued before go out as they are, ahead of them.
+    pub fn seal_writes(&mut self) {
+        if let Some(inner) = self.inner.as_mut() {
+            if let Sealing::Off = inner.sealing {
+                inner.sealing = Sealing::Awaiting;
+            }
+        }
+    }
+
+    // Seal what is written with `session` from now on, and open what is read with it, anything
+    // read that it does not open being an error. Only to be done between messages. Frames held
+    // back by `seal_writes` go out on the next write.
+    pub fn set_session(&mut self, session: Session) -> Result<()> {
+        let inner = self.inner
+            .as_mut()
+            .ok_or(CommonError::UninitialisedSocket)?;
+        if inner.read_len != 0 || inner.streaming.is_some() {
+            return Err(CommonError::InvalidMessage("sealing started in the middle of a message"));
+        }
+        // Whatever follows the last message read came sealed
+        let unread = inner.read_buffer[inner.read_pos..].to_vec();
+        inner.release_read_buffer();
+        inner.sealing = Sealing::On(session);
+        inner.sealed_read = unread;
+        inner.open_records()
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        let inner = self.inner
+            .as_ref()
+            .ok_or(CommonError::UninitialisedSocket)?;
+        Ok(inner.stream.local_addr()?)
+    }
+
     pub fn peer_addr(&self) -> Result<SocketAddr> {
         let inner = self.inner
             .as_ref()
@@ -67,6 +482,128 @@ impl Socket {
         Ok(inner.stream.take_error()?)
     }
 
+    pub fn set_drop_policy(&mut self, drop_policy: DropPolicy) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.drop_policy = drop_policy;
+        }
+    }
+
+    pub fn set_rate_limit(&mut self, rate_limit: RateLimit) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.rate_limit = rate_limit;
+        }
+    }
+
+    // Count what is queued towards `queue_limits` from now on, along with what already is.
+    pub fn set_queue_limits(&mut self, queue_limits: QueueLimits) {
+        if let Some(inner) = self.inner.as_mut() {
+            let queued = inner.queued_bytes;
+            inner.unqueue(queued);
+            inner.queue_limits = queue_limits;
+            inner.enqueue(queued);
+        }
+    }
+
+    // Bytes waiting to be written.
+    pub fn queued_bytes(&self) -> usize {
+        self.inner.as_ref().map_or(0, |inner| inner.queued_bytes)
+    }
+
+    // How long data has been waiting to be written without any of it going out, if there is
+    // some. A peer reading steadily through a backlog, or our own upload limit holding it back,
+    // does not count.
+    pub fn write_stall(&self) -> Option<Duration> {
+        self.inner
+            .as_ref()
+            .and_then(|inner| if inner.queued_bytes > 0 {
+                          Some(inner.drained_at.elapsed())
+                      } else {
+                          None
+                      })
+    }
+
+    // How many bytes the frame of `msg`, with a body of `body_len` bytes as `write_with_body`
+    // takes, counts for in the queue.
+    pub fn frame_len<T: Serialize>(msg: &T, body_len: usize) -> ::Res<usize> {
+        Ok(mem::size_of::<u32>() + serialise(msg)?.len() + body_len)
+    }
+
+    // Whether a frame of `len` bytes and `priority` can be queued within the queue limits, making
+    // room for it first if their policy is to drop lower priority messages.
+    pub fn admit(&mut self, len: usize, priority: Priority) -> bool {
+        let inner = match self.inner.as_mut() {
+            Some(inner) => inner,
+            None => return false,
+        };
+        if inner.queue_limits.fits(inner.queued_bytes, len) {
+            return true;
+        }
+        if inner.queue_limits.policy != QueueFullPolicy::DropLowerPriority {
+            return false;
+        }
+        let min_priority = cmp::max(priority.saturating_add(1), MSG_DROP_PRIORITY);
+        while !inner.queue_limits.fits(inner.queued_bytes, len) {
+            let lowest = match inner.write_queue.keys().next_back() {
+                Some(&lowest) if lowest >= min_priority => lowest,
+                _ => return false,
+            };
+            let (frame, empty) = {
+                let queue = unwrap!(inner.write_queue.get_mut(&lowest));
+                (queue.pop_front().map(|(_, frame)| frame), queue.is_empty())
+            };
+            if empty {
+                let _ = inner.write_queue.remove(&lowest);
+            }
+            if let Some(frame) = frame {
+                trace!("Send queue full. Dropping a message with priority {}.", lowest);
+                inner.dropped(frame);
+            }
+        }
+        true
+    }
+
+    // Whether a message of `priority` would be refused, its backlog being full and the drop
+    // policy being to refuse new messages. Writing it anyway fails with `BacklogFull`.
+    pub fn refuses(&self, priority: Priority) -> bool {
+        self.inner
+            .as_ref()
+            .map_or(false, |inner| inner.refuses(priority))
+    }
+
+    // Check receive buffers out of `buffer_pool` rather than allocating them, handing each back
+    // once all the messages in it have been read.
+    pub fn set_buffer_pool(&mut self, buffer_pool: BufferPool) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.buffer_pool = Some(buffer_pool);
+        }
+    }
+
+    // Whether any bytes have been read since this was last asked, even if no message is complete
+    // yet - as happens to large messages received under a tight rate limit.
+    pub fn take_read_progress(&mut self) -> bool {
+        self.inner
+            .as_mut()
+            .map_or(false, |inner| mem::replace(&mut inner.read_progress, false))
+    }
+
+    // If the rate limit held back the last read or write, how long until it is worth retrying.
+    // Nothing else will wake the socket up for it.
+    pub fn throttled_for(&self) -> Option<Duration> {
+        let inner = match self.inner.as_ref() {
+            Some(inner) => inner,
+            None => return None,
+        };
+        let mut wait = None;
+        if inner.read_throttled {
+            wait = Some(inner.rate_limit.down_wait());
+        }
+        if inner.write_throttled {
+            let up_wait = inner.rate_limit.up_wait();
+            wait = Some(wait.map_or(up_wait, |wait| cmp::min(wait, up_wait)));
+        }
+        wait
+    }
+
     // Read message from the socket. Call this from inside the `ready` handler.
     //
     // Returns:
@@ -74,7 +611,17 @@ impl Socket {
     //   - Ok(None):       there is not enough data in the socket. Call `read`
     //                     again in the next invocation of the `ready` handler.
     //   - Err(error):     there was an error reading from the socket.
-    pub fn read<T: Deserialize>(&mut self) -> Result<Option<T>> {
+    pub fn read<T: Decode>(&mut self) -> Result<Option<T>> {
+        match self.read_chunked()? {
+            Some(Received::Message(msg)) => Ok(Some(msg)),
+            Some(Received::Chunk(..)) => Err(CommonError::PayloadSizeProhibitive),
+            None => Ok(None),
+        }
+    }
+
+    // Like `read`, but also passes on oversized messages in chunks, see `stream_oversized`.
+    // Chunks of a message come in order, with nothing else in between.
+    pub fn read_chunked<T: Decode>(&mut self) -> Result<Option<Received<T>>> {
         let inner = self.inner
             .as_mut()
             .ok_or(CommonError::UninitialisedSocket)?;
@@ -96,8 +643,74 @@ impl Socket {
         let inner = self.inner
             .as_mut()
             .ok_or(CommonError::UninitialisedSocket)?;
+        let msg = match msg {
+            Some((msg, priority)) => Some((Frame::new(&msg, None)?, priority)),
+            None => None,
+        };
         inner.write(poll, token, msg)
     }
+
+    // Write a message whose last field is a `Vec<u8>` payload, left empty in `msg` and passed as
+    // `body` instead. The body is sent from where it is rather than being copied into the frame,
+    // and may be shared with whoever else holds it. With a `receipt`, whether the message got
+    // written in full is told by `take_receipts`. Returns as `write` does.
+    pub fn write_with_body<T: Serialize>(&mut self,
+                                         poll: &Poll,
+                                         token: Token,
+                                         msg: T,
+                                         body: Arc<Vec<u8>>,
+                                         priority: Priority,
+                                         receipt: Option<u64>)
+                                         -> ::Res<bool> {
+        let inner = self.inner
+            .as_mut()
+            .ok_or(CommonError::UninitialisedSocket)?;
+        let mut frame = match Frame::new(&msg, Some(body)) {
+            Ok(frame) => frame,
+            Err(e) => {
+                // Never queued, so it is settled here
+                if let Some(receipt) = receipt {
+                    inner.receipts.push((receipt, false));
+                }
+                return Err(From::from(e));
+            }
+        };
+        frame.receipt = receipt;
+        inner.write(poll, token, Some((frame, priority)))
+    }
+
+    // The receipts of messages that have since been written in full (`true`) or dropped
+    // (`false`).
+    pub fn take_receipts(&mut self) -> Vec<(u64, bool)> {
+        self.inner
+            .as_mut()
+            .map_or(Vec::new(), |inner| mem::replace(&mut inner.receipts, Vec::new()))
+    }
+
+    // Bytes of the bodies given to `write_with_body` that have since been written in full.
+    pub fn take_body_bytes_written(&mut self) -> usize {
+        self.inner
+            .as_mut()
+            .map_or(0, |inner| mem::replace(&mut inner.body_bytes_written, 0))
+    }
+
+    // Give up on everything still waiting to be written, e.g. as the connection is closed, its
+    // receipts then being taken as dropped.
+    pub fn abandon_writes(&mut self) {
+        if let Some(inner) = self.inner.as_mut() {
+            let frames = inner
+                .current_write
+                .take()
+                .into_iter()
+                .chain(mem::replace(&mut inner.write_queue, BTreeMap::new())
+                           .into_iter()
+                           .flat_map(|(_, queue)| queue)
+                           .map(|(_, frame)| frame));
+            for frame in frames {
+                inner.dropped(frame);
+            }
+        }
+    }
 }
 
 impl Default for Socket {
@@ -106,6 +719,31 @@ impl Default for Socket {
     }
 }
 
+impl Drop for Socket {
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.checkin_read_buffer();
+            let queued = inner.queued_bytes;
+            inner.unqueue(queued);
+        }
+    }
+}
+
+/// A socket taken off one event loop to be attached to another, along with what had been read
+/// from it and not yet handed out.
+pub struct Detached {
+    stream: Box<Stream>,
+    unread: Vec<u8>,
+    max_message_size: usize,
+    stream_oversized: bool,
+    stream_prefix: Option<Vec<u8>>,
+    dscp_lanes: DscpLanes,
+    dscp: Option<u8>,
+    dscp_refused: bool,
+    sealing: Sealing,
+    sealed_read: Vec<u8>,
+}
+
 impl Evented for Socket {
     fn register(&self,
                 poll: &Poll,
@@ -149,11 +787,50 @@ impl Evented for Socket {
 }
 
 struct SockInner {
-    stream: TcpStream,
+    stream: Box<Stream>,
     read_buffer: Vec<u8>,
+    // Start of the unread data in `read_buffer`.
+    read_pos: usize,
     read_len: usize,
-    write_queue: BTreeMap<Priority, VecDeque<(Instant, Vec<u8>)>>,
-    current_write: Option<Vec<u8>>,
+    buffer_pool: Option<BufferPool>,
+    max_message_size: usize,
+    stream_oversized: bool,
+    // What an oversized message must start with to be streamed.
+    stream_prefix: Option<Vec<u8>>,
+    // Bytes of the payload being streamed still to come.
+    streaming: Option<usize>,
+    write_queue: BTreeMap<Priority, VecDeque<(Instant, Frame)>>,
+    current_write: Option<Frame>,
+    // When bytes were last written, or first queued since the queue was empty.
+    drained_at: Instant,
+    receipts: Vec<(u64, bool)>,
+    // Bytes of the bodies of messages written in full since `take_body_bytes_written`.
+    body_bytes_written: usize,
+    queue_limits: QueueLimits,
+    // Bytes of `write_queue` and `current_write` not yet written.
+    queued_bytes: usize,
+    drop_policy: DropPolicy,
+    rate_limit: RateLimit,
+    read_throttled: bool,
+    write_throttled: bool,
+    // Whether bytes have been read since `take_read_progress` was last called.
+    read_progress: bool,
+    dscp_lanes: DscpLanes,
+    // What the stream is marked with, if known.
+    dscp: Option<u8>,
+    // Whether the stream could not be marked, so that it is not tried for every message.
+    dscp_refused: bool,
+    sealing: Sealing,
+    // Sealed records read but not yet opened into `read_buffer`.
+    sealed_read: Vec<u8>,
+}
+
+// Whether frames are sealed, see `Socket::seal_writes` and `Socket::set_session`.
+enum Sealing {
+    Off,
+    // Frames queued from now on are held back until there is a session to seal them with
+    Awaiting,
+    On(Session),
 }
 
 impl SockInner {
@@ -164,7 +841,7 @@ impl SockInner {
     //   - Ok(None):       there is not enough data in the socket. Call `read`
     //                     again in the next invocation of the `ready` handler.
     //   - Err(error):     there was an error reading from the socket.
-    fn read<T: Deserialize>(&mut self) -> Result<Option<T>> {
+    fn read<T: Decode>(&mut self) -> Result<Option<Received<T>>> {
         if let Some(message) = self.read_from_buffer()? {
             return Ok(Some(message));
         }
@@ -172,10 +849,22 @@ impl SockInner {
         // the mio reading window is max at 64k (64 * 1024)
         let mut buffer = [0; 65536];
         let mut is_something_read = false;
+        self.read_throttled = false;
 
         loop {
-            match self.stream.read(&mut buffer) {
+            let allowance = self.rate_limit.down_allowance(buffer.len());
+            if allowance == 0 {
+                self.read_throttled = true;
+                return if is_something_read {
+                           self.read_from_buffer()
+                       } else {
+                           Ok(None)
+                       };
+            }
+            match self.stream.read(&mut buffer[..allowance]) {
                 Ok(bytes_read) => {
+                    self.rate_limit.consume_down(bytes_read);
+                    self.read_progress |= bytes_read > 0;
                     if bytes_read == 0 {
                         let e = Err(CommonError::ZeroByteRead);
                         if is_something_read {
@@ -187,9 +876,15 @@ impl SockInner {
                             return e;
                         }
                     }
-                    self.read_buffer
-                        .extend_from_slice(&buffer[0..bytes_read]);
+                    self.take_in(&buffer[0..bytes_read])?;
                     is_something_read = true;
+                    // Rather than take in all the peer has sent of an oversized message, hand
+                    // it out as it comes. Streaming readers read until there is nothing left.
+                    if self.streams() {
+                        if let Some(received) = self.read_from_buffer()? {
+                            return Ok(Some(received));
+                        }
+                    }
                 }
                 Err(error) => {
                     return if error.kind() == ErrorKind::WouldBlock ||
@@ -207,33 +902,190 @@ impl SockInner {
         }
     }
 
-    fn read_from_buffer<T: Deserialize>(&mut self) -> Result<Option<T>> {
+    fn read_from_buffer<T: Decode>(&mut self) -> Result<Option<Received<T>>> {
         let u32_size = mem::size_of::<u32>();
 
+        if let Some(remaining) = self.streaming {
+            return Ok(self.next_chunk(remaining));
+        }
+
         if self.read_len == 0 {
-            if self.read_buffer.len() < u32_size {
+            if self.read_buffer.len() - self.read_pos < u32_size {
                 return Ok(None);
             }
 
-            self.read_len = Cursor::new(&self.read_buffer).read_u32::<LittleEndian>()? as usize;
+            let len = Cursor::new(&self.read_buffer[self.read_pos..])
+                .read_u32::<LittleEndian>()? as usize;
 
-            if self.read_len > MAX_PAYLOAD_SIZE {
-                return Err(CommonError::PayloadSizeProhibitive);
+            if len > self.max_message_size {
+                return if self.start_streaming(len)? {
+                           self.read_from_buffer()
+                       } else {
+                           Ok(None)
+                       };
             }
 
-            self.read_buffer = self.read_buffer[u32_size..].to_owned();
+            self.read_len = len;
+
+            self.read_pos += u32_size;
+            // Move a large message into a buffer big enough for all of it up front, rather than
+            // growing the one it started in piecemeal.
+            let missing = self.read_len
+                .saturating_sub(self.read_buffer.len() - self.read_pos);
+            if missing > 0 {
+                self.reserve_read(missing);
+            }
         }
 
-        if self.read_len > self.read_buffer.len() {
+        let end = self.read_pos + self.read_len;
+        if end > self.read_buffer.len() {
             return Ok(None);
         }
 
-        let result = deserialise_from(&mut Cursor::new(&self.read_buffer))?;
+        let result = T::decode(&self.read_buffer[self.read_pos..end])?;
 
-        self.read_buffer = self.read_buffer[self.read_len..].to_owned();
+        self.read_pos = end;
         self.read_len = 0;
+        if self.read_pos == self.read_buffer.len() {
+            self.release_read_buffer();
+        }
+
+        Ok(Some(Received::Message(result)))
+    }
 
-        Ok(Some(result))
+    // Take in `bytes` read off the stream, opening them into `read_buffer` first if they come
+    // sealed.
+    fn take_in(&mut self, bytes: &[u8]) -> Result<()> {
+        if let Sealing::On(_) = self.sealing {
+            self.sealed_read.extend_from_slice(bytes);
+            return self.open_records();
+        }
+        self.reserve_read(bytes.len());
+        self.read_buffer.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    // Open the records read in full, in order, onto the end of `read_buffer`. A record is a frame
+    // of `Message::Sealed` with a segment of what would otherwise have been sent sealed in it.
+    fn open_records(&mut self) -> Result<()> {
+        let u32_size = mem::size_of::<u32>();
+        let mut opened = Vec::new();
+        let mut pos = 0;
+        {
+            let session = match self.sealing {
+                Sealing::On(ref mut session) => session,
+                _ => return Ok(()),
+            };
+            while self.sealed_read.len() - pos >= u32_size {
+                let len = Cursor::new(&self.sealed_read[pos..]).read_u32::<LittleEndian>()? as
+                          usize;
+                if len > MAX_RECORD {
+                    return Err(CommonError::InvalidMessage("unsealed frame"));
+                }
+                let end = pos + u32_size + len;
+                if end > self.sealed_read.len() {
+                    break;
+                }
+                let sealed = match Message::decode(&self.sealed_read[pos + u32_size..end])? {
+                    Message::Sealed(sealed) => sealed,
+                    _ => return Err(CommonError::InvalidMessage("unsealed frame")),
+                };
+                match session.open(&sealed) {
+                    Some(plain) => opened.push(plain),
+                    None => return Err(CommonError::InvalidMessage("record failed to open")),
+                }
+                pos = end;
+            }
+        }
+        let _ = self.sealed_read.drain(..pos);
+        for plain in opened {
+            self.reserve_read(plain.len());
+            self.read_buffer.extend_from_slice(&plain);
+        }
+        Ok(())
+    }
+
+    fn streams(&self) -> bool {
+        self.stream_oversized && self.stream_prefix.is_some()
+    }
+
+    // Start streaming the oversized message of `len` bytes at the head of the buffer, returning
+    // whether enough of it is in to tell if it can be streamed.
+    fn start_streaming(&mut self, len: usize) -> Result<bool> {
+        let u32_size = mem::size_of::<u32>();
+        let u64_size = mem::size_of::<u64>();
+
+        let prefix_len = match self.stream_prefix {
+            Some(ref prefix) if self.stream_oversized => prefix.len(),
+            _ => return Err(CommonError::PayloadSizeProhibitive),
+        };
+        let head_len = u32_size + prefix_len + u64_size;
+        if self.read_buffer.len() - self.read_pos < head_len {
+            return Ok(false);
+        }
+
+        let start = self.read_pos + u32_size;
+        let is_match = match self.stream_prefix {
+            Some(ref prefix) => self.read_buffer[start..].starts_with(prefix),
+            None => false,
+        };
+        let payload_len = Cursor::new(&self.read_buffer[start + prefix_len..])
+            .read_u64::<LittleEndian>()?;
+        let frame_len = payload_len.checked_add((prefix_len + u64_size) as u64);
+        if !is_match || frame_len != Some(len as u64) {
+            return Err(CommonError::PayloadSizeProhibitive);
+        }
+
+        self.read_pos += head_len;
+        self.streaming = Some(payload_len as usize);
+        Ok(true)
+    }
+
+    fn next_chunk<T>(&mut self, remaining: usize) -> Option<Received<T>> {
+        let available = self.read_buffer.len() - self.read_pos;
+        if available == 0 {
+            return None;
+        }
+        let len = cmp::min(available, remaining);
+        let chunk = self.read_buffer[self.read_pos..self.read_pos + len].to_vec();
+        self.read_pos += len;
+        let remaining = remaining - len;
+        self.streaming = if remaining == 0 { None } else { Some(remaining) };
+        if self.read_pos == self.read_buffer.len() {
+            self.release_read_buffer();
+        }
+        Some(Received::Chunk(chunk, remaining == 0))
+    }
+
+    // Make room for `additional` more bytes of unread data, checking a buffer out of the pool if
+    // we hold none or the one we hold is too small.
+    fn reserve_read(&mut self, additional: usize) {
+        if self.read_pos > 0 {
+            let _ = self.read_buffer.drain(..self.read_pos);
+            self.read_pos = 0;
+        }
+        let needed = self.read_buffer.len() + additional;
+        if needed <= self.read_buffer.capacity() {
+            return;
+        }
+        if let Some(ref pool) = self.buffer_pool {
+            let mut buffer = pool.checkout(needed);
+            buffer.extend_from_slice(&self.read_buffer);
+            let old = mem::replace(&mut self.read_buffer, buffer);
+            if old.capacity() > 0 {
+                pool.checkin(old);
+            }
+        }
+    }
+
+    fn release_read_buffer(&mut self) {
+        self.read_pos = 0;
+        let buffer = mem::replace(&mut self.read_buffer, Vec::new());
+        match self.buffer_pool {
+            Some(ref pool) => pool.checkin(buffer),
+            None => self.read_buffer = buffer,
+        }
+        self.read_buffer.clear();
     }
 
     // Write a message to the socket.
@@ -243,71 +1095,120 @@ impl SockInner {
     //   - Ok(false):  the message has been queued, but not yet fully written.
     //                 Write event is already scheduled for next time.
     //   - Err(error): there was an error while writing to the socket.
-    fn write<T: Serialize>(&mut self,
-                           poll: &Poll,
-                           token: Token,
-                           msg: Option<(T, Priority)>)
-                           -> ::Res<bool> {
+    fn write(&mut self,
+             poll: &Poll,
+             token: Token,
+             msg: Option<(Frame, Priority)>)
+             -> ::Res<bool> {
+        let max_age_secs = self.drop_policy.max_age_secs;
         let expired_keys: Vec<u8> = self.write_queue
             .iter()
             .skip_while(|&(&priority, queue)| {
                             priority < MSG_DROP_PRIORITY || // Don't drop high-priority messages.
                 queue.front().map_or(false, |&(ref timestamp, _)| {
-                    timestamp.elapsed().as_secs() <= MAX_MSG_AGE_SECS
+                    timestamp.elapsed().as_secs() <= max_age_secs
                 })
                         })
             .map(|(&priority, _)| priority)
             .collect();
-        let dropped_msgs: usize = expired_keys
-            .iter()
-            .filter_map(|priority| self.write_queue.remove(priority))
-            .map(|queue| queue.len())
-            .fold(0, |s, len| s + len); // TODO: Use `sum` once that's stable.
+        let mut dropped_msgs = 0;
+        for priority in &expired_keys {
+            if let Some(queue) = self.write_queue.remove(priority) {
+                dropped_msgs += queue.len();
+                for (_, frame) in queue {
+                    self.dropped(frame);
+                }
+            }
+        }
         if dropped_msgs > 0 {
             trace!("Insufficient bandwidth. Dropping {} messages with priority >= {}.",
                    dropped_msgs,
                    expired_keys[0]);
         }
 
-        if let Some((msg, priority)) = msg {
-            let mut data = Cursor::new(Vec::with_capacity(mem::size_of::<u32>()));
-
-            let _ = data.write_u32::<LittleEndian>(0);
-
-            serialise_into(&msg, &mut data)?;
-
-            let len = data.position() - mem::size_of::<u32>() as u64;
-            data.set_position(0);
-            data.write_u32::<LittleEndian>(len as u32)?;
-
-            let entry = self.write_queue
-                .entry(priority)
-                .or_insert_with(|| VecDeque::with_capacity(10));
-            entry.push_back((Instant::now(), data.into_inner()));
+        if let Some((mut frame, priority)) = msg {
+            frame.seal = match self.sealing {
+                Sealing::Off => false,
+                Sealing::Awaiting | Sealing::On(_) => true,
+            };
+            if self.refuses(priority) {
+                trace!("Insufficient bandwidth. Backlog of priority {} is full.", priority);
+                // Never queued, so it is settled here
+                if let Some(receipt) = frame.receipt {
+                    self.receipts.push((receipt, false));
+                }
+                return Err(From::from(CommonError::BacklogFull));
+            }
+            self.enqueue(frame.remaining());
+            let dropped = {
+                let entry = self.write_queue
+                    .entry(priority)
+                    .or_insert_with(|| VecDeque::with_capacity(10));
+                match self.drop_policy.max_backlog {
+                    Some(max) if priority >= MSG_DROP_PRIORITY &&
+                                 entry.len() >= cmp::max(max, 1) => {
+                        trace!("Insufficient bandwidth. Backlog of priority {} is full.",
+                               priority);
+                        let oldest = entry.pop_front().map(|(_, frame)| frame);
+                        entry.push_back((Instant::now(), frame));
+                        oldest
+                    }
+                    _ => {
+                        entry.push_back((Instant::now(), frame));
+                        None
+                    }
+                }
+            };
+            if let Some(frame) = dropped {
+                self.dropped(frame);
+            }
         }
 
         if self.current_write.is_none() {
-            let (key, (_time_stamp, data), empty) = match self.write_queue.iter_mut().next() {
-                Some((key, queue)) => (*key, unwrap!(queue.pop_front()), queue.is_empty()),
-                None => return Ok(true),
-            };
-            if empty {
-                let _ = self.write_queue.remove(&key);
+            match self.next_frame() {
+                Some((priority, frame)) => {
+                    if let Some(dscp) = self.dscp_lanes.of(priority) {
+                        self.mark(dscp);
+                    }
+                    let frame = self.sealed(frame)?;
+                    self.current_write = Some(frame);
+                }
+                None if self.write_queue.is_empty() => return Ok(true),
+                // Held back until there is a session to seal it with
+                None => (),
             }
-            self.current_write = Some(data);
         }
 
-        if let Some(data) = self.current_write.take() {
-            match self.stream.write(&data) {
+        self.write_throttled = false;
+        if let Some(mut frame) = self.current_write.take() {
+            let allowance = self.rate_limit.up_allowance(frame.remaining());
+            let res = if allowance == 0 {
+                self.write_throttled = true;
+                self.drained_at = Instant::now();
+                Err(io::Error::new(ErrorKind::WouldBlock, "Rate limited"))
+            } else {
+                frame.write_to(&mut *self.stream, allowance)
+            };
+            match res {
                 Ok(bytes_txd) => {
-                    if bytes_txd < data.len() {
-                        self.current_write = Some(data[bytes_txd..].to_owned());
+                    if bytes_txd > 0 {
+                        self.drained_at = Instant::now();
+                    }
+                    self.rate_limit.consume_up(bytes_txd);
+                    self.unqueue(bytes_txd);
+                    if frame.remaining() > 0 {
+                        self.current_write = Some(frame);
+                    } else {
+                        self.body_bytes_written += frame.body_len;
+                        if let Some(receipt) = frame.receipt {
+                            self.receipts.push((receipt, true));
+                        }
                     }
                 }
                 Err(error) => {
                     if error.kind() == ErrorKind::WouldBlock ||
                        error.kind() == ErrorKind::Interrupted {
-                        self.current_write = Some(data);
+                        self.current_write = Some(frame);
                     } else {
                         return Err(From::from(error));
                     }
@@ -316,8 +1217,11 @@ impl SockInner {
         }
 
         let done = self.current_write.is_none() && self.write_queue.is_empty();
+        let held = self.current_write.is_none() && !done;
 
-        let event_set = if done {
+        // A throttled write is retried once the rate limit allows, not when the socket is writable.
+        // Nor is there anything to write while what is queued is held back.
+        let event_set = if done || held || self.write_throttled {
             Ready::error() | Ready::hup() | Ready::readable()
         } else {
             Ready::error() | Ready::hup() | Ready::readable() | Ready::writable()
@@ -329,6 +1233,122 @@ impl SockInner {
     }
 }
 
+impl SockInner {
+    // The frame to write next and its priority: the first by priority, unless some frame queued
+    // before sealing began is still to go, as those have to go ahead of any sealed one. None if
+    // that is a sealed one and there is no session yet.
+    fn next_frame(&mut self) -> Option<(Priority, Frame)> {
+        let plain_first = match self.sealing {
+            Sealing::Off => None,
+            Sealing::Awaiting | Sealing::On(_) => {
+                self.write_queue
+                    .iter()
+                    .find(|&(_, queue)| queue.front().map_or(false, |&(_, ref frame)| !frame.seal))
+                    .map(|(&priority, _)| priority)
+            }
+        };
+        let priority = match plain_first.or_else(|| self.write_queue.keys().next().cloned()) {
+            Some(priority) => priority,
+            None => return None,
+        };
+        let (frame, empty) = {
+            let queue = unwrap!(self.write_queue.get_mut(&priority));
+            let held = match (queue.front(), &self.sealing) {
+                (Some(&(_, ref frame)), &Sealing::Awaiting) => frame.seal,
+                _ => false,
+            };
+            if held {
+                return None;
+            }
+            (queue.pop_front().map(|(_, frame)| frame), queue.is_empty())
+        };
+        if empty {
+            let _ = self.write_queue.remove(&priority);
+        }
+        frame.map(|frame| (priority, frame))
+    }
+
+    // `frame` as it is to go on the wire: cut into segments, each sealed into a record of its own,
+    // if it is to be sealed.
+    fn sealed(&mut self, frame: Frame) -> Result<Frame> {
+        let res = match self.sealing {
+            Sealing::On(ref mut session) if frame.seal => frame.seal_with(session),
+            _ => return Ok(frame),
+        };
+        let head = match res {
+            Ok(head) => head,
+            Err(e) => {
+                self.dropped(frame);
+                return Err(e);
+            }
+        };
+        let sealed = Frame {
+            head: head,
+            body: None,
+            body_len: frame.body_len,
+            written: 0,
+            receipt: frame.receipt,
+            seal: false,
+        };
+        let grown = sealed.remaining() - frame.remaining();
+        self.enqueue(grown);
+        Ok(sealed)
+    }
+
+    fn enqueue(&mut self, bytes: usize) {
+        if self.queued_bytes == 0 {
+            self.drained_at = Instant::now();
+        }
+        self.queued_bytes += bytes;
+        let _ = self.queue_limits.queued.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn unqueue(&mut self, bytes: usize) {
+        self.queued_bytes -= bytes;
+        let _ = self.queue_limits.queued.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    // Mark the stream for the lane of the message about to be written. Segments of the message
+    // before it may still go out marked for its lane, which the network can live with.
+    fn mark(&mut self, dscp: u8) {
+        if self.dscp == Some(dscp) || self.dscp_refused {
+            return;
+        }
+        match self.stream.set_dscp(dscp) {
+            Ok(()) => self.dscp = Some(dscp),
+            Err(e) => {
+                debug!("Could not mark stream with DSCP {}: {}", dscp, e);
+                self.dscp_refused = true;
+            }
+        }
+    }
+
+    fn refuses(&self, priority: Priority) -> bool {
+        let max = match self.drop_policy.max_backlog {
+            Some(max) if self.drop_policy.drop_newest && priority >= MSG_DROP_PRIORITY => max,
+            _ => return false,
+        };
+        self.write_queue
+            .get(&priority)
+            .map_or(0, |queue| queue.len()) >= cmp::max(max, 1)
+    }
+
+    fn dropped(&mut self, frame: Frame) {
+        self.unqueue(frame.remaining());
+        if let Some(receipt) = frame.receipt {
+            self.receipts.push((receipt, false));
+        }
+    }
+
+    fn checkin_read_buffer(&mut self) {
+        if self.read_buffer.capacity() > 0 {
+            if let Some(ref pool) = self.buffer_pool {
+                pool.checkin(mem::replace(&mut self.read_buffer, Vec::new()));
+            }
+        }
+    }
+}
+
 impl Evented for SockInner {
     fn register(&self,
                 poll: &Poll,
@@ -352,3 +1372,356 @@ impl Evented for SockInner {
         self.stream.deregister(poll)
     }
 }
+
+// A length prefixed message waiting to be written, possibly with its payload kept apart from the
+// rest of it.
+struct Frame {
+    head: Vec<u8>,
+    body: Option<Arc<Vec<u8>>>,
+    // Length of the body it was given, which sealing folds into the head.
+    body_len: usize,
+    written: usize,
+    receipt: Option<u64>,
+    // Whether it is to be sealed before it is written.
+    seal: bool,
+}
+
+impl Frame {
+    fn new<T: Serialize>(msg: &T, body: Option<Arc<Vec<u8>>>) -> Result<Self> {
+        let mut head = Cursor::new(Vec::with_capacity(mem::size_of::<u32>()));
+        let _ = head.write_u32::<LittleEndian>(0);
+        serialise_into(msg, &mut head)?;
+        let mut head = head.into_inner();
+
+        // The empty payload leaves the message ending in a length of zero, which is made the
+        // length of the body instead
+        let body_len = body.as_ref().map_or(0, |body| body.len());
+        if body_len > 0 {
+            let empty_len = serialise(&0u64)?;
+            if !head.ends_with(&empty_len) {
+                return Err(CommonError::NoPayloadForBody);
+            }
+            let len_pos = head.len() - empty_len.len();
+            head.truncate(len_pos);
+            head.extend_from_slice(&serialise(&(body_len as u64))?);
+        }
+
+        let len = head.len() - mem::size_of::<u32>() + body_len;
+        if len > u32::max_value() as usize {
+            return Err(CommonError::PayloadSizeProhibitive);
+        }
+        Cursor::new(&mut head[..]).write_u32::<LittleEndian>(len as u32)?;
+
+        Ok(Frame {
+               head: head,
+               body: body,
+               body_len: body_len,
+               written: 0,
+               receipt: None,
+               seal: false,
+           })
+    }
+
+    // The records the frame comes to sealed with `session`, one for each segment of it.
+    fn seal_with(&self, session: &mut Session) -> Result<Vec<u8>> {
+        let mut plain = self.head.clone();
+        if let Some(ref body) = self.body {
+            plain.extend_from_slice(body);
+        }
+        let records = (plain.len() + MAX_SEGMENT - 1) / MAX_SEGMENT;
+        let mut sealed = Vec::with_capacity(plain.len() + records * (MAX_RECORD - MAX_SEGMENT));
+        for segment in plain.chunks(MAX_SEGMENT) {
+            let record = serialise(&Message::Sealed(session.seal(segment)))?;
+            sealed.write_u32::<LittleEndian>(record.len() as u32)?;
+            sealed.extend_from_slice(&record);
+        }
+        Ok(sealed)
+    }
+
+    fn remaining(&self) -> usize {
+        let (head, body) = self.unwritten();
+        head.len() + body.len()
+    }
+
+    // Write at most `max` bytes of what is left with a single vectored write, returning how many
+    // were written.
+    fn write_to(&mut self, stream: &mut Stream, max: usize) -> io::Result<usize> {
+        let written = {
+            let (head, body) = self.unwritten();
+            let head_len = cmp::min(head.len(), max);
+            let body_len = cmp::min(body.len(), max - head_len);
+            let mut bufs: Vec<&[u8]> = Vec::with_capacity(2);
+            if head_len > 0 {
+                bufs.push(&head[..head_len]);
+            }
+            if body_len > 0 {
+                bufs.push(&body[..body_len]);
+            }
+            stream.write_bufs(&bufs)?
+        };
+        self.written += written;
+        Ok(written)
+    }
+
+    fn unwritten(&self) -> (&[u8], &[u8]) {
+        let body = self.body.as_ref().map_or(&[][..], |body| &body[..]);
+        if self.written < self.head.len() {
+            (&self.head[self.written..], body)
+        } else {
+            (&[][..], &body[self.written - self.head.len()..])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::Message;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn recv_buffer_before_connecting() {
+        use net2::{TcpBuilder, TcpStreamExt};
+
+        let socket = unwrap!(TcpBuilder::new_v4());
+        unwrap!(set_recv_buffer_size(&socket, 96 * 1024));
+        let stream = unwrap!(socket.to_tcp_stream());
+        // Linux doubles it for its own bookkeeping
+        assert!(unwrap!(stream.recv_buffer_size()) >= 96 * 1024);
+
+        let socket = unwrap!(TcpBuilder::new_v6());
+        unwrap!(set_hop_limit(&socket, 7));
+    }
+
+    #[test]
+    fn frame_with_body() {
+        let payload = vec![7; 1000];
+        let copied = unwrap!(Frame::new(&Message::Data(payload.clone()), None));
+        let split = unwrap!(Frame::new(&Message::Data(Vec::new()), Some(Arc::new(payload))));
+        assert_eq!(split.remaining(), copied.remaining());
+
+        let (head, body) = split.unwritten();
+        let mut joined = head.to_vec();
+        joined.extend_from_slice(body);
+        assert_eq!(joined, copied.head);
+
+        // Only a message ending in an empty payload can have the body stand in for it
+        let body = Some(Arc::new(vec![7; 10]));
+        match Frame::new(&Message::ChooseConnection, body) {
+            Err(CommonError::NoPayloadForBody) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|frame| frame.remaining())),
+        }
+    }
+
+    // A socket to a peer that never reads, both ends buffering little so that a megabyte is
+    // enough to hold up its writes.
+    fn stalled_socket() -> (Socket, ::std::net::TcpStream, Poll, Token) {
+        use net2::TcpStreamExt;
+
+        let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        let stream = unwrap!(TcpStream::connect(&unwrap!(listener.local_addr())));
+        unwrap!(stream.set_send_buffer_size(4096));
+        let (peer, _) = unwrap!(listener.accept());
+        unwrap!(peer.set_recv_buffer_size(4096));
+        let socket = Socket::wrap(stream);
+        let poll = unwrap!(Poll::new());
+        let token = Token(0);
+        unwrap!(poll.register(&socket, token, Ready::writable(), PollOpt::edge()));
+        (socket, peer, poll, token)
+    }
+
+    #[test]
+    fn receipts() {
+        let (mut socket, _peer, poll, token) = stalled_socket();
+
+        let small = Arc::new(vec![7; 100]);
+        let mut done = unwrap!(socket.write_with_body(&poll,
+                                                      token,
+                                                      Message::Data(Vec::new()),
+                                                      small,
+                                                      0,
+                                                      Some(1)));
+        while !done {
+            thread::sleep(Duration::from_millis(10));
+            done = unwrap!(socket.write::<Message>(&poll, token, None));
+        }
+        assert_eq!(socket.take_receipts(), vec![(1, true)]);
+        assert_eq!(socket.take_body_bytes_written(), 100);
+        assert_eq!(socket.write_stall(), None);
+
+        // The peer never reads, so these are still queued when given up on
+        let large = Arc::new(vec![7; 1024 * 1024]);
+        for receipt in 2..4 {
+            let _ = unwrap!(socket.write_with_body(&poll,
+                                                   token,
+                                                   Message::Data(Vec::new()),
+                                                   large.clone(),
+                                                   0,
+                                                   Some(receipt)));
+        }
+        assert!(socket.take_receipts().is_empty());
+        thread::sleep(Duration::from_millis(10));
+        assert!(unwrap!(socket.write_stall()) >= Duration::from_millis(10));
+        socket.abandon_writes();
+        assert_eq!(socket.take_receipts(), vec![(2, false), (3, false)]);
+        assert_eq!(socket.take_body_bytes_written(), 0);
+        assert!(!socket.has_pending_writes());
+    }
+
+    #[test]
+    fn queue_limits() {
+        let (mut socket, _peer, poll, token) = stalled_socket();
+
+        // The peer never reads, so this holds up everything queued after it
+        let large = Arc::new(vec![7; 1024 * 1024]);
+        let _ = unwrap!(socket.write_with_body(&poll,
+                                               token,
+                                               Message::Data(Vec::new()),
+                                               large,
+                                               0,
+                                               None));
+        let limits = QueueLimits::new(Some(socket.queued_bytes() + 1000),
+                                      None,
+                                      QueueFullPolicy::DropLowerPriority);
+        socket.set_queue_limits(limits.clone());
+        assert_eq!(limits.total_queued(), socket.queued_bytes());
+
+        let small = Arc::new(vec![7; 400]);
+        let small_len = unwrap!(Socket::frame_len(&Message::Data(Vec::new()), small.len()));
+        for receipt in 0..2 {
+            assert!(socket.admit(small_len, MSG_DROP_PRIORITY + 1));
+            let _ = unwrap!(socket.write_with_body(&poll,
+                                                   token,
+                                                   Message::Data(Vec::new()),
+                                                   small.clone(),
+                                                   MSG_DROP_PRIORITY + 1,
+                                                   Some(receipt)));
+        }
+        // Only lower priority messages make room
+        assert!(!socket.admit(small_len, MSG_DROP_PRIORITY + 1));
+        assert!(socket.admit(small_len, MSG_DROP_PRIORITY));
+        assert_eq!(socket.take_receipts(), vec![(0, false)]);
+        assert_eq!(limits.total_queued(), socket.queued_bytes());
+
+        drop(socket);
+        assert_eq!(limits.total_queued(), 0);
+    }
+
+    #[test]
+    fn drop_policy() {
+        let (mut socket, _peer, poll, token) = stalled_socket();
+
+        // The peer never reads, so this holds up everything queued after it
+        let large = Arc::new(vec![7; 1024 * 1024]);
+        let _ = unwrap!(socket.write_with_body(&poll,
+                                               token,
+                                               Message::Data(Vec::new()),
+                                               large,
+                                               0,
+                                               None));
+        socket.set_drop_policy(DropPolicy {
+                                   max_backlog: Some(2),
+                                   ..Default::default()
+                               });
+
+        // A full backlog makes room by dropping its oldest message
+        let small = Arc::new(vec![7; 100]);
+        for receipt in 0..3 {
+            let _ = unwrap!(socket.write_with_body(&poll,
+                                                   token,
+                                                   Message::Data(Vec::new()),
+                                                   small.clone(),
+                                                   MSG_DROP_PRIORITY,
+                                                   Some(receipt)));
+        }
+        assert_eq!(socket.take_receipts(), vec![(0, false)]);
+
+        // Or refuses the newest, saying so
+        socket.set_drop_policy(DropPolicy {
+                                   max_backlog: Some(2),
+                                   drop_newest: true,
+                                   ..Default::default()
+                               });
+        assert!(socket.refuses(MSG_DROP_PRIORITY));
+        assert!(!socket.refuses(MSG_DROP_PRIORITY + 1));
+        assert!(!socket.refuses(0));
+        let queued = socket.queued_bytes();
+        match socket.write_with_body(&poll,
+                                     token,
+                                     Message::Data(Vec::new()),
+                                     small,
+                                     MSG_DROP_PRIORITY,
+                                     Some(3)) {
+            Err(::CrustError::Common(CommonError::BacklogFull)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert_eq!(socket.take_receipts(), vec![(3, false)]);
+        assert_eq!(socket.queued_bytes(), queued);
+    }
+
+    #[test]
+    fn stream_oversized() {
+        let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        let mut socket = unwrap!(Socket::connect(&unwrap!(listener.local_addr())));
+        let (mut peer, _) = unwrap!(listener.accept());
+        let config = SocketConfig {
+            max_message_size: Some(100),
+            stream_oversized: Some(true),
+            ..Default::default()
+        };
+        unwrap!(socket.configure(&config));
+        unwrap!(socket.stream_oversized(&Message::Data(Vec::new())));
+
+        let payload: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let oversized = unwrap!(Frame::new(&Message::Data(payload.clone()), None));
+        let heartbeat = unwrap!(Frame::new(&Message::Heartbeat, None));
+        unwrap!(peer.write_all(&oversized.head));
+        unwrap!(peer.write_all(&heartbeat.head));
+
+        let mut streamed = Vec::new();
+        loop {
+            match unwrap!(socket.read_chunked::<Message>()) {
+                Some(Received::Chunk(chunk, last)) => {
+                    streamed.extend_from_slice(&chunk);
+                    if last {
+                        break;
+                    }
+                }
+                Some(Received::Message(msg)) => panic!("Unexpected message: {:?}", msg),
+                None => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+        assert_eq!(streamed, payload);
+        loop {
+            match unwrap!(socket.read_chunked::<Message>()) {
+                Some(Received::Message(msg)) => {
+                    assert_eq!(msg, Message::Heartbeat);
+                    break;
+                }
+                Some(Received::Chunk(..)) => panic!("Unexpected chunk"),
+                None => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+
+        // Without streaming, the oversized message is refused
+        let config = SocketConfig {
+            max_message_size: Some(100),
+            ..Default::default()
+        };
+        unwrap!(socket.configure(&config));
+        unwrap!(peer.write_all(&oversized.head));
+        loop {
+            match socket.read::<Message>() {
+                Ok(None) => thread::sleep(Duration::from_millis(10)),
+                Ok(Some(msg)) => panic!("Unexpected message: {:?}", msg),
+                Err(CommonError::PayloadSizeProhibitive) => break,
+                Err(e) => panic!("Unexpected error: {:?}", e),
+            }
+        }
+    }
+}
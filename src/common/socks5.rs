@@ -0,0 +1,282 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{Handshake, Listener, Reply, Stream, Transport, TunnelStream};
+use mio::tcp::TcpListener;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+
+const VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+const METHOD_NONE: u8 = 0;
+const METHOD_PASSWORD: u8 = 2;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Dials peers through a SOCKS5 proxy (RFC 1928), e.g. a corporate egress proxy or Tor, and
+/// listens directly. Hole punching cannot pass through a proxy, so peers the proxy cannot reach
+/// directly are only reached through the relay.
+#[derive(Clone, Debug)]
+pub struct Socks5 {
+    proxy: SocketAddr,
+    auth: Option<(String, String)>,
+}
+
+impl Socks5 {
+    /// Tunnel through the proxy at `proxy`, which has to let us in without authenticating.
+    pub fn new(proxy: SocketAddr) -> Self {
+        Socks5 {
+            proxy: proxy,
+            auth: None,
+        }
+    }
+
+    /// Authenticate to the proxy with `username` and `password` (RFC 1929) if it asks us to.
+    pub fn with_auth(mut self, username: String, password: String) -> Self {
+        self.auth = Some((username, password));
+        self
+    }
+}
+
+impl Transport for Socks5 {
+    fn name(&self) -> &'static str {
+        "socks5"
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Box<Stream>> {
+        if let Some((ref username, ref password)) = self.auth {
+            if username.len() > 255 || password.len() > 255 {
+                return Err(io::Error::new(ErrorKind::InvalidInput,
+                                          "SOCKS5 username or password over 255 bytes"));
+            }
+        }
+        let handshake = Socks5Handshake {
+            target: *addr,
+            auth: self.auth.clone(),
+            phase: Phase::Greeting,
+        };
+        Ok(Box::new(TunnelStream::connect(&self.proxy, addr, self.name(), handshake)?))
+    }
+
+    fn listen(&self, addr: &SocketAddr) -> io::Result<Box<Listener>> {
+        Ok(Box::new(TcpListener::bind(addr)?))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    Greeting,
+    Auth,
+    Connect,
+}
+
+struct Socks5Handshake {
+    target: SocketAddr,
+    auth: Option<(String, String)>,
+    phase: Phase,
+}
+
+impl Socks5Handshake {
+    fn connect_request(&mut self) -> Vec<u8> {
+        let mut request = vec![VERSION, CMD_CONNECT, 0];
+        match self.target {
+            SocketAddr::V4(ref addr) => {
+                request.push(ATYP_IPV4);
+                request.extend_from_slice(&addr.ip().octets());
+            }
+            SocketAddr::V6(ref addr) => {
+                request.push(ATYP_IPV6);
+                request.extend_from_slice(&addr.ip().octets());
+            }
+        }
+        let port = self.target.port();
+        request.extend_from_slice(&[(port >> 8) as u8, port as u8]);
+        self.phase = Phase::Connect;
+        request
+    }
+}
+
+impl Handshake for Socks5Handshake {
+    fn start(&mut self) -> Vec<u8> {
+        if self.auth.is_some() {
+            vec![VERSION, 2, METHOD_NONE, METHOD_PASSWORD]
+        } else {
+            vec![VERSION, 1, METHOD_NONE]
+        }
+    }
+
+    fn reply(&mut self, incoming: &[u8]) -> io::Result<Reply> {
+        match self.phase {
+            Phase::Greeting => {
+                if incoming.len() < 2 {
+                    return Ok(Reply::Incomplete);
+                }
+                if incoming[0] != VERSION {
+                    return Err(invalid_reply());
+                }
+                let auth_request = match (incoming[1], self.auth.as_ref()) {
+                    (METHOD_NONE, _) => None,
+                    (METHOD_PASSWORD, Some(&(ref username, ref password))) => {
+                        let mut request = vec![AUTH_VERSION, username.len() as u8];
+                        request.extend_from_slice(username.as_bytes());
+                        request.push(password.len() as u8);
+                        request.extend_from_slice(password.as_bytes());
+                        Some(request)
+                    }
+                    _ => {
+                        return Err(io::Error::new(ErrorKind::PermissionDenied,
+                                                  "SOCKS5 proxy accepts none of our \
+                                                   authentication methods"))
+                    }
+                };
+                let next = match auth_request {
+                    Some(request) => {
+                        self.phase = Phase::Auth;
+                        request
+                    }
+                    None => self.connect_request(),
+                };
+                Ok(Reply::Continue(2, next))
+            }
+            Phase::Auth => {
+                if incoming.len() < 2 {
+                    return Ok(Reply::Incomplete);
+                }
+                if incoming[1] != 0 {
+                    return Err(io::Error::new(ErrorKind::PermissionDenied,
+                                              "SOCKS5 proxy refused our username and password"));
+                }
+                Ok(Reply::Continue(2, self.connect_request()))
+            }
+            Phase::Connect => {
+                if incoming.len() < 5 {
+                    return Ok(Reply::Incomplete);
+                }
+                if incoming[0] != VERSION {
+                    return Err(invalid_reply());
+                }
+                let addr_len = match incoming[3] {
+                    ATYP_IPV4 => 4,
+                    ATYP_IPV6 => 16,
+                    ATYP_DOMAIN => 1 + incoming[4] as usize,
+                    _ => return Err(invalid_reply()),
+                };
+                let len = 4 + addr_len + 2;
+                if incoming.len() < len {
+                    return Ok(Reply::Incomplete);
+                }
+                if incoming[1] != 0 {
+                    return Err(connect_error(incoming[1]));
+                }
+                Ok(Reply::Done(len))
+            }
+        }
+    }
+}
+
+fn invalid_reply() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "Invalid reply from SOCKS5 proxy")
+}
+
+fn connect_error(reply: u8) -> io::Error {
+    let (kind, reason) = match reply {
+        2 => (ErrorKind::PermissionDenied, "not allowed by its rules"),
+        3 => (ErrorKind::Other, "network unreachable"),
+        4 => (ErrorKind::Other, "host unreachable"),
+        5 => (ErrorKind::ConnectionRefused, "connection refused"),
+        6 => (ErrorKind::TimedOut, "TTL expired"),
+        _ => (ErrorKind::Other, "general failure"),
+    };
+    io::Error::new(kind, format!("SOCKS5 proxy failed to connect: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio::{Events, Poll, PollOpt, Ready, Token};
+    use std::io::{Read, Write};
+    use std::net;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn connect_through_proxy() {
+        let proxy = unwrap!(net::TcpListener::bind("127.0.0.1:0"));
+        let proxy_addr = unwrap!(proxy.local_addr());
+        let target = unwrap!("10.1.2.3:4567".parse());
+
+        let joiner = thread::spawn(move || {
+            let (mut conn, _) = unwrap!(proxy.accept());
+            let mut greeting = [0; 4];
+            unwrap!(conn.read_exact(&mut greeting));
+            assert_eq!(greeting, [VERSION, 2, METHOD_NONE, METHOD_PASSWORD]);
+            unwrap!(conn.write_all(&[VERSION, METHOD_PASSWORD]));
+
+            let mut auth = [0; 11];
+            unwrap!(conn.read_exact(&mut auth));
+            assert_eq!(&auth[..], b"\x01\x04user\x04pass");
+            unwrap!(conn.write_all(&[AUTH_VERSION, 0]));
+
+            let mut request = [0; 10];
+            unwrap!(conn.read_exact(&mut request));
+            assert_eq!(request, [VERSION, CMD_CONNECT, 0, ATYP_IPV4, 10, 1, 2, 3, 0x11, 0xd7]);
+            // The peer's first bytes arrive along with the reply.
+            unwrap!(conn.write_all(b"\x05\x00\x00\x01\x7f\x00\x00\x01\x30\x39hello"));
+
+            let mut ping = [0; 4];
+            unwrap!(conn.read_exact(&mut ping));
+            assert_eq!(&ping, b"ping");
+        });
+
+        let socks = Socks5::new(proxy_addr).with_auth("user".to_owned(), "pass".to_owned());
+        let mut stream = unwrap!(socks.connect(&target));
+        assert_eq!(unwrap!(stream.peer_addr()), target);
+
+        let poll = unwrap!(Poll::new());
+        unwrap!(poll.register(&*stream,
+                              Token(0),
+                              Ready::readable() | Ready::writable(),
+                              PollOpt::edge()));
+        let mut events = Events::with_capacity(16);
+        let mut sent = false;
+        let mut received = Vec::new();
+        while !sent || received.len() < 5 {
+            let _ = unwrap!(poll.poll(&mut events, Some(Duration::from_secs(5))));
+            assert!(!events.is_empty(), "Timed out");
+            if !sent {
+                match stream.write_bufs(&[&b"ping"[..]]) {
+                    Ok(len) => {
+                        assert_eq!(len, 4);
+                        sent = true;
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+                    Err(e) => panic!("Write failed: {}", e),
+                }
+            }
+            let mut buf = [0; 16];
+            match stream.read(&mut buf) {
+                Ok(len) => received.extend_from_slice(&buf[..len]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(e) => panic!("Read failed: {}", e),
+            }
+        }
+        assert_eq!(&received[..], b"hello");
+        unwrap!(joiner.join());
+    }
+}
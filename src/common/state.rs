@@ -16,14 +16,23 @@
 // relating to use of the SAFE Network Software.
 
 use common::Core;
+use main::PeerId;
 use mio::{Poll, Ready};
 use std::any::Any;
 
 pub type Priority = u8;
 
 pub trait State {
+    /// The kind of state this is, as shown by `Core::debug_snapshot`.
+    fn name(&self) -> &'static str;
+
     fn as_any(&mut self) -> &mut Any;
 
+    /// The peer the state deals with, if it is known, as reported should the state crash.
+    fn peer_id(&self) -> Option<PeerId> {
+        None
+    }
+
     fn ready(&mut self, _core: &mut Core, _poll: &Poll, _kind: Ready) {}
 
     fn terminate(&mut self, _core: &mut Core, _poll: &Poll) {}
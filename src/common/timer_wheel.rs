@@ -0,0 +1,276 @@
+// Copyright 2017 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+// A hierarchical timer wheel, so that any number of timeouts cost the event loop a single one of
+// its own.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+const SLOT_BITS: usize = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+// With 10ms ticks this spans well over a day, later deadlines just waiting in the top level.
+const LEVELS: usize = 4;
+
+/// Handle to a timeout set on a `TimerWheel`, by which it can be cancelled.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Timeout {
+    id: u64,
+    deadline: u64,
+}
+
+struct Entry<T> {
+    deadline: u64,
+    level: usize,
+    slot: usize,
+    value: T,
+}
+
+/// Timeouts kept in slots of ever coarser levels, those of a coarser one being spread over the
+/// finer ones as their time comes closer. Setting and cancelling a timeout take constant time.
+pub struct TimerWheel<T> {
+    tick: Duration,
+    start: Instant,
+    // Ticks since `start` that have been gone through
+    now: u64,
+    levels: Vec<Vec<HashSet<u64>>>,
+    entries: HashMap<u64, Entry<T>>,
+    next_id: u64,
+}
+
+impl<T> TimerWheel<T> {
+    /// A wheel firing timeouts with a precision of `tick`.
+    pub fn new(tick: Duration) -> Self {
+        TimerWheel {
+            tick: tick,
+            start: Instant::now(),
+            now: 0,
+            levels: (0..LEVELS)
+                .map(|_| (0..SLOTS).map(|_| HashSet::new()).collect())
+                .collect(),
+            entries: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Have `value` fire once `after` has passed.
+    pub fn set_timeout(&mut self, after: Duration, value: T) -> Timeout {
+        let elapsed = (Instant::now() + after) - self.start;
+        let deadline = ticks_in(elapsed, self.tick, true);
+        let deadline = if deadline > self.now {
+            deadline
+        } else {
+            self.now + 1
+        };
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let _ = self.entries.insert(id,
+                                    Entry {
+                                        deadline: deadline,
+                                        level: 0,
+                                        slot: 0,
+                                        value: value,
+                                    });
+        self.place(id);
+
+        Timeout {
+            id: id,
+            deadline: deadline,
+        }
+    }
+
+    /// Cancel `timeout`, giving back its value unless it already fired.
+    pub fn cancel_timeout(&mut self, timeout: &Timeout) -> Option<T> {
+        self.entries
+            .remove(&timeout.id)
+            .map(|entry| {
+                     let _ = self.levels[entry.level][entry.slot].remove(&timeout.id);
+                     entry.value
+                 })
+    }
+
+    /// Whether no timeouts are waiting to fire.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The values of the timeouts waiting to fire, along with how long until each is due.
+    pub fn pending(&self) -> Vec<(&T, Duration)> {
+        self.entries
+            .values()
+            .map(|entry| (&entry.value, self.until_tick(entry.deadline)))
+            .collect()
+    }
+
+    /// How long until `timeout` is due, or was due should it have fired already.
+    pub fn until(&self, timeout: &Timeout) -> Duration {
+        self.until_tick(timeout.deadline)
+    }
+
+    /// How long until the wheel next has something to do, be it firing timeouts or spreading
+    /// those of a coarser level over the finer ones, or `None` if it holds no timeouts.
+    pub fn next_wakeup(&self) -> Option<Duration> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut tick = self.now + 1;
+        while tick & SLOT_MASK != 0 && self.levels[0][(tick & SLOT_MASK) as usize].is_empty() {
+            tick += 1;
+        }
+        Some(self.until_tick(tick))
+    }
+
+    /// Go through the ticks up to now, returning the values of the timeouts that fell due in the
+    /// order they did.
+    pub fn poll(&mut self) -> Vec<T> {
+        let target = ticks_in(self.start.elapsed(), self.tick, false);
+        let mut fired = Vec::new();
+        if self.is_empty() {
+            self.now = target;
+            return fired;
+        }
+
+        while self.now < target && !self.is_empty() {
+            self.now += 1;
+            for level in (1..LEVELS).rev() {
+                let shift = SLOT_BITS * level;
+                if self.now & ((1 << shift) - 1) == 0 {
+                    let slot = ((self.now >> shift) & SLOT_MASK) as usize;
+                    let ids: Vec<_> = self.levels[level][slot].drain().collect();
+                    for id in ids {
+                        self.place(id);
+                    }
+                }
+            }
+
+            let slot = (self.now & SLOT_MASK) as usize;
+            let ids: Vec<_> = self.levels[0][slot].drain().collect();
+            for id in ids {
+                let due = self.entries
+                    .get(&id)
+                    .map_or(false, |entry| entry.deadline <= self.now);
+                if due {
+                    if let Some(entry) = self.entries.remove(&id) {
+                        fired.push(entry.value);
+                    }
+                } else {
+                    self.place(id);
+                }
+            }
+        }
+        self.now = target;
+
+        fired
+    }
+
+    // Put the entry of `id` in the slot of the finest level its deadline fits in from now.
+    fn place(&mut self, id: u64) {
+        let now = self.now;
+        let entry = match self.entries.get_mut(&id) {
+            Some(entry) => entry,
+            None => return,
+        };
+        let at = if entry.deadline > now {
+            entry.deadline
+        } else {
+            now
+        };
+        let delta = at - now;
+        let mut level = 0;
+        while level < LEVELS - 1 && delta >> (SLOT_BITS * (level + 1)) != 0 {
+            level += 1;
+        }
+        entry.level = level;
+        entry.slot = ((at >> (SLOT_BITS * level)) & SLOT_MASK) as usize;
+        let _ = self.levels[level][entry.slot].insert(id);
+    }
+
+    fn until_tick(&self, tick: u64) -> Duration {
+        let at = match duration_of(tick, self.tick).and_then(|d| self.start.checked_add(d)) {
+            Some(at) => at,
+            // Later than can be told at all, which is as good as never
+            None => return Duration::from_secs(u32::max_value() as u64),
+        };
+        let now = Instant::now();
+        if at > now {
+            at - now
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+}
+
+// How many `tick`s there are in `duration`, rounding up or down.
+fn ticks_in(duration: Duration, tick: Duration, round_up: bool) -> u64 {
+    let nanos = |d: Duration| d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64;
+    let (duration, tick) = (nanos(duration), nanos(tick));
+    if round_up {
+        (duration + tick - 1) / tick
+    } else {
+        duration / tick
+    }
+}
+
+// How long `ticks` of `tick` take, unless that is more than a `Duration` holds.
+fn duration_of(ticks: u64, tick: Duration) -> Option<Duration> {
+    let secs = tick.as_secs().checked_mul(ticks)?;
+    let nanos = (tick.subsec_nanos() as u64).checked_mul(ticks)?;
+    secs.checked_add(nanos / 1_000_000_000)
+        .map(|secs| Duration::new(secs, (nanos % 1_000_000_000) as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn fire_and_cancel() {
+        let mut wheel = TimerWheel::new(Duration::from_millis(1));
+        let _ = wheel.set_timeout(Duration::from_millis(5), 0);
+        let cancelled = wheel.set_timeout(Duration::from_millis(10), 1);
+        // Far enough out to start in a coarser level
+        let _ = wheel.set_timeout(Duration::from_millis(150), 2);
+        assert!(wheel.poll().is_empty());
+        assert_eq!(wheel.cancel_timeout(&cancelled), Some(1));
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(wheel.poll(), vec![0]);
+        assert!(unwrap!(wheel.next_wakeup()) <= Duration::from_millis(64));
+
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(wheel.poll(), vec![2]);
+        assert!(wheel.is_empty());
+        assert_eq!(wheel.next_wakeup(), None);
+        assert_eq!(wheel.cancel_timeout(&cancelled), None);
+    }
+
+    #[test]
+    fn far_deadline() {
+        let wheel = TimerWheel::<()>::new(Duration::from_millis(10));
+        // More ticks than a u32 holds
+        let ticks = 1 << 33;
+        let until = wheel.until_tick(ticks);
+        assert!(until > Duration::from_millis(10 * ticks - 1000));
+        assert!(until <= Duration::from_millis(10 * ticks));
+        assert_eq!(duration_of(ticks, Duration::from_millis(10)),
+                   Some(Duration::from_millis(10 * ticks)));
+        assert_eq!(duration_of(u64::max_value(), Duration::from_secs(2)), None);
+    }
+}
@@ -0,0 +1,107 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use std::fmt;
+
+/// Target the default `LogSubscriber` logs under, to be enabled on its own.
+pub const TRACE_TARGET: &'static str = "crust::lifecycle";
+
+/// The state machine a `TraceEvent` comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TraceState {
+    /// Mapping a socket to the addresses it can be reached on from outside.
+    Mapping,
+    /// Connecting to a peer over whichever paths its connection info offers.
+    Connect,
+    /// Exchanging handshake messages over a new connection, in either direction.
+    Handshake,
+    /// A connection to a peer that completed the handshake.
+    ActiveConnection,
+}
+
+impl TraceState {
+    /// The state as it appears in the logs.
+    pub fn label(&self) -> &'static str {
+        match *self {
+            TraceState::Mapping => "mapping",
+            TraceState::Connect => "connect",
+            TraceState::Handshake => "handshake",
+            TraceState::ActiveConnection => "active_connection",
+        }
+    }
+}
+
+/// Something that happened to a connection. `conn` stays the same from the handshake on, and a
+/// connect attempt names each of the connections it makes as its `path`.
+pub struct TraceEvent<'a> {
+    /// Identifies the connection, or the attempt to make or map one.
+    pub conn: usize,
+    /// The state machine the event comes from.
+    pub state: TraceState,
+    /// What happened, e.g. `"started"` or `"failed"`.
+    pub name: &'static str,
+    /// Whatever else is known about it.
+    pub fields: &'a [(&'static str, &'a fmt::Display)],
+}
+
+impl<'a> fmt::Display for TraceEvent<'a> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter,
+               "conn={} state={} event={}",
+               self.conn,
+               self.state.label(),
+               self.name)?;
+        for &(key, value) in self.fields {
+            write!(formatter, " {}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Receives the `TraceEvent`s of a `Service` as they happen, on its event loop.
+pub trait TraceSubscriber: Send {
+    /// Handles an event. Holds up the event loop, so should be quick.
+    fn on_event(&self, event: &TraceEvent);
+}
+
+/// Logs the events at trace level under `TRACE_TARGET`, unless another subscriber is set.
+pub struct LogSubscriber;
+
+impl TraceSubscriber for LogSubscriber {
+    fn on_event(&self, event: &TraceEvent) {
+        trace!(target: TRACE_TARGET, "{}", event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let peer_addr = "127.0.0.1:5483";
+        let event = TraceEvent {
+            conn: 7,
+            state: TraceState::Handshake,
+            name: "started",
+            fields: &[("direction", &"inbound"), ("peer_addr", &peer_addr)],
+        };
+        assert_eq!(event.to_string(),
+                   "conn=7 state=handshake event=started direction=inbound \
+                    peer_addr=127.0.0.1:5483");
+    }
+}
@@ -0,0 +1,282 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{Device, SocketConfig, bind_to_device, set_dscp, set_recv_buffer_size};
+use iovec::IoVec;
+use mio::Evented;
+use mio::tcp::{Shutdown, TcpListener, TcpStream};
+use net2::TcpBuilder;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// A connection to a peer over some transport: a reliable, ordered stream of bytes which is read
+/// and written without blocking once the event loop reports it ready. Streams may be moved to
+/// another event loop once connected.
+pub trait Stream: Evented + Send {
+    /// Name of the transport the stream runs over, e.g. `"tcp"`.
+    fn transport(&self) -> &'static str;
+    /// Read what has arrived into `buf`, `0` bytes meaning the peer has closed the stream.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+    /// Write as much of `bufs` in order as the stream takes, returning how many bytes it took.
+    fn write_bufs(&mut self, bufs: &[&[u8]]) -> io::Result<usize>;
+    /// Our end of the stream.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    /// The peer's end of the stream.
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+    /// Any error the stream has run into in the background, e.g. failing to connect.
+    fn take_error(&self) -> io::Result<Option<io::Error>>;
+    /// Close the stream in both directions.
+    fn shutdown(&self) -> io::Result<()>;
+    /// Apply whichever of the options in `config` mean something to the transport.
+    fn configure(&self, _config: &SocketConfig) -> io::Result<()> {
+        Ok(())
+    }
+    /// Mark what is written from now on with the DSCP codepoint `dscp`, where the transport can.
+    fn set_dscp(&self, _dscp: u8) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "DSCP marking is not supported by the transport"))
+    }
+}
+
+/// Accepts streams from peers.
+pub trait Listener: Evented {
+    /// A stream a peer has opened to us and where from, or a `WouldBlock` error if there is none
+    /// waiting.
+    fn accept(&self) -> io::Result<(Box<Stream>, SocketAddr)>;
+    /// The address listened on.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// A protocol to exchange messages with peers over, which the event loop dials and listens with.
+/// Peers are addressed by `SocketAddr` whatever the transport, so a transport with addresses of
+/// its own has to map them onto those.
+pub trait Transport {
+    /// Name of the transport, e.g. `"tcp"`.
+    fn name(&self) -> &'static str;
+    /// Start connecting to `addr`, the stream becoming writable once connected.
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Box<Stream>>;
+    /// Listen for peers on `addr`, which may leave the port for the OS to pick.
+    fn listen(&self, addr: &SocketAddr) -> io::Result<Box<Listener>>;
+    /// Whether the NAT mapping and hole punching of TCP sockets apply to the transport, which
+    /// they only do to TCP itself. Without it, the listener is only advertised at the address it
+    /// is bound to and peers are only dialed directly or through the relay.
+    fn nat_traversal(&self) -> bool {
+        false
+    }
+    /// Whether the transport's listeners hold the UDP port they listen on, which peers would
+    /// otherwise find the UDP echo service of a TCP listener on the same port at.
+    fn holds_udp_port(&self) -> bool {
+        false
+    }
+    /// Carry a TCP stream crust set up itself, e.g. by hole punching, as the transport's own
+    /// streams are carried. Only ever called if `nat_traversal`.
+    fn adopt(&self, stream: TcpStream) -> io::Result<Box<Stream>> {
+        Ok(Box::new(stream))
+    }
+    /// Likewise for a listener on a mapped TCP socket.
+    fn adopt_listener(&self, listener: TcpListener) -> io::Result<Box<Listener>> {
+        Ok(Box::new(listener))
+    }
+}
+
+/// The transport crust uses unless another one is set.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Tcp;
+
+impl Transport for Tcp {
+    fn name(&self) -> &'static str {
+        "tcp"
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Box<Stream>> {
+        Ok(Box::new(TcpStream::connect(addr)?))
+    }
+
+    fn listen(&self, addr: &SocketAddr) -> io::Result<Box<Listener>> {
+        Ok(Box::new(TcpListener::bind(addr)?))
+    }
+
+    fn nat_traversal(&self) -> bool {
+        true
+    }
+}
+
+/// TCP with the options that have to be set on a socket before it connects or listens, rather
+/// than by `SocketConfig::apply` once it has: the network interface to pin sockets to, so that
+/// peers are dialed and accepted on it alone whatever the routing table says, e.g. the data NIC of
+/// a server which also has a management one, and the receive buffer size, which the window scale
+/// offered to the peer is picked by.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TunedTcp {
+    device: Option<Device>,
+    recv_buffer_size: Option<usize>,
+}
+
+impl TunedTcp {
+    /// Pin sockets to `device`.
+    pub fn device(mut self, device: Device) -> Self {
+        self.device = Some(device);
+        self
+    }
+
+    /// Give sockets a receive buffer of `size` bytes.
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    fn socket(&self, addr: &SocketAddr) -> io::Result<TcpBuilder> {
+        let socket = match *addr {
+            SocketAddr::V4(..) => TcpBuilder::new_v4()?,
+            SocketAddr::V6(..) => TcpBuilder::new_v6()?,
+        };
+        if let Some(ref device) = self.device {
+            bind_to_device(&socket, device)?;
+        }
+        // Failing that, it is set once connected
+        if let Some(size) = self.recv_buffer_size {
+            let _ = set_recv_buffer_size(&socket, size);
+        }
+        Ok(socket)
+    }
+}
+
+impl Transport for TunedTcp {
+    fn name(&self) -> &'static str {
+        "tcp"
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Box<Stream>> {
+        let socket = self.socket(addr)?.to_tcp_stream()?;
+        Ok(Box::new(TcpStream::connect_stream(socket, addr)?))
+    }
+
+    fn listen(&self, addr: &SocketAddr) -> io::Result<Box<Listener>> {
+        let socket = self.socket(addr)?;
+        let _ = socket.reuse_address(true)?;
+        let _ = socket.bind(addr)?;
+        let listener = socket.listen(LISTEN_BACKLOG)?;
+        let local_addr = listener.local_addr()?;
+        Ok(Box::new(TcpListener::from_listener(listener, &local_addr)?))
+    }
+
+    fn nat_traversal(&self) -> bool {
+        true
+    }
+}
+
+impl Stream for TcpStream {
+    fn transport(&self) -> &'static str {
+        "tcp"
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(self, buf)
+    }
+
+    fn write_bufs(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        let bufs: Vec<&IoVec> = bufs.iter().map(|&buf| buf.into()).collect();
+        TcpStream::write_bufs(self, &bufs)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        TcpStream::take_error(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn configure(&self, config: &SocketConfig) -> io::Result<()> {
+        config.apply(self)
+    }
+
+    fn set_dscp(&self, dscp: u8) -> io::Result<()> {
+        set_dscp(self, TcpStream::local_addr(self)?.is_ipv6(), dscp)
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&self) -> io::Result<(Box<Stream>, SocketAddr)> {
+        let (stream, addr) = TcpListener::accept(self)?;
+        Ok((Box::new(stream), addr))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio::{Events, Poll, PollOpt, Ready, Token};
+    use std::time::Duration;
+
+    // Wait a few seconds at most for a stream to arrive at `listener`.
+    fn accept(listener: &Listener) -> (Box<Stream>, SocketAddr) {
+        let poll = unwrap!(Poll::new());
+        unwrap!(poll.register(listener, Token(0), Ready::readable(), PollOpt::level()));
+        let mut events = Events::with_capacity(1);
+        for _ in 0..50 {
+            match listener.accept() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                res => return unwrap!(res),
+            }
+            let _ = unwrap!(poll.poll(&mut events, Some(Duration::from_millis(100))));
+        }
+        panic!("Nothing to accept");
+    }
+
+    #[test]
+    fn tcp() {
+        let listener = unwrap!(Tcp.listen(&unwrap!("127.0.0.1:0".parse())));
+        let stream = unwrap!(Tcp.connect(&unwrap!(listener.local_addr())));
+        assert_eq!(stream.transport(), Tcp.name());
+
+        let (accepted, addr) = accept(&*listener);
+        assert_eq!(addr, unwrap!(stream.local_addr()));
+        assert_eq!(unwrap!(accepted.peer_addr()), unwrap!(stream.local_addr()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn device_tcp() {
+        let transport = TunedTcp::default().device(unwrap!(Device::new("lo")));
+        // Older kernels only let privileged processes bind to a device
+        let listener = match transport.listen(&unwrap!("127.0.0.1:0".parse())) {
+            Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => return,
+            res => unwrap!(res),
+        };
+        let stream = unwrap!(transport.connect(&unwrap!(listener.local_addr())));
+
+        let (_, addr) = accept(&*listener);
+        assert_eq!(addr, unwrap!(stream.local_addr()));
+
+        let transport = TunedTcp::default().device(unwrap!(Device::new("nosuchif0")));
+        assert!(transport.connect(&unwrap!(listener.local_addr())).is_err());
+    }
+}
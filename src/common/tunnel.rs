@@ -0,0 +1,237 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{SocketConfig, Stream, set_dscp};
+use iovec::IoVec;
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use mio::tcp::{Shutdown, TcpStream};
+use std::cell::Cell;
+use std::cmp;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+
+/// The exchange with a proxy which opens a tunnel through it to a peer.
+pub trait Handshake: Send {
+    /// What to send the proxy first.
+    fn start(&mut self) -> Vec<u8>;
+    /// Act on what the proxy has sent so far, which may also hold the peer's first bytes once
+    /// the tunnel is open.
+    fn reply(&mut self, incoming: &[u8]) -> io::Result<Reply>;
+}
+
+/// How far a reply from the proxy takes the handshake.
+pub enum Reply {
+    /// The reply is not all in yet.
+    Incomplete,
+    /// The reply is the given number of bytes long and is answered with more to send.
+    Continue(usize, Vec<u8>),
+    /// The reply is the given number of bytes long and the tunnel is open.
+    Done(usize),
+}
+
+/// A stream to a peer through a proxy. The handshake with the proxy is driven by reading and
+/// writing the stream as for any other, which report `WouldBlock` until it is done.
+pub struct TunnelStream<H> {
+    stream: TcpStream,
+    target: SocketAddr,
+    transport: &'static str,
+    handshake: H,
+    done: bool,
+    // Handshake yet to be written to the proxy.
+    outgoing: Vec<u8>,
+    // What the proxy sent that is yet to be parsed, or once the handshake is done, to be read.
+    incoming: Vec<u8>,
+    // Once the handshake is done, neither the data read along with the last reply nor the writes
+    // refused until then would be reported ready by the socket again, so this reports them.
+    registration: Registration,
+    readiness: SetReadiness,
+    registered: Cell<bool>,
+}
+
+impl<H: Handshake> TunnelStream<H> {
+    /// Start connecting to `proxy` to open a tunnel to `target` with `handshake`, for the
+    /// transport named `transport`.
+    pub fn connect(proxy: &SocketAddr,
+                   target: &SocketAddr,
+                   transport: &'static str,
+                   mut handshake: H)
+                   -> io::Result<Self> {
+        let outgoing = handshake.start();
+        let (registration, readiness) = Registration::new2();
+        Ok(TunnelStream {
+               stream: TcpStream::connect(proxy)?,
+               target: *target,
+               transport: transport,
+               handshake: handshake,
+               done: false,
+               outgoing: outgoing,
+               incoming: Vec::new(),
+               registration: registration,
+               readiness: readiness,
+               registered: Cell::new(false),
+           })
+    }
+
+    // Take the handshake as far as the proxy lets us, returning whether the tunnel is open.
+    fn open(&mut self) -> io::Result<bool> {
+        while !self.done {
+            while !self.outgoing.is_empty() {
+                match Write::write(&mut self.stream, &self.outgoing) {
+                    Ok(0) => {
+                        return Err(io::Error::new(ErrorKind::WriteZero,
+                                                  "Proxy closed the connection"))
+                    }
+                    Ok(written) => {
+                        let _ = self.outgoing.drain(..written);
+                    }
+                    // Still connecting to the proxy.
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                                  e.kind() == ErrorKind::NotConnected => return Ok(false),
+                    Err(e) => return Err(e),
+                }
+            }
+
+            match self.handshake.reply(&self.incoming)? {
+                Reply::Incomplete => {
+                    let mut buf = [0; 1024];
+                    match Read::read(&mut self.stream, &mut buf) {
+                        Ok(0) => {
+                            return Err(io::Error::new(ErrorKind::UnexpectedEof,
+                                                      "Proxy closed the connection"))
+                        }
+                        Ok(len) => self.incoming.extend_from_slice(&buf[..len]),
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                        Err(e) => return Err(e),
+                    }
+                }
+                Reply::Continue(len, next) => {
+                    let _ = self.incoming.drain(..len);
+                    self.outgoing = next;
+                }
+                Reply::Done(len) => {
+                    let _ = self.incoming.drain(..len);
+                    self.done = true;
+                }
+            }
+        }
+
+        let _ = self.readiness
+            .set_readiness(Ready::readable() | Ready::writable());
+        Ok(true)
+    }
+
+    // While waiting for the proxy to reply, the socket being writable is no reason to wake up.
+    fn socket_interest(&self, interest: Ready) -> Ready {
+        if !self.done && self.outgoing.is_empty() {
+            interest - Ready::writable()
+        } else {
+            interest
+        }
+    }
+}
+
+impl<H: Handshake> Stream for TunnelStream<H> {
+    fn transport(&self) -> &'static str {
+        self.transport
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.open()? {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        if self.incoming.is_empty() {
+            return Read::read(&mut self.stream, buf);
+        }
+        let len = cmp::min(buf.len(), self.incoming.len());
+        buf[..len].copy_from_slice(&self.incoming[..len]);
+        let _ = self.incoming.drain(..len);
+        Ok(len)
+    }
+
+    fn write_bufs(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        if !self.open()? {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        let bufs: Vec<&IoVec> = bufs.iter().map(|&buf| buf.into()).collect();
+        TcpStream::write_bufs(&self.stream, &bufs)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(&self.stream)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.target)
+    }
+
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        TcpStream::take_error(&self.stream)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(&self.stream, Shutdown::Both)
+    }
+
+    fn configure(&self, config: &SocketConfig) -> io::Result<()> {
+        config.apply(&self.stream)
+    }
+
+    fn set_dscp(&self, dscp: u8) -> io::Result<()> {
+        set_dscp(&self.stream, TcpStream::local_addr(&self.stream)?.is_ipv6(), dscp)
+    }
+}
+
+// The registration only matters during the handshake, so a stream moved to another event loop
+// once connected leaves it behind.
+impl<H: Handshake> Evented for TunnelStream<H> {
+    fn register(&self,
+                poll: &Poll,
+                token: Token,
+                interest: Ready,
+                opts: PollOpt)
+                -> io::Result<()> {
+        self.stream
+            .register(poll, token, self.socket_interest(interest), opts)?;
+        if !self.done {
+            self.registration.register(poll, token, interest, opts)?;
+            self.registered.set(true);
+        }
+        Ok(())
+    }
+
+    fn reregister(&self,
+                  poll: &Poll,
+                  token: Token,
+                  interest: Ready,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        self.stream
+            .reregister(poll, token, self.socket_interest(interest), opts)?;
+        if self.registered.get() {
+            self.registration.reregister(poll, token, interest, opts)?;
+        }
+        Ok(())
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        if self.registered.get() {
+            let _ = self.registration.deregister(poll);
+            self.registered.set(false);
+        }
+        self.stream.deregister(poll)
+    }
+}
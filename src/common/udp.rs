@@ -0,0 +1,1097 @@
+// Copyright 2017 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use byteorder::{BigEndian, ByteOrder};
+use common::{Listener, Stream, Transport, lock, set_dscp};
+use maidsafe_utilities::thread::{self, Joiner};
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use mio::udp::UdpSocket;
+use net2::UdpBuilder;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, ErrorKind};
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+// Segment kinds. Data and the end of the stream are numbered in one sequence, which acks give the
+// next expected number of. A reset tells a peer that its stream is gone at our end. A segment too
+// large for the path goes in fragments, and probes of the path MTU are answered with their size.
+const DATA: u8 = 0;
+const ACK: u8 = 1;
+const FIN: u8 = 2;
+const RST: u8 = 3;
+const FRAG: u8 = 4;
+const PROBE: u8 = 5;
+const PROBE_ACK: u8 = 6;
+// Kind and sequence number.
+const HEADER_LEN: usize = 9;
+// Then the index of the fragment, how many there are and the kind of the segment fragmented.
+const FRAG_HEADER_LEN: usize = HEADER_LEN + 3;
+/// Largest datagram taken to get through whole on any path: the least MTU of IPv6, less the IPv6
+/// and UDP headers.
+pub const MIN_DATAGRAM: usize = 1232;
+// Largest datagram probed for: the Ethernet MTU, less the IPv4 and UDP headers.
+const MAX_PROBE: usize = 1472;
+// Most fragments a segment may come in, which bounds what is held of those partly arrived.
+const MAX_FRAGMENTS: usize = 4;
+/// Largest datagram taken in.
+pub const MAX_DATAGRAM: usize = 2048;
+// How near the probes get to the path MTU before settling, how many times a probe is sent before
+// its size is taken to be too large, and how long until probing for a larger MTU again.
+const PROBE_PRECISION: usize = 16;
+const PROBE_TRIES: u32 = 3;
+const PROBE_INTERVAL_SECS: u64 = 600;
+// Times in a row what is unacked gets sent again before taking datagrams larger than the least
+// MTU to be dropped on the path, and fragmenting segments down to it.
+const BLACK_HOLE_RETRANSMITS: u32 = 3;
+// Most segments sent and not yet acked, and how far ahead of the next expected one segments are
+// held on to.
+const SEND_WINDOW: usize = 128;
+const RECV_WINDOW: u64 = 256;
+// Past this many bytes waiting to be read, data is turned away for the peer to send again.
+const MAX_BUFFERED: usize = 1024 * 1024;
+const INITIAL_RTO_MS: u64 = 250;
+const MAX_RTO_MS: u64 = 4000;
+// Times in a row what is unacked gets sent again without a word from the peer before giving up.
+const MAX_RETRANSMITS: u32 = 10;
+// How often streams are looked at for what is due to be sent again.
+const TICK_MS: u64 = 50;
+// For how long datagrams from a peer just accepted, sent before its stream was set up, may still
+// reach the listener.
+const ACCEPT_GRACE_SECS: u64 = 10;
+/// Whether the OS hands a datagram to the socket connected to its sender ahead of a socket merely
+/// bound to the same port, which accepted streams rely on to get theirs.
+pub const CONNECTED_SOCKET_FIRST: bool = cfg!(any(target_os = "linux",
+                                              target_os = "android",
+                                              target_os = "macos",
+                                              target_os = "ios",
+                                              target_os = "freebsd",
+                                              target_os = "dragonfly",
+                                              target_os = "netbsd",
+                                              target_os = "openbsd"));
+
+/// UDP, with the ordering and retransmission a stream needs done by crust: data goes in numbered
+/// segments, acked cumulatively and sent again until they are. A listener takes a peer's first
+/// segment as the stream opening, answering it from a socket connected to the peer on the
+/// listener's own port, which relies on the OS handing datagrams to connected sockets ahead of
+/// the listening one, as Linux and the BSDs do. Elsewhere streams can be dialed but not listened
+/// for. Segments are as large as the path MTU found by probing allows, and are sent in fragments
+/// should the path take less than they were written for. Listening on the port of a TCP listener
+/// takes the place of the UDP echo service peers would otherwise find there.
+#[derive(Clone)]
+pub struct Udp {
+    timers: Arc<Timers>,
+}
+
+impl Udp {
+    /// A transport whose streams are sent again what they lose on a thread of its own, which ends
+    /// once the transport and all its streams are dropped.
+    pub fn new() -> Self {
+        Udp { timers: Arc::new(Timers::new()) }
+    }
+
+    fn dial(&self, addr: &SocketAddr) -> io::Result<UdpStream> {
+        let any = match *addr {
+            SocketAddr::V4(..) => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+            SocketAddr::V6(..) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
+        };
+        let socket = net::UdpSocket::bind(SocketAddr::new(any, 0))?;
+        forbid_fragmentation(&socket, addr.is_ipv6());
+        socket.connect(addr)?;
+        Ok(UdpStream::new(UdpSocket::from_socket(socket)?, *addr, &self.timers))
+    }
+}
+
+impl Default for Udp {
+    fn default() -> Self {
+        Udp::new()
+    }
+}
+
+impl Transport for Udp {
+    fn name(&self) -> &'static str {
+        "udp"
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Box<Stream>> {
+        Ok(Box::new(self.dial(addr)?))
+    }
+
+    fn listen(&self, addr: &SocketAddr) -> io::Result<Box<Listener>> {
+        if !CONNECTED_SOCKET_FIRST {
+            return Err(io::Error::new(ErrorKind::Other,
+                                      "Listening over UDP is not supported on this platform"));
+        }
+        let socket = UdpSocket::from_socket(reusably_bound_socket(addr)?)?;
+        let local_addr = socket.local_addr()?;
+        Ok(Box::new(UdpListener {
+                        socket: socket,
+                        local_addr: local_addr,
+                        accepted: RefCell::new(HashMap::new()),
+                        timers: self.timers.clone(),
+                    }))
+    }
+
+    fn holds_udp_port(&self) -> bool {
+        true
+    }
+}
+
+/// A UDP socket bound to `addr` which the sockets of the streams accepted on it can share it with.
+pub fn reusably_bound_socket(addr: &SocketAddr) -> io::Result<net::UdpSocket> {
+    let socket = match *addr {
+        SocketAddr::V4(..) => UdpBuilder::new_v4()?,
+        SocketAddr::V6(ref addr) => {
+            let socket = UdpBuilder::new_v6()?;
+            if addr.ip().is_unspecified() {
+                let _ = socket.only_v6(false)?;
+            }
+            socket
+        }
+    };
+    let _ = socket.reuse_address(true)?;
+    // Linux shares the port on address reuse alone, whereas port reuse would have it spread
+    // datagrams over the sockets regardless of which one is connected to their sender
+    #[cfg(all(unix, not(target_os = "linux")))]
+    {
+        use net2::unix::UnixUdpBuilderExt;
+        let _ = socket.reuse_port(true)?;
+    }
+    let socket = socket.bind(addr)?;
+    forbid_fragmentation(&socket, addr.is_ipv6());
+    Ok(socket)
+}
+
+// Have datagrams too large for the path dropped on the way rather than fragmented, whatever the OS
+// has learnt of the path, so that probes tell what gets through whole. Should the OS not let us,
+// probes only find what it gets through.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[allow(unsafe_code)]
+fn forbid_fragmentation(socket: &net::UdpSocket, ipv6: bool) {
+    use libc;
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let set = |level, name| {
+        let value = libc::IP_PMTUDISC_PROBE;
+        let value_ptr: *const libc::c_int = &value;
+        unsafe {
+            libc::setsockopt(socket.as_raw_fd(),
+                             level,
+                             name,
+                             value_ptr as *const libc::c_void,
+                             mem::size_of::<libc::c_int>() as libc::socklen_t)
+        }
+    };
+    // Dual-stack sockets get both, for their IPv4 traffic
+    if ipv6 {
+        let _ = set(libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER);
+    }
+    let _ = set(libc::IPPROTO_IP, libc::IP_MTU_DISCOVER);
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn forbid_fragmentation(_socket: &net::UdpSocket, _ipv6: bool) {}
+
+fn datagram(kind: u8, seq: u64, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = vec![0; HEADER_LEN];
+    datagram[0] = kind;
+    BigEndian::write_u64(&mut datagram[1..HEADER_LEN], seq);
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+fn parse(datagram: &[u8]) -> Option<(u8, u64, &[u8])> {
+    if datagram.len() < HEADER_LEN {
+        return None;
+    }
+    let seq = BigEndian::read_u64(&datagram[1..HEADER_LEN]);
+    Some((datagram[0], seq, &datagram[HEADER_LEN..]))
+}
+
+// The datagrams to send a segment in that are no larger than `max_len`.
+fn fragments(kind: u8, seq: u64, payload: &[u8], max_len: usize) -> Vec<Vec<u8>> {
+    let chunk_len = max_len - FRAG_HEADER_LEN;
+    let count = (payload.len() + chunk_len - 1) / chunk_len;
+    payload
+        .chunks(chunk_len)
+        .enumerate()
+        .map(|(index, chunk)| {
+                 let mut fragment = datagram(FRAG, seq, &[index as u8, count as u8, kind]);
+                 fragment.extend_from_slice(chunk);
+                 fragment
+             })
+        .collect()
+}
+
+// What is known of the largest datagram that reaches the peer whole, and the probing for more.
+struct PathMtu {
+    // Largest size known to get through, and the largest not yet known not to.
+    confirmed: usize,
+    ceiling: usize,
+    // Size of the probe awaiting an answer, when it was last sent and how many times it has been.
+    probe: Option<(usize, Instant, u32)>,
+    // Whether a probe has been lost, after which the ceiling is no longer tried first.
+    lost: bool,
+    // Until when not to probe any further, once the MTU has been found.
+    settled_until: Option<Instant>,
+}
+
+impl PathMtu {
+    fn new() -> Self {
+        PathMtu {
+            confirmed: MIN_DATAGRAM,
+            ceiling: MAX_PROBE,
+            probe: None,
+            lost: false,
+            settled_until: None,
+        }
+    }
+
+    // The size of the probe to send next, if one is due.
+    fn next_probe(&mut self, now: Instant) -> Option<usize> {
+        if self.probe.is_some() {
+            return None;
+        }
+        if let Some(until) = self.settled_until {
+            if now < until {
+                return None;
+            }
+            // The path may have changed since
+            self.settled_until = None;
+            self.ceiling = MAX_PROBE;
+            self.lost = false;
+        }
+        if self.ceiling < self.confirmed + PROBE_PRECISION {
+            self.settled_until = Some(now + Duration::from_secs(PROBE_INTERVAL_SECS));
+            return None;
+        }
+        if self.lost {
+            Some((self.confirmed + self.ceiling + 1) / 2)
+        } else {
+            Some(self.ceiling)
+        }
+    }
+
+    fn confirm(&mut self, size: usize) {
+        if self.probe.map_or(false, |(probed, _, _)| probed == size) {
+            self.probe = None;
+            self.confirmed = cmp::max(self.confirmed, size);
+        }
+    }
+
+    fn too_large(&mut self, size: usize) {
+        self.probe = None;
+        self.lost = true;
+        self.ceiling = cmp::max(cmp::min(self.ceiling, size - 1), self.confirmed);
+    }
+
+    // Go back to the least MTU, as the path no longer takes what it did.
+    fn fall_back(&mut self, now: Instant) {
+        self.confirmed = MIN_DATAGRAM;
+        self.probe = None;
+        self.settled_until = Some(now + Duration::from_secs(PROBE_INTERVAL_SECS));
+    }
+}
+
+/// When a stream has something to send again, and how to wake whoever reads it to do so.
+pub struct Timing {
+    /// Set while the stream is registered with an event loop.
+    pub readiness: Option<SetReadiness>,
+    /// When the first of what is unacked is due to be sent again.
+    pub retransmit_at: Option<Instant>,
+    /// A socket of the stream's to wake it as soon as anything arrives on it, for streams whose
+    /// readers are not waiting for it to be readable, as when waiting for a stream to connect.
+    pub arrivals: Option<net::UdpSocket>,
+}
+
+impl Timing {
+    /// A stream with nothing to send again yet.
+    pub fn new() -> Self {
+        Timing {
+            readiness: None,
+            retransmit_at: None,
+            arrivals: None,
+        }
+    }
+
+    /// Report the stream both readable and writable.
+    pub fn wake(&self) {
+        if let Some(ref readiness) = self.readiness {
+            let _ = readiness.set_readiness(Ready::readable() | Ready::writable());
+        }
+    }
+
+    fn has_arrivals(&self) -> bool {
+        let socket = match self.arrivals {
+            Some(ref socket) => socket,
+            None => return false,
+        };
+        // Errors, as when the peer's port is unreachable, are for the stream to find as well
+        match socket.peek(&mut [0; 1]) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => false,
+            _ => true,
+        }
+    }
+}
+
+/// Wakes streams with segments due to be sent again, on a thread which ends once this is
+/// dropped.
+pub struct Timers {
+    tx: Mutex<Sender<Weak<Mutex<Timing>>>>,
+    _joiner: Joiner,
+}
+
+impl Timers {
+    /// Start the thread.
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        Timers {
+            tx: Mutex::new(tx),
+            _joiner: thread::named("Crust-Udp-Timers", move || run(rx)),
+        }
+    }
+
+    /// Wake the stream `timing` is of whenever it has something due, for as long as it lasts.
+    pub fn watch(&self, timing: &Arc<Mutex<Timing>>) {
+        let _ = lock(&self.tx).send(Arc::downgrade(timing));
+    }
+}
+
+fn run(rx: Receiver<Weak<Mutex<Timing>>>) {
+    let mut watched = Vec::new();
+    loop {
+        match rx.recv_timeout(Duration::from_millis(TICK_MS)) {
+            Ok(timing) => watched.push(timing),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        let now = Instant::now();
+        watched.retain(|timing| match timing.upgrade() {
+                           Some(timing) => {
+                               let timing = lock(&timing);
+                               if timing.retransmit_at.map_or(false, |at| at <= now) ||
+                                  timing.has_arrivals() {
+                                   timing.wake();
+                               }
+                               true
+                           }
+                           None => false,
+                       });
+    }
+}
+
+struct Segment {
+    seq: u64,
+    kind: u8,
+    payload: Vec<u8>,
+    sent_at: Instant,
+}
+
+// Both directions of a stream.
+struct Connection {
+    socket: UdpSocket,
+    peer_addr: SocketAddr,
+    timing: Arc<Mutex<Timing>>,
+    next_seq: u64,
+    unacked: VecDeque<Segment>,
+    rto: Duration,
+    retransmits: u32,
+    // Whether a write was turned away for want of room in the send window.
+    blocked: bool,
+    // Whether anything has been heard from the peer, before which the path is not probed.
+    established: bool,
+    pmtu: PathMtu,
+    expected: u64,
+    out_of_order: BTreeMap<u64, (u8, Vec<u8>)>,
+    // Segments some of whose fragments have arrived, by sequence number.
+    partial: BTreeMap<u64, (u8, Vec<Option<Vec<u8>>>)>,
+    received: VecDeque<u8>,
+    // Whether the peer has ended the stream, and whether we have.
+    finished: bool,
+    closed: bool,
+    error: Option<ErrorKind>,
+}
+
+impl Connection {
+    fn check(&self) -> io::Result<()> {
+        match self.error {
+            Some(kind) => Err(io::Error::new(kind, "UDP stream failed")),
+            None => Ok(()),
+        }
+    }
+
+    fn fail(&mut self, kind: ErrorKind) -> io::Error {
+        self.error = Some(kind);
+        io::Error::new(kind, "UDP stream failed")
+    }
+
+    fn send(&self, kind: u8, seq: u64, payload: &[u8]) -> io::Result<()> {
+        // A datagram the socket has no room for is as good as lost on the way, which sending it
+        // again makes up for
+        if HEADER_LEN + payload.len() <= self.pmtu.confirmed {
+            return self.socket
+                       .send(&datagram(kind, seq, payload))
+                       .map(|_| ());
+        }
+        for fragment in fragments(kind, seq, payload, self.pmtu.confirmed) {
+            let _ = self.socket.send(&fragment)?;
+        }
+        Ok(())
+    }
+
+    // Most payload of a segment written now.
+    fn mss(&self) -> usize {
+        self.pmtu.confirmed - HEADER_LEN
+    }
+
+    fn push(&mut self, kind: u8, payload: Vec<u8>) -> io::Result<()> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if let Err(e) = self.send(kind, seq, &payload) {
+            return Err(self.fail(e.kind()));
+        }
+        self.unacked
+            .push_back(Segment {
+                           seq: seq,
+                           kind: kind,
+                           payload: payload,
+                           sent_at: Instant::now(),
+                       });
+        self.schedule();
+        Ok(())
+    }
+
+    fn schedule(&self) {
+        let at = self.unacked
+            .front()
+            .map(|segment| segment.sent_at + self.rto);
+        let probe_at = self.pmtu
+            .probe
+            .map(|(_, sent_at, _)| sent_at + self.rto);
+        lock(&self.timing).retransmit_at = match (at, probe_at) {
+            (Some(at), Some(probe_at)) => Some(cmp::min(at, probe_at)),
+            (at, probe_at) => at.or(probe_at),
+        };
+    }
+
+    // Send the probe due, if any, and again the one out should it have gone unanswered.
+    fn probe_path(&mut self, now: Instant) {
+        if !self.established || self.error.is_some() {
+            return;
+        }
+        if let Some((size, sent_at, tries)) = self.pmtu.probe {
+            if sent_at + self.rto > now {
+                return;
+            }
+            if tries < PROBE_TRIES {
+                return self.send_probe(size, tries + 1, now);
+            }
+            self.pmtu.too_large(size);
+        }
+        if let Some(size) = self.pmtu.next_probe(now) {
+            self.send_probe(size, 1, now);
+        }
+    }
+
+    fn send_probe(&mut self, size: usize, tries: u32, now: Instant) {
+        let mut probe = datagram(PROBE, size as u64, &[]);
+        probe.resize(size, 0);
+        // Failing to send it at all, as when it is larger than our own end takes, rules it out
+        if self.socket.send(&probe).is_err() {
+            return self.pmtu.too_large(size);
+        }
+        self.pmtu.probe = Some((size, now, tries));
+    }
+
+    // Take in what has arrived and send again what is due to be.
+    fn pump(&mut self) -> io::Result<()> {
+        self.check()?;
+        let mut buf = [0; MAX_DATAGRAM];
+        loop {
+            match self.socket.recv(&mut buf) {
+                Ok(Some(len)) => self.handle(&buf[..len])?,
+                Ok(None) => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Err(self.fail(e.kind())),
+            }
+        }
+        self.retransmit()
+    }
+
+    fn handle(&mut self, datagram: &[u8]) -> io::Result<()> {
+        let (kind, seq, payload) = match parse(datagram) {
+            Some(segment) => segment,
+            None => return Ok(()),
+        };
+        self.established = true;
+        match kind {
+            ACK => {
+                self.acked(seq);
+                Ok(())
+            }
+            DATA | FIN => self.handle_segment(kind, seq, payload),
+            // One taken in already is acked again, for the peer to stop sending it
+            FRAG if seq < self.expected => self.handle_segment(DATA, seq, &[]),
+            FRAG => {
+                match self.reassemble(seq, payload) {
+                    Some((kind, payload)) => self.handle_segment(kind, seq, &payload),
+                    None => Ok(()),
+                }
+            }
+            PROBE => {
+                // Answered only if it arrived whole
+                if seq == datagram.len() as u64 {
+                    let _ = self.socket.send(&self::datagram(PROBE_ACK, seq, &[]));
+                }
+                Ok(())
+            }
+            PROBE_ACK => {
+                self.pmtu.confirm(seq as usize);
+                self.schedule();
+                Ok(())
+            }
+            RST => Err(self.fail(ErrorKind::ConnectionReset)),
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_segment(&mut self, kind: u8, seq: u64, payload: &[u8]) -> io::Result<()> {
+        if seq >= self.expected && seq < self.expected + RECV_WINDOW &&
+           self.received.len() < MAX_BUFFERED {
+            let _ = self.out_of_order
+                .entry(seq)
+                .or_insert_with(|| (kind, payload.to_vec()));
+            while let Some((kind, payload)) = self.out_of_order.remove(&self.expected) {
+                self.expected += 1;
+                if kind == FIN {
+                    self.finished = true;
+                } else {
+                    self.received.extend(payload);
+                }
+            }
+        }
+        let expected = self.expected;
+        if let Err(e) = self.send(ACK, expected, &[]) {
+            return Err(self.fail(e.kind()));
+        }
+        Ok(())
+    }
+
+    // Hold on to a fragment of segment `seq`, giving back the segment once it is complete.
+    fn reassemble(&mut self, seq: u64, payload: &[u8]) -> Option<(u8, Vec<u8>)> {
+        if payload.len() < FRAG_HEADER_LEN - HEADER_LEN {
+            return None;
+        }
+        let (index, count, kind) = (payload[0] as usize, payload[1] as usize, payload[2]);
+        if index >= count || count > MAX_FRAGMENTS || (kind != DATA && kind != FIN) ||
+           seq >= self.expected + RECV_WINDOW {
+            return None;
+        }
+        let expected = self.expected;
+        self.partial = self.partial.split_off(&expected);
+
+        let complete = {
+            let entry = self.partial
+                .entry(seq)
+                .or_insert_with(|| (kind, vec![None; count]));
+            if entry.0 != kind || entry.1.len() != count {
+                *entry = (kind, vec![None; count]);
+            }
+            entry.1[index] = Some(payload[FRAG_HEADER_LEN - HEADER_LEN..].to_vec());
+            entry.1.iter().all(Option::is_some)
+        };
+        if !complete {
+            return None;
+        }
+        self.partial
+            .remove(&seq)
+            .map(|(kind, parts)| (kind, parts.into_iter().flat_map(Option::unwrap).collect()))
+    }
+
+    fn acked(&mut self, next: u64) {
+        // Any answer at all shows the peer is still there
+        self.retransmits = 0;
+        let mut freed = false;
+        while self.unacked
+                  .front()
+                  .map_or(false, |segment| segment.seq < next) {
+            let _ = self.unacked.pop_front();
+            freed = true;
+        }
+        if freed {
+            self.rto = Duration::from_millis(INITIAL_RTO_MS);
+            self.schedule();
+            if self.blocked {
+                self.blocked = false;
+                lock(&self.timing).wake();
+            }
+        }
+    }
+
+    fn retransmit(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        self.probe_path(now);
+        match self.unacked.front() {
+            Some(segment) if segment.sent_at + self.rto <= now => (),
+            _ => {
+                self.schedule();
+                return Ok(());
+            }
+        }
+        self.retransmits += 1;
+        if self.retransmits > MAX_RETRANSMITS {
+            return Err(self.fail(ErrorKind::TimedOut));
+        }
+        if self.retransmits == BLACK_HOLE_RETRANSMITS && self.pmtu.confirmed > MIN_DATAGRAM {
+            self.pmtu.fall_back(now);
+        }
+        self.rto = cmp::min(self.rto * 2, Duration::from_millis(MAX_RTO_MS));
+        for segment in &self.unacked {
+            if let Err(e) = self.send(segment.kind, segment.seq, &segment.payload) {
+                let kind = e.kind();
+                return Err(self.fail(kind));
+            }
+        }
+        for segment in &mut self.unacked {
+            segment.sent_at = now;
+        }
+        self.schedule();
+        Ok(())
+    }
+}
+
+/// A stream over `Udp`.
+pub struct UdpStream {
+    conn: RefCell<Connection>,
+    // Made afresh on every registration, as one cannot move from one event loop to another.
+    registration: RefCell<Option<Registration>>,
+    _timers: Arc<Timers>,
+}
+
+impl UdpStream {
+    fn new(socket: UdpSocket, peer_addr: SocketAddr, timers: &Arc<Timers>) -> Self {
+        let timing = Arc::new(Mutex::new(Timing::new()));
+        timers.watch(&timing);
+        UdpStream {
+            conn: RefCell::new(Connection {
+                                   socket: socket,
+                                   peer_addr: peer_addr,
+                                   timing: timing,
+                                   next_seq: 0,
+                                   unacked: VecDeque::new(),
+                                   rto: Duration::from_millis(INITIAL_RTO_MS),
+                                   retransmits: 0,
+                                   blocked: false,
+                                   established: false,
+                                   pmtu: PathMtu::new(),
+                                   expected: 0,
+                                   out_of_order: BTreeMap::new(),
+                                   partial: BTreeMap::new(),
+                                   received: VecDeque::new(),
+                                   finished: false,
+                                   closed: false,
+                                   error: None,
+                               }),
+            registration: RefCell::new(None),
+            _timers: timers.clone(),
+        }
+    }
+}
+
+impl Stream for UdpStream {
+    fn transport(&self) -> &'static str {
+        "udp"
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let conn = self.conn.get_mut();
+        conn.pump()?;
+        let len = cmp::min(buf.len(), conn.received.len());
+        for (dst, src) in buf.iter_mut().zip(conn.received.drain(..len)) {
+            *dst = src;
+        }
+        if len > 0 || buf.is_empty() || conn.finished {
+            Ok(len)
+        } else {
+            Err(io::Error::new(ErrorKind::WouldBlock, "Nothing has arrived"))
+        }
+    }
+
+    fn write_bufs(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        let conn = self.conn.get_mut();
+        conn.check()?;
+        if conn.closed {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "Stream closed"));
+        }
+
+        let mss = conn.mss();
+        let room = SEND_WINDOW.saturating_sub(conn.unacked.len()) * mss;
+        let mut data = Vec::new();
+        for buf in bufs {
+            let len = cmp::min(buf.len(), room - data.len());
+            data.extend_from_slice(&buf[..len]);
+        }
+        let total: usize = bufs.iter().map(|buf| buf.len()).sum();
+        if data.len() < total {
+            conn.blocked = true;
+        }
+        if data.is_empty() && total > 0 {
+            return Err(io::Error::new(ErrorKind::WouldBlock, "The send window is full"));
+        }
+        for chunk in data.chunks(mss) {
+            conn.push(DATA, chunk.to_vec())?;
+        }
+        Ok(data.len())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.conn.borrow().socket.local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.conn.borrow().peer_addr)
+    }
+
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        let conn = self.conn.borrow();
+        match conn.error {
+            Some(kind) => Ok(Some(io::Error::new(kind, "UDP stream failed"))),
+            None => conn.socket.take_error(),
+        }
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        let mut conn = self.conn.borrow_mut();
+        if conn.closed || conn.error.is_some() {
+            return Ok(());
+        }
+        conn.closed = true;
+        conn.push(FIN, Vec::new())
+    }
+
+    fn set_dscp(&self, dscp: u8) -> io::Result<()> {
+        let conn = self.conn.borrow();
+        set_dscp(&conn.socket, conn.socket.local_addr()?.is_ipv6(), dscp)
+    }
+}
+
+impl Evented for UdpStream {
+    fn register(&self,
+                poll: &Poll,
+                token: Token,
+                interest: Ready,
+                opts: PollOpt)
+                -> io::Result<()> {
+        let conn = self.conn.borrow();
+        conn.socket.register(poll, token, interest, opts)?;
+        let (registration, readiness) = Registration::new2();
+        poll.register(&registration, token, interest, opts)?;
+        // What came in while on no event loop, as when moving to another, is picked up there
+        if !conn.received.is_empty() || conn.finished || !conn.unacked.is_empty() {
+            let _ = readiness.set_readiness(Ready::readable() | Ready::writable());
+        }
+        lock(&conn.timing).readiness = Some(readiness);
+        *self.registration.borrow_mut() = Some(registration);
+        Ok(())
+    }
+
+    fn reregister(&self,
+                  poll: &Poll,
+                  token: Token,
+                  interest: Ready,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        self.conn
+            .borrow()
+            .socket
+            .reregister(poll, token, interest, opts)?;
+        match *self.registration.borrow() {
+            Some(ref registration) => poll.reregister(registration, token, interest, opts),
+            None => Ok(()),
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        let conn = self.conn.borrow();
+        lock(&conn.timing).readiness = None;
+        if let Some(registration) = self.registration.borrow_mut().take() {
+            let _ = poll.deregister(&registration);
+        }
+        conn.socket.deregister(poll)
+    }
+}
+
+impl Drop for UdpStream {
+    fn drop(&mut self) {
+        let _ = Stream::shutdown(self);
+    }
+}
+
+/// Accepts streams over `Udp`.
+pub struct UdpListener {
+    socket: UdpSocket,
+    local_addr: SocketAddr,
+    // Peers accepted lately, whose datagrams sent before their stream was set up may still
+    // arrive here.
+    accepted: RefCell<HashMap<SocketAddr, Instant>>,
+    timers: Arc<Timers>,
+}
+
+impl Listener for UdpListener {
+    fn accept(&self) -> io::Result<(Box<Stream>, SocketAddr)> {
+        let mut buf = [0; MAX_DATAGRAM];
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut buf)? {
+                Some(res) => res,
+                None => return Err(io::Error::new(ErrorKind::WouldBlock, "No stream to accept")),
+            };
+            let (kind, seq) = match parse(&buf[..len]) {
+                Some((kind, seq, _)) => (kind, seq),
+                None => continue,
+            };
+
+            let now = Instant::now();
+            let grace = Duration::from_secs(ACCEPT_GRACE_SECS);
+            let mut accepted = self.accepted.borrow_mut();
+            accepted.retain(|_, &mut at| now.duration_since(at) < grace);
+            if accepted.contains_key(&addr) {
+                continue;
+            }
+            if kind != DATA || seq != 0 {
+                // Left over from a stream which has since gone, as the peer is told
+                if kind != RST {
+                    let _ = self.socket.send_to(&datagram(RST, 0, &[]), &addr);
+                }
+                continue;
+            }
+
+            let socket = reusably_bound_socket(&self.local_addr)?;
+            socket.connect(addr)?;
+            let stream = UdpStream::new(UdpSocket::from_socket(socket)?, addr, &self.timers);
+            stream.conn.borrow_mut().handle(&buf[..len])?;
+            let _ = accepted.insert(addr, now);
+            return Ok((Box::new(stream), addr));
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+impl Evented for UdpListener {
+    fn register(&self,
+                poll: &Poll,
+                token: Token,
+                interest: Ready,
+                opts: PollOpt)
+                -> io::Result<()> {
+        self.socket.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self,
+                  poll: &Poll,
+                  token: Token,
+                  interest: Ready,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        self.socket.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.socket.deregister(poll)
+    }
+}
+
+// Only where there are listeners to test against.
+#[cfg(all(test,
+          any(target_os = "linux",
+              target_os = "android",
+              target_os = "macos",
+              target_os = "ios",
+              target_os = "freebsd",
+              target_os = "dragonfly",
+              target_os = "netbsd",
+              target_os = "openbsd")))]
+mod tests {
+    use super::*;
+    use mio::Events;
+
+    // Poll until `f` gives something other than `WouldBlock`.
+    fn wait_for<T, F>(poll: &Poll, mut f: F) -> io::Result<T>
+        where F: FnMut() -> io::Result<T>
+    {
+        let mut events = Events::with_capacity(16);
+        for _ in 0..200 {
+            match f() {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+                res => return res,
+            }
+            let _ = unwrap!(poll.poll(&mut events, Some(Duration::from_millis(50))));
+        }
+        Err(io::Error::new(ErrorKind::TimedOut, "Timed out"))
+    }
+
+    #[test]
+    fn udp() {
+        let poll = unwrap!(Poll::new());
+        let transport = Udp::new();
+        let listener = unwrap!(transport.listen(&unwrap!("127.0.0.1:0".parse())));
+        unwrap!(poll.register(&*listener, Token(0), Ready::readable(), PollOpt::edge()));
+        let mut stream = unwrap!(transport.dial(&unwrap!(listener.local_addr())));
+        unwrap!(poll.register(&stream,
+                              Token(1),
+                              Ready::readable() | Ready::writable(),
+                              PollOpt::edge()));
+        assert_eq!(stream.transport(), transport.name());
+
+        // More than fits in one segment, so that it has to be put back together in order
+        let data: Vec<u8> = (0..10 * MIN_DATAGRAM).map(|i| i as u8).collect();
+        assert_eq!(unwrap!(stream.write_bufs(&[&data[..3], &data[3..]])), data.len());
+
+        let (mut accepted, addr) = unwrap!(wait_for(&poll, || listener.accept()));
+        assert_eq!(addr, unwrap!(stream.local_addr()));
+        assert_eq!(unwrap!(accepted.peer_addr()), unwrap!(stream.local_addr()));
+        unwrap!(poll.register(&*accepted,
+                              Token(2),
+                              Ready::readable() | Ready::writable(),
+                              PollOpt::edge()));
+
+        let mut received = Vec::new();
+        let mut buf = [0; 4096];
+        while received.len() < data.len() {
+            // Segments which reached the listener ahead of the stream are sent again once the
+            // dialing end finds them unacked
+            let len = unwrap!(wait_for(&poll, || {
+                let _ = unwrap!(stream.read(&mut []));
+                accepted.read(&mut buf)
+            }));
+            received.extend_from_slice(&buf[..len]);
+        }
+        assert_eq!(received, data);
+
+        // Answered over the socket connected to the peer rather than the listening one
+        assert_eq!(unwrap!(accepted.write_bufs(&[b"pong"])), 4);
+        let len = unwrap!(wait_for(&poll, || stream.read(&mut buf)));
+        assert_eq!(&buf[..len], b"pong");
+        assert!(stream.conn.get_mut().unacked.is_empty());
+
+        unwrap!(accepted.shutdown());
+        assert_eq!(unwrap!(wait_for(&poll, || stream.read(&mut buf))), 0);
+    }
+
+    #[test]
+    fn path_mtu() {
+        let poll = unwrap!(Poll::new());
+        let transport = Udp::new();
+        let listener = unwrap!(transport.listen(&unwrap!("127.0.0.1:0".parse())));
+        unwrap!(poll.register(&*listener, Token(0), Ready::readable(), PollOpt::edge()));
+        let mut stream = unwrap!(transport.dial(&unwrap!(listener.local_addr())));
+        unwrap!(poll.register(&stream, Token(1), Ready::readable(), PollOpt::edge()));
+        assert_eq!(unwrap!(stream.write_bufs(&[b"ping"])), 4);
+        let (mut accepted, _) = unwrap!(wait_for(&poll, || listener.accept()));
+        unwrap!(poll.register(&*accepted, Token(2), Ready::readable(), PollOpt::edge()));
+
+        // Loopback takes anything probed for
+        unwrap!(wait_for(&poll, || {
+            let _ = unwrap!(stream.read(&mut []));
+            let _ = unwrap!(accepted.read(&mut []));
+            if stream.conn.get_mut().pmtu.confirmed == MAX_PROBE {
+                Ok(())
+            } else {
+                Err(io::Error::new(ErrorKind::WouldBlock, "Still probing"))
+            }
+        }));
+        assert_eq!(stream.conn.get_mut().pmtu.probe, None);
+
+        // So that two segments take what took three before
+        let data = vec![7; 2 * (MAX_PROBE - HEADER_LEN)];
+        assert_eq!(unwrap!(stream.write_bufs(&[&data])), data.len());
+        assert_eq!(stream.conn.get_mut().next_seq, 3);
+        let mut received = Vec::new();
+        let mut buf = [0; 4096];
+        while received.len() < 4 + data.len() {
+            let len = unwrap!(wait_for(&poll, || accepted.read(&mut buf)));
+            received.extend_from_slice(&buf[..len]);
+        }
+        assert_eq!(&received[4..], &data[..]);
+    }
+
+    #[test]
+    fn fragmentation() {
+        let segments = fragments(DATA, 7, &[7; 3000], MIN_DATAGRAM);
+        assert_eq!(segments.len(), 3);
+        assert!(segments.iter().all(|segment| segment.len() <= MIN_DATAGRAM));
+
+        let poll = unwrap!(Poll::new());
+        let transport = Udp::new();
+        let listener = unwrap!(transport.listen(&unwrap!("127.0.0.1:0".parse())));
+        unwrap!(poll.register(&*listener, Token(0), Ready::readable(), PollOpt::edge()));
+        let mut stream = unwrap!(transport.dial(&unwrap!(listener.local_addr())));
+        unwrap!(poll.register(&stream, Token(1), Ready::readable(), PollOpt::edge()));
+
+        // Segments written for a larger MTU than the path turns out to take
+        {
+            let conn = stream.conn.get_mut();
+            conn.pmtu.confirmed = MAX_PROBE;
+            conn.pmtu.settled_until = Some(Instant::now() + Duration::from_secs(60));
+        }
+        let data: Vec<u8> = (0..10 * MAX_PROBE).map(|i| i as u8).collect();
+        assert_eq!(unwrap!(stream.write_bufs(&[&data])), data.len());
+        stream.conn.get_mut().pmtu.confirmed = MIN_DATAGRAM;
+
+        // All but the first reach the listener ahead of the stream, and are sent again in
+        // fragments
+        let (mut accepted, _) = unwrap!(wait_for(&poll, || listener.accept()));
+        unwrap!(poll.register(&*accepted, Token(2), Ready::readable(), PollOpt::edge()));
+        let mut received = Vec::new();
+        let mut buf = [0; 4096];
+        while received.len() < data.len() {
+            let len = unwrap!(wait_for(&poll, || {
+                let _ = unwrap!(stream.read(&mut []));
+                accepted.read(&mut buf)
+            }));
+            received.extend_from_slice(&buf[..len]);
+        }
+        assert_eq!(received, data);
+        assert_eq!(stream.conn.get_mut().pmtu.confirmed, MIN_DATAGRAM);
+    }
+
+    #[test]
+    fn reset() {
+        let poll = unwrap!(Poll::new());
+        let transport = Udp::new();
+        let listener = unwrap!(transport.listen(&unwrap!("127.0.0.1:0".parse())));
+        unwrap!(poll.register(&*listener, Token(0), Ready::readable(), PollOpt::edge()));
+        let mut stream = unwrap!(transport.dial(&unwrap!(listener.local_addr())));
+        unwrap!(poll.register(&stream, Token(1), Ready::readable(), PollOpt::edge()));
+
+        // As if the stream had been opened before the listener lost track of it
+        stream.conn.get_mut().next_seq = 1;
+        assert_eq!(unwrap!(stream.write_bufs(&[b"ping"])), 4);
+        let mut buf = [0; 16];
+        let res = wait_for(&poll, || {
+            assert_eq!(unwrap!(listener.accept().err()).kind(), ErrorKind::WouldBlock);
+            stream.read(&mut buf)
+        });
+        assert_eq!(unwrap!(res.err()).kind(), ErrorKind::ConnectionReset);
+    }
+}
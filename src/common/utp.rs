@@ -0,0 +1,1107 @@
+// Copyright 2017 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use byteorder::{BigEndian, ByteOrder};
+use common::{Listener, Stream, Transport, lock, set_dscp};
+use common::udp::{CONNECTED_SOCKET_FIRST, MAX_DATAGRAM, MIN_DATAGRAM, Timers, Timing,
+                  reusably_bound_socket};
+use mio::{Evented, Poll, PollOpt, Ready, Registration, Token};
+use mio::udp::UdpSocket;
+use rand;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind};
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Packet types, in the high nibble of the first byte of the header, the version of the protocol
+// being in the low one. Data and the end of the stream are numbered in one sequence, which state
+// packets ack the last received in order of. A reset tells a peer that its stream is gone at our
+// end, and a SYN opens a stream.
+const ST_DATA: u8 = 0;
+const ST_FIN: u8 = 1;
+const ST_STATE: u8 = 2;
+const ST_RESET: u8 = 3;
+const ST_SYN: u8 = 4;
+const VERSION: u8 = 1;
+// Type and version, first extension, connection id, timestamp, timestamp difference, window,
+// sequence and ack numbers.
+const HEADER_LEN: usize = 20;
+// Packets are kept small enough to get through whole on any path.
+const MAX_PAYLOAD: usize = MIN_DATAGRAM - HEADER_LEN;
+// The queuing delay LEDBAT aims for, and how many bytes the window grows by per window acked
+// when there is no queuing delay at all.
+const TARGET_DELAY_US: u32 = 100_000;
+const MAX_WINDOW_GAIN: usize = 3000;
+// Bounds of the window, which starts at a few packets.
+const MIN_WINDOW: usize = MAX_PAYLOAD;
+const INITIAL_WINDOW: usize = 4 * MAX_PAYLOAD;
+const MAX_WINDOW: usize = MAX_IN_FLIGHT * MAX_PAYLOAD;
+// Most packets sent and not yet acked, and how far ahead of the next expected one packets are held
+// on to, both well within the half of the sequence space acks are told apart in.
+const MAX_IN_FLIGHT: usize = 1024;
+const REORDER_WINDOW: u16 = 1024;
+// Past this many bytes waiting to be read, the window the peer is told of is closed.
+const MAX_BUFFERED: usize = 1024 * 1024;
+// The base delay is the least delay seen over this many minutes.
+const BASE_DELAY_MINUTES: usize = 2;
+const INITIAL_RTO_MS: u64 = 1000;
+const MIN_RTO_MS: u64 = 500;
+const MAX_RTO_MS: u64 = 8000;
+// Times in a row what is unacked gets sent again without a word from the peer before giving up,
+// and likewise for a SYN, whose peer may just not be listening.
+const MAX_RETRANSMITS: u32 = 10;
+const MAX_SYN_RETRANSMITS: u32 = 4;
+// Acks of the same packet in a row taken to tell of the one after it being lost.
+const DUPLICATE_ACKS: u32 = 3;
+// For how long SYNs from a peer just accepted, sent again before its stream was set up, may still
+// reach the listener.
+const ACCEPT_GRACE_SECS: u64 = 10;
+
+/// uTP, the Micro Transport Protocol (BEP 29): reliable, ordered streams in UDP datagrams whose
+/// congestion control (LEDBAT) backs off as soon as the delay of the path starts growing, so that
+/// they yield to other traffic rather than fill up the queues of the NAT devices on the way.
+///
+/// Streams dial from a socket of their own and are accepted as with `Udp`, on sockets connected to
+/// the peer on the listener's own port, so they can only be listened for where the OS hands
+/// datagrams to connected sockets ahead of the listening one. Register the transport with
+/// `Service::register_transport` to listen on the UDP port of each TCP listener and dial over uTP
+/// should TCP not get through, or set it with `Service::set_transport` and register `Tcp` to have
+/// uTP tried first and fall back on TCP where both are available.
+#[derive(Clone)]
+pub struct Utp {
+    timers: Arc<Timers>,
+}
+
+impl Utp {
+    /// A transport whose streams are sent again what they lose on a thread of its own, which ends
+    /// once the transport and all its streams are dropped.
+    pub fn new() -> Self {
+        Utp { timers: Arc::new(Timers::new()) }
+    }
+
+    fn dial(&self, addr: &SocketAddr) -> io::Result<UtpStream> {
+        let any = match *addr {
+            SocketAddr::V4(..) => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+            SocketAddr::V6(..) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
+        };
+        let socket = net::UdpSocket::bind(SocketAddr::new(any, 0))?;
+        socket.connect(addr)?;
+        let arrivals = socket.try_clone()?;
+        arrivals.set_nonblocking(true)?;
+        let recv_id: u16 = rand::random();
+        let stream = UtpStream::new(UdpSocket::from_socket(socket)?,
+                                    *addr,
+                                    recv_id,
+                                    recv_id.wrapping_add(1),
+                                    &self.timers);
+        {
+            let mut conn = stream.conn.borrow_mut();
+            // Whoever waits for the stream to connect only waits for it to be writable
+            lock(&conn.timing).arrivals = Some(arrivals);
+            conn.push(ST_SYN, Vec::new())?;
+        }
+        Ok(stream)
+    }
+}
+
+impl Default for Utp {
+    fn default() -> Self {
+        Utp::new()
+    }
+}
+
+impl Transport for Utp {
+    fn name(&self) -> &'static str {
+        "utp"
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Box<Stream>> {
+        Ok(Box::new(self.dial(addr)?))
+    }
+
+    fn listen(&self, addr: &SocketAddr) -> io::Result<Box<Listener>> {
+        if !CONNECTED_SOCKET_FIRST {
+            return Err(io::Error::new(ErrorKind::Other,
+                                      "Listening over uTP is not supported on this platform"));
+        }
+        let socket = UdpSocket::from_socket(reusably_bound_socket(addr)?)?;
+        let local_addr = socket.local_addr()?;
+        Ok(Box::new(UtpListener {
+                        socket: socket,
+                        local_addr: local_addr,
+                        accepted: RefCell::new(HashMap::new()),
+                        timers: self.timers.clone(),
+                    }))
+    }
+
+    fn holds_udp_port(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Header {
+    kind: u8,
+    conn_id: u16,
+    // When the packet was sent in microseconds, and how long the latest packet from its sender's
+    // peer took to arrive, each by the sender's clock.
+    timestamp: u32,
+    timestamp_diff: u32,
+    wnd_size: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+}
+
+impl Header {
+    fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0; HEADER_LEN];
+        // No extensions
+        packet[0] = self.kind << 4 | VERSION;
+        BigEndian::write_u16(&mut packet[2..4], self.conn_id);
+        BigEndian::write_u32(&mut packet[4..8], self.timestamp);
+        BigEndian::write_u32(&mut packet[8..12], self.timestamp_diff);
+        BigEndian::write_u32(&mut packet[12..16], self.wnd_size);
+        BigEndian::write_u16(&mut packet[16..18], self.seq_nr);
+        BigEndian::write_u16(&mut packet[18..20], self.ack_nr);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    // The header of `packet` and its payload, past any extensions, which are not acted on.
+    fn parse(packet: &[u8]) -> Option<(Header, &[u8])> {
+        if packet.len() < HEADER_LEN || packet[0] & 0x0f != VERSION || packet[0] >> 4 > ST_SYN {
+            return None;
+        }
+        let header = Header {
+            kind: packet[0] >> 4,
+            conn_id: BigEndian::read_u16(&packet[2..4]),
+            timestamp: BigEndian::read_u32(&packet[4..8]),
+            timestamp_diff: BigEndian::read_u32(&packet[8..12]),
+            wnd_size: BigEndian::read_u32(&packet[12..16]),
+            seq_nr: BigEndian::read_u16(&packet[16..18]),
+            ack_nr: BigEndian::read_u16(&packet[18..20]),
+        };
+        // Each extension gives the type of the next one and its own length
+        let mut extension = packet[1];
+        let mut pos = HEADER_LEN;
+        while extension != 0 {
+            if packet.len() < pos + 2 {
+                return None;
+            }
+            extension = packet[pos];
+            pos += 2 + packet[pos + 1] as usize;
+            if packet.len() < pos {
+                return None;
+            }
+        }
+        Some((header, &packet[pos..]))
+    }
+}
+
+// Whether sequence number `a` comes before `b`, the numbers wrapping around.
+fn before(a: u16, b: u16) -> bool {
+    a != b && b.wrapping_sub(a) < 0x8000
+}
+
+// Likewise for delays in microseconds, which are measured across two clocks and so wrap around
+// too.
+fn earlier(a: u32, b: u32) -> bool {
+    a != b && b.wrapping_sub(a) < 0x8000_0000
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+struct Packet {
+    seq_nr: u16,
+    kind: u8,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    // Whether it has been sent again, after which its ack tells nothing of the round trip time.
+    resent: bool,
+}
+
+// Both directions of a stream.
+struct Connection {
+    socket: UdpSocket,
+    peer_addr: SocketAddr,
+    timing: Arc<Mutex<Timing>>,
+    // What our timestamps count microseconds from.
+    epoch: Instant,
+    // Ids of the packets the peer sends us and of those we send it.
+    recv_id: u16,
+    send_id: u16,
+    // Whether the peer has answered our SYN, or for accepted streams, sent its own.
+    connected: bool,
+    seq_nr: u16,
+    unacked: VecDeque<Packet>,
+    // Bytes of payload sent and not yet acked, and most of them LEDBAT and the peer allow.
+    in_flight: usize,
+    max_window: usize,
+    peer_window: usize,
+    // Smoothed round trip time and its variation in milliseconds, once measured.
+    rtt: Option<(u64, u64)>,
+    rto: Duration,
+    retransmits: u32,
+    duplicate_acks: u32,
+    // Whether a write was turned away for want of room in the window.
+    blocked: bool,
+    // Least one-way delay of our packets the peer measured in each of the last minutes, by its
+    // clock less ours, and when the latest of those minutes began.
+    base_delays: VecDeque<u32>,
+    base_delay_since: Instant,
+    // How long, by the same measure, the latest packet from the peer took to arrive, which is
+    // told it in turn.
+    reply_micro: u32,
+    // Last packet received in order, and those received ahead of it.
+    ack_nr: u16,
+    out_of_order: HashMap<u16, (u8, Vec<u8>)>,
+    received: VecDeque<u8>,
+    // Whether the peer has ended the stream, and whether we have.
+    finished: bool,
+    closed: bool,
+    error: Option<ErrorKind>,
+}
+
+impl Connection {
+    fn check(&self) -> io::Result<()> {
+        match self.error {
+            Some(kind) => Err(io::Error::new(kind, "uTP stream failed")),
+            None => Ok(()),
+        }
+    }
+
+    fn fail(&mut self, kind: ErrorKind) -> io::Error {
+        self.error = Some(kind);
+        io::Error::new(kind, "uTP stream failed")
+    }
+
+    fn timestamp(&self) -> u32 {
+        let elapsed = self.epoch.elapsed();
+        (elapsed.as_secs() * 1_000_000 + (elapsed.subsec_nanos() / 1000) as u64) as u32
+    }
+
+    // Room left for what is waiting to be read, which the peer is told.
+    fn window(&self) -> usize {
+        MAX_BUFFERED.saturating_sub(self.received.len())
+    }
+
+    fn send(&self, kind: u8, seq_nr: u16, payload: &[u8]) -> io::Result<()> {
+        let header = Header {
+            kind: kind,
+            // A SYN gives the id it is to be answered with
+            conn_id: if kind == ST_SYN {
+                self.recv_id
+            } else {
+                self.send_id
+            },
+            timestamp: self.timestamp(),
+            timestamp_diff: self.reply_micro,
+            wnd_size: self.window() as u32,
+            seq_nr: seq_nr,
+            ack_nr: self.ack_nr,
+        };
+        // A datagram the socket has no room for is as good as lost on the way, which sending it
+        // again makes up for
+        self.socket.send(&header.encode(payload)).map(|_| ())
+    }
+
+    fn push(&mut self, kind: u8, payload: Vec<u8>) -> io::Result<()> {
+        let seq_nr = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        if let Err(e) = self.send(kind, seq_nr, &payload) {
+            return Err(self.fail(e.kind()));
+        }
+        self.in_flight += payload.len();
+        self.unacked
+            .push_back(Packet {
+                           seq_nr: seq_nr,
+                           kind: kind,
+                           payload: payload,
+                           sent_at: Instant::now(),
+                           resent: false,
+                       });
+        self.schedule();
+        Ok(())
+    }
+
+    // Ack what has arrived, which also tells the peer our window.
+    fn send_state(&mut self) -> io::Result<()> {
+        let seq_nr = self.seq_nr;
+        if let Err(e) = self.send(ST_STATE, seq_nr, &[]) {
+            return Err(self.fail(e.kind()));
+        }
+        Ok(())
+    }
+
+    // How many bytes may be written now.
+    fn room(&self) -> usize {
+        if self.unacked.len() >= MAX_IN_FLIGHT {
+            return 0;
+        }
+        let window = cmp::min(self.max_window, self.peer_window);
+        // With nothing in flight a packet goes regardless, for the peer to tell us once a window
+        // it has closed opens again
+        if self.in_flight == 0 {
+            cmp::max(window, MAX_PAYLOAD)
+        } else {
+            window.saturating_sub(self.in_flight)
+        }
+    }
+
+    fn schedule(&self) {
+        lock(&self.timing).retransmit_at = self.unacked
+            .front()
+            .map(|packet| packet.sent_at + self.rto);
+    }
+
+    // Take in what has arrived and send again what is due to be.
+    fn pump(&mut self) -> io::Result<()> {
+        self.check()?;
+        let mut buf = [0; MAX_DATAGRAM];
+        loop {
+            match self.socket.recv(&mut buf) {
+                Ok(Some(len)) => self.handle(&buf[..len])?,
+                Ok(None) => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Err(self.fail(e.kind())),
+            }
+        }
+        self.retransmit()
+    }
+
+    fn handle(&mut self, datagram: &[u8]) -> io::Result<()> {
+        let (header, payload) = match Header::parse(datagram) {
+            Some(packet) => packet,
+            None => return Ok(()),
+        };
+        match header.kind {
+            ST_RESET if header.conn_id == self.recv_id || header.conn_id == self.send_id => {
+                return Err(self.fail(ErrorKind::ConnectionReset))
+            }
+            // Our answer to the peer's SYN was lost on the way
+            ST_SYN if header.conn_id == self.send_id && header.seq_nr == self.ack_nr => {
+                return self.send_state()
+            }
+            ST_RESET | ST_SYN => return Ok(()),
+            _ if header.conn_id != self.recv_id => return Ok(()),
+            _ => (),
+        }
+
+        if !self.connected {
+            let syn_acked = header.kind == ST_STATE &&
+                            self.unacked
+                                .front()
+                                .map_or(false, |syn| syn.seq_nr == header.ack_nr);
+            if !syn_acked {
+                return Ok(());
+            }
+            self.connected = true;
+            // The peer's first packet is numbered as its answer was
+            self.ack_nr = header.seq_nr.wrapping_sub(1);
+            let mut timing = lock(&self.timing);
+            timing.arrivals = None;
+            timing.wake();
+        }
+
+        self.reply_micro = self.timestamp().wrapping_sub(header.timestamp);
+        self.peer_window = header.wnd_size as usize;
+        self.acked(&header, payload.is_empty())?;
+        match header.kind {
+            ST_DATA | ST_FIN => self.handle_packet(header.kind, header.seq_nr, payload),
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_packet(&mut self, kind: u8, seq_nr: u16, payload: &[u8]) -> io::Result<()> {
+        let ahead = seq_nr.wrapping_sub(self.ack_nr);
+        if !self.finished && ahead > 0 && ahead <= REORDER_WINDOW &&
+           self.received.len() < MAX_BUFFERED {
+            let _ = self.out_of_order
+                .entry(seq_nr)
+                .or_insert_with(|| (kind, payload.to_vec()));
+            loop {
+                let next = self.ack_nr.wrapping_add(1);
+                let (kind, payload) = match self.out_of_order.remove(&next) {
+                    Some(packet) => packet,
+                    None => break,
+                };
+                self.ack_nr = next;
+                if kind == ST_FIN {
+                    self.finished = true;
+                    self.out_of_order.clear();
+                    break;
+                }
+                self.received.extend(payload);
+            }
+        }
+        self.send_state()
+    }
+
+    fn acked(&mut self, header: &Header, bare: bool) -> io::Result<()> {
+        // Any answer at all shows the peer is still there
+        self.retransmits = 0;
+        let now = Instant::now();
+        let mut acked_bytes = 0;
+        let mut freed = false;
+        while self.unacked
+                  .front()
+                  .map_or(false, |packet| !before(header.ack_nr, packet.seq_nr)) {
+            if let Some(packet) = self.unacked.pop_front() {
+                if !packet.resent {
+                    self.sample_rtt(now.duration_since(packet.sent_at));
+                }
+                acked_bytes += packet.payload.len();
+                freed = true;
+            }
+        }
+
+        if !freed {
+            // The peer acking the same packet again as more arrive tells of the one after it
+            // having been lost
+            let next_lost = self.unacked
+                .front()
+                .map_or(false, |packet| packet.seq_nr == header.ack_nr.wrapping_add(1));
+            if !(bare && header.kind == ST_STATE && next_lost) {
+                return Ok(());
+            }
+            self.duplicate_acks += 1;
+            if self.duplicate_acks != DUPLICATE_ACKS {
+                return Ok(());
+            }
+            self.max_window = cmp::max(self.max_window / 2, MIN_WINDOW);
+            return self.resend_first(now);
+        }
+
+        self.duplicate_acks = 0;
+        self.in_flight -= acked_bytes;
+        if header.timestamp_diff != 0 {
+            self.update_window(header.timestamp_diff, acked_bytes, now);
+        }
+        self.schedule();
+        if self.blocked {
+            self.blocked = false;
+            lock(&self.timing).wake();
+        }
+        Ok(())
+    }
+
+    fn sample_rtt(&mut self, rtt: Duration) {
+        let sample = millis(rtt);
+        let (rtt, variation) = match self.rtt {
+            None => (sample, sample / 2),
+            Some((rtt, variation)) => {
+                let delta = if rtt > sample {
+                    rtt - sample
+                } else {
+                    sample - rtt
+                };
+                ((rtt * 7 + sample) / 8, (variation * 3 + delta) / 4)
+            }
+        };
+        self.rtt = Some((rtt, variation));
+        let rto = cmp::min(cmp::max(rtt + 4 * variation, MIN_RTO_MS), MAX_RTO_MS);
+        self.rto = Duration::from_millis(rto);
+    }
+
+    // Grow the window by how far the queuing delay of the path is below the target, or shrink it
+    // by how far it is above, in proportion to how much of the window `acked_bytes` is. The
+    // queuing delay is how much longer than the least seen lately our packets took to arrive.
+    fn update_window(&mut self, delay: u32, acked_bytes: usize, now: Instant) {
+        if self.base_delays.is_empty() ||
+           now.duration_since(self.base_delay_since) >= Duration::from_secs(60) {
+            self.base_delays.push_back(delay);
+            if self.base_delays.len() > BASE_DELAY_MINUTES {
+                let _ = self.base_delays.pop_front();
+            }
+            self.base_delay_since = now;
+        } else if let Some(least) = self.base_delays.back_mut() {
+            if earlier(delay, *least) {
+                *least = delay;
+            }
+        }
+        let base = self.base_delays
+            .iter()
+            .fold(delay,
+                  |base, &least| if earlier(least, base) { least } else { base });
+
+        let queuing = delay.wrapping_sub(base) as f64;
+        let off_target = (TARGET_DELAY_US as f64 - queuing) / TARGET_DELAY_US as f64;
+        let gain = MAX_WINDOW_GAIN as f64 * off_target * acked_bytes as f64 /
+                   self.max_window as f64;
+        let window = self.max_window as f64 + gain;
+        self.max_window = if window < MIN_WINDOW as f64 {
+            MIN_WINDOW
+        } else if window > MAX_WINDOW as f64 {
+            MAX_WINDOW
+        } else {
+            window as usize
+        };
+    }
+
+    fn retransmit(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        match self.unacked.front() {
+            Some(packet) if packet.sent_at + self.rto <= now => (),
+            _ => {
+                self.schedule();
+                return Ok(());
+            }
+        }
+        self.retransmits += 1;
+        let most = if self.connected {
+            MAX_RETRANSMITS
+        } else {
+            MAX_SYN_RETRANSMITS
+        };
+        if self.retransmits > most {
+            return Err(self.fail(ErrorKind::TimedOut));
+        }
+        // LEDBAT takes a timeout for the path being congested, down to a packet at a time
+        self.max_window = MIN_WINDOW;
+        self.rto = cmp::min(self.rto * 2, Duration::from_millis(MAX_RTO_MS));
+        self.resend_first(now)
+    }
+
+    // Send again the first of what is unacked, the rest waiting for another timeout from now.
+    fn resend_first(&mut self, now: Instant) -> io::Result<()> {
+        let res = match self.unacked.front() {
+            Some(packet) => self.send(packet.kind, packet.seq_nr, &packet.payload),
+            None => return Ok(()),
+        };
+        if let Err(e) = res {
+            return Err(self.fail(e.kind()));
+        }
+        for packet in &mut self.unacked {
+            packet.sent_at = now;
+            packet.resent = true;
+        }
+        self.schedule();
+        Ok(())
+    }
+}
+
+/// A stream over `Utp`.
+pub struct UtpStream {
+    conn: RefCell<Connection>,
+    // Made afresh on every registration, as one cannot move from one event loop to another.
+    registration: RefCell<Option<Registration>>,
+    _timers: Arc<Timers>,
+}
+
+impl UtpStream {
+    fn new(socket: UdpSocket,
+           peer_addr: SocketAddr,
+           recv_id: u16,
+           send_id: u16,
+           timers: &Arc<Timers>)
+           -> Self {
+        let timing = Arc::new(Mutex::new(Timing::new()));
+        timers.watch(&timing);
+        let now = Instant::now();
+        UtpStream {
+            conn: RefCell::new(Connection {
+                                   socket: socket,
+                                   peer_addr: peer_addr,
+                                   timing: timing,
+                                   epoch: now,
+                                   recv_id: recv_id,
+                                   send_id: send_id,
+                                   connected: false,
+                                   seq_nr: 1,
+                                   unacked: VecDeque::new(),
+                                   in_flight: 0,
+                                   max_window: INITIAL_WINDOW,
+                                   peer_window: INITIAL_WINDOW,
+                                   rtt: None,
+                                   rto: Duration::from_millis(INITIAL_RTO_MS),
+                                   retransmits: 0,
+                                   duplicate_acks: 0,
+                                   blocked: false,
+                                   base_delays: VecDeque::new(),
+                                   base_delay_since: now,
+                                   reply_micro: 0,
+                                   ack_nr: 0,
+                                   out_of_order: HashMap::new(),
+                                   received: VecDeque::new(),
+                                   finished: false,
+                                   closed: false,
+                                   error: None,
+                               }),
+            registration: RefCell::new(None),
+            _timers: timers.clone(),
+        }
+    }
+}
+
+impl Stream for UtpStream {
+    fn transport(&self) -> &'static str {
+        "utp"
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let conn = self.conn.get_mut();
+        conn.pump()?;
+        let closed = conn.window() < MAX_PAYLOAD;
+        let len = cmp::min(buf.len(), conn.received.len());
+        for (dst, src) in buf.iter_mut().zip(conn.received.drain(..len)) {
+            *dst = src;
+        }
+        // The peer is told once there is room again
+        if closed && conn.window() >= MAX_PAYLOAD {
+            conn.send_state()?;
+        }
+        if len > 0 || buf.is_empty() || conn.finished {
+            Ok(len)
+        } else {
+            Err(io::Error::new(ErrorKind::WouldBlock, "Nothing has arrived"))
+        }
+    }
+
+    fn write_bufs(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        let conn = self.conn.get_mut();
+        // Acks free room in the window, and tell that the stream is connected
+        conn.pump()?;
+        if !conn.received.is_empty() || conn.finished {
+            lock(&conn.timing).wake();
+        }
+        if conn.closed {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "Stream closed"));
+        }
+        if !conn.connected {
+            return Err(io::Error::new(ErrorKind::WouldBlock, "Still connecting"));
+        }
+
+        let room = conn.room();
+        let mut data = Vec::new();
+        for buf in bufs {
+            let len = cmp::min(buf.len(), room - data.len());
+            data.extend_from_slice(&buf[..len]);
+        }
+        let total: usize = bufs.iter().map(|buf| buf.len()).sum();
+        if data.len() < total {
+            conn.blocked = true;
+        }
+        if data.is_empty() && total > 0 {
+            return Err(io::Error::new(ErrorKind::WouldBlock, "The window is full"));
+        }
+        for chunk in data.chunks(MAX_PAYLOAD) {
+            conn.push(ST_DATA, chunk.to_vec())?;
+        }
+        Ok(data.len())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.conn.borrow().socket.local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.conn.borrow().peer_addr)
+    }
+
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        let conn = self.conn.borrow();
+        match conn.error {
+            Some(kind) => Ok(Some(io::Error::new(kind, "uTP stream failed"))),
+            None => conn.socket.take_error(),
+        }
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        let mut conn = self.conn.borrow_mut();
+        if conn.closed || conn.error.is_some() {
+            return Ok(());
+        }
+        conn.closed = true;
+        if conn.connected {
+            return conn.push(ST_FIN, Vec::new());
+        }
+        // Nor is the SYN sent again
+        conn.unacked.clear();
+        conn.schedule();
+        Ok(())
+    }
+
+    fn set_dscp(&self, dscp: u8) -> io::Result<()> {
+        let conn = self.conn.borrow();
+        set_dscp(&conn.socket, conn.socket.local_addr()?.is_ipv6(), dscp)
+    }
+}
+
+// Datagrams can always be sent, so the stream is reported writable by itself alone: once it has
+// connected and whenever acks make room in the window after a write was turned away.
+impl Evented for UtpStream {
+    fn register(&self,
+                poll: &Poll,
+                token: Token,
+                interest: Ready,
+                opts: PollOpt)
+                -> io::Result<()> {
+        let conn = self.conn.borrow();
+        conn.socket
+            .register(poll, token, interest - Ready::writable(), opts)?;
+        let (registration, readiness) = Registration::new2();
+        poll.register(&registration, token, interest, opts)?;
+        // What came in while on no event loop, as when moving to another, is picked up there
+        if !conn.received.is_empty() || conn.finished || conn.error.is_some() ||
+           (conn.connected && !conn.blocked) {
+            let _ = readiness.set_readiness(Ready::readable() | Ready::writable());
+        }
+        lock(&conn.timing).readiness = Some(readiness);
+        *self.registration.borrow_mut() = Some(registration);
+        Ok(())
+    }
+
+    fn reregister(&self,
+                  poll: &Poll,
+                  token: Token,
+                  interest: Ready,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        self.conn
+            .borrow()
+            .socket
+            .reregister(poll, token, interest - Ready::writable(), opts)?;
+        match *self.registration.borrow() {
+            Some(ref registration) => poll.reregister(registration, token, interest, opts),
+            None => Ok(()),
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        let conn = self.conn.borrow();
+        lock(&conn.timing).readiness = None;
+        if let Some(registration) = self.registration.borrow_mut().take() {
+            let _ = poll.deregister(&registration);
+        }
+        conn.socket.deregister(poll)
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let _ = Stream::shutdown(self);
+    }
+}
+
+/// Accepts streams over `Utp`.
+pub struct UtpListener {
+    socket: UdpSocket,
+    local_addr: SocketAddr,
+    // Peers accepted lately, whose SYNs sent again before their stream was set up may still
+    // arrive here.
+    accepted: RefCell<HashMap<SocketAddr, Instant>>,
+    timers: Arc<Timers>,
+}
+
+impl Listener for UtpListener {
+    fn accept(&self) -> io::Result<(Box<Stream>, SocketAddr)> {
+        let mut buf = [0; MAX_DATAGRAM];
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut buf)? {
+                Some(res) => res,
+                None => return Err(io::Error::new(ErrorKind::WouldBlock, "No stream to accept")),
+            };
+            let header = match Header::parse(&buf[..len]) {
+                Some((header, _)) => header,
+                None => continue,
+            };
+
+            let now = Instant::now();
+            let grace = Duration::from_secs(ACCEPT_GRACE_SECS);
+            let mut accepted = self.accepted.borrow_mut();
+            accepted.retain(|_, &mut at| now.duration_since(at) < grace);
+            if accepted.contains_key(&addr) {
+                continue;
+            }
+            if header.kind != ST_SYN {
+                // Left over from a stream which has since gone, as the peer is told
+                if header.kind != ST_RESET {
+                    let reset = Header {
+                        kind: ST_RESET,
+                        conn_id: header.conn_id,
+                        timestamp: 0,
+                        timestamp_diff: 0,
+                        wnd_size: 0,
+                        seq_nr: 0,
+                        ack_nr: header.seq_nr,
+                    };
+                    let _ = self.socket.send_to(&reset.encode(&[]), &addr);
+                }
+                continue;
+            }
+
+            let socket = reusably_bound_socket(&self.local_addr)?;
+            socket.connect(addr)?;
+            let stream = UtpStream::new(UdpSocket::from_socket(socket)?,
+                                        addr,
+                                        header.conn_id.wrapping_add(1),
+                                        header.conn_id,
+                                        &self.timers);
+            {
+                let mut conn = stream.conn.borrow_mut();
+                conn.connected = true;
+                conn.seq_nr = rand::random();
+                conn.ack_nr = header.seq_nr;
+                conn.reply_micro = conn.timestamp().wrapping_sub(header.timestamp);
+                conn.peer_window = header.wnd_size as usize;
+                conn.send_state()?;
+            }
+            let _ = accepted.insert(addr, now);
+            return Ok((Box::new(stream), addr));
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+impl Evented for UtpListener {
+    fn register(&self,
+                poll: &Poll,
+                token: Token,
+                interest: Ready,
+                opts: PollOpt)
+                -> io::Result<()> {
+        self.socket.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self,
+                  poll: &Poll,
+                  token: Token,
+                  interest: Ready,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        self.socket.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.socket.deregister(poll)
+    }
+}
+
+// Only where there are listeners to test against.
+#[cfg(all(test,
+          any(target_os = "linux",
+              target_os = "android",
+              target_os = "macos",
+              target_os = "ios",
+              target_os = "freebsd",
+              target_os = "dragonfly",
+              target_os = "netbsd",
+              target_os = "openbsd")))]
+mod tests {
+    use super::*;
+    use mio::Events;
+
+    // Poll until `f` gives something other than `WouldBlock`.
+    fn wait_for<T, F>(poll: &Poll, mut f: F) -> io::Result<T>
+        where F: FnMut() -> io::Result<T>
+    {
+        let mut events = Events::with_capacity(16);
+        for _ in 0..200 {
+            match f() {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+                res => return res,
+            }
+            let _ = unwrap!(poll.poll(&mut events, Some(Duration::from_millis(50))));
+        }
+        Err(io::Error::new(ErrorKind::TimedOut, "Timed out"))
+    }
+
+    #[test]
+    fn header() {
+        let header = Header {
+            kind: ST_DATA,
+            conn_id: 0xfffe,
+            timestamp: 1,
+            timestamp_diff: 2,
+            wnd_size: 3,
+            seq_nr: 4,
+            ack_nr: 5,
+        };
+        let mut packet = header.encode(b"data");
+        assert_eq!(packet.len(), HEADER_LEN + 4);
+        assert_eq!(packet[0], 0x01);
+        assert_eq!(Header::parse(&packet), Some((header, &b"data"[..])));
+
+        // Extensions are skipped, a selective ack here
+        packet[1] = 1;
+        let mut extended = packet[..HEADER_LEN].to_vec();
+        extended.extend_from_slice(&[0, 4, 0xff, 0xff, 0xff, 0xff]);
+        extended.extend_from_slice(b"data");
+        assert_eq!(Header::parse(&extended), Some((header, &b"data"[..])));
+        assert_eq!(Header::parse(&extended[..HEADER_LEN + 3]), None);
+
+        packet[0] = 0x02;
+        assert_eq!(Header::parse(&packet), None);
+        assert!(before(0xffff, 0));
+        assert!(!before(0, 0xffff));
+    }
+
+    #[test]
+    fn utp() {
+        let poll = unwrap!(Poll::new());
+        let transport = Utp::new();
+        let listener = unwrap!(transport.listen(&unwrap!("127.0.0.1:0".parse())));
+        unwrap!(poll.register(&*listener, Token(0), Ready::readable(), PollOpt::edge()));
+        let mut stream = unwrap!(transport.dial(&unwrap!(listener.local_addr())));
+        unwrap!(poll.register(&stream,
+                              Token(1),
+                              Ready::readable() | Ready::writable(),
+                              PollOpt::edge()));
+        assert_eq!(stream.transport(), transport.name());
+        assert_eq!(unwrap!(stream.write_bufs(&[b"early"]).err()).kind(),
+                   ErrorKind::WouldBlock);
+
+        let (mut accepted, addr) = unwrap!(wait_for(&poll, || listener.accept()));
+        assert_eq!(addr, unwrap!(stream.local_addr()));
+        assert_eq!(unwrap!(accepted.peer_addr()), unwrap!(stream.local_addr()));
+        unwrap!(poll.register(&*accepted,
+                              Token(2),
+                              Ready::readable() | Ready::writable(),
+                              PollOpt::edge()));
+
+        // More than fits in one packet, so that it has to be put back together in order, and
+        // more than the initial window, so that it takes acks to go out
+        let data: Vec<u8> = (0..10 * MIN_DATAGRAM).map(|i| i as u8).collect();
+        let mut written = 0;
+        let mut received = Vec::new();
+        let mut buf = [0; 4096];
+        while received.len() < data.len() {
+            let len = unwrap!(wait_for(&poll, || {
+                if written < data.len() {
+                    match stream.write_bufs(&[&data[written..]]) {
+                        Ok(len) => written += len,
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+                        Err(e) => return Err(e),
+                    }
+                }
+                accepted.read(&mut buf)
+            }));
+            received.extend_from_slice(&buf[..len]);
+        }
+        assert_eq!(received, data);
+        assert!(unwrap!(stream.conn.get_mut().rtt).0 < MAX_RTO_MS);
+
+        // Answered over the socket connected to the peer rather than the listening one
+        assert_eq!(unwrap!(accepted.write_bufs(&[b"pong"])), 4);
+        let len = unwrap!(wait_for(&poll, || stream.read(&mut buf)));
+        assert_eq!(&buf[..len], b"pong");
+        unwrap!(wait_for(&poll, || {
+            let _ = unwrap!(accepted.read(&mut []));
+            if stream.conn.get_mut().unacked.is_empty() {
+                Ok(())
+            } else {
+                Err(io::Error::new(ErrorKind::WouldBlock, "Still unacked"))
+            }
+        }));
+
+        unwrap!(accepted.shutdown());
+        assert_eq!(unwrap!(wait_for(&poll, || stream.read(&mut buf))), 0);
+    }
+
+    #[test]
+    fn connect() {
+        let poll = unwrap!(Poll::new());
+        let transport = Utp::new();
+        let listener = unwrap!(transport.listen(&unwrap!("127.0.0.1:0".parse())));
+        unwrap!(poll.register(&*listener, Token(0), Ready::readable(), PollOpt::edge()));
+        let mut stream = unwrap!(transport.dial(&unwrap!(listener.local_addr())));
+        unwrap!(poll.register(&stream, Token(1), Ready::writable(), PollOpt::edge()));
+
+        // Not writable until the listener answers, which it is told of without being read
+        let mut events = Events::with_capacity(16);
+        let _ = unwrap!(poll.poll(&mut events, Some(Duration::from_millis(200))));
+        assert!(events.iter().all(|event| event.token() != Token(1)));
+        let _accepted = unwrap!(wait_for(&poll, || listener.accept()));
+        let mut writable = false;
+        for _ in 0..40 {
+            let _ = unwrap!(poll.poll(&mut events, Some(Duration::from_millis(50))));
+            if events
+                   .iter()
+                   .any(|event| event.token() == Token(1) && event.readiness().is_writable()) {
+                writable = true;
+                break;
+            }
+        }
+        assert!(writable);
+        assert_eq!(unwrap!(stream.write_bufs(&[b"ping"])), 4);
+    }
+
+    #[test]
+    fn ledbat() {
+        let mut stream = unwrap!(Utp::new().dial(&unwrap!("127.0.0.1:9".parse())));
+        let conn = stream.conn.get_mut();
+        let now = Instant::now();
+
+        // With no queuing delay the window grows
+        conn.update_window(0xffff_fff0, MAX_PAYLOAD, now);
+        assert!(conn.max_window > INITIAL_WINDOW);
+        // As it does with the delay wrapping around the clock, but less
+        let grown = conn.max_window;
+        conn.update_window(0x10, MAX_PAYLOAD, now);
+        assert!(conn.max_window > grown && conn.max_window - grown < grown - INITIAL_WINDOW);
+        // Past the target it shrinks, though never below a packet
+        let grown = conn.max_window;
+        conn.update_window(0xffff_fff0u32.wrapping_add(3 * TARGET_DELAY_US), MAX_PAYLOAD, now);
+        assert!(conn.max_window < grown);
+        for _ in 0..100 {
+            conn.update_window(0xffff_fff0u32.wrapping_add(3 * TARGET_DELAY_US), MAX_WINDOW, now);
+        }
+        assert_eq!(conn.max_window, MIN_WINDOW);
+
+        // The base delay is forgotten once older than the minutes kept
+        conn.update_window(5 * TARGET_DELAY_US, 0, now + Duration::from_secs(60));
+        conn.update_window(5 * TARGET_DELAY_US, 0, now + Duration::from_secs(120));
+        assert_eq!(conn.base_delays, vec![5 * TARGET_DELAY_US; 2]);
+    }
+
+    #[test]
+    fn reset() {
+        let poll = unwrap!(Poll::new());
+        let listener = unwrap!(Utp::new().listen(&unwrap!("127.0.0.1:0".parse())));
+        unwrap!(poll.register(&*listener, Token(0), Ready::readable(), PollOpt::edge()));
+
+        // As if from a stream opened before the listener lost track of it
+        let peer = unwrap!(net::UdpSocket::bind("127.0.0.1:0"));
+        unwrap!(peer.set_read_timeout(Some(Duration::from_millis(50))));
+        let data = Header {
+            kind: ST_DATA,
+            conn_id: 7,
+            timestamp: 0,
+            timestamp_diff: 0,
+            wnd_size: 0,
+            seq_nr: 2,
+            ack_nr: 0,
+        };
+        let _ = unwrap!(peer.send_to(&data.encode(b"ping"), unwrap!(listener.local_addr())));
+        let mut buf = [0; MAX_DATAGRAM];
+        let (len, _) = unwrap!(wait_for(&poll, || {
+            assert_eq!(unwrap!(listener.accept().err()).kind(), ErrorKind::WouldBlock);
+            peer.recv_from(&mut buf)
+        }));
+        let (reset, _) = unwrap!(Header::parse(&buf[..len]));
+        assert_eq!(reset.kind, ST_RESET);
+        assert_eq!(reset.conn_id, 7);
+    }
+}
@@ -0,0 +1,895 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use byteorder::{BigEndian, ByteOrder};
+use common::{Handshake, Listener, Reply, SocketConfig, Stream, Transport, set_dscp};
+use common::http_connect::base64;
+use iovec::IoVec;
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use mio::tcp::{Shutdown, TcpListener, TcpStream};
+use rand;
+use std::cell::Cell;
+use std::cmp;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::str;
+
+// What the request of a peer opening a WebSocket starts with, which no message of a peer speaking
+// crust directly does, as those start with their length.
+const UPGRADE_PREFIX: &'static [u8] = b"GET ";
+// Longest request or response head to wait for.
+const MAX_HEAD: usize = 8 * 1024;
+// Appended to the key of a request to derive the key its response accepts it with.
+const ACCEPT_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+// Most payload of a control frame, and of a frame of ours.
+const MAX_CONTROL_PAYLOAD: u64 = 125;
+const MAX_FRAME_PAYLOAD: usize = 64 * 1024;
+
+/// WebSocket (RFC 6455), for peers on networks which let little but web traffic through: streams
+/// open with an HTTP upgrade and carry what crust sends in binary frames. Listeners take peers
+/// speaking crust directly over TCP as well, telling them apart by what they send first, so with
+/// `Tcp` added as a fallback transport peers are kept in touch with whichever they speak. Streams
+/// set up by hole punching have no end to play the client, so they carry crust directly, as over
+/// `Tcp`. Only `ws://` is spoken, there being no TLS in crust to speak `wss://` with.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WebSocket;
+
+impl Transport for WebSocket {
+    fn name(&self) -> &'static str {
+        "websocket"
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Box<Stream>> {
+        let handshake = ClientHandshake {
+            host: *addr,
+            key: base64(&rand::random::<[u8; 16]>()),
+        };
+        Ok(Box::new(WsStream::client(TcpStream::connect(addr)?, handshake)))
+    }
+
+    fn listen(&self, addr: &SocketAddr) -> io::Result<Box<Listener>> {
+        Ok(Box::new(WsListener { listener: TcpListener::bind(addr)? }))
+    }
+
+    fn nat_traversal(&self) -> bool {
+        true
+    }
+
+    fn adopt_listener(&self, listener: TcpListener) -> io::Result<Box<Listener>> {
+        Ok(Box::new(WsListener { listener: listener }))
+    }
+}
+
+struct ClientHandshake {
+    host: SocketAddr,
+    key: String,
+}
+
+impl Handshake for ClientHandshake {
+    fn start(&mut self) -> Vec<u8> {
+        format!("GET / HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+                self.host,
+                self.key)
+                .into_bytes()
+    }
+
+    fn reply(&mut self, incoming: &[u8]) -> io::Result<Reply> {
+        let (head_len, head) = match parse_head(incoming)? {
+            Some(head) => head,
+            None => return Ok(Reply::Incomplete),
+        };
+        let status_line = head.lines().next().unwrap_or("");
+        if !status_line.starts_with("HTTP/1.1 101") {
+            return Err(io::Error::new(ErrorKind::ConnectionRefused,
+                                      format!("WebSocket upgrade refused: {}", status_line)));
+        }
+        let accepted = header(head, "sec-websocket-accept") == Some(accept(&self.key).as_str());
+        if !is_upgrade(head) || !accepted {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Invalid WebSocket upgrade"));
+        }
+        Ok(Reply::Done(head_len))
+    }
+}
+
+// Answers the upgrade request of a peer, and should it be one we cannot take, fails once the peer
+// has been told so.
+#[derive(Default)]
+struct ServerHandshake {
+    answered: bool,
+    refused: bool,
+}
+
+impl Handshake for ServerHandshake {
+    fn start(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn reply(&mut self, incoming: &[u8]) -> io::Result<Reply> {
+        if self.refused {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Invalid WebSocket upgrade"));
+        }
+        if self.answered {
+            return Ok(Reply::Done(0));
+        }
+        let (head_len, head) = match parse_head(incoming)? {
+            Some(head) => head,
+            None => return Ok(Reply::Incomplete),
+        };
+        self.answered = true;
+        let key = match header(head, "sec-websocket-key") {
+            Some(key) if is_upgrade(head) &&
+                         header(head, "sec-websocket-version") == Some("13") => key,
+            _ => {
+                self.refused = true;
+                let response = b"HTTP/1.1 400 Bad Request\r\nSec-WebSocket-Version: 13\r\n\
+                                 Content-Length: 0\r\n\r\n";
+                return Ok(Reply::Continue(head_len, response.to_vec()));
+            }
+        };
+        let response = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                                Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                               accept(key));
+        Ok(Reply::Continue(head_len, response.into_bytes()))
+    }
+}
+
+// The length of the request or response head `incoming` starts with and the head itself, once it
+// is all in.
+fn parse_head(incoming: &[u8]) -> io::Result<Option<(usize, &str)>> {
+    let head_len = match incoming.windows(4).position(|end| end == b"\r\n\r\n") {
+        Some(pos) => pos + 4,
+        None if incoming.len() > MAX_HEAD => {
+            return Err(io::Error::new(ErrorKind::InvalidData, "HTTP head too long"))
+        }
+        None => return Ok(None),
+    };
+    match str::from_utf8(&incoming[..head_len]) {
+        Ok(head) => Ok(Some((head_len, head))),
+        Err(_) => Err(io::Error::new(ErrorKind::InvalidData, "Invalid HTTP head")),
+    }
+}
+
+// The value of header `name`, given in lower case, in `head`.
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines()
+        .skip(1)
+        .filter_map(|line| {
+                        let mut parts = line.splitn(2, ':');
+                        match (parts.next(), parts.next()) {
+                            (Some(field), Some(value)) => Some((field, value)),
+                            _ => None,
+                        }
+                    })
+        .find(|&(field, _)| field.trim().to_lowercase() == name)
+        .map(|(_, value)| value.trim())
+}
+
+fn is_upgrade(head: &str) -> bool {
+    let upgrade = header(head, "upgrade").map(str::to_lowercase);
+    let connection = header(head, "connection").map(str::to_lowercase);
+    upgrade.as_ref().map(String::as_str) == Some("websocket") &&
+    connection.map_or(false, |connection| connection.contains("upgrade"))
+}
+
+// The key a response accepts the request with `key` with.
+fn accept(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()))
+}
+
+// SHA-1, which nothing but the upgrade uses, and only as the protocol has it.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476, 0xc3d2_e1f0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    let mut bits = [0; 8];
+    BigEndian::write_u64(&mut bits, data.len() as u64 * 8);
+    message.extend_from_slice(&bits);
+
+    for block in message.chunks(64) {
+        let mut words = [0u32; 80];
+        for i in 0..16 {
+            words[i] = BigEndian::read_u32(&block[4 * i..4 * i + 4]);
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+        let (mut a, mut b, mut c, mut d, mut e) =
+            (state[0], state[1], state[2], state[3], state[4]);
+        for (i, &word) in words.iter().enumerate() {
+            let (f, k) = match i {
+                0...19 => ((b & c) | (!b & d), 0x5a82_7999),
+                20...39 => (b ^ c ^ d, 0x6ed9_eba1),
+                40...59 => ((b & c) | (b & d) | (c & d), 0x8f1b_bcdc),
+                _ => (b ^ c ^ d, 0xca62_c1d6),
+            };
+            let temp = a.rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (word, add) in state.iter_mut().zip(&[a, b, c, d, e]) {
+            *word = word.wrapping_add(*add);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (i, word) in state.iter().enumerate() {
+        BigEndian::write_u32(&mut digest[4 * i..4 * i + 4], *word);
+    }
+    digest
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FrameHead {
+    fin: bool,
+    opcode: u8,
+    len: u64,
+    mask: Option<[u8; 4]>,
+}
+
+impl FrameHead {
+    fn encode(&self) -> Vec<u8> {
+        let mut head = vec![if self.fin { 0x80 } else { 0 } | self.opcode];
+        let masked = if self.mask.is_some() { 0x80 } else { 0 };
+        if self.len < 126 {
+            head.push(masked | self.len as u8);
+        } else if self.len <= 0xffff {
+            head.push(masked | 126);
+            let mut len = [0; 2];
+            BigEndian::write_u16(&mut len, self.len as u16);
+            head.extend_from_slice(&len);
+        } else {
+            head.push(masked | 127);
+            let mut len = [0; 8];
+            BigEndian::write_u64(&mut len, self.len);
+            head.extend_from_slice(&len);
+        }
+        if let Some(mask) = self.mask {
+            head.extend_from_slice(&mask);
+        }
+        head
+    }
+
+    // The head `incoming` starts with and its length, once it is all in.
+    fn parse(incoming: &[u8]) -> io::Result<Option<(FrameHead, usize)>> {
+        if incoming.len() < 2 {
+            return Ok(None);
+        }
+        // No extensions are agreed on, which would give the reserved bits a meaning
+        if incoming[0] & 0x70 != 0 {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Unexpected WebSocket extension"));
+        }
+        let (len, mut head_len) = match incoming[1] & 0x7f {
+            126 if incoming.len() >= 4 => (BigEndian::read_u16(&incoming[2..4]) as u64, 4),
+            127 if incoming.len() >= 10 => (BigEndian::read_u64(&incoming[2..10]), 10),
+            126 | 127 => return Ok(None),
+            len => (len as u64, 2),
+        };
+        let mask = if incoming[1] & 0x80 == 0 {
+            None
+        } else if incoming.len() >= head_len + 4 {
+            let mut mask = [0; 4];
+            mask.copy_from_slice(&incoming[head_len..head_len + 4]);
+            head_len += 4;
+            Some(mask)
+        } else {
+            return Ok(None);
+        };
+        let head = FrameHead {
+            fin: incoming[0] & 0x80 != 0,
+            opcode: incoming[0] & 0x0f,
+            len: len,
+            mask: mask,
+        };
+        Ok(Some((head, head_len)))
+    }
+}
+
+fn apply_mask(mask: Option<[u8; 4]>, offset: u64, data: &mut [u8]) {
+    if let Some(mask) = mask {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte ^= mask[((offset + i as u64) % 4) as usize];
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Phase {
+    // Accepted, and not yet known to be opened as a WebSocket or spoken to in crust directly.
+    Sniffing,
+    Handshaking,
+    Open,
+    // Spoken to in crust directly, which is passed through as it is.
+    Direct,
+}
+
+/// A stream over `WebSocket`. The handshake is driven by reading and writing the stream as for
+/// any other, which report `WouldBlock` until it is done.
+pub struct WsStream {
+    stream: TcpStream,
+    // Whether we opened the stream, so mask what we send and take in frames unmasked.
+    client: bool,
+    phase: Phase,
+    handshake: Option<Box<Handshake>>,
+    // What is yet to be parsed or, while speaking crust directly, read.
+    incoming: Vec<u8>,
+    // The frame being taken in: how much of its payload is still to come and how much has come.
+    frame_left: u64,
+    frame_mask: Option<[u8; 4]>,
+    frame_offset: u64,
+    // Whether the peer has closed the WebSocket.
+    closed: bool,
+    // Handshake, frame heads and control frames yet to be written, and control frames waiting
+    // for the frame being written to end.
+    outgoing: Vec<u8>,
+    control: Vec<u8>,
+    // The frame being written: how much of its payload is still to go and how much has gone.
+    owed: u64,
+    owed_mask: Option<[u8; 4]>,
+    owed_offset: u64,
+    // Once the handshake is done, neither what was read along with it nor the writes refused
+    // until then would be reported ready by the socket again, so this reports them.
+    registration: Registration,
+    readiness: SetReadiness,
+    registered: Cell<bool>,
+}
+
+impl WsStream {
+    fn new(stream: TcpStream, client: bool, phase: Phase) -> Self {
+        let (registration, readiness) = Registration::new2();
+        WsStream {
+            stream: stream,
+            client: client,
+            phase: phase,
+            handshake: None,
+            incoming: Vec::new(),
+            frame_left: 0,
+            frame_mask: None,
+            frame_offset: 0,
+            closed: false,
+            outgoing: Vec::new(),
+            control: Vec::new(),
+            owed: 0,
+            owed_mask: None,
+            owed_offset: 0,
+            registration: registration,
+            readiness: readiness,
+            registered: Cell::new(false),
+        }
+    }
+
+    fn client(stream: TcpStream, mut handshake: ClientHandshake) -> Self {
+        let mut ws = WsStream::new(stream, true, Phase::Handshaking);
+        ws.outgoing = handshake.start();
+        ws.handshake = Some(Box::new(handshake));
+        ws
+    }
+
+    // Take in what the peer has sent, returning whether anything came.
+    fn fill(&mut self, len: usize) -> io::Result<bool> {
+        let mut buf = vec![0; cmp::max(len, 1024)];
+        match Read::read(&mut self.stream, &mut buf) {
+            Ok(0) => Err(io::Error::new(ErrorKind::UnexpectedEof, "Peer closed the stream")),
+            Ok(len) => {
+                self.incoming.extend_from_slice(&buf[..len]);
+                Ok(true)
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Write what is waiting to be, returning whether all of it went.
+    fn flush(&mut self) -> io::Result<bool> {
+        if self.owed == 0 && !self.control.is_empty() {
+            self.outgoing.append(&mut self.control);
+        }
+        while !self.outgoing.is_empty() {
+            match Write::write(&mut self.stream, &self.outgoing) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "Peer closed the stream")),
+                Ok(written) => {
+                    let _ = self.outgoing.drain(..written);
+                }
+                // Still connecting
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                              e.kind() == ErrorKind::NotConnected => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    // Take the handshake as far as the peer lets us, returning whether the stream is open.
+    fn open(&mut self) -> io::Result<bool> {
+        loop {
+            match self.phase {
+                Phase::Open | Phase::Direct => {
+                    let _ = self.readiness
+                        .set_readiness(Ready::readable() | Ready::writable());
+                    return Ok(true);
+                }
+                Phase::Sniffing => {
+                    let len = cmp::min(self.incoming.len(), UPGRADE_PREFIX.len());
+                    if self.incoming[..len] != UPGRADE_PREFIX[..len] {
+                        self.phase = Phase::Direct;
+                    } else if len == UPGRADE_PREFIX.len() {
+                        self.phase = Phase::Handshaking;
+                        self.handshake = Some(Box::new(ServerHandshake::default()));
+                    } else if !self.fill(UPGRADE_PREFIX.len())? {
+                        return Ok(false);
+                    }
+                }
+                Phase::Handshaking => {
+                    if !self.flush()? {
+                        return Ok(false);
+                    }
+                    let reply = match self.handshake {
+                        Some(ref mut handshake) => handshake.reply(&self.incoming)?,
+                        None => Reply::Done(0),
+                    };
+                    match reply {
+                        Reply::Incomplete => {
+                            if !self.fill(MAX_HEAD)? {
+                                return Ok(false);
+                            }
+                        }
+                        Reply::Continue(len, next) => {
+                            let _ = self.incoming.drain(..len);
+                            self.outgoing = next;
+                        }
+                        Reply::Done(len) => {
+                            let _ = self.incoming.drain(..len);
+                            self.handshake = None;
+                            self.phase = Phase::Open;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Take the payload of the data frames in what has come into `buf`, acting on control frames
+    // along the way.
+    fn decode(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pos = 0;
+        let mut written = 0;
+        while pos < self.incoming.len() && !self.closed {
+            if self.frame_left > 0 {
+                let len = cmp::min(cmp::min(self.incoming.len() - pos, buf.len() - written) as u64,
+                                   self.frame_left) as usize;
+                if len == 0 {
+                    break;
+                }
+                buf[written..written + len].copy_from_slice(&self.incoming[pos..pos + len]);
+                apply_mask(self.frame_mask,
+                           self.frame_offset,
+                           &mut buf[written..written + len]);
+                pos += len;
+                written += len;
+                self.frame_left -= len as u64;
+                self.frame_offset += len as u64;
+                continue;
+            }
+
+            let (head, head_len) = match FrameHead::parse(&self.incoming[pos..])? {
+                Some(head) => head,
+                None => break,
+            };
+            // Only what clients send is masked
+            if head.mask.is_some() == self.client {
+                return Err(io::Error::new(ErrorKind::InvalidData, "Wrongly masked frame"));
+            }
+            match head.opcode {
+                OP_CONTINUATION | OP_BINARY => {
+                    pos += head_len;
+                    self.frame_left = head.len;
+                    self.frame_mask = head.mask;
+                    self.frame_offset = 0;
+                }
+                OP_CLOSE | OP_PING | OP_PONG if head.fin && head.len <= MAX_CONTROL_PAYLOAD => {
+                    let end = head_len + head.len as usize;
+                    if self.incoming.len() - pos < end {
+                        break;
+                    }
+                    let mut payload = self.incoming[pos + head_len..pos + end].to_vec();
+                    apply_mask(head.mask, 0, &mut payload);
+                    pos += end;
+                    match head.opcode {
+                        OP_PING => self.send_control(OP_PONG, &payload),
+                        OP_CLOSE => {
+                            // Echoing the status code given, if any
+                            let status_len = cmp::min(payload.len(), 2);
+                            self.send_control(OP_CLOSE, &payload[..status_len]);
+                            self.closed = true;
+                        }
+                        _ => (),
+                    }
+                }
+                OP_TEXT => {
+                    return Err(io::Error::new(ErrorKind::InvalidData,
+                                              "Text frame on a crust WebSocket"))
+                }
+                _ => return Err(io::Error::new(ErrorKind::InvalidData, "Invalid frame")),
+            }
+        }
+        let _ = self.incoming.drain(..pos);
+        Ok(written)
+    }
+
+    fn send_control(&mut self, opcode: u8, payload: &[u8]) {
+        let mask = if self.client {
+            Some(rand::random())
+        } else {
+            None
+        };
+        let head = FrameHead {
+            fin: true,
+            opcode: opcode,
+            len: payload.len() as u64,
+            mask: mask,
+        };
+        let mut payload = payload.to_vec();
+        apply_mask(mask, 0, &mut payload);
+        self.control.extend(head.encode());
+        self.control.extend(payload);
+    }
+
+    // While waiting for the peer, the socket being writable is no reason to wake up.
+    fn socket_interest(&self, interest: Ready) -> Ready {
+        match self.phase {
+            Phase::Sniffing | Phase::Handshaking if self.outgoing.is_empty() => {
+                interest - Ready::writable()
+            }
+            _ => interest,
+        }
+    }
+}
+
+impl Stream for WsStream {
+    fn transport(&self) -> &'static str {
+        if self.phase == Phase::Direct {
+            "tcp"
+        } else {
+            "websocket"
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.open()? {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        if self.phase == Phase::Direct {
+            if self.incoming.is_empty() {
+                return Read::read(&mut self.stream, buf);
+            }
+            let len = cmp::min(buf.len(), self.incoming.len());
+            buf[..len].copy_from_slice(&self.incoming[..len]);
+            let _ = self.incoming.drain(..len);
+            return Ok(len);
+        }
+
+        loop {
+            let len = self.decode(buf)?;
+            // Answers to control frames go as soon as they can
+            let _ = self.flush()?;
+            if len > 0 || buf.is_empty() || self.closed {
+                return Ok(len);
+            }
+            match self.fill(buf.len()) {
+                Ok(true) => (),
+                Ok(false) => return Err(ErrorKind::WouldBlock.into()),
+                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(0),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn write_bufs(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        if !self.open()? {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        if self.phase == Phase::Direct {
+            let bufs: Vec<&IoVec> = bufs.iter().map(|&buf| buf.into()).collect();
+            return TcpStream::write_bufs(&self.stream, &bufs);
+        }
+        if self.closed {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "Peer closed the WebSocket"));
+        }
+        // What is left of a frame head goes first
+        if !self.flush()? {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        let total: usize = bufs.iter().map(|buf| buf.len()).sum();
+        if total == 0 {
+            return Ok(0);
+        }
+        if self.owed == 0 {
+            self.owed = cmp::min(total, MAX_FRAME_PAYLOAD) as u64;
+            self.owed_mask = if self.client {
+                Some(rand::random())
+            } else {
+                None
+            };
+            self.owed_offset = 0;
+            let head = FrameHead {
+                fin: true,
+                opcode: OP_BINARY,
+                len: self.owed,
+                mask: self.owed_mask,
+            };
+            self.outgoing = head.encode();
+            if !self.flush()? {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+        }
+
+        let mut payload = Vec::new();
+        for buf in bufs {
+            let len = cmp::min(buf.len() as u64, self.owed - payload.len() as u64) as usize;
+            payload.extend_from_slice(&buf[..len]);
+        }
+        apply_mask(self.owed_mask, self.owed_offset, &mut payload);
+        let written = Write::write(&mut self.stream, &payload)?;
+        self.owed -= written as u64;
+        self.owed_offset += written as u64;
+        Ok(written)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(&self.stream)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(&self.stream)
+    }
+
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        TcpStream::take_error(&self.stream)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(&self.stream, Shutdown::Both)
+    }
+
+    fn configure(&self, config: &SocketConfig) -> io::Result<()> {
+        config.apply(&self.stream)
+    }
+
+    fn set_dscp(&self, dscp: u8) -> io::Result<()> {
+        set_dscp(&self.stream, TcpStream::local_addr(&self.stream)?.is_ipv6(), dscp)
+    }
+}
+
+// The registration only matters during the handshake, so a stream moved to another event loop
+// once open leaves it behind.
+impl Evented for WsStream {
+    fn register(&self,
+                poll: &Poll,
+                token: Token,
+                interest: Ready,
+                opts: PollOpt)
+                -> io::Result<()> {
+        self.stream
+            .register(poll, token, self.socket_interest(interest), opts)?;
+        if self.phase == Phase::Sniffing || self.phase == Phase::Handshaking {
+            self.registration.register(poll, token, interest, opts)?;
+            self.registered.set(true);
+        }
+        Ok(())
+    }
+
+    fn reregister(&self,
+                  poll: &Poll,
+                  token: Token,
+                  interest: Ready,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        self.stream
+            .reregister(poll, token, self.socket_interest(interest), opts)?;
+        if self.registered.get() {
+            self.registration.reregister(poll, token, interest, opts)?;
+        }
+        Ok(())
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        if self.registered.get() {
+            let _ = self.registration.deregister(poll);
+            self.registered.set(false);
+        }
+        self.stream.deregister(poll)
+    }
+}
+
+/// Accepts streams over `WebSocket`, and from peers speaking crust directly over TCP.
+pub struct WsListener {
+    listener: TcpListener,
+}
+
+impl Listener for WsListener {
+    fn accept(&self) -> io::Result<(Box<Stream>, SocketAddr)> {
+        let (stream, addr) = self.listener.accept()?;
+        Ok((Box::new(WsStream::new(stream, false, Phase::Sniffing)), addr))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+impl Evented for WsListener {
+    fn register(&self,
+                poll: &Poll,
+                token: Token,
+                interest: Ready,
+                opts: PollOpt)
+                -> io::Result<()> {
+        self.listener.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self,
+                  poll: &Poll,
+                  token: Token,
+                  interest: Ready,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        self.listener.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.listener.deregister(poll)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio::Events;
+    use std::time::{Duration, Instant};
+
+    // Call `op` whenever `poll` wakes up until it stops reporting `WouldBlock`.
+    fn wait_for<T, F: FnMut() -> io::Result<T>>(poll: &Poll, mut op: F) -> T {
+        let mut events = Events::with_capacity(16);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            match op() {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+                res => return unwrap!(res),
+            }
+            let _ = unwrap!(poll.poll(&mut events, Some(Duration::from_millis(100))));
+        }
+        panic!("Timed out");
+    }
+
+    // Send `data` from `from` to `to`, reading as it goes so neither end waits on the other.
+    fn transfer(poll: &Poll, from: &mut Box<Stream>, to: &mut Box<Stream>, data: &[u8]) {
+        let mut events = Events::with_capacity(16);
+        let mut written = 0;
+        let mut received = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            while written < data.len() {
+                match from.write_bufs(&[&data[written..]]) {
+                    Ok(len) => written += len,
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => panic!("{:?}", e),
+                }
+            }
+            loop {
+                let mut buf = [0; 4096];
+                match to.read(&mut buf) {
+                    Ok(0) => panic!("Stream closed"),
+                    Ok(len) => received.extend_from_slice(&buf[..len]),
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => panic!("{:?}", e),
+                }
+            }
+            if received.len() == data.len() {
+                assert_eq!(received, data);
+                return;
+            }
+            let _ = unwrap!(poll.poll(&mut events, Some(Duration::from_millis(100))));
+        }
+        panic!("Timed out");
+    }
+
+    #[test]
+    fn accept_key() {
+        let digest: Vec<String> = sha1(b"abc").iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(digest.concat(), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        // The example of RFC 6455
+        assert_eq!(accept("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn frame_heads() {
+        for &len in &[0, 125, 126, 0xffff, 0x10000] {
+            for &mask in &[None, Some([1, 2, 3, 4])] {
+                let head = FrameHead {
+                    fin: true,
+                    opcode: OP_BINARY,
+                    len: len,
+                    mask: mask,
+                };
+                let encoded = head.encode();
+                assert_eq!(unwrap!(FrameHead::parse(&encoded)), Some((head, encoded.len())));
+                assert_eq!(unwrap!(FrameHead::parse(&encoded[..encoded.len() - 1])), None);
+            }
+        }
+        assert!(FrameHead::parse(&[0xc2, 0]).is_err());
+    }
+
+    #[test]
+    fn bad_request() {
+        let mut handshake = ServerHandshake::default();
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        match unwrap!(handshake.reply(request)) {
+            Reply::Continue(len, response) => {
+                assert_eq!(len, request.len());
+                assert!(response.starts_with(b"HTTP/1.1 400"));
+            }
+            _ => panic!("Request not answered"),
+        }
+        assert!(handshake.reply(&[]).is_err());
+    }
+
+    #[test]
+    fn websocket() {
+        let poll = unwrap!(Poll::new());
+        let listener = unwrap!(WebSocket.listen(&unwrap!("127.0.0.1:0".parse())));
+        let addr = unwrap!(listener.local_addr());
+        unwrap!(poll.register(&*listener, Token(0), Ready::readable(), PollOpt::level()));
+
+        let mut client = unwrap!(WebSocket.connect(&addr));
+        unwrap!(poll.register(&*client,
+                              Token(1),
+                              Ready::readable() | Ready::writable(),
+                              PollOpt::level()));
+        let (mut server, _) = wait_for(&poll, || listener.accept());
+        unwrap!(poll.register(&*server, Token(2), Ready::readable(), PollOpt::level()));
+
+        // More than fits in one frame, to the server and back
+        let data: Vec<u8> = (0..150_000).map(|i| i as u8).collect();
+        transfer(&poll, &mut client, &mut server, &data);
+        assert_eq!(server.transport(), "websocket");
+        transfer(&poll, &mut server, &mut client, b"reply");
+
+        // Peers speaking crust directly are let through as they are
+        let mut direct: Box<Stream> = Box::new(unwrap!(TcpStream::connect(&addr)));
+        unwrap!(poll.register(&*direct, Token(3), Ready::writable(), PollOpt::level()));
+        let (mut accepted, _) = wait_for(&poll, || listener.accept());
+        unwrap!(poll.register(&*accepted, Token(4), Ready::readable(), PollOpt::level()));
+        transfer(&poll, &mut direct, &mut accepted, &[0, 0, 0, 5]);
+        assert_eq!(accepted.transport(), "tcp");
+    }
+}
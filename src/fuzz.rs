@@ -0,0 +1,146 @@
+// Copyright 2017 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Entry points into the parsers of the wire protocol for the targets under `fuzz/`, which feed
+//! them arbitrary bytes. None of them may panic, hang or run out of memory whatever they are
+//! given. Enabled by the `fuzzing` feature.
+
+use common::{Challenge, Decode, Message, Socket, SocketConfig, Stream};
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+use rust_sodium::crypto::box_::{PUBLICKEYBYTES, PublicKey};
+use std::cmp;
+use std::io;
+use std::net::SocketAddr;
+
+/// Decode `data` as a message from a peer, checking any proof of identity it carries as the
+/// handshake would.
+pub fn handshake(data: &[u8]) {
+    let our_pk = PublicKey([0; PUBLICKEYBYTES]);
+    let our_challenge = Challenge(Default::default());
+    match Message::decode(data) {
+        Ok(Message::IdentifiedBootstrapGranted(their_pk, their_challenge, proof, _)) => {
+            let _ = proof.verify(&their_pk, &our_pk, &their_challenge, &our_challenge);
+        }
+        Ok(Message::Identify(proof)) => {
+            let _ = proof.verify(&our_pk, &our_pk, &our_challenge, &our_challenge);
+        }
+        Ok(Message::MigrateProven(their_pk, proof)) => {
+            let _ = proof.verify_migration(&their_pk, &our_pk, &our_challenge);
+        }
+        Ok(_) | Err(_) => (),
+    }
+}
+
+/// Read messages off a socket receiving `data` until it fails. The first byte picks how the socket
+/// is configured and how many bytes arrive at a time, so that the fuzzer also covers messages
+/// split across reads and oversized ones passed on in chunks.
+pub fn read_frames(data: &[u8]) {
+    let (setup, data) = match data.split_first() {
+        Some((setup, data)) => (*setup, data),
+        None => return,
+    };
+
+    let mut socket = Socket::from_stream(Box::new(Replay {
+                                                      data: data.to_vec(),
+                                                      pos: 0,
+                                                      chunk: (setup & 0x0f) as usize + 1,
+                                                  }));
+    let config = SocketConfig {
+        max_message_size: Some(((setup >> 4) as usize + 1) * 16),
+        stream_oversized: Some(setup & 0x80 != 0),
+        ..Default::default()
+    };
+    if socket.configure(&config).is_err() ||
+       socket.stream_oversized(&Message::Data(Vec::new())).is_err() {
+        return;
+    }
+
+    while let Ok(Some(_)) = socket.read_chunked::<Message>() {}
+}
+
+// A stream replaying the bytes it was made with, then hitting EOF.
+struct Replay {
+    data: Vec<u8>,
+    pos: usize,
+    chunk: usize,
+}
+
+impl Stream for Replay {
+    fn transport(&self) -> &'static str {
+        "replay"
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = cmp::min(cmp::min(self.chunk, buf.len()), self.data.len() - self.pos);
+        buf[..len].copy_from_slice(&self.data[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+
+    fn write_bufs(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        Ok(bufs.iter().map(|buf| buf.len()).sum())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(io::ErrorKind::NotConnected, "Replayed stream"))
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(io::ErrorKind::NotConnected, "Replayed stream"))
+    }
+
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        Ok(None)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Evented for Replay {
+    fn register(&self, _: &Poll, _: Token, _: Ready, _: PollOpt) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn reregister(&self, _: &Poll, _: Token, _: Ready, _: PollOpt) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn deregister(&self, _: &Poll) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maidsafe_utilities::serialisation::serialise;
+
+    #[test]
+    fn replayed_frames() {
+        let msg = unwrap!(serialise(&Message::Data(vec![1; 100])));
+        let mut data = vec![0x83];
+        for _ in 0..3 {
+            data.extend_from_slice(&[msg.len() as u8, 0, 0, 0]);
+            data.extend_from_slice(&msg);
+        }
+        read_frames(&data);
+        read_frames(&data[..data.len() / 2]);
+        handshake(&data[5..]);
+    }
+}
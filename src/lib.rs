@@ -38,6 +38,8 @@
          missing_debug_implementations, variant_size_differences)]
 
 #![cfg_attr(feature="cargo-clippy", allow(too_many_arguments))]
+// `quick_error!` recurses once per variant of `CrustError`
+#![recursion_limit = "256"]
 // TODO FIXME Remove this soon
 #![allow(deprecated)]
 
@@ -55,20 +57,23 @@ extern crate byteorder;
 extern crate c_linked_list;
 extern crate config_file_handler;
 extern crate crossbeam;
+extern crate futures;
 extern crate igd;
+extern crate iovec;
 extern crate libc;
+extern crate lz4;
 extern crate maidsafe_utilities;
 extern crate mio;
 extern crate net2;
 extern crate rand;
 extern crate rust_sodium;
 extern crate serde;
+extern crate serde_json;
+extern crate zstd;
 
 #[cfg(windows)]
 extern crate winapi;
 
-#[cfg(test)]
-extern crate serde_json;
 #[cfg(test)]
 #[macro_use]
 mod tests;
@@ -78,9 +83,28 @@ mod common;
 mod service_discovery;
 mod nat;
 
-pub use common::{CrustUser, MSG_DROP_PRIORITY, Priority};
-pub use main::{Config, ConnectionInfoResult, CrustError, Event, PeerId, PrivConnectionInfo,
-               PubConnectionInfo, Service};
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+#[cfg(feature = "test_utils")]
+pub mod test_utils;
+
+pub use common::{BufferPoolStats, Capabilities, Compression, CrustUser, Device, DscpLanes,
+                 FEATURE_MIGRATION, FEATURE_OVERSIZED, FEATURE_REPLAY, FEATURE_STREAMS,
+                 HttpConnect, Identity, Listener, LogSubscriber, MIN_PROTOCOL_VERSION,
+                 MSG_DROP_PRIORITY, PROTOCOL_VERSION, Priority, QueueFullPolicy, QuotaPolicy,
+                 SocketConfig, Socks5, StateCrash, StateSnapshot, Stream, TRACE_TARGET, Tcp,
+                 TraceEvent, TraceState, TraceSubscriber, Transport, Udp, UdpListener, UdpStream,
+                 Utp, UtpListener, UtpStream, WebSocket, WsListener, WsStream};
+pub use main::{AsyncService, BootstrapFailure, Candidate, CandidateKind, CandidatePair,
+               CandidateTransport, CandidatesResult, Completion, Config, ConfigBuilder,
+               ConnectionCandidates, ConnectionInfoResult, ConnectionStats, CrustError, Event,
+               Events, HandshakeKind, Latencies, ListenerReachability, LocalCandidates,
+               MetricsSnapshot, OfferStamp, OutOfBand, PeerId, PeerTraffic, PortForwarding,
+               PrivConnectionInfo, PubConnectionInfo, SCHEMA_VERSION, SealedConnectionInfo,
+               SendToken, Service, StreamId, TraversalOutcome, UdpHolePunchResult,
+               event_channel};
+pub use nat::{GatewayStats, MappedAddr, MappedAddrSource, MappingEvent, MappingId, NatDiagnostics,
+              NatStats, NatType, Router, StunStats};
 
 /// Used to receive events from a `Service`.
 pub type CrustEventSender = ::maidsafe_utilities::event_sender::MaidSafeObserver<Event>;
@@ -15,26 +15,170 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-use common::{Core, CoreTimer, Message, Priority, Socket, State};
-use main::{ConnectionId, ConnectionMap, Event, PeerId};
-use mio::{Poll, Ready, Token};
-use mio::timer::Timeout;
+use common::{self, BandwidthLimits, Capabilities, Challenge, CommonError, Compression, Core,
+             CoreMessage, CoreTimer, Device, DropPolicy, DscpLanes, FEATURE_EXT_ADDR,
+             FEATURE_HELPERS, FEATURE_KEEP_ALIVE, FEATURE_MIGRATION, FEATURE_PING, IdentityProof,
+             KeyExchange, MAX_DSCP, MAX_PAYLOAD_SIZE, MSG_DROP_PRIORITY, Message, PeerQuota,
+             Priority, QueueFullPolicy, QueueLimits, QuotaPolicy, RateLimit, Received,
+             SUPPORTED_COMPRESSIONS, Session, Socket, SocketConfig, State, Timeout, TraceState,
+             lock};
+use main::{Config, ConnectionId, ConnectionMap, Event, Metrics, MigrationDial, Mux, PeerId,
+           Reconnect, Relayed, SendToken, StreamId};
+use maidsafe_utilities::thread;
+use mio::{Poll, PollOpt, Ready, Token};
+use nat::{self, MappingContext};
+use rand;
+use rust_sodium::crypto::box_::Nonce;
 use std::any::Any;
 use std::cell::RefCell;
+use std::cmp;
+use std::collections::VecDeque;
 use std::collections::hash_map::Entry;
+use std::mem;
 use std::net::SocketAddr;
 use std::rc::Rc;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::usize;
 
 #[cfg(not(test))]
 pub const INACTIVITY_TIMEOUT_MS: u64 = 120_000;
 #[cfg(not(test))]
-const HEARTBEAT_PERIOD_MS: u64 = 20_000;
+pub const HEARTBEAT_PERIOD_MS: u64 = 20_000;
 
 #[cfg(test)]
 pub const INACTIVITY_TIMEOUT_MS: u64 = 900;
 #[cfg(test)]
-const HEARTBEAT_PERIOD_MS: u64 = 300;
+pub const HEARTBEAT_PERIOD_MS: u64 = 300;
+
+/// Fewest heartbeats to miss before giving up on a peer, so one delayed heartbeat is not fatal.
+const MIN_HEARTBEAT_MISSES: u32 = 2;
+const THROTTLE_TIMER_ID: u8 = 2;
+const MIGRATION_TIMER_ID: u8 = 3;
+const RETIRE_TIMER_ID: u8 = 4;
+const SLOW_PEER_TIMER_ID: u8 = 5;
+const EXT_ADDR_TIMER_ID: u8 = 6;
+/// How long the peer is given to tell us the address it sees us as.
+const EXT_ADDR_TIMEOUT_SEC: u64 = 10;
+/// How often a relayed connection tries to move onto a direct path.
+const MIGRATION_PERIOD_SECS: u64 = 60;
+/// How long the old path of a migrated connection may take to drain.
+const RETIRE_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 16 * 1024;
+/// Most sequenced messages to receive before acknowledging them, if no heartbeat is due first.
+const ACK_BATCH: usize = 32;
+/// How often to ping a peer that messages keep us from sending heartbeats to, to measure the
+/// round trip time all the same.
+const RTT_SAMPLE_PERIOD_MS: u64 = HEARTBEAT_PERIOD_MS;
+
+/// How often to send a heartbeat on an otherwise idle connection, both to show the peer we are
+/// alive and to keep NAT bindings along the way from expiring. Capped at half of the inactivity
+/// timeout so that peers never give up on us.
+pub fn keep_alive_period(config: &Config) -> Duration {
+    let period_ms = config.tcp_keep_alive_ms.unwrap_or(HEARTBEAT_PERIOD_MS);
+    Duration::from_millis(cmp::min(cmp::max(period_ms, 1), INACTIVITY_TIMEOUT_MS / 2))
+}
+
+/// How far apart the boundaries heartbeats are batched on are in network-conservation mode, so
+/// that the radio is woken once per boundary for every connection. Capped at half the keep-alive
+/// period, which is also the default, so that batched heartbeats still come well within the
+/// inactivity timeout of the peer.
+pub fn keep_alive_batch(config: &Config) -> Option<Duration> {
+    if !config.conserve_network.unwrap_or(false) {
+        return None;
+    }
+    let half_period = keep_alive_period(config) / 2;
+    let batch = config.keep_alive_batch_ms.map_or(half_period, Duration::from_millis);
+    Some(cmp::min(batch, half_period))
+}
+
+/// How long a connection may go without hearing from the peer before it is considered dead. With
+/// `heartbeat_misses` set, this is that many of our heartbeat periods, which assumes the peer
+/// sends heartbeats at least as often as we do.
+pub fn inactivity_timeout(config: &Config) -> Duration {
+    match config.heartbeat_misses {
+        Some(misses) => keep_alive_period(config) * cmp::max(misses, MIN_HEARTBEAT_MISSES),
+        None => Duration::from_millis(INACTIVITY_TIMEOUT_MS),
+    }
+}
+
+/// How connections shed their backlog of droppable messages when bandwidth is insufficient.
+pub fn drop_policy(config: &Config) -> DropPolicy {
+    let default = DropPolicy::default();
+    DropPolicy {
+        max_age_secs: config.msg_max_age_secs.unwrap_or(default.max_age_secs),
+        max_backlog: config.msg_max_backlog,
+        drop_newest: config.msg_drop_newest.unwrap_or(default.drop_newest),
+    }
+}
+
+/// Options for every tcp socket of ours.
+pub fn socket_config(config: &Config) -> SocketConfig {
+    SocketConfig {
+        nodelay: config.tcp_nodelay,
+        keepalive_ms: config.tcp_keepalive_idle_ms,
+        send_buffer_size: config.tcp_send_buffer_size,
+        recv_buffer_size: config.tcp_recv_buffer_size,
+        ttl: config.tcp_ttl,
+        max_message_size: config.max_message_size,
+        stream_oversized: config.stream_oversized_messages,
+        dscp: config.dscp.map(|dscp| cmp::min(dscp, MAX_DSCP)),
+        dscp_lanes: config
+            .dscp_lanes
+            .as_ref()
+            .map_or_else(DscpLanes::default, |codepoints| DscpLanes::new(codepoints)),
+        device: config.bind_device.as_ref().and_then(|name| Device::new(name)),
+    }
+}
+
+/// How a connection to a peer is doing, as returned by `Service::connection_info_of`.
+#[derive(Clone, Debug)]
+pub struct ConnectionStats {
+    /// Name of the `Transport` the connection runs over, e.g. `"tcp"`.
+    pub transport: Option<&'static str>,
+    /// Our end of the connection.
+    pub local_addr: Option<SocketAddr>,
+    /// The other end of the connection, which is the relay if `relayed`.
+    pub remote_addr: Option<SocketAddr>,
+    /// Whether the connection goes through a relay rather than straight to the peer.
+    pub relayed: bool,
+    /// Smoothed round trip time of pings, once the peer has answered one. Only peers that told us
+    /// their capabilities are pinged, see `Config::negotiate_capabilities`.
+    pub rtt: Option<Duration>,
+    /// Bytes of messages sent to the peer.
+    pub bytes_sent: u64,
+    /// Bytes of messages received from the peer.
+    pub bytes_received: u64,
+    /// Messages sent to the peer.
+    pub msgs_sent: u64,
+    /// Messages received from the peer, counting a streamed one once.
+    pub msgs_received: u64,
+    /// How long ago the connection was established.
+    pub age: Duration,
+    /// What the peer told us it supports, if it negotiated capabilities.
+    pub capabilities: Option<Capabilities>,
+}
+
+/// Codec to compress messages of more than `threshold` bytes with.
+#[derive(Clone, Copy, Debug)]
+pub struct CompressionPolicy {
+    pub codec: Compression,
+    pub threshold: usize,
+}
+
+/// How to compress large messages, if at all.
+pub fn compression_policy(config: &Config) -> Option<CompressionPolicy> {
+    config
+        .compression
+        .map(|codec| {
+                 CompressionPolicy {
+                     codec: codec,
+                     threshold: config
+                         .compression_threshold
+                         .unwrap_or(DEFAULT_COMPRESSION_THRESHOLD),
+                 }
+             })
+}
 
 pub struct ActiveConnection {
     token: Token,
@@ -44,23 +188,155 @@ pub struct ActiveConnection {
     their_id: PeerId,
     event_tx: ::CrustEventSender,
     heartbeat: Heartbeat,
+    mux: Mux,
+    throttle_timeout: Option<Timeout>,
+    compression: Option<CompressionPolicy>,
+    their_compressions: Vec<Compression>,
+    compressions_sent: bool,
+    // Messages to the peer waiting on the one at the front to be compressed off the event loop,
+    // so that they go out in the order they were sent.
+    outgoing: VecDeque<Outgoing>,
+    compressing: bool,
+    their_capabilities: Option<Capabilities>,
+    capabilities_sent: bool,
+    // Our half of the key exchange until the peer's is in, then the session agreed, which every
+    // path of the connection is sealed with.
+    key_exchange: Option<KeyExchange>,
+    session: Option<Session>,
+    // Paths the connection was on before its current one, which tells the nonces of its session.
+    paths: u32,
+    // What to tell the user of the connection once the peer has shown it has the session too.
+    event: Option<Event>,
+    // The traversal helper we last told the peer we serve as, and the one it told us it does.
+    helper_sent: Option<SocketAddr>,
+    their_helper: Option<SocketAddr>,
+    drop_policy: DropPolicy,
+    rate_limit: RateLimit,
+    queue_limits: QueueLimits,
+    queue_full: bool,
+    slow_peer_timeout: Option<Timeout>,
+    slow: bool,
+    // Set while we wait for the peer to tell us the address it sees us as, along with where to
+    // record it for the next mapping.
+    ext_addr_query: Option<(Timeout, Arc<MappingContext>)>,
+    quota: Option<PeerQuota>,
+    // Whether the peer has been over its quota since we last read from it.
+    over_quota: bool,
+    metrics: Metrics,
+    opened: Instant,
+    traffic: Traffic,
+    // Nonce of the ping awaiting an answer and when it was sent, and when we last sent one.
+    ping: Option<(u64, Instant)>,
+    pinged: Option<Instant>,
+    rtt: Option<Duration>,
+    relayed: Option<Relayed>,
+    migration: Migration,
+    migration_timeout: Option<Timeout>,
+    retiring: Option<Retiring>,
+    reconnect: Option<Reconnect>,
+    acks: Vec<u64>,
+}
+
+// Gives up on a connection to `their_id` that never got going, so that the peer is not left
+// handshaking in `cm` for good.
+fn abandon(cm: &ConnectionMap, their_id: PeerId, event_tx: &::CrustEventSender) {
+    {
+        let mut guard = lock(cm);
+        let done = match guard.get_mut(&their_id) {
+            Some(conn_id) => {
+                conn_id.currently_handshaking = conn_id.currently_handshaking.saturating_sub(1);
+                conn_id.currently_handshaking == 0 && conn_id.active_connection.is_none()
+            }
+            None => false,
+        };
+        if done {
+            let _ = guard.remove(&their_id);
+        }
+    }
+    let _ = event_tx.send(Event::LostPeer(their_id));
 }
 
 impl ActiveConnection {
     pub fn start(core: &mut Core,
                  poll: &Poll,
                  token: Token,
-                 socket: Socket,
+                 mut socket: Socket,
                  cm: ConnectionMap,
                  our_id: PeerId,
                  their_id: PeerId,
                  event: Event,
+                 keep_alive: Duration,
+                 inactivity_timeout: Duration,
+                 drop_policy: DropPolicy,
+                 bandwidth: BandwidthLimits,
+                 metrics: Metrics,
+                 compression: Option<CompressionPolicy>,
+                 relayed: Option<Relayed>,
+                 reconnect: Option<Reconnect>,
                  event_tx: ::CrustEventSender) {
         trace!("Entered state ActiveConnection: {:?} -> {:?}",
                our_id,
                their_id);
 
-        let heartbeat = match Heartbeat::new(core, token) {
+        // Handshakes all happen on the first event loop, which hands the connections on to the
+        // least loaded. Relayed ones may yet migrate and reconnecting ones redial from there, so
+        // they stay where they are.
+        let hand_off = match core.shards() {
+            Some(shards) if shards.index() == 0 && relayed.is_none() && reconnect.is_none() => {
+                shards.least_loaded().map(|index| (index, shards.clone()))
+            }
+            _ => None,
+        };
+        if let Some((index, shards)) = hand_off {
+            match socket.detach(poll) {
+                Ok(detached) => {
+                    let cm_on_err = cm.clone();
+                    let event_tx_on_err = event_tx.clone();
+                    let msg = CoreMessage::new(move |core, poll| {
+                        let token = core.get_new_token();
+                        let socket = Socket::attach(detached);
+                        if let Err(e) = poll.register(&socket,
+                                                      token,
+                                                      Ready::readable() | Ready::error() |
+                                                      Ready::hup(),
+                                                      PollOpt::edge()) {
+                            debug!("Could not register connection to {:?} handed over: {:?}",
+                                   their_id,
+                                   e);
+                            return abandon(&cm, their_id, &event_tx);
+                        }
+                        ActiveConnection::start(core,
+                                                poll,
+                                                token,
+                                                socket,
+                                                cm,
+                                                our_id,
+                                                their_id,
+                                                event,
+                                                keep_alive,
+                                                inactivity_timeout,
+                                                drop_policy,
+                                                bandwidth,
+                                                metrics,
+                                                compression,
+                                                None,
+                                                None,
+                                                event_tx)
+                    });
+                    if let Err(e) = shards.send(index, msg) {
+                        error!("Could not hand connection to {:?} over to event loop {}: {:?}",
+                               their_id,
+                               index,
+                               e);
+                        abandon(&cm_on_err, their_id, &event_tx_on_err);
+                    }
+                    return;
+                }
+                Err(returned) => socket = returned,
+            }
+        }
+
+        let heartbeat = match Heartbeat::new(core, token, keep_alive, inactivity_timeout) {
             Ok(heartbeat) => heartbeat,
             Err(e) => {
                 debug!("{:?} - Failed to initialize heartbeat: {:?} - killing ActiveConnection \
@@ -69,12 +345,20 @@ impl ActiveConnection {
                        e,
                        their_id);
                 let _ = poll.deregister(&socket);
-                let _ = event_tx.send(Event::LostPeer(their_id));
-                // TODO See if this plays well with ConnectionMap manipulation below
-                return;
+                return abandon(&cm, their_id, &event_tx);
             }
         };
 
+        let rate_limit = bandwidth.for_connection();
+        let queue_limits = bandwidth.queue_limits().clone();
+        socket.set_drop_policy(drop_policy);
+        socket.set_rate_limit(rate_limit.clone());
+        socket.set_queue_limits(queue_limits.clone());
+        socket.set_buffer_pool(core.buffer_pool().clone());
+        if let Err(e) = socket.stream_oversized(&Message::Data(Vec::new())) {
+            debug!("Could not stream oversized messages: {:?}", e);
+        }
+
         let state = Rc::new(RefCell::new(ActiveConnection {
                                              token: token,
                                              socket: socket,
@@ -83,13 +367,57 @@ impl ActiveConnection {
                                              their_id: their_id,
                                              event_tx: event_tx,
                                              heartbeat: heartbeat,
+                                             mux: Mux::new(our_id < their_id),
+                                             throttle_timeout: None,
+                                             compression: compression,
+                                             their_compressions: Vec::new(),
+                                             compressions_sent: false,
+                                             outgoing: VecDeque::new(),
+                                             compressing: false,
+                                             their_capabilities: None,
+                                             capabilities_sent: false,
+                                             key_exchange: None,
+                                             session: None,
+                                             paths: 0,
+                                             event: Some(event),
+                                             helper_sent: None,
+                                             their_helper: None,
+                                             drop_policy: drop_policy,
+                                             rate_limit: rate_limit,
+                                             queue_limits: queue_limits,
+                                             queue_full: false,
+                                             slow_peer_timeout: None,
+                                             ext_addr_query: None,
+                                             slow: false,
+                                             quota: bandwidth.quota_for_connection(),
+                                             over_quota: false,
+                                             metrics: metrics,
+                                             opened: Instant::now(),
+                                             traffic: Traffic::default(),
+                                             ping: None,
+                                             pinged: None,
+                                             rtt: None,
+                                             relayed: relayed,
+                                             migration: Migration::Idle,
+                                             migration_timeout: None,
+                                             retiring: None,
+                                             reconnect: reconnect,
+                                             acks: Vec::new(),
                                          }));
 
         let _ = core.insert_state(token, state.clone());
+        if let Some(shards) = core.shards() {
+            shards.opened();
+        }
+        core.trace(token,
+                   TraceState::ActiveConnection,
+                   "started",
+                   &[("peer", &their_id)]);
 
         let mut state_mut = state.borrow_mut();
+        state_mut.metrics.connection_opened(their_id);
         {
-            let mut guard = unwrap!(state_mut.cm.lock());
+            let mut guard = lock(&state_mut.cm);
             {
                 let conn_id = guard
                     .entry(their_id)
@@ -104,26 +432,87 @@ impl ActiveConnection {
                    their_id,
                    guard.get(&their_id));
         }
-        let _ = state_mut.event_tx.send(event);
+        if !state_mut.start_key_exchange(core, poll) {
+            return;
+        }
+        // Peers on a protocol version we speak, which is all the handshake lets through, know of
+        // codecs, so ours are told right away. Our capabilities tell of them too. Like everything
+        // else from here on, they go out sealed once the key exchange is done.
+        if core.negotiates_capabilities() {
+            state_mut.advertise_capabilities(core, poll);
+        } else {
+            state_mut.advertise_compressions(core, poll);
+        }
+        state_mut.schedule_slow_peer_check(core, poll);
+        // Only one side tries to migrate, so that both do not dial each other at once
+        if state_mut
+               .relayed
+               .as_ref()
+               .map_or(false, |relayed| !relayed.their_direct.is_empty()) {
+            state_mut.schedule_migration(core, poll);
+        }
+        // Whatever the peer had not acknowledged before we reconnected may have been lost, and
+        // whatever was sent meanwhile is still to go
+        let pending = state_mut
+            .reconnect
+            .as_mut()
+            .map_or(Vec::new(), |reconnect| reconnect.take_pending());
+        for (data, priority, receipt) in pending {
+            state_mut.send_data(core, poll, data, priority, receipt);
+        }
         state_mut.read(core, poll);
     }
 
     fn read(&mut self, core: &mut Core, poll: &Poll) {
+        // Until the old path of a migration is drained, what the peer sends on the new one has to
+        // wait
+        if self.retiring
+               .as_ref()
+               .map_or(false, |retiring| !retiring.done_received) {
+            return;
+        }
         loop {
-            match self.socket.read::<Message>() {
-                Ok(Some(Message::Data(data))) => {
-                    let _ = self.event_tx
-                        .send(Event::NewMessage(self.their_id, data));
-                    self.reset_receive_heartbeat(core, poll);
+            if !self.within_quota(core, poll) {
+                return;
+            }
+            match self.socket.read_chunked::<Message>() {
+                Ok(Some(Received::Message(msg))) => {
+                    self.announce();
+                    let len = payload_len(&msg);
+                    if let Some(ref quota) = self.quota {
+                        quota.consume(len);
+                    }
+                    if len > 0 {
+                        self.traffic.received(len, true);
+                    }
+                    self.metrics.received(self.their_id, len);
+                    if !self.handle_msg(core, poll, msg) {
+                        return;
+                    }
                 }
-                Ok(Some(Message::Heartbeat)) => {
+                Ok(Some(Received::Chunk(data, last))) => {
+                    if self.session.is_none() {
+                        debug!("{:?} - Unsealed message from {:?}", self.our_id, self.their_id);
+                        return self.terminate(core, poll);
+                    }
+                    self.announce();
+                    if let Some(ref quota) = self.quota {
+                        quota.consume(data.len());
+                    }
+                    self.traffic.received(data.len(), last);
+                    self.metrics.received(self.their_id, data.len());
+                    let _ = self.event_tx
+                        .send(Event::NewMessageChunk(self.their_id, data, last));
                     self.reset_receive_heartbeat(core, poll);
                 }
-                Ok(Some(message)) => {
-                    debug!("{:?} - Unexpected message: {:?}", self.our_id, message);
-                    self.reset_receive_heartbeat(core, poll);
+                Ok(None) => {
+                    // A peer getting a large message through a tight rate limit is still alive
+                    if self.socket.take_read_progress() {
+                        self.reset_receive_heartbeat(core, poll);
+                    }
+                    self.advertise_helper(core, poll);
+                    return self.schedule_throttled(core, poll);
                 }
-                Ok(None) => return,
                 Err(e) => {
                     debug!("{:?} - Failed to read from socket: {:?}", self.our_id, e);
                     return self.terminate(core, poll);
@@ -132,6 +521,183 @@ impl ActiveConnection {
         }
     }
 
+    // Returns whether the connection is still worth reading from.
+    fn handle_msg(&mut self, core: &mut Core, poll: &Poll, msg: Message) -> bool {
+        // Nothing but the peer's half of the key exchange comes unsealed
+        if self.session.is_none() {
+            if let Message::SessionKey(nonce, sealed) = msg {
+                return self.finish_key_exchange(core, poll, &nonce, &sealed);
+            }
+            debug!("{:?} - Unsealed message from {:?}: {:?}",
+                   self.our_id,
+                   self.their_id,
+                   msg);
+            self.terminate(core, poll);
+            return false;
+        }
+        match msg {
+            Message::Data(data) => {
+                let _ = self.event_tx.send(Event::NewMessage(self.their_id, data));
+                self.reset_receive_heartbeat(core, poll);
+            }
+            Message::CompressedData(codec, data) => {
+                match common::decompress(codec, &data, self.socket.max_message_size()) {
+                    Ok(data) => {
+                        let _ = self.event_tx.send(Event::NewMessage(self.their_id, data));
+                    }
+                    Err(e) => {
+                        debug!("{:?} - Failed to decompress message: {:?}", self.our_id, e);
+                        self.terminate(core, poll);
+                        return false;
+                    }
+                }
+                self.reset_receive_heartbeat(core, poll);
+            }
+            Message::Compressions(codecs) => {
+                self.their_compressions = codecs;
+                if !self.compressions_sent {
+                    self.advertise_compressions(core, poll);
+                }
+                self.reset_receive_heartbeat(core, poll);
+            }
+            Message::Capabilities(capabilities) => {
+                trace!("{:?} - {:?} supports {:?}",
+                       self.our_id,
+                       self.their_id,
+                       capabilities);
+                self.their_compressions = capabilities.compressions.clone();
+                self.their_capabilities = Some(capabilities);
+                if !self.capabilities_sent {
+                    self.advertise_capabilities(core, poll);
+                }
+                self.advertise_helper(core, poll);
+                self.advertise_keep_alive(core, poll);
+                self.reset_receive_heartbeat(core, poll);
+            }
+            Message::KeepAlive(period_ms) => {
+                // Nobody sends less often than that, see `keep_alive_period`
+                let period_ms = cmp::min(period_ms, INACTIVITY_TIMEOUT_MS / 2);
+                let their_period = Duration::from_millis(period_ms);
+                if let Err(e) = self.heartbeat.allow_period(core, their_period) {
+                    debug!("{:?} - Failed to reset heartbeat: {:?}", self.our_id, e);
+                    self.terminate(core, poll);
+                    return false;
+                }
+            }
+            Message::EchoAddrReq => {
+                // Over a relay we would only see the relay
+                if self.relayed.is_none() {
+                    if let Ok(addr) = self.socket.peer_addr() {
+                        self.write(core, poll, Some((Message::EchoAddrResp(addr), 0)));
+                    }
+                }
+                self.reset_receive_heartbeat(core, poll);
+            }
+            Message::EchoAddrResp(addr) => {
+                self.handle_ext_addr(core, Some(nat::unmap_ipv4(&addr)));
+                self.reset_receive_heartbeat(core, poll);
+            }
+            Message::Helper(addr) => {
+                self.handle_helper(core, addr);
+                self.reset_receive_heartbeat(core, poll);
+            }
+            Message::Heartbeat => {
+                self.reset_receive_heartbeat(core, poll);
+            }
+            Message::Ping(nonce) => {
+                self.write(core, poll, Some((Message::Pong(nonce), 0)));
+                self.reset_receive_heartbeat(core, poll);
+            }
+            Message::Pong(nonce) => {
+                if let Some((sent_nonce, sent_at)) = self.ping {
+                    if nonce == sent_nonce {
+                        self.ping = None;
+                        self.record_rtt(sent_at.elapsed());
+                    }
+                }
+                self.reset_receive_heartbeat(core, poll);
+            }
+            Message::StreamOpen(id) => {
+                match self.mux.handle_open(id) {
+                    Ok(true) => {
+                        let _ = self.event_tx.send(Event::StreamOpened(self.their_id, id));
+                    }
+                    Ok(false) => (),
+                    Err(refusal) => self.write(core, poll, Some(refusal)),
+                }
+                self.reset_receive_heartbeat(core, poll);
+            }
+            Message::StreamData(id, data) => {
+                match self.mux.handle_data(id, data.len()) {
+                    Ok(true) => {
+                        let _ = self.event_tx
+                            .send(Event::NewStreamMessage(self.their_id, id, data));
+                    }
+                    Ok(false) => {
+                        debug!("{:?} - Data on unknown stream {}", self.our_id, id);
+                    }
+                    Err(close) => {
+                        self.write(core, poll, Some(close));
+                        let _ = self.event_tx.send(Event::StreamClosed(self.their_id, id));
+                    }
+                }
+                self.reset_receive_heartbeat(core, poll);
+            }
+            Message::StreamWindow(id, credit) => {
+                let msgs = self.mux.handle_window(id, credit);
+                self.write_all(core, poll, msgs);
+                self.reset_receive_heartbeat(core, poll);
+            }
+            Message::StreamClose(id) => {
+                if self.mux.handle_close(id) {
+                    let _ = self.event_tx.send(Event::StreamClosed(self.their_id, id));
+                }
+                self.reset_receive_heartbeat(core, poll);
+            }
+            Message::Sequenced(seq, msg) => {
+                match *msg {
+                    msg @ Message::Data(_) |
+                    msg @ Message::CompressedData(..) => {
+                        self.acks.push(seq);
+                        if self.acks.len() >= ACK_BATCH {
+                            self.send_acks(core, poll);
+                        }
+                        return self.handle_msg(core, poll, msg);
+                    }
+                    msg => {
+                        debug!("{:?} - Unexpected sequenced message: {:?}", self.our_id, msg);
+                        self.reset_receive_heartbeat(core, poll);
+                    }
+                }
+            }
+            Message::Ack(seqs) => {
+                if let Some(ref mut reconnect) = self.reconnect {
+                    reconnect.replay().ack(&seqs);
+                }
+                self.reset_receive_heartbeat(core, poll);
+            }
+            Message::MigrateReq => {
+                self.handle_migrate_req(core, poll);
+                self.reset_receive_heartbeat(core, poll);
+            }
+            Message::MigrateChallenge(challenge) => {
+                self.handle_migrate_challenge(core, poll, challenge);
+                self.reset_receive_heartbeat(core, poll);
+            }
+            Message::MigrateDone => {
+                if !self.handle_migrate_done(core, poll) {
+                    return false;
+                }
+                self.reset_receive_heartbeat(core, poll);
+            }
+            message => {
+                debug!("{:?} - Unexpected message: {:?}", self.our_id, message);
+                self.reset_receive_heartbeat(core, poll);
+            }
+        }
+        true
+    }
+
     #[cfg(not(test))]
     /// Helper function that returns a socket address of the connection
     pub fn peer_addr(&self) -> ::Res<SocketAddr> {
@@ -146,11 +712,980 @@ impl ActiveConnection {
         Ok(unwrap!(FromStr::from_str("192.168.0.1:0")))
     }
 
+    /// Open substream `id` to the peer. Does nothing if it is open already.
+    pub fn open_stream(&mut self, core: &mut Core, poll: &Poll, id: StreamId) {
+        if let Some(msg) = self.mux.open(id) {
+            self.write(core, poll, Some(msg));
+            self.reset_send_heartbeat(core, poll);
+        }
+    }
+
+    /// Send `data` on substream `id`, queueing it until the peer has room for it.
+    pub fn write_stream(&mut self,
+                        core: &mut Core,
+                        poll: &Poll,
+                        id: StreamId,
+                        data: Vec<u8>,
+                        priority: Priority) {
+        match self.mux.send(id, data, priority) {
+            Some(msgs) => self.write_all(core, poll, msgs),
+            None => debug!("{:?} - Write to unknown stream {}", self.our_id, id),
+        }
+    }
+
+    /// The application consumed `len` bytes delivered on substream `id`, so the peer may send more.
+    pub fn consume_stream(&mut self, core: &mut Core, poll: &Poll, id: StreamId, len: usize) {
+        if let Some(credit) = self.mux.consumed(id, len) {
+            self.write(core, poll, Some(credit));
+            self.reset_send_heartbeat(core, poll);
+        }
+    }
+
+    /// Close substream `id` once everything queued on it has been sent.
+    pub fn close_stream(&mut self, core: &mut Core, poll: &Poll, id: StreamId) {
+        let msgs = self.mux.close(id);
+        self.write_all(core, poll, msgs);
+    }
+
+    fn write_all(&mut self, core: &mut Core, poll: &Poll, msgs: Vec<(Message, Priority)>) {
+        if msgs.is_empty() {
+            return;
+        }
+        for msg in msgs {
+            self.write(core, poll, Some(msg));
+        }
+        self.reset_send_heartbeat(core, poll);
+    }
+
     fn write(&mut self, core: &mut Core, poll: &Poll, msg: Option<(Message, Priority)>) {
-        if let Err(e) = self.socket.write(poll, self.token, msg) {
-            debug!("{:?} - Failed to write socket: {:?}", self.our_id, e);
+        let res = self.socket.write(poll, self.token, msg);
+        self.handle_write(core, poll, res);
+    }
+
+    fn handle_write(&mut self, core: &mut Core, poll: &Poll, res: ::Res<bool>) {
+        match res {
+            Ok(true) => (),
+            Ok(false) => self.schedule_throttled(core, poll),
+            Err(e) => {
+                debug!("{:?} - Failed to write socket: {:?}", self.our_id, e);
+                return self.terminate(core, poll);
+            }
+        }
+        self.report_receipts();
+        self.report_sent();
+    }
+
+    /// Send `data` as `Service::send_tracked` does, telling via `Event::MessageSent` or
+    /// `Event::MessageNotSent` with `token` how it fared.
+    pub fn send_tracked(&mut self,
+                        core: &mut Core,
+                        poll: &Poll,
+                        data: Vec<u8>,
+                        priority: Priority,
+                        token: SendToken) {
+        self.send_data(core, poll, Arc::new(data), priority, Some(token));
+    }
+
+    // Count the messages that have now gone out on either path as sent.
+    fn report_sent(&mut self) {
+        let mut written = self.socket.take_body_bytes_written();
+        if let Some(ref mut retiring) = self.retiring {
+            written += retiring.socket.take_body_bytes_written();
+        }
+        if written > 0 {
+            self.metrics.sent(self.their_id, written);
+        }
+    }
+
+    // Tell whoever tracked messages sent on either path how they fared, unless one that did not
+    // make it is to be sent again on reconnecting.
+    fn report_receipts(&mut self) {
+        let mut receipts = self.socket.take_receipts();
+        if let Some(ref mut retiring) = self.retiring {
+            receipts.extend(retiring.socket.take_receipts());
+        }
+        for (token, sent) in receipts {
+            let event = if sent {
+                if let Some(ref mut reconnect) = self.reconnect {
+                    reconnect.replay().settle(token);
+                }
+                Event::MessageSent(self.their_id, token)
+            } else if self.reconnect
+                          .as_mut()
+                          .map_or(false, |reconnect| reconnect.replay().withhold(token)) {
+                continue;
+            } else {
+                Event::MessageNotSent(self.their_id, token)
+            };
+            let _ = self.event_tx.send(event);
+        }
+    }
+
+    // The payload is handed to the socket as it is, never copied, unless it is compressed.
+    // Payloads over the peer's limit are sent plain so it can stream them, and are not kept for
+    // replay.
+    fn send_data(&mut self,
+                 core: &mut Core,
+                 poll: &Poll,
+                 data: Arc<Vec<u8>>,
+                 priority: Priority,
+                 receipt: Option<SendToken>) {
+        // Peers would drop the connection over a message they cannot take
+        if self.their_capabilities
+               .as_ref()
+               .map_or(false, |theirs| !theirs.accepts(data.len())) {
+            let data = Arc::try_unwrap(data).unwrap_or_else(|data| (*data).clone());
+            let _ = self.event_tx
+                .send(Event::WriteMsgSizeProhibitive(self.their_id, data));
+            if let Some(token) = receipt {
+                let _ = self.event_tx
+                    .send(Event::MessageNotSent(self.their_id, token));
+            }
+            return;
+        }
+        self.outgoing
+            .push_back(Outgoing {
+                           data: data,
+                           priority: priority,
+                           receipt: receipt,
+                       });
+        self.send_outgoing(core, poll);
+    }
+
+    // Send the messages waiting to be, in order, until one has to be compressed off the event
+    // loop first.
+    fn send_outgoing(&mut self, core: &mut Core, poll: &Poll) {
+        while !self.compressing {
+            let outgoing = match self.outgoing.pop_front() {
+                Some(outgoing) => outgoing,
+                None => return,
+            };
+            let compressed = match self.codec_for(&outgoing.data) {
+                Some(codec) if codec.is_slow() => {
+                    self.compress_off_loop(core, codec, outgoing.data.clone());
+                    self.outgoing.push_front(outgoing);
+                    return;
+                }
+                Some(codec) => {
+                    let res = common::compress(codec, &outgoing.data);
+                    self.compressed(codec, &outgoing.data, res)
+                }
+                None => None,
+            };
+            self.send_prepared(core, poll, outgoing, compressed);
+        }
+    }
+
+    fn send_prepared(&mut self,
+                     core: &mut Core,
+                     poll: &Poll,
+                     outgoing: Outgoing,
+                     compressed: Option<(Compression, Vec<u8>)>) {
+        let Outgoing {
+            data,
+            priority,
+            receipt,
+        } = outgoing;
+        let oversized = data.len() > self.their_max_message_size();
+        let (msg, body) = match compressed {
+            Some((codec, compressed)) => {
+                (Message::CompressedData(codec, Vec::new()), Arc::new(compressed))
+            }
+            None => (Message::Data(Vec::new()), data.clone()),
+        };
+        // Droppable messages are not worth sending again
+        let seq = match self.reconnect {
+            Some(ref mut reconnect) if priority < MSG_DROP_PRIORITY && !oversized => {
+                reconnect.replay().next_seq()
+            }
+            _ => None,
+        };
+        let msg = match seq {
+            Some(seq) => Message::Sequenced(seq, Box::new(msg)),
+            None => msg,
+        };
+        // A full backlog refusing new messages is no reason to drop others or the peer. Otherwise
+        // the queue counts the frame as it goes out, compressed, rather than the message.
+        let admitted = if self.socket.refuses(priority) {
+            trace!("{:?} - Backlog to {:?} of priority {} is full",
+                   self.our_id,
+                   self.their_id,
+                   priority);
+            false
+        } else {
+            match Socket::frame_len(&msg, body.len()) {
+                Ok(len) => self.admit(core, poll, len, priority),
+                Err(e) => {
+                    debug!("{:?} - Could not frame message: {:?}", self.our_id, e);
+                    false
+                }
+            }
+        };
+        if !admitted {
+            if let Some(token) = receipt {
+                let _ = self.event_tx
+                    .send(Event::MessageNotSent(self.their_id, token));
+            }
+            return;
+        }
+        if seq.is_some() {
+            if let Some(ref mut reconnect) = self.reconnect {
+                let _ = reconnect.replay().push(data, priority, receipt);
+            }
+        }
+        self.traffic.bytes_sent += body.len() as u64;
+        self.traffic.msgs_sent += 1;
+        let res = self.socket
+            .write_with_body(poll, self.token, msg, body, priority, receipt);
+        self.handle_write(core, poll, res);
+        self.sample_rtt(core, poll);
+        self.reset_send_heartbeat(core, poll);
+    }
+
+    // Whether a message of `len` bytes fits in what may be queued for sending, after making room
+    // for it if the queue limits say so. If not, the peer is disconnected if they say that.
+    fn admit(&mut self, core: &mut Core, poll: &Poll, len: usize, priority: Priority) -> bool {
+        if self.socket.admit(len, priority) {
+            self.queue_full = false;
+            return true;
+        }
+        if !self.queue_full {
+            self.queue_full = true;
+            debug!("{:?} - Queue to {:?} is full", self.our_id, self.their_id);
+            let _ = self.event_tx.send(Event::QueueFull(self.their_id));
+        }
+        if self.queue_limits.policy == QueueFullPolicy::Disconnect {
+            // A peer this slow is not one to get back
+            self.reconnect = None;
             self.terminate(core, poll);
         }
+        false
+    }
+
+    // Checked twice per `slow_after`, so a peer is found slow at most half as late again.
+    fn schedule_slow_peer_check(&mut self, core: &mut Core, poll: &Poll) {
+        let period = match self.queue_limits.slow_after {
+            Some(after) => cmp::max(after / 2, Duration::from_millis(100)),
+            None => return,
+        };
+        match core.set_timeout(period, CoreTimer::new(self.token, SLOW_PEER_TIMER_ID)) {
+            Ok(timeout) => self.slow_peer_timeout = Some(timeout),
+            Err(e) => {
+                debug!("{:?} - Failed to set slow peer timer: {:?}", self.our_id, e);
+                self.terminate(core, poll);
+            }
+        }
+    }
+
+    fn check_slow_peer(&mut self, core: &mut Core, poll: &Poll) {
+        self.slow_peer_timeout = None;
+        let slow = match (self.queue_limits.slow_after, self.socket.write_stall()) {
+            (Some(after), Some(age)) => age >= after,
+            _ => false,
+        };
+        if !slow {
+            self.slow = false;
+            return self.schedule_slow_peer_check(core, poll);
+        }
+        if !self.slow {
+            self.slow = true;
+            debug!("{:?} - {:?} is not reading what we send", self.our_id, self.their_id);
+            let _ = self.event_tx.send(Event::SlowPeer(self.their_id));
+        }
+        if self.queue_limits.disconnect_slow {
+            // Not a peer to get back
+            self.reconnect = None;
+            return self.terminate(core, poll);
+        }
+        self.schedule_slow_peer_check(core, poll);
+    }
+
+    // Heartbeats are pings to peers that told us they answer them, straight away, so that they
+    // measure the round trip time too.
+    fn send_heartbeat(&mut self, core: &mut Core, poll: &Poll) {
+        if self.answers_pings() {
+            self.send_ping(core, poll);
+        } else {
+            self.write(core, poll, Some((Message::Heartbeat, 0)));
+        }
+    }
+
+    // Messages going out hold off heartbeats, so a busy peer is pinged alongside them instead,
+    // though no more often than heartbeats would be sent.
+    fn sample_rtt(&mut self, core: &mut Core, poll: &Poll) {
+        let period = Duration::from_millis(RTT_SAMPLE_PERIOD_MS);
+        if self.answers_pings() && self.pinged.map_or(true, |at| at.elapsed() >= period) {
+            self.send_ping(core, poll);
+        }
+    }
+
+    // An unanswered ping is given up on once the next one is sent.
+    fn send_ping(&mut self, core: &mut Core, poll: &Poll) {
+        let nonce = rand::random();
+        let now = Instant::now();
+        self.ping = Some((nonce, now));
+        self.pinged = Some(now);
+        self.write(core, poll, Some((Message::Ping(nonce), 0)));
+    }
+
+    fn answers_pings(&self) -> bool {
+        self.their_capabilities
+            .as_ref()
+            .map_or(false, |theirs| theirs.supports(FEATURE_PING))
+    }
+
+    fn record_rtt(&mut self, sample: Duration) {
+        self.rtt = Some(match self.rtt {
+                            Some(rtt) => (rtt * 7 + sample) / 8,
+                            None => sample,
+                        });
+    }
+
+    /// How the connection is doing.
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            transport: self.socket.transport(),
+            local_addr: self.socket.local_addr().ok(),
+            remote_addr: self.socket.peer_addr().ok(),
+            relayed: self.relayed.is_some(),
+            rtt: self.rtt,
+            bytes_sent: self.traffic.bytes_sent,
+            bytes_received: self.traffic.bytes_received,
+            msgs_sent: self.traffic.msgs_sent,
+            msgs_received: self.traffic.msgs_received,
+            age: self.opened.elapsed(),
+            capabilities: self.their_capabilities.clone(),
+        }
+    }
+
+    /// Whether messages are still waiting to be written to the peer.
+    pub fn is_flushing(&self) -> bool {
+        self.socket.has_pending_writes()
+    }
+
+    fn send_acks(&mut self, core: &mut Core, poll: &Poll) {
+        let acks = mem::replace(&mut self.acks, Vec::new());
+        self.write(core, poll, Some((Message::Ack(acks), 0)));
+        self.reset_send_heartbeat(core, poll);
+    }
+
+    // Offer the peer our half of the key exchange ahead of everything else, which is held back
+    // until the session is agreed and then sealed with it. Returns whether the connection is
+    // still alive.
+    fn start_key_exchange(&mut self, core: &mut Core, poll: &Poll) -> bool {
+        let key_exchange = KeyExchange::new();
+        let (nonce, sealed) = match core.secret_key() {
+            Some(our_sk) => key_exchange.offer(&self.our_id.0, our_sk, &self.their_id.0),
+            None => {
+                debug!("{:?} - No key to exchange with {:?}", self.our_id, self.their_id);
+                self.terminate(core, poll);
+                return false;
+            }
+        };
+        self.key_exchange = Some(key_exchange);
+        self.write(core, poll, Some((Message::SessionKey(nonce, sealed), 0)));
+        self.socket.seal_writes();
+        true
+    }
+
+    // Agree the session from the peer's half of the key exchange, and seal the connection with
+    // it. Returns whether the connection is still worth reading from.
+    fn finish_key_exchange(&mut self,
+                           core: &mut Core,
+                           poll: &Poll,
+                           nonce: &Nonce,
+                           sealed: &[u8])
+                           -> bool {
+        let session = match (self.key_exchange.take(), core.secret_key()) {
+            (Some(key_exchange), Some(our_sk)) => {
+                key_exchange.finish(our_sk, &self.their_id.0, nonce, sealed)
+            }
+            _ => None,
+        };
+        let session = match session {
+            Some(session) => session,
+            None => {
+                debug!("{:?} - {:?} failed the key exchange", self.our_id, self.their_id);
+                self.terminate(core, poll);
+                return false;
+            }
+        };
+        if let Err(e) = self.socket.set_session(session.for_path(self.paths)) {
+            debug!("{:?} - Could not seal connection: {:?}", self.our_id, e);
+            self.terminate(core, poll);
+            return false;
+        }
+        self.session = Some(session);
+        // What was held back goes out now
+        self.write(core, poll, None);
+        self.reset_receive_heartbeat(core, poll);
+        true
+    }
+
+    // Tell the user of the connection once the peer has sent us something sealed with the
+    // session, which only the holder of its `PeerId` key could have, and only for this connection.
+    fn announce(&mut self) {
+        if self.session.is_none() {
+            return;
+        }
+        if let Some(event) = self.event.take() {
+            let _ = self.event_tx.send(event);
+        }
+    }
+
+    fn advertise_capabilities(&mut self, core: &mut Core, poll: &Poll) {
+        self.capabilities_sent = true;
+        let msg = Message::Capabilities(core.capabilities());
+        self.write(core, poll, Some((msg, 0)));
+    }
+
+    /// Ask the peer which address it sees us as, to be reported via
+    /// `Event::ExternalAddrReported`. Peers that said they cannot tell us, or that we are relayed
+    /// through, are not asked at all. An answer is also recorded in `mc`, so that mappings can
+    /// use it rather than asking the peers again.
+    pub fn query_ext_addr(&mut self, core: &mut Core, poll: &Poll, mc: Arc<MappingContext>) {
+        if self.ext_addr_query.is_some() {
+            return;
+        }
+        let unsupported = self.their_capabilities
+            .as_ref()
+            .map_or(false, |theirs| !theirs.supports(FEATURE_EXT_ADDR));
+        if self.relayed.is_some() || unsupported {
+            let _ = self.event_tx
+                .send(Event::ExternalAddrReported(self.their_id, None));
+            return;
+        }
+        let timer = CoreTimer::new(self.token, EXT_ADDR_TIMER_ID);
+        match core.set_timeout(Duration::from_secs(EXT_ADDR_TIMEOUT_SEC), timer) {
+            Ok(timeout) => self.ext_addr_query = Some((timeout, mc)),
+            Err(e) => {
+                debug!("{:?} - Could not set timer: {:?}", self.our_id, e);
+                let _ = self.event_tx
+                    .send(Event::ExternalAddrReported(self.their_id, None));
+                return;
+            }
+        }
+        self.write(core, poll, Some((Message::EchoAddrReq, 0)));
+    }
+
+    // Answers we did not ask for, or no longer wait for, are dropped.
+    fn handle_ext_addr(&mut self, core: &mut Core, addr: Option<SocketAddr>) {
+        if let Some((timeout, mc)) = self.ext_addr_query.take() {
+            let _ = core.cancel_timeout(&timeout);
+            if let (Some(addr), Ok(local_addr)) = (addr, self.socket.local_addr()) {
+                mc.record_ext_addr(local_addr.port(), &addr);
+            }
+            let _ = self.event_tx
+                .send(Event::ExternalAddrReported(self.their_id, addr));
+        }
+    }
+
+    // Tell the peer whenever we start or stop serving as a traversal helper, if it can take it.
+    // Relayed peers would not believe us, as they only take helpers on the address we connected
+    // to them from.
+    fn advertise_helper(&mut self, core: &mut Core, poll: &Poll) {
+        if self.relayed.is_some() ||
+           !self.their_capabilities
+                .as_ref()
+                .map_or(false, |theirs| theirs.supports(FEATURE_HELPERS)) {
+            return;
+        }
+        let ours = core.helpers().ours();
+        if ours == self.helper_sent {
+            return;
+        }
+        self.helper_sent = ours;
+        let msg = Message::Helper(ours);
+        self.write(core, poll, Some((msg, 0)));
+    }
+
+    // Tell the peer how often we send heartbeats, if it can take it.
+    fn advertise_keep_alive(&mut self, core: &mut Core, poll: &Poll) {
+        if !self.their_capabilities
+                .as_ref()
+                .map_or(false, |theirs| theirs.supports(FEATURE_KEEP_ALIVE)) {
+            return;
+        }
+        let msg = Message::KeepAlive(millis(self.heartbeat.send_period));
+        self.write(core, poll, Some((msg, 0)));
+    }
+
+    // Only helpers on the address the peer connected from are taken, so that peers cannot have
+    // us query hosts of their choosing.
+    fn handle_helper(&mut self, core: &Core, addr: Option<SocketAddr>) {
+        if let Some(addr) = addr {
+            let from = self.socket
+                .peer_addr()
+                .ok()
+                .map(|from| nat::unmap_ipv4(&from).ip());
+            if from != Some(nat::unmap_ipv4(&addr).ip()) {
+                debug!("{:?} - Ignoring helper {} advertised by {:?} from {:?}",
+                       self.our_id,
+                       addr,
+                       self.their_id,
+                       from);
+                return;
+            }
+        }
+        if addr == self.their_helper {
+            return;
+        }
+        if let Some(old) = self.their_helper.take() {
+            core.helpers().advertised(old, false);
+        }
+        if let Some(addr) = addr {
+            trace!("{:?} - {:?} serves as a helper on {}",
+                   self.our_id,
+                   self.their_id,
+                   addr);
+            core.helpers().advertised(addr, true);
+        }
+        self.their_helper = addr;
+    }
+
+    // The largest message the peer takes whole, beyond which it has to stream it. Peers which do
+    // not tell us are taken to have the default limit, as ours is for all we know.
+    fn their_max_message_size(&self) -> usize {
+        self.their_capabilities
+            .as_ref()
+            .map_or(MAX_PAYLOAD_SIZE, |theirs| {
+                cmp::min(theirs.max_message_size, usize::MAX as u64) as usize
+            })
+    }
+
+    fn advertise_compressions(&mut self, core: &mut Core, poll: &Poll) {
+        self.compressions_sent = true;
+        let msg = Message::Compressions(SUPPORTED_COMPRESSIONS.to_vec());
+        self.write(core, poll, Some((msg, 0)));
+    }
+
+    // The codec to compress `data` with, if it is large enough, small enough not to be streamed
+    // and the peer can decompress it.
+    fn codec_for(&self, data: &[u8]) -> Option<Compression> {
+        match self.compression {
+            Some(CompressionPolicy { codec, threshold })
+                if data.len() > threshold && data.len() <= self.their_max_message_size() &&
+                   self.their_compressions.contains(&codec) => Some(codec),
+            _ => None,
+        }
+    }
+
+    // Slow codecs would hold up every other connection on the event loop, so `data` is compressed
+    // on a thread of its own and sent once it is.
+    fn compress_off_loop(&mut self, core: &Core, codec: Compression, data: Arc<Vec<u8>>) {
+        self.compressing = true;
+        let tx = core.sender().clone();
+        let token = self.token;
+        let _ = thread::named("Compression", move || {
+            let res = common::compress(codec, &data);
+            let _ = tx.send(CoreMessage::new(move |core, poll| {
+                if let Some(state) = core.get_state(token) {
+                    let mut state = state.borrow_mut();
+                    if let Some(conn) = state.as_any().downcast_mut::<ActiveConnection>() {
+                        conn.handle_compressed(core, poll, codec, &data, res);
+                    }
+                }
+            }));
+        });
+    }
+
+    fn handle_compressed(&mut self,
+                         core: &mut Core,
+                         poll: &Poll,
+                         codec: Compression,
+                         data: &Arc<Vec<u8>>,
+                         res: common::Result<Vec<u8>>) {
+        // The token may have been handed on to another connection since
+        let outgoing = match self.outgoing.pop_front() {
+            Some(outgoing) => {
+                if !self.compressing || !Arc::ptr_eq(&outgoing.data, data) {
+                    return self.outgoing.push_front(outgoing);
+                }
+                outgoing
+            }
+            None => return,
+        };
+        self.compressing = false;
+        let compressed = self.compressed(codec, data, res);
+        self.send_prepared(core, poll, outgoing, compressed);
+        self.send_outgoing(core, poll);
+    }
+
+    // What `data` came to compressed with `codec`, unless that failed or did not make it any
+    // smaller, in which case it is sent as it is.
+    fn compressed(&self,
+                  codec: Compression,
+                  data: &[u8],
+                  res: common::Result<Vec<u8>>)
+                  -> Option<(Compression, Vec<u8>)> {
+        match res {
+            Ok(compressed) => {
+                if compressed.len() < data.len() {
+                    Some((codec, compressed))
+                } else {
+                    None
+                }
+            }
+            Err(e) => {
+                debug!("{:?} - Failed to compress message: {:?}", self.our_id, e);
+                None
+            }
+        }
+    }
+
+    /// Do not try to reconnect to the peer once the connection is terminated.
+    pub fn cancel_reconnect(&mut self) {
+        self.reconnect = None;
+    }
+
+    /// Take over `socket`, which the peer dialed us directly on with `proof` of its identity, as
+    /// the new path of this relayed connection. The socket is dropped unless the proof answers
+    /// the challenge we sent over the relay and is of the identity the peer proved on connecting,
+    /// so that neither the relay nor anyone it told of the challenge can take the connection over.
+    pub fn accept_migration(&mut self,
+                            core: &mut Core,
+                            poll: &Poll,
+                            socket: Socket,
+                            proof: IdentityProof) {
+        let proven = match (&self.migration, &self.relayed) {
+            (&Migration::Expecting(ref challenge), &Some(ref relayed)) => {
+                proof.verify_migration(&self.their_id.0, &self.our_id.0, challenge)
+                    .map_or(false, |identity| Some(identity) == relayed.their_identity)
+            }
+            _ => false,
+        };
+        // A bad proof leaves the challenge standing for the peer itself to answer
+        if !proven {
+            debug!("{:?} - Unexpected migration of connection to {:?}",
+                   self.our_id,
+                   self.their_id);
+            return;
+        }
+        self.migration = Migration::Idle;
+        if let Err(e) = self.switch_path(core, poll, socket, false) {
+            debug!("{:?} - Failed to migrate connection: {:?}", self.our_id, e);
+            return self.terminate(core, poll);
+        }
+        if self.flush_retiring(core, poll) {
+            self.read_retiring(core, poll);
+        }
+    }
+
+    fn schedule_migration(&mut self, core: &mut Core, poll: &Poll) {
+        match core.set_timeout(Duration::from_secs(MIGRATION_PERIOD_SECS),
+                               CoreTimer::new(self.token, MIGRATION_TIMER_ID)) {
+            Ok(timeout) => self.migration_timeout = Some(timeout),
+            Err(e) => {
+                debug!("{:?} - Failed to set migration timer: {:?}", self.our_id, e);
+                self.cancel_migration(core, poll);
+            }
+        }
+    }
+
+    // Give up on any migration attempt still under way, which by now is stale, and start afresh.
+    fn handle_migration_timeout(&mut self, core: &mut Core, poll: &Poll) {
+        self.migration_timeout = None;
+        if self.relayed.is_none() {
+            return;
+        }
+        self.cancel_migration(core, poll);
+        // Peers that told us what they support, and that left migration out, would ignore us
+        let migrates = self.their_capabilities
+            .as_ref()
+            .map_or(true, |theirs| theirs.supports(FEATURE_MIGRATION));
+        // Nor is a new path dialed while the service is paused
+        if self.retiring.is_none() && migrates && !core.is_paused() {
+            self.migration = Migration::Requested;
+            self.write(core, poll, Some((Message::MigrateReq, 0)));
+        }
+        self.schedule_migration(core, poll);
+    }
+
+    fn cancel_migration(&mut self, core: &mut Core, poll: &Poll) {
+        if let Migration::Dialing(child) = mem::replace(&mut self.migration, Migration::Idle) {
+            if let Some(child) = core.get_state(child) {
+                child.borrow_mut().terminate(core, poll);
+            }
+        }
+    }
+
+    fn handle_migrate_req(&mut self, core: &mut Core, poll: &Poll) {
+        // Peers from before migration had to be proven could not read the challenge
+        let proves = self.their_capabilities
+            .as_ref()
+            .map_or(true, |theirs| theirs.supports(FEATURE_MIGRATION));
+        if self.relayed.is_none() || self.retiring.is_some() || !proves {
+            debug!("{:?} - Ignoring request to migrate connection to {:?}",
+                   self.our_id,
+                   self.their_id);
+            return;
+        }
+        let challenge = Challenge::new();
+        self.migration = Migration::Expecting(challenge);
+        self.write(core, poll, Some((Message::MigrateChallenge(challenge), 0)));
+    }
+
+    fn handle_migrate_challenge(&mut self, core: &mut Core, poll: &Poll, challenge: Challenge) {
+        let relayed = match (&self.migration, &self.relayed) {
+            (&Migration::Requested, &Some(ref relayed)) => relayed.clone(),
+            _ => return,
+        };
+        let token = self.token;
+        let finish = move |core: &mut Core, _poll: &Poll, res: Option<Socket>| if let Some(state) =
+            core.get_state(token) {
+            let mut state = state.borrow_mut();
+            if let Some(conn) = state.as_any().downcast_mut::<ActiveConnection>() {
+                conn.handle_migration_dial(res);
+            }
+        };
+        let proof = relayed
+            .identity
+            .prove_migration(&self.our_id.0, &self.their_id.0, &challenge);
+        match MigrationDial::start(core, poll, &relayed, self.our_id.0, proof, Box::new(finish)) {
+            Ok(child) => self.migration = Migration::Dialing(child),
+            Err(e) => {
+                debug!("{:?} - Could not dial {:?} directly: {:?}",
+                       self.our_id,
+                       self.their_id,
+                       e);
+                self.migration = Migration::Idle;
+            }
+        }
+    }
+
+    // The peer switches to the dialed socket once it recognises it, and tells us so on the old
+    // path. Until then we hold on to it.
+    fn handle_migration_dial(&mut self, res: Option<Socket>) {
+        if let Migration::Dialing(_) = self.migration {
+            self.migration = match res {
+                Some(socket) => Migration::Dialed(socket),
+                None => Migration::Idle,
+            };
+        }
+    }
+
+    // Returns whether the connection is still worth reading from.
+    fn handle_migrate_done(&mut self, core: &mut Core, poll: &Poll) -> bool {
+        let drained = match self.retiring {
+            Some(ref mut retiring) if !retiring.done_received => {
+                retiring.done_received = true;
+                true
+            }
+            _ => false,
+        };
+        if drained {
+            self.maybe_retire(core, poll);
+            return true;
+        }
+        match mem::replace(&mut self.migration, Migration::Idle) {
+            Migration::Dialed(socket) => {
+                if let Err(e) = self.switch_path(core, poll, socket, true) {
+                    debug!("{:?} - Failed to migrate connection: {:?}", self.our_id, e);
+                    self.terminate(core, poll);
+                    return false;
+                }
+                self.flush_retiring(core, poll)
+            }
+            migration => {
+                debug!("{:?} - Unexpected end of path from {:?}",
+                       self.our_id,
+                       self.their_id);
+                self.migration = migration;
+                true
+            }
+        }
+    }
+
+    // Make `socket` the path of the connection. What is still queued on the old path is sent
+    // before telling the peer it is done with, and the peer is read from on the old path until it
+    // says the same, which keeps messages in order across the switch.
+    fn switch_path(&mut self,
+                   core: &mut Core,
+                   poll: &Poll,
+                   mut socket: Socket,
+                   done_received: bool)
+                   -> ::Res<()> {
+        {
+            let session = self.session
+                .as_ref()
+                .ok_or(CommonError::InvalidMessage("migration before the key exchange"))?;
+            socket.set_session(session.for_path(self.paths + 1))?;
+        }
+        self.paths += 1;
+        let token = core.get_new_token();
+        poll.deregister(&self.socket)?;
+        poll.register(&self.socket,
+                      token,
+                      Ready::error() | Ready::hup() | Ready::readable() | Ready::writable(),
+                      PollOpt::edge())?;
+        poll.register(&socket,
+                      self.token,
+                      Ready::error() | Ready::hup() | Ready::readable(),
+                      PollOpt::edge())?;
+        let timeout = core.set_timeout(Duration::from_secs(RETIRE_TIMEOUT_SECS),
+                                       CoreTimer::new(self.token, RETIRE_TIMER_ID))?;
+        let _ = core.insert_state(token, Rc::new(RefCell::new(RetiringPath { conn: self.token })));
+
+        socket.set_drop_policy(self.drop_policy);
+        socket.set_rate_limit(self.rate_limit.clone());
+        socket.set_queue_limits(self.queue_limits.clone());
+        socket.set_buffer_pool(core.buffer_pool().clone());
+        if let Err(e) = socket.stream_oversized(&Message::Data(Vec::new())) {
+            debug!("Could not stream oversized messages: {:?}", e);
+        }
+        let mut old = mem::replace(&mut self.socket, socket);
+        // Nothing would wake the old path up if it were throttled
+        old.set_rate_limit(RateLimit::default());
+
+        self.retiring = Some(Retiring {
+                                 token: token,
+                                 socket: old,
+                                 timeout: timeout,
+                                 done_sent: false,
+                                 done_received: done_received,
+                                 flushed: false,
+                             });
+        self.relayed = None;
+        if let Some(timeout) = self.migration_timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
+        trace!("{:?} - Migrated connection to {:?} onto a direct path",
+               self.our_id,
+               self.their_id);
+        Ok(())
+    }
+
+    fn retiring_ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        if kind.is_error() {
+            debug!("{:?} - Error on old path to {:?}: {:?}",
+                   self.our_id,
+                   self.their_id,
+                   self.retiring.as_ref().map(|retiring| retiring.socket.take_error()));
+            return self.terminate(core, poll);
+        }
+        if kind.is_writable() && !self.flush_retiring(core, poll) {
+            return;
+        }
+        if kind.is_readable() || kind.is_hup() {
+            self.read_retiring(core, poll);
+        }
+        if kind.is_hup() && self.retiring.is_some() {
+            // The peer closes the old path once it has got everything from us
+            self.retire(core, poll);
+        }
+    }
+
+    fn read_retiring(&mut self, core: &mut Core, poll: &Poll) {
+        loop {
+            let res = match self.retiring {
+                Some(ref mut retiring) if !retiring.done_received => {
+                    retiring.socket.read::<Message>()
+                }
+                _ => return self.read(core, poll),
+            };
+            match res {
+                Ok(Some(msg)) => {
+                    if !self.handle_msg(core, poll, msg) {
+                        return;
+                    }
+                }
+                Ok(None) => return,
+                Err(e) => {
+                    debug!("{:?} - Failed to read from old path: {:?}", self.our_id, e);
+                    return self.terminate(core, poll);
+                }
+            }
+        }
+    }
+
+    // Returns whether the connection is still alive.
+    fn flush_retiring(&mut self, core: &mut Core, poll: &Poll) -> bool {
+        let res = match self.retiring {
+            Some(ref mut retiring) => retiring.flush(poll),
+            None => return true,
+        };
+        self.report_receipts();
+        match res {
+            Ok(true) => self.maybe_retire(core, poll),
+            Ok(false) => (),
+            Err(e) => {
+                debug!("{:?} - Failed to write to old path: {:?}", self.our_id, e);
+                self.terminate(core, poll);
+                return false;
+            }
+        }
+        true
+    }
+
+    fn maybe_retire(&mut self, core: &mut Core, poll: &Poll) {
+        if self.retiring
+               .as_ref()
+               .map_or(false, |retiring| retiring.is_drained()) {
+            self.retire(core, poll);
+        }
+    }
+
+    fn retire(&mut self, core: &mut Core, poll: &Poll) {
+        if let Some(ref mut retiring) = self.retiring {
+            retiring.socket.abandon_writes();
+        }
+        self.report_receipts();
+        if let Some(retiring) = self.retiring.take() {
+            let _ = poll.deregister(&retiring.socket);
+            let _ = core.remove_state(retiring.token);
+            let _ = core.cancel_timeout(&retiring.timeout);
+        }
+    }
+
+    // Whether the peer is within its quota. If not, it is throttled or disconnected as the quota
+    // policy says.
+    fn within_quota(&mut self, core: &mut Core, poll: &Poll) -> bool {
+        let (wait, policy) = match self.quota {
+            Some(ref quota) => {
+                match quota.exceeded_for() {
+                    Some(wait) => (wait, quota.policy),
+                    None => {
+                        self.over_quota = false;
+                        return true;
+                    }
+                }
+            }
+            None => return true,
+        };
+        if !self.over_quota {
+            self.over_quota = true;
+            debug!("{:?} - {:?} exceeded its quota", self.our_id, self.their_id);
+            let _ = self.event_tx.send(Event::PeerQuotaExceeded(self.their_id));
+        }
+        match policy {
+            QuotaPolicy::Throttle => self.set_throttle_timer(core, poll, wait),
+            QuotaPolicy::Disconnect => {
+                // Not a peer to get back
+                self.reconnect = None;
+                self.terminate(core, poll);
+            }
+        }
+        false
+    }
+
+    // Retry reading and writing once the rate limit allows, if it held either back.
+    fn schedule_throttled(&mut self, core: &mut Core, poll: &Poll) {
+        if let Some(wait) = self.socket.throttled_for() {
+            self.set_throttle_timer(core, poll, wait);
+        }
+    }
+
+    fn set_throttle_timer(&mut self, core: &mut Core, poll: &Poll, wait: Duration) {
+        if self.throttle_timeout.is_some() {
+            return;
+        }
+        match core.set_timeout(wait, CoreTimer::new(self.token, THROTTLE_TIMER_ID)) {
+            Ok(timeout) => self.throttle_timeout = Some(timeout),
+            Err(e) => {
+                debug!("{:?} - Failed to set throttle timer: {:?}", self.our_id, e);
+                self.terminate(core, poll);
+            }
+        }
     }
 
     fn reset_receive_heartbeat(&mut self, core: &mut Core, poll: &Poll) {
@@ -169,6 +1704,14 @@ impl ActiveConnection {
 }
 
 impl State for ActiveConnection {
+    fn name(&self) -> &'static str {
+        "ActiveConnection"
+    }
+
+    fn peer_id(&self) -> Option<PeerId> {
+        Some(self.their_id)
+    }
+
     fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
         if kind.is_error() || kind.is_hup() {
             trace!("{:?} Terminating connection to peer: {:?}. \
@@ -189,17 +1732,50 @@ impl State for ActiveConnection {
     }
 
     fn write(&mut self, core: &mut Core, poll: &Poll, data: Vec<u8>, priority: Priority) {
-        self.write(core, poll, Some((Message::Data(data), priority)));
-        self.reset_send_heartbeat(core, poll);
+        self.send_data(core, poll, Arc::new(data), priority, None);
     }
 
     fn terminate(&mut self, core: &mut Core, poll: &Poll) {
         self.heartbeat.terminate(core);
+        if let Some(timeout) = self.throttle_timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
+        if let Some(timeout) = self.migration_timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
+        if let Some(timeout) = self.slow_peer_timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
+        self.handle_ext_addr(core, None);
+        // A helper is only worth asking while we can tell it is still up
+        if let Some(helper) = self.their_helper.take() {
+            core.helpers().advertised(helper, false);
+        }
+        self.cancel_migration(core, poll);
+        self.retire(core, poll);
+        self.socket.abandon_writes();
+        self.report_receipts();
+        // Those still waiting on compression never went out either
+        for outgoing in self.outgoing.drain(..) {
+            if let Some(token) = outgoing.receipt {
+                let _ = self.event_tx
+                    .send(Event::MessageNotSent(self.their_id, token));
+            }
+        }
         let _ = poll.deregister(&self.socket);
-        let _ = core.remove_state(self.token);
+        if core.remove_state(self.token).is_some() {
+            if let Some(shards) = core.shards() {
+                shards.closed();
+            }
+            self.metrics.connection_closed(self.their_id);
+            core.trace(self.token,
+                       TraceState::ActiveConnection,
+                       "closed",
+                       &[("peer", &self.their_id)]);
+        }
 
         {
-            let mut guard = unwrap!(self.cm.lock());
+            let mut guard = lock(&self.cm);
             if let Entry::Occupied(mut oe) = guard.entry(self.their_id) {
                 oe.get_mut().active_connection = None;
                 if oe.get().currently_handshaking == 0 {
@@ -211,12 +1787,49 @@ impl State for ActiveConnection {
                    guard.get(&self.their_id));
         }
 
-        let _ = self.event_tx.send(Event::LostPeer(self.their_id));
+        match self.reconnect.take() {
+            Some(reconnect) => reconnect.retry(core),
+            None => {
+                let _ = self.event_tx.send(Event::LostPeer(self.their_id));
+            }
+        }
     }
 
     fn timeout(&mut self, core: &mut Core, poll: &Poll, timer_id: u8) {
+        if timer_id == THROTTLE_TIMER_ID {
+            self.throttle_timeout = None;
+            self.write(core, poll, None);
+            return self.read(core, poll);
+        }
+        if timer_id == MIGRATION_TIMER_ID {
+            return self.handle_migration_timeout(core, poll);
+        }
+        if timer_id == SLOW_PEER_TIMER_ID {
+            return self.check_slow_peer(core, poll);
+        }
+        if timer_id == EXT_ADDR_TIMER_ID {
+            self.ext_addr_query = None;
+            let _ = self.event_tx
+                .send(Event::ExternalAddrReported(self.their_id, None));
+            return;
+        }
+        if timer_id == RETIRE_TIMER_ID {
+            if self.retiring.is_none() {
+                return;
+            }
+            debug!("{:?} - Old path to {:?} did not drain in time",
+                   self.our_id,
+                   self.their_id);
+            return self.terminate(core, poll);
+        }
         match self.heartbeat.timeout(core, timer_id) {
-            HeartbeatAction::Send => self.write(core, poll, Some((Message::Heartbeat, 0))),
+            // Acknowledgements show we are alive just as well
+            HeartbeatAction::Send if !self.acks.is_empty() => self.send_acks(core, poll),
+            HeartbeatAction::Send => {
+                self.advertise_helper(core, poll);
+                self.send_heartbeat(core, poll);
+            }
+            HeartbeatAction::Wait => (),
             HeartbeatAction::Terminate => {
                 debug!("Dropping connection to {:?} due to peer inactivity",
                        self.their_id);
@@ -230,39 +1843,119 @@ impl State for ActiveConnection {
     }
 }
 
+enum Migration {
+    Idle,
+    // We asked the peer for a challenge to answer on dialing it directly
+    Requested,
+    Dialing(Token),
+    // Waiting for the peer to take over the dialed socket
+    Dialed(Socket),
+    // We challenged the peer to prove its identity on dialing us directly
+    Expecting(Challenge),
+}
+
+// The old path of a migrated connection, kept until both sides have drained it.
+struct Retiring {
+    token: Token,
+    socket: Socket,
+    timeout: Timeout,
+    done_sent: bool,
+    done_received: bool,
+    flushed: bool,
+}
+
+impl Retiring {
+    // Send whatever is still queued, followed by `MigrateDone`. Returns whether all of it is sent.
+    fn flush(&mut self, poll: &Poll) -> ::Res<bool> {
+        self.flushed = self.socket.write::<Message>(poll, self.token, None)?;
+        if self.flushed && !self.done_sent {
+            self.done_sent = true;
+            self.flushed = self.socket
+                .write(poll, self.token, Some((Message::MigrateDone, 0)))?;
+        }
+        Ok(self.flushed)
+    }
+
+    fn is_drained(&self) -> bool {
+        self.done_sent && self.done_received && self.flushed
+    }
+}
+
+// Stands in for a connection under the token of its old path while that drains.
+struct RetiringPath {
+    conn: Token,
+}
+
+impl State for RetiringPath {
+    fn name(&self) -> &'static str {
+        "RetiringPath"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        if let Some(state) = core.get_state(self.conn) {
+            let mut state = state.borrow_mut();
+            if let Some(conn) = state.as_any().downcast_mut::<ActiveConnection>() {
+                conn.retiring_ready(core, poll, kind);
+            }
+        }
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
 struct Heartbeat {
     recv_timeout: Timeout,
     recv_timer: CoreTimer,
+    recv_period: Duration,
     send_timeout: Timeout,
     send_timer: CoreTimer,
+    send_period: Duration,
 }
 
 impl Heartbeat {
-    fn new(core: &mut Core, state_id: Token) -> ::Res<Self> {
+    fn new(core: &mut Core,
+           state_id: Token,
+           send_period: Duration,
+           recv_period: Duration)
+           -> ::Res<Self> {
         let recv_timer = CoreTimer::new(state_id, 0);
-        let recv_timeout = core.set_timeout(Duration::from_millis(INACTIVITY_TIMEOUT_MS),
-                                            recv_timer)?;
+        let recv_timeout = core.set_timeout(recv_period, recv_timer)?;
 
         let send_timer = CoreTimer::new(state_id, 1);
-        let send_timeout =
-            core.set_timeout(Duration::from_millis(HEARTBEAT_PERIOD_MS), send_timer)?;
+        let send_delay = core.batched(send_period);
+        let send_timeout = core.set_timeout(send_delay, send_timer)?;
 
         Ok(Heartbeat {
                recv_timeout: recv_timeout,
                recv_timer: recv_timer,
+               recv_period: recv_period,
                send_timeout: send_timeout,
                send_timer: send_timer,
+               send_period: send_period,
            })
     }
 
     fn timeout(&mut self, core: &mut Core, timer_id: u8) -> HeartbeatAction {
+        let paused = core.is_paused();
         if timer_id == self.recv_timer.timer_id {
-            HeartbeatAction::Terminate
+            // The peer may well have been paused along with us, so it is given another period
+            if paused && self.reset_receive(core).is_ok() {
+                HeartbeatAction::Wait
+            } else {
+                HeartbeatAction::Terminate
+            }
         } else {
-            core.set_timeout(Duration::from_millis(HEARTBEAT_PERIOD_MS), self.send_timer)
+            let period = core.batched(self.send_period);
+            core.set_timeout(period, self.send_timer)
                 .map(|t| {
                          self.send_timeout = t;
-                         HeartbeatAction::Send
+                         if paused {
+                             HeartbeatAction::Wait
+                         } else {
+                             HeartbeatAction::Send
+                         }
                      })
                 .unwrap_or_else(|e| {
                                     debug!("Failed to reschedule heartbeat send timer: {:?}", e);
@@ -271,17 +1964,28 @@ impl Heartbeat {
         }
     }
 
+    // Give a peer sending heartbeats every `their_period` as many of them as it is given of ours
+    // before it is considered dead, should that take longer.
+    fn allow_period(&mut self, core: &mut Core, their_period: Duration) -> ::Res<()> {
+        let ours = cmp::max(millis(self.send_period), 1);
+        let allowed = millis(their_period).saturating_mul(millis(self.recv_period)) / ours;
+        if allowed <= millis(self.recv_period) {
+            return Ok(());
+        }
+        self.recv_period = Duration::from_millis(allowed);
+        self.reset_receive(core)
+    }
+
     fn reset_receive(&mut self, core: &mut Core) -> ::Res<()> {
         let _ = core.cancel_timeout(&self.recv_timeout);
-        self.recv_timeout = core.set_timeout(Duration::from_millis(INACTIVITY_TIMEOUT_MS),
-                                             self.recv_timer)?;
+        self.recv_timeout = core.set_timeout(self.recv_period, self.recv_timer)?;
         Ok(())
     }
 
     fn reset_send(&mut self, core: &mut Core) -> ::Res<()> {
         let _ = core.cancel_timeout(&self.send_timeout);
-        self.send_timeout = core.set_timeout(Duration::from_millis(HEARTBEAT_PERIOD_MS),
-                                             self.send_timer)?;
+        let period = core.batched(self.send_period);
+        self.send_timeout = core.set_timeout(period, self.send_timer)?;
         Ok(())
     }
 
@@ -291,7 +1995,50 @@ impl Heartbeat {
     }
 }
 
+// Bytes of data in `msg` that count against the peer's quota.
+fn payload_len(msg: &Message) -> usize {
+    match *msg {
+        Message::Data(ref data) |
+        Message::CompressedData(_, ref data) |
+        Message::StreamData(_, ref data) => data.len(),
+        Message::Sequenced(_, ref msg) => payload_len(msg),
+        _ => 0,
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+// A message of the user's to the peer, not yet handed to the socket.
+struct Outgoing {
+    data: Arc<Vec<u8>>,
+    priority: Priority,
+    receipt: Option<SendToken>,
+}
+
+// Data exchanged with the peer.
+#[derive(Default)]
+struct Traffic {
+    bytes_sent: u64,
+    bytes_received: u64,
+    msgs_sent: u64,
+    msgs_received: u64,
+}
+
+impl Traffic {
+    // `complete` is whether the bytes end a message.
+    fn received(&mut self, bytes: usize, complete: bool) {
+        self.bytes_received += bytes as u64;
+        if complete {
+            self.msgs_received += 1;
+        }
+    }
+}
+
 enum HeartbeatAction {
     Send,
+    // Paused, so neither sending nor giving up on the peer
+    Wait,
     Terminate,
 }
@@ -0,0 +1,323 @@
+// Copyright 2017 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{Identity, Priority, lock};
+use futures::{Async, Future, Poll};
+use futures::sync::{mpsc as futures_mpsc, oneshot};
+use maidsafe_utilities::thread::{self, Joiner};
+use main::{Config, CrustError, Event, PeerId, PrivConnectionInfo, PubConnectionInfo, SendToken,
+           Service, event_channel};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The events of an `AsyncService` that no future is waiting on.
+pub type Events = futures_mpsc::UnboundedReceiver<Event>;
+
+/// Wraps a `Service` to have the outcome of its requests come back as futures rather than as
+/// events, the rest of its events being yielded by an `Events` stream. This is driven by a thread
+/// of its own, so the futures can be waited on or polled from any executor.
+pub struct AsyncService {
+    service: Service,
+    requests: Arc<Mutex<Requests>>,
+    next_token: AtomicUsize,
+    _joiner: Joiner,
+}
+
+impl AsyncService {
+    /// Construct the service with the config read from the config file, as `Service::new` does.
+    pub fn new() -> ::Res<(AsyncService, Events)> {
+        AsyncService::start(Service::new)
+    }
+
+    /// Construct the service with the given config, as `Service::with_config` does.
+    pub fn with_config(config: Config) -> ::Res<(AsyncService, Events)> {
+        AsyncService::start(move |event_tx| Service::with_config(event_tx, config))
+    }
+
+    fn start<F>(new_service: F) -> ::Res<(AsyncService, Events)>
+        where F: FnOnce(::CrustEventSender) -> ::Res<Service>
+    {
+        let (event_tx, event_rx) = event_channel();
+        let service = new_service(event_tx)?;
+
+        let (events_tx, events_rx) = futures_mpsc::unbounded();
+        let requests = Arc::new(Mutex::new(Requests {
+                                               conn_infos: HashMap::new(),
+                                               connects: HashMap::new(),
+                                               identities: HashMap::new(),
+                                               sends: HashMap::new(),
+                                               events_tx: events_tx,
+                                           }));
+        let requests_0 = requests.clone();
+        // Ends once the service is dropped and with it every sender of events, dropping whatever
+        // requests are outstanding.
+        let joiner = thread::named("Crust-Async", move || for event in event_rx.iter() {
+            lock(&requests_0).dispatch(event);
+        });
+
+        Ok((AsyncService {
+                service: service,
+                requests: requests,
+                next_token: AtomicUsize::new(0),
+                _joiner: joiner,
+            },
+            events_rx))
+    }
+
+    /// The wrapped service, for everything without a future of its own.
+    pub fn service(&self) -> &Service {
+        &self.service
+    }
+
+    /// The wrapped service, for everything without a future of its own.
+    pub fn service_mut(&mut self) -> &mut Service {
+        &mut self.service
+    }
+
+    /// Prepare our connection info as `Service::prepare_connection_info` does, resolving to it
+    /// instead of reporting `Event::ConnectionInfoPrepared`.
+    pub fn prepare_connection_info(&self) -> Completion<PrivConnectionInfo> {
+        let (tx, rx) = oneshot::channel();
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed) as u32;
+        let _ = lock(&self.requests).conn_infos.insert(token, tx);
+        self.service.prepare_connection_info(token);
+        Completion { rx: rx }
+    }
+
+    /// Connect to a peer as `Service::connect` does, resolving to the identity it proved instead
+    /// of reporting `Event::ConnectSuccess`, or failing with `CrustError::ConnectFailed` instead
+    /// of reporting `Event::ConnectFailure`. Resolves straight away to the identity of a peer we
+    /// are connected to already.
+    pub fn connect(&self,
+                   our_ci: PrivConnectionInfo,
+                   their_ci: PubConnectionInfo)
+                   -> Completion<Identity> {
+        let (tx, rx) = oneshot::channel();
+        let their_id = their_ci.id;
+        {
+            let mut requests = lock(&self.requests);
+            // Nothing is reported for a peer we are connected to already. Should its connection
+            // not have been reported yet, that report completes the request instead.
+            if let Some(&identity) = requests.identities.get(&their_id) {
+                if self.service.is_connected(&their_id) {
+                    let _ = tx.send(Ok(identity));
+                    return Completion { rx: rx };
+                }
+            }
+            // Registered before connecting, since the outcome can be reported before `connect`
+            // returns.
+            requests
+                .connects
+                .entry(their_id)
+                .or_insert_with(Vec::new)
+                .push(tx);
+        }
+        if let Err(e) = self.service.connect(our_ci, their_ci) {
+            lock(&self.requests).complete_connect(their_id, Err(e));
+        }
+        Completion { rx: rx }
+    }
+
+    /// Send data to a peer as `Service::send_tracked` does, resolving once it is written out
+    /// instead of reporting `Event::MessageSent`, or failing with `CrustError::SendFailed` instead
+    /// of reporting `Event::MessageNotSent`.
+    pub fn send(&self, peer_id: PeerId, msg: Vec<u8>, priority: Priority) -> Completion<()> {
+        let (tx, rx) = oneshot::channel();
+        // Locked throughout, since the outcome can be reported before `send_tracked` returns.
+        let mut requests = lock(&self.requests);
+        match self.service.send_tracked(peer_id, msg, priority) {
+            Ok(token) => {
+                let _ = requests.sends.insert(token, tx);
+            }
+            Err(e) => {
+                let _ = tx.send(Err(e));
+            }
+        }
+        Completion { rx: rx }
+    }
+}
+
+/// The outcome of a request made through `AsyncService`. Fails with `CrustError::ChannelRecv`
+/// should the service be dropped before the request completes.
+pub struct Completion<T> {
+    rx: oneshot::Receiver<::Res<T>>,
+}
+
+impl<T> Future for Completion<T> {
+    type Item = T;
+    type Error = CrustError;
+
+    fn poll(&mut self) -> Poll<T, CrustError> {
+        match self.rx.poll() {
+            Ok(Async::Ready(res)) => res.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(oneshot::Canceled) => Err(CrustError::ChannelRecv(mpsc::RecvError)),
+        }
+    }
+}
+
+// Requests waiting on an event, along with where every other event goes.
+struct Requests {
+    conn_infos: HashMap<u32, oneshot::Sender<::Res<PrivConnectionInfo>>>,
+    connects: HashMap<PeerId, Vec<oneshot::Sender<::Res<Identity>>>>,
+    // Of the peers we are connected to, whichever way
+    identities: HashMap<PeerId, Identity>,
+    sends: HashMap<SendToken, oneshot::Sender<::Res<()>>>,
+    events_tx: futures_mpsc::UnboundedSender<Event>,
+}
+
+impl Requests {
+    fn dispatch(&mut self, event: Event) {
+        let event = match event {
+            Event::ConnectionInfoPrepared(result) => {
+                match self.conn_infos.remove(&result.result_token) {
+                    Some(tx) => {
+                        let _ = tx.send(result.result);
+                        return;
+                    }
+                    None => Event::ConnectionInfoPrepared(result),
+                }
+            }
+            Event::ConnectSuccess(peer_id, identity) => {
+                let _ = self.identities.insert(peer_id, identity);
+                if self.connects.contains_key(&peer_id) {
+                    return self.complete_connect(peer_id, Ok(identity));
+                }
+                Event::ConnectSuccess(peer_id, identity)
+            }
+            Event::BootstrapAccept(peer_id, kind, identity) => {
+                let _ = self.identities.insert(peer_id, identity);
+                self.complete_connect(peer_id, Ok(identity));
+                Event::BootstrapAccept(peer_id, kind, identity)
+            }
+            Event::BootstrapConnect(peer_id, addr, identity) => {
+                let _ = self.identities.insert(peer_id, identity);
+                self.complete_connect(peer_id, Ok(identity));
+                Event::BootstrapConnect(peer_id, addr, identity)
+            }
+            Event::LostPeer(peer_id) => {
+                let _ = self.identities.remove(&peer_id);
+                Event::LostPeer(peer_id)
+            }
+            Event::ConnectFailure(peer_id) => {
+                if self.connects.contains_key(&peer_id) {
+                    return self.complete_connect(peer_id, Err(CrustError::ConnectFailed(peer_id)));
+                }
+                Event::ConnectFailure(peer_id)
+            }
+            Event::MessageSent(peer_id, token) => {
+                match self.sends.remove(&token) {
+                    Some(tx) => {
+                        let _ = tx.send(Ok(()));
+                        return;
+                    }
+                    None => Event::MessageSent(peer_id, token),
+                }
+            }
+            Event::MessageNotSent(peer_id, token) => {
+                match self.sends.remove(&token) {
+                    Some(tx) => {
+                        let _ = tx.send(Err(CrustError::SendFailed(peer_id)));
+                        return;
+                    }
+                    None => Event::MessageNotSent(peer_id, token),
+                }
+            }
+            event => event,
+        };
+        // Nobody listening for events is no reason to stop completing requests
+        let _ = self.events_tx.unbounded_send(event);
+    }
+
+    fn complete_connect(&mut self, peer_id: PeerId, res: ::Res<Identity>) {
+        let txs = match self.connects.remove(&peer_id) {
+            Some(txs) => txs,
+            None => return,
+        };
+        match res {
+            Ok(identity) => {
+                for tx in txs {
+                    let _ = tx.send(Ok(identity));
+                }
+            }
+            Err(e) => {
+                // `CrustError` is not `Clone`, so the first gets the error and the rest are told
+                // the peer could not be connected to.
+                let mut e = Some(e);
+                for tx in txs {
+                    let _ = tx.send(Err(e.take()
+                                        .unwrap_or_else(|| CrustError::ConnectFailed(peer_id))));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Stream;
+    use futures::stream::Wait;
+    use std::time::Duration;
+    use tests::{gen_config, timebomb};
+
+    fn listening() -> (AsyncService, Wait<Events>) {
+        let (mut service, events) = unwrap!(AsyncService::with_config(gen_config()));
+        unwrap!(service.service_mut().start_listening_tcp());
+        let mut events = events.wait();
+        match events.next() {
+            Some(Ok(Event::ListenerStarted(_))) => (),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        (service, events)
+    }
+
+    #[test]
+    fn connect() {
+        timebomb(Duration::from_secs(30), || {
+            let (service_0, _events_0) = listening();
+            let (service_1, mut events_1) = listening();
+
+            let priv_info_0 = unwrap!(service_0.prepare_connection_info().wait());
+            let priv_info_1 = unwrap!(service_1.prepare_connection_info().wait());
+            let pub_info_0 = priv_info_0.to_pub_connection_info();
+            let pub_info_1 = priv_info_1.to_pub_connection_info();
+
+            let connect_0 = service_0.connect(priv_info_0, pub_info_1);
+            let connect_1 = service_1.connect(priv_info_1, pub_info_0);
+            assert_eq!(unwrap!(connect_0.wait()), service_1.service().identity());
+            assert_eq!(unwrap!(connect_1.wait()), service_0.service().identity());
+
+            unwrap!(service_0.send(service_1.service().id(), vec![1, 2, 3], 0).wait());
+            match events_1.next() {
+                Some(Ok(Event::NewMessage(peer_id, data))) => {
+                    assert_eq!(peer_id, service_0.service().id());
+                    assert_eq!(data, vec![1, 2, 3]);
+                }
+                event => panic!("Unexpected event: {:?}", event),
+            }
+
+            // Already connected
+            let priv_info_0 = unwrap!(service_0.prepare_connection_info().wait());
+            let priv_info_1 = unwrap!(service_1.prepare_connection_info().wait());
+            let pub_info_1 = priv_info_1.to_pub_connection_info();
+            let connect_0 = service_0.connect(priv_info_0, pub_info_1);
+            assert_eq!(unwrap!(connect_0.wait()), service_1.service().identity());
+        })
+    }
+}
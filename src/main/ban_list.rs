@@ -0,0 +1,190 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::lock;
+use main::{Config, PeerId};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const DEFAULT_BAN_AFTER_FAILURES: u32 = 3;
+const DEFAULT_BAN_SECS: u64 = 600;
+
+struct Inner {
+    blacklist: HashSet<SocketAddr>,
+    // Handshake failures in a row, per peer not banned yet.
+    failures: HashMap<PeerId, u32>,
+    // When each temporary ban ends.
+    banned: HashMap<PeerId, Instant>,
+    ban_after: u32,
+    ban_for: Duration,
+}
+
+impl Inner {
+    fn ban(&mut self, peer_id: PeerId) {
+        let _ = self.failures.remove(&peer_id);
+        let _ = self.banned.insert(peer_id, Instant::now() + self.ban_for);
+    }
+}
+
+/// Who not to dial: endpoints blacklisted for good, in the config or through the `Service`, and
+/// peers banned for a while after repeatedly failing the handshake or misbehaving.
+///
+/// Bans are by peer rather than by endpoint, since an endpoint failing says little about the
+/// peer: it may be a LAN address reaching some other host, or a path that merely lost the race.
+/// So only failures down to the peer itself count, once it has answered with its key. Shared by
+/// everything on the event loop that dials out, and by the `Service`.
+#[derive(Clone)]
+pub struct BanList {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl BanList {
+    pub fn new(config: &Config) -> Self {
+        let blacklist = config
+            .blacklisted_contacts
+            .as_ref()
+            .map_or_else(HashSet::new, |contacts| contacts.iter().cloned().collect());
+        let ban_after = config
+            .ban_after_failures
+            .unwrap_or(DEFAULT_BAN_AFTER_FAILURES);
+        let ban_for = Duration::from_secs(config.ban_secs.unwrap_or(DEFAULT_BAN_SECS));
+        BanList {
+            inner: Arc::new(Mutex::new(Inner {
+                                           blacklist: blacklist,
+                                           failures: HashMap::new(),
+                                           banned: HashMap::new(),
+                                           ban_after: ban_after,
+                                           ban_for: ban_for,
+                                       })),
+        }
+    }
+
+    /// Whether `addr` must never be dialed.
+    pub fn is_blacklisted(&self, addr: &SocketAddr) -> bool {
+        lock(&self.inner).blacklist.contains(addr)
+    }
+
+    /// Whether `peer_id` is banned for now.
+    pub fn is_banned(&self, peer_id: &PeerId) -> bool {
+        let mut inner = lock(&self.inner);
+        match inner.banned.get(peer_id).cloned() {
+            Some(until) if until > Instant::now() => true,
+            Some(_) => {
+                let _ = inner.banned.remove(peer_id);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Never dial `addr` again, until unblacklisted.
+    pub fn blacklist(&self, addr: SocketAddr) {
+        let _ = lock(&self.inner).blacklist.insert(addr);
+    }
+
+    /// Allow dialing `addr` again. Returns whether it was blacklisted.
+    pub fn unblacklist(&self, addr: &SocketAddr) -> bool {
+        lock(&self.inner).blacklist.remove(addr)
+    }
+
+    /// Lift any ban on `peer_id`, forgetting its failures too. Returns whether it was banned.
+    pub fn unban(&self, peer_id: &PeerId) -> bool {
+        let mut inner = lock(&self.inner);
+        let _ = inner.failures.remove(peer_id);
+        inner.banned.remove(peer_id).is_some()
+    }
+
+    /// Count a handshake `peer_id` failed, banning it for a while once there have been
+    /// `ban_after_failures` in a row.
+    pub fn record_failure(&self, peer_id: PeerId) {
+        let mut inner = lock(&self.inner);
+        let failures = {
+            let failures = inner.failures.entry(peer_id).or_insert(0);
+            *failures += 1;
+            *failures
+        };
+        if failures >= inner.ban_after {
+            debug!("Banning {:?} after {} failed handshakes", peer_id, failures);
+            inner.ban(peer_id);
+        }
+    }
+
+    /// Ban `peer_id` for a while straight away, e.g. for proving an identity other than the one
+    /// it was known by.
+    pub fn record_misbehaviour(&self, peer_id: PeerId) {
+        debug!("Banning {:?} for misbehaving", peer_id);
+        lock(&self.inner).ban(peer_id);
+    }
+
+    /// Forget the failures of `peer_id`, having completed a handshake with it.
+    pub fn record_success(&self, peer_id: &PeerId) {
+        let _ = lock(&self.inner).failures.remove(peer_id);
+    }
+
+    /// Blacklisted endpoints.
+    pub fn blacklisted(&self) -> Vec<SocketAddr> {
+        lock(&self.inner).blacklist.iter().cloned().collect()
+    }
+
+    /// Peers banned for now.
+    pub fn banned(&self) -> Vec<PeerId> {
+        let inner = lock(&self.inner);
+        let now = Instant::now();
+        inner
+            .banned
+            .iter()
+            .filter(|&(_, until)| *until > now)
+            .map(|(peer_id, _)| *peer_id)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use main::{Config, PeerId};
+    use rust_sodium::crypto::box_::PublicKey;
+
+    #[test]
+    fn ban_after_failures() {
+        let blacklisted = unwrap!("10.0.0.1:5483".parse());
+        let flaky = PeerId(PublicKey([1; 32]));
+        let mut config = Config::default();
+        config.blacklisted_contacts = Some(vec![blacklisted]);
+        config.ban_after_failures = Some(2);
+        let ban_list = BanList::new(&config);
+
+        assert!(ban_list.is_blacklisted(&blacklisted));
+        assert!(!ban_list.is_banned(&flaky));
+
+        ban_list.record_failure(flaky);
+        ban_list.record_success(&flaky);
+        ban_list.record_failure(flaky);
+        assert!(!ban_list.is_banned(&flaky));
+        ban_list.record_failure(flaky);
+        assert!(ban_list.is_banned(&flaky));
+        assert_eq!(ban_list.banned(), vec![flaky]);
+
+        assert!(ban_list.unban(&flaky));
+        assert!(ban_list.unblacklist(&blacklisted));
+        assert!(!ban_list.is_banned(&flaky));
+        assert!(!ban_list.is_blacklisted(&blacklisted));
+        assert!(!ban_list.unban(&flaky));
+    }
+}
@@ -16,32 +16,107 @@
 // relating to use of the SAFE Network Software.
 
 use config_file_handler::{self, FileHandler};
+use serde_json;
+use std::cmp::Ordering;
 use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, ErrorKind};
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MAX_BOOTSTRAP_CACHE_CONTACTS: usize = 1500;
+// Peers not connected to for this long are pruned.
+const MAX_AGE_SECS: u64 = 30 * 24 * 60 * 60;
+// Peers failing this many times in a row are pruned.
+const MAX_CONSECUTIVE_FAILURES: u32 = 5;
+// Time since the last success after which a peer's score is halved.
+const HALF_LIFE_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Entry {
+    addr: SocketAddr,
+    // Seconds since the epoch.
+    last_success: u64,
+    successes: u32,
+    failures: u32,
+    consecutive_failures: u32,
+}
 
-const _ENABLE_BOOTSTRAP_CACHE: bool = false;
-const _MAX_BOOTSTRAP_CACHE_CONTACTS: usize = 1500;
+impl Entry {
+    // The smoothed connect success rate, halved for every `HALF_LIFE_SECS` since the last success.
+    fn score(&self, now: u64) -> f64 {
+        let attempts = self.successes as f64 + self.failures as f64;
+        let rate = (self.successes as f64 + 1.0) / (attempts + 2.0);
+        let age = now.saturating_sub(self.last_success) as f64;
+        rate * 0.5f64.powf(age / HALF_LIFE_SECS as f64)
+    }
 
-pub struct Cache {
-    file_handler: FileHandler<Vec<SocketAddr>>,
+    fn is_dead(&self, now: u64) -> bool {
+        self.consecutive_failures >= MAX_CONSECUTIVE_FAILURES ||
+        now.saturating_sub(self.last_success) > MAX_AGE_SECS
+    }
 }
 
-impl Cache {
-    pub fn _cleanup() -> ::Res<()> {
-        config_file_handler::cleanup(&Self::get_default_file_name()?)?;
-        Ok(())
+enum Store {
+    FileHandler(FileHandler<Vec<Entry>>),
+    Path(PathBuf),
+}
+
+impl Store {
+    fn read(&self) -> ::Res<Vec<Entry>> {
+        match *self {
+            Store::FileHandler(ref file_handler) => Ok(file_handler.read_file()?),
+            Store::Path(ref path) => {
+                let file = File::open(path)?;
+                Ok(serde_json::from_reader(file)
+                       .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?)
+            }
+        }
     }
 
+    fn write(&self, entries: &Vec<Entry>) -> ::Res<()> {
+        match *self {
+            Store::FileHandler(ref file_handler) => Ok(file_handler.write_file(entries)?),
+            Store::Path(ref path) => {
+                let mut file = File::create(path)?;
+                Ok(serde_json::to_writer_pretty(&mut file, entries)
+                       .map_err(|e| io::Error::new(ErrorKind::Other, e))?)
+            }
+        }
+    }
+}
+
+/// Peers we have bootstrapped off before, kept on disk so we can rejoin the network quickly after
+/// a restart. Each is scored by how recently and how reliably we have connected to it, and those
+/// which keep failing or have not been connected to for long are pruned.
+pub struct Cache {
+    store: Store,
+    entries: Vec<Entry>,
+    dirty: bool,
+}
+
+impl Cache {
+    /// Open the cache file `name`, looked for where the config file is unless it is an absolute
+    /// path.
     pub fn new(name: &Option<String>) -> ::Res<Self> {
-        let name = if let Some(name) = name.clone() {
-            OsString::from(name)
-        } else {
-            Self::get_default_file_name()?
+        let store = match *name {
+            Some(ref name) if Path::new(name).is_absolute() => Store::Path(PathBuf::from(name)),
+            Some(ref name) => Store::FileHandler(FileHandler::new(&OsString::from(name), true)?),
+            None => Store::FileHandler(FileHandler::new(&Self::get_default_file_name()?, true)?),
         };
+        let entries = store
+            .read()
+            .unwrap_or_else(|e| {
+                                debug!("Could not read the bootstrap cache: {:?}", e);
+                                Vec::new()
+                            });
 
         Ok(Cache {
-            file_handler: try!(FileHandler::new(&name, true)), // last_updated: Instant::now(),
-        })
+               store: store,
+               entries: entries,
+               dirty: false,
+           })
     }
 
     pub fn get_default_file_name() -> ::Res<OsString> {
@@ -50,63 +125,131 @@ impl Cache {
         Ok(name)
     }
 
-    // pub fn update_contacts(&mut self,
-    //                        contacts: Vec<StaticContactInfo>,
-    //                        prune: Vec<StaticContactInfo>)
-    //                        -> ::Res<()> {
-    //     if ENABLE_BOOTSTRAP_CACHE {
-    //         try!(self.insert_contacts(contacts, prune));
-    //         // TODO(Team) this implementation is missing and should be considered in next
-    //         // planning
-    //         if Instant::now() > self.last_updated + Self::duration_between_updates() {
-    //             // self.check_bootstrap_contacts();
-    //         }
-    //     }
-
-    //     Ok(())
-    // }
-
-    pub fn read_file(&mut self) -> Vec<SocketAddr> {
-        self.file_handler
-            .read_file()
-            .ok()
-            .unwrap_or_else(|| vec![])
+    /// The cached peers still worth trying, best first.
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        let now = now_secs();
+        let mut entries: Vec<_> = self.entries
+            .iter()
+            .filter(|entry| !entry.is_dead(now))
+            .collect();
+        entries.sort_by(|a, b| {
+                            b.score(now)
+                                .partial_cmp(&a.score(now))
+                                .unwrap_or(Ordering::Equal)
+                        });
+        entries.into_iter().map(|entry| entry.addr).collect()
     }
 
-    pub fn remove_peer_acceptor(&mut self, _peer: SocketAddr) {}
-
-    // fn duration_between_updates() -> Duration {
-    //     Duration::from_secs(4 * 60 * 60)
-    // }
-
-    // fn max_contacts() -> usize {
-    //     MAX_BOOTSTRAP_CACHE_CONTACTS
-    // }
-
-    // fn insert_contacts(&mut self,
-    //                    mut contacts: Vec<StaticContactInfo>,
-    //                    prune: Vec<StaticContactInfo>)
-    //                    -> ::Res<()> {
-    //     let mut bootstrap_contacts = self.read_file().unwrap_or_else(|e| {
-    //         debug!("CrustError reading bootstrap cache file: {}.", e);
-    //         Vec::new()
-    //     });
-
-    //     bootstrap_contacts.retain(|contact| !prune.contains(&contact));
-    //     contacts.retain(|contact| !bootstrap_contacts.contains(&contact));
-
-    //     if bootstrap_contacts.is_empty() {
-    //         bootstrap_contacts = contacts;
-    //     } else {
-    //         loop {
-    //             if bootstrap_contacts.len() < Self::max_contacts() && !contacts.is_empty() {
-    //                 bootstrap_contacts.insert(0usize, contacts.remove(0usize));
-    //             } else {
-    //                 break;
-    //             }
-    //         }
-    //     }
-
-    //     Ok(try!(self.file_handler.write_file(&bootstrap_contacts)))
-    // }
+    pub fn record_success(&mut self, addr: SocketAddr) {
+        let now = now_secs();
+        self.dirty = true;
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.addr == addr) {
+            entry.last_success = now;
+            entry.successes = entry.successes.saturating_add(1);
+            entry.consecutive_failures = 0;
+            return;
+        }
+        self.entries
+            .push(Entry {
+                      addr: addr,
+                      last_success: now,
+                      successes: 1,
+                      failures: 0,
+                      consecutive_failures: 0,
+                  });
+    }
+
+    /// Only peers already cached are kept track of.
+    pub fn record_failure(&mut self, addr: SocketAddr) {
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.addr == addr) {
+            entry.failures = entry.failures.saturating_add(1);
+            entry.consecutive_failures = entry.consecutive_failures.saturating_add(1);
+            self.dirty = true;
+        }
+    }
+
+    /// Prune the cache and write it out, if anything changed.
+    pub fn commit(&mut self) -> ::Res<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        self.prune(now_secs());
+        self.store.write(&self.entries)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn prune(&mut self, now: u64) {
+        self.entries.retain(|entry| !entry.is_dead(now));
+        if self.entries.len() > MAX_BOOTSTRAP_CACHE_CONTACTS {
+            self.entries
+                .sort_by(|a, b| {
+                             b.score(now)
+                                 .partial_cmp(&a.score(now))
+                                 .unwrap_or(Ordering::Equal)
+                         });
+            self.entries.truncate(MAX_BOOTSTRAP_CACHE_CONTACTS);
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn score_and_prune() {
+        let mut path = env::temp_dir();
+        path.push(format!("crust_test_{}.bootstrap.cache", ::rand::random::<u64>()));
+        let name = Some(unwrap!(path.to_str()).to_owned());
+
+        let reliable: SocketAddr = unwrap!("10.0.0.1:5483".parse());
+        let flaky: SocketAddr = unwrap!("10.0.0.2:5483".parse());
+        let dead: SocketAddr = unwrap!("10.0.0.3:5483".parse());
+        {
+            let mut cache = unwrap!(Cache::new(&name));
+            assert!(cache.peers().is_empty());
+            for addr in &[flaky, reliable, dead] {
+                cache.record_success(*addr);
+            }
+            cache.record_success(reliable);
+            cache.record_failure(flaky);
+            for _ in 0..MAX_CONSECUTIVE_FAILURES {
+                cache.record_failure(dead);
+            }
+            // Unknown peers failing are not cached
+            cache.record_failure(unwrap!("10.0.0.4:5483".parse()));
+            unwrap!(cache.commit());
+        }
+
+        let cache = unwrap!(Cache::new(&name));
+        assert_eq!(cache.peers(), vec![reliable, flaky]);
+        assert_eq!(cache.entries.len(), 2);
+        unwrap!(fs::remove_file(&path));
+    }
+
+    #[test]
+    fn recency() {
+        let entry = Entry {
+            addr: unwrap!("10.0.0.1:5483".parse()),
+            last_success: 0,
+            successes: 1,
+            failures: 1,
+            consecutive_failures: 0,
+        };
+        assert_eq!(entry.score(0), 0.5);
+        assert_eq!(entry.score(HALF_LIFE_SECS), 0.25);
+        assert!(!entry.is_dead(MAX_AGE_SECS));
+        assert!(entry.is_dead(MAX_AGE_SECS + 1));
+    }
 }
@@ -0,0 +1,467 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+// Resolves bootstrap seeds given as DNS names. Plain names are looked up by the OS; SRV and TXT
+// records, which it has no API for, are queried from the nameservers in `/etc/resolv.conf`, over
+// TCP should the answer not fit in a datagram. Where there is no such file, as on Windows, only
+// plain names can be resolved.
+
+use rand;
+use rust_sodium::crypto::box_::{PUBLICKEYBYTES, PublicKey};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+#[cfg(unix)]
+const RESOLV_CONF: &'static str = "/etc/resolv.conf";
+const DNS_PORT: u16 = 53;
+const QUERY_TIMEOUT_MS: u64 = 2000;
+const QUERY_ATTEMPTS: usize = 2;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+// Most compression pointers to follow in a name, so a malicious response can't loop us.
+const MAX_POINTERS: usize = 16;
+
+/// A bootstrap contact learnt from DNS, with the public key it must present if the record gave
+/// one. DNS answers are not authenticated, so the key only narrows who is taken for the contact:
+/// a peer without it is refused, while one with it is trusted no more than any other contact.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Contact {
+    pub addr: SocketAddr,
+    pub pk: Option<PublicKey>,
+}
+
+/// Resolve each seed, which is one of:
+///
+/// - `host:port`, looked up as any other name.
+/// - `srv:_crust._tcp.example.com`, whose SRV records give the hosts and ports, by priority.
+/// - `txt:seeds.example.com`, whose TXT records each hold a `host:port`, optionally followed by
+///   ` pk=` and the hex public key of the peer there.
+///
+/// Blocks on the lookups, and skips seeds which fail.
+pub fn resolve(seeds: &[String]) -> Vec<Contact> {
+    let mut contacts = Vec::new();
+    for seed in seeds {
+        let res = if seed.starts_with("srv:") {
+            resolve_srv(&seed[4..])
+        } else if seed.starts_with("txt:") {
+            resolve_txt(&seed[4..])
+        } else {
+            resolve_host(seed).map(|addrs| {
+                                        addrs
+                                            .into_iter()
+                                            .map(|addr| Contact { addr: addr, pk: None })
+                                            .collect()
+                                    })
+        };
+        match res {
+            Ok(resolved) => {
+                trace!("Bootstrap seed {} resolved to {:?}", seed, resolved);
+                contacts.extend(resolved);
+            }
+            Err(e) => debug!("Could not resolve bootstrap seed {}: {:?}", seed, e),
+        }
+    }
+    contacts
+}
+
+fn resolve_host(host_port: &str) -> io::Result<Vec<SocketAddr>> {
+    Ok(host_port.to_socket_addrs()?.collect())
+}
+
+fn resolve_srv(name: &str) -> io::Result<Vec<Contact>> {
+    let response = query(name, TYPE_SRV)?;
+    let mut targets = parse_srv(&response)?;
+    // Lowest priority first, then heaviest weight
+    targets.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+
+    let mut contacts = Vec::new();
+    for (_, _, port, target) in targets {
+        match resolve_host(&format!("{}:{}", target, port)) {
+            Ok(addrs) => {
+                contacts.extend(addrs
+                                    .into_iter()
+                                    .map(|addr| Contact { addr: addr, pk: None }))
+            }
+            Err(e) => debug!("Could not resolve SRV target {}: {:?}", target, e),
+        }
+    }
+    Ok(contacts)
+}
+
+fn resolve_txt(name: &str) -> io::Result<Vec<Contact>> {
+    let response = query(name, TYPE_TXT)?;
+    let mut contacts = Vec::new();
+    for txt in parse_txt(&response)? {
+        match parse_txt_contact(&txt) {
+            Some((host_port, pk)) => {
+                match resolve_host(host_port) {
+                    Ok(addrs) => {
+                        contacts.extend(addrs
+                                            .into_iter()
+                                            .map(|addr| Contact { addr: addr, pk: pk }))
+                    }
+                    Err(e) => debug!("Could not resolve TXT contact {}: {:?}", host_port, e),
+                }
+            }
+            None => debug!("Ignoring TXT record {:?} of {}", txt, name),
+        }
+    }
+    Ok(contacts)
+}
+
+// `host:port` and the optional public key of a TXT record.
+fn parse_txt_contact(txt: &str) -> Option<(&str, Option<PublicKey>)> {
+    let mut parts = txt.split_whitespace();
+    let host_port = match parts.next() {
+        Some(host_port) => host_port,
+        None => return None,
+    };
+    let mut pk = None;
+    for part in parts {
+        if part.starts_with("pk=") {
+            match parse_pk(&part[3..]) {
+                Some(key) => pk = Some(key),
+                None => return None,
+            }
+        }
+    }
+    Some((host_port, pk))
+}
+
+fn parse_pk(hex: &str) -> Option<PublicKey> {
+    if hex.len() != PUBLICKEYBYTES * 2 {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(PUBLICKEYBYTES);
+    for i in 0..PUBLICKEYBYTES {
+        match u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16) {
+            Ok(byte) => bytes.push(byte),
+            Err(_) => return None,
+        }
+    }
+    PublicKey::from_slice(&bytes)
+}
+
+// Ask each nameserver in turn until one answers.
+fn query(name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let nameservers = nameservers()?;
+    let id: u16 = rand::random();
+    let request = build_query(id, name, qtype)?;
+    let mut last_err = io::Error::new(ErrorKind::NotFound, "No nameservers configured");
+
+    for nameserver in nameservers {
+        let bind_addr = match nameserver {
+            IpAddr::V4(..) => "0.0.0.0:0",
+            IpAddr::V6(..) => "[::]:0",
+        };
+        let socket = UdpSocket::bind(bind_addr)?;
+        socket
+            .set_read_timeout(Some(Duration::from_millis(QUERY_TIMEOUT_MS)))?;
+        let server = SocketAddr::new(nameserver, DNS_PORT);
+        for _ in 0..QUERY_ATTEMPTS {
+            if let Err(e) = socket.send_to(&request, server) {
+                last_err = e;
+                break;
+            }
+            let mut buf = [0; 4096];
+            match socket.recv_from(&mut buf) {
+                Ok((len, from)) if from == server && len >= 4 && buf[..2] == request[..2] => {
+                    if !is_truncated(&buf[..len]) {
+                        return Ok(buf[..len].to_vec());
+                    }
+                    match query_tcp(server, &request) {
+                        Ok(response) => return Ok(response),
+                        Err(e) => {
+                            last_err = e;
+                            break;
+                        }
+                    }
+                }
+                Ok(_) => (),
+                Err(e) => last_err = e,
+            }
+        }
+    }
+    Err(last_err)
+}
+
+// Whether the server had more to answer than fits in a datagram.
+fn is_truncated(msg: &[u8]) -> bool {
+    read_u16(msg, 2).map_or(false, |flags| flags & 0x0200 != 0)
+}
+
+// Ask again over TCP, where each message goes prefixed with its length.
+fn query_tcp(server: SocketAddr, request: &[u8]) -> io::Result<Vec<u8>> {
+    let timeout = Duration::from_millis(QUERY_TIMEOUT_MS);
+    let mut stream = TcpStream::connect_timeout(&server, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut msg = Vec::with_capacity(2 + request.len());
+    push_u16(&mut msg, request.len() as u16);
+    msg.extend_from_slice(request);
+    stream.write_all(&msg)?;
+
+    let mut len = [0; 2];
+    stream.read_exact(&mut len)?;
+    let mut response = vec![0; read_u16(&len, 0)? as usize];
+    stream.read_exact(&mut response)?;
+    if response.len() < 2 || response[..2] != request[..2] {
+        return Err(malformed());
+    }
+    Ok(response)
+}
+
+#[cfg(unix)]
+fn nameservers() -> io::Result<Vec<IpAddr>> {
+    use std::fs::File;
+
+    let mut conf = String::new();
+    let _ = File::open(RESOLV_CONF)?.read_to_string(&mut conf)?;
+    let mut nameservers = Vec::new();
+    for line in conf.lines() {
+        let mut words = line.split_whitespace();
+        if let (Some("nameserver"), Some(ip)) = (words.next(), words.next()) {
+            if let Ok(ip) = ip.parse() {
+                nameservers.push(ip);
+            }
+        }
+    }
+    Ok(nameservers)
+}
+
+#[cfg(not(unix))]
+fn nameservers() -> io::Result<Vec<IpAddr>> {
+    Err(io::Error::new(ErrorKind::Other,
+                       "SRV and TXT records can only be looked up where there is /etc/resolv.conf"))
+}
+
+fn build_query(id: u16, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let mut msg = Vec::with_capacity(512);
+    push_u16(&mut msg, id);
+    // Recursion desired
+    push_u16(&mut msg, 0x0100);
+    // One question, no answer, authority or additional records
+    push_u16(&mut msg, 1);
+    push_u16(&mut msg, 0);
+    push_u16(&mut msg, 0);
+    push_u16(&mut msg, 0);
+    for label in name.trim_right_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "Invalid DNS name"));
+        }
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    push_u16(&mut msg, qtype);
+    push_u16(&mut msg, CLASS_IN);
+    Ok(msg)
+}
+
+// The `(priority, weight, port, target)` of each SRV record in the answer.
+fn parse_srv(msg: &[u8]) -> io::Result<Vec<(u16, u16, u16, String)>> {
+    let mut records = Vec::new();
+    for (rtype, start, len) in answers(msg)? {
+        if rtype != TYPE_SRV || len < 7 {
+            continue;
+        }
+        let priority = read_u16(msg, start)?;
+        let weight = read_u16(msg, start + 2)?;
+        let port = read_u16(msg, start + 4)?;
+        let (target, _) = read_name(msg, start + 6)?;
+        records.push((priority, weight, port, target));
+    }
+    Ok(records)
+}
+
+// The text of each TXT record in the answer, its strings joined.
+fn parse_txt(msg: &[u8]) -> io::Result<Vec<String>> {
+    let mut records = Vec::new();
+    for (rtype, start, len) in answers(msg)? {
+        if rtype != TYPE_TXT {
+            continue;
+        }
+        let mut text = Vec::new();
+        let mut pos = start;
+        while pos < start + len {
+            let str_len = msg[pos] as usize;
+            let str_end = pos + 1 + str_len;
+            if str_end > start + len {
+                return Err(malformed());
+            }
+            text.extend_from_slice(&msg[pos + 1..str_end]);
+            pos = str_end;
+        }
+        records.push(String::from_utf8_lossy(&text).into_owned());
+    }
+    Ok(records)
+}
+
+// The type, data offset and data length of each answer record.
+fn answers(msg: &[u8]) -> io::Result<Vec<(u16, usize, usize)>> {
+    let flags = read_u16(msg, 2)?;
+    let rcode = flags & 0x000f;
+    if flags & 0x8000 == 0 || rcode != 0 {
+        return Err(io::Error::new(ErrorKind::NotFound,
+                                  format!("DNS query failed with code {}", rcode)));
+    }
+    let questions = read_u16(msg, 4)?;
+    let answers = read_u16(msg, 6)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        let (_, end) = read_name(msg, pos)?;
+        pos = end + 4;
+    }
+
+    let mut records = Vec::with_capacity(answers as usize);
+    for _ in 0..answers {
+        let (_, end) = read_name(msg, pos)?;
+        let rtype = read_u16(msg, end)?;
+        let len = read_u16(msg, end + 8)? as usize;
+        let start = end + 10;
+        if start + len > msg.len() {
+            return Err(malformed());
+        }
+        records.push((rtype, start, len));
+        pos = start + len;
+    }
+    Ok(records)
+}
+
+// Read the possibly compressed name at `pos`, returning it and where the record goes on after it.
+fn read_name(msg: &[u8], mut pos: usize) -> io::Result<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *msg.get(pos).ok_or_else(malformed)? as usize;
+        if len == 0 {
+            return Ok((labels.join("."), end.unwrap_or(pos + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            pointers += 1;
+            if pointers > MAX_POINTERS {
+                return Err(malformed());
+            }
+            if end.is_none() {
+                end = Some(pos + 2);
+            }
+            pos = read_u16(msg, pos)? as usize & 0x3fff;
+            continue;
+        }
+        let label = msg.get(pos + 1..pos + 1 + len).ok_or_else(malformed)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len;
+    }
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> io::Result<u16> {
+    match msg.get(pos..pos + 2) {
+        Some(bytes) => Ok((bytes[0] as u16) << 8 | bytes[1] as u16),
+        None => Err(malformed()),
+    }
+}
+
+fn push_u16(msg: &mut Vec<u8>, value: u16) {
+    msg.push((value >> 8) as u8);
+    msg.push(value as u8);
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "Malformed DNS response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // A response to `build_query` with the answers appended, naming the question by pointer.
+    fn response(query: &[u8], answers: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut msg = query.to_vec();
+        msg[2] |= 0x80;
+        msg[7] = answers.len() as u8;
+        for &(rtype, ref data) in answers {
+            msg.extend_from_slice(&[0xc0, 12]);
+            push_u16(&mut msg, rtype);
+            push_u16(&mut msg, CLASS_IN);
+            msg.extend_from_slice(&[0, 0, 0, 60]);
+            push_u16(&mut msg, data.len() as u16);
+            msg.extend_from_slice(data);
+        }
+        msg
+    }
+
+    #[test]
+    fn srv_and_txt() {
+        let query = unwrap!(build_query(7, "_crust._tcp.example.com", TYPE_SRV));
+
+        let mut srv = vec![0, 10, 0, 5, 0x15, 0x6b];
+        srv.extend_from_slice(b"\x04seed\xc0\x18");
+        let txt = b"\x0c10.0.0.1:548\x013".to_vec();
+        let msg = response(&query, &[(TYPE_SRV, srv), (TYPE_TXT, txt)]);
+
+        assert_eq!(unwrap!(parse_srv(&msg)),
+                   vec![(10, 5, 5483, "seed.example.com".to_owned())]);
+        assert_eq!(unwrap!(parse_txt(&msg)), vec!["10.0.0.1:5483".to_owned()]);
+
+        let pk = "ab".repeat(PUBLICKEYBYTES);
+        let txt = format!("10.0.0.1:5483 pk={}", pk);
+        let (host_port, key) = unwrap!(parse_txt_contact(&txt));
+        assert_eq!(host_port, "10.0.0.1:5483");
+        assert_eq!(key, PublicKey::from_slice(&[0xab; PUBLICKEYBYTES]));
+        assert!(parse_txt_contact("10.0.0.1:5483 pk=00").is_none());
+    }
+
+    #[test]
+    fn truncated_answer_over_tcp() {
+        let query = unwrap!(build_query(9, "_crust._tcp.example.com", TYPE_TXT));
+        let mut truncated = response(&query, &[]);
+        assert!(!is_truncated(&truncated));
+        truncated[2] |= 0x02;
+        assert!(is_truncated(&truncated));
+
+        let txt = b"\x0d10.0.0.1:5483".to_vec();
+        let full = response(&query, &[(TYPE_TXT, txt)]);
+        let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        let server = unwrap!(listener.local_addr());
+        let expected_query = query.clone();
+        let answer = full.clone();
+        let serve = thread::spawn(move || {
+            let (mut stream, _) = unwrap!(listener.accept());
+            let mut len = [0; 2];
+            unwrap!(stream.read_exact(&mut len));
+            let mut request = vec![0; unwrap!(read_u16(&len, 0)) as usize];
+            unwrap!(stream.read_exact(&mut request));
+            assert_eq!(request, expected_query);
+            let mut msg = Vec::new();
+            push_u16(&mut msg, answer.len() as u16);
+            msg.extend_from_slice(&answer);
+            unwrap!(stream.write_all(&msg));
+        });
+
+        let msg = unwrap!(query_tcp(server, &query));
+        unwrap!(serve.join());
+        assert_eq!(msg, full);
+        assert_eq!(unwrap!(parse_txt(&msg)), vec!["10.0.0.1:5483".to_owned()]);
+    }
+}
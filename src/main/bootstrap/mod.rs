@@ -16,46 +16,103 @@
 // relating to use of the SAFE Network Software.
 
 mod cache;
+mod dns;
+mod relay_watch;
 mod try_peer;
 
 use self::cache::Cache;
+use self::dns::Contact;
+pub use self::relay_watch::{OurRelay, RelayWatch};
 use self::try_peer::TryPeer;
-use common::{BootstrapDenyReason, Core, CoreTimer, ExternalReachability, NameHash, Socket, State};
-use main::{ActiveConnection, Config, ConnectionMap, CrustError, Event, PeerId};
+use common::{BandwidthLimits, Core, CoreMessage, CoreTimer, CrustUser, DropPolicy,
+             ExternalReachability, Identity, IdentityKeys, NameHash, Socket, SocketConfig, State,
+             Timeout, lock};
+use main::{ActiveConnection, BanList, CompressionPolicy, Config, ConnectionMap, CrustError, Event,
+           HandshakeKind, IpWhitelist, Metrics, PeerId, compression_policy, drop_policy,
+           inactivity_timeout, keep_alive_period, socket_config};
+use maidsafe_utilities::thread;
 use mio::{Poll, Token};
-use mio::timer::Timeout;
 use rand::{self, Rng};
 use rust_sodium::crypto::box_::PublicKey;
 use service_discovery::ServiceDiscovery;
 use std::any::Any;
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::mem;
 use std::net::SocketAddr;
 use std::rc::{Rc, Weak};
 use std::sync::mpsc::{self, Receiver};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-const BOOTSTRAP_TIMEOUT_SEC: u64 = 10;
+const DEFAULT_BOOTSTRAP_TIMEOUT_SEC: u64 = 10;
 const SERVICE_DISCOVERY_TIMEOUT_SEC: u64 = 1;
 const BOOTSTRAP_TIMER_ID: u8 = 0;
 const SERVICE_DISCOVERY_TIMER_ID: u8 = BOOTSTRAP_TIMER_ID + 1;
 const MAX_CONTACTS_EXPECTED: usize = 1500;
 
+/// Why a contact could not be bootstrapped off.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootstrapFailure {
+    /// It could not be dialed at all.
+    Unreachable,
+    /// The connection broke before the peer took us on.
+    Disconnected,
+    /// It refused us for being on another network.
+    NetworkMismatch,
+    /// It refused us for not being reachable from outside.
+    NotReachable,
+    /// It refused to take us on through it, see `Config::bootstrap_via_relay`, as it does not
+    /// relay.
+    NotRelaying,
+    /// It did not have the public key its DNS record gave.
+    KeyMismatch,
+    /// It speaks a version of the wire protocol we do not, or does not speak ours.
+    IncompatibleVersion,
+    /// It was not dialed, or did not answer, before bootstrapping ended, e.g. on the deadline.
+    TimedOut,
+    /// It turned out to be a peer banned for now for failing handshakes or misbehaving.
+    Banned,
+}
+
 pub struct Bootstrap {
     token: Token,
     cm: ConnectionMap,
     peers: Vec<SocketAddr>,
+    // Whether we have started trying peers.
+    started: bool,
+    // Peers to try once there is room among the children.
+    queue: VecDeque<SocketAddr>,
+    fan_out: usize,
+    connections_wanted: usize,
+    connected: usize,
+    failures: Vec<(SocketAddr, BootstrapFailure)>,
+    // Whether bootstrap seeds given as DNS names are still being resolved.
+    dns_pending: bool,
+    // Public keys the peers learnt from DNS records must have.
+    expected_keys: HashMap<SocketAddr, PublicKey>,
     blacklist: HashSet<SocketAddr>,
+    ban_list: BanList,
+    whitelist: IpWhitelist,
+    metrics: Metrics,
     name_hash: NameHash,
     ext_reachability: ExternalReachability,
+    // Where the contact we bootstrap off through is recorded when bootstrapping as a relayed node.
+    our_relay: OurRelay,
     our_pk: PublicKey,
+    identity: IdentityKeys,
     event_tx: ::CrustEventSender,
     sd_meta: Option<ServiceDiscMeta>,
     bs_timer: CoreTimer,
     bs_timeout: Timeout,
     cache: Cache,
-    children: HashSet<Token>,
+    keep_alive: Duration,
+    inactivity_timeout: Duration,
+    drop_policy: DropPolicy,
+    bandwidth: BandwidthLimits,
+    compression: Option<CompressionPolicy>,
+    socket_config: SocketConfig,
+    // Peers being tried and when they were dialed.
+    children: HashMap<Token, (SocketAddr, Instant)>,
     self_weak: Weak<RefCell<Bootstrap>>,
 }
 
@@ -64,22 +121,31 @@ impl Bootstrap {
                  poll: &Poll,
                  name_hash: NameHash,
                  ext_reachability: ExternalReachability,
+                 our_relay: OurRelay,
                  our_pk: PublicKey,
+                 identity: IdentityKeys,
                  cm: ConnectionMap,
                  config: &Config,
+                 bandwidth: BandwidthLimits,
                  blacklist: HashSet<SocketAddr>,
+                 ban_list: BanList,
+                 whitelist: IpWhitelist,
+                 metrics: Metrics,
                  token: Token,
                  service_discovery_token: Token,
                  event_tx: ::CrustEventSender)
                  -> ::Res<()> {
         let mut peers = Vec::with_capacity(MAX_CONTACTS_EXPECTED);
 
-        let mut cache = Cache::new(&config.bootstrap_cache_name)?;
-        peers.extend(cache.read_file());
+        let cache = Cache::new(&config.bootstrap_cache_name)?;
+        peers.extend(cache.peers());
         peers.extend(config.hard_coded_contacts.clone());
 
         let bs_timer = CoreTimer::new(token, BOOTSTRAP_TIMER_ID);
-        let bs_timeout = core.set_timeout(Duration::from_secs(BOOTSTRAP_TIMEOUT_SEC), bs_timer)?;
+        let deadline = config
+            .bootstrap_timeout_secs
+            .unwrap_or(DEFAULT_BOOTSTRAP_TIMEOUT_SEC);
+        let bs_timeout = core.set_timeout(Duration::from_secs(deadline), bs_timer)?;
         let sd_meta = match seek_peers(core, service_discovery_token, token) {
             Ok((rx, timeout)) => {
                 Some(ServiceDiscMeta {
@@ -94,21 +160,49 @@ impl Bootstrap {
             }
         };
 
+        let dns_seeds = config.bootstrap_dns_seeds.clone().unwrap_or_else(Vec::new);
+        let dns_pending = !dns_seeds.is_empty();
+        if dns_pending {
+            resolve_dns_seeds(core, dns_seeds, token);
+        }
+
         let state = Rc::new(RefCell::new(Bootstrap {
                                              token: token,
                                              cm: cm,
                                              peers: peers,
+                                             started: false,
+                                             queue: VecDeque::new(),
+                                             fan_out: config
+                                                 .bootstrap_fan_out
+                                                 .unwrap_or(usize::max_value()),
+                                             connections_wanted: config
+                                                 .bootstrap_connections
+                                                 .unwrap_or(1),
+                                             connected: 0,
+                                             failures: Vec::new(),
+                                             dns_pending: dns_pending,
+                                             expected_keys: HashMap::new(),
                                              blacklist: blacklist,
+                                             ban_list: ban_list,
+                                             whitelist: whitelist,
+                                             metrics: metrics,
                                              name_hash: name_hash,
                                              ext_reachability: ext_reachability,
+                                             our_relay: our_relay,
                                              our_pk: our_pk,
+                                             identity: identity,
                                              event_tx: event_tx,
                                              sd_meta: sd_meta,
                                              bs_timer: bs_timer,
                                              bs_timeout: bs_timeout,
                                              cache: cache,
-                                             children:
-                                                 HashSet::with_capacity(MAX_CONTACTS_EXPECTED),
+                                             keep_alive: keep_alive_period(config),
+                                             inactivity_timeout: inactivity_timeout(config),
+                                             drop_policy: drop_policy(config),
+                                             bandwidth: bandwidth,
+                                             compression: compression_policy(config),
+                                             socket_config: socket_config(config),
+                                             children: HashMap::new(),
                                              self_weak: Weak::new(),
                                          }));
 
@@ -124,15 +218,59 @@ impl Bootstrap {
     }
 
     fn begin_bootstrap(&mut self, core: &mut Core, poll: &Poll) {
-        let mut peers = mem::replace(&mut self.peers, Vec::new());
-        peers.retain(|addr| !self.blacklist.contains(addr));
-        if peers.is_empty() {
-            let _ = self.event_tx.send(Event::BootstrapFailed);
-            return self.terminate(core, poll);
+        self.started = true;
+        let peers = mem::replace(&mut self.peers, Vec::new());
+        self.try_peers(core, poll, peers);
+        self.maybe_terminate(core, poll);
+    }
+
+    // Those resolved before we started are tried along with the rest, later ones as they come.
+    fn add_dns_contacts(&mut self, core: &mut Core, poll: &Poll, contacts: Vec<Contact>) {
+        self.dns_pending = false;
+        let mut peers = Vec::with_capacity(contacts.len());
+        for contact in contacts {
+            if let Some(pk) = contact.pk {
+                let _ = self.expected_keys.insert(contact.addr, pk);
+            }
+            peers.push(contact.addr);
+        }
+        if self.started {
+            self.try_peers(core, poll, peers);
+            self.maybe_terminate(core, poll);
+        } else {
+            self.peers.extend(peers);
+        }
+    }
+
+    fn try_peers(&mut self, core: &mut Core, poll: &Poll, mut peers: Vec<SocketAddr>) {
+        peers.retain(|addr| {
+                         !self.blacklist.contains(addr) && !self.ban_list.is_blacklisted(addr) &&
+                         self.whitelist.allows(addr, Some(CrustUser::Node))
+                     });
+        // Peers we have bootstrapped off before go first, best first, and the rest in random order
+        let mut cached = self.cache.peers();
+        {
+            let tried: HashSet<_> = peers.iter().collect();
+            cached.retain(|addr| tried.contains(addr));
+        }
+        {
+            let cached: HashSet<_> = cached.iter().collect();
+            peers.retain(|addr| !cached.contains(addr));
         }
         rand::thread_rng().shuffle(&mut peers);
+        cached.extend(peers);
+
+        self.queue.extend(cached);
+        self.fill(core, poll);
+    }
 
-        for peer in peers {
+    // Dial queued peers until `fan_out` are being tried at once.
+    fn fill(&mut self, core: &mut Core, poll: &Poll) {
+        while self.children.len() < self.fan_out {
+            let peer = match self.queue.pop_front() {
+                Some(peer) => peer,
+                None => return,
+            };
             let self_weak = self.self_weak.clone();
             let finish = move |core: &mut Core, poll: &Poll, child, res| if let Some(self_rc) =
                 self_weak.upgrade() {
@@ -141,17 +279,23 @@ impl Bootstrap {
                     .handle_result(core, poll, child, res)
             };
 
-            if let Ok(child) = TryPeer::start(core,
-                                              poll,
-                                              peer,
-                                              self.our_pk,
-                                              self.name_hash,
-                                              self.ext_reachability.clone(),
-                                              Box::new(finish)) {
-                let _ = self.children.insert(child);
+            match TryPeer::start(core,
+                                 poll,
+                                 peer,
+                                 self.our_pk,
+                                 self.identity.clone(),
+                                 self.name_hash,
+                                 self.ext_reachability.clone(),
+                                 Box::new(finish)) {
+                Ok(child) => {
+                    let _ = self.children.insert(child, (peer, Instant::now()));
+                }
+                Err(e) => {
+                    debug!("Could not dial bootstrap contact {}: {:?}", peer, e);
+                    self.fail_peer(peer, BootstrapFailure::Unreachable);
+                }
             }
         }
-        self.maybe_terminate(core, poll);
     }
 
 
@@ -159,52 +303,114 @@ impl Bootstrap {
                      core: &mut Core,
                      poll: &Poll,
                      child: Token,
-                     res: Result<(Socket, SocketAddr, PeerId),
-                                 (SocketAddr, Option<BootstrapDenyReason>)>) {
-        let _ = self.children.remove(&child);
+                     res: Result<(Socket, SocketAddr, PeerId, Identity),
+                                 (SocketAddr, BootstrapFailure)>) {
+        let dialed_at = self.children.remove(&child).map(|(_, at)| at);
         match res {
-            Ok((socket, peer_addr, peer_id)) => {
-                self.terminate(core, poll);
-                return ActiveConnection::start(core,
-                                               poll,
-                                               child,
-                                               socket,
-                                               self.cm.clone(),
-                                               PeerId(self.our_pk),
-                                               peer_id,
-                                               Event::BootstrapConnect(peer_id, peer_addr),
-                                               self.event_tx.clone());
-            }
-            #[cfg_attr(rustfmt, rustfmt_skip)]
-            Err((bad_peer, opt_reason)) => {
-                self.cache.remove_peer_acceptor(bad_peer);
-                if let Some(reason) = opt_reason {
-                    let err_msg = match reason {
-                        BootstrapDenyReason::InvalidNameHash => "Network name mismatch.",
-                        BootstrapDenyReason::FailedExternalReachability => {
-                            "Bootstrappee node could not establish connection to us."
-                        }
-                    };
-                    error!("Failed to Bootstrap: ({:?}) {}", reason, err_msg);
+            Ok((mut socket, peer_addr, peer_id, identity)) => {
+                if self.expected_keys
+                       .get(&peer_addr)
+                       .map_or(false, |pk| *pk != peer_id.0) {
+                    debug!("Bootstrap peer {} does not have the key its DNS record gave",
+                           peer_addr);
+                    self.fail_peer(peer_addr, BootstrapFailure::KeyMismatch);
+                    return self.next(core, poll);
+                }
+                if self.ban_list.is_banned(&peer_id) {
+                    debug!("Bootstrap peer {} is {:?}, banned for now", peer_addr, peer_id);
+                    self.fail_peer(peer_addr, BootstrapFailure::Banned);
+                    return self.next(core, poll);
+                }
+                self.cache.record_success(peer_addr);
+                self.ban_list.record_success(&peer_id);
+                if let Some(at) = dialed_at {
+                    self.metrics.handshake(HandshakeKind::Bootstrap, at.elapsed());
+                }
+                if let ExternalReachability::Relayed { .. } = self.ext_reachability {
+                    let mut our_relay = lock(&self.our_relay);
+                    if our_relay.is_none() {
+                        trace!("Peers are to connect to us through {}", peer_addr);
+                        *our_relay = Some((peer_id, peer_addr));
+                    }
+                }
+                self.connected += 1;
+                if self.connected >= self.connections_wanted {
                     self.terminate(core, poll);
-                    let _ = self.event_tx.send(Event::BootstrapFailed);
+                }
+                if let Err(e) = socket.configure(&self.socket_config) {
+                    debug!("Could not set socket options: {:?}", e);
+                }
+                ActiveConnection::start(core,
+                                        poll,
+                                        child,
+                                        socket,
+                                        self.cm.clone(),
+                                        PeerId(self.our_pk),
+                                        peer_id,
+                                        Event::BootstrapConnect(peer_id, peer_addr, identity),
+                                        self.keep_alive,
+                                        self.inactivity_timeout,
+                                        self.drop_policy,
+                                        self.bandwidth.clone(),
+                                        self.metrics.clone(),
+                                        self.compression,
+                                        None,
+                                        None,
+                                        self.event_tx.clone());
+                if self.connected >= self.connections_wanted {
                     return;
                 }
             }
+            // Whatever the reason, other contacts may well still take us on
+            Err((bad_peer, failure)) => {
+                debug!("Could not bootstrap off {}: {:?}", bad_peer, failure);
+                self.fail_peer(bad_peer, failure);
+            }
         }
+        self.next(core, poll);
+    }
+
+    fn fail_peer(&mut self, peer: SocketAddr, failure: BootstrapFailure) {
+        // Contacts are endpoints, whose peer is unknown until it takes us on, so failing them
+        // counts against the endpoint in the cache rather than against a peer in the ban list
+        self.cache.record_failure(peer);
+        self.failures.push((peer, failure));
+    }
+
+    // Try the next peers, finishing if there are none left.
+    fn next(&mut self, core: &mut Core, poll: &Poll) {
+        self.fill(core, poll);
         self.maybe_terminate(core, poll);
     }
 
     fn maybe_terminate(&mut self, core: &mut Core, poll: &Poll) {
-        if self.children.is_empty() {
-            error!("Bootstrapper has no active children left - bootstrap has failed");
-            self.terminate(core, poll);
-            let _ = self.event_tx.send(Event::BootstrapFailed);
+        if self.children.is_empty() && self.queue.is_empty() && !self.dns_pending {
+            if self.connected == 0 {
+                error!("Bootstrapper has no active children left - bootstrap has failed");
+            }
+            self.finish(core, poll);
+        }
+    }
+
+    // Stop, reporting failure unless we got any connection at all.
+    fn finish(&mut self, core: &mut Core, poll: &Poll) {
+        let pending: Vec<_> = self.children
+            .values()
+            .map(|&(peer, _)| peer)
+            .chain(self.queue.drain(..))
+            .collect();
+        self.terminate(core, poll);
+        if self.connected == 0 {
+            let mut failures = mem::replace(&mut self.failures, Vec::new());
+            failures.extend(pending
+                                .into_iter()
+                                .map(|peer| (peer, BootstrapFailure::TimedOut)));
+            let _ = self.event_tx.send(Event::BootstrapFailed(failures));
         }
     }
 
     fn terminate_children(&mut self, core: &mut Core, poll: &Poll) {
-        for child in self.children.drain() {
+        for (child, _) in self.children.drain() {
             let child = match core.get_state(child) {
                 Some(state) => state,
                 None => continue,
@@ -216,16 +422,24 @@ impl Bootstrap {
 }
 
 impl State for Bootstrap {
+    fn name(&self) -> &'static str {
+        "Bootstrap"
+    }
+
     fn timeout(&mut self, core: &mut Core, poll: &Poll, timer_id: u8) {
         if timer_id == self.bs_timer.timer_id {
-            let _ = self.event_tx.send(Event::BootstrapFailed);
-            return self.terminate(core, poll);
+            return self.finish(core, poll);
         }
 
         let rx = unwrap!(self.sd_meta.take()).rx;
 
+        // Peers can answer more than once, as when they are sought with more than one request
         while let Ok(listeners) = rx.try_recv() {
-            self.peers.extend(listeners);
+            for listener in listeners {
+                if !self.peers.contains(&listener) {
+                    self.peers.push(listener);
+                }
+            }
         }
 
         self.begin_bootstrap(core, poll);
@@ -233,6 +447,9 @@ impl State for Bootstrap {
 
     fn terminate(&mut self, core: &mut Core, poll: &Poll) {
         self.terminate_children(core, poll);
+        if let Err(e) = self.cache.commit() {
+            debug!("Could not write the bootstrap cache: {:?}", e);
+        }
         if let Some(sd_meta) = self.sd_meta.take() {
             let _ = core.cancel_timeout(&sd_meta.timeout);
         }
@@ -245,6 +462,25 @@ impl State for Bootstrap {
     }
 }
 
+// Resolve the seeds on a thread of their own, handing the contacts to the bootstrap at `token`
+// if it is still going.
+fn resolve_dns_seeds(core: &Core, seeds: Vec<String>, token: Token) {
+    let tx = core.sender().clone();
+    let _ = thread::named("Bootstrap-DNS", move || {
+        let contacts = dns::resolve(&seeds);
+        let _ = tx.send(CoreMessage::new(move |core, poll| {
+            let state = match core.get_state(token) {
+                Some(state) => state,
+                None => return,
+            };
+            let mut state = state.borrow_mut();
+            if let Some(bootstrap) = state.as_any().downcast_mut::<Bootstrap>() {
+                bootstrap.add_dns_contacts(core, poll, contacts);
+            }
+        }));
+    });
+}
+
 struct ServiceDiscMeta {
     rx: Receiver<Vec<SocketAddr>>,
     timeout: Timeout,
@@ -0,0 +1,111 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{Core, CoreTimer, State, Timeout, lock};
+use main::{ConnectionMap, PeerId};
+use mio::{Poll, Token};
+use std::any::Any;
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const CHECK_INTERVAL_SECS: u64 = 5;
+
+/// The contact we bootstrapped off under `Config::bootstrap_via_relay` and its address, for peers
+/// to reach us through.
+pub type OurRelay = Arc<Mutex<Option<(PeerId, SocketAddr)>>>;
+
+pub type LostHandler = Box<FnMut(&mut Core, &Poll)>;
+
+/// Forgets our relay once we are no longer connected to it, so that it is not handed out to
+/// peers any more, and has another found.
+pub struct RelayWatch {
+    token: Token,
+    timeout: Timeout,
+    our_relay: OurRelay,
+    cm: ConnectionMap,
+    on_lost: LostHandler,
+}
+
+impl RelayWatch {
+    pub fn start(core: &mut Core,
+                 our_relay: OurRelay,
+                 cm: ConnectionMap,
+                 on_lost: LostHandler)
+                 -> ::Res<Token> {
+        let token = core.get_new_token();
+        let state = RelayWatch {
+            token: token,
+            timeout: core.set_timeout(Duration::from_secs(CHECK_INTERVAL_SECS),
+                                      CoreTimer::new(token, 0))?,
+            our_relay: our_relay,
+            cm: cm,
+            on_lost: on_lost,
+        };
+
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+
+        Ok(token)
+    }
+
+    // Whether we had a relay but are no longer connected to it, forgetting it if so.
+    fn lost(&self) -> bool {
+        let mut our_relay = lock(&self.our_relay);
+        let (relay_id, relay_addr) = match *our_relay {
+            Some(relay) => relay,
+            None => return false,
+        };
+        // Still connected to, or reconnecting to
+        if lock(&self.cm).contains_key(&relay_id) {
+            return false;
+        }
+        debug!("Lost our relay {:?} at {}", relay_id, relay_addr);
+        *our_relay = None;
+        true
+    }
+}
+
+impl State for RelayWatch {
+    fn name(&self) -> &'static str {
+        "RelayWatch"
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u8) {
+        match core.set_timeout(Duration::from_secs(CHECK_INTERVAL_SECS),
+                               CoreTimer::new(self.token, 0)) {
+            Ok(timeout) => self.timeout = timeout,
+            Err(e) => {
+                debug!("Could not schedule checking on our relay: {:?}", e);
+                return self.terminate(core, poll);
+            }
+        }
+        if self.lost() {
+            (*self.on_lost)(core, poll);
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core, _poll: &Poll) {
+        let _ = core.cancel_timeout(&self.timeout);
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
@@ -15,8 +15,10 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-use common::{BootstrapDenyReason, Core, ExternalReachability, Message, NameHash, Priority, Socket,
-             State};
+use super::BootstrapFailure;
+use common::{BootstrapDenyReason, Challenge, Core, CoreTimer, ExternalReachability, Identity,
+             IdentityKeys, Message, NameHash, PROTOCOL_VERSION, Priority, Socket, State, Timeout,
+             TraceState, is_compatible_version};
 use main::PeerId;
 use mio::{Poll, PollOpt, Ready, Token};
 use rust_sodium::crypto::box_::PublicKey;
@@ -29,14 +31,25 @@ use std::rc::Rc;
 pub type Finish = Box<FnMut(&mut Core,
                             &Poll,
                             Token,
-                            Result<(Socket, SocketAddr, PeerId),
-                                   (SocketAddr, Option<BootstrapDenyReason>)>)>;
+                            Result<(Socket, SocketAddr, PeerId, Identity),
+                                   (SocketAddr, BootstrapFailure)>)>;
 
 pub struct TryPeer {
     token: Token,
     peer: SocketAddr,
     socket: Socket,
+    our_pk: PublicKey,
+    identity: IdentityKeys,
+    // What the peer has to sign over, along with its own challenge, to prove its identity.
+    challenge: Challenge,
     request: Option<(Message, Priority)>,
+    bootstrap_request: Message,
+    // How many fallback transports the peer has been dialed over, and until when the attempt
+    // under way has to connect before it is dialed over the next.
+    fell_back: usize,
+    fallback_timeout: Option<Timeout>,
+    // Whether the socket dialed last has connected.
+    connected: bool,
     finish: Finish,
 }
 
@@ -45,11 +58,12 @@ impl TryPeer {
                  poll: &Poll,
                  peer: SocketAddr,
                  our_pk: PublicKey,
+                 identity: IdentityKeys,
                  name_hash: NameHash,
                  ext_reachability: ExternalReachability,
                  finish: Finish)
                  -> ::Res<Token> {
-        let socket = Socket::connect(&peer)?;
+        let socket = Socket::dial(&*core.transport(), &peer)?;
         let token = core.get_new_token();
 
         poll.register(&socket,
@@ -57,56 +71,181 @@ impl TryPeer {
                       Ready::error() | Ready::hup() | Ready::writable(),
                       PollOpt::edge())?;
 
+        let fallback_timeout = match core.fallback_transport(0) {
+            Some((_, delay)) => Some(core.set_timeout(delay, CoreTimer::new(token, 0))?),
+            None => None,
+        };
+
+        let challenge = Challenge::new();
+        let request = Message::IdentifiedBootstrapRequest(our_pk,
+                                                          name_hash,
+                                                          ext_reachability,
+                                                          challenge,
+                                                          PROTOCOL_VERSION);
         let state = TryPeer {
             token: token,
             peer: peer,
             socket: socket,
-            request: Some((Message::BootstrapRequest(our_pk, name_hash, ext_reachability), 0)),
+            our_pk: our_pk,
+            identity: identity,
+            challenge: challenge,
+            request: Some((request.clone(), 0)),
+            bootstrap_request: request,
+            fell_back: 0,
+            fallback_timeout: fallback_timeout,
+            connected: false,
             finish: finish,
         };
 
         let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+        core.trace(token,
+                   TraceState::Handshake,
+                   "started",
+                   &[("direction", &"bootstrap"), ("peer_addr", &peer)]);
 
         Ok(token)
     }
 
     fn write(&mut self, core: &mut Core, poll: &Poll, msg: Option<(Message, Priority)>) {
         if self.socket.write(poll, self.token, msg).is_err() {
-            self.handle_error(core, poll, None);
+            self.connection_failed(core, poll);
         }
     }
 
     fn read(&mut self, core: &mut Core, poll: &Poll) {
         match self.socket.read::<Message>() {
-            Ok(Some(Message::BootstrapGranted(peer_pk))) => {
+            Ok(Some(Message::IdentifiedBootstrapGranted(peer_pk,
+                                                        their_challenge,
+                                                        proof,
+                                                        version))) => {
+                if !is_compatible_version(version) {
+                    debug!("Bootstrap peer {} speaks protocol version {}", self.peer, version);
+                    return self.handle_error(core, poll, BootstrapFailure::IncompatibleVersion);
+                }
+                let their_identity = match proof.verify(&peer_pk,
+                                                        &self.our_pk,
+                                                        &their_challenge,
+                                                        &self.challenge) {
+                    Some(identity) => identity,
+                    None => {
+                        debug!("Bootstrap peer {} failed to prove its identity", self.peer);
+                        return self.handle_error(core, poll, BootstrapFailure::Disconnected);
+                    }
+                };
+                // The peer waits for our proof in turn, which the connection sends on
+                let proof =
+                    self.identity
+                        .prove(&self.our_pk, &peer_pk, &self.challenge, &their_challenge);
+                if self.socket
+                       .write(poll, self.token, Some((Message::Identify(proof), 0)))
+                       .is_err() {
+                    return self.handle_error(core, poll, BootstrapFailure::Disconnected);
+                }
+                if let Some(timeout) = self.fallback_timeout.take() {
+                    let _ = core.cancel_timeout(&timeout);
+                }
                 let _ = core.remove_state(self.token);
                 let token = self.token;
+                let peer_id = PeerId(peer_pk);
+                core.trace(token, TraceState::Handshake, "completed", &[("peer", &peer_id)]);
                 let socket = mem::replace(&mut self.socket, Socket::default());
-                let data = (socket, self.peer, PeerId(peer_pk));
+                let data = (socket, self.peer, peer_id, their_identity);
                 (*self.finish)(core, poll, token, Ok(data));
             }
             Ok(Some(Message::BootstrapDenied(reason))) => {
-                self.handle_error(core, poll, Some(reason))
+                self.handle_error(core, poll, denied(reason))
             }
             Ok(None) => (),
-            Ok(Some(_)) | Err(_) => self.handle_error(core, poll, None),
+            Ok(Some(_)) => self.handle_error(core, poll, BootstrapFailure::Disconnected),
+            Err(_) => self.connection_failed(core, poll),
+        }
+    }
+
+    fn connection_failed(&mut self, core: &mut Core, poll: &Poll) {
+        if !self.fall_back(core, poll) {
+            // Refused, or never answered, as opposed to dropped partway through the handshake
+            let failure = if self.connected {
+                BootstrapFailure::Disconnected
+            } else {
+                BootstrapFailure::Unreachable
+            };
+            self.handle_error(core, poll, failure);
+        }
+    }
+
+    // Dial the peer again over the next fallback transport there is, returning whether it is
+    // being.
+    fn fall_back(&mut self, core: &mut Core, poll: &Poll) -> bool {
+        if let Some(timeout) = self.fallback_timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
+        let (transport, socket) = loop {
+            let transport = match core.fallback_transport(self.fell_back) {
+                Some((transport, _)) => transport,
+                None => return false,
+            };
+            self.fell_back += 1;
+            match Socket::dial(&*transport, &self.peer) {
+                Ok(socket) => break (transport, socket),
+                Err(e) => {
+                    debug!("Could not dial {} over {}: {:?}", self.peer, transport.name(), e)
+                }
+            }
+        };
+        let _ = poll.deregister(&self.socket);
+        if poll.register(&socket,
+                         self.token,
+                         Ready::error() | Ready::hup() | Ready::writable(),
+                         PollOpt::edge())
+               .is_err() {
+            return false;
+        }
+        core.trace(self.token,
+                   TraceState::Handshake,
+                   "fell_back",
+                   &[("peer_addr", &self.peer), ("transport", &transport.name())]);
+        self.socket = socket;
+        self.request = Some((self.bootstrap_request.clone(), 0));
+        self.connected = false;
+        if let Some((_, delay)) = core.fallback_transport(self.fell_back) {
+            let timer = CoreTimer::new(self.token, 0);
+            self.fallback_timeout = core.set_timeout(delay, timer).ok();
         }
+        true
     }
 
-    fn handle_error(&mut self, core: &mut Core, poll: &Poll, reason: Option<BootstrapDenyReason>) {
+    fn handle_error(&mut self, core: &mut Core, poll: &Poll, failure: BootstrapFailure) {
         self.terminate(core, poll);
         let token = self.token;
         let peer = self.peer;
-        (*self.finish)(core, poll, token, Err((peer, reason)));
+        core.trace(token,
+                   TraceState::Handshake,
+                   "failed",
+                   &[("peer_addr", &peer), ("failure", &format!("{:?}", failure))]);
+        (*self.finish)(core, poll, token, Err((peer, failure)));
+    }
+}
+
+fn denied(reason: BootstrapDenyReason) -> BootstrapFailure {
+    match reason {
+        BootstrapDenyReason::InvalidNameHash => BootstrapFailure::NetworkMismatch,
+        BootstrapDenyReason::FailedExternalReachability => BootstrapFailure::NotReachable,
+        BootstrapDenyReason::NotRelaying => BootstrapFailure::NotRelaying,
+        BootstrapDenyReason::IncompatibleVersion => BootstrapFailure::IncompatibleVersion,
     }
 }
 
 impl State for TryPeer {
+    fn name(&self) -> &'static str {
+        "TryPeer"
+    }
+
     fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
         if kind.is_error() {
-            return self.handle_error(core, poll, None);
+            return self.connection_failed(core, poll);
         } else if kind.is_writable() || kind.is_readable() {
             if kind.is_writable() {
+                self.connected = true;
                 let req = self.request.take();
                 self.write(core, poll, req);
             }
@@ -118,10 +257,20 @@ impl State for TryPeer {
 
         debug!("Considering the following event to indicate dirupted connection: {:?}",
                kind);
-        self.handle_error(core, poll, None);
+        self.connection_failed(core, poll);
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u8) {
+        self.fallback_timeout = None;
+        if !self.connected {
+            let _ = self.fall_back(core, poll);
+        }
     }
 
     fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        if let Some(timeout) = self.fallback_timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
         let _ = core.remove_state(self.token);
         let _ = poll.deregister(&self.socket);
     }
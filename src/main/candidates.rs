@@ -0,0 +1,532 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::Core;
+use main::{OfferStamp, PeerId, PrivConnectionInfo, PubConnectionInfo};
+use mio::Poll;
+use mio::udp::UdpSocket;
+use nat::{DetectNatType, MappedAddrSource, MappedTcpSocket, MappedUdpSocket, MappingContext,
+          MappingResult, NatType};
+use net2::TcpBuilder;
+use std::cell::RefCell;
+use std::cmp;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+
+/// How a candidate is reached, in the terms of ICE (RFC 8445).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CandidateKind {
+    /// An address of one of the peer's own interfaces.
+    Host,
+    /// An address the peer's NAT maps one of its sockets to, as learnt from its router or from
+    /// helpers.
+    ServerReflexive,
+    /// The relay the peer is reached through.
+    Relayed,
+}
+
+/// What to reach a candidate over, with the TCP candidate types of RFC 6544.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CandidateTransport {
+    /// A TCP listener, to be dialed.
+    TcpPassive,
+    /// A TCP socket that dials us while we dial it, for a simultaneous open.
+    TcpSimultaneousOpen,
+    /// A UDP socket, to punch a hole to.
+    Udp,
+}
+
+/// An address a peer may be reached at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Candidate {
+    /// Where the peer is reached.
+    pub addr: SocketAddr,
+    /// What it is reached over.
+    pub transport: CandidateTransport,
+    /// How it is reached.
+    pub kind: CandidateKind,
+    /// How much the peer would rather be reached at it than at its other candidates, the
+    /// highest first, as computed by ICE.
+    pub priority: u32,
+}
+
+impl Candidate {
+    /// `index` ranks the candidate among those of the same kind and transport, the best first.
+    fn new(addr: SocketAddr,
+           transport: CandidateTransport,
+           kind: CandidateKind,
+           index: usize)
+           -> Self {
+        Candidate {
+            addr: addr,
+            transport: transport,
+            kind: kind,
+            priority: priority(kind, transport, index),
+        }
+    }
+}
+
+// The ICE priority of RFC 8445 for a candidate of our one component. The local preference puts
+// listeners first, since crust connects over TCP, then the order mappings ranked addresses in.
+fn priority(kind: CandidateKind, transport: CandidateTransport, index: usize) -> u32 {
+    let type_pref = match kind {
+        CandidateKind::Host => 126,
+        CandidateKind::ServerReflexive => 100,
+        CandidateKind::Relayed => 0,
+    };
+    let transport_pref = match transport {
+        CandidateTransport::TcpPassive => 6,
+        CandidateTransport::TcpSimultaneousOpen => 4,
+        CandidateTransport::Udp => 2,
+    };
+    let local_pref = (transport_pref << 13) + (8191 - cmp::min(index, 8191) as u32);
+    (type_pref << 24) + (local_pref << 8) + 255
+}
+
+/// Every way a peer may be reached at, to be handed out of band to the peers that are to connect
+/// to it. Created by `Service::gather_candidates`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionCandidates {
+    /// The peer the candidates are of.
+    pub id: PeerId,
+    /// Its candidates, the highest priority first.
+    pub candidates: Vec<Candidate>,
+    /// How its NAT treats UDP traffic, if that could be found out.
+    pub nat_type: Option<NatType>,
+    /// When the candidates were gathered, when they expire and their nonce, as for connection
+    /// info.
+    pub stamp: OfferStamp,
+}
+
+impl ConnectionCandidates {
+    /// Pair our candidates with `theirs` into the check list of ICE, the highest priority pair
+    /// first. The lesser of the two peers controls, as with relays, so that both order the pairs
+    /// alike. Their listeners and relay are dialed from whichever of our addresses, so those
+    /// pairs have no local candidate.
+    pub fn pair_with(&self, theirs: &ConnectionCandidates) -> Vec<CandidatePair> {
+        let controlling = self.id < theirs.id;
+        let mut pairs = Vec::new();
+        for remote in &theirs.candidates {
+            if remote.transport == CandidateTransport::TcpPassive {
+                pairs.push(CandidatePair::new(None, *remote, controlling));
+                continue;
+            }
+            for local in &self.candidates {
+                if local.transport == remote.transport &&
+                   local.addr.is_ipv4() == remote.addr.is_ipv4() {
+                    pairs.push(CandidatePair::new(Some(*local), *remote, controlling));
+                }
+            }
+        }
+        pairs.sort_by(|a, b| b.priority.cmp(&a.priority));
+        pairs
+    }
+
+    /// The connection info to `Service::connect` to the peer with, made of its TCP candidates.
+    pub fn to_pub_connection_info(&self) -> PubConnectionInfo {
+        let addrs = |transport| {
+            self.candidates
+                .iter()
+                .filter(|candidate| {
+                            candidate.transport == transport &&
+                            candidate.kind != CandidateKind::Relayed
+                        })
+                .map(|candidate| candidate.addr)
+                .collect()
+        };
+        PubConnectionInfo {
+            id: self.id,
+            for_direct: addrs(CandidateTransport::TcpPassive),
+            for_hole_punch: addrs(CandidateTransport::TcpSimultaneousOpen),
+            relay: self.candidates
+                .iter()
+                .find(|candidate| candidate.kind == CandidateKind::Relayed)
+                .map(|candidate| candidate.addr),
+            stamp: self.stamp,
+        }
+    }
+}
+
+/// One of our candidates and one of a peer's to check connectivity between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CandidatePair {
+    /// Our side, if a particular one of our candidates is used.
+    pub local: Option<Candidate>,
+    /// The peer's side.
+    pub remote: Candidate,
+    /// How the pair ranks among the others, the highest first, as computed by ICE.
+    pub priority: u64,
+}
+
+impl CandidatePair {
+    fn new(local: Option<Candidate>, remote: Candidate, controlling: bool) -> Self {
+        // A dial goes out from our host
+        let ours = local.map_or(priority(CandidateKind::Host, CandidateTransport::TcpPassive, 0),
+                                |local| local.priority) as u64;
+        let theirs = remote.priority as u64;
+        let (g, d) = if controlling {
+            (ours, theirs)
+        } else {
+            (theirs, ours)
+        };
+        CandidatePair {
+            local: local,
+            remote: remote,
+            priority: (cmp::min(g, d) << 32) + 2 * cmp::max(g, d) + if g > d { 1 } else { 0 },
+        }
+    }
+}
+
+/// Our candidates as gathered by `Service::gather_candidates`, along with the sockets behind them.
+#[derive(Debug)]
+pub struct LocalCandidates {
+    offer: ConnectionCandidates,
+    tcp_socket: Option<TcpBuilder>,
+    udp_socket: Option<UdpSocket>,
+}
+
+impl LocalCandidates {
+    /// Our candidates, to be handed to the peers that are to connect to us.
+    pub fn offer(&self) -> &ConnectionCandidates {
+        &self.offer
+    }
+
+    /// The UDP socket our UDP candidates are of, if there was one.
+    pub fn take_udp_socket(&mut self) -> Option<UdpSocket> {
+        self.udp_socket.take()
+    }
+
+    /// The connection info to `Service::connect` with, made of our TCP candidates.
+    pub fn into_connection_info(self) -> PrivConnectionInfo {
+        let info = self.offer.to_pub_connection_info();
+        PrivConnectionInfo {
+            id: info.id,
+            for_direct: info.for_direct,
+            for_hole_punch: info.for_hole_punch,
+            hole_punch_socket: self.tcp_socket,
+            relay: info.relay,
+            stamp: info.stamp,
+        }
+    }
+}
+
+/// The result of a `Service::gather_candidates` call.
+#[derive(Debug)]
+pub struct CandidatesResult {
+    /// The token that was passed to `gather_candidates`.
+    pub result_token: u32,
+    /// Our candidates, if successful.
+    pub result: ::Res<LocalCandidates>,
+}
+
+/// The result of a `Service::punch_udp_hole` call.
+#[derive(Debug)]
+pub struct UdpHolePunchResult {
+    /// The token that was passed to `punch_udp_hole`.
+    pub result_token: u32,
+    /// Our UDP socket along with the endpoint of the peer it now reaches, if successful.
+    pub result: ::Res<(UdpSocket, SocketAddr)>,
+}
+
+/// What is known of us before any mapping: our id, listeners and the relay we are reached
+/// through.
+pub struct Gathering {
+    pub id: PeerId,
+    pub listeners: Vec<SocketAddr>,
+    pub relay: Option<SocketAddr>,
+    pub local_ips: Vec<IpAddr>,
+    pub stamp: OfferStamp,
+}
+
+impl Gathering {
+    /// Our local IPs as `mc` knows them.
+    pub fn local_ips(mc: &MappingContext) -> Vec<IpAddr> {
+        mc.ifv4s()
+            .into_iter()
+            .map(IpAddr::V4)
+            .chain(mc.ifv6s().into_iter().map(IpAddr::V6))
+            .collect()
+    }
+
+    /// Our candidates without mapping any sockets, i.e. just our listeners and relay.
+    pub fn unmapped(self) -> LocalCandidates {
+        self.finish(None, None, None)
+    }
+
+    fn kind(&self, addr: &SocketAddr) -> CandidateKind {
+        if self.local_ips.contains(&addr.ip()) || addr.ip().is_loopback() {
+            CandidateKind::Host
+        } else {
+            CandidateKind::ServerReflexive
+        }
+    }
+
+    fn finish(self,
+              tcp: Option<MappingResult>,
+              udp: Option<(UdpSocket, Vec<SocketAddr>)>,
+              nat_type: Option<NatType>)
+              -> LocalCandidates {
+        let mut candidates = Vec::new();
+        for (index, addr) in self.listeners.iter().enumerate() {
+            candidates.push(Candidate::new(*addr,
+                                           CandidateTransport::TcpPassive,
+                                           self.kind(addr),
+                                           index));
+        }
+        let tcp_socket = match tcp {
+            Some(Ok((socket, mapped_addrs))) => {
+                for (index, mapped) in mapped_addrs.iter().enumerate() {
+                    let kind = match mapped.source {
+                        MappedAddrSource::Local => CandidateKind::Host,
+                        MappedAddrSource::Router |
+                        MappedAddrSource::Stun |
+                        MappedAddrSource::Configured => CandidateKind::ServerReflexive,
+                    };
+                    candidates.push(Candidate::new(mapped.addr,
+                                                   CandidateTransport::TcpSimultaneousOpen,
+                                                   kind,
+                                                   index));
+                }
+                Some(socket)
+            }
+            Some(Err(e)) => {
+                debug!("Could not map tcp socket: {}", e);
+                None
+            }
+            None => None,
+        };
+        let udp_socket = udp.map(|(socket, mapped_addrs)| {
+            for (index, addr) in mapped_addrs.iter().enumerate() {
+                candidates.push(Candidate::new(*addr,
+                                               CandidateTransport::Udp,
+                                               self.kind(addr),
+                                               index));
+            }
+            socket
+        });
+        if let Some(relay) = self.relay {
+            candidates.push(Candidate::new(relay,
+                                           CandidateTransport::TcpPassive,
+                                           CandidateKind::Relayed,
+                                           0));
+        }
+        candidates.sort_by(|a, b| b.priority.cmp(&a.priority));
+        LocalCandidates {
+            offer: ConnectionCandidates {
+                id: self.id,
+                candidates: candidates,
+                nat_type: nat_type,
+                stamp: self.stamp,
+            },
+            tcp_socket: tcp_socket,
+            udp_socket: udp_socket,
+        }
+    }
+}
+
+// Whatever of the gathering has finished, and how many parts of it are still under way.
+struct Parts<F> {
+    gathering: Option<Gathering>,
+    pending: usize,
+    tcp: Option<MappingResult>,
+    udp: Option<(UdpSocket, Vec<SocketAddr>)>,
+    nat_type: Option<NatType>,
+    finish: Option<F>,
+}
+
+/// Map a TCP and a UDP socket and find out our NAT type all at once, calling `finish` with our
+/// candidates once everything is done. Whatever fails only leaves its candidates out.
+pub fn gather<F>(core: &mut Core,
+                 poll: &Poll,
+                 mc: &MappingContext,
+                 gathering: Gathering,
+                 finish: F)
+    where F: FnOnce(&mut Core, &Poll, LocalCandidates) + 'static
+{
+    let parts = Rc::new(RefCell::new(Parts {
+                                         gathering: Some(gathering),
+                                         pending: 3,
+                                         tcp: None,
+                                         udp: None,
+                                         nat_type: None,
+                                         finish: Some(finish),
+                                     }));
+
+    let parts_tcp = parts.clone();
+    let on_tcp = move |core: &mut Core, poll: &Poll, res: MappingResult| {
+        parts_tcp.borrow_mut().tcp = Some(res);
+        part_done(core, poll, &parts_tcp);
+    };
+    if let Err(e) = MappedTcpSocket::start(core, poll, 0, mc, on_tcp) {
+        parts.borrow_mut().tcp = Some(Err(e));
+        part_done(core, poll, &parts);
+    }
+
+    let parts_udp = parts.clone();
+    let on_udp = move |core: &mut Core,
+                       poll: &Poll,
+                       socket: UdpSocket,
+                       mapped_addrs: Vec<SocketAddr>| {
+        parts_udp.borrow_mut().udp = Some((socket, mapped_addrs));
+        part_done(core, poll, &parts_udp);
+    };
+    if let Err(e) = MappedUdpSocket::start(core, poll, 0, mc, on_udp) {
+        debug!("Could not map udp socket: {}", e);
+        part_done(core, poll, &parts);
+    }
+
+    let parts_nat = parts.clone();
+    let on_nat_type = move |core: &mut Core, poll: &Poll, nat_type: Option<NatType>| {
+        parts_nat.borrow_mut().nat_type = nat_type;
+        part_done(core, poll, &parts_nat);
+    };
+    if let Err(e) = DetectNatType::start(core, poll, mc, on_nat_type) {
+        debug!("Could not detect NAT type: {}", e);
+        part_done(core, poll, &parts);
+    }
+}
+
+fn part_done<F>(core: &mut Core, poll: &Poll, parts: &Rc<RefCell<Parts<F>>>)
+    where F: FnOnce(&mut Core, &Poll, LocalCandidates)
+{
+    let (candidates, finish) = {
+        let mut parts = parts.borrow_mut();
+        parts.pending -= 1;
+        if parts.pending > 0 {
+            return;
+        }
+        let gathering = unwrap!(parts.gathering.take());
+        let tcp = parts.tcp.take();
+        let udp = parts.udp.take();
+        let nat_type = parts.nat_type.take();
+        (gathering.finish(tcp, udp, nat_type), unwrap!(parts.finish.take()))
+    };
+    finish(core, poll, candidates);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nat::MappedAddr;
+    use rust_sodium::crypto::box_;
+    use std::time::Duration;
+
+    #[test]
+    fn prioritises_candidates() {
+        let listener = unwrap!("192.168.0.2:5483".parse());
+        let forwarded = unwrap!("8.8.8.8:5483".parse());
+        let mapped = unwrap!("8.8.8.8:6000".parse());
+        let relay = unwrap!("8.8.4.4:5483".parse());
+        let gathering = Gathering {
+            id: PeerId(box_::gen_keypair().0),
+            listeners: vec![listener, forwarded],
+            relay: Some(relay),
+            local_ips: vec![listener.ip()],
+            stamp: OfferStamp::new(Duration::from_secs(60)),
+        };
+        let tcp = Ok((unwrap!(TcpBuilder::new_v4()),
+                      vec![MappedAddr::new(mapped, MappedAddrSource::Stun)]));
+        let mut candidates = gathering.finish(Some(tcp), None, None);
+        assert!(candidates.take_udp_socket().is_none());
+
+        let offer = candidates.offer().clone();
+        let order: Vec<_> = offer
+            .candidates
+            .iter()
+            .map(|candidate| (candidate.addr, candidate.transport, candidate.kind))
+            .collect();
+        assert_eq!(order,
+                   vec![(listener, CandidateTransport::TcpPassive, CandidateKind::Host),
+                        (forwarded,
+                         CandidateTransport::TcpPassive,
+                         CandidateKind::ServerReflexive),
+                        (mapped,
+                         CandidateTransport::TcpSimultaneousOpen,
+                         CandidateKind::ServerReflexive),
+                        (relay, CandidateTransport::TcpPassive, CandidateKind::Relayed)]);
+
+        let info = offer.to_pub_connection_info();
+        assert_eq!(info.for_direct, vec![listener, forwarded]);
+        assert_eq!(info.for_hole_punch, vec![mapped]);
+        assert_eq!(info.relay, Some(relay));
+        assert_eq!(info.stamp, offer.stamp);
+        assert!(candidates.into_connection_info().hole_punch_socket.is_some());
+    }
+
+    #[test]
+    fn pairs_candidates() {
+        let candidate = |addr: &str, transport, kind, index| {
+            Candidate::new(unwrap!(addr.parse()), transport, kind, index)
+        };
+        let ours = ConnectionCandidates {
+            id: PeerId(box_::gen_keypair().0),
+            candidates: vec![candidate("192.168.0.2:5483",
+                                       CandidateTransport::TcpPassive,
+                                       CandidateKind::Host,
+                                       0),
+                             candidate("8.8.8.8:6000",
+                                       CandidateTransport::TcpSimultaneousOpen,
+                                       CandidateKind::ServerReflexive,
+                                       0),
+                             candidate("[2001:db8::1]:6001",
+                                       CandidateTransport::TcpSimultaneousOpen,
+                                       CandidateKind::Host,
+                                       0)],
+            nat_type: None,
+            stamp: OfferStamp::new(Duration::from_secs(60)),
+        };
+        let theirs = ConnectionCandidates {
+            id: PeerId(box_::gen_keypair().0),
+            candidates: vec![candidate("8.8.4.4:5483",
+                                       CandidateTransport::TcpPassive,
+                                       CandidateKind::ServerReflexive,
+                                       0),
+                             candidate("8.8.4.4:7000",
+                                       CandidateTransport::TcpSimultaneousOpen,
+                                       CandidateKind::ServerReflexive,
+                                       0),
+                             candidate("8.8.4.4:7001",
+                                       CandidateTransport::Udp,
+                                       CandidateKind::ServerReflexive,
+                                       0),
+                             candidate("1.1.1.1:5483",
+                                       CandidateTransport::TcpPassive,
+                                       CandidateKind::Relayed,
+                                       0)],
+            nat_type: None,
+            stamp: OfferStamp::new(Duration::from_secs(60)),
+        };
+
+        let pairs = ours.pair_with(&theirs);
+        let remotes: Vec<_> = pairs.iter().map(|pair| pair.remote.addr).collect();
+        assert_eq!(remotes,
+                   vec![theirs.candidates[0].addr,
+                        theirs.candidates[1].addr,
+                        theirs.candidates[3].addr]);
+        assert_eq!(pairs[0].local, None);
+        assert_eq!(pairs[1].local, Some(ours.candidates[1]));
+
+        // Both peers order the pairs alike
+        let priorities: Vec<_> = theirs
+            .pair_with(&ours)
+            .into_iter()
+            .filter(|pair| pair.local.is_some())
+            .map(|pair| pair.priority)
+            .collect();
+        assert_eq!(priorities, vec![pairs[1].priority]);
+    }
+}
@@ -15,10 +15,17 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
+use common::{Compression, QueueFullPolicy, QuotaPolicy};
 use config_file_handler::{self, FileHandler};
+use main::CrustError;
+use serde_json::{self, Value};
 use std::collections::HashSet;
+use std::env;
 use std::ffi::OsString;
 use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+const ENV_PREFIX: &'static str = "CRUST_";
 
 #[cfg(test)]
 use std::path::PathBuf;
@@ -28,8 +35,30 @@ use std::path::PathBuf;
 pub struct Config {
     /// Direct contacts one should connect to
     pub hard_coded_contacts: Vec<SocketAddr>,
+    /// Bootstrap contacts looked up in DNS when bootstrapping, so seed nodes can change without
+    /// a new config file. Each is a `host:port`, `srv:` followed by a name whose SRV records give
+    /// the contacts, or `txt:` followed by a name whose TXT records each hold a `host:port`,
+    /// optionally followed by ` pk=` and the hex public key the peer there must have. SRV and TXT
+    /// records are looked up with the nameservers of `/etc/resolv.conf`, so only on Unix.
+    pub bootstrap_dns_seeds: Option<Vec<String>>,
+    /// Most bootstrap contacts to try at once. All of them by default.
+    pub bootstrap_fan_out: Option<usize>,
+    /// Bootstrap connections to make before bootstrapping stops. Defaults to 1.
+    pub bootstrap_connections: Option<usize>,
+    /// Seconds bootstrapping may take in all, after which it fails unless connected to anyone.
+    /// Defaults to 10.
+    pub bootstrap_timeout_secs: Option<u64>,
     /// Port for TCP acceptor
     pub tcp_acceptor_port: Option<u16>,
+    /// First and last port, inclusive, the TCP acceptor may use. The first free one is taken, which
+    /// keeps our port stable across restarts while fitting firewall rules. Ignored if
+    /// `tcp_acceptor_port` is set.
+    pub tcp_acceptor_port_range: Option<(u16, u16)>,
+    /// Addresses to listen on instead of every interface, each with its own port, `0` leaving it
+    /// to the OS. Each is advertised as it is, rather than as mapped on the router, and one
+    /// failing leaves the others listening, so multi-homed hosts control what they expose.
+    /// `tcp_acceptor_port` and `tcp_acceptor_port_range` are then ignored. Not set by default.
+    pub tcp_listen_addrs: Option<Vec<SocketAddr>>,
     /// Force usage of `tcp_acceptor_port` as our router mapped port. Normally if there is a port
     /// forwarding, crust will find out what the external world sees our local tcp acceptor
     /// endpoint as and include this information in our connection info that we share with others.
@@ -41,38 +70,871 @@ pub struct Config {
     /// can specify this value as true, which will force crust to add the above `tcp_acceptor_port`
     /// to one of our externally reachable endpoint.
     pub force_acceptor_port_in_ext_ep: bool,
+    /// Seconds a peer connecting to us has to complete the handshake before it is dropped.
+    /// Defaults to 20.
+    pub handshake_timeout_secs: Option<u64>,
+    /// Most peers connecting to us that may be in the middle of the handshake at once. Any more
+    /// are dropped as soon as accepted. Defaults to 256.
+    pub max_pending_handshakes: Option<usize>,
+    /// Most connections from any one IP that may be in the middle of the handshake at once. Any
+    /// more are dropped as soon as accepted. Defaults to 8.
+    pub max_pending_handshakes_per_ip: Option<usize>,
+    /// Most connections accepted from any one IP in a second. Any more are dropped as soon as
+    /// accepted. Defaults to 20.
+    pub max_accepts_per_ip_per_sec: Option<u32>,
     /// Port for service discovery on local network
     pub service_discovery_port: Option<u16>,
-    /// File for bootstrap cache
+    /// File for the bootstrap cache, which keeps the peers we bootstrapped off to try first next
+    /// time. Looked for where the config file is unless an absolute path.
     pub bootstrap_cache_name: Option<String>,
     /// Bootstrap whitelisted IPs
     pub bootstrap_whitelisted_ips: HashSet<IpAddr>,
+    /// Peer endpoints never to bootstrap off or dial.
+    pub blacklisted_contacts: Option<Vec<SocketAddr>>,
+    /// IPs, or CIDR ranges such as `10.0.0.0/8`, that nodes may connect from or be dialed at.
+    /// Anyone else is dropped as soon as accepted, before any handshake. Unrestricted if unset.
+    pub whitelisted_node_ips: Option<Vec<String>>,
+    /// As `whitelisted_node_ips`, for clients bootstrapping off us. Unrestricted if unset.
+    pub whitelisted_client_ips: Option<Vec<String>>,
+    /// Failed handshakes in a row, by a peer that answered with its key, after which it is not
+    /// connected to for a while. Defaults to 3.
+    pub ban_after_failures: Option<u32>,
+    /// Seconds a peer stays banned for after failing handshakes or misbehaving. Defaults to 600.
+    pub ban_secs: Option<u64>,
+    /// File holding the secret key of our identity, created with a new one if missing. Peers
+    /// then know us by the same identity every run, rather than by a new one each time.
+    pub identity_file: Option<String>,
     /// Network ID
     ///
     /// This is a mechanism to prevent nodes from different decentralized
     /// networks to connect to each other (issue #209)
     pub network_name: Option<String>,
+    /// Genesis value of the network, e.g. the hash of its first block, hashed together with
+    /// `network_name` into the ID peers must share to connect or find each other on the LAN. Sets
+    /// apart networks launched under the same name, such as successive test networks. Nodes
+    /// predating genesis values are neither found on the LAN nor answered there by nodes with one,
+    /// as they could never connect.
+    pub network_genesis: Option<String>,
+    /// Deadline in milliseconds for finding out our externally visible addresses (via IGD,
+    /// NAT-PMP, PCP and peers' echo service) each time a socket is mapped. Defaults to 3 seconds.
+    pub nat_mapping_timeout_ms: Option<u64>,
+    /// Finish mapping a socket as soon as one globally routable address for it is known instead of
+    /// waiting for every router and peer to answer. Trades completeness of our connection info for
+    /// latency. Defaults to false.
+    pub nat_mapping_first_external: Option<bool>,
+    /// How many times to ask a peer for our external address again after it failed to answer,
+    /// waiting twice as long before each retry, for tcp and udp sockets alike. Each attempt is
+    /// given its share of the time allowed for asking, so that all of them fit. Defaults to 2.
+    pub nat_stun_retries: Option<u32>,
+    /// Advertise loopback addresses in our connection info, e.g. to run several peers on one
+    /// machine. Otherwise they are only advertised when there is nothing else. Defaults to false.
+    pub nat_keep_loopback: Option<bool>,
+    /// External addresses we are reachable at, for hosts behind a static 1:1 NAT or with ports
+    /// forwarded by hand. They are advertised ahead of anything discovered and taken as verified.
+    /// A port of 0 stands for the port of whichever socket the address is used for, as on a 1:1
+    /// NAT, while an address with a port of its own is only advertised for our listener.
+    pub nat_external_addrs: Option<Vec<SocketAddr>>,
+    /// Rely on `nat_external_addrs` and our interfaces alone, never asking routers (IGD, NAT-PMP,
+    /// PCP) nor peers for our external addresses. Defaults to false.
+    pub nat_external_addrs_only: Option<bool>,
+    /// Never look for IGD gateways nor send them UPnP requests, for routers that crash or flood
+    /// their logs when sent those. NAT-PMP, PCP and the peers are still asked. Defaults to false.
+    pub nat_disable_igd: Option<bool>,
+    /// Never ask peers for our external addresses, relying on the routers and
+    /// `nat_external_addrs` instead. Defaults to false.
+    pub nat_disable_stun: Option<bool>,
+    /// Peer to route connections through when neither a direct connection nor hole punching
+    /// succeeds. The peer we connect to must have configured the same relay.
+    pub relay: Option<SocketAddr>,
+    /// Move connections made through the relay onto a direct connection to the peer should one
+    /// become possible later, without the connection being lost. The peer dialing the direct
+    /// connection has to prove its identity on it. Peers running a crust from before migration,
+    /// or from before that proof, may drop connections to us if this is set unless capabilities
+    /// are negotiated. Off by default.
+    pub migrate_relayed: Option<bool>,
+    /// Bootstrap as a node through a contact that relays (see `act_as_relay`) should it not be
+    /// able to reach our listeners, for nodes that can neither accept connections nor have holes
+    /// punched to them. The contact bootstrapped off then relays our connections, and is handed
+    /// out in our connection info so that peers connect to us through it. Should we lose it, it
+    /// stops being handed out and another is bootstrapped off. Contacts that do not relay are
+    /// skipped. Defaults to false.
+    pub bootstrap_via_relay: Option<bool>,
+    /// Milliseconds to wait on connecting before dialing each next endpoint of the peer, or its
+    /// relay once all are dialed, while earlier attempts are still under way. The next is dialed
+    /// straight away should an attempt fail, and the first to succeed is kept. 0 dials them all
+    /// at once. Defaults to 250.
+    pub connect_stagger_ms: Option<u64>,
+    /// Whether to dial the IPv6 endpoints of a peer before its IPv4 ones, alternating between the
+    /// two as in RFC 8305. Peers are dialed over whichever family they were last reached over
+    /// first on reconnecting. Defaults to true.
+    pub connect_prefer_ipv6: Option<bool>,
+    /// Milliseconds to wait on connecting before dialing the first endpoint of the family not
+    /// preferred. Defaults to `connect_stagger_ms`.
+    pub connect_family_stagger_ms: Option<u64>,
+    /// Seconds the connection info and candidates we hand out may be connected with for. Each
+    /// carries its expiry and a nonce, and `Service::connect` refuses info that has expired or
+    /// that it already connected with, so that stale or replayed info cannot have us dial wherever
+    /// it names. The nonce is sent on connecting, and we refuse peers sending one we did not hand
+    /// out or that another peer connected with. Expiry goes by the clock of whoever connects, so
+    /// theirs and ours must roughly agree. Defaults to 10 minutes.
+    pub connection_info_ttl_secs: Option<u64>,
+    /// Make this many attempts to reconnect to a peer we connected to via `Service::connect`
+    /// should the connection drop, with exponential backoff in between. Its listeners and relay
+    /// are dialed again, and a hole punched again from the port punched from before. The peer is
+    /// reported as lost only once all attempts fail, and `Event::PeerReconnected` is sent if one
+    /// succeeds. Meanwhile up to 256 messages sent to it are held to be sent once reconnected,
+    /// droppable ones not being held. Connecting to the peer afresh, or it connecting to us,
+    /// stops reconnecting to it. Off by default.
+    pub reconnect_attempts: Option<u32>,
+    /// Delay in milliseconds before the first attempt to reconnect, doubled for each one after
+    /// it. Defaults to 500.
+    pub reconnect_initial_delay_ms: Option<u64>,
+    /// Longest delay in milliseconds between attempts to reconnect. Defaults to 30 seconds.
+    pub reconnect_max_delay_ms: Option<u64>,
+    /// Most messages per peer to keep until the peer acknowledges them, to be sent again after
+    /// reconnecting. Messages may then be delivered twice, and droppable ones are never kept.
+    /// Peers running a crust from before reconnection will drop connections to us if this is
+    /// set. None kept by default.
+    pub reconnect_replay_buffer: Option<usize>,
+    /// How often in milliseconds to send a heartbeat on an idle tcp connection, keeping the NAT
+    /// bindings along the way open. Lower it for NATs which drop idle flows early. Capped at a
+    /// minute. Defaults to 20 seconds.
+    pub tcp_keep_alive_ms: Option<u64>,
+    /// Consider a peer dead once this many of our `tcp_keep_alive_ms` periods pass without
+    /// hearing from it, so that `LostPeer` is reported within seconds rather than minutes. At
+    /// least 2. Peers with `negotiate_capabilities` set tell us their own period and are given as
+    /// many of theirs should they send less often, while the others must be given the same
+    /// `tcp_keep_alive_ms`. If not set, peers are given two minutes.
+    pub heartbeat_misses: Option<u32>,
+    /// Go easy on the battery and background limits of mobile devices: heartbeats of every
+    /// connection are batched so the radio wakes once for all of them, and gateways are not looked
+    /// for again nor the peers asked for our external IP merely because time has passed. Defaults
+    /// to false.
+    pub conserve_network: Option<bool>,
+    /// How far apart in milliseconds the wake-ups heartbeats are batched on are, with
+    /// `conserve_network`. Capped at, and defaulting to, half of `tcp_keep_alive_ms`.
+    pub keep_alive_batch_ms: Option<u64>,
+    /// Relay connections between other peers of our network that ask us to. Defaults to false.
+    pub act_as_relay: Option<bool>,
+    /// Serve peers as a traversal helper - echoing their external addresses and brokering their
+    /// rendezvous - whenever one of our listeners is found reachable from outside our network,
+    /// telling connected peers when we start and stop doing so. The helpers peers tell us of are
+    /// used for our own traversal. Only peers that negotiate capabilities (see
+    /// `negotiate_capabilities`) are told. Off by default, as it has us answer whoever asks.
+    pub act_as_helper: Option<bool>,
+    /// Seconds a message sent with a priority of at least `MSG_DROP_PRIORITY` may wait for
+    /// bandwidth before it and all waiting messages of lower priority are dropped. Defaults to a
+    /// minute.
+    pub msg_max_age_secs: Option<u64>,
+    /// Most messages of each droppable priority to hold back per connection while bandwidth is
+    /// insufficient. Unlimited by default.
+    pub msg_max_backlog: Option<usize>,
+    /// Refuse new messages rather than dropping the oldest waiting ones when `msg_max_backlog` is
+    /// reached, a refused message sent with `Service::send_tracked` being reported by
+    /// `Event::MessageNotSent` straight away. Defaults to false.
+    pub msg_drop_newest: Option<bool>,
+    /// Most bytes to queue for sending to each peer. Unlimited by default.
+    pub max_queued_bytes_per_peer: Option<usize>,
+    /// Most bytes to queue for sending to all peers together. Unlimited by default.
+    pub max_queued_bytes: Option<usize>,
+    /// What to do about messages that would go over `max_queued_bytes_per_peer` or
+    /// `max_queued_bytes`. Either way `Event::QueueFull` is sent. Defaults to refusing them.
+    pub queue_full_policy: Option<QueueFullPolicy>,
+    /// Milliseconds data may wait without any of it being sent to a peer before `Event::SlowPeer`
+    /// is sent for it, so that peers which accept connections but stop reading are noticed. Off by
+    /// default.
+    pub slow_peer_ms: Option<u64>,
+    /// Disconnect peers once `slow_peer_ms` is reached. Defaults to false.
+    pub disconnect_slow_peers: Option<bool>,
+    /// Most bytes per second to send over each connection. Unlimited by default.
+    pub max_conn_upload_bytes_per_sec: Option<u64>,
+    /// Most bytes per second to receive over each connection. Unlimited by default.
+    pub max_conn_download_bytes_per_sec: Option<u64>,
+    /// Most bytes per second to send over all connections together, e.g. to stay within the
+    /// allowance of a metered link. Unlimited by default.
+    pub max_upload_bytes_per_sec: Option<u64>,
+    /// Most bytes per second to receive over all connections together. Unlimited by default.
+    pub max_download_bytes_per_sec: Option<u64>,
+    /// Most messages per second to accept from each peer. Unlimited by default.
+    pub peer_max_msgs_per_sec: Option<u64>,
+    /// Most bytes of messages per second to accept from each peer. Unlimited by default.
+    pub peer_max_bytes_per_sec: Option<u64>,
+    /// Whether to throttle peers exceeding `peer_max_msgs_per_sec` or `peer_max_bytes_per_sec`,
+    /// or to disconnect them. Either way `Event::PeerQuotaExceeded` is sent. Defaults to
+    /// throttling.
+    pub peer_quota_policy: Option<QuotaPolicy>,
+    /// Compress messages sent to peers which support it with this codec. Peers tell each other the
+    /// codecs they support on connecting, whatever this is set to. Off by default.
+    pub compression: Option<Compression>,
+    /// Size in bytes above which messages are compressed, if `compression` is set. Defaults to
+    /// 16 KiB.
+    pub compression_threshold: Option<usize>,
+    /// Send small tcp packets right away rather than coalescing them (`TCP_NODELAY`), for lower
+    /// latency at some cost in bandwidth. OS default if not set.
+    pub tcp_nodelay: Option<bool>,
+    /// Idle time in milliseconds after which the OS starts probing whether a peer is still there
+    /// (`SO_KEEPALIVE`), `0` turning the probes off. Unlike `tcp_keep_alive_ms` no data is sent.
+    /// OS default if not set.
+    pub tcp_keepalive_idle_ms: Option<u32>,
+    /// Size in bytes of each tcp socket's send buffer (`SO_SNDBUF`). OS default if not set.
+    pub tcp_send_buffer_size: Option<usize>,
+    /// Size in bytes of each tcp socket's receive buffer (`SO_RCVBUF`), set before connecting so
+    /// that the TCP window can grow to it. OS default if not set.
+    pub tcp_recv_buffer_size: Option<usize>,
+    /// Time to live of outgoing tcp packets (`IP_TTL`, or the hop limit over IPv6). OS default if
+    /// not set.
+    pub tcp_ttl: Option<u32>,
+    /// DSCP codepoint (0 to 63) to mark the packets of our tcp connections and of the udp
+    /// sockets we map with, for managed networks to classify crust traffic by for QoS. Where the
+    /// OS does not let us mark sockets they go unmarked. Unmarked if not set.
+    pub dscp: Option<u8>,
+    /// DSCP codepoints to mark the messages of each priority with instead of `dscp`, the first
+    /// for priority 0 and the last also for every priority after it, e.g. `[46, 34, 10]` to give
+    /// droppable messages their own class. Only the first 8 are told apart. The connection is
+    /// marked anew whenever a message of another lane is sent, so segments around the switch may
+    /// go out marked for the wrong one.
+    pub dscp_lanes: Option<Vec<u8>>,
+    /// Network interface to pin our sockets to by name (`SO_BINDTODEVICE`), e.g. `"eth1"`, so
+    /// that listeners only accept peers arriving on it and peers are only dialed through it,
+    /// whatever the routing table says. Covers the sockets we listen and dial with as well as
+    /// those we map and hole punch with, though not a proxy's. Only supported on Linux and
+    /// Android, elsewhere sockets cannot be created with it set. Any interface if not set.
+    pub bind_device: Option<String>,
+    /// Largest message in bytes to accept from a peer, which drops the connection on a larger
+    /// one unless `stream_oversized_messages` is set. Defaults to 2 MiB.
+    pub max_message_size: Option<usize>,
+    /// Deliver messages over `max_message_size` in parts as they arrive, as
+    /// `Event::NewMessageChunk`, rather than refusing them. Only uncompressed messages can be
+    /// streamed, so peers send oversized messages uncompressed and do not replay them after
+    /// reconnecting. Off by default.
+    pub stream_oversized_messages: Option<bool>,
+    /// Tell each peer the optional features we support (transports, compression codecs, largest
+    /// message, streams, migration, replay) as soon as a connection is established, and use only
+    /// what both sides support. Peers that tell us theirs are answered whatever this is set to.
+    /// Our protocol version is told in the handshake regardless, peers on one we do not speak
+    /// being turned away there, so every peer we connect to understands this. Off by default.
+    pub negotiate_capabilities: Option<bool>,
+    /// Record connection, traffic and handshake metrics, to be read with `Service::metrics`. Off
+    /// by default.
+    pub metrics: Option<bool>,
+    /// Serve the metrics in the Prometheus text format over http on this address. Implies
+    /// `metrics`. Not served by default.
+    pub metrics_listen_addr: Option<SocketAddr>,
+    /// Event loops to spread connections over, up to 128. The first runs the listener,
+    /// bootstrapping and every handshake, see `reuse_port_listeners` otherwise, handing
+    /// established connections on to whichever loop holds the fewest. Relayed and reconnecting
+    /// connections stay on the first. Defaults to 1.
+    pub event_loops: Option<usize>,
+    /// On Linux, have each event loop listen on the TCP listeners' ports as well, sharing them
+    /// with `SO_REUSEPORT`, so that the kernel spreads incoming connections and their handshakes
+    /// over the loops rather than the first doing every handshake. Not done while we act as a
+    /// relay, as relayed connections stay on the first loop. Off by default.
+    pub reuse_port_listeners: Option<bool>,
+    /// SOCKS5 proxy to dial every peer through, e.g. to get past an egress proxy or to run over
+    /// Tor. Hole punching is then off and we still listen directly. Peers are dialed directly by
+    /// default.
+    pub socks5_proxy: Option<SocketAddr>,
+    /// Username to authenticate to `socks5_proxy` with, along with `socks5_password`.
+    pub socks5_username: Option<String>,
+    /// Password to authenticate to `socks5_proxy` with, along with `socks5_username`.
+    pub socks5_password: Option<String>,
+    /// HTTP proxy to tunnel connections to peers through with `CONNECT`, for networks which only
+    /// let traffic out through one. Peers are dialed directly first and through the proxy only if
+    /// that fails or takes longer than `http_proxy_fallback_ms`, unless `http_proxy_only` is set.
+    /// Not used by default.
+    pub http_proxy: Option<SocketAddr>,
+    /// Username to authenticate to `http_proxy` with, along with `http_proxy_password`.
+    pub http_proxy_username: Option<String>,
+    /// Password to authenticate to `http_proxy` with, along with `http_proxy_username`.
+    pub http_proxy_password: Option<String>,
+    /// Milliseconds to wait for a direct connection to a peer before also dialing it through
+    /// `http_proxy`. Defaults to 2 seconds.
+    pub http_proxy_fallback_ms: Option<u64>,
+    /// Dial every peer through `http_proxy` without trying to reach them directly first, as
+    /// `socks5_proxy` does. Hole punching is then off. Cannot be set along with `socks5_proxy`,
+    /// as only one proxy can carry every connection. Defaults to false.
+    pub http_proxy_only: Option<bool>,
 }
 
 impl Default for Config {
     fn default() -> Config {
         Config {
             hard_coded_contacts: vec![],
+            bootstrap_dns_seeds: None,
+            bootstrap_fan_out: None,
+            bootstrap_connections: None,
+            bootstrap_timeout_secs: None,
             tcp_acceptor_port: None,
+            tcp_acceptor_port_range: None,
+            tcp_listen_addrs: None,
             force_acceptor_port_in_ext_ep: false,
+            handshake_timeout_secs: None,
+            max_pending_handshakes: None,
+            max_pending_handshakes_per_ip: None,
+            max_accepts_per_ip_per_sec: None,
             service_discovery_port: None,
             bootstrap_cache_name: None,
             bootstrap_whitelisted_ips: HashSet::new(),
+            blacklisted_contacts: None,
+            whitelisted_node_ips: None,
+            whitelisted_client_ips: None,
+            ban_after_failures: None,
+            ban_secs: None,
+            identity_file: None,
             network_name: None,
+            network_genesis: None,
+            nat_mapping_timeout_ms: None,
+            nat_mapping_first_external: None,
+            nat_stun_retries: None,
+            nat_keep_loopback: None,
+            nat_external_addrs: None,
+            nat_external_addrs_only: None,
+            nat_disable_igd: None,
+            nat_disable_stun: None,
+            relay: None,
+            migrate_relayed: None,
+            bootstrap_via_relay: None,
+            connect_stagger_ms: None,
+            connect_prefer_ipv6: None,
+            connect_family_stagger_ms: None,
+            connection_info_ttl_secs: None,
+            reconnect_attempts: None,
+            reconnect_initial_delay_ms: None,
+            reconnect_max_delay_ms: None,
+            reconnect_replay_buffer: None,
+            tcp_keep_alive_ms: None,
+            heartbeat_misses: None,
+            conserve_network: None,
+            keep_alive_batch_ms: None,
+            act_as_relay: None,
+            act_as_helper: None,
+            msg_max_age_secs: None,
+            msg_max_backlog: None,
+            msg_drop_newest: None,
+            max_queued_bytes_per_peer: None,
+            max_queued_bytes: None,
+            queue_full_policy: None,
+            slow_peer_ms: None,
+            disconnect_slow_peers: None,
+            max_conn_upload_bytes_per_sec: None,
+            max_conn_download_bytes_per_sec: None,
+            max_upload_bytes_per_sec: None,
+            max_download_bytes_per_sec: None,
+            peer_max_msgs_per_sec: None,
+            peer_max_bytes_per_sec: None,
+            peer_quota_policy: None,
+            compression: None,
+            compression_threshold: None,
+            tcp_nodelay: None,
+            tcp_keepalive_idle_ms: None,
+            tcp_send_buffer_size: None,
+            tcp_recv_buffer_size: None,
+            tcp_ttl: None,
+            dscp: None,
+            dscp_lanes: None,
+            bind_device: None,
+            max_message_size: None,
+            stream_oversized_messages: None,
+            negotiate_capabilities: None,
+            metrics: None,
+            metrics_listen_addr: None,
+            event_loops: None,
+            reuse_port_listeners: None,
+            socks5_proxy: None,
+            socks5_username: None,
+            socks5_password: None,
+            http_proxy: None,
+            http_proxy_username: None,
+            http_proxy_password: None,
+            http_proxy_fallback_ms: None,
+            http_proxy_only: None,
         }
     }
 }
 
-/// Reads the default crust config file.
+/// Builds a `Config` in code, for embedders that would rather not ship a config file. Anything
+/// not set is left at its default, and fields without a setter here can still be set on the
+/// built `Config` before passing it to `Service::with_config`.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    /// Start from the defaults, as for an empty config file.
+    pub fn new() -> Self {
+        ConfigBuilder::default()
+    }
+
+    /// Start from `config`, e.g. one read from a file, to override parts of it.
+    pub fn from_config(config: Config) -> Self {
+        ConfigBuilder { config: config }
+    }
+
+    /// Contacts to bootstrap off, in addition to any already set.
+    pub fn hard_coded_contacts<I>(mut self, contacts: I) -> Self
+        where I: IntoIterator<Item = SocketAddr>
+    {
+        self.config.hard_coded_contacts.extend(contacts);
+        self
+    }
+
+    /// Bootstrap contacts to look up in DNS, as for `Config::bootstrap_dns_seeds`.
+    pub fn bootstrap_dns_seeds<I, S>(mut self, seeds: I) -> Self
+        where I: IntoIterator<Item = S>,
+              S: Into<String>
+    {
+        self.config.bootstrap_dns_seeds = Some(seeds.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// How long bootstrapping may take in all, to the second.
+    pub fn bootstrap_timeout(mut self, timeout: Duration) -> Self {
+        self.config.bootstrap_timeout_secs = Some(timeout.as_secs());
+        self
+    }
+
+    /// File to keep the bootstrap cache in.
+    pub fn bootstrap_cache_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.config.bootstrap_cache_name = Some(name.into());
+        self
+    }
+
+    /// IPs of the only peers to accept as bootstrapping off us, in addition to any already set.
+    pub fn bootstrap_whitelisted_ips<I>(mut self, ips: I) -> Self
+        where I: IntoIterator<Item = IpAddr>
+    {
+        self.config.bootstrap_whitelisted_ips.extend(ips);
+        self
+    }
+
+    /// Peer endpoints never to bootstrap off or dial, in addition to any already set.
+    pub fn blacklisted_contacts<I>(mut self, contacts: I) -> Self
+        where I: IntoIterator<Item = SocketAddr>
+    {
+        let mut blacklist = self.config.blacklisted_contacts.take().unwrap_or_else(Vec::new);
+        blacklist.extend(contacts);
+        self.config.blacklisted_contacts = Some(blacklist);
+        self
+    }
+
+    /// IPs or CIDR ranges nodes may connect from or be dialed at, in addition to any set already.
+    pub fn whitelisted_node_ips<I, S>(mut self, ranges: I) -> Self
+        where I: IntoIterator<Item = S>,
+              S: Into<String>
+    {
+        let mut whitelist = self.config.whitelisted_node_ips.take().unwrap_or_else(Vec::new);
+        whitelist.extend(ranges.into_iter().map(Into::into));
+        self.config.whitelisted_node_ips = Some(whitelist);
+        self
+    }
+
+    /// IPs or CIDR ranges clients may bootstrap off us from, in addition to any set already.
+    pub fn whitelisted_client_ips<I, S>(mut self, ranges: I) -> Self
+        where I: IntoIterator<Item = S>,
+              S: Into<String>
+    {
+        let mut whitelist = self.config.whitelisted_client_ips.take().unwrap_or_else(Vec::new);
+        whitelist.extend(ranges.into_iter().map(Into::into));
+        self.config.whitelisted_client_ips = Some(whitelist);
+        self
+    }
+
+    /// Port for the TCP acceptor.
+    pub fn tcp_acceptor_port(mut self, port: u16) -> Self {
+        self.config.tcp_acceptor_port = Some(port);
+        self
+    }
+
+    /// First and last port, inclusive, the TCP acceptor may use.
+    pub fn tcp_acceptor_port_range(mut self, first: u16, last: u16) -> Self {
+        self.config.tcp_acceptor_port_range = Some((first, last));
+        self
+    }
+
+    /// Listen on each of `addrs` rather than on every interface.
+    pub fn tcp_listen_addrs<I>(mut self, addrs: I) -> Self
+        where I: IntoIterator<Item = SocketAddr>
+    {
+        self.config.tcp_listen_addrs = Some(addrs.into_iter().collect());
+        self
+    }
+
+    /// How long a peer connecting to us has to complete the handshake, to the second.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.config.handshake_timeout_secs = Some(timeout.as_secs());
+        self
+    }
+
+    /// Most peers that may be in the middle of the handshake with us at once, and most
+    /// connections accepted from any one IP in a second.
+    pub fn accept_limits(mut self, max_pending: usize, per_ip_per_sec: u32) -> Self {
+        self.config.max_pending_handshakes = Some(max_pending);
+        self.config.max_accepts_per_ip_per_sec = Some(per_ip_per_sec);
+        self
+    }
+
+    /// Most connections from any one IP that may be in the middle of the handshake at once.
+    pub fn max_pending_handshakes_per_ip(mut self, max_pending: usize) -> Self {
+        self.config.max_pending_handshakes_per_ip = Some(max_pending);
+        self
+    }
+
+    /// Port for service discovery on the local network.
+    pub fn service_discovery_port(mut self, port: u16) -> Self {
+        self.config.service_discovery_port = Some(port);
+        self
+    }
+
+    /// File to keep our identity's secret key in, so that it lasts across runs.
+    pub fn identity_file<S: Into<String>>(mut self, path: S) -> Self {
+        self.config.identity_file = Some(path.into());
+        self
+    }
+
+    /// Name of the network, so as not to connect to peers of other networks.
+    pub fn network_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.config.network_name = Some(name.into());
+        self
+    }
+
+    /// Genesis value of the network, telling it apart from others of the same name.
+    pub fn network_genesis<S: Into<String>>(mut self, genesis: S) -> Self {
+        self.config.network_genesis = Some(genesis.into());
+        self
+    }
+
+    /// Peer to route connections through when all else fails.
+    pub fn relay(mut self, relay: SocketAddr) -> Self {
+        self.config.relay = Some(relay);
+        self
+    }
+
+    /// Whether to bootstrap as a node through a contact that relays, rather than by proving we
+    /// can be reached.
+    pub fn bootstrap_via_relay(mut self, via_relay: bool) -> Self {
+        self.config.bootstrap_via_relay = Some(via_relay);
+        self
+    }
+
+    /// How long to wait on connecting before racing each next endpoint of the peer.
+    pub fn connect_stagger(mut self, stagger: Duration) -> Self {
+        self.config.connect_stagger_ms = Some(millis(stagger));
+        self
+    }
+
+    /// Whether to dial IPv6 endpoints of peers first, and how long to wait before dialing the
+    /// first endpoint of the other family.
+    pub fn connect_families(mut self, prefer_ipv6: bool, family_stagger: Duration) -> Self {
+        self.config.connect_prefer_ipv6 = Some(prefer_ipv6);
+        self.config.connect_family_stagger_ms = Some(millis(family_stagger));
+        self
+    }
+
+    /// How long the connection info we hand out may be connected with for.
+    pub fn connection_info_ttl(mut self, ttl: Duration) -> Self {
+        self.config.connection_info_ttl_secs = Some(ttl.as_secs());
+        self
+    }
+
+    /// Whether to relay connections between other peers that ask us to.
+    pub fn act_as_relay(mut self, act_as_relay: bool) -> Self {
+        self.config.act_as_relay = Some(act_as_relay);
+        self
+    }
+
+    /// Whether to serve peers as a traversal helper when reachable from outside our network.
+    pub fn act_as_helper(mut self, act_as_helper: bool) -> Self {
+        self.config.act_as_helper = Some(act_as_helper);
+        self
+    }
+
+    /// Deadline for finding out our externally visible addresses each time a socket is mapped.
+    pub fn nat_mapping_timeout(mut self, timeout: Duration) -> Self {
+        self.config.nat_mapping_timeout_ms = Some(millis(timeout));
+        self
+    }
+
+    /// External addresses we are reachable at, advertised ahead of anything discovered. With
+    /// `only`, routers and peers are not asked for ours at all.
+    pub fn nat_external_addrs(mut self, addrs: Vec<SocketAddr>, only: bool) -> Self {
+        self.config.nat_external_addrs = Some(addrs);
+        self.config.nat_external_addrs_only = Some(only);
+        self
+    }
+
+    /// Whether to leave IGD gateways alone, mapping ports through NAT-PMP and PCP only.
+    pub fn nat_disable_igd(mut self, disable: bool) -> Self {
+        self.config.nat_disable_igd = Some(disable);
+        self
+    }
+
+    /// Whether to never ask peers for our external addresses.
+    pub fn nat_disable_stun(mut self, disable: bool) -> Self {
+        self.config.nat_disable_stun = Some(disable);
+        self
+    }
+
+    /// How often to send a heartbeat on an idle connection.
+    pub fn keep_alive(mut self, period: Duration) -> Self {
+        self.config.tcp_keep_alive_ms = Some(millis(period));
+        self
+    }
+
+    /// Heartbeat periods to go without hearing from a peer before considering it dead.
+    pub fn heartbeat_misses(mut self, misses: u32) -> Self {
+        self.config.heartbeat_misses = Some(misses);
+        self
+    }
+
+    /// Batch heartbeats and hold off on background traffic, for mobile devices.
+    pub fn conserve_network(mut self, conserve: bool) -> Self {
+        self.config.conserve_network = Some(conserve);
+        self
+    }
+
+    /// How far apart the wake-ups heartbeats are batched on are, with `conserve_network`.
+    pub fn keep_alive_batch(mut self, batch: Duration) -> Self {
+        self.config.keep_alive_batch_ms = Some(millis(batch));
+        self
+    }
+
+    /// Reconnect to peers lost after `Service::connect`, making at most `attempts`.
+    pub fn reconnect_attempts(mut self, attempts: u32) -> Self {
+        self.config.reconnect_attempts = Some(attempts);
+        self
+    }
+
+    /// Compress messages over `threshold` bytes with `codec`, for peers which support it.
+    pub fn compression(mut self, codec: Compression, threshold: usize) -> Self {
+        self.config.compression = Some(codec);
+        self.config.compression_threshold = Some(threshold);
+        self
+    }
+
+    /// Most bytes per second to send and to receive over all connections together.
+    pub fn bandwidth(mut self, upload_bytes_per_sec: u64, download_bytes_per_sec: u64) -> Self {
+        self.config.max_upload_bytes_per_sec = Some(upload_bytes_per_sec);
+        self.config.max_download_bytes_per_sec = Some(download_bytes_per_sec);
+        self
+    }
+
+    /// Most bytes to queue for sending to each peer and to all of them together, and what to do
+    /// about messages that would go over.
+    pub fn queue_limits(mut self,
+                        per_peer: Option<usize>,
+                        total: Option<usize>,
+                        policy: QueueFullPolicy)
+                        -> Self {
+        self.config.max_queued_bytes_per_peer = per_peer;
+        self.config.max_queued_bytes = total;
+        self.config.queue_full_policy = Some(policy);
+        self
+    }
+
+    /// How long data may wait without any of it being sent to a peer before it is considered
+    /// slow, and whether to disconnect it then.
+    pub fn slow_peers(mut self, after: Duration, disconnect: bool) -> Self {
+        self.config.slow_peer_ms = Some(millis(after));
+        self.config.disconnect_slow_peers = Some(disconnect);
+        self
+    }
+
+    /// Most messages and bytes per second to accept from each peer, and what to do about peers
+    /// sending more.
+    pub fn peer_quota(mut self,
+                      msgs_per_sec: Option<u64>,
+                      bytes_per_sec: Option<u64>,
+                      policy: QuotaPolicy)
+                      -> Self {
+        self.config.peer_max_msgs_per_sec = msgs_per_sec;
+        self.config.peer_max_bytes_per_sec = bytes_per_sec;
+        self.config.peer_quota_policy = Some(policy);
+        self
+    }
+
+    /// Largest message in bytes to accept from a peer.
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.config.max_message_size = Some(size);
+        self
+    }
+
+    /// DSCP codepoint to mark our packets with.
+    pub fn dscp(mut self, dscp: u8) -> Self {
+        self.config.dscp = Some(dscp);
+        self
+    }
+
+    /// DSCP codepoints to mark the messages of each priority with, the last for all lower ones.
+    pub fn dscp_lanes(mut self, codepoints: Vec<u8>) -> Self {
+        self.config.dscp_lanes = Some(codepoints);
+        self
+    }
+
+    /// Network interface to pin our sockets to.
+    pub fn bind_device(mut self, name: &str) -> Self {
+        self.config.bind_device = Some(name.to_owned());
+        self
+    }
+
+    /// Whether to tell peers what we support as soon as a connection is established.
+    pub fn negotiate_capabilities(mut self, negotiate: bool) -> Self {
+        self.config.negotiate_capabilities = Some(negotiate);
+        self
+    }
+
+    /// Record metrics, serving them for Prometheus on `listen_addr` if given.
+    pub fn metrics(mut self, listen_addr: Option<SocketAddr>) -> Self {
+        self.config.metrics = Some(true);
+        self.config.metrics_listen_addr = listen_addr;
+        self
+    }
+
+    /// Spread connections over `count` event loops.
+    pub fn event_loops(mut self, count: usize) -> Self {
+        self.config.event_loops = Some(count);
+        self
+    }
+
+    /// Whether every event loop accepts connections on the listeners' ports, on Linux.
+    pub fn reuse_port_listeners(mut self, reuse: bool) -> Self {
+        self.config.reuse_port_listeners = Some(reuse);
+        self
+    }
+
+    /// Dial peers through the SOCKS5 proxy at `proxy`, authenticating with a username and
+    /// password if given.
+    pub fn socks5_proxy(mut self, proxy: SocketAddr, auth: Option<(String, String)>) -> Self {
+        self.config.socks5_proxy = Some(proxy);
+        let (username, password) = match auth {
+            Some((username, password)) => (Some(username), Some(password)),
+            None => (None, None),
+        };
+        self.config.socks5_username = username;
+        self.config.socks5_password = password;
+        self
+    }
+
+    /// Fall back on tunneling through the HTTP proxy at `proxy` when peers cannot be dialed
+    /// directly, authenticating with a username and password if given.
+    pub fn http_proxy(mut self, proxy: SocketAddr, auth: Option<(String, String)>) -> Self {
+        self.config.http_proxy = Some(proxy);
+        let (username, password) = match auth {
+            Some((username, password)) => (Some(username), Some(password)),
+            None => (None, None),
+        };
+        self.config.http_proxy_username = username;
+        self.config.http_proxy_password = password;
+        self
+    }
+
+    /// Let `CRUST_*` environment variables override what has been set so far, as they would a
+    /// config file.
+    pub fn env_overrides(self) -> ::Res<Self> {
+        Ok(ConfigBuilder { config: apply_env_overrides(self.config, env::vars())? })
+    }
+
+    /// The config built.
+    pub fn build(self) -> Config {
+        self.config
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+/// Reads the default crust config file, with any `CRUST_*` environment variables overriding the
+/// values in it. Without a config file the variables override the default config instead.
 pub fn read_config_file() -> ::Res<Config> {
-    let file_handler = FileHandler::new(&get_file_name()?, false)?;
-    let cfg = file_handler.read_file()?;
-    Ok(cfg)
+    let cfg = match FileHandler::new(&get_file_name()?, false) {
+        Ok(file_handler) => file_handler.read_file()?,
+        Err(e) => {
+            debug!("No config file to read ({:?}), starting from the default config", e);
+            Config::default()
+        }
+    };
+    apply_env_overrides(cfg, env::vars())
+}
+
+/// Overrides fields of `config` with the variables named `CRUST_` and the field name in upper
+/// case, e.g. `CRUST_TCP_ACCEPTOR_PORT=5483`. A value is read as JSON if that fits the field, and
+/// otherwise as a string or a comma separated list, e.g.
+/// `CRUST_HARD_CODED_CONTACTS=1.2.3.4:5483,5.6.7.8:5483`. Variables naming no field are ignored.
+pub fn apply_env_overrides<I>(mut config: Config, vars: I) -> ::Res<Config>
+    where I: IntoIterator<Item = (String, String)>
+{
+    let mut fields = match unwrap!(serde_json::to_value(&config)) {
+        Value::Object(fields) => fields,
+        _ => return Ok(config),
+    };
+
+    for (name, value) in vars {
+        if !name.starts_with(ENV_PREFIX) {
+            continue;
+        }
+        let field = name[ENV_PREFIX.len()..].to_lowercase();
+        if !fields.contains_key(&field) {
+            continue;
+        }
+        let mut overridden = None;
+        for candidate in env_values(&value) {
+            let _ = fields.insert(field.clone(), candidate);
+            if let Ok(cfg) = serde_json::from_value(Value::Object(fields.clone())) {
+                overridden = Some(cfg);
+                break;
+            }
+        }
+        config = match overridden {
+            Some(cfg) => cfg,
+            None => return Err(CrustError::ConfigEnvVar(name, value)),
+        };
+    }
+
+    Ok(config)
+}
+
+/// Checks that the values of `config` can be used together.
+pub fn check_config(config: &Config) -> ::Res<()> {
+    if config.socks5_proxy.is_some() && config.http_proxy.is_some() &&
+       config.http_proxy_only.unwrap_or(false) {
+        return Err(CrustError::InvalidConfig("socks5_proxy cannot be combined with \
+                                              http_proxy_only"));
+    }
+    if config.socks5_username.is_some() && config.socks5_password.is_none() {
+        return Err(CrustError::InvalidConfig("socks5_username given without socks5_password"));
+    }
+    if config.http_proxy_username.is_some() && config.http_proxy_password.is_none() {
+        return Err(CrustError::InvalidConfig("http_proxy_username given without \
+                                              http_proxy_password"));
+    }
+    if let Some((first, last)) = config.tcp_acceptor_port_range {
+        if first > last {
+            return Err(CrustError::InvalidConfig("tcp_acceptor_port_range ends before it \
+                                                  starts"));
+        }
+    }
+    if config.tcp_listen_addrs.as_ref().map_or(false, |addrs| addrs.is_empty()) {
+        return Err(CrustError::InvalidConfig("tcp_listen_addrs is empty"));
+    }
+    Ok(())
+}
+
+// What an environment variable's value could mean, in the order to try them.
+fn env_values(value: &str) -> Vec<Value> {
+    let mut values = Vec::with_capacity(3);
+    if let Ok(json) = serde_json::from_str(value) {
+        values.push(json);
+    }
+    values.push(Value::String(value.to_owned()));
+    let items = value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| serde_json::from_str(item).unwrap_or_else(|_| Value::String(item.to_owned())))
+        .collect();
+    values.push(Value::Array(items));
+    values
 }
 
 /// Writes a Crust config file **for use by tests and examples**.
@@ -86,7 +948,6 @@ pub fn read_config_file() -> ::Res<Config> {
 #[allow(dead_code)]
 pub fn write_config_file(hard_coded_contacts: Option<Vec<SocketAddr>>) -> ::Res<PathBuf> {
     use std::io::Write;
-    use serde_json;
 
     let mut config = Config::default();
 
@@ -112,10 +973,12 @@ fn get_file_name() -> ::Res<OsString> {
 
 #[cfg(test)]
 mod tests {
-    use super::Config;
+    use super::{Config, ConfigBuilder, apply_env_overrides, check_config};
+    use main::CrustError;
     use serde_json;
     use std::io::Read;
     use std::path::Path;
+    use std::time::Duration;
 
     #[test]
     fn parse_sample_config_file() {
@@ -138,4 +1001,105 @@ mod tests {
             panic!(format!("CrustError parsing sample.config: {:?}", what));
         }
     }
+
+    #[test]
+    fn builder() {
+        let contact = unwrap!("10.0.0.1:5483".parse());
+        let config = ConfigBuilder::new()
+            .hard_coded_contacts(vec![contact])
+            .tcp_acceptor_port(5483)
+            .network_name("test")
+            .network_genesis("0000")
+            .keep_alive(Duration::from_millis(1500))
+            .build();
+        assert_eq!(config.hard_coded_contacts, vec![contact]);
+        assert_eq!(config.tcp_acceptor_port, Some(5483));
+        assert_eq!(config.network_name, Some("test".to_owned()));
+        assert_eq!(config.network_genesis, Some("0000".to_owned()));
+        assert_eq!(config.tcp_keep_alive_ms, Some(1500));
+
+        let config = ConfigBuilder::from_config(config)
+            .tcp_acceptor_port(5484)
+            .build();
+        assert_eq!(config.tcp_acceptor_port, Some(5484));
+        assert_eq!(config.network_name, Some("test".to_owned()));
+    }
+
+    #[test]
+    fn env_overrides() {
+        let vars = vec![("CRUST_TCP_ACCEPTOR_PORT".to_owned(), "5483".to_owned()),
+                        ("CRUST_HARD_CODED_CONTACTS".to_owned(),
+                         "10.0.0.1:5483, 10.0.0.2:5483".to_owned()),
+                        ("CRUST_NETWORK_NAME".to_owned(), "1234".to_owned()),
+                        ("CRUST_TCP_ACCEPTOR_PORT_RANGE".to_owned(), "5000,5010".to_owned()),
+                        ("CRUST_LOG".to_owned(), "debug".to_owned()),
+                        ("PATH".to_owned(), "/bin".to_owned())];
+        let config = unwrap!(apply_env_overrides(Config::default(), vars));
+        assert_eq!(config.tcp_acceptor_port, Some(5483));
+        assert_eq!(config.hard_coded_contacts,
+                   vec![unwrap!("10.0.0.1:5483".parse()), unwrap!("10.0.0.2:5483".parse())]);
+        assert_eq!(config.network_name, Some("1234".to_owned()));
+        assert_eq!(config.tcp_acceptor_port_range, Some((5000, 5010)));
+
+        let vars = vec![("CRUST_TCP_ACCEPTOR_PORT".to_owned(), "none".to_owned())];
+        assert!(apply_env_overrides(Config::default(), vars).is_err());
+    }
+
+    #[test]
+    fn conflicting_proxies() {
+        let proxy = unwrap!("10.0.0.1:1080".parse());
+        let mut config = ConfigBuilder::new()
+            .socks5_proxy(proxy, None)
+            .http_proxy(proxy, None)
+            .build();
+        unwrap!(check_config(&config));
+        config.http_proxy_only = Some(true);
+        match check_config(&config) {
+            Err(CrustError::InvalidConfig(_)) => (),
+            res => panic!("Unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn reversed_port_range() {
+        let mut config = ConfigBuilder::new()
+            .tcp_acceptor_port_range(5000, 5000)
+            .build();
+        unwrap!(check_config(&config));
+        config.tcp_acceptor_port_range = Some((5010, 5000));
+        match check_config(&config) {
+            Err(CrustError::InvalidConfig(_)) => (),
+            res => panic!("Unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn empty_listen_addrs() {
+        let mut config = Config::default();
+        config.tcp_listen_addrs = Some(vec![unwrap!("0.0.0.0:5483".parse())]);
+        unwrap!(check_config(&config));
+        config.tcp_listen_addrs = Some(vec![]);
+        match check_config(&config) {
+            Err(CrustError::InvalidConfig(_)) => (),
+            res => panic!("Unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn proxy_username_without_password() {
+        let proxy = unwrap!("10.0.0.1:1080".parse());
+        let mut config = ConfigBuilder::new().socks5_proxy(proxy, None).build();
+        config.socks5_username = Some("user".to_owned());
+        match check_config(&config) {
+            Err(CrustError::InvalidConfig(_)) => (),
+            res => panic!("Unexpected {:?}", res),
+        }
+
+        let mut config = ConfigBuilder::new().http_proxy(proxy, None).build();
+        config.http_proxy_username = Some("user".to_owned());
+        match check_config(&config) {
+            Err(CrustError::InvalidConfig(_)) => (),
+            res => panic!("Unexpected {:?}", res),
+        }
+    }
 }
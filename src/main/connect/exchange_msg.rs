@@ -15,24 +15,40 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-use common::{Core, Message, NameHash, Priority, Socket, State};
-use main::{ConnectionId, ConnectionMap, PeerId};
+use common::{Challenge, Core, Identity, IdentityKeys, IdentityProof, KeyProof, Message, NameHash,
+             PROTOCOL_VERSION, Priority, Socket, State, TraceState, is_compatible_version, lock};
+use main::{BanList, ConnectionId, ConnectionMap, Offers, PeerId};
 use mio::{Poll, PollOpt, Ready, Token};
+use rust_sodium::crypto::box_::PublicKey;
 use std::any::Any;
 use std::cell::RefCell;
 use std::collections::hash_map::Entry;
 use std::mem;
 use std::rc::Rc;
 
-pub type Finish = Box<FnMut(&mut Core, &Poll, Token, Option<Socket>)>;
+pub type Finish = Box<FnMut(&mut Core, &Poll, Token, Option<(Socket, Identity)>)>;
 
 pub struct ExchangeMsg {
     token: Token,
+    our_id: PeerId,
     expected_id: PeerId,
     expected_nh: NameHash,
+    expected_identity: Option<Identity>,
+    identity: IdentityKeys,
+    // What each of us has to sign over, along with the other's, to prove its identity.
+    challenge: Challenge,
+    their_challenge: Option<Challenge>,
+    offers: Offers,
+    // The nonce of our info the peer connected with, to be its once it proves its identity.
+    our_nonce: Option<u64>,
     socket: Socket,
     cm: ConnectionMap,
+    relay_req: Option<(Message, Priority)>,
+    // Whether we are waiting for the relay to challenge us to prove we are who we asked as,
+    // before which nothing is sent to the peer.
+    awaiting_relay: bool,
     msg: Option<(Message, Priority)>,
+    ban_list: BanList,
     finish: Finish,
 }
 
@@ -41,9 +57,15 @@ impl ExchangeMsg {
                  poll: &Poll,
                  socket: Socket,
                  our_id: PeerId,
+                 identity: &IdentityKeys,
                  expected_id: PeerId,
+                 expected_identity: Option<Identity>,
+                 their_nonce: u64,
+                 offers: Offers,
                  name_hash: NameHash,
                  cm: ConnectionMap,
+                 relayed: bool,
+                 ban_list: BanList,
                  finish: Finish)
                  -> ::Res<Token> {
         let token = core.get_new_token();
@@ -54,7 +76,7 @@ impl ExchangeMsg {
                       PollOpt::edge())?;
 
         {
-            let mut guard = unwrap!(cm.lock());
+            let mut guard = lock(&cm);
             guard
                 .entry(expected_id)
                 .or_insert(ConnectionId {
@@ -67,17 +89,41 @@ impl ExchangeMsg {
                    guard.get(&expected_id));
         }
 
+        let challenge = Challenge::new();
+        let connect = Message::IdentifiedConnect(our_id.0,
+                                                 name_hash,
+                                                 challenge,
+                                                 Some(their_nonce),
+                                                 PROTOCOL_VERSION);
         let state = ExchangeMsg {
             token: token,
+            our_id: our_id,
             expected_id: expected_id,
             expected_nh: name_hash,
+            expected_identity: expected_identity,
+            identity: identity.clone(),
+            challenge: challenge,
+            their_challenge: None,
+            offers: offers,
+            our_nonce: None,
             socket: socket,
             cm: cm,
-            msg: Some((Message::Connect(our_id.0, name_hash), 0)),
+            relay_req: if relayed {
+                Some((Message::RelayConnect(our_id.0, expected_id.0, name_hash), 0))
+            } else {
+                None
+            },
+            awaiting_relay: relayed,
+            msg: Some((connect, 0)),
+            ban_list: ban_list,
             finish: finish,
         };
 
         let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+        core.trace(token,
+                   TraceState::Handshake,
+                   "started",
+                   &[("direction", &"outbound"), ("peer", &expected_id), ("relayed", &relayed)]);
 
         Ok(token)
     }
@@ -88,37 +134,176 @@ impl ExchangeMsg {
         }
     }
 
+    // The peer's challenge and its proof may well arrive together, so read until there is nothing
+    // left or the handshake is over.
     fn receive_response(&mut self, core: &mut Core, poll: &Poll) {
-        match self.socket.read::<Message>() {
-            Ok(Some(Message::Connect(their_pk, name_hash))) => {
-                if their_pk != self.expected_id.0 || name_hash != self.expected_nh {
+        loop {
+            match self.socket.read::<Message>() {
+                Ok(Some(Message::IdentifiedConnect(their_pk,
+                                                   name_hash,
+                                                   challenge,
+                                                   nonce,
+                                                   version))) => {
+                    if !is_compatible_version(version) {
+                        debug!("Peer {:?} speaks protocol version {}",
+                               self.expected_id,
+                               version);
+                        // Only against the peer when it is the one that answered, rather than
+                        // whoever else happens to be at the endpoint
+                        if their_pk == self.expected_id.0 {
+                            self.ban_list.record_failure(self.expected_id);
+                        }
+                        return self.handle_error(core, poll);
+                    }
+                    if !self.handle_connect(poll, their_pk, name_hash, challenge, nonce) {
+                        return self.handle_error(core, poll);
+                    }
+                }
+                Ok(Some(Message::RelayChallenge(relay_pk, challenge))) if self.awaiting_relay => {
+                    if !self.handle_relay_challenge(core, poll, relay_pk, challenge) {
+                        return self.handle_error(core, poll);
+                    }
+                }
+                Ok(Some(Message::Identify(proof))) => {
+                    return self.handle_identify(core, poll, proof);
+                }
+                Ok(Some(Message::Connect(..))) => {
+                    debug!("Peer {:?} speaks a protocol from before identities",
+                           self.expected_id);
                     return self.handle_error(core, poll);
                 }
-                let _ = core.remove_state(self.token);
-                let token = self.token;
-                let socket = mem::replace(&mut self.socket, Socket::default());
+                Ok(None) => return,
+                Ok(Some(_)) | Err(_) => return self.handle_error(core, poll),
+            }
+        }
+    }
+
+    // Answers the peer's challenge, returning whether it could be. A listener answering our
+    // dial names none of our info, while a peer dialing us in turn must name info we handed out.
+    fn handle_connect(&mut self,
+                      poll: &Poll,
+                      their_pk: PublicKey,
+                      name_hash: NameHash,
+                      their_challenge: Challenge,
+                      nonce: Option<u64>)
+                      -> bool {
+        if their_pk != self.expected_id.0 || name_hash != self.expected_nh ||
+           self.their_challenge.is_some() {
+            return false;
+        }
+        if let Some(nonce) = nonce {
+            if !lock(&self.offers).admits(&self.expected_id, nonce) {
+                debug!("Peer {:?} connects with info we did not hand it",
+                       self.expected_id);
+                return false;
+            }
+            self.our_nonce = Some(nonce);
+        }
+        self.their_challenge = Some(their_challenge);
+        let proof = self.identity
+            .prove(&self.our_id.0, &their_pk, &self.challenge, &their_challenge);
+        self.socket
+            .write(poll, self.token, Some((Message::Identify(proof), 0)))
+            .is_ok()
+    }
+
+    // Proves to the relay that we hold our `PeerId` key, which has it pair us up with the peer,
+    // and only then greets the peer. Returns whether the proof could be sent.
+    fn handle_relay_challenge(&mut self,
+                              core: &mut Core,
+                              poll: &Poll,
+                              relay_pk: PublicKey,
+                              challenge: Challenge)
+                              -> bool {
+        let proof = match core.secret_key() {
+            Some(our_sk) => KeyProof::new(our_sk, &relay_pk, &challenge, &(self.expected_id.0).0),
+            None => return false,
+        };
+        self.awaiting_relay = false;
+        if self.socket
+               .write(poll, self.token, Some((Message::RelayProof(proof), 0)))
+               .is_err() {
+            return false;
+        }
+        let req = self.msg.take();
+        self.socket.write(poll, self.token, req).is_ok()
+    }
 
-                (*self.finish)(core, poll, token, Some(socket));
+    fn handle_identify(&mut self, core: &mut Core, poll: &Poll, proof: IdentityProof) {
+        let their_challenge = match self.their_challenge {
+            Some(their_challenge) => their_challenge,
+            None => return self.handle_error(core, poll),
+        };
+        let their_identity = match proof.verify(&self.expected_id.0,
+                                                &self.our_id.0,
+                                                &their_challenge,
+                                                &self.challenge) {
+            Some(identity) => identity,
+            None => {
+                debug!("Peer {:?} failed to prove its identity", self.expected_id);
+                return self.handle_error(core, poll);
             }
-            Ok(None) => (),
-            Ok(Some(_)) | Err(_) => self.handle_error(core, poll),
+        };
+        if self.expected_identity
+               .map_or(false, |expected| expected != their_identity) {
+            debug!("Peer {:?} is {:?} rather than {:?}",
+                   self.expected_id,
+                   their_identity,
+                   self.expected_identity);
+            self.ban_list.record_misbehaviour(self.expected_id);
+            return self.handle_error(core, poll);
         }
+        self.ban_list.record_success(&self.expected_id);
+        if let Some(nonce) = self.our_nonce {
+            lock(&self.offers).redeem(self.expected_id, nonce);
+        }
+        let _ = core.remove_state(self.token);
+        let token = self.token;
+        core.trace(token,
+                   TraceState::Handshake,
+                   "completed",
+                   &[("peer", &self.expected_id)]);
+        let socket = mem::replace(&mut self.socket, Socket::default());
+
+        (*self.finish)(core, poll, token, Some((socket, their_identity)));
     }
 
     fn handle_error(&mut self, core: &mut Core, poll: &Poll) {
         self.terminate(core, poll);
         let token = self.token;
+        core.trace(token,
+                   TraceState::Handshake,
+                   "failed",
+                   &[("peer", &self.expected_id)]);
         (*self.finish)(core, poll, token, None);
     }
 }
 
 impl State for ExchangeMsg {
+    fn name(&self) -> &'static str {
+        "connect::ExchangeMsg"
+    }
+
+    fn peer_id(&self) -> Option<PeerId> {
+        Some(self.expected_id)
+    }
+
     fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
         if kind.is_error() || kind.is_hup() {
             self.handle_error(core, poll);
         } else {
             if kind.is_writable() {
-                let req = self.msg.take();
+                // Asks the relay to pair us up with the peer before we talk to it
+                if let Some(relay_req) = self.relay_req.take() {
+                    if self.socket.write(poll, self.token, Some(relay_req)).is_err() {
+                        return self.handle_error(core, poll);
+                    }
+                }
+                let req = if self.awaiting_relay {
+                    None
+                } else {
+                    self.msg.take()
+                };
                 self.write(core, poll, req);
             }
             if kind.is_readable() {
@@ -131,7 +316,7 @@ impl State for ExchangeMsg {
         let _ = core.remove_state(self.token);
         let _ = poll.deregister(&self.socket);
 
-        let mut guard = unwrap!(self.cm.lock());
+        let mut guard = lock(&self.cm);
         if let Entry::Occupied(mut oe) = guard.entry(self.expected_id) {
             oe.get_mut().currently_handshaking -= 1;
             if oe.get().currently_handshaking == 0 && oe.get().active_connection.is_none() {
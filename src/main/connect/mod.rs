@@ -18,31 +18,144 @@
 mod exchange_msg;
 
 use self::exchange_msg::ExchangeMsg;
-use common::{Core, CoreTimer, NameHash, Socket, State};
-use main::{ActiveConnection, ConnectionCandidate, ConnectionMap, CrustError, Event, PeerId,
-           PrivConnectionInfo, PubConnectionInfo};
-use mio::{Poll, PollOpt, Ready, Token};
-use mio::tcp::{TcpListener, TcpStream};
-use mio::timer::Timeout;
-use nat;
+use common::{BandwidthLimits, Core, CoreTimer, CrustUser, DropPolicy, Identity, IdentityKeys,
+             NameHash, Priority, Socket, SocketConfig, State, Timeout, TraceState, lock};
+use main::{ActiveConnection, BanList, CompressionPolicy, Config, ConnectionCandidate,
+           ConnectionMap, CrustError, Event, ExpectedIdentities, HandshakeKind, IpWhitelist,
+           Metrics, OfferStamp, Offers, PeerId, PrivConnectionInfo, PubConnectionInfo, Reconnect,
+           Relayed, TraversalOutcome};
+use mio::{Poll, Token};
+use mio::tcp::TcpStream;
+use nat::{StatsRecorder, TcpRendezvousConnect};
 use std::any::Any;
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
 use std::rc::{Rc, Weak};
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 const TIMEOUT_SEC: u64 = 60;
+const FALLBACK_TIMER_ID: u8 = 1;
+const STAGGER_TIMER_ID: u8 = 2;
+const DEFAULT_STAGGER_MS: u64 = 250;
+// How many peers the family they were last reached over is remembered of.
+const MAX_FAMILIES: usize = 1024;
+
+/// How the endpoints of a peer are raced against each other on connecting.
+///
+/// Endpoints are dialed alternating between IPv6 and IPv4 as in RFC 8305, starting with the
+/// family the peer was last reached over, else the preferred one.
+#[derive(Clone, Debug)]
+pub struct RacePolicy {
+    /// How long to wait before dialing each next endpoint while earlier attempts are under way.
+    pub stagger: Duration,
+    /// Whether to dial IPv6 endpoints before IPv4 ones.
+    pub prefer_ipv6: bool,
+    /// How long to wait before dialing the first endpoint of the other family.
+    pub family_stagger: Duration,
+    families: Arc<Mutex<Families>>,
+}
+
+// Per peer, whether it was last reached over IPv6 rather than IPv4, for the `MAX_FAMILIES` peers
+// reached most recently.
+#[derive(Debug, Default)]
+struct Families {
+    by_peer: HashMap<PeerId, bool>,
+    // Least recently reached first.
+    order: VecDeque<PeerId>,
+}
+
+impl RacePolicy {
+    // The endpoints of `their_id` in the order to dial them.
+    fn order(&self, their_id: &PeerId, addrs: Vec<SocketAddr>) -> VecDeque<SocketAddr> {
+        let ipv6_first = lock(&self.families)
+            .by_peer
+            .get(their_id)
+            .cloned()
+            .unwrap_or(self.prefer_ipv6);
+        let (mut first, mut second): (VecDeque<_>, VecDeque<_>) =
+            addrs.into_iter().partition(|addr| addr.is_ipv6() == ipv6_first);
+        let mut ordered = VecDeque::with_capacity(first.len() + second.len());
+        loop {
+            match (first.pop_front(), second.pop_front()) {
+                (None, None) => return ordered,
+                (a, b) => ordered.extend(a.into_iter().chain(b)),
+            }
+        }
+    }
+
+    fn reached(&self, their_id: PeerId, addr: &SocketAddr) {
+        let mut families = lock(&self.families);
+        if families.by_peer.insert(their_id, addr.is_ipv6()).is_some() {
+            families.order.retain(|id| *id != their_id);
+        }
+        families.order.push_back(their_id);
+        if families.order.len() > MAX_FAMILIES {
+            if let Some(oldest) = families.order.pop_front() {
+                let _ = families.by_peer.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// How to race the endpoints of peers we connect to.
+pub fn race_policy(config: &Config) -> RacePolicy {
+    let stagger = config.connect_stagger_ms.unwrap_or(DEFAULT_STAGGER_MS);
+    RacePolicy {
+        stagger: Duration::from_millis(stagger),
+        prefer_ipv6: config.connect_prefer_ipv6.unwrap_or(true),
+        family_stagger: Duration::from_millis(config.connect_family_stagger_ms.unwrap_or(stagger)),
+        families: Arc::new(Mutex::new(Families::default())),
+    }
+}
 
 pub struct Connect {
     token: Token,
     timeout: Timeout,
+    started: Instant,
     cm: ConnectionMap,
     our_nh: NameHash,
     our_id: PeerId,
     their_id: PeerId,
+    identity: IdentityKeys,
+    // The identity the peer must prove, if any in particular.
+    expected_identity: Option<Identity>,
+    expected_identities: ExpectedIdentities,
+    // The stamp of the peer's info, its nonce being sent on each path.
+    their_stamp: OfferStamp,
+    offers: Offers,
+    // What the peer proved over each path, how that path was set up and the endpoint we dialed
+    // for it if we did, by child.
+    their_identities: HashMap<Token, (Identity, TraversalOutcome, Option<SocketAddr>)>,
     self_weak: Weak<RefCell<Connect>>,
-    listener: Option<TcpListener>,
     children: HashSet<Token>,
+    // Endpoints being handshaken with that we dialed ourselves, by child.
+    dialed: HashMap<Token, SocketAddr>,
+    // Endpoints still to dial, in turn.
+    pending: VecDeque<SocketAddr>,
+    race: RacePolicy,
+    stagger_timeout: Option<Timeout>,
+    // Whether an IPv4 and an IPv6 endpoint have been dialed yet.
+    dialed_ipv4: bool,
+    dialed_ipv6: bool,
+    // How many fallback transports each endpoint has been dialed again over, how many fallback
+    // delays have run out, and when those still being handshaken with are dialed over the next.
+    fallen_back: HashMap<SocketAddr, usize>,
+    fallback_step: usize,
+    fallback_timeout: Option<Timeout>,
+    relay: Option<SocketAddr>,
+    relay_child: Option<Token>,
+    migration: Relayed,
+    socket_config: SocketConfig,
+    keep_alive: Duration,
+    inactivity_timeout: Duration,
+    drop_policy: DropPolicy,
+    bandwidth: BandwidthLimits,
+    metrics: Metrics,
+    compression: Option<CompressionPolicy>,
+    reconnect: Option<Reconnect>,
+    ban_list: BanList,
     event_tx: ::CrustEventSender,
 }
 
@@ -53,71 +166,245 @@ impl Connect {
                  their_ci: PubConnectionInfo,
                  cm: ConnectionMap,
                  our_nh: NameHash,
+                 identity: IdentityKeys,
+                 expected_identity: Option<Identity>,
+                 expected_identities: ExpectedIdentities,
+                 offers: Offers,
+                 relay: Option<SocketAddr>,
+                 migrate: bool,
+                 race: RacePolicy,
+                 stats: StatsRecorder,
+                 socket_config: SocketConfig,
+                 keep_alive: Duration,
+                 inactivity_timeout: Duration,
+                 drop_policy: DropPolicy,
+                 bandwidth: BandwidthLimits,
+                 compression: Option<CompressionPolicy>,
+                 reconnect: Option<Reconnect>,
+                 ban_list: BanList,
+                 whitelist: IpWhitelist,
+                 metrics: Metrics,
                  event_tx: ::CrustEventSender)
                  -> ::Res<()> {
         let their_id = their_ci.id;
-        let their_direct = their_ci.for_direct;
-        let their_hole_punch = their_ci.for_hole_punch;
+        let their_stamp = their_ci.stamp;
+        if ban_list.is_banned(&their_id) {
+            let _ = event_tx.send(Event::ConnectFailure(their_id));
+            return Err(CrustError::PeerBanned(their_id));
+        }
+        let allows = |addr: &SocketAddr| whitelist.allows(addr, Some(CrustUser::Node));
+        let their_direct = their_ci
+            .for_direct
+            .into_iter()
+            .filter(|addr| !ban_list.is_blacklisted(addr) && allows(addr))
+            .collect::<Vec<_>>();
+        let their_hole_punch = their_ci
+            .for_hole_punch
+            .into_iter()
+            .filter(|addr| allows(addr))
+            .collect::<Vec<_>>();
+        let relay = relay.and_then(|relay| if ban_list.is_blacklisted(&relay) || !allows(&relay) {
+                                       None
+                                   } else {
+                                       Some(relay)
+                                   });
 
-        if their_direct.is_empty() && their_hole_punch.is_empty() {
+        if their_direct.is_empty() && their_hole_punch.is_empty() && relay.is_none() {
+            metrics.traversal(TraversalOutcome::Failed);
             let _ = event_tx.send(Event::ConnectFailure(their_id));
             return Err(CrustError::InsufficientConnectionInfo);
         }
 
+        // Only the lesser peer dials the other directly later, should we end up relayed
+        let migration = Relayed {
+            their_direct: if migrate && our_ci.id < their_id {
+                their_direct.clone()
+            } else {
+                Vec::new()
+            },
+            socket_config: socket_config,
+            identity: identity.clone(),
+            their_identity: None,
+        };
+
+        let expected_identity = expected_identity.or_else(|| {
+            reconnect
+                .as_ref()
+                .and_then(|reconnect| reconnect.their_identity())
+        });
+        // So that the peer cannot connect to our listener as anyone else either
+        if let Some(identity) = expected_identity {
+            let _ = lock(&expected_identities).insert(their_id, identity);
+        }
+
         let token = core.get_new_token();
+        // Messages sent to the peer meanwhile are held here
+        let reconnect = reconnect.and_then(|mut reconnect| if !reconnect.is_reconnecting() ||
+                                                               reconnect.claim(token) {
+                                               Some(reconnect)
+                                           } else {
+                                               None
+                                           });
 
         let state =
             Rc::new(RefCell::new(Connect {
                                      token: token,
                                      timeout: core.set_timeout(Duration::from_secs(TIMEOUT_SEC),
                                                                CoreTimer::new(token, 0))?,
+                                     started: Instant::now(),
                                      cm: cm,
                                      our_nh: our_nh,
                                      our_id: our_ci.id,
                                      their_id: their_id,
+                                     identity: identity,
+                                     expected_identity: expected_identity,
+                                     expected_identities: expected_identities,
+                                     their_stamp: their_stamp,
+                                     offers: offers,
+                                     their_identities: HashMap::new(),
                                      self_weak: Weak::new(),
-                                     listener: None,
-                                     children: HashSet::with_capacity(their_direct.len() +
-                                                                      their_hole_punch.len()),
+                                     children: HashSet::with_capacity(their_direct.len() + 1),
+                                     dialed: HashMap::with_capacity(their_direct.len() + 1),
+                                     pending: race.order(&their_id, their_direct.clone()),
+                                     race: race,
+                                     stagger_timeout: None,
+                                     dialed_ipv4: false,
+                                     dialed_ipv6: false,
+                                     fallen_back: HashMap::new(),
+                                     fallback_step: 0,
+                                     fallback_timeout: None,
+                                     relay: relay,
+                                     relay_child: None,
+                                     migration: migration,
+                                     socket_config: socket_config,
+                                     keep_alive: keep_alive,
+                                     inactivity_timeout: inactivity_timeout,
+                                     drop_policy: drop_policy,
+                                     bandwidth: bandwidth,
+                                     metrics: metrics,
+                                     compression: compression,
+                                     reconnect: reconnect,
+                                     ban_list: ban_list,
                                      event_tx: event_tx,
                                  }));
 
         state.borrow_mut().self_weak = Rc::downgrade(&state);
+        let _ = core.insert_state(token, state.clone());
+        core.trace(token,
+                   TraceState::Connect,
+                   "started",
+                   &[("peer", &their_id),
+                     ("direct", &their_direct.len()),
+                     ("hole_punch", &their_hole_punch.len()),
+                     ("relay", &relay.is_some())]);
 
-        let mut sockets = their_direct
-            .into_iter()
-            .filter_map(|elt| Socket::connect(&elt).ok())
-            .collect::<Vec<_>>();
+        let transport = core.transport();
+
+        // Hole punching rendezvous over raw TCP, so it is only attempted on transports that can
+        // take part in it
+        let hole_punch_sock = if transport.nat_traversal() {
+            our_ci.hole_punch_socket
+        } else {
+            None
+        };
+        if let Some(hole_punch_sock) = hole_punch_sock {
+            let self_weak = Rc::downgrade(&state);
+            let handler = move |core: &mut Core, poll: &Poll, child, res| if let Some(self_rc) =
+                self_weak.upgrade() {
+                self_rc
+                    .borrow_mut()
+                    .handle_rendezvous_connect(core, poll, child, res);
+            };
 
-        if let Some(hole_punch_sock) = our_ci.hole_punch_socket {
-            if let Ok((listener, nat_sockets)) =
-                nat::get_sockets(&hole_punch_sock, their_hole_punch.len()) {
-                poll.register(&listener,
-                              token,
-                              Ready::readable() | Ready::error() | Ready::hup(),
-                              PollOpt::edge())?;
-                state.borrow_mut().listener = Some(listener);
-                sockets.extend(nat_sockets
-                                   .into_iter()
-                                   .zip(their_hole_punch.into_iter().map(|elt| elt))
-                                   .filter_map(|elt| {
-                                                   TcpStream::connect_stream(elt.0, &elt.1).ok()
-                                               })
-                                   .map(Socket::wrap)
-                                   .collect::<Vec<_>>());
+            if let Ok(child) = TcpRendezvousConnect::start(core,
+                                                           poll,
+                                                           &hole_punch_sock,
+                                                           their_hole_punch,
+                                                           stats,
+                                                           socket_config,
+                                                           Box::new(handler)) {
+                let _ = state.borrow_mut().children.insert(child);
             }
         }
 
-        for socket in sockets {
-            state.borrow_mut().exchange_msg(core, poll, socket);
+        // Peers not reached directly in time are also dialed over the fallback transports
+        match core.fallback_transport(0) {
+            Some((_, delay)) if !their_direct.is_empty() => {
+                let timer = CoreTimer::new(token, FALLBACK_TIMER_ID);
+                state.borrow_mut().fallback_timeout = core.set_timeout(delay, timer).ok();
+            }
+            _ => (),
         }
 
-        let _ = core.insert_state(token, state);
+        state.borrow_mut().dial_next(core, poll);
 
         Ok(())
     }
 
-    fn exchange_msg(&mut self, core: &mut Core, poll: &Poll, socket: Socket) {
+    // Dial the next endpoint of the peer, or race the relay once all are dialed, moving on to
+    // the one after if it cannot be dialed at all. With no stagger, everything is dialed at once.
+    fn dial_next(&mut self, core: &mut Core, poll: &Poll) {
+        if let Some(timeout) = self.stagger_timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
+        let staggered = self.race.stagger > Duration::from_millis(0);
+        loop {
+            let started = if let Some(addr) = self.pending.pop_front() {
+                if addr.is_ipv6() {
+                    self.dialed_ipv6 = true;
+                } else {
+                    self.dialed_ipv4 = true;
+                }
+                match Socket::dial(&*core.transport(), &addr) {
+                    Ok(socket) => self.exchange_msg(core, poll, socket, Some(addr), false),
+                    Err(_) => false,
+                }
+            } else if let Some(relay) = self.relay.take() {
+                trace!("Connecting to {:?} via relay {}", self.their_id, relay);
+                match Socket::dial(&*core.transport(), &relay) {
+                    Ok(socket) => self.exchange_msg(core, poll, socket, None, true),
+                    Err(e) => {
+                        debug!("Could not connect to relay {}: {:?}", relay, e);
+                        false
+                    }
+                }
+            } else {
+                break;
+            };
+            if started && staggered {
+                break;
+            }
+        }
+        if !self.pending.is_empty() || self.relay.is_some() {
+            // The other family gets its own delay before its first endpoint is dialed
+            let delay = match self.pending.front() {
+                Some(addr) if addr.is_ipv6() && !self.dialed_ipv6 && self.dialed_ipv4 => {
+                    self.race.family_stagger
+                }
+                Some(addr) if !addr.is_ipv6() && !self.dialed_ipv4 && self.dialed_ipv6 => {
+                    self.race.family_stagger
+                }
+                _ => self.race.stagger,
+            };
+            let timer = CoreTimer::new(self.token, STAGGER_TIMER_ID);
+            self.stagger_timeout = core.set_timeout(delay, timer).ok();
+        }
+        self.maybe_terminate(core, poll);
+    }
+
+    // `dialed` is the endpoint we dialed for the socket, if we did. Returns whether the handshake
+    // got going.
+    fn exchange_msg(&mut self,
+                    core: &mut Core,
+                    poll: &Poll,
+                    mut socket: Socket,
+                    dialed: Option<SocketAddr>,
+                    relayed: bool)
+                    -> bool {
+        if let Err(e) = socket.configure(&self.socket_config) {
+            debug!("Could not set socket options: {:?}", e);
+        }
+
         let self_weak = self.self_weak.clone();
         let handler = move |core: &mut Core, poll: &Poll, child, res| if let Some(self_rc) =
             self_weak.upgrade() {
@@ -130,22 +417,92 @@ impl Connect {
                                               poll,
                                               socket,
                                               self.our_id,
+                                              &self.identity,
                                               self.their_id,
+                                              self.expected_identity,
+                                              self.their_stamp.nonce,
+                                              self.offers.clone(),
                                               self.our_nh,
                                               self.cm.clone(),
+                                              relayed,
+                                              self.ban_list.clone(),
                                               Box::new(handler)) {
             let _ = self.children.insert(child);
+            if let Some(addr) = dialed {
+                let _ = self.dialed.insert(child, addr);
+            }
+            if relayed {
+                self.relay_child = Some(child);
+            }
+            let via = if relayed {
+                "relay"
+            } else if dialed.is_some() {
+                "direct"
+            } else {
+                "hole_punch"
+            };
+            core.trace(self.token,
+                       TraceState::Connect,
+                       "path_started",
+                       &[("path", &child.0), ("via", &via)]);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn handle_rendezvous_connect(&mut self,
+                                 core: &mut Core,
+                                 poll: &Poll,
+                                 child: Token,
+                                 res: Option<TcpStream>) {
+        // Every stream punched is handshaken, leaving it to `ConnectionCandidate` to pick the
+        // same one as the peer does
+        let stream = match res {
+            Some(stream) => stream,
+            None => {
+                let _ = self.children.remove(&child);
+                return self.maybe_terminate(core, poll);
+            }
+        };
+        match core.transport().adopt(stream) {
+            Ok(stream) => {
+                let _ = self.exchange_msg(core, poll, Socket::from_stream(stream), None, false);
+            }
+            Err(e) => debug!("Could not carry the hole punched stream: {:?}", e),
         }
-        self.maybe_terminate(core, poll);
     }
 
     fn handle_exchange_msg(&mut self,
                            core: &mut Core,
                            poll: &Poll,
                            child: Token,
-                           res: Option<Socket>) {
+                           res: Option<(Socket, Identity)>) {
         let _ = self.children.remove(&child);
-        if let Some(socket) = res {
+        let outcome = if self.dialed.contains_key(&child) {
+            TraversalOutcome::Direct
+        } else if self.relay_child == Some(child) {
+            TraversalOutcome::Relay
+        } else {
+            TraversalOutcome::HolePunch
+        };
+        let dialed = self.dialed.remove(&child);
+        if let Some(addr) = dialed {
+            if res.is_none() {
+                if let Some(socket) = self.dial_fallback(core, &addr) {
+                    if self.exchange_msg(core, poll, socket, Some(addr), false) {
+                        return;
+                    }
+                }
+                // Rather than waiting out the stagger
+                if !self.pending.is_empty() {
+                    return self.dial_next(core, poll);
+                }
+            }
+        }
+        if let Some((socket, their_identity)) = res {
+            let _ = self.their_identities
+                .insert(child, (their_identity, outcome, dialed));
             let self_weak = self.self_weak.clone();
             let handler = move |core: &mut Core, poll: &Poll, child, res| if let Some(self_rc) =
                 self_weak.upgrade() {
@@ -174,8 +531,39 @@ impl Connect {
                                    child: Token,
                                    res: Option<Socket>) {
         let _ = self.children.remove(&child);
-        if let Some(socket) = res {
+        let their_identity = self.their_identities.remove(&child);
+        if let (Some(socket), Some((their_identity, outcome, dialed))) = (res, their_identity) {
+            if let Some(addr) = dialed {
+                self.race.reached(self.their_id, &addr);
+            }
+            self.metrics.traversal(outcome);
+            self.metrics
+                .handshake(HandshakeKind::Connect, self.started.elapsed());
+            lock(&self.offers).use_offer(self.their_id, &self.their_stamp);
+            core.trace(self.token,
+                       TraceState::Connect,
+                       "connected",
+                       &[("path", &child.0), ("via", &outcome.label())]);
+            let mut reconnect = self.reconnect.take();
+            let event = match reconnect {
+                Some(ref mut reconnect) if reconnect.is_reconnecting() => {
+                    reconnect.reset();
+                    Event::PeerReconnected(self.their_id)
+                }
+                Some(ref mut reconnect) => {
+                    reconnect.set_their_identity(their_identity);
+                    Event::ConnectSuccess(self.their_id, their_identity)
+                }
+                None => Event::ConnectSuccess(self.their_id, their_identity),
+            };
             self.terminate(core, poll);
+            let relayed = if self.relay_child == Some(child) {
+                let mut relayed = self.migration.clone();
+                relayed.their_identity = Some(their_identity);
+                Some(relayed)
+            } else {
+                None
+            };
             return ActiveConnection::start(core,
                                            poll,
                                            child,
@@ -183,28 +571,63 @@ impl Connect {
                                            self.cm.clone(),
                                            self.our_id,
                                            self.their_id,
-                                           Event::ConnectSuccess(self.their_id),
+                                           event,
+                                           self.keep_alive,
+                                           self.inactivity_timeout,
+                                           self.drop_policy,
+                                           self.bandwidth.clone(),
+                                           self.metrics.clone(),
+                                           self.compression,
+                                           relayed,
+                                           reconnect,
                                            self.event_tx.clone());
         }
         self.maybe_terminate(core, poll);
     }
 
-    fn maybe_terminate(&mut self, core: &mut Core, poll: &Poll) {
-        if self.children.is_empty() {
-            self.terminate(core, poll);
+    // Dial `addr` over the next fallback transport it has not been dialed over yet, moving on to
+    // the one after should it not be dialed at all.
+    fn dial_fallback(&mut self, core: &Core, addr: &SocketAddr) -> Option<Socket> {
+        loop {
+            let nth = self.fallen_back.get(addr).cloned().unwrap_or(0);
+            let transport = match core.fallback_transport(nth) {
+                Some((transport, _)) => transport,
+                None => return None,
+            };
+            let _ = self.fallen_back.insert(*addr, nth + 1);
+            core.trace(self.token,
+                       TraceState::Connect,
+                       "fell_back",
+                       &[("peer_addr", addr), ("transport", &transport.name())]);
+            match Socket::dial(&*transport, addr) {
+                Ok(socket) => return Some(socket),
+                Err(e) => debug!("Could not dial {} over {}: {:?}", addr, transport.name(), e),
+            }
         }
     }
 
-    fn accept(&mut self, core: &mut Core, poll: &Poll) {
-        loop {
-            match unwrap!(self.listener.as_ref()).accept() {
-                Ok((socket, _)) => self.exchange_msg(core, poll, Socket::wrap(socket)),
-                Err(_) => return,
-            }
+    /// The reconnection this is an attempt at, if it is one.
+    pub fn reconnect_mut(&mut self) -> Option<&mut Reconnect> {
+        match self.reconnect {
+            Some(ref mut reconnect) if reconnect.is_reconnecting() => Some(reconnect),
+            _ => None,
         }
     }
 
+    fn maybe_terminate(&mut self, core: &mut Core, poll: &Poll) {
+        if !self.children.is_empty() {
+            return;
+        }
+        // Nothing is under way, so whatever is left to dial need not wait its turn
+        if !self.pending.is_empty() || self.relay.is_some() {
+            return self.dial_next(core, poll);
+        }
+        self.terminate(core, poll);
+    }
+
     fn terminate_children(&mut self, core: &mut Core, poll: &Poll) {
+        self.dialed.clear();
+        self.their_identities.clear();
         for child in self.children.drain() {
             let child = match core.get_state(child) {
                 Some(state) => state,
@@ -217,13 +640,40 @@ impl Connect {
 }
 
 impl State for Connect {
-    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
-        if !kind.is_error() && !kind.is_hup() && kind.is_readable() {
-            self.accept(core, poll);
-        }
+    fn name(&self) -> &'static str {
+        "Connect"
     }
 
-    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u8) {
+    fn peer_id(&self) -> Option<PeerId> {
+        Some(self.their_id)
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, timer_id: u8) {
+        if timer_id == FALLBACK_TIMER_ID {
+            // Whatever was dialed and is still going gets another chance in parallel, over the
+            // next fallback transport unless failures have already moved it on to that
+            self.fallback_timeout = None;
+            self.fallback_step += 1;
+            let pending: Vec<SocketAddr> = self.dialed.values().cloned().collect();
+            for addr in pending {
+                if self.fallen_back.get(&addr).cloned().unwrap_or(0) >= self.fallback_step {
+                    continue;
+                }
+                if let Some(socket) = self.dial_fallback(core, &addr) {
+                    let _ = self.exchange_msg(core, poll, socket, Some(addr), false);
+                }
+            }
+            if let Some((_, delay)) = core.fallback_transport(self.fallback_step) {
+                let timer = CoreTimer::new(self.token, FALLBACK_TIMER_ID);
+                self.fallback_timeout = core.set_timeout(delay, timer).ok();
+            }
+            return;
+        }
+        if timer_id == STAGGER_TIMER_ID {
+            self.stagger_timeout = None;
+            return self.dial_next(core, poll);
+        }
+
         debug!("Connect to peer {:?} timed out", self.their_id);
         self.terminate(core, poll);
     }
@@ -231,14 +681,44 @@ impl State for Connect {
     fn terminate(&mut self, core: &mut Core, poll: &Poll) {
         self.terminate_children(core, poll);
 
-        if let Some(listener) = self.listener.take() {
-            let _ = poll.deregister(&listener);
-        }
+        self.pending.clear();
+        self.relay = None;
         let _ = core.cancel_timeout(&self.timeout);
+        if let Some(timeout) = self.fallback_timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
+        if let Some(timeout) = self.stagger_timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
         let _ = core.remove_state(self.token);
+        if self.expected_identity.is_some() {
+            let _ = lock(&self.expected_identities).remove(&self.their_id);
+        }
+
+        if !lock(&self.cm).contains_key(&self.their_id) {
+            match self.reconnect.take() {
+                Some(reconnect) => {
+                    if reconnect.is_reconnecting() {
+                        core.trace(self.token, TraceState::Connect, "retrying", &[]);
+                        reconnect.retry(core)
+                    } else {
+                        core.trace(self.token, TraceState::Connect, "failed", &[]);
+                        self.metrics.traversal(TraversalOutcome::Failed);
+                        let _ = self.event_tx.send(Event::ConnectFailure(self.their_id));
+                    }
+                }
+                None => {
+                    core.trace(self.token, TraceState::Connect, "failed", &[]);
+                    self.metrics.traversal(TraversalOutcome::Failed);
+                    let _ = self.event_tx.send(Event::ConnectFailure(self.their_id));
+                }
+            }
+        }
+    }
 
-        if !unwrap!(self.cm.lock()).contains_key(&self.their_id) {
-            let _ = self.event_tx.send(Event::ConnectFailure(self.their_id));
+    fn write(&mut self, _core: &mut Core, _poll: &Poll, data: Vec<u8>, priority: Priority) {
+        if let Some(reconnect) = self.reconnect_mut() {
+            reconnect.hold(data, priority, None);
         }
     }
 
@@ -246,3 +726,63 @@ impl State for Connect {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_sodium::crypto::box_;
+
+    #[test]
+    fn interleaves_families() {
+        let v4_0 = unwrap!("1.2.3.4:5483".parse());
+        let v4_1 = unwrap!("1.2.3.5:5483".parse());
+        let v4_2 = unwrap!("1.2.3.6:5483".parse());
+        let v6_0 = unwrap!("[2001:db8::1]:5483".parse());
+        let v6_1 = unwrap!("[2001:db8::2]:5483".parse());
+        let addrs = vec![v4_0, v4_1, v6_0, v4_2, v6_1];
+        let their_id = PeerId(box_::gen_keypair().0);
+
+        let mut config = Config::default();
+        config.connect_stagger_ms = Some(100);
+        let race = race_policy(&config);
+        assert_eq!(race.family_stagger, Duration::from_millis(100));
+        assert_eq!(race.order(&their_id, addrs.clone()),
+                   vec![v6_0, v4_0, v6_1, v4_1, v4_2]);
+
+        config.connect_prefer_ipv6 = Some(false);
+        let race = race_policy(&config);
+        assert_eq!(race.order(&their_id, addrs.clone()),
+                   vec![v4_0, v6_0, v4_1, v6_1, v4_2]);
+
+        // Reconnects start with whichever family the peer was last reached over
+        race.reached(their_id, &v6_1);
+        assert_eq!(race.order(&their_id, addrs.clone()),
+                   vec![v6_0, v4_0, v6_1, v4_1, v4_2]);
+        assert_eq!(race.clone().order(&their_id, vec![v4_0]), vec![v4_0]);
+    }
+
+    #[test]
+    fn forgets_families() {
+        let v4 = unwrap!("1.2.3.4:5483".parse());
+        let v6 = unwrap!("[2001:db8::1]:5483".parse());
+        let peer = |i: usize| {
+            let mut key = [0; box_::PUBLICKEYBYTES];
+            key[0] = i as u8;
+            key[1] = (i >> 8) as u8;
+            PeerId(box_::PublicKey(key))
+        };
+        let race = race_policy(&Config::default());
+
+        race.reached(peer(0), &v4);
+        race.reached(peer(1), &v4);
+        for i in 2..MAX_FAMILIES {
+            race.reached(peer(i), &v6);
+        }
+        // Reaching a peer again keeps it from being the next one forgotten
+        race.reached(peer(0), &v4);
+        race.reached(peer(MAX_FAMILIES), &v6);
+        assert_eq!(lock(&race.families).by_peer.len(), MAX_FAMILIES);
+        assert_eq!(race.order(&peer(0), vec![v6, v4]), vec![v4, v6]);
+        assert_eq!(race.order(&peer(1), vec![v4, v6]), vec![v6, v4]);
+    }
+}
@@ -15,7 +15,7 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-use common::{Core, Message, Priority, Socket, State};
+use common::{Core, Message, Priority, Socket, State, lock};
 use main::{ConnectionId, ConnectionMap, PeerId};
 use mio::{Poll, PollOpt, Ready, Token};
 use std::any::Any;
@@ -78,7 +78,7 @@ impl ConnectionCandidate {
     }
 
     fn write(&mut self, core: &mut Core, poll: &Poll, msg: Option<(Message, Priority)>) {
-        let terminate = match unwrap!(self.cm.lock()).get(&self.their_id) {
+        let terminate = match lock(&self.cm).get(&self.their_id) {
             Some(&ConnectionId { active_connection: Some(_), .. }) => true,
             _ => false,
         };
@@ -119,6 +119,14 @@ impl ConnectionCandidate {
 }
 
 impl State for ConnectionCandidate {
+    fn name(&self) -> &'static str {
+        "ConnectionCandidate"
+    }
+
+    fn peer_id(&self) -> Option<PeerId> {
+        Some(self.their_id)
+    }
+
     fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
         if kind.is_error() || kind.is_hup() {
             return self.handle_error(core, poll);
@@ -136,7 +144,7 @@ impl State for ConnectionCandidate {
         let _ = core.remove_state(self.token);
         let _ = poll.deregister(&self.socket);
 
-        let mut guard = unwrap!(self.cm.lock());
+        let mut guard = lock(&self.cm);
         if let Entry::Occupied(mut oe) = guard.entry(self.their_id) {
             oe.get_mut().currently_handshaking -= 1;
             if oe.get().currently_handshaking == 0 && oe.get().active_connection.is_none() {
@@ -152,3 +160,89 @@ impl State for ConnectionCandidate {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{CoreMessage, spawn_event_loop};
+    use main::ConnectionId;
+    use mio::tcp::TcpStream;
+    use rust_sodium::crypto::box_::PublicKey;
+    use std::collections::HashMap;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::{Arc, Mutex, mpsc};
+    use std::time::Duration;
+    use tests::timebomb;
+
+    fn start(core: &mut Core,
+             poll: &Poll,
+             stream: ::std::net::TcpStream,
+             cm: &ConnectionMap,
+             our_id: PeerId,
+             their_id: PeerId,
+             tx: mpsc::Sender<(PeerId, SocketAddr, SocketAddr)>) {
+        let token = core.get_new_token();
+        let socket = Socket::wrap(unwrap!(TcpStream::from_stream(stream)));
+        unwrap!(poll.register(&socket, token, Ready::readable(), PollOpt::edge()));
+        let cm_0 = cm.clone();
+        let finish = move |_: &mut Core, _: &Poll, token, res: Option<Socket>| {
+            if let Some(socket) = res {
+                unwrap!(lock(&cm_0).get_mut(&their_id)).active_connection = Some(token);
+                let addrs = (unwrap!(socket.local_addr()), unwrap!(socket.peer_addr()));
+                let _ = tx.send((our_id, addrs.0, addrs.1));
+            }
+        };
+        let _ = unwrap!(ConnectionCandidate::start(core,
+                                                   poll,
+                                                   token,
+                                                   socket,
+                                                   cm.clone(),
+                                                   our_id,
+                                                   their_id,
+                                                   Box::new(finish)));
+    }
+
+    // Peers that got their streams in different orders still end up on the same one.
+    #[test]
+    fn peers_choose_the_same_stream() {
+        timebomb(Duration::from_secs(10), || {
+            let el = unwrap!(spawn_event_loop(0, None));
+            let higher = PeerId(PublicKey([2; 32]));
+            let lower = PeerId(PublicKey([1; 32]));
+            let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+            let addr = unwrap!(listener.local_addr());
+            let mut pairs = Vec::new();
+            for _ in 0..2 {
+                let dialed = unwrap!(::std::net::TcpStream::connect(addr));
+                pairs.push((dialed, unwrap!(listener.accept()).0));
+            }
+            let (tx, rx) = mpsc::channel();
+
+            unwrap!(el.send(CoreMessage::new(move |core, poll| {
+                let cm = |their_id| {
+                    let mut cm = HashMap::new();
+                    let _ = cm.insert(their_id,
+                                      ConnectionId {
+                                          active_connection: None,
+                                          currently_handshaking: 2,
+                                      });
+                    Arc::new(Mutex::new(cm))
+                };
+                let (higher_cm, lower_cm) = (cm(lower), cm(higher));
+                let (first, second) = (pairs.remove(0), pairs.remove(0));
+                start(core, poll, first.0, &higher_cm, higher, lower, tx.clone());
+                start(core, poll, second.0, &higher_cm, higher, lower, tx.clone());
+                start(core, poll, second.1, &lower_cm, lower, higher, tx.clone());
+                start(core, poll, first.1, &lower_cm, lower, higher, tx);
+            })));
+
+            let mut chosen = HashMap::new();
+            for _ in 0..2 {
+                let (id, local, peer) = unwrap!(rx.recv());
+                assert!(chosen.insert(id, (local, peer)).is_none());
+            }
+            let (higher_local, higher_peer) = chosen[&higher];
+            assert_eq!(chosen[&lower], (higher_peer, higher_local));
+        })
+    }
+}
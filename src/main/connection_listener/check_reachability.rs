@@ -15,9 +15,8 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-use common::{Core, CoreTimer, Socket, State};
+use common::{Core, CoreTimer, Socket, State, Timeout};
 use mio::{Poll, PollOpt, Ready, Token};
-use mio::timer::Timeout;
 use std::any::Any;
 use std::cell::RefCell;
 use std::net::SocketAddr;
@@ -45,7 +44,7 @@ impl<T> CheckReachability<T>
                  t: T,
                  finish: Finish<T>)
                  -> ::Res<Token> {
-        let socket = Socket::connect(&their_listener)?;
+        let socket = Socket::dial(&*core.transport(), &their_listener)?;
         let token = core.get_new_token();
 
         poll.register(&socket,
@@ -86,6 +85,10 @@ impl<T> CheckReachability<T>
 impl<T> State for CheckReachability<T>
     where T: 'static + Clone
 {
+    fn name(&self) -> &'static str {
+        "CheckReachability"
+    }
+
     fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
         if kind.is_error() || kind.is_hup() || !kind.is_writable() {
             self.handle_error(core, poll);
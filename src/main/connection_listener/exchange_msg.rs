@@ -16,22 +16,60 @@
 // relating to use of the SAFE Network Software.
 
 use super::check_reachability::CheckReachability;
-use common::{BootstrapDenyReason, Core, CoreTimer, CrustUser, ExternalReachability, Message,
-             NameHash, Priority, Socket, State};
-use main::{ActiveConnection, ConnectionCandidate, ConnectionId, ConnectionMap, Event, PeerId};
+use super::relay::{Relay, RelayMap};
+use common::{BandwidthLimits, BootstrapDenyReason, Challenge, Core, CoreMessage, CoreTimer,
+             CrustUser, DropPolicy, ExternalReachability, Identity, IdentityKeys, IdentityProof,
+             KeyProof, Message, NameHash, PROTOCOL_VERSION, Priority, Shards, Socket, State,
+             Timeout, TraceState, is_compatible_version, lock, shard_of};
+use main::{ActiveConnection, CompressionPolicy, ConnectionCandidate, ConnectionId, ConnectionMap,
+           Event, ExpectedIdentities, HandshakeKind, IpWhitelist, Metrics, Offers, PeerId};
 use mio::{Poll, PollOpt, Ready, Token};
-use mio::timer::Timeout;
-use nat::ip_addr_is_global;
+use nat::{self, ip_addr_is_global};
 use rust_sodium::crypto::box_::PublicKey;
 use std::any::Any;
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry;
 use std::mem;
+use std::net::{IpAddr, SocketAddr};
 use std::rc::{Rc, Weak};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-pub const EXCHANGE_MSG_TIMEOUT_SEC: u64 = 10 * 60;
+pub const EXCHANGE_MSG_TIMEOUT_SEC: u64 = 20;
+
+/// The connections in the middle of the handshake, in all and from each IP.
+#[derive(Default)]
+pub struct PendingHandshakes {
+    pub total: usize,
+    pub by_ip: HashMap<IpAddr, usize>,
+}
+
+/// Counts a connection from `IpAddr` as in the middle of the handshake for as long as it is held.
+pub struct PendingHandshake(Rc<RefCell<PendingHandshakes>>, IpAddr);
+
+impl PendingHandshake {
+    pub fn new(pending: &Rc<RefCell<PendingHandshakes>>, ip: IpAddr) -> Self {
+        {
+            let mut pending = pending.borrow_mut();
+            pending.total += 1;
+            *pending.by_ip.entry(ip).or_insert(0) += 1;
+        }
+        PendingHandshake(pending.clone(), ip)
+    }
+}
+
+impl Drop for PendingHandshake {
+    fn drop(&mut self) {
+        let mut pending = self.0.borrow_mut();
+        pending.total -= 1;
+        if let Entry::Occupied(mut oe) = pending.by_ip.entry(self.1) {
+            *oe.get_mut() -= 1;
+            if *oe.get() == 0 {
+                let _ = oe.remove();
+            }
+        }
+    }
+}
 
 pub struct ExchangeMsg {
     token: Token,
@@ -40,9 +78,34 @@ pub struct ExchangeMsg {
     name_hash: NameHash,
     next_state: NextState,
     our_pk: PublicKey,
+    identity: IdentityKeys,
+    expected_identities: ExpectedIdentities,
+    offers: Offers,
+    // The nonce of our info the peer connects with, to be its once it proves its identity.
+    our_nonce: Option<u64>,
+    whitelist: IpWhitelist,
+    // What each of us has to sign over, along with the other's, to prove its identity.
+    challenge: Challenge,
+    their_challenge: Option<Challenge>,
+    // What the peer proved, which it does only once it has our challenge.
+    their_identity: Option<Identity>,
     socket: Socket,
+    _pending: PendingHandshake,
+    started: Instant,
     timeout: Timeout,
+    keep_alive: Duration,
+    inactivity_timeout: Duration,
+    drop_policy: DropPolicy,
+    bandwidth: BandwidthLimits,
+    metrics: Metrics,
+    compression: Option<CompressionPolicy>,
     reachability_children: HashSet<Token>,
+    // The bootstrapper to take on through us should none of its listeners be reached.
+    relay_fallback: Option<PeerId>,
+    relays: Option<RelayMap>,
+    // The ends a peer asked us to relay between, and what it is to answer to prove it is the
+    // first of them.
+    relay_req: Option<(PublicKey, PublicKey, Challenge)>,
     self_weak: Weak<RefCell<ExchangeMsg>>,
 }
 
@@ -50,16 +113,42 @@ impl ExchangeMsg {
     pub fn start(core: &mut Core,
                  poll: &Poll,
                  timeout_sec: Option<u64>,
+                 keep_alive: Duration,
+                 inactivity_timeout: Duration,
+                 drop_policy: DropPolicy,
+                 bandwidth: BandwidthLimits,
+                 metrics: Metrics,
+                 compression: Option<CompressionPolicy>,
                  socket: Socket,
+                 pending: PendingHandshake,
                  our_pk: PublicKey,
+                 identity: IdentityKeys,
+                 expected_identities: ExpectedIdentities,
+                 offers: Offers,
+                 whitelist: IpWhitelist,
                  name_hash: NameHash,
                  cm: ConnectionMap,
+                 relays: Option<RelayMap>,
                  event_tx: ::CrustEventSender)
                  -> ::Res<()> {
         let token = core.get_new_token();
 
         let kind = Ready::error() | Ready::hup() | Ready::readable();
         poll.register(&socket, token, kind, PollOpt::edge())?;
+        match socket.peer_addr() {
+            Ok(peer_addr) => {
+                core.trace(token,
+                           TraceState::Handshake,
+                           "started",
+                           &[("direction", &"inbound"), ("peer_addr", &peer_addr)])
+            }
+            Err(_) => {
+                core.trace(token,
+                           TraceState::Handshake,
+                           "started",
+                           &[("direction", &"inbound")])
+            }
+        }
 
         let timeout =
             core.set_timeout(Duration::from_secs(timeout_sec.unwrap_or(EXCHANGE_MSG_TIMEOUT_SEC)),
@@ -72,9 +161,28 @@ impl ExchangeMsg {
                                              name_hash: name_hash,
                                              next_state: NextState::None,
                                              our_pk: our_pk,
+                                             identity: identity,
+                                             expected_identities: expected_identities,
+                                             offers: offers,
+                                             our_nonce: None,
+                                             whitelist: whitelist,
+                                             challenge: Challenge::new(),
+                                             their_challenge: None,
+                                             their_identity: None,
                                              socket: socket,
+                                             _pending: pending,
+                                             started: Instant::now(),
                                              timeout: timeout,
+                                             keep_alive: keep_alive,
+                                             inactivity_timeout: inactivity_timeout,
+                                             drop_policy: drop_policy,
+                                             bandwidth: bandwidth,
+                                             metrics: metrics,
+                                             compression: compression,
                                              reachability_children: HashSet::with_capacity(4),
+                                             relay_fallback: None,
+                                             relays: relays,
+                                             relay_req: None,
                                              self_weak: Default::default(),
                                          }));
 
@@ -87,21 +195,61 @@ impl ExchangeMsg {
 
     fn read(&mut self, core: &mut Core, poll: &Poll) {
         match self.socket.read::<Message>() {
-            Ok(Some(Message::BootstrapRequest(their_public_key, name_hash, ext_reachability))) => {
+            Ok(Some(Message::IdentifiedBootstrapRequest(their_public_key,
+                                                        name_hash,
+                                                        ext_reachability,
+                                                        their_challenge,
+                                                        version))) => {
+                if !is_compatible_version(version) {
+                    debug!("Rejecting bootstrapper speaking protocol version {}", version);
+                    let reason = BootstrapDenyReason::IncompatibleVersion;
+                    return self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
+                }
                 match self.get_peer_id(their_public_key) {
                     Ok(their_id) => {
+                        self.their_challenge = Some(their_challenge);
                         self.handle_bootstrap_req(core, poll, their_id, name_hash, ext_reachability)
                     }
                     Err(()) => self.terminate(core, poll),
                 }
             }
-            Ok(Some(Message::Connect(their_public_key, name_hash))) => {
+            Ok(Some(Message::IdentifiedConnect(their_public_key,
+                                               name_hash,
+                                               challenge,
+                                               nonce,
+                                               version))) => {
+                if !is_compatible_version(version) {
+                    debug!("Rejecting peer speaking protocol version {}", version);
+                    return self.terminate(core, poll);
+                }
                 match self.get_peer_id(their_public_key) {
-                    Ok(their_id) => self.handle_connect(core, poll, their_id, name_hash),
+                    Ok(their_id) => {
+                        self.their_challenge = Some(challenge);
+                        self.handle_connect(core, poll, their_id, name_hash, nonce)
+                    }
                     Err(()) => self.terminate(core, poll),
                 }
             }
+            Ok(Some(Message::BootstrapRequest(..))) |
+            Ok(Some(Message::Connect(..))) => {
+                debug!("Peer speaks a protocol from before identities");
+                self.terminate(core, poll)
+            }
+            Ok(Some(Message::Identify(proof))) => self.handle_identify(core, poll, proof),
             Ok(Some(Message::EchoAddrReq)) => self.handle_echo_addr_req(core, poll),
+            Ok(Some(Message::ReachabilityReq(their_ext_addr))) => {
+                self.handle_reachability_req(core, poll, their_ext_addr)
+            }
+            Ok(Some(Message::RelayConnect(from, to, name_hash))) => {
+                self.handle_relay_connect(core, poll, from, to, name_hash)
+            }
+            Ok(Some(Message::RelayProof(proof))) => self.handle_relay_proof(core, poll, proof),
+            Ok(Some(Message::MigrateProven(their_public_key, proof))) => {
+                match self.get_peer_id(their_public_key) {
+                    Ok(their_id) => self.handle_migrate(core, poll, their_id, proof),
+                    Err(()) => self.terminate(core, poll),
+                }
+            }
             Ok(Some(message)) => {
                 trace!("Unexpected message in direct connect: {:?}", message);
                 self.terminate(core, poll)
@@ -126,39 +274,45 @@ impl ExchangeMsg {
                        poll,
                        Some((Message::BootstrapDenied(BootstrapDenyReason::InvalidNameHash), 0)));
         }
-        match ext_reachability {
-            ExternalReachability::Required { direct_listeners } => {
-                for their_listener in direct_listeners
-                        .into_iter()
-                        .filter(|addr| ip_addr_is_global(&addr.ip())) {
-                    let self_weak = self.self_weak.clone();
-                    let finish = move |core: &mut Core, poll: &Poll, child, res| {
-                        if let Some(self_rc) = self_weak.upgrade() {
-                            self_rc
-                                .borrow_mut()
-                                .handle_check_reachability(core, poll, child, res)
-                        }
-                    };
-
-                    if let Ok(child) = CheckReachability::<PeerId>::start(core,
-                                                                          poll,
-                                                                          their_listener,
-                                                                          their_id,
-                                                                          Box::new(finish)) {
-                        let _ = self.reachability_children.insert(child);
-                    }
-                }
-                if self.reachability_children.is_empty() {
-                    trace!("Bootstrapper failed to pass requisite condition of external \
-                            recheability. Denying bootstrap.");
-                    let reason = BootstrapDenyReason::FailedExternalReachability;
-                    self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
+        let direct_listeners = match ext_reachability {
+            ExternalReachability::NotRequired => {
+                return self.send_bootstrap_resp(core, poll, their_id, CrustUser::Client);
+            }
+            ExternalReachability::Required { direct_listeners } => direct_listeners,
+            ExternalReachability::Relayed { direct_listeners } => {
+                if self.relays.is_none() {
+                    trace!("Rejecting bootstrapper to be relayed as we are not a relay.");
+                    let reason = BootstrapDenyReason::NotRelaying;
+                    return self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
                 }
+                self.relay_fallback = Some(their_id);
+                direct_listeners
             }
-            ExternalReachability::NotRequired => {
-                self.send_bootstrap_resp(core, poll, their_id, CrustUser::Client)
+        };
+
+        for their_listener in direct_listeners
+                .into_iter()
+                .filter(|addr| ip_addr_is_global(&addr.ip())) {
+            let self_weak = self.self_weak.clone();
+            let finish = move |core: &mut Core, poll: &Poll, child, res| {
+                if let Some(self_rc) = self_weak.upgrade() {
+                    self_rc
+                        .borrow_mut()
+                        .handle_check_reachability(core, poll, child, res)
+                }
+            };
+
+            if let Ok(child) = CheckReachability::<PeerId>::start(core,
+                                                                  poll,
+                                                                  their_listener,
+                                                                  their_id,
+                                                                  Box::new(finish)) {
+                let _ = self.reachability_children.insert(child);
             }
         }
+        if self.reachability_children.is_empty() {
+            self.handle_unreachable(core, poll);
+        }
     }
 
     fn handle_check_reachability(&mut self,
@@ -172,53 +326,325 @@ impl ExchangeMsg {
             return self.send_bootstrap_resp(core, poll, their_id, CrustUser::Node);
         }
         if self.reachability_children.is_empty() {
-            trace!("Bootstrapper failed to pass requisite condition of external recheability. \
-                    Denying bootstrap.");
-            let reason = BootstrapDenyReason::FailedExternalReachability;
-            self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
+            self.handle_unreachable(core, poll);
         }
     }
 
+    // None of the bootstrapper's listeners could be reached, so it is taken on through us if it
+    // asked to be and denied otherwise.
+    fn handle_unreachable(&mut self, core: &mut Core, poll: &Poll) {
+        if let Some(their_id) = self.relay_fallback.take() {
+            trace!("Bootstrapper could not be reached, so is to be reached through us.");
+            return self.send_bootstrap_resp(core, poll, their_id, CrustUser::Node);
+        }
+        trace!("Bootstrapper failed to pass requisite condition of external recheability. \
+                Denying bootstrap.");
+        let reason = BootstrapDenyReason::FailedExternalReachability;
+        self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
+    }
+
     fn send_bootstrap_resp(&mut self,
                            core: &mut Core,
                            poll: &Poll,
                            their_id: PeerId,
                            peer_kind: CrustUser) {
+        if !self.is_whitelisted(peer_kind) {
+            return self.terminate(core, poll);
+        }
         self.enter_handshaking_mode(their_id);
 
         let our_pk = self.our_pk;
+        let challenge = self.challenge;
+        let proof = self.prove(their_id);
         self.next_state = NextState::ActiveConnection(their_id, peer_kind);
-        self.write(core, poll, Some((Message::BootstrapGranted(our_pk), 0)))
+        self.write(core,
+                   poll,
+                   Some((Message::IdentifiedBootstrapGranted(our_pk,
+                                                             challenge,
+                                                             proof,
+                                                             PROTOCOL_VERSION),
+                         0)))
     }
 
     fn handle_connect(&mut self,
                       core: &mut Core,
                       poll: &Poll,
                       their_id: PeerId,
-                      name_hash: NameHash) {
-        if !self.is_valid_name_hash(name_hash) {
+                      name_hash: NameHash,
+                      nonce: Option<u64>) {
+        if !self.is_valid_name_hash(name_hash) || !self.is_whitelisted(CrustUser::Node) {
             return self.terminate(core, poll);
         }
+        // Only peers connecting with info we handed out, and which nobody else connected with
+        match nonce {
+            Some(nonce) if lock(&self.offers).admits(&their_id, nonce) => {
+                self.our_nonce = Some(nonce);
+            }
+            _ => {
+                debug!("Peer {:?} connects with info we did not hand it", their_id);
+                return self.terminate(core, poll);
+            }
+        }
 
         self.enter_handshaking_mode(their_id);
 
         let our_pk = self.our_pk;
         let name_hash = self.name_hash;
+        let challenge = self.challenge;
+        let proof = self.prove(their_id);
         self.next_state = NextState::ConnectionCandidate(their_id);
-        self.write(core, poll, Some((Message::Connect(our_pk, name_hash), 0)));
+        self.write(core,
+                   poll,
+                   Some((Message::IdentifiedConnect(our_pk,
+                                                    name_hash,
+                                                    challenge,
+                                                    None,
+                                                    PROTOCOL_VERSION),
+                         0)));
+        self.write(core, poll, Some((Message::Identify(proof), 0)));
+    }
+
+    // Our answer to the challenge the peer sent us.
+    fn prove(&self, their_id: PeerId) -> IdentityProof {
+        let their_challenge = unwrap!(self.their_challenge);
+        self.identity
+            .prove(&self.our_pk, &their_id.0, &self.challenge, &their_challenge)
+    }
+
+    // The peer's answer to our challenge, which it can only send once it has ours.
+    fn handle_identify(&mut self, core: &mut Core, poll: &Poll, proof: IdentityProof) {
+        let their_id = match self.next_state {
+            NextState::ActiveConnection(their_id, _) |
+            NextState::ConnectionCandidate(their_id) if self.their_identity.is_none() => their_id,
+            _ => return self.terminate(core, poll),
+        };
+        let their_challenge = unwrap!(self.their_challenge);
+        let their_identity =
+            match proof.verify(&their_id.0, &self.our_pk, &their_challenge, &self.challenge) {
+                Some(identity) => identity,
+                None => {
+                    debug!("Peer {:?} failed to prove its identity", their_id);
+                    return self.terminate(core, poll);
+                }
+            };
+        if let NextState::ConnectionCandidate(..) = self.next_state {
+            if lock(&self.expected_identities)
+                   .get(&their_id)
+                   .map_or(false, |expected| *expected != their_identity) {
+                debug!("Peer {:?} is {:?} rather than who we are connecting to",
+                       their_id,
+                       their_identity);
+                return self.terminate(core, poll);
+            }
+            if let Some(nonce) = self.our_nonce {
+                lock(&self.offers).redeem(their_id, nonce);
+            }
+        }
+        self.their_identity = Some(their_identity);
+        self.done(core, poll);
     }
 
     fn handle_echo_addr_req(&mut self, core: &mut Core, poll: &Poll) {
         self.next_state = NextState::None;
         if let Ok(peer_addr) = self.socket.peer_addr() {
+            let peer_addr = nat::unmap_ipv4(&peer_addr);
             self.write(core, poll, Some((Message::EchoAddrResp(peer_addr), 0)));
         } else {
             self.terminate(core, poll);
         }
     }
 
+    fn handle_reachability_req(&mut self,
+                               core: &mut Core,
+                               poll: &Poll,
+                               their_ext_addr: SocketAddr) {
+        self.next_state = NextState::None;
+        // Only connect back to the asker itself, lest we be used to probe arbitrary hosts.
+        let peer_ip = match self.socket.peer_addr() {
+            Ok(peer_addr) => nat::unmap_ipv4(&peer_addr).ip(),
+            Err(_) => return self.terminate(core, poll),
+        };
+        let their_ext_addr = nat::unmap_ipv4(&their_ext_addr);
+        if their_ext_addr.ip() != peer_ip {
+            trace!("Refusing to check reachability of {} on behalf of {}",
+                   their_ext_addr,
+                   peer_ip);
+            return self.terminate(core, poll);
+        }
+
+        let self_weak = self.self_weak.clone();
+        let finish = move |core: &mut Core, poll: &Poll, child, res: Result<(), ()>| {
+            if let Some(self_rc) = self_weak.upgrade() {
+                self_rc
+                    .borrow_mut()
+                    .handle_connect_back(core, poll, child, res.is_ok())
+            }
+        };
+
+        match CheckReachability::<()>::start(core, poll, their_ext_addr, (), Box::new(finish)) {
+            Ok(child) => {
+                let _ = self.reachability_children.insert(child);
+            }
+            Err(e) => {
+                debug!("Could not connect back to {}: {:?}", their_ext_addr, e);
+                self.write(core, poll, Some((Message::ReachabilityResp(false), 0)));
+            }
+        }
+    }
+
+    fn handle_connect_back(&mut self,
+                           core: &mut Core,
+                           poll: &Poll,
+                           child: Token,
+                           reachable: bool) {
+        let _ = self.reachability_children.remove(&child);
+        self.write(core, poll, Some((Message::ReachabilityResp(reachable), 0)));
+    }
+
+    fn handle_relay_connect(&mut self,
+                            core: &mut Core,
+                            poll: &Poll,
+                            from: PublicKey,
+                            to: PublicKey,
+                            name_hash: NameHash) {
+        let relays = match self.relays.clone() {
+            Some(relays) => relays,
+            None => {
+                trace!("Rejecting relay request as we are not a relay.");
+                return self.terminate(core, poll);
+            }
+        };
+        if !self.is_valid_name_hash(name_hash) || from == to || from == self.our_pk ||
+           to == self.our_pk || self.relay_req.is_some() {
+            return self.terminate(core, poll);
+        }
+        if !self.relay_admits(&relays, &from, &to) {
+            debug!("Refusing to relay from {:?} to {:?}", PeerId(from), PeerId(to));
+            return self.terminate(core, poll);
+        }
+
+        // Anyone could ask as `from`, so it has to prove it is
+        let challenge = Challenge::new();
+        self.relay_req = Some((from, to, challenge));
+        let our_pk = self.our_pk;
+        self.write(core, poll, Some((Message::RelayChallenge(our_pk, challenge), 0)));
+    }
+
+    fn handle_relay_proof(&mut self, core: &mut Core, poll: &Poll, proof: KeyProof) {
+        let (relays, (from, to, challenge)) = match (self.relays.clone(), self.relay_req.take()) {
+            (Some(relays), Some(relay_req)) => (relays, relay_req),
+            _ => return self.terminate(core, poll),
+        };
+        let proven = core.secret_key()
+            .map_or(false, |our_sk| proof.verify(&from, our_sk, &challenge, &to.0));
+        if !proven {
+            debug!("Peer failed to prove it is {:?} in asking to be relayed",
+                   PeerId(from));
+            return self.terminate(core, poll);
+        }
+        // Another end may have come along meanwhile
+        if !self.relay_admits(&relays, &from, &to) {
+            return self.terminate(core, poll);
+        }
+        let ip = match self.socket.peer_addr() {
+            Ok(addr) => addr.ip(),
+            Err(_) => return self.terminate(core, poll),
+        };
+
+        let _ = core.remove_state(self.token);
+        let _ = core.cancel_timeout(&self.timeout);
+        core.trace(self.token,
+                   TraceState::Handshake,
+                   "relaying",
+                   &[("from", &PeerId(from)), ("to", &PeerId(to))]);
+        let socket = mem::replace(&mut self.socket, Socket::default());
+        if let Err(e) = Relay::start(core,
+                                     poll,
+                                     self.token,
+                                     socket,
+                                     from,
+                                     to,
+                                     ip,
+                                     relays,
+                                     &self.bandwidth) {
+            debug!("Could not start relaying: {:?}", e);
+        }
+    }
+
+    fn relay_admits(&self, relays: &RelayMap, from: &PublicKey, to: &PublicKey) -> bool {
+        self.socket
+            .peer_addr()
+            .map(|addr| relays.borrow().admits(from, to, addr.ip()))
+            .unwrap_or(false)
+    }
+
+    // Hands the socket over to the relayed connection to the peer, which checks the proof.
+    fn handle_migrate(&mut self,
+                      core: &mut Core,
+                      poll: &Poll,
+                      their_id: PeerId,
+                      proof: IdentityProof) {
+        let token = match lock(&self.cm).get(&their_id) {
+            Some(&ConnectionId { active_connection: Some(token), .. }) => Some(token),
+            _ => None,
+        };
+        // Relayed connections stay on the first event loop, which need not be the one that
+        // accepted the socket, see `Config::reuse_port_listeners`.
+        let elsewhere = match (token, core.shards()) {
+            (Some(token), Some(shards)) if shard_of(token) != shards.index() => {
+                Some((token, shards.clone()))
+            }
+            _ => None,
+        };
+        if let Some((token, shards)) = elsewhere {
+            return self.hand_over_migration(core, poll, token, shards, proof);
+        }
+        let state = match token.and_then(|token| core.get_state(token)) {
+            Some(state) => state,
+            None => return self.terminate(core, poll),
+        };
+
+        self.terminate(core, poll);
+        let socket = mem::replace(&mut self.socket, Socket::default());
+        let mut state = state.borrow_mut();
+        if let Some(conn) = state.as_any().downcast_mut::<ActiveConnection>() {
+            conn.accept_migration(core, poll, socket, proof);
+        }
+    }
+
+    // Hands the socket over to the connection with `token` on the event loop it runs on.
+    fn hand_over_migration(&mut self,
+                           core: &mut Core,
+                           poll: &Poll,
+                           token: Token,
+                           shards: Shards,
+                           proof: IdentityProof) {
+        let socket = mem::replace(&mut self.socket, Socket::default());
+        self.terminate(core, poll);
+        let detached = match socket.detach(poll) {
+            Ok(detached) => detached,
+            Err(_) => {
+                debug!("Could not hand migrating connection over to its event loop");
+                return;
+            }
+        };
+        let msg = CoreMessage::new(move |core, poll| {
+            let state = match core.get_state(token) {
+                Some(state) => state,
+                None => return,
+            };
+            let mut state = state.borrow_mut();
+            if let Some(conn) = state.as_any().downcast_mut::<ActiveConnection>() {
+                conn.accept_migration(core, poll, Socket::attach(detached), proof);
+            }
+        });
+        if let Err(e) = shards.send(shard_of(token), msg) {
+            debug!("Could not hand migrating connection over to its event loop: {:?}", e);
+        }
+    }
+
     fn enter_handshaking_mode(&self, their_id: PeerId) {
-        let mut guard = unwrap!(self.cm.lock());
+        let mut guard = lock(&self.cm);
         guard
             .entry(their_id)
             .or_insert(ConnectionId {
@@ -231,6 +657,13 @@ impl ExchangeMsg {
                guard.get(&their_id));
     }
 
+    fn is_whitelisted(&self, peer_kind: CrustUser) -> bool {
+        match self.socket.peer_addr() {
+            Ok(addr) => self.whitelist.admits(&addr, Some(peer_kind)),
+            Err(_) => false,
+        }
+    }
+
     fn is_valid_name_hash(&self, name_hash: NameHash) -> bool {
         self.name_hash == name_hash
     }
@@ -249,7 +682,7 @@ impl ExchangeMsg {
     fn write(&mut self, core: &mut Core, poll: &Poll, msg: Option<(Message, Priority)>) {
         // Do not accept multiple bootstraps from same peer
         if let NextState::ActiveConnection(their_id, _) = self.next_state {
-            let terminate = match unwrap!(self.cm.lock()).get(&their_id).cloned() {
+            let terminate = match lock(&self.cm).get(&their_id).cloned() {
                 Some(ConnectionId { active_connection: Some(_), .. }) => true,
                 _ => false,
             };
@@ -269,14 +702,33 @@ impl ExchangeMsg {
     }
 
     fn done(&mut self, core: &mut Core, poll: &Poll) {
+        // The peer has yet to prove its identity
+        match self.next_state {
+            NextState::ActiveConnection(..) |
+            NextState::ConnectionCandidate(..) if self.their_identity.is_none() => return,
+            // Nor has the peer asking to be relayed proven who it is
+            NextState::None if self.relay_req.is_some() => return,
+            _ => (),
+        }
+
         let _ = core.remove_state(self.token);
         let _ = core.cancel_timeout(&self.timeout);
 
         let our_id = PeerId(self.our_pk);
+        let keep_alive = self.keep_alive;
+        let inactivity_timeout = self.inactivity_timeout;
+        let drop_policy = self.drop_policy;
+        let bandwidth = self.bandwidth.clone();
+        let metrics = self.metrics.clone();
+        let compression = self.compression;
         let event_tx = self.event_tx.clone();
+        let elapsed = self.started.elapsed();
 
         match self.next_state {
             NextState::ActiveConnection(their_id, peer_kind) => {
+                let their_identity = unwrap!(self.their_identity);
+                metrics.handshake(HandshakeKind::Accept, elapsed);
+                core.trace(self.token, TraceState::Handshake, "completed", &[("peer", &their_id)]);
                 let socket = mem::replace(&mut self.socket, Socket::default());
                 ActiveConnection::start(core,
                                         poll,
@@ -285,13 +737,24 @@ impl ExchangeMsg {
                                         self.cm.clone(),
                                         our_id,
                                         their_id,
-                                        Event::BootstrapAccept(their_id, peer_kind),
+                                        Event::BootstrapAccept(their_id, peer_kind, their_identity),
+                                        keep_alive,
+                                        inactivity_timeout,
+                                        drop_policy,
+                                        bandwidth,
+                                        metrics,
+                                        compression,
+                                        None,
+                                        None,
                                         event_tx);
             }
             NextState::ConnectionCandidate(their_id) => {
+                let their_identity = unwrap!(self.their_identity);
+                core.trace(self.token, TraceState::Handshake, "completed", &[("peer", &their_id)]);
                 let cm = self.cm.clone();
                 let handler =
                     move |core: &mut Core, poll: &Poll, token, res| if let Some(socket) = res {
+                        metrics.handshake(HandshakeKind::Accept, elapsed);
                         ActiveConnection::start(core,
                                                 poll,
                                                 token,
@@ -299,7 +762,15 @@ impl ExchangeMsg {
                                                 cm.clone(),
                                                 our_id,
                                                 their_id,
-                                                Event::ConnectSuccess(their_id),
+                                                Event::ConnectSuccess(their_id, their_identity),
+                                                keep_alive,
+                                                inactivity_timeout,
+                                                drop_policy,
+                                                bandwidth.clone(),
+                                                metrics.clone(),
+                                                compression,
+                                                None,
+                                                None,
                                                 event_tx.clone());
                     };
 
@@ -313,7 +784,10 @@ impl ExchangeMsg {
                                                    their_id,
                                                    Box::new(handler));
             }
-            NextState::None => self.terminate(core, poll),
+            NextState::None => {
+                core.trace(self.token, TraceState::Handshake, "finished", &[]);
+                self.terminate(core, poll)
+            }
         }
     }
 
@@ -326,12 +800,21 @@ impl ExchangeMsg {
 }
 
 impl State for ExchangeMsg {
+    fn name(&self) -> &'static str {
+        "connection_listener::ExchangeMsg"
+    }
+
     fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
         if kind.is_error() || kind.is_hup() {
             self.terminate(core, poll);
         } else {
             if kind.is_readable() {
-                self.read(core, poll)
+                self.read(core, poll);
+                // Only once we have sent our challenge can the peer have proven its identity,
+                // upon which the socket is handed over
+                if self.their_identity.is_some() {
+                    return;
+                }
             }
             if kind.is_writable() {
                 self.write(core, poll, None)
@@ -341,12 +824,14 @@ impl State for ExchangeMsg {
 
     fn terminate(&mut self, core: &mut Core, poll: &Poll) {
         self.terminate_childern(core, poll);
-        let _ = core.remove_state(self.token);
+        if core.remove_state(self.token).is_some() {
+            core.trace(self.token, TraceState::Handshake, "failed", &[]);
+        }
 
         match self.next_state {
             NextState::ConnectionCandidate(their_id) |
             NextState::ActiveConnection(their_id, _) => {
-                let mut guard = unwrap!(self.cm.lock());
+                let mut guard = lock(&self.cm);
                 if let Entry::Occupied(mut oe) = guard.entry(their_id) {
                     oe.get_mut().currently_handshaking -= 1;
                     if oe.get().currently_handshaking == 0 && oe.get().active_connection.is_none() {
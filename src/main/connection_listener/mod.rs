@@ -17,42 +17,127 @@
 
 mod check_reachability;
 mod exchange_msg;
+mod reachability;
+mod relay;
 
-use self::exchange_msg::ExchangeMsg;
-use common::{Core, NameHash, Socket, State};
-use main::{ConnectionMap, Event};
+pub use self::reachability::{ListenerReachability, PortForwarding};
+use self::exchange_msg::{ExchangeMsg, PendingHandshake, PendingHandshakes};
+use self::relay::{RelayMap, Relays};
+use common::{BandwidthLimits, Core, CoreMessage, DropPolicy, IdentityKeys, Listener, NameHash,
+             Socket, SocketConfig, State, Transport, lock, shard_of};
+use main::{CompressionPolicy, Config, ConnectionMap, Event, ExpectedIdentities, IpWhitelist,
+           Metrics, Offers};
 use mio::{Poll, PollOpt, Ready, Token};
 use mio::tcp::TcpListener;
-use nat::{MappedTcpSocket, MappingContext};
-use nat::ip_addr_is_global;
-use net2::TcpBuilder;
+use nat::{EchoServer, MappedAddr, MappedAddrSource, MappedTcpSocket, MappingContext,
+          MappingResult, PortRange, VerifyReachability};
+use nat::{self, declared_addrs, ip_addr_is_global, new_reusably_bound_tcp_socket};
 use rust_sodium::crypto::box_::PublicKey;
 use std::any::Any;
 use std::cell::RefCell;
-use std::io::ErrorKind;
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 const LISTENER_BACKLOG: i32 = 100;
+const DEFAULT_MAX_PENDING_HANDSHAKES: usize = 256;
+const DEFAULT_MAX_PENDING_HANDSHAKES_PER_IP: usize = 8;
+const DEFAULT_MAX_ACCEPTS_PER_IP_PER_SEC: u32 = 20;
+// Past this many, IPs that have not connected within the last second are forgotten.
+const MAX_TRACKED_IPS: usize = 1024;
+
+/// How many connections the listener takes on that have yet to complete the handshake.
+#[derive(Debug, Clone, Copy)]
+pub struct AcceptLimits {
+    /// Most connections in the middle of the handshake at once.
+    pub max_pending: usize,
+    /// Most connections from any one IP in the middle of the handshake at once.
+    pub max_pending_per_ip: usize,
+    /// Most connections accepted from any one IP in a second.
+    pub per_ip_per_sec: u32,
+}
+
+/// The listener's `AcceptLimits`.
+pub fn accept_limits(config: &Config) -> AcceptLimits {
+    AcceptLimits {
+        max_pending: config
+            .max_pending_handshakes
+            .unwrap_or(DEFAULT_MAX_PENDING_HANDSHAKES),
+        max_pending_per_ip: config
+            .max_pending_handshakes_per_ip
+            .unwrap_or(DEFAULT_MAX_PENDING_HANDSHAKES_PER_IP),
+        per_ip_per_sec: config
+            .max_accepts_per_ip_per_sec
+            .unwrap_or(DEFAULT_MAX_ACCEPTS_PER_IP_PER_SEC),
+    }
+}
 
 pub struct ConnectionListener {
     token: Token,
     cm: ConnectionMap,
     event_tx: ::CrustEventSender,
-    listener: TcpListener,
+    listener: Box<Listener>,
+    // Listeners on the same port over the transports registered besides the main one, each
+    // under a token of its own.
+    extra_listeners: Vec<(Token, Box<Listener>)>,
     name_hash: NameHash,
     our_pk: PublicKey,
+    identity: IdentityKeys,
+    expected_identities: ExpectedIdentities,
+    offers: Offers,
+    whitelist: IpWhitelist,
     timeout_sec: Option<u64>,
+    limits: AcceptLimits,
+    pending: Rc<RefCell<PendingHandshakes>>,
+    // When each IP's current second of accepts began and how many were accepted in it.
+    accepts: HashMap<IpAddr, (Instant, u32)>,
+    keep_alive: Duration,
+    inactivity_timeout: Duration,
+    drop_policy: DropPolicy,
+    bandwidth: BandwidthLimits,
+    metrics: Metrics,
+    compression: Option<CompressionPolicy>,
+    socket_config: SocketConfig,
+    udp_echo_server: Option<Token>,
+    relays: Option<RelayMap>,
+    our_listeners: Arc<Mutex<Vec<SocketAddr>>>,
+    // What this listener added to `our_listeners`, to be withdrawn once it stops.
+    advertised: Vec<SocketAddr>,
+    reachability: ListenerReachability,
+    // Whether its port was mapped on the routers and by the peers, and whether to advertise that
+    // port on our external IPs regardless of what it was mapped to.
+    mapped: bool,
+    force_port: bool,
+    // The listeners sharing our port on the other event loops, `None` once we stopped.
+    siblings: Siblings,
 }
 
+type Siblings = Arc<Mutex<Option<Vec<Token>>>>;
+
 impl ConnectionListener {
     pub fn start(core: &mut Core,
                  poll: &Poll,
                  handshake_timeout_sec: Option<u64>,
-                 port: u16,
+                 limits: AcceptLimits,
+                 keep_alive: Duration,
+                 inactivity_timeout: Duration,
+                 drop_policy: DropPolicy,
+                 bandwidth: BandwidthLimits,
+                 metrics: Metrics,
+                 compression: Option<CompressionPolicy>,
+                 ports: PortRange,
+                 bind: Option<SocketAddr>,
                  force_include_port: bool,
+                 act_as_relay: bool,
+                 reuse_port: bool,
                  our_pk: PublicKey,
+                 identity: IdentityKeys,
+                 expected_identities: ExpectedIdentities,
+                 offers: Offers,
+                 whitelist: IpWhitelist,
                  name_hash: NameHash,
                  cm: ConnectionMap,
                  mc: Arc<MappingContext>,
@@ -60,96 +145,360 @@ impl ConnectionListener {
                  token: Token,
                  event_tx: ::CrustEventSender) {
         let event_tx_0 = event_tx.clone();
-        let finish =
-            move |core: &mut Core, poll: &Poll, socket, mut mapped_addrs: Vec<SocketAddr>| {
-                if force_include_port && port != 0 &&
-                   !mapped_addrs
-                        .iter()
-                        .any(|s| ip_addr_is_global(&s.ip()) && s.port() == port) {
-                    let global_addrs: Vec<_> = mapped_addrs
-                        .iter()
-                        .filter_map(|s| if ip_addr_is_global(&s.ip()) {
-                                        let mut s = *s;
-                                        s.set_port(port);
-                                        Some(s)
-                                    } else {
-                                        None
-                                    })
-                        .collect();
-                    mapped_addrs.extend(global_addrs);
+        let mc_0 = mc.clone();
+        let force_port = force_include_port && !ports.is_ephemeral();
+        // Only TCP sockets we bind ourselves can share their port
+        let shared = reuse_port && core.transport().nat_traversal();
+        let handle = move |core: &mut Core,
+                           poll: &Poll,
+                           listener: Box<Listener>,
+                           mapped_addrs: Vec<MappedAddr>,
+                           mapped: bool| {
+            let local_addr = match listener.local_addr() {
+                Ok(addr) => addr,
+                Err(e) => {
+                    error!("Could not get the port of the listening socket: {:?}", e);
+                    let _ = event_tx.send(Event::ListenerFailed(bind));
+                    return;
                 }
-                if let Err(e) = ConnectionListener::handle_mapped_socket(core,
-                                                                         poll,
-                                                                         handshake_timeout_sec,
-                                                                         socket,
-                                                                         mapped_addrs,
-                                                                         our_pk,
-                                                                         name_hash,
-                                                                         cm,
-                                                                         our_listeners,
-                                                                         token,
-                                                                         event_tx.clone()) {
-                    error!("TCP Listener failed to handle mapped socket: {:?}", e);
-                    let _ = event_tx.send(Event::ListenerFailed);
+            };
+            let mapped_addrs = rank_addrs(&mc_0, local_addr.port(), force_port, mapped_addrs);
+            if let Err(e) = ConnectionListener::handle_mapped_socket(core,
+                                                                     poll,
+                                                                     handshake_timeout_sec,
+                                                                     limits,
+                                                                     keep_alive,
+                                                                     inactivity_timeout,
+                                                                     drop_policy,
+                                                                     bandwidth,
+                                                                     metrics,
+                                                                     compression,
+                                                                     listener,
+                                                                     mapped_addrs,
+                                                                     mapped,
+                                                                     force_port,
+                                                                     act_as_relay,
+                                                                     shared,
+                                                                     our_pk,
+                                                                     identity,
+                                                                     expected_identities,
+                                                                     offers,
+                                                                     whitelist,
+                                                                     name_hash,
+                                                                     cm,
+                                                                     &mc_0,
+                                                                     our_listeners,
+                                                                     token,
+                                                                     event_tx.clone()) {
+                error!("Listener failed to handle mapped socket: {:?}", e);
+                let _ = event_tx.send(Event::ListenerFailed(Some(local_addr)));
+            }
+        };
+
+        // An address to listen on given explicitly is advertised as it is, so that what is
+        // exposed on multi-homed hosts stays under control.
+        let transport = core.transport();
+        if let Some(addr) = bind {
+            match listen_on(&*transport, &addr, shared, &mc) {
+                Ok((listener, mapped_addrs)) => handle(core, poll, listener, mapped_addrs, false),
+                Err(e) => {
+                    error!("Could not listen on {} over {}: {:?}", addr, transport.name(), e);
+                    let _ = event_tx_0.send(Event::ListenerFailed(Some(addr)));
+                }
+            }
+            return;
+        }
+
+        // Only TCP can be mapped on the router or reached through a peer's echo service, so
+        // other transports are listened on as they are and advertised on our interfaces alone.
+        if !transport.nat_traversal() {
+            match listen_unmapped(&*transport, ports, &mc) {
+                Ok((listener, mapped_addrs)) => handle(core, poll, listener, mapped_addrs, false),
+                Err(e) => {
+                    error!("Could not listen over {}: {:?}", transport.name(), e);
+                    let _ = event_tx_0.send(Event::ListenerFailed(None));
+                }
+            }
+            return;
+        }
+
+        let event_tx_1 = event_tx_0.clone();
+        let finish = move |core: &mut Core, poll: &Poll, res: MappingResult| {
+            let (socket, mapped_addrs) = match res {
+                Ok(res) => res,
+                Err(e) => {
+                    error!("Could not map tcp listening socket: {:?}", e);
+                    let _ = event_tx_1.send(Event::ListenerFailed(None));
+                    return;
                 }
             };
+            let transport = core.transport();
+            let listener = socket
+                .listen(LISTENER_BACKLOG)
+                .and_then(|listener| {
+                              let local_addr = listener.local_addr()?;
+                              TcpListener::from_listener(listener, &local_addr)
+                          })
+                .and_then(|listener| transport.adopt_listener(listener));
+            match listener {
+                Ok(listener) => handle(core, poll, listener, mapped_addrs, true),
+                Err(e) => {
+                    error!("Could not listen on the mapped socket: {:?}", e);
+                    let _ = event_tx_1.send(Event::ListenerFailed(None));
+                }
+            }
+        };
 
-        if let Err(e) = MappedTcpSocket::start(core, poll, port, &mc, finish) {
+        if let Err(e) = MappedTcpSocket::start(core, poll, ports, &mc, finish) {
             error!("Error starting tcp_listening_socket: {:?}", e);
-            let _ = event_tx_0.send(Event::ListenerFailed);
+            let _ = event_tx_0.send(Event::ListenerFailed(None));
         }
     }
 
     fn handle_mapped_socket(core: &mut Core,
                             poll: &Poll,
                             timeout_sec: Option<u64>,
-                            socket: TcpBuilder,
-                            mapped_addrs: Vec<SocketAddr>,
+                            limits: AcceptLimits,
+                            keep_alive: Duration,
+                            inactivity_timeout: Duration,
+                            drop_policy: DropPolicy,
+                            bandwidth: BandwidthLimits,
+                            metrics: Metrics,
+                            compression: Option<CompressionPolicy>,
+                            listener: Box<Listener>,
+                            mapped_addrs: Vec<MappedAddr>,
+                            mapped: bool,
+                            force_port: bool,
+                            act_as_relay: bool,
+                            shared: bool,
                             our_pk: PublicKey,
+                            identity: IdentityKeys,
+                            expected_identities: ExpectedIdentities,
+                            offers: Offers,
+                            whitelist: IpWhitelist,
                             name_hash: NameHash,
                             cm: ConnectionMap,
+                            mc: &MappingContext,
                             our_listeners: Arc<Mutex<Vec<SocketAddr>>>,
                             token: Token,
                             event_tx: ::CrustEventSender)
                             -> ::Res<()> {
-        let listener = socket.listen(LISTENER_BACKLOG)?;
         let local_addr = listener.local_addr()?;
-
-        let listener = TcpListener::from_listener(listener, &local_addr)?;
-        poll.register(&listener,
+        poll.register(&*listener,
                       token,
                       Ready::readable() | Ready::error() | Ready::hup(),
                       PollOpt::edge())?;
 
-        *unwrap!(our_listeners.lock()) = mapped_addrs.into_iter().collect();
+        let mut extra_listeners = Vec::new();
+        let mut udp_port_held = false;
+        for transport in core.listen_transports() {
+            let listener = match transport.listen(&local_addr) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    debug!("Could not listen on {} over {}: {:?}", local_addr, transport.name(), e);
+                    continue;
+                }
+            };
+            let extra_token = core.get_new_token();
+            if let Err(e) = poll.register(&*listener,
+                                          extra_token,
+                                          Ready::readable() | Ready::error() | Ready::hup(),
+                                          PollOpt::edge()) {
+                debug!("Could not listen on {} over {}: {:?}", local_addr, transport.name(), e);
+                continue;
+            }
+            udp_port_held |= transport.holds_udp_port();
+            extra_listeners.push((extra_token, listener));
+        }
+
+        let advertised: Vec<SocketAddr> = mapped_addrs.iter().map(|mapped| mapped.addr).collect();
+        advertise(&our_listeners, &[], &advertised);
+        let external: Vec<MappedAddr> = mapped_addrs
+            .iter()
+            .filter(|mapped| ip_addr_is_global(&mapped.addr.ip()))
+            .cloned()
+            .collect();
+
+        // Failure to echo over udp only degrades udp traversal for our peers, so it is not fatal
+        // to the listener.
+        let nat_traversal = core.transport().nat_traversal();
+        let udp_echo_server = if !nat_traversal || udp_port_held {
+            None
+        } else {
+            match EchoServer::start(core, poll, &local_addr) {
+                Ok(child) => Some(child),
+                Err(e) => {
+                    debug!("Could not start udp echo server: {:?}", e);
+                    None
+                }
+            }
+        };
 
         let state = ConnectionListener {
             token: token,
             cm: cm,
             event_tx: event_tx.clone(),
             listener: listener,
+            extra_listeners: extra_listeners,
             name_hash: name_hash,
             our_pk: our_pk,
+            identity: identity,
+            expected_identities: expected_identities,
+            offers: offers,
+            whitelist: whitelist,
             timeout_sec: timeout_sec,
+            limits: limits,
+            pending: Rc::new(RefCell::new(PendingHandshakes::default())),
+            accepts: HashMap::new(),
+            keep_alive: keep_alive,
+            inactivity_timeout: inactivity_timeout,
+            drop_policy: drop_policy,
+            bandwidth: bandwidth,
+            metrics: metrics,
+            compression: compression,
+            socket_config: mc.mapping_config().socket,
+            udp_echo_server: udp_echo_server,
+            relays: if act_as_relay {
+                Some(Rc::new(RefCell::new(Relays::default())))
+            } else {
+                None
+            },
+            our_listeners: our_listeners.clone(),
+            advertised: advertised.clone(),
+            reachability: ListenerReachability::unchecked(local_addr, &external),
+            mapped: mapped,
+            force_port: force_port,
+            siblings: Arc::new(Mutex::new(Some(Vec::new()))),
         };
+        if shared {
+            state.share_port(core);
+        }
 
-        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
-        let _ = event_tx.send(Event::ListenerStarted(local_addr.port()));
+        let extra_tokens: Vec<Token> = state.extra_listeners.iter().map(|&(t, _)| t).collect();
+        let state = Rc::new(RefCell::new(state));
+        for extra_token in extra_tokens {
+            let _ = core.insert_state(extra_token, state.clone());
+        }
+        let _ = core.insert_state(token, state);
+        let _ = event_tx.send(Event::ListenerStarted(local_addr));
+
+        if nat_traversal {
+            verify_reachability(core, poll, token, local_addr, external, mc);
+        }
 
         Ok(())
     }
 
-    fn accept(&self, core: &mut Core, poll: &Poll) {
+    /// Find out afresh which addresses the listener is reached at, e.g. as our network changed,
+    /// advertising those from then on instead. The port it listens on is mapped again if it was
+    /// to begin with.
+    pub fn remap(&mut self, core: &mut Core, poll: &Poll, mc: &Arc<MappingContext>) {
+        let local_addr = self.reachability.local_addr;
+        if !self.mapped {
+            let mapped_addrs = local_mapped_addrs(&local_addr, mc);
+            return self.readvertise(core, poll, mc, mapped_addrs);
+        }
+
+        let token = self.token;
+        let mc_0 = mc.clone();
+        let finish = move |core: &mut Core, poll: &Poll, res: MappingResult| {
+            // The listener already holds the port, so the socket mapped is of no further use
+            let mapped_addrs = match res {
+                Ok((_, mapped_addrs)) => mapped_addrs,
+                Err(e) => {
+                    debug!("Could not map listening port {} again: {:?}",
+                           local_addr.port(),
+                           e);
+                    return;
+                }
+            };
+            let state = match core.get_state(token) {
+                Some(state) => state,
+                None => return,
+            };
+            let mut state = state.borrow_mut();
+            if let Some(listener) = state.as_any().downcast_mut::<ConnectionListener>() {
+                listener.readvertise(core, poll, &mc_0, mapped_addrs);
+            }
+        };
+        if let Err(e) = MappedTcpSocket::start(core,
+                                               poll,
+                                               PortRange::from(local_addr.port()),
+                                               mc,
+                                               finish) {
+            debug!("Could not map listening port {} again: {:?}",
+                   local_addr.port(),
+                   e);
+        }
+    }
+
+    // Advertise `mapped_addrs` instead of what was advertised so far, checking them again.
+    fn readvertise(&mut self,
+                   core: &mut Core,
+                   poll: &Poll,
+                   mc: &MappingContext,
+                   mapped_addrs: Vec<MappedAddr>) {
+        let local_addr = self.reachability.local_addr;
+        let mapped_addrs = rank_addrs(mc, local_addr.port(), self.force_port, mapped_addrs);
+        let advertised: Vec<SocketAddr> = mapped_addrs.iter().map(|mapped| mapped.addr).collect();
+        advertise(&self.our_listeners, &self.advertised, &advertised);
+        self.advertised = advertised;
+        let external: Vec<MappedAddr> = mapped_addrs
+            .into_iter()
+            .filter(|mapped| ip_addr_is_global(&mapped.addr.ip()))
+            .collect();
+        self.reachability = ListenerReachability::unchecked(local_addr, &external);
+        core.helpers().elect(local_addr, None);
+        if core.transport().nat_traversal() {
+            verify_reachability(core, poll, self.token, local_addr, external, mc);
+        }
+    }
+
+    fn accept(&mut self, core: &mut Core, poll: &Poll) {
+        for nth in 0..self.extra_listeners.len() + 1 {
+            self.accept_from(core, poll, nth);
+        }
+    }
+
+    // Accept what is waiting on the `nth` listener, the one over the main transport coming first.
+    fn accept_from(&mut self, core: &mut Core, poll: &Poll, nth: usize) {
         loop {
-            match self.listener.accept() {
-                Ok((socket, _)) => {
+            let res = if nth == 0 {
+                self.listener.accept()
+            } else {
+                self.extra_listeners[nth - 1].1.accept()
+            };
+            match res {
+                Ok((stream, addr)) => {
+                    // Dropped before it can so much as handshake
+                    if !self.whitelist.admits(&addr, None) || !self.within_limits(&addr) {
+                        continue;
+                    }
+                    let pending = PendingHandshake::new(&self.pending,
+                                                        nat::unmap_ipv4(&addr).ip());
+                    let mut socket = Socket::from_stream(stream);
+                    if let Err(e) = socket.configure(&self.socket_config) {
+                        debug!("Could not set socket options: {:?}", e);
+                    }
                     if let Err(e) = ExchangeMsg::start(core,
                                                        poll,
                                                        self.timeout_sec,
-                                                       Socket::wrap(socket),
+                                                       self.keep_alive,
+                                                       self.inactivity_timeout,
+                                                       self.drop_policy,
+                                                       self.bandwidth.clone(),
+                                                       self.metrics.clone(),
+                                                       self.compression,
+                                                       socket,
+                                                       pending,
                                                        self.our_pk,
+                                                       self.identity.clone(),
+                                                       self.expected_identities.clone(),
+                                                       self.offers.clone(),
+                                                       self.whitelist.clone(),
                                                        self.name_hash,
                                                        self.cm.clone(),
+                                                       self.relays.clone(),
                                                        self.event_tx.clone()) {
                         debug!("Error accepting direct connection: {:?}", e);
                     }
@@ -163,21 +512,142 @@ impl ConnectionListener {
             }
         }
     }
+
+    // Have the other event loops accept connections on our port too, the kernel spreading them
+    // over the loops. Only we map and advertise the port.
+    fn share_port(&self, core: &Core) {
+        let shards = match core.shards() {
+            Some(shards) => shards,
+            None => return,
+        };
+        for index in (0..shards.count()).filter(|&index| index != shards.index()) {
+            let sibling = Sibling {
+                local_addr: self.reachability.local_addr,
+                siblings: self.siblings.clone(),
+                cm: self.cm.clone(),
+                event_tx: self.event_tx.clone(),
+                name_hash: self.name_hash,
+                our_pk: self.our_pk,
+                identity: self.identity.clone(),
+                expected_identities: self.expected_identities.clone(),
+                offers: self.offers.clone(),
+                whitelist: self.whitelist.clone(),
+                timeout_sec: self.timeout_sec,
+                limits: self.limits,
+                keep_alive: self.keep_alive,
+                inactivity_timeout: self.inactivity_timeout,
+                drop_policy: self.drop_policy,
+                bandwidth: self.bandwidth.clone(),
+                metrics: self.metrics.clone(),
+                compression: self.compression,
+                socket_config: self.socket_config,
+                our_listeners: self.our_listeners.clone(),
+                reachability: self.reachability.clone(),
+            };
+            let msg = CoreMessage::new(move |core, poll| {
+                let local_addr = sibling.local_addr;
+                if let Err(e) = sibling.start(core, poll) {
+                    debug!("Could not share listening port {} with event loop {}: {:?}",
+                           local_addr.port(),
+                           index,
+                           e);
+                }
+            });
+            if let Err(e) = shards.send(index, msg) {
+                debug!("Could not share listening port with event loop {}: {:?}",
+                       index,
+                       e);
+            }
+        }
+    }
+
+    /// How peers outside our network reach this listener, as far as is known yet.
+    pub fn reachability(&self) -> ListenerReachability {
+        self.reachability.clone()
+    }
+
+    // Whether to take on another connection from `addr` without exceeding our `AcceptLimits`.
+    fn within_limits(&mut self, addr: &SocketAddr) -> bool {
+        let ip = nat::unmap_ipv4(addr).ip();
+        {
+            let pending = self.pending.borrow();
+            if pending.total >= self.limits.max_pending {
+                debug!("Dropping connection from {} with {} handshakes pending",
+                       addr,
+                       pending.total);
+                return false;
+            }
+            if pending.by_ip.get(&ip).map_or(false, |&n| n >= self.limits.max_pending_per_ip) {
+                debug!("Dropping connection from {} with {} handshakes pending from its IP",
+                       addr,
+                       pending.by_ip[&ip]);
+                return false;
+            }
+        }
+
+        let now = Instant::now();
+        let second = Duration::from_secs(1);
+        if self.accepts.len() >= MAX_TRACKED_IPS && !self.accepts.contains_key(&ip) {
+            self.accepts
+                .retain(|_, &mut (since, _)| now.duration_since(since) < second);
+            // Too many IPs at once to track each, leaving only the cap on pending handshakes
+            if self.accepts.len() >= MAX_TRACKED_IPS {
+                return true;
+            }
+        }
+        let accepts = self.accepts.entry(ip).or_insert((now, 0));
+        if now.duration_since(accepts.0) >= second {
+            *accepts = (now, 0);
+        }
+        if accepts.1 >= self.limits.per_ip_per_sec {
+            debug!("Dropping connection from {} for connecting too often", addr);
+            return false;
+        }
+        accepts.1 += 1;
+        true
+    }
 }
 
 impl State for ConnectionListener {
+    fn name(&self) -> &'static str {
+        "ConnectionListener"
+    }
+
     fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
         if kind.is_error() || kind.is_hup() {
             self.terminate(core, poll);
-            let _ = self.event_tx.send(Event::ListenerFailed);
+            let local_addr = self.reachability.local_addr;
+            let _ = self.event_tx.send(Event::ListenerFailed(Some(local_addr)));
         } else if kind.is_readable() {
             self.accept(core, poll);
         }
     }
 
     fn terminate(&mut self, core: &mut Core, poll: &Poll) {
-        let _ = poll.deregister(&self.listener);
+        if let Some(child) = self.udp_echo_server.take() {
+            if let Some(child) = core.get_state(child) {
+                child.borrow_mut().terminate(core, poll);
+            }
+        }
+        let _ = poll.deregister(&*self.listener);
         let _ = core.remove_state(self.token);
+        for (token, listener) in self.extra_listeners.drain(..) {
+            let _ = poll.deregister(&*listener);
+            let _ = core.remove_state(token);
+        }
+        let siblings = lock(&self.siblings).take().unwrap_or_default();
+        if let Some(shards) = core.shards() {
+            for token in siblings {
+                let msg = CoreMessage::new(move |core, poll| {
+                    if let Some(state) = core.get_state(token) {
+                        state.borrow_mut().terminate(core, poll);
+                    }
+                });
+                let _ = shards.send(shard_of(token), msg);
+            }
+        }
+        core.helpers().elect(self.reachability.local_addr, None);
+        advertise(&self.our_listeners, &self.advertised, &[]);
     }
 
     fn as_any(&mut self) -> &mut Any {
@@ -185,17 +655,239 @@ impl State for ConnectionListener {
     }
 }
 
+// Now that we are listening on `local_addr`, find out how peers reach us at `external`. The
+// addresses stay advertised in the order they were ranked in, as one helper failing to reach us
+// proves little.
+fn verify_reachability(core: &mut Core,
+                       poll: &Poll,
+                       token: Token,
+                       local_addr: SocketAddr,
+                       external: Vec<MappedAddr>,
+                       mc: &MappingContext) {
+    let finish = move |core: &mut Core,
+                       _: &Poll,
+                       external: Vec<MappedAddr>,
+                       unreachable: Vec<SocketAddr>| {
+        trace!("Verified listener addresses: {:?}, unreachable: {:?}",
+               external,
+               unreachable);
+        let state = match core.get_state(token) {
+            Some(state) => state,
+            None => return,
+        };
+        let mut state = state.borrow_mut();
+        if let Some(listener) = state.as_any().downcast_mut::<ConnectionListener>() {
+            listener.reachability =
+                ListenerReachability::checked(local_addr, &external, &unreachable);
+            core.helpers()
+                .elect(local_addr, listener.reachability.helper_addr());
+        }
+    };
+    VerifyReachability::start(core, poll, external, mc, finish);
+}
+
+// The addresses a listener on `port` is advertised at, given those it was mapped to. Addresses
+// declared in the config go first, ports forwarded to us by hand included, and with `force_port`
+// our external IPs are advertised with `port` itself too.
+fn rank_addrs(mc: &MappingContext,
+              port: u16,
+              force_port: bool,
+              mut mapped_addrs: Vec<MappedAddr>)
+              -> Vec<MappedAddr> {
+    let declared = declared_addrs(mc.declared_ext_addrs(), port, true);
+    mapped_addrs.retain(|mapped| !declared.iter().any(|d| d.addr == mapped.addr));
+    mapped_addrs = declared.into_iter().chain(mapped_addrs).collect();
+    if force_port &&
+       !mapped_addrs
+            .iter()
+            .any(|s| ip_addr_is_global(&s.addr.ip()) && s.addr.port() == port) {
+        let global_addrs: Vec<_> = mapped_addrs
+            .iter()
+            .filter_map(|s| if ip_addr_is_global(&s.addr.ip()) {
+                            let mut s = *s;
+                            s.addr.set_port(port);
+                            Some(s)
+                        } else {
+                            None
+                        })
+            .collect();
+        mapped_addrs.extend(global_addrs);
+    }
+    mapped_addrs
+}
+
+// Listen over a transport that takes no part in NAT traversal on the first free port of `ports`,
+// along with the addresses of our interfaces it can be reached at.
+fn listen_unmapped(transport: &Transport,
+                   ports: PortRange,
+                   mc: &MappingContext)
+                   -> io::Result<(Box<Listener>, Vec<MappedAddr>)> {
+    let mut port = ports.first;
+    let listener;
+    loop {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+        match transport.listen(&addr) {
+            Err(ref e) if e.kind() == ErrorKind::AddrInUse && port < ports.last => port += 1,
+            res => {
+                listener = res?;
+                break;
+            }
+        }
+    }
+    let local_addr = listener.local_addr()?;
+    Ok((listener, local_mapped_addrs(&local_addr, mc)))
+}
+
+// Listen over `transport` on exactly `addr`, along with the addresses it can be reached at. A
+// `shared` port can be listened on by the other event loops too.
+fn listen_on(transport: &Transport,
+             addr: &SocketAddr,
+             shared: bool,
+             mc: &MappingContext)
+             -> io::Result<(Box<Listener>, Vec<MappedAddr>)> {
+    let listener = if shared {
+        listen_shared(transport, addr, &mc.mapping_config().socket)?
+    } else {
+        transport.listen(addr)?
+    };
+    let local_addr = listener.local_addr()?;
+    Ok((listener, local_mapped_addrs(&local_addr, mc)))
+}
+
+// Listen over TCP `transport` on `addr` with a socket that shares its port, as the sockets mapped
+// for listening do.
+fn listen_shared(transport: &Transport,
+                 addr: &SocketAddr,
+                 config: &SocketConfig)
+                 -> io::Result<Box<Listener>> {
+    let listener = new_reusably_bound_tcp_socket(addr, config)?
+        .listen(LISTENER_BACKLOG)?;
+    let local_addr = listener.local_addr()?;
+    transport.adopt_listener(TcpListener::from_listener(listener, &local_addr)?)
+}
+
+// What a listener on another event loop needs to accept connections on the port of one on the
+// first, see `Config::reuse_port_listeners`.
+struct Sibling {
+    local_addr: SocketAddr,
+    siblings: Siblings,
+    cm: ConnectionMap,
+    event_tx: ::CrustEventSender,
+    name_hash: NameHash,
+    our_pk: PublicKey,
+    identity: IdentityKeys,
+    expected_identities: ExpectedIdentities,
+    offers: Offers,
+    whitelist: IpWhitelist,
+    timeout_sec: Option<u64>,
+    limits: AcceptLimits,
+    keep_alive: Duration,
+    inactivity_timeout: Duration,
+    drop_policy: DropPolicy,
+    bandwidth: BandwidthLimits,
+    metrics: Metrics,
+    compression: Option<CompressionPolicy>,
+    socket_config: SocketConfig,
+    our_listeners: Arc<Mutex<Vec<SocketAddr>>>,
+    reachability: ListenerReachability,
+}
+
+impl Sibling {
+    fn start(self, core: &mut Core, poll: &Poll) -> io::Result<()> {
+        let siblings = self.siblings.clone();
+        let mut siblings = lock(&siblings);
+        // The listener on the first event loop stopped in the meantime
+        let tokens = match *siblings {
+            Some(ref mut tokens) => tokens,
+            None => return Ok(()),
+        };
+        let listener = listen_shared(&*core.transport(), &self.local_addr, &self.socket_config)?;
+        let token = core.get_new_token();
+        poll.register(&*listener,
+                      token,
+                      Ready::readable() | Ready::error() | Ready::hup(),
+                      PollOpt::edge())?;
+        let state = ConnectionListener {
+            token: token,
+            cm: self.cm,
+            event_tx: self.event_tx,
+            listener: listener,
+            extra_listeners: Vec::new(),
+            name_hash: self.name_hash,
+            our_pk: self.our_pk,
+            identity: self.identity,
+            expected_identities: self.expected_identities,
+            offers: self.offers,
+            whitelist: self.whitelist,
+            timeout_sec: self.timeout_sec,
+            limits: self.limits,
+            pending: Rc::new(RefCell::new(PendingHandshakes::default())),
+            accepts: HashMap::new(),
+            keep_alive: self.keep_alive,
+            inactivity_timeout: self.inactivity_timeout,
+            drop_policy: self.drop_policy,
+            bandwidth: self.bandwidth,
+            metrics: self.metrics,
+            compression: self.compression,
+            socket_config: self.socket_config,
+            udp_echo_server: None,
+            relays: None,
+            our_listeners: self.our_listeners,
+            advertised: Vec::new(),
+            reachability: self.reachability,
+            mapped: false,
+            force_port: false,
+            siblings: Arc::new(Mutex::new(None)),
+        };
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+        tokens.push(token);
+        Ok(())
+    }
+}
+
+// The addresses a listener bound to `local_addr` can be reached at without going through a NAT:
+// those of our interfaces if it is bound to all of them, else just its own.
+fn local_mapped_addrs(local_addr: &SocketAddr, mc: &MappingContext) -> Vec<MappedAddr> {
+    let ips: Vec<IpAddr> = match local_addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            mc.ifv4s().into_iter().map(IpAddr::V4).collect()
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            mc.ifv6s().into_iter().map(IpAddr::V6).collect()
+        }
+        ip => vec![ip],
+    };
+    ips.into_iter()
+        .map(|ip| SocketAddr::new(ip, local_addr.port()))
+        .map(|addr| MappedAddr::new(addr, MappedAddrSource::Local))
+        .collect()
+}
+
+// Replace the addresses `old` a listener advertised in `our_listeners` with `new`.
+fn advertise(our_listeners: &Mutex<Vec<SocketAddr>>, old: &[SocketAddr], new: &[SocketAddr]) {
+    let mut listeners = lock(&our_listeners);
+    listeners.retain(|addr| !old.contains(addr));
+    for addr in new {
+        if !listeners.contains(addr) {
+            listeners.push(*addr);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use super::exchange_msg::EXCHANGE_MSG_TIMEOUT_SEC;
     use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-    use common::{self, CoreMessage, CrustUser, EventLoop, ExternalReachability, Message, NameHash};
+    use common::{self, BandwidthLimits, BootstrapDenyReason, Challenge, CoreMessage, CrustUser,
+                 DropPolicy, EventLoop, ExternalReachability, Identity, IdentityKeys,
+                 MIN_PROTOCOL_VERSION, Message, NameHash, PROTOCOL_VERSION};
     use maidsafe_utilities::event_sender::MaidSafeEventCategory;
     use maidsafe_utilities::serialisation::{deserialise, serialise};
-    use main::{Event, PeerId};
+    use main::{Config, Event, HEARTBEAT_PERIOD_MS, INACTIVITY_TIMEOUT_MS, IpWhitelist, Metrics,
+               OfferLedger, Offers, PeerId};
     use mio::Token;
-    use nat::MappingContext;
+    use nat::{MappingContext, PortRange};
     use rust_sodium::crypto::box_::{self, PublicKey};
     use rust_sodium::crypto::hash::sha256;
     use serde::de::Deserialize;
@@ -206,7 +898,7 @@ mod tests {
     use std::net::TcpStream;
     use std::sync::{Arc, Mutex};
     use std::sync::mpsc;
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
 
     // Make sure this is < EXCHANGE_MSG_TIMEOUT_SEC else blocking reader socket in this test will
     // exit with an EAGAIN error (unless this is what is wanted).
@@ -218,11 +910,17 @@ mod tests {
     struct Listener {
         _el: EventLoop,
         pk: PublicKey,
+        identity: Identity,
         addr: SocketAddr,
+        offers: Offers,
         event_rx: mpsc::Receiver<Event>,
     }
 
     fn start_listener() -> Listener {
+        start_listener_with(accept_limits(&Config::default()), false)
+    }
+
+    fn start_listener_with(limits: AcceptLimits, act_as_relay: bool) -> Listener {
         let el = unwrap!(common::spawn_event_loop(LISTENER_TOKEN + 1,
                                                   Some("Connection Listener Test")));
 
@@ -235,14 +933,32 @@ mod tests {
         let listeners = Arc::new(Mutex::new(Vec::with_capacity(5)));
 
         let listeners_clone = listeners.clone();
+        let offers = Arc::new(Mutex::new(OfferLedger::default()));
+        let offers_clone = offers.clone();
         let (pk, _) = box_::gen_keypair();
+        let identity = IdentityKeys::generate();
+        let listener_identity = identity.identity();
         unwrap!(el.send(CoreMessage::new(move |core, poll| {
             ConnectionListener::start(core,
                                       poll,
                                       Some(HANDSHAKE_TIMEOUT_SEC),
-                                      0,
+                                      limits,
+                                      Duration::from_millis(HEARTBEAT_PERIOD_MS),
+                                      Duration::from_millis(INACTIVITY_TIMEOUT_MS),
+                                      DropPolicy::default(),
+                                      BandwidthLimits::default(),
+                                      Metrics::default(),
+                                      None,
+                                      PortRange::from(0),
+                                      None,
+                                      false,
+                                      act_as_relay,
                                       false,
                                       pk,
+                                      identity,
+                                      Arc::new(Mutex::new(HashMap::new())),
+                                      offers_clone,
+                                      unwrap!(IpWhitelist::new(&Config::default())),
                                       NAME_HASH,
                                       cm,
                                       mc,
@@ -254,7 +970,7 @@ mod tests {
 
         for it in event_rx.iter() {
             match it {
-                Event::ListenerStarted(_port) => break,
+                Event::ListenerStarted(_addr) => break,
                 _ => panic!("Unexpected event notification - {:?}", it),
             }
         }
@@ -264,11 +980,20 @@ mod tests {
         Listener {
             _el: el,
             pk: pk,
+            identity: listener_identity,
             addr: addr,
+            offers: offers,
             event_rx: event_rx,
         }
     }
 
+    // The nonce of connection info the listener hands out.
+    fn issue(listener: &Listener) -> u64 {
+        unwrap!(listener.offers.lock())
+            .issue(Duration::from_secs(60))
+            .nonce
+    }
+
     fn connect_to_listener(listener: &Listener) -> TcpStream {
         let listener_addr = StdSocketAddr::new(listener.addr.ip(), listener.addr.port());
         let stream = unwrap!(TcpStream::connect(listener_addr),
@@ -314,50 +1039,95 @@ mod tests {
 
         let expected_kind = match ext_reachability {
             ExternalReachability::NotRequired => CrustUser::Client,
-            ExternalReachability::Required { .. } => CrustUser::Node,
+            ExternalReachability::Required { .. } |
+            ExternalReachability::Relayed { .. } => CrustUser::Node,
         };
 
-        let message =
-            unwrap!(serialise(&Message::BootstrapRequest(pk, name_hash, ext_reachability)));
+        let challenge = Challenge::new();
+        let message = unwrap!(serialise(&Message::IdentifiedBootstrapRequest(pk,
+                                                                            name_hash,
+                                                                            ext_reachability,
+                                                                            challenge,
+                                                                            PROTOCOL_VERSION)));
         unwrap!(write(&mut us, &message), "Could not write.");
 
-        match unwrap!(read(&mut us), "Could not read.") {
-            Message::BootstrapGranted(peer_pk) => assert_eq!(peer_pk, listener.pk),
+        let their_challenge = match unwrap!(read(&mut us), "Could not read.") {
+            Message::IdentifiedBootstrapGranted(peer_pk, their_challenge, proof, version) => {
+                assert_eq!(peer_pk, listener.pk);
+                assert_eq!(version, PROTOCOL_VERSION);
+                assert_eq!(proof.verify(&peer_pk, &pk, &their_challenge, &challenge),
+                           Some(listener.identity));
+                their_challenge
+            }
             msg => panic!("Unexpected message: {:?}", msg),
-        }
+        };
+
+        let identity = IdentityKeys::generate();
+        let proof = identity.prove(&pk, &listener.pk, &challenge, &their_challenge);
+        let message = unwrap!(serialise(&Message::Identify(proof)));
+        unwrap!(write(&mut us, &message), "Could not write.");
 
         match unwrap!(listener.event_rx.recv(), "Could not read event channel") {
-            Event::BootstrapAccept(peer_id, peer_kind) => {
+            Event::BootstrapAccept(peer_id, peer_kind, peer_identity) => {
                 assert_eq!(peer_id, PeerId(pk));
                 assert_eq!(peer_kind, expected_kind);
+                assert_eq!(peer_identity, identity.identity());
             }
             event => panic!("Unexpected event notification: {:?}", event),
         }
     }
 
-    fn connect(name_hash: NameHash, pk: PublicKey, listener: &Listener) {
+    // Proves the identity for the key `prover`, which is `pk` unless forging a proof, having been
+    // handed info carrying `nonce`.
+    fn connect(name_hash: NameHash,
+               pk: PublicKey,
+               prover: PublicKey,
+               nonce: u64,
+               listener: &Listener) {
         let mut us = connect_to_listener(listener);
 
-        let message = unwrap!(serialise(&Message::Connect(pk, name_hash)));
+        let challenge = Challenge::new();
+        let message = unwrap!(serialise(&Message::IdentifiedConnect(pk,
+                                                                    name_hash,
+                                                                    challenge,
+                                                                    Some(nonce),
+                                                                    PROTOCOL_VERSION)));
         unwrap!(write(&mut us, &message), "Could not write.");
 
         let our_id = PeerId(pk);
-        let their_id = match unwrap!(read(&mut us), "Could not read.") {
-            Message::Connect(peer_pk, peer_hash) => {
+        let (their_id, their_challenge) = match unwrap!(read(&mut us), "Could not read.") {
+            Message::IdentifiedConnect(peer_pk, peer_hash, their_challenge, None, _) => {
                 assert_eq!(peer_pk, listener.pk);
                 assert_eq!(peer_hash, NAME_HASH);
-                PeerId(peer_pk)
+                (PeerId(peer_pk), their_challenge)
             }
             msg => panic!("Unexpected message: {:?}", msg),
         };
+        match unwrap!(read(&mut us), "Could not read.") {
+            Message::Identify(proof) => {
+                assert_eq!(proof.verify(&their_id.0, &pk, &their_challenge, &challenge),
+                           Some(listener.identity));
+            }
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+
+        let identity = IdentityKeys::generate();
+        let proof = identity.prove(&prover, &listener.pk, &challenge, &their_challenge);
+        let message = unwrap!(serialise(&Message::Identify(proof)));
+        unwrap!(write(&mut us, &message), "Could not write.");
 
         if our_id > their_id {
             let message = unwrap!(serialise(&Message::ChooseConnection));
             unwrap!(write(&mut us, &message), "Could not write.");
         }
 
-        match unwrap!(listener.event_rx.recv(), "Could not read event channel") {
-            Event::ConnectSuccess(id) => assert_eq!(id, PeerId(pk)),
+        // Nothing is heard of a peer which failed to prove its identity
+        let event = listener.event_rx.recv_timeout(Duration::from_secs(10));
+        match unwrap!(event, "Could not read event channel") {
+            Event::ConnectSuccess(id, peer_identity) => {
+                assert_eq!(id, PeerId(pk));
+                assert_eq!(peer_identity, identity.identity());
+            }
             event => panic!("Unexpected event notification: {:?}", event),
         }
     }
@@ -369,18 +1139,173 @@ mod tests {
         bootstrap(NAME_HASH, ExternalReachability::NotRequired, pk, &listener);
     }
 
+    #[test]
+    fn bootstrap_via_relay() {
+        // Granted as a node despite none of its listeners being reached
+        let listener = start_listener_with(accept_limits(&Config::default()), true);
+        let (pk, _) = box_::gen_keypair();
+        bootstrap(NAME_HASH,
+                  ExternalReachability::Relayed { direct_listeners: Vec::new() },
+                  pk,
+                  &listener);
+    }
+
+    #[test]
+    fn bootstrap_via_listener_not_relaying() {
+        let listener = start_listener();
+        let (pk, _) = box_::gen_keypair();
+        let mut us = connect_to_listener(&listener);
+        let relayed = ExternalReachability::Relayed { direct_listeners: Vec::new() };
+        let message = unwrap!(serialise(&Message::IdentifiedBootstrapRequest(pk,
+                                                                            NAME_HASH,
+                                                                            relayed,
+                                                                            Challenge::new(),
+                                                                            PROTOCOL_VERSION)));
+        unwrap!(write(&mut us, &message), "Could not write.");
+
+        match unwrap!(read(&mut us), "Could not read.") {
+            Message::BootstrapDenied(BootstrapDenyReason::NotRelaying) => (),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn bootstrap_with_incompatible_version() {
+        let listener = start_listener();
+        let (pk, _) = box_::gen_keypair();
+        let mut us = connect_to_listener(&listener);
+        let reachability = ExternalReachability::NotRequired;
+        let version = MIN_PROTOCOL_VERSION - 1;
+        let message = unwrap!(serialise(&Message::IdentifiedBootstrapRequest(pk,
+                                                                            NAME_HASH,
+                                                                            reachability,
+                                                                            Challenge::new(),
+                                                                            version)));
+        unwrap!(write(&mut us, &message), "Could not write.");
+
+        match unwrap!(read(&mut us), "Could not read.") {
+            Message::BootstrapDenied(BootstrapDenyReason::IncompatibleVersion) => (),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+
     #[test]
     fn connect_with_correct_parameters() {
         let listener = start_listener();
         let (pk, _) = box_::gen_keypair();
-        connect(NAME_HASH, pk, &listener);
+        connect(NAME_HASH, pk, pk, issue(&listener), &listener);
+    }
+
+    #[test]
+    #[should_panic]
+    fn connect_with_forged_identity() {
+        let listener = start_listener();
+        let (pk, _) = box_::gen_keypair();
+        let (other_pk, _) = box_::gen_keypair();
+        connect(NAME_HASH, pk, other_pk, issue(&listener), &listener);
+    }
+
+    #[test]
+    fn connect_with_replayed_proof() {
+        let listener = start_listener();
+        let (pk, _) = box_::gen_keypair();
+        let mut us = connect_to_listener(&listener);
+
+        let challenge = Challenge::new();
+        let nonce = Some(issue(&listener));
+        let message = unwrap!(serialise(&Message::IdentifiedConnect(pk,
+                                                                    NAME_HASH,
+                                                                    challenge,
+                                                                    nonce,
+                                                                    PROTOCOL_VERSION)));
+        unwrap!(write(&mut us, &message), "Could not write.");
+        for _ in 0..2 {
+            match unwrap!(read(&mut us), "Could not read.") {
+                Message::IdentifiedConnect(..) |
+                Message::Identify(..) => (),
+                msg => panic!("Unexpected message: {:?}", msg),
+            }
+        }
+
+        // As made in some earlier handshake, for another challenge than the listener's
+        let identity = IdentityKeys::generate();
+        let proof = identity.prove(&pk, &listener.pk, &challenge, &Challenge::new());
+        let message = unwrap!(serialise(&Message::Identify(proof)));
+        unwrap!(write(&mut us, &message), "Could not write.");
+
+        let mut buf = [0; 512];
+        assert_eq!(0,
+                   unwrap!(us.read(&mut buf), "read should have returned EOF (0)"));
+    }
+
+    #[test]
+    #[should_panic]
+    fn connect_with_info_not_handed_out() {
+        let listener = start_listener();
+        let (pk, _) = box_::gen_keypair();
+        let nonce = issue(&listener).wrapping_add(1);
+        connect(NAME_HASH, pk, pk, nonce, &listener);
+    }
+
+    #[test]
+    fn connect_with_info_used_by_another() {
+        let listener = start_listener();
+        let (pk, _) = box_::gen_keypair();
+        let nonce = issue(&listener);
+        connect(NAME_HASH, pk, pk, nonce, &listener);
+
+        let (other_pk, _) = box_::gen_keypair();
+        let mut us = connect_to_listener(&listener);
+        let message = unwrap!(serialise(&Message::IdentifiedConnect(other_pk,
+                                                                    NAME_HASH,
+                                                                    Challenge::new(),
+                                                                    Some(nonce),
+                                                                    PROTOCOL_VERSION)));
+        unwrap!(write(&mut us, &message), "Could not write.");
+
+        let mut buf = [0; 512];
+        assert_eq!(0,
+                   unwrap!(us.read(&mut buf), "read should have returned EOF (0)"));
+    }
+
+    #[test]
+    fn connect_from_before_identities() {
+        let listener = start_listener();
+        let (pk, _) = box_::gen_keypair();
+        let mut us = connect_to_listener(&listener);
+
+        let message = unwrap!(serialise(&Message::Connect(pk, NAME_HASH)));
+        unwrap!(write(&mut us, &message), "Could not write.");
+
+        let mut buf = [0; 512];
+        assert_eq!(0,
+                   unwrap!(us.read(&mut buf), "read should have returned EOF (0)"));
+    }
+
+    #[test]
+    fn migrate_without_relayed_connection() {
+        let listener = start_listener();
+        let (pk, _) = box_::gen_keypair();
+        let identity = IdentityKeys::generate();
+        let proof = identity.prove_migration(&pk, &listener.pk, &Challenge::new());
+
+        // Whether proven or from before it had to be, there is nothing to migrate
+        for message in &[Message::MigrateProven(pk, proof), Message::Migrate(pk, 0)] {
+            let mut us = connect_to_listener(&listener);
+            let message = unwrap!(serialise(message));
+            unwrap!(write(&mut us, &message), "Could not write.");
+
+            let mut buf = [0; 512];
+            assert_eq!(0,
+                       unwrap!(us.read(&mut buf), "read should have returned EOF (0)"));
+        }
     }
 
     #[test]
     #[should_panic]
     fn connect_to_self() {
         let listener = start_listener();
-        connect(NAME_HASH, listener.pk, &listener);
+        connect(NAME_HASH, listener.pk, listener.pk, issue(&listener), &listener);
     }
 
     #[test]
@@ -399,7 +1324,7 @@ mod tests {
     fn connect_with_invalid_version_hash() {
         let listener = start_listener();
         let (pk, _) = box_::gen_keypair();
-        connect(NAME_HASH_2, pk, &listener);
+        connect(NAME_HASH_2, pk, pk, issue(&listener), &listener);
     }
 
     #[test]
@@ -416,7 +1341,7 @@ mod tests {
     #[should_panic]
     fn connect_with_invalid_pub_key() {
         let listener = start_listener();
-        connect(NAME_HASH, listener.pk, &listener);
+        connect(NAME_HASH, listener.pk, listener.pk, issue(&listener), &listener);
     }
 
     #[test]
@@ -441,6 +1366,46 @@ mod tests {
                    unwrap!(us.read(&mut buf), "read should have returned EOF (0)"));
     }
 
+    #[test]
+    fn accept_limits_exceeded() {
+        // A connection dropped on accept rather than on timing out of the handshake
+        fn assert_dropped(us: &mut TcpStream) {
+            let started = Instant::now();
+            let mut buf = [0; 512];
+            assert_eq!(0,
+                       unwrap!(us.read(&mut buf), "read should have returned EOF (0)"));
+            assert!(started.elapsed() < Duration::from_secs(HANDSHAKE_TIMEOUT_SEC));
+        }
+
+        let limits = AcceptLimits {
+            max_pending: 1,
+            max_pending_per_ip: 100,
+            per_ip_per_sec: 100,
+        };
+        let listener = start_listener_with(limits, false);
+        let _pending = connect_to_listener(&listener);
+        assert_dropped(&mut connect_to_listener(&listener));
+
+        let limits = AcceptLimits {
+            max_pending: 100,
+            max_pending_per_ip: 2,
+            per_ip_per_sec: 100,
+        };
+        let listener = start_listener_with(limits, false);
+        let _first = connect_to_listener(&listener);
+        let _second = connect_to_listener(&listener);
+        assert_dropped(&mut connect_to_listener(&listener));
+
+        let limits = AcceptLimits {
+            max_pending: 100,
+            max_pending_per_ip: 100,
+            per_ip_per_sec: 1,
+        };
+        let listener = start_listener_with(limits, false);
+        let _first = connect_to_listener(&listener);
+        assert_dropped(&mut connect_to_listener(&listener));
+    }
+
     #[test]
     fn stun_service() {
         let listener = start_listener();
@@ -464,4 +1429,33 @@ mod tests {
         assert_eq!(0,
                    unwrap!(us.read(&mut buf), "read should have returned EOF (0)"));
     }
+
+    #[test]
+    fn reachability_service() {
+        let listener = start_listener();
+        let mut us = connect_to_listener(&listener);
+
+        // The listener itself lives on our IP, so it can stand in for our external address.
+        let message = unwrap!(serialise(&Message::ReachabilityReq(listener.addr)));
+        unwrap!(write(&mut us, &message), "Could not write.");
+
+        match unwrap!(read(&mut us), "Could not read.") {
+            Message::ReachabilityResp(reachable) => assert!(reachable),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+
+        let mut buf = [0; 512];
+        assert_eq!(0,
+                   unwrap!(us.read(&mut buf), "read should have returned EOF (0)"));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn shared_port() {
+        let addr = unwrap!("127.0.0.1:0".parse());
+        let first = unwrap!(listen_shared(&common::Tcp, &addr, &SocketConfig::default()));
+        let addr = unwrap!(first.local_addr());
+        let second = unwrap!(listen_shared(&common::Tcp, &addr, &SocketConfig::default()));
+        assert_eq!(unwrap!(second.local_addr()), addr);
+    }
 }
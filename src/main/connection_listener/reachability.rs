@@ -0,0 +1,178 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use nat::{MappedAddr, MappedAddrSource};
+use std::net::SocketAddr;
+
+/// How peers outside our network reach a listener, as found by having a helper peer connect back
+/// to its external addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortForwarding {
+    /// Reached on a global address of one of our own interfaces, with no NAT in the way.
+    Direct,
+    /// Reached on a port our router forwarded to us when asked over UPnP IGD, NAT-PMP or PCP.
+    Upnp,
+    /// Reached on an external address from the config, i.e. a port forwarded by hand or a 1:1
+    /// NAT.
+    Manual,
+    /// Reached on the external address peers saw our socket at, which a NAT keeping one mapping
+    /// for all destinations (full cone) lets anyone connect to - unless the port also happens to
+    /// be forwarded by hand.
+    Stun,
+    /// A helper peer tried and could not reach it, so the router needs configuring for peers to
+    /// connect to us.
+    Unreachable,
+    /// Not found out yet, e.g. no helper peer could be asked.
+    Unknown,
+}
+
+/// How a listener can be reached from outside our network, see `Service::external_reachability`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenerReachability {
+    /// The address the listener is bound to.
+    pub local_addr: SocketAddr,
+    /// The best way it is reached by, over all of `addrs`.
+    pub forwarding: PortForwarding,
+    /// Each external address it was advertised on and how that is reached.
+    pub addrs: Vec<(SocketAddr, PortForwarding)>,
+}
+
+impl ListenerReachability {
+    /// `external` of a listener bound to `local_addr`, before any of them is checked.
+    pub fn unchecked(local_addr: SocketAddr, external: &[MappedAddr]) -> Self {
+        let addrs = external
+            .iter()
+            .map(|mapped| (mapped.addr, PortForwarding::Unknown))
+            .collect();
+        ListenerReachability::from_addrs(local_addr, addrs)
+    }
+
+    /// How each of `external` is reached once checked: verified ones were reached, those in
+    /// `unreachable` were not and the rest are not known about.
+    pub fn checked(local_addr: SocketAddr,
+                   external: &[MappedAddr],
+                   unreachable: &[SocketAddr])
+                   -> Self {
+        let addrs = external
+            .iter()
+            .map(|mapped| {
+                let forwarding = if unreachable.contains(&mapped.addr) {
+                    PortForwarding::Unreachable
+                } else if !mapped.verified {
+                    PortForwarding::Unknown
+                } else {
+                    match mapped.source {
+                        MappedAddrSource::Local => PortForwarding::Direct,
+                        MappedAddrSource::Router => PortForwarding::Upnp,
+                        MappedAddrSource::Stun => PortForwarding::Stun,
+                        MappedAddrSource::Configured => PortForwarding::Manual,
+                    }
+                };
+                (mapped.addr, forwarding)
+            })
+            .collect();
+        ListenerReachability::from_addrs(local_addr, addrs)
+    }
+
+    /// The external address to serve peers as a traversal helper on, if the listener is reached
+    /// there without us having to connect out first.
+    pub fn helper_addr(&self) -> Option<SocketAddr> {
+        match self.forwarding {
+            PortForwarding::Direct |
+            PortForwarding::Upnp |
+            PortForwarding::Manual |
+            PortForwarding::Stun => {
+                self.addrs
+                    .iter()
+                    .find(|&&(_, forwarding)| forwarding == self.forwarding)
+                    .map(|&(addr, _)| addr)
+            }
+            PortForwarding::Unreachable | PortForwarding::Unknown => None,
+        }
+    }
+
+    fn from_addrs(local_addr: SocketAddr, addrs: Vec<(SocketAddr, PortForwarding)>) -> Self {
+        let reached_by = |forwarding| addrs.iter().any(|&(_, f)| f == forwarding);
+        let forwarding = if reached_by(PortForwarding::Direct) {
+            PortForwarding::Direct
+        } else if reached_by(PortForwarding::Upnp) {
+            PortForwarding::Upnp
+        } else if reached_by(PortForwarding::Manual) {
+            PortForwarding::Manual
+        } else if reached_by(PortForwarding::Stun) {
+            PortForwarding::Stun
+        } else if !addrs.is_empty() &&
+                  addrs
+                      .iter()
+                      .all(|&(_, f)| f == PortForwarding::Unreachable) {
+            PortForwarding::Unreachable
+        } else {
+            PortForwarding::Unknown
+        };
+        ListenerReachability {
+            local_addr: local_addr,
+            forwarding: forwarding,
+            addrs: addrs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_by_source() {
+        let local = unwrap!("192.168.0.2:5483".parse());
+        let upnp = unwrap!("8.8.8.8:5483".parse());
+        let stun = unwrap!("8.8.8.8:5484".parse());
+        let unchecked = unwrap!("8.8.4.4:5483".parse());
+        let manual = unwrap!("8.8.4.4:5484".parse());
+
+        let external = vec![MappedAddr::new(upnp, MappedAddrSource::Router),
+                            MappedAddr::new(stun, MappedAddrSource::Stun),
+                            MappedAddr::new(unchecked, MappedAddrSource::Stun)];
+        let reachability = ListenerReachability::unchecked(local, &external);
+        assert_eq!(reachability.forwarding, PortForwarding::Unknown);
+        assert_eq!(reachability.helper_addr(), None);
+
+        let mut checked = external.clone();
+        checked[1].verified = true;
+        let reachability = ListenerReachability::checked(local, &checked, &[upnp]);
+        assert_eq!(reachability.forwarding, PortForwarding::Stun);
+        assert_eq!(reachability.helper_addr(), Some(stun));
+        assert_eq!(reachability.addrs,
+                   vec![(upnp, PortForwarding::Unreachable),
+                        (stun, PortForwarding::Stun),
+                        (unchecked, PortForwarding::Unknown)]);
+
+        // A port forwarded by hand ranks above one the NAT happens to leave open
+        let mut configured = MappedAddr::new(manual, MappedAddrSource::Configured);
+        configured.verified = true;
+        checked.push(configured);
+        let reachability = ListenerReachability::checked(local, &checked, &[upnp]);
+        assert_eq!(reachability.forwarding, PortForwarding::Manual);
+        assert_eq!(reachability.helper_addr(), Some(manual));
+
+        let reachability =
+            ListenerReachability::checked(local, &external, &[upnp, stun, unchecked]);
+        assert_eq!(reachability.forwarding, PortForwarding::Unreachable);
+        assert_eq!(reachability.helper_addr(), None);
+        let reachability = ListenerReachability::checked(local, &[], &[]);
+        assert_eq!(reachability.forwarding, PortForwarding::Unknown);
+    }
+}
@@ -0,0 +1,349 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{BandwidthLimits, Core, CoreTimer, Message, QueueLimits, Socket, State, Timeout};
+use mio::{Poll, PollOpt, Ready, Token};
+use rust_sodium::crypto::box_::PublicKey;
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::time::Duration;
+
+const PAIRING_TIMEOUT_SEC: u64 = 60;
+// Most ends waiting for their counterpart at once, in all and from any one IP.
+const MAX_WAITING: usize = 256;
+const MAX_WAITING_PER_IP: usize = 4;
+const PAIRING_TIMER_ID: u8 = 0;
+const SLOW_PEER_TIMER_ID: u8 = 1;
+const THROTTLE_TIMER_ID: u8 = 2;
+
+/// Relay ends waiting for their counterpart.
+pub type RelayMap = Rc<RefCell<Relays>>;
+
+/// Relay ends waiting for their counterpart, keyed by (from, to) public keys, along with the IP
+/// each asked from.
+#[derive(Default)]
+pub struct Relays {
+    waiting: HashMap<(PublicKey, PublicKey), (Token, IpAddr)>,
+}
+
+impl Relays {
+    /// Whether `from`, asking from `ip`, may be paired up with `to`. The counterpart of an end
+    /// waiting always may, while another end for the same pair may not as long as the first one
+    /// waits, and no one may once too many ends wait in all or from `ip`.
+    pub fn admits(&self, from: &PublicKey, to: &PublicKey, ip: IpAddr) -> bool {
+        if self.waiting.contains_key(&(*to, *from)) {
+            return true;
+        }
+        if self.waiting.contains_key(&(*from, *to)) || self.waiting.len() >= MAX_WAITING {
+            return false;
+        }
+        self.waiting
+            .values()
+            .filter(|&&(_, waiting_ip)| waiting_ip == ip)
+            .count() < MAX_WAITING_PER_IP
+    }
+}
+
+/// One end of a connection we relay between two peers that cannot reach each other directly.
+///
+/// The first peer to ask waits for the other one, having proven that it is the peer it asks as.
+/// Once both are here every message read from one
+/// end is written to the other as is, so to the peers it looks like any other connection and the
+/// usual connect handshake runs over it. What is queued for either end counts towards our queue
+/// limits, and an end not reading what is relayed to it is dropped like a slow peer would be.
+/// Either end reads and writes within our bandwidth limits as a connection of our own would.
+pub struct Relay {
+    token: Token,
+    socket: Socket,
+    key: (PublicKey, PublicKey),
+    relays: RelayMap,
+    other_end: Option<Token>,
+    timeout: Option<Timeout>,
+    queue_limits: QueueLimits,
+    slow_peer_timeout: Option<Timeout>,
+    throttle_timeout: Option<Timeout>,
+}
+
+impl Relay {
+    pub fn start(core: &mut Core,
+                 poll: &Poll,
+                 token: Token,
+                 mut socket: Socket,
+                 from: PublicKey,
+                 to: PublicKey,
+                 ip: IpAddr,
+                 relays: RelayMap,
+                 bandwidth: &BandwidthLimits)
+                 -> ::Res<()> {
+        let queue_limits = bandwidth.queue_limits().clone();
+        socket.set_queue_limits(queue_limits.clone());
+        socket.set_rate_limit(bandwidth.for_connection());
+        poll.reregister(&socket,
+                        token,
+                        Ready::error() | Ready::hup() | Ready::readable(),
+                        PollOpt::edge())?;
+
+        let other_end = relays
+            .borrow_mut()
+            .waiting
+            .remove(&(to, from))
+            .map(|(token, _)| token);
+        let timeout = match other_end {
+            Some(_) => None,
+            None => {
+                Some(core.set_timeout(Duration::from_secs(PAIRING_TIMEOUT_SEC),
+                                      CoreTimer::new(token, PAIRING_TIMER_ID))?)
+            }
+        };
+
+        let state = Rc::new(RefCell::new(Relay {
+                                             token: token,
+                                             socket: socket,
+                                             key: (from, to),
+                                             relays: relays.clone(),
+                                             other_end: other_end,
+                                             timeout: timeout,
+                                             queue_limits: queue_limits,
+                                             slow_peer_timeout: None,
+                                             throttle_timeout: None,
+                                         }));
+        let _ = core.insert_state(token, state.clone());
+        state.borrow_mut().schedule_slow_peer_check(core, poll);
+
+        let other_end = match other_end.and_then(|other_end| core.get_state(other_end)) {
+            Some(other_end) => other_end,
+            None => {
+                let _ = relays
+                    .borrow_mut()
+                    .waiting
+                    .insert((from, to), (token, ip));
+                return Ok(());
+            }
+        };
+
+        trace!("Relaying between {:?} and {:?}", from, to);
+        if let Some(other_end) = other_end.borrow_mut().as_any().downcast_mut::<Relay>() {
+            other_end.paired(core, token);
+        }
+        // Either side may have sent something already, which edge triggering won't tell us about
+        other_end.borrow_mut().ready(core, poll, Ready::readable());
+        state.borrow_mut().ready(core, poll, Ready::readable());
+
+        Ok(())
+    }
+
+    fn paired(&mut self, core: &mut Core, other_end: Token) {
+        self.other_end = Some(other_end);
+        if let Some(timeout) = self.timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
+    }
+
+    fn read(&mut self, core: &mut Core, poll: &Poll) {
+        let other_end = match self.other_end {
+            Some(other_end) => other_end,
+            None => return,
+        };
+
+        loop {
+            match self.socket.read::<Message>() {
+                Ok(Some(msg)) => {
+                    let other_end = match core.get_state(other_end) {
+                        Some(state) => state,
+                        None => return self.terminate(core, poll),
+                    };
+                    let forwarded = match other_end.borrow_mut().as_any().downcast_mut::<Relay>() {
+                        Some(relay) => relay.forward(core, poll, msg),
+                        None => false,
+                    };
+                    if !forwarded {
+                        return self.terminate(core, poll);
+                    }
+                }
+                Ok(None) => return self.schedule_throttled(core),
+                Err(e) => {
+                    trace!("Relayed connection closed: {:?}", e);
+                    return self.terminate(core, poll);
+                }
+            }
+        }
+    }
+
+    // Failure is left to the caller to act upon as it is the one holding a borrow of the other end.
+    // A full queue is a failure too, unless the queue limits have us drop something for it.
+    fn forward(&mut self, core: &mut Core, poll: &Poll, msg: Message) -> bool {
+        let admitted = Socket::frame_len(&msg, 0).map_or(false, |len| self.socket.admit(len, 0));
+        if !admitted {
+            debug!("Queue of relayed connection to {:?} is full", self.key.0);
+            return false;
+        }
+        if self.socket.write(poll, self.token, Some((msg, 0))).is_err() {
+            return false;
+        }
+        self.schedule_throttled(core);
+        true
+    }
+
+    // Retry reading and writing once the rate limit allows, if it held either back.
+    fn schedule_throttled(&mut self, core: &mut Core) {
+        if self.throttle_timeout.is_some() {
+            return;
+        }
+        if let Some(wait) = self.socket.throttled_for() {
+            match core.set_timeout(wait, CoreTimer::new(self.token, THROTTLE_TIMER_ID)) {
+                Ok(timeout) => self.throttle_timeout = Some(timeout),
+                Err(e) => debug!("Failed to set throttle timer for relayed connection: {:?}", e),
+            }
+        }
+    }
+
+    // Checked twice per `slow_after`, as for our own connections.
+    fn schedule_slow_peer_check(&mut self, core: &mut Core, poll: &Poll) {
+        let period = match self.queue_limits.slow_after {
+            Some(after) => cmp::max(after / 2, Duration::from_millis(100)),
+            None => return,
+        };
+        match core.set_timeout(period, CoreTimer::new(self.token, SLOW_PEER_TIMER_ID)) {
+            Ok(timeout) => self.slow_peer_timeout = Some(timeout),
+            Err(e) => {
+                debug!("Failed to set slow peer timer for relayed connection: {:?}", e);
+                self.terminate(core, poll);
+            }
+        }
+    }
+
+    // There is no one to tell about a slow end, so it is only dropped if slow peers are to be.
+    fn check_slow_peer(&mut self, core: &mut Core, poll: &Poll) {
+        self.slow_peer_timeout = None;
+        let slow = match (self.queue_limits.slow_after, self.socket.write_stall()) {
+            (Some(after), Some(stall)) => stall >= after,
+            _ => false,
+        };
+        if slow {
+            debug!("{:?} is not reading what we relay to it", self.key.0);
+            if self.queue_limits.disconnect_slow {
+                return self.terminate(core, poll);
+            }
+        }
+        self.schedule_slow_peer_check(core, poll);
+    }
+}
+
+impl State for Relay {
+    fn name(&self) -> &'static str {
+        "Relay"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        if kind.is_error() || kind.is_hup() {
+            return self.terminate(core, poll);
+        }
+        if kind.is_writable() {
+            if self.socket.write::<Message>(poll, self.token, None).is_err() {
+                return self.terminate(core, poll);
+            }
+            self.schedule_throttled(core);
+        }
+        if kind.is_readable() {
+            self.read(core, poll);
+        }
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, timer_id: u8) {
+        if timer_id == THROTTLE_TIMER_ID {
+            self.throttle_timeout = None;
+            return self.ready(core, poll, Ready::readable() | Ready::writable());
+        }
+        if timer_id == SLOW_PEER_TIMER_ID {
+            return self.check_slow_peer(core, poll);
+        }
+        trace!("Gave up waiting for {:?} to ask for relaying", self.key.1);
+        self.terminate(core, poll);
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        let _ = core.remove_state(self.token);
+        let _ = poll.deregister(&self.socket);
+        if let Some(timeout) = self.timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
+        if let Some(timeout) = self.slow_peer_timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
+        if let Some(timeout) = self.throttle_timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
+
+        {
+            let mut relays = self.relays.borrow_mut();
+            if relays.waiting.get(&self.key).map(|&(token, _)| token) == Some(self.token) {
+                let _ = relays.waiting.remove(&self.key);
+            }
+        }
+
+        if let Some(other_end) = self.other_end.take().and_then(|token| core.get_state(token)) {
+            other_end.borrow_mut().terminate(core, poll);
+        }
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(n: usize) -> PublicKey {
+        let mut bytes = [0; 32];
+        bytes[0] = n as u8;
+        bytes[1] = (n >> 8) as u8;
+        PublicKey(bytes)
+    }
+
+    #[test]
+    fn admission() {
+        let ip = unwrap!("10.0.0.1".parse());
+        let other_ip = unwrap!("10.0.0.2".parse());
+        let mut relays = Relays::default();
+        assert!(relays.admits(&key(0), &key(1), ip));
+        let _ = relays.waiting.insert((key(0), key(1)), (Token(0), ip));
+
+        // Not a second time while the first waits, though its counterpart may come along
+        assert!(!relays.admits(&key(0), &key(1), other_ip));
+        assert!(relays.admits(&key(1), &key(0), ip));
+
+        for i in 1..MAX_WAITING_PER_IP {
+            let _ = relays.waiting.insert((key(0), key(1 + i)), (Token(i), ip));
+        }
+        assert!(!relays.admits(&key(10), &key(0), ip));
+        assert!(relays.admits(&key(10), &key(0), other_ip));
+        assert!(relays.admits(&key(1), &key(0), ip));
+
+        for i in MAX_WAITING_PER_IP..MAX_WAITING {
+            let ip = IpAddr::from([10, 1, (i >> 8) as u8, i as u8]);
+            let _ = relays.waiting.insert((key(1000 + i), key(0)), (Token(i), ip));
+        }
+        assert!(!relays.admits(&key(10), &key(0), other_ip));
+        assert!(relays.admits(&key(1), &key(0), ip));
+    }
+}
@@ -21,6 +21,7 @@ use maidsafe_utilities::serialisation::SerialisationError;
 use main::PeerId;
 use mio;
 use nat;
+use serde_json;
 use service_discovery;
 use std::io;
 use std::sync::mpsc;
@@ -43,6 +44,26 @@ quick_error! {
             cause(e)
             from()
         }
+        /// A `CRUST_*` environment variable has a value its config field cannot take
+        ConfigEnvVar(name: String, value: String) {
+            description("Invalid config value in environment variable")
+            display("Invalid config value in environment variable {}: {:?}", name, value)
+        }
+        /// An IP whitelist entry that is neither an IP nor a CIDR range
+        InvalidIpRange(range: String) {
+            description("Invalid IP range")
+            display("Invalid IP range: {:?}", range)
+        }
+        /// `Config::bind_device` that cannot be the name of a network interface
+        InvalidDeviceName(name: String) {
+            description("Invalid network interface name")
+            display("Invalid network interface name: {:?}", name)
+        }
+        /// Config values that cannot be used together or at all
+        InvalidConfig(reason: &'static str) {
+            description("Invalid config")
+            display("Invalid config: {}", reason)
+        }
         /// Wrapper for a `std::io::Error`
         Io(e: io::Error) {
             description("IO error")
@@ -80,11 +101,32 @@ quick_error! {
             cause(e)
             from()
         }
+        /// Too much data is already waiting to be sent, so the caller should back off and try
+        /// again later
+        EventLoopBusy {
+            description("Event loop is busy")
+            display("Too much data is already queued on the event loop")
+        }
+        /// As many bytes as `Config::max_queued_bytes` allows are already queued for sending
+        QueueFull {
+            description("Send queue is full")
+            display("Too much data is already queued for sending")
+        }
+        /// The service was paused with `Service::pause_network`
+        NetworkPaused {
+            description("Network activity is paused")
+            display("Network activity is paused")
+        }
         /// Peer not found
         PeerNotFound(peer_id: PeerId) {
             description("Peer not found")
             display("Peer {:?} not found", peer_id)
         }
+        /// Peer banned for a while for failing handshakes or misbehaving, see `Service::unban`
+        PeerBanned(peer_id: PeerId) {
+            description("Peer banned")
+            display("Peer {:?} is banned for now", peer_id)
+        }
         /// Serialisation error
         Serialisation(e: SerialisationError) {
             description("Serialisation error")
@@ -92,10 +134,52 @@ quick_error! {
             cause(e)
             from()
         }
+        /// JSON encoding error
+        Json(e: serde_json::Error) {
+            description("JSON error")
+            display("JSON error: {}", e)
+            cause(e)
+            from()
+        }
+        /// Out-of-band data was encoded with a schema version we do not read
+        UnsupportedSchema(version: u32) {
+            description("Unsupported schema version")
+            display("Unsupported schema version {}", version)
+        }
+        /// Out-of-band data was too short to hold even its schema version
+        TruncatedOutOfBand(len: usize) {
+            description("Out-of-band data too short")
+            display("Out-of-band data of {} bytes is too short to hold its schema version", len)
+        }
+        /// Sealed connection info failed to decrypt or verify
+        InvalidConnectionInfo(reason: &'static str) {
+            description("Invalid sealed connection info")
+            display("Invalid sealed connection info: {}", reason)
+        }
+        /// Connection info was connected with after it expired
+        ConnectionInfoExpired {
+            description("Connection info expired")
+            display("Connection info expired")
+        }
+        /// Connection info was connected with a second time
+        ConnectionInfoReplayed {
+            description("Connection info already used")
+            display("Connection info already used")
+        }
         /// Requested connect to self
         RequestedConnectToSelf {
             description("Requested connection to self")
             display("Requested connection to self")
         }
+        /// Could not connect to the peer
+        ConnectFailed(peer_id: PeerId) {
+            description("Failed to connect to peer")
+            display("Failed to connect to peer {:?}", peer_id)
+        }
+        /// A message was dropped, or its connection lost, before it was written in full
+        SendFailed(peer_id: PeerId) {
+            description("Failed to send message")
+            display("Failed to send message to peer {:?}", peer_id)
+        }
     }
 }
@@ -15,37 +15,122 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-use super::ConnectionInfoResult;
+use super::{CandidatesResult, ConnectionInfoResult, UdpHolePunchResult};
 
-use super::PeerId;
-use common::CrustUser;
+use super::{BootstrapFailure, PeerId, StreamId};
+use common::{CrustUser, Identity, StateCrash};
+use maidsafe_utilities::event_sender::{MaidSafeEventCategory, MaidSafeObserver};
+use nat::{NatDiagnostics, NatType};
 use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver};
+
+/// Identifies a message sent with `Service::send_tracked` in the events telling how it fared.
+pub type SendToken = u64;
+
+/// A channel to hand the sending half of to `Service::new` or `Service::with_config`, every
+/// `Event` of the service then being received on the other half. For when events are read from
+/// a channel of their own rather than one shared with other `MaidSafeObserver`s.
+pub fn event_channel() -> (::CrustEventSender, Receiver<Event>) {
+    let (event_tx, event_rx) = mpsc::channel();
+    let (category_tx, _) = mpsc::channel();
+    (MaidSafeObserver::new(event_tx, MaidSafeEventCategory::Crust, category_tx), event_rx)
+}
 
 /// Enum representing different events that will be sent over the asynchronous channel to the user
 /// of this module.
+///
+/// The `PeerId` passed by the connection events wraps the static public key the peer proved in
+/// the key exchange starting every connection, and they are sent only once that exchange is done
+/// and everything on the connection is sealed.
 #[derive(Debug)]
 pub enum Event {
-    /// Invoked when a bootstrap peer connects to us
-    BootstrapAccept(PeerId, CrustUser),
-    /// Invoked when we bootstrap to a new peer.
-    BootstrapConnect(PeerId, SocketAddr),
-    /// Invoked when we failed to connect to all bootstrap contacts.
-    BootstrapFailed,
+    /// Invoked when a bootstrap peer connects to us. Passes its static key and the identity it
+    /// proved.
+    BootstrapAccept(PeerId, CrustUser, Identity),
+    /// Invoked when we bootstrap to a new peer. Passes its static key and the identity it proved.
+    BootstrapConnect(PeerId, SocketAddr, Identity),
+    /// Invoked when we failed to connect to all bootstrap contacts. Passes why each contact tried
+    /// failed.
+    BootstrapFailed(Vec<(SocketAddr, BootstrapFailure)>),
     /// Invoked when we are ready to listen for incomming connection. Contains
-    /// the listening port.
-    ListenerStarted(u16),
-    /// Invoked when listener failed to start.
-    ListenerFailed,
+    /// the address the listener is bound to.
+    ListenerStarted(SocketAddr),
+    /// Invoked when listener failed to start, or stopped on an error. Contains the address it was
+    /// to listen on or was bound to, if one is known.
+    ListenerFailed(Option<SocketAddr>),
     /// Invoked as a result to the call of `Service::prepare_contact_info`.
     ConnectionInfoPrepared(ConnectionInfoResult),
-    /// Invoked when connection to a new peer has been established.
-    ConnectSuccess(PeerId),
+    /// Invoked as a result to the call of `Service::gather_candidates`.
+    CandidatesGathered(CandidatesResult),
+    /// Invoked as a result to the call of `Service::punch_udp_hole`.
+    UdpHolePunched(UdpHolePunchResult),
+    /// Invoked when connection to a new peer has been established. Passes its static key and the
+    /// identity it proved.
+    ConnectSuccess(PeerId, Identity),
     /// Invoked when connection to a new peer has failed.
     ConnectFailure(PeerId),
     /// Invoked when a peer disconnects or can no longer be contacted.
     LostPeer(PeerId),
+    /// Invoked when a lost connection to a peer has been re-established, see
+    /// `Config::reconnect_attempts`. Passes the static key it proved anew. No `LostPeer` is sent
+    /// for it.
+    PeerReconnected(PeerId),
     /// Invoked when a new message is received. Passes the message.
     NewMessage(PeerId, Vec<u8>),
+    /// Invoked with part of a message too large to be received whole, see
+    /// `Config::stream_oversized_messages`, and whether it is the last part. The parts of a
+    /// message come in order with no other message from the peer in between.
+    NewMessageChunk(PeerId, Vec<u8>, bool),
+    /// Invoked when a peer opens a substream of its connection to us, see `Service::open_stream`.
+    StreamOpened(PeerId, StreamId),
+    /// Invoked when a message is received on a substream. The peer may only send so much more on
+    /// it until told of the message being dealt with by `Service::consume_stream`.
+    NewStreamMessage(PeerId, StreamId, Vec<u8>),
+    /// Invoked when a substream is closed by the peer or refused by it on opening, or closed by us
+    /// for the peer sending more on it than it was allowed.
+    StreamClosed(PeerId, StreamId),
     /// Invoked when trying to sending a too large data.
     WriteMsgSizeProhibitive(PeerId, Vec<u8>),
+    /// Invoked once a message sent with `Service::send_tracked` has been written to the socket in
+    /// full. It is not yet known to have reached the peer.
+    MessageSent(PeerId, SendToken),
+    /// Invoked when a message sent with `Service::send_tracked` is dropped for want of bandwidth
+    /// or refused for its size, or its connection is lost, before it was written in full.
+    MessageNotSent(PeerId, SendToken),
+    /// Invoked when a message to a peer would go over `Config::max_queued_bytes_per_peer` or
+    /// `Config::max_queued_bytes`, as it is refused, lower priority messages are dropped or the
+    /// peer is disconnected according to `Config::queue_full_policy`. Sent once each time the
+    /// queue fills up.
+    QueueFull(PeerId),
+    /// Invoked when no data has been sent to a peer for `Config::slow_peer_ms` while some was
+    /// waiting to be, as it is disconnected if `Config::disconnect_slow_peers`. Sent once each
+    /// time the peer falls behind.
+    SlowPeer(PeerId),
+    /// Invoked when a peer sends more than `Config::peer_max_msgs_per_sec` or
+    /// `Config::peer_max_bytes_per_sec` allow, as it is throttled or disconnected according to
+    /// `Config::peer_quota_policy`. Sent once each time the peer goes over.
+    PeerQuotaExceeded(PeerId),
+    /// Invoked as a result to the call of `Service::detect_nat_type`.
+    NatTypeDetected(Option<NatType>),
+    /// Invoked as a result to the call of `Service::diagnose_nat`, `None` meaning the diagnosis
+    /// could not be run at all.
+    NatDiagnosed(Option<NatDiagnostics>),
+    /// Invoked as a result to the call of `Service::query_ext_addr`, with the address the peer
+    /// sees us as over the connection, or `None` if it could not tell us, e.g. as the connection
+    /// is relayed or it did not answer in time.
+    ExternalAddrReported(PeerId, Option<SocketAddr>),
+    /// Invoked as a result to the call of `Service::rendezvous`, with the key met at and the other
+    /// client's external address and info, or `None` if no other client came in time.
+    RendezvousMet(Vec<u8>, Option<(SocketAddr, Vec<u8>)>),
+    /// Invoked when a router stops forwarding one of our mapped external addresses. Traversal
+    /// should be redone (e.g. by restarting the listener) for it to be reachable again.
+    MappingLost(SocketAddr),
+    /// Invoked when our network interfaces have changed, e.g. on moving from Wi-Fi to ethernet.
+    /// Mappings made before are likely void, so the listeners are mapped and advertised anew,
+    /// and fresh connection info should be handed out.
+    NetworkChanged,
+    /// Invoked when one of the service's state machines, e.g. a connection or a mapping,
+    /// panicked and was terminated. Everything else carries on, but whatever the state was doing
+    /// is lost, e.g. a connection without a `LostPeer` for it.
+    StateCrashed(StateCrash),
 }
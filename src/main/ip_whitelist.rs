@@ -0,0 +1,258 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{CrustUser, lock};
+use main::{Config, CrustError};
+use nat;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+// How many IPs rejections are counted for. Once full, the least rejected IP makes way for a new
+// one, so that churning through addresses (as over IPv6 is cheap) cannot grow it without bound.
+const MAX_REJECTED_IPS: usize = 1024;
+
+/// An IP address, or a CIDR range of them such as `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, *ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = CrustError;
+
+    fn from_str(s: &str) -> ::Res<Self> {
+        let invalid = || CrustError::InvalidIpRange(s.to_owned());
+        let mut parts = s.trim().splitn(2, '/');
+        let addr = IpAddr::from_str(unwrap!(parts.next()))
+            .map_err(|_| invalid())?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => u8::from_str(prefix).map_err(|_| invalid())?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+        Ok(IpRange {
+               addr: addr,
+               prefix: prefix,
+           })
+    }
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let whole = (prefix / 8) as usize;
+    let rest = prefix % 8;
+    net[..whole] == ip[..whole] && (rest == 0 || (net[whole] ^ ip[whole]) >> (8 - rest) == 0)
+}
+
+fn parse_ranges(ranges: &Option<Vec<String>>) -> ::Res<Option<Vec<IpRange>>> {
+    match *ranges {
+        Some(ref ranges) => {
+            Ok(Some(ranges
+                        .iter()
+                        .map(|range| IpRange::from_str(range))
+                        .collect::<::Res<_>>()?))
+        }
+        None => Ok(None),
+    }
+}
+
+struct Inner {
+    // `None` lets anyone of that kind in.
+    nodes: Option<Vec<IpRange>>,
+    clients: Option<Vec<IpRange>>,
+    rejected: HashMap<IpAddr, u64>,
+}
+
+impl Inner {
+    fn lists(&self, user: Option<CrustUser>) -> Vec<&Option<Vec<IpRange>>> {
+        match user {
+            Some(CrustUser::Node) => vec![&self.nodes],
+            Some(CrustUser::Client) => vec![&self.clients],
+            None => vec![&self.nodes, &self.clients],
+        }
+    }
+
+    fn contains(&self, ip: &IpAddr, user: Option<CrustUser>) -> bool {
+        self.lists(user)
+            .into_iter()
+            .any(|list| {
+                     list.as_ref()
+                         .map_or(true, |ranges| ranges.iter().any(|range| range.contains(ip)))
+                 })
+    }
+
+    fn count_rejection(&mut self, ip: IpAddr) {
+        if self.rejected.len() >= MAX_REJECTED_IPS && !self.rejected.contains_key(&ip) {
+            let least = self.rejected
+                .iter()
+                .min_by_key(|&(_, count)| *count)
+                .map(|(ip, _)| *ip);
+            if let Some(least) = least {
+                let _ = self.rejected.remove(&least);
+            }
+        }
+        *self.rejected.entry(ip).or_insert(0) += 1;
+    }
+}
+
+/// The IPs peers may connect from, or be dialed at, as given by `Config::whitelisted_node_ips`
+/// and `Config::whitelisted_client_ips`, along with how often each IP outside them tried to
+/// connect to us and was turned away. Shared by the listener, everything that dials out, and the
+/// `Service`.
+#[derive(Clone)]
+pub struct IpWhitelist {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl IpWhitelist {
+    pub fn new(config: &Config) -> ::Res<Self> {
+        Ok(IpWhitelist {
+               inner: Arc::new(Mutex::new(Inner {
+                                              nodes: parse_ranges(&config.whitelisted_node_ips)?,
+                                              clients:
+                                                  parse_ranges(&config.whitelisted_client_ips)?,
+                                              rejected: HashMap::new(),
+                                          })),
+           })
+    }
+
+    /// Whether the peer at `addr` may handshake with us as `user`, or as either kind if it has
+    /// not said which yet. Counts a rejection against its IP if not.
+    pub fn admits(&self, addr: &SocketAddr, user: Option<CrustUser>) -> bool {
+        let ip = nat::unmap_ipv4(addr).ip();
+        let mut inner = lock(&self.inner);
+        let admitted = inner.contains(&ip, user);
+        if !admitted {
+            debug!("Rejecting {} as it is not whitelisted", addr);
+            inner.count_rejection(ip);
+        }
+        admitted
+    }
+
+    /// Whether we may dial the peer at `addr` as `user`. Unlike `admits`, this is our own choice
+    /// and so not counted as a rejection.
+    pub fn allows(&self, addr: &SocketAddr, user: Option<CrustUser>) -> bool {
+        let ip = nat::unmap_ipv4(addr).ip();
+        lock(&self.inner).contains(&ip, user)
+    }
+
+    /// How many times each IP not whitelisted was turned away, for the most rejected of them.
+    pub fn rejections(&self) -> HashMap<IpAddr, u64> {
+        lock(&self.inner).rejected.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::CrustUser;
+    use main::Config;
+    use std::net::Ipv6Addr;
+
+    #[test]
+    fn ranges() {
+        let range = unwrap!(IpRange::from_str("10.1.0.0/16"));
+        assert!(range.contains(&unwrap!("10.1.200.3".parse())));
+        assert!(!range.contains(&unwrap!("10.2.0.1".parse())));
+        let range = unwrap!(IpRange::from_str("192.168.1.128/25"));
+        assert!(range.contains(&unwrap!("192.168.1.200".parse())));
+        assert!(!range.contains(&unwrap!("192.168.1.127".parse())));
+        let range = unwrap!(IpRange::from_str("fd00::/8"));
+        assert!(range.contains(&unwrap!("fd12::1".parse())));
+        assert!(!range.contains(&unwrap!("10.1.0.1".parse())));
+        let range = unwrap!(IpRange::from_str("10.0.0.1"));
+        assert!(range.contains(&unwrap!("10.0.0.1".parse())));
+        assert!(!range.contains(&unwrap!("10.0.0.2".parse())));
+
+        assert!(IpRange::from_str("10.0.0.0/33").is_err());
+        assert!(IpRange::from_str("10.0.0/8").is_err());
+    }
+
+    #[test]
+    fn admits() {
+        let node = unwrap!("10.0.0.1:5483".parse());
+        let client = unwrap!("192.168.0.7:5483".parse());
+        let stranger = unwrap!("172.16.0.1:5483".parse());
+        let mut config = Config::default();
+        config.whitelisted_node_ips = Some(vec!["10.0.0.0/8".to_owned()]);
+        config.whitelisted_client_ips = Some(vec!["192.168.0.0/24".to_owned()]);
+        let whitelist = unwrap!(IpWhitelist::new(&config));
+
+        assert!(whitelist.admits(&node, None));
+        assert!(whitelist.admits(&client, None));
+        assert!(whitelist.admits(&node, Some(CrustUser::Node)));
+        assert!(!whitelist.admits(&client, Some(CrustUser::Node)));
+        assert!(!whitelist.admits(&stranger, None));
+        assert!(!whitelist.admits(&stranger, None));
+
+        let rejections = whitelist.rejections();
+        assert_eq!(rejections.get(&stranger.ip()), Some(&2));
+        assert_eq!(rejections.get(&client.ip()), Some(&1));
+
+        // Not dialing an IP is not a rejection
+        let other = unwrap!("172.16.0.2:5483".parse());
+        assert!(!whitelist.allows(&other, Some(CrustUser::Node)));
+        assert!(whitelist.allows(&node, Some(CrustUser::Node)));
+        assert_eq!(whitelist.rejections().get(&other.ip()), None);
+
+        // Without a client whitelist anyone may be a client
+        config.whitelisted_client_ips = None;
+        let whitelist = unwrap!(IpWhitelist::new(&config));
+        assert!(whitelist.admits(&stranger, None));
+        assert!(whitelist.admits(&stranger, Some(CrustUser::Client)));
+        assert!(!whitelist.admits(&stranger, Some(CrustUser::Node)));
+    }
+
+    #[test]
+    fn rejections_bounded() {
+        let mut config = Config::default();
+        config.whitelisted_node_ips = Some(vec!["10.0.0.0/8".to_owned()]);
+        config.whitelisted_client_ips = Some(vec![]);
+        let whitelist = unwrap!(IpWhitelist::new(&config));
+
+        let persistent = unwrap!("172.16.0.1:5483".parse());
+        assert!(!whitelist.admits(&persistent, None));
+        assert!(!whitelist.admits(&persistent, None));
+        for i in 0..2 * MAX_REJECTED_IPS as u16 {
+            let ip = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i);
+            assert!(!whitelist.admits(&SocketAddr::new(IpAddr::V6(ip), 5483), None));
+        }
+
+        let rejections = whitelist.rejections();
+        assert_eq!(rejections.len(), MAX_REJECTED_IPS);
+        assert_eq!(rejections.get(&persistent.ip()), Some(&2));
+    }
+}
@@ -0,0 +1,456 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::lock;
+use maidsafe_utilities::thread::{self, Joiner};
+use main::PeerId;
+use std::collections::HashMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// Upper bounds of the handshake latency histogram buckets.
+const LATENCY_BUCKETS_MS: &'static [u64] = &[10, 50, 100, 250, 500, 1000, 2500, 5000, 10_000];
+// How long the exporter sleeps between checks for scrapes and for being stopped.
+const EXPORTER_POLL_MS: u64 = 100;
+const EXPORTER_IO_TIMEOUT_SECS: u64 = 5;
+
+/// Kinds of handshake whose latencies are recorded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HandshakeKind {
+    /// Us bootstrapping off a peer.
+    Bootstrap,
+    /// A peer bootstrapping off or connecting to us.
+    Accept,
+    /// Us connecting to a peer via `Service::connect`.
+    Connect,
+}
+
+impl HandshakeKind {
+    fn label(&self) -> &'static str {
+        match *self {
+            HandshakeKind::Bootstrap => "bootstrap",
+            HandshakeKind::Accept => "accept",
+            HandshakeKind::Connect => "connect",
+        }
+    }
+}
+
+/// How an attempt to connect to a peer via `Service::connect` came out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TraversalOutcome {
+    /// Connected by dialing one of the peer's direct addresses.
+    Direct,
+    /// Connected by TCP hole punching.
+    HolePunch,
+    /// Connected through the relay.
+    Relay,
+    /// Not connected at all.
+    Failed,
+}
+
+impl TraversalOutcome {
+    /// The outcome as labelled in the metrics and the logs.
+    pub fn label(&self) -> &'static str {
+        match *self {
+            TraversalOutcome::Direct => "direct",
+            TraversalOutcome::HolePunch => "hole_punch",
+            TraversalOutcome::Relay => "relay",
+            TraversalOutcome::Failed => "failed",
+        }
+    }
+}
+
+/// Bytes of messages exchanged with a peer over its current connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PeerTraffic {
+    /// Bytes received from the peer.
+    pub bytes_in: u64,
+    /// Bytes sent to the peer.
+    pub bytes_out: u64,
+}
+
+/// A histogram of handshake latencies.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Latencies {
+    /// For each bucket, its upper bound and how many handshakes took no longer.
+    pub buckets: Vec<(Duration, u64)>,
+    /// Handshakes recorded.
+    pub count: u64,
+    /// Time all the recorded handshakes took together.
+    pub sum: Duration,
+}
+
+impl Latencies {
+    fn record(&mut self, latency: Duration) {
+        if self.buckets.is_empty() {
+            self.buckets = LATENCY_BUCKETS_MS
+                .iter()
+                .map(|ms| (Duration::from_millis(*ms), 0))
+                .collect();
+        }
+        for bucket in &mut self.buckets {
+            if latency <= bucket.0 {
+                bucket.1 += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += latency;
+    }
+}
+
+/// Crust's metrics as they were when `Service::metrics` was called.
+#[derive(Clone, Debug, Default)]
+pub struct MetricsSnapshot {
+    /// Connections to peers open now.
+    pub connections: usize,
+    /// Traffic with each peer connected to now.
+    pub peers: HashMap<PeerId, PeerTraffic>,
+    /// Bytes received from all peers ever connected to.
+    pub bytes_in: u64,
+    /// Bytes sent to all peers ever connected to.
+    pub bytes_out: u64,
+    /// Latencies of the handshakes that succeeded, by kind.
+    pub handshakes: HashMap<HandshakeKind, Latencies>,
+    /// How many attempts to connect to peers came out each way.
+    pub traversals: HashMap<TraversalOutcome, u64>,
+    /// Requests from the `Service` waiting for the event loop to handle them.
+    pub queued_requests: usize,
+}
+
+impl MetricsSnapshot {
+    /// The metrics in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# TYPE crust_connections gauge");
+        let _ = writeln!(out, "crust_connections {}", self.connections);
+
+        let _ = writeln!(out, "# TYPE crust_received_bytes_total counter");
+        let _ = writeln!(out, "crust_received_bytes_total {}", self.bytes_in);
+        let _ = writeln!(out, "# TYPE crust_sent_bytes_total counter");
+        let _ = writeln!(out, "crust_sent_bytes_total {}", self.bytes_out);
+
+        // Only peers connected to now are listed, and over their current connection alone, so
+        // these go down as well as up
+        let _ = writeln!(out, "# TYPE crust_peer_received_bytes gauge");
+        for (peer, traffic) in &self.peers {
+            let _ = writeln!(out,
+                             "crust_peer_received_bytes{{peer=\"{}\"}} {}",
+                             hex(peer),
+                             traffic.bytes_in);
+        }
+        let _ = writeln!(out, "# TYPE crust_peer_sent_bytes gauge");
+        for (peer, traffic) in &self.peers {
+            let _ = writeln!(out,
+                             "crust_peer_sent_bytes{{peer=\"{}\"}} {}",
+                             hex(peer),
+                             traffic.bytes_out);
+        }
+
+        let _ = writeln!(out, "# TYPE crust_handshake_duration_seconds histogram");
+        for (kind, latencies) in &self.handshakes {
+            for &(bound, count) in &latencies.buckets {
+                let _ = writeln!(out,
+                                 "crust_handshake_duration_seconds_bucket{{kind=\"{}\",le=\"{}\"}} \
+                                  {}",
+                                 kind.label(),
+                                 secs(bound),
+                                 count);
+            }
+            let _ = writeln!(out,
+                             "crust_handshake_duration_seconds_bucket{{kind=\"{}\",le=\"+Inf\"}} \
+                              {}",
+                             kind.label(),
+                             latencies.count);
+            let _ = writeln!(out,
+                             "crust_handshake_duration_seconds_sum{{kind=\"{}\"}} {}",
+                             kind.label(),
+                             secs(latencies.sum));
+            let _ = writeln!(out,
+                             "crust_handshake_duration_seconds_count{{kind=\"{}\"}} {}",
+                             kind.label(),
+                             latencies.count);
+        }
+
+        let _ = writeln!(out, "# TYPE crust_traversals_total counter");
+        for (outcome, count) in &self.traversals {
+            let _ = writeln!(out,
+                             "crust_traversals_total{{outcome=\"{}\"}} {}",
+                             outcome.label(),
+                             count);
+        }
+
+        let _ = writeln!(out, "# TYPE crust_queued_requests gauge");
+        let _ = writeln!(out, "crust_queued_requests {}", self.queued_requests);
+        out
+    }
+}
+
+fn hex(peer: &PeerId) -> String {
+    (peer.0)
+        .0
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+#[derive(Default)]
+struct Inner {
+    snapshot: MetricsSnapshot,
+    // Connections open to each peer, so its traffic is forgotten once the last one closes.
+    open: HashMap<PeerId, usize>,
+}
+
+/// Where the metrics are recorded, shared by everything on the event loop and the `Service`.
+/// Recording does nothing unless metrics are enabled in the config.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Option<Arc<Mutex<Inner>>>,
+}
+
+impl Metrics {
+    pub fn new(enabled: bool) -> Self {
+        Metrics {
+            inner: if enabled {
+                Some(Arc::new(Mutex::new(Inner::default())))
+            } else {
+                None
+            },
+        }
+    }
+
+    fn update<F: FnOnce(&mut Inner)>(&self, f: F) {
+        if let Some(ref inner) = self.inner {
+            f(&mut lock(&inner));
+        }
+    }
+
+    pub fn connection_opened(&self, peer: PeerId) {
+        self.update(|inner| {
+            inner.snapshot.connections += 1;
+            *inner.open.entry(peer).or_insert(0) += 1;
+            let _ = inner.snapshot.peers.entry(peer).or_insert_with(PeerTraffic::default);
+        })
+    }
+
+    pub fn connection_closed(&self, peer: PeerId) {
+        self.update(|inner| {
+            inner.snapshot.connections = inner.snapshot.connections.saturating_sub(1);
+            let last = match inner.open.get_mut(&peer) {
+                Some(open) => {
+                    *open -= 1;
+                    *open == 0
+                }
+                None => false,
+            };
+            if last {
+                let _ = inner.open.remove(&peer);
+                let _ = inner.snapshot.peers.remove(&peer);
+            }
+        })
+    }
+
+    pub fn received(&self, peer: PeerId, bytes: usize) {
+        self.update(|inner| {
+            inner.snapshot.bytes_in += bytes as u64;
+            if let Some(traffic) = inner.snapshot.peers.get_mut(&peer) {
+                traffic.bytes_in += bytes as u64;
+            }
+        })
+    }
+
+    pub fn sent(&self, peer: PeerId, bytes: usize) {
+        self.update(|inner| {
+            inner.snapshot.bytes_out += bytes as u64;
+            if let Some(traffic) = inner.snapshot.peers.get_mut(&peer) {
+                traffic.bytes_out += bytes as u64;
+            }
+        })
+    }
+
+    pub fn handshake(&self, kind: HandshakeKind, latency: Duration) {
+        self.update(|inner| {
+                        inner
+                            .snapshot
+                            .handshakes
+                            .entry(kind)
+                            .or_insert_with(Latencies::default)
+                            .record(latency)
+                    })
+    }
+
+    pub fn traversal(&self, outcome: TraversalOutcome) {
+        self.update(|inner| *inner.snapshot.traversals.entry(outcome).or_insert(0) += 1)
+    }
+
+    pub fn request_queued(&self) {
+        self.update(|inner| inner.snapshot.queued_requests += 1)
+    }
+
+    pub fn request_handled(&self) {
+        self.update(|inner| {
+                        inner.snapshot.queued_requests =
+                            inner.snapshot.queued_requests.saturating_sub(1)
+                    })
+    }
+
+    pub fn snapshot(&self) -> Option<MetricsSnapshot> {
+        self.inner
+            .as_ref()
+            .map(|inner| lock(&inner).snapshot.clone())
+    }
+}
+
+/// Serves the metrics over HTTP for Prometheus to scrape, until dropped.
+pub struct MetricsExporter {
+    stop: Arc<AtomicBool>,
+    _joiner: Joiner,
+}
+
+impl MetricsExporter {
+    pub fn start(addr: &SocketAddr, metrics: Metrics) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_clone = stop.clone();
+        let joiner = thread::named("CRUST-Metrics-Exporter", move || {
+            while !stop_clone.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        if let Err(e) = serve(stream, &metrics) {
+                            debug!("Could not serve metrics: {:?}", e);
+                        }
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        ::std::thread::sleep(Duration::from_millis(EXPORTER_POLL_MS))
+                    }
+                    Err(e) => debug!("Metrics exporter failed to accept: {:?}", e),
+                }
+            }
+        });
+        Ok(MetricsExporter {
+               stop: stop,
+               _joiner: joiner,
+           })
+    }
+}
+
+impl Drop for MetricsExporter {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+// Answer whatever was asked with the metrics, as a scrape is all the exporter is for.
+fn serve(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream
+        .set_read_timeout(Some(Duration::from_secs(EXPORTER_IO_TIMEOUT_SECS)))?;
+    stream
+        .set_write_timeout(Some(Duration::from_secs(EXPORTER_IO_TIMEOUT_SECS)))?;
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.ends_with(b"\r\n\r\n") && request.len() < 8 * 1024 {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let body = metrics
+        .snapshot()
+        .map_or_else(String::new, |snapshot| snapshot.to_prometheus());
+    write!(stream,
+           "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: \
+            {}\r\nConnection: close\r\n\r\n{}",
+           body.len(),
+           body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    #[test]
+    fn record() {
+        let disabled = Metrics::default();
+        disabled.connection_opened(rand::random());
+        assert!(disabled.snapshot().is_none());
+
+        let metrics = Metrics::new(true);
+        let peer = rand::random();
+        metrics.connection_opened(peer);
+        metrics.received(peer, 10);
+        metrics.sent(peer, 20);
+        metrics.handshake(HandshakeKind::Connect, Duration::from_millis(200));
+        metrics.traversal(TraversalOutcome::HolePunch);
+        let snapshot = unwrap!(metrics.snapshot());
+        assert_eq!(snapshot.connections, 1);
+        assert_eq!(snapshot.peers[&peer],
+                   PeerTraffic {
+                       bytes_in: 10,
+                       bytes_out: 20,
+                   });
+        let latencies = &snapshot.handshakes[&HandshakeKind::Connect];
+        assert_eq!(latencies.count, 1);
+        assert_eq!(latencies.buckets[2], (Duration::from_millis(100), 0));
+        assert_eq!(latencies.buckets[3], (Duration::from_millis(250), 1));
+        assert_eq!(snapshot.traversals[&TraversalOutcome::HolePunch], 1);
+        let text = snapshot.to_prometheus();
+        assert!(text.contains("crust_connections 1\n"));
+        assert!(text.contains("crust_traversals_total{outcome=\"hole_punch\"} 1\n"));
+
+        // The totals outlive the peer's connection
+        metrics.connection_closed(peer);
+        let snapshot = unwrap!(metrics.snapshot());
+        assert_eq!(snapshot.connections, 0);
+        assert!(snapshot.peers.is_empty());
+        assert_eq!((snapshot.bytes_in, snapshot.bytes_out), (10, 20));
+        let text = snapshot.to_prometheus();
+        assert!(text.contains("crust_sent_bytes_total 20\n"));
+        assert!(!text.contains("crust_peer_sent_bytes{"));
+    }
+
+    #[test]
+    fn export() {
+        let metrics = Metrics::new(true);
+        metrics.connection_opened(rand::random());
+        // Find a free port by binding the exporter to it
+        let listener = unwrap!(::std::net::TcpListener::bind("127.0.0.1:0"));
+        let addr = unwrap!(listener.local_addr());
+        drop(listener);
+        let _exporter = unwrap!(MetricsExporter::start(&addr, metrics));
+
+        let mut stream = unwrap!(TcpStream::connect(&addr));
+        unwrap!(stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n"));
+        let mut response = String::new();
+        let _ = unwrap!(stream.read_to_string(&mut response));
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("crust_connections 1\n"));
+    }
+}
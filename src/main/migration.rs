@@ -0,0 +1,183 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{Core, CoreTimer, Identity, IdentityKeys, IdentityProof, Message, Priority, Socket,
+             SocketConfig, State, Timeout, Transport};
+use main::CrustError;
+use mio::{Poll, PollOpt, Ready, Token};
+use rust_sodium::crypto::box_::PublicKey;
+use std::any::Any;
+use std::cell::RefCell;
+use std::mem;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
+
+const DIAL_TIMEOUT_SEC: u64 = 10;
+
+pub type Finish = Box<FnMut(&mut Core, &Poll, Option<Socket>)>;
+
+/// A connection routed through a relay, which may later move onto a direct path to the peer.
+#[derive(Clone, Debug)]
+pub struct Relayed {
+    /// Addresses to dial the peer directly at. Only one side of a connection dials, so this is
+    /// left empty on the other.
+    pub their_direct: Vec<SocketAddr>,
+    /// Options for the direct socket.
+    pub socket_config: SocketConfig,
+    /// Ours, to prove on the direct path that it leads to the same peer as the relayed one.
+    pub identity: IdentityKeys,
+    /// The identity the peer proved on connecting, which it has to prove again on the direct
+    /// path for us to switch over to it.
+    pub their_identity: Option<Identity>,
+}
+
+/// Dials the peer's direct addresses in turn and asks the first to answer to take over the
+/// relayed connection, proving our identity with `proof`. Finishes with the socket once the
+/// request is sent, without waiting for the peer to accept it, which it confirms on the relayed
+/// path.
+pub struct MigrationDial {
+    token: Token,
+    timeout: Timeout,
+    addrs: Vec<SocketAddr>,
+    socket_config: SocketConfig,
+    transport: Rc<Transport>,
+    socket: Socket,
+    request: Message,
+    pending: Option<(Message, Priority)>,
+    finish: Finish,
+}
+
+impl MigrationDial {
+    pub fn start(core: &mut Core,
+                 poll: &Poll,
+                 relayed: &Relayed,
+                 our_pk: PublicKey,
+                 proof: IdentityProof,
+                 finish: Finish)
+                 -> ::Res<Token> {
+        let token = core.get_new_token();
+        let mut addrs = relayed.their_direct.clone();
+        addrs.reverse();
+
+        let mut state = MigrationDial {
+            token: token,
+            timeout: core.set_timeout(Duration::from_secs(DIAL_TIMEOUT_SEC),
+                                      CoreTimer::new(token, 0))?,
+            addrs: addrs,
+            socket_config: relayed.socket_config,
+            transport: core.transport(),
+            socket: Socket::default(),
+            request: Message::MigrateProven(our_pk, proof),
+            pending: None,
+            finish: finish,
+        };
+
+        // `finish` must not be called before we return, so the first dial is done up front
+        if !state.dial_next(poll) {
+            let _ = core.cancel_timeout(&state.timeout);
+            return Err(CrustError::InsufficientConnectionInfo);
+        }
+
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+
+        Ok(token)
+    }
+
+    fn dial_next(&mut self, poll: &Poll) -> bool {
+        let _ = poll.deregister(&self.socket);
+        while let Some(addr) = self.addrs.pop() {
+            let mut socket = match Socket::dial(&*self.transport, &addr) {
+                Ok(socket) => socket,
+                Err(e) => {
+                    debug!("Could not dial {} directly: {:?}", addr, e);
+                    continue;
+                }
+            };
+            if let Err(e) = socket.configure(&self.socket_config) {
+                debug!("Could not set socket options: {:?}", e);
+            }
+            if poll.register(&socket,
+                             self.token,
+                             Ready::error() | Ready::hup() | Ready::writable(),
+                             PollOpt::edge())
+                   .is_ok() {
+                self.socket = socket;
+                self.pending = Some((self.request.clone(), 0));
+                return true;
+            }
+        }
+        false
+    }
+
+    fn write(&mut self, core: &mut Core, poll: &Poll) {
+        let msg = self.pending.take();
+        match self.socket.write(poll, self.token, msg) {
+            Ok(true) => self.done(core, poll),
+            Ok(false) => (),
+            Err(e) => {
+                debug!("Failed to write migration request: {:?}", e);
+                self.handle_error(core, poll);
+            }
+        }
+    }
+
+    fn done(&mut self, core: &mut Core, poll: &Poll) {
+        let _ = core.remove_state(self.token);
+        let _ = core.cancel_timeout(&self.timeout);
+        let _ = poll.deregister(&self.socket);
+        let socket = mem::replace(&mut self.socket, Socket::default());
+        (*self.finish)(core, poll, Some(socket));
+    }
+
+    fn handle_error(&mut self, core: &mut Core, poll: &Poll) {
+        if !self.dial_next(poll) {
+            self.terminate(core, poll);
+            (*self.finish)(core, poll, None);
+        }
+    }
+}
+
+impl State for MigrationDial {
+    fn name(&self) -> &'static str {
+        "MigrationDial"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        if kind.is_error() || kind.is_hup() {
+            self.handle_error(core, poll);
+        } else if kind.is_writable() {
+            self.write(core, poll);
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        let _ = core.remove_state(self.token);
+        let _ = core.cancel_timeout(&self.timeout);
+        let _ = poll.deregister(&self.socket);
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u8) {
+        debug!("Direct dial for connection migration timed out");
+        self.terminate(core, poll);
+        (*self.finish)(core, poll, None);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
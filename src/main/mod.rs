@@ -15,23 +15,47 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-pub use self::active_connection::{ActiveConnection, INACTIVITY_TIMEOUT_MS};
-pub use self::bootstrap::Bootstrap;
-pub use self::config_handler::Config;
-pub use self::connect::Connect;
+pub use self::active_connection::{ActiveConnection, CompressionPolicy, ConnectionStats,
+                                  HEARTBEAT_PERIOD_MS, INACTIVITY_TIMEOUT_MS, compression_policy,
+                                  drop_policy, inactivity_timeout, keep_alive_batch,
+                                  keep_alive_period, socket_config};
+pub use self::async_service::{AsyncService, Completion, Events};
+pub use self::ban_list::BanList;
+pub use self::candidates::{Candidate, CandidateKind, CandidatePair, CandidateTransport,
+                           CandidatesResult, ConnectionCandidates, Gathering, LocalCandidates,
+                           UdpHolePunchResult};
+pub use self::bootstrap::{Bootstrap, BootstrapFailure, OurRelay, RelayWatch};
+pub use self::config_handler::{Config, ConfigBuilder};
+pub use self::connect::{Connect, RacePolicy, race_policy};
 pub use self::connection_candidate::ConnectionCandidate;
-pub use self::connection_listener::ConnectionListener;
+pub use self::connection_listener::{AcceptLimits, ConnectionListener, ListenerReachability,
+                                    PortForwarding, accept_limits};
 pub use self::error::CrustError;
-pub use self::event::Event;
+pub use self::event::{Event, SendToken, event_channel};
+pub use self::ip_whitelist::IpWhitelist;
+pub use self::metrics::{HandshakeKind, Latencies, Metrics, MetricsExporter, MetricsSnapshot,
+                        PeerTraffic, TraversalOutcome};
+pub use self::migration::{MigrationDial, Relayed};
+pub use self::reconnect::{Reconnect, Reconnecting, reconnect_policy};
+pub use self::mux::{Mux, StreamId};
+pub use self::out_of_band::{OutOfBand, SCHEMA_VERSION};
 pub use self::service::Service;
-pub use self::types::{ConnectionId, ConnectionInfoResult, PeerId, PrivConnectionInfo,
-                      PubConnectionInfo};
+pub use self::types::{ConnectionId, ConnectionInfoResult, OfferLedger, OfferStamp, PeerId,
+                      PrivConnectionInfo, PubConnectionInfo, SealedConnectionInfo};
+use common::Identity;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
 pub type ConnectionMap = Arc<Mutex<HashMap<PeerId, ConnectionId>>>;
+/// Identities peers being connected to must prove, for however the connection comes about.
+pub type ExpectedIdentities = Arc<Mutex<HashMap<PeerId, Identity>>>;
+/// The connection info handed out and connected with, for peers to connect with each once.
+pub type Offers = Arc<Mutex<OfferLedger>>;
 
 mod active_connection;
+mod async_service;
+mod ban_list;
+mod candidates;
 mod bootstrap;
 mod config_handler;
 mod connect;
@@ -39,5 +63,11 @@ mod connection_candidate;
 mod connection_listener;
 mod event;
 mod error;
+mod ip_whitelist;
+mod metrics;
+mod migration;
+mod mux;
+mod out_of_band;
+mod reconnect;
 mod service;
 mod types;
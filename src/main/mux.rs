@@ -0,0 +1,285 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{MSG_DROP_PRIORITY, Message, Priority};
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+
+/// Identifies a substream of a peer connection. The peer with the lower id opens even substreams
+/// and the other odd ones, so they never clash.
+pub type StreamId = u32;
+
+/// Bytes a peer may send on a substream before waiting for more credit.
+const INITIAL_WINDOW: usize = 256 * 1024;
+/// Substreams a peer may have open on one connection.
+const MAX_STREAMS: usize = 256;
+
+#[derive(Debug)]
+struct Substream {
+    // Credit we have left to send with.
+    send_window: usize,
+    queued: VecDeque<(Vec<u8>, Priority)>,
+    // Credit the peer has left to send with, as far as we know.
+    recv_window: usize,
+    // Bytes consumed since we last granted the peer credit.
+    consumed: usize,
+    closing: bool,
+}
+
+impl Substream {
+    fn new() -> Substream {
+        Substream {
+            send_window: INITIAL_WINDOW,
+            queued: VecDeque::new(),
+            recv_window: INITIAL_WINDOW,
+            consumed: 0,
+            closing: false,
+        }
+    }
+}
+
+// Whether `len` bytes fit `window`. A message larger than the whole window fits once everything
+// before it has been credited back, so it cannot get stuck.
+fn fits(len: usize, window: usize) -> bool {
+    len <= window || window == INITIAL_WINDOW
+}
+
+/// Carries independent substreams over a single peer connection, each with its own flow control
+/// so that a bulk transfer on one cannot starve the others.
+///
+/// Data is only sent on a substream while the peer has granted it credit, and the peer grants
+/// more only as the application consumes what it delivered. A peer sending beyond its credit has
+/// the substream closed on it.
+#[derive(Debug)]
+pub struct Mux {
+    // The parity of the ids of the substreams we open.
+    our_parity: StreamId,
+    streams: HashMap<StreamId, Substream>,
+}
+
+impl Mux {
+    /// For a connection on which we open even substreams if `ours_even`, odd ones otherwise.
+    pub fn new(ours_even: bool) -> Self {
+        Mux {
+            our_parity: if ours_even { 0 } else { 1 },
+            streams: HashMap::new(),
+        }
+    }
+
+    /// Open substream `id`, returning the message announcing it or `None` if it is open already or
+    /// is not ours to open.
+    pub fn open(&mut self, id: StreamId) -> Option<(Message, Priority)> {
+        if id % 2 != self.our_parity || self.streams.contains_key(&id) {
+            return None;
+        }
+        let _ = self.streams.insert(id, Substream::new());
+        Some((Message::StreamOpen(id), 0))
+    }
+
+    /// Queue `data` on substream `id`, returning what may be sent right away or `None` if there is
+    /// no such substream.
+    pub fn send(&mut self,
+                id: StreamId,
+                data: Vec<u8>,
+                priority: Priority)
+                -> Option<Vec<(Message, Priority)>> {
+        // A dropped message would never be credited back and so stall the substream.
+        let priority = cmp::min(priority, MSG_DROP_PRIORITY - 1);
+        match self.streams.get_mut(&id) {
+            Some(stream) if !stream.closing => stream.queued.push_back((data, priority)),
+            _ => return None,
+        }
+        Some(self.flush(id))
+    }
+
+    /// Close substream `id` once whatever is queued on it has been sent.
+    pub fn close(&mut self, id: StreamId) -> Vec<(Message, Priority)> {
+        match self.streams.get_mut(&id) {
+            Some(stream) => stream.closing = true,
+            None => return Vec::new(),
+        }
+        self.flush(id)
+    }
+
+    /// The application consumed `len` bytes delivered on substream `id`. Returns the message
+    /// granting the peer more credit, if it is time to.
+    pub fn consumed(&mut self, id: StreamId, len: usize) -> Option<(Message, Priority)> {
+        let stream = match self.streams.get_mut(&id) {
+            Some(stream) => stream,
+            None => return None,
+        };
+        stream.consumed += len;
+        // Granted in batches, or all at once when the peer has nothing left in flight, in case it
+        // is waiting to send a message the credit it has does not fit
+        let outstanding = INITIAL_WINDOW - stream.recv_window;
+        if stream.consumed < INITIAL_WINDOW / 2 && stream.consumed < outstanding {
+            return None;
+        }
+        let credit = cmp::min(stream.consumed, outstanding);
+        stream.consumed = 0;
+        if credit == 0 {
+            return None;
+        }
+        stream.recv_window += credit;
+        Some((Message::StreamWindow(id, credit as u32), 0))
+    }
+
+    /// The peer opened substream `id`. Returns whether it is new to us, or the message refusing it
+    /// if the peer has too many open or the id is one only we open.
+    pub fn handle_open(&mut self, id: StreamId) -> Result<bool, (Message, Priority)> {
+        if id % 2 == self.our_parity {
+            debug!("Peer opened substream {}, which is ours to open", id);
+            // Closing it would close ours of the same id at the peer
+            return if self.streams.contains_key(&id) {
+                       Ok(false)
+                   } else {
+                       Err((Message::StreamClose(id), 0))
+                   };
+        }
+        if self.streams.contains_key(&id) {
+            return Ok(false);
+        }
+        if self.streams.len() >= MAX_STREAMS {
+            return Err((Message::StreamClose(id), 0));
+        }
+        let _ = self.streams.insert(id, Substream::new());
+        Ok(true)
+    }
+
+    /// The peer sent `len` bytes on substream `id`. Returns whether the substream is open, or the
+    /// message closing it if the peer sent more than it had credit for.
+    pub fn handle_data(&mut self, id: StreamId, len: usize) -> Result<bool, (Message, Priority)> {
+        let overrun = match self.streams.get_mut(&id) {
+            Some(stream) => {
+                if fits(len, stream.recv_window) {
+                    stream.recv_window = stream.recv_window.saturating_sub(len);
+                    false
+                } else {
+                    true
+                }
+            }
+            None => return Ok(false),
+        };
+        if overrun {
+            debug!("Peer sent {} bytes on substream {} beyond its credit", len, id);
+            let _ = self.streams.remove(&id);
+            return Err((Message::StreamClose(id), 0));
+        }
+        Ok(true)
+    }
+
+    /// The peer granted substream `id` more credit, returning what may now be sent.
+    pub fn handle_window(&mut self, id: StreamId, credit: u32) -> Vec<(Message, Priority)> {
+        match self.streams.get_mut(&id) {
+            Some(stream) => {
+                stream.send_window = cmp::min(stream.send_window + credit as usize, INITIAL_WINDOW)
+            }
+            None => return Vec::new(),
+        }
+        self.flush(id)
+    }
+
+    /// The peer closed substream `id`, dropping anything still queued on it. Returns whether it was
+    /// open.
+    pub fn handle_close(&mut self, id: StreamId) -> bool {
+        self.streams.remove(&id).is_some()
+    }
+
+    fn flush(&mut self, id: StreamId) -> Vec<(Message, Priority)> {
+        let mut msgs = Vec::new();
+        let done = match self.streams.get_mut(&id) {
+            Some(stream) => {
+                while stream
+                          .queued
+                          .front()
+                          .map_or(false, |&(ref data, _)| fits(data.len(), stream.send_window)) {
+                    let (data, priority) = unwrap!(stream.queued.pop_front());
+                    stream.send_window = stream.send_window.saturating_sub(data.len());
+                    msgs.push((Message::StreamData(id, data), priority));
+                }
+                stream.closing && stream.queued.is_empty()
+            }
+            None => false,
+        };
+        if done {
+            let _ = self.streams.remove(&id);
+            msgs.push((Message::StreamClose(id), 0));
+        }
+        msgs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::Message;
+
+    #[test]
+    fn flow_control() {
+        let mut ours = Mux::new(false);
+        let mut theirs = Mux::new(true);
+
+        // Only odd substreams are ours to open
+        assert!(ours.open(2).is_none());
+        let (open, _) = unwrap!(ours.open(1));
+        assert_eq!(open, Message::StreamOpen(1));
+        assert_eq!(theirs.handle_open(1), Ok(true));
+        assert_eq!(ours.handle_open(3), Err((Message::StreamClose(3), 0)));
+
+        // The window takes four chunks, the fifth has to wait for credit
+        let chunk = vec![0; INITIAL_WINDOW / 4];
+        let mut sent = Vec::new();
+        for _ in 0..4 {
+            sent.extend(unwrap!(ours.send(1, chunk.clone(), 0)));
+        }
+        assert_eq!(sent.len(), 4);
+        assert_eq!(unwrap!(ours.send(1, chunk.clone(), 0)).len(), 0);
+
+        // Credit comes only once the data is consumed, not as it arrives
+        for _ in 0..4 {
+            assert_eq!(theirs.handle_data(1, INITIAL_WINDOW / 4), Ok(true));
+        }
+        assert!(theirs.consumed(1, INITIAL_WINDOW / 4).is_none());
+        let credit = match unwrap!(theirs.consumed(1, INITIAL_WINDOW / 4)) {
+            (Message::StreamWindow(1, credit), _) => credit,
+            msg => panic!("Unexpected message: {:?}", msg),
+        };
+        assert_eq!(credit as usize, INITIAL_WINDOW / 2);
+
+        assert_eq!(ours.handle_window(1, credit).len(), 1);
+        let close = ours.close(1);
+        assert_eq!(close, vec![(Message::StreamClose(1), 0)]);
+        assert!(theirs.handle_close(1));
+        assert!(ours.send(1, chunk, 0).is_none());
+    }
+
+    #[test]
+    fn window_enforced() {
+        let mut theirs = Mux::new(true);
+        assert_eq!(theirs.handle_open(1), Ok(true));
+        assert_eq!(theirs.handle_data(1, INITIAL_WINDOW / 2), Ok(true));
+        assert_eq!(theirs.handle_data(1, INITIAL_WINDOW / 2), Ok(true));
+        assert_eq!(theirs.handle_data(1, 1), Err((Message::StreamClose(1), 0)));
+        assert_eq!(theirs.handle_data(1, 1), Ok(false));
+
+        // A message larger than the window is taken once nothing is outstanding
+        assert_eq!(theirs.handle_open(3), Ok(true));
+        assert_eq!(theirs.handle_data(3, INITIAL_WINDOW * 2), Ok(true));
+        assert!(theirs.consumed(3, INITIAL_WINDOW * 2).is_some());
+        assert_eq!(theirs.handle_data(3, 1), Ok(true));
+    }
+}
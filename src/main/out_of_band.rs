@@ -0,0 +1,142 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use byteorder::{ByteOrder, LittleEndian};
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use main::{ConnectionCandidates, CrustError, PubConnectionInfo, SealedConnectionInfo};
+use nat::MappedAddr;
+use serde::{Deserialize, Serialize};
+use serde_json;
+use std::mem;
+
+/// Version of the schema types handed between peers out of band are encoded with, raised
+/// whenever any of them changes.
+pub const SCHEMA_VERSION: u32 = 3;
+
+#[derive(Serialize)]
+struct Envelope<'a, T: 'a> {
+    version: u32,
+    value: &'a T,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    version: u32,
+}
+
+#[derive(Deserialize)]
+struct Body<T> {
+    value: T,
+}
+
+/// Types applications hand between peers out of band, e.g. connection info, in a compact binary
+/// or a JSON encoding. Either carries `SCHEMA_VERSION`, so that a peer running a crust with
+/// another schema fails to decode them with `CrustError::UnsupportedSchema` rather than misreading
+/// them.
+pub trait OutOfBand: Serialize + Deserialize + Sized {
+    /// The binary encoding: the schema version as four little-endian bytes, then the value.
+    fn to_bytes(&self) -> ::Res<Vec<u8>> {
+        let mut bytes = vec![0; mem::size_of::<u32>()];
+        LittleEndian::write_u32(&mut bytes, SCHEMA_VERSION);
+        bytes.extend_from_slice(&serialise(self)?);
+        Ok(bytes)
+    }
+
+    /// Decode the binary encoding.
+    fn from_bytes(bytes: &[u8]) -> ::Res<Self> {
+        let header = mem::size_of::<u32>();
+        if bytes.len() < header {
+            return Err(CrustError::TruncatedOutOfBand(bytes.len()));
+        }
+        check_version(LittleEndian::read_u32(bytes))?;
+        Ok(deserialise(&bytes[header..])?)
+    }
+
+    /// The JSON encoding: an object of the schema `version` and the `value`.
+    fn to_json(&self) -> ::Res<String> {
+        let envelope = Envelope {
+            version: SCHEMA_VERSION,
+            value: self,
+        };
+        Ok(serde_json::to_string(&envelope)?)
+    }
+
+    /// Decode the JSON encoding.
+    fn from_json(json: &str) -> ::Res<Self> {
+        let header: Header = serde_json::from_str(json)?;
+        check_version(header.version)?;
+        let body: Body<Self> = serde_json::from_str(json)?;
+        Ok(body.value)
+    }
+}
+
+fn check_version(version: u32) -> ::Res<()> {
+    if version == SCHEMA_VERSION {
+        Ok(())
+    } else {
+        Err(CrustError::UnsupportedSchema(version))
+    }
+}
+
+impl OutOfBand for PubConnectionInfo {}
+impl OutOfBand for SealedConnectionInfo {}
+impl OutOfBand for ConnectionCandidates {}
+impl OutOfBand for MappedAddr {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use main::{OfferStamp, PeerId};
+    use nat::MappedAddrSource;
+    use rust_sodium::crypto::box_;
+    use std::time::Duration;
+
+    #[test]
+    fn round_trips() {
+        let info = PubConnectionInfo {
+            id: PeerId(box_::gen_keypair().0),
+            for_hole_punch: vec![unwrap!("8.8.8.8:6000".parse())],
+            for_direct: vec![unwrap!("192.168.0.2:5483".parse())],
+            relay: None,
+            stamp: OfferStamp::new(Duration::from_secs(60)),
+        };
+        let bytes = unwrap!(info.to_bytes());
+        assert_eq!(unwrap!(PubConnectionInfo::from_bytes(&bytes)), info);
+        let json = unwrap!(info.to_json());
+        assert_eq!(unwrap!(PubConnectionInfo::from_json(&json)), info);
+
+        let mapped = MappedAddr::new(unwrap!("8.8.8.8:6000".parse()), MappedAddrSource::Stun);
+        let json = unwrap!(mapped.to_json());
+        assert_eq!(unwrap!(MappedAddr::from_json(&json)), mapped);
+
+        let mut bytes = unwrap!(mapped.to_bytes());
+        bytes[0] += 1;
+        match MappedAddr::from_bytes(&bytes) {
+            Err(CrustError::UnsupportedSchema(version)) => assert_eq!(version, SCHEMA_VERSION + 1),
+            res => panic!("Unexpected {:?}", res),
+        }
+        match MappedAddr::from_bytes(&bytes[..2]) {
+            Err(CrustError::TruncatedOutOfBand(2)) => (),
+            res => panic!("Unexpected {:?}", res),
+        }
+        let json = json.replace(&format!("\"version\":{}", SCHEMA_VERSION), "\"version\":0");
+        match MappedAddr::from_json(&json) {
+            Err(CrustError::UnsupportedSchema(0)) => (),
+            res => panic!("Unexpected {:?}", res),
+        }
+    }
+}
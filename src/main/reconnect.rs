@@ -0,0 +1,612 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{Core, CoreTimer, Identity, MSG_DROP_PRIORITY, Priority, State, Timeout, lock};
+use main::{Config, Connect, ConnectionMap, Event, OfferStamp, PeerId, PubConnectionInfo,
+           SendToken};
+use mio::{Poll, Token};
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::mem;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const DEFAULT_INITIAL_DELAY_MS: u64 = 500;
+const DEFAULT_MAX_DELAY_MS: u64 = 30_000;
+// Most messages per peer held while reconnecting to it, to be sent once reconnected.
+const MAX_HELD_MESSAGES: usize = 256;
+
+/// Starts another attempt to connect to the peer.
+pub type Redial = Rc<Fn(&mut Core, &Poll, PubConnectionInfo, Reconnect)>;
+/// The peers being reconnected to, by the token of the state doing so.
+pub type Reconnecting = Arc<Mutex<HashMap<PeerId, Token>>>;
+
+/// How lost connections are re-established.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// Attempts to make before giving up on the peer.
+    pub max_attempts: u32,
+    /// Delay before the first attempt, doubled for each one after it.
+    pub initial_delay_ms: u64,
+    /// Longest delay between attempts.
+    pub max_delay_ms: u64,
+    /// Most unacknowledged messages to keep for replay after reconnecting, `0` keeping none.
+    pub replay_buffer: usize,
+}
+
+impl ReconnectPolicy {
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u64 << cmp::min(attempt.saturating_sub(1), 32);
+        let delay_ms = self.initial_delay_ms.saturating_mul(factor);
+        Duration::from_millis(cmp::min(delay_ms, self.max_delay_ms))
+    }
+}
+
+/// How to reconnect to peers we lose, if at all.
+pub fn reconnect_policy(config: &Config) -> Option<ReconnectPolicy> {
+    config
+        .reconnect_attempts
+        .map(|max_attempts| {
+                 ReconnectPolicy {
+                     max_attempts: max_attempts,
+                     initial_delay_ms: config
+                         .reconnect_initial_delay_ms
+                         .unwrap_or(DEFAULT_INITIAL_DELAY_MS),
+                     max_delay_ms: config
+                         .reconnect_max_delay_ms
+                         .unwrap_or(DEFAULT_MAX_DELAY_MS),
+                     replay_buffer: config.reconnect_replay_buffer.unwrap_or(0),
+                 }
+             })
+}
+
+/// What a connection needs to be re-established should it drop, along with whatever it sent that
+/// the peer has not acknowledged yet.
+pub struct Reconnect {
+    policy: ReconnectPolicy,
+    their_id: PeerId,
+    their_identity: Option<Identity>,
+    their_direct: Vec<SocketAddr>,
+    their_hole_punch: Vec<SocketAddr>,
+    their_relay: Option<SocketAddr>,
+    // Where our hole punching socket was bound, so that the peer finds us at the same mapping.
+    our_hole_punch: Option<SocketAddr>,
+    // Redials go by the info the connection was made with, whether or not it has expired since.
+    their_stamp: OfferStamp,
+    attempt: u32,
+    replay: ReplayBuffer,
+    // Sent while there was no connection to send it on.
+    held: VecDeque<(Arc<Vec<u8>>, Priority, Option<SendToken>)>,
+    // The state this is with while between connections, as registered in `reconnecting`.
+    holder: Option<Token>,
+    reconnecting: Reconnecting,
+    cm: ConnectionMap,
+    redial: Redial,
+    event_tx: ::CrustEventSender,
+}
+
+impl Reconnect {
+    /// Returns `None` if we would have nowhere to reconnect to. Hole punching is tried again
+    /// from `our_hole_punch`, the address our hole punching socket was bound to.
+    pub fn new(policy: ReconnectPolicy,
+               their_ci: &PubConnectionInfo,
+               our_hole_punch: Option<SocketAddr>,
+               reconnecting: Reconnecting,
+               cm: ConnectionMap,
+               redial: Redial,
+               event_tx: ::CrustEventSender)
+               -> Option<Self> {
+        let our_hole_punch = if their_ci.for_hole_punch.is_empty() {
+            None
+        } else {
+            our_hole_punch
+        };
+        if their_ci.for_direct.is_empty() && their_ci.relay.is_none() && our_hole_punch.is_none() {
+            return None;
+        }
+        Some(Reconnect {
+                 policy: policy,
+                 their_id: their_ci.id,
+                 their_identity: None,
+                 their_direct: their_ci.for_direct.clone(),
+                 their_hole_punch: if our_hole_punch.is_some() {
+                     their_ci.for_hole_punch.clone()
+                 } else {
+                     Vec::new()
+                 },
+                 their_relay: their_ci.relay,
+                 our_hole_punch: our_hole_punch,
+                 their_stamp: their_ci.stamp,
+                 attempt: 0,
+                 replay: ReplayBuffer::new(policy.replay_buffer),
+                 held: VecDeque::new(),
+                 holder: None,
+                 reconnecting: reconnecting,
+                 cm: cm,
+                 redial: redial,
+                 event_tx: event_tx,
+             })
+    }
+
+    /// Whether this is an attempt to re-establish a lost connection rather than the first one.
+    pub fn is_reconnecting(&self) -> bool {
+        self.attempt > 0
+    }
+
+    /// Mark the connection as re-established.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+        self.release();
+    }
+
+    /// Where to bind the socket to punch a hole to the peer again from, if anywhere.
+    pub fn our_hole_punch(&self) -> Option<SocketAddr> {
+        self.our_hole_punch
+    }
+
+    /// Register the state of `token` as the one reconnecting to the peer, so that messages sent
+    /// meanwhile are held there. Returns `false` if this reconnection is not to go on: another
+    /// one to the peer is already under way, or we started connecting to it afresh since.
+    pub fn claim(&mut self, token: Token) -> bool {
+        let mut reconnecting = lock(&self.reconnecting);
+        let ours = match reconnecting.get(&self.their_id) {
+            Some(holder) => self.holder == Some(*holder),
+            None => self.holder.is_none(),
+        };
+        if ours {
+            let _ = reconnecting.insert(self.their_id, token);
+            self.holder = Some(token);
+        }
+        ours
+    }
+
+    // Let go of the peer, unless someone else has taken it over already.
+    fn release(&mut self) {
+        if let Some(holder) = self.holder.take() {
+            let mut reconnecting = lock(&self.reconnecting);
+            if reconnecting.get(&self.their_id) == Some(&holder) {
+                let _ = reconnecting.remove(&self.their_id);
+            }
+        }
+    }
+
+    /// Hold a message sent while there is no connection to the peer, to be sent once there is
+    /// again. Droppable messages and those beyond `MAX_HELD_MESSAGES` are not sent.
+    pub fn hold(&mut self, data: Vec<u8>, priority: Priority, receipt: Option<SendToken>) {
+        if priority < MSG_DROP_PRIORITY && self.held.len() < MAX_HELD_MESSAGES {
+            self.held.push_back((Arc::new(data), priority, receipt));
+        } else if let Some(receipt) = receipt {
+            let _ = self.event_tx.send(Event::MessageNotSent(self.their_id, receipt));
+        }
+    }
+
+    /// Take out what is to be sent on reconnecting: everything the peer had not acknowledged,
+    /// then whatever was held meanwhile, oldest first.
+    pub fn take_pending(&mut self) -> Vec<(Arc<Vec<u8>>, Priority, Option<SendToken>)> {
+        let mut pending = self.replay.take_unacked();
+        pending.extend(self.held.drain(..));
+        pending
+    }
+
+    /// The identity the peer proved on connecting, which it has to prove again on reconnecting.
+    pub fn their_identity(&self) -> Option<Identity> {
+        self.their_identity
+    }
+
+    pub fn set_their_identity(&mut self, identity: Identity) {
+        self.their_identity = Some(identity);
+    }
+
+    pub fn replay(&mut self) -> &mut ReplayBuffer {
+        &mut self.replay
+    }
+
+    /// Try to connect to the peer again once the backoff delay is over, or report it as lost if
+    /// we are out of attempts.
+    pub fn retry(mut self, core: &mut Core) {
+        // Connected again some other way, e.g. by the peer
+        if is_connected(&self.cm, &self.their_id) {
+            debug!("Already reconnected to {:?}", self.their_id);
+            return;
+        }
+        if self.attempt >= self.policy.max_attempts {
+            debug!("Giving up on reconnecting to {:?}", self.their_id);
+            let _ = self.event_tx.send(Event::LostPeer(self.their_id));
+            return;
+        }
+        self.attempt += 1;
+
+        let token = core.get_new_token();
+        if !self.claim(token) {
+            debug!("Not reconnecting to {:?} as another connection to it is under way",
+                   self.their_id);
+            return;
+        }
+        let delay = self.policy.delay(self.attempt);
+        match core.set_timeout(delay, CoreTimer::new(token, 0)) {
+            Ok(timeout) => {
+                trace!("Reconnecting to {:?} in {:?}, attempt {}",
+                       self.their_id,
+                       delay,
+                       self.attempt);
+                let state = Backoff {
+                    token: token,
+                    timeout: timeout,
+                    reconnect: Some(self),
+                };
+                let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+            }
+            Err(e) => {
+                debug!("Failed to set reconnect timer: {:?}", e);
+                let _ = self.event_tx.send(Event::LostPeer(self.their_id));
+            }
+        }
+    }
+}
+
+impl Drop for Reconnect {
+    fn drop(&mut self) {
+        self.release();
+        // Whatever was held back to be sent again is not going to be after all
+        let held = self.held.drain(..).filter_map(|(_, _, receipt)| receipt);
+        for receipt in self.replay.take_withheld().into_iter().chain(held) {
+            let _ = self.event_tx.send(Event::MessageNotSent(self.their_id, receipt));
+        }
+    }
+}
+
+/// Hold a message for the peer `state` is reconnecting to, returning whether it is reconnecting
+/// to any.
+pub fn hold(state: &mut State,
+            data: Vec<u8>,
+            priority: Priority,
+            receipt: Option<SendToken>)
+            -> bool {
+    let any = state.as_any();
+    let reconnect = if any.is::<Backoff>() {
+        any.downcast_mut::<Backoff>()
+            .and_then(|backoff| backoff.reconnect.as_mut())
+    } else {
+        any.downcast_mut::<Connect>()
+            .and_then(|connect| connect.reconnect_mut())
+    };
+    match reconnect {
+        Some(reconnect) => {
+            reconnect.hold(data, priority, receipt);
+            true
+        }
+        None => false,
+    }
+}
+
+fn is_connected(cm: &ConnectionMap, peer_id: &PeerId) -> bool {
+    lock(cm)
+        .get(peer_id)
+        .map_or(false, |conn_id| conn_id.active_connection.is_some())
+}
+
+// Waits out the delay before the next attempt to reconnect.
+struct Backoff {
+    token: Token,
+    timeout: Timeout,
+    reconnect: Option<Reconnect>,
+}
+
+impl State for Backoff {
+    fn name(&self) -> &'static str {
+        "Backoff"
+    }
+
+    fn peer_id(&self) -> Option<PeerId> {
+        self.reconnect.as_ref().map(|reconnect| reconnect.their_id)
+    }
+
+    fn terminate(&mut self, core: &mut Core, _poll: &Poll) {
+        let _ = core.remove_state(self.token);
+        let _ = core.cancel_timeout(&self.timeout);
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u8) {
+        // The attempt waits for the network to be resumed rather than being used up
+        if core.is_paused() {
+            if let Some(delay) = self.reconnect
+                   .as_ref()
+                   .map(|reconnect| reconnect.policy.delay(reconnect.attempt)) {
+                match core.set_timeout(delay, CoreTimer::new(self.token, 0)) {
+                    Ok(timeout) => {
+                        self.timeout = timeout;
+                        return;
+                    }
+                    Err(e) => debug!("Failed to set reconnect timer: {:?}", e),
+                }
+            }
+        }
+        let _ = core.remove_state(self.token);
+        if let Some(reconnect) = self.reconnect.take() {
+            if is_connected(&reconnect.cm, &reconnect.their_id) {
+                debug!("Already reconnected to {:?}", reconnect.their_id);
+                return;
+            }
+            let their_ci = PubConnectionInfo {
+                id: reconnect.their_id,
+                for_hole_punch: reconnect.their_hole_punch.clone(),
+                for_direct: reconnect.their_direct.clone(),
+                relay: reconnect.their_relay,
+                stamp: reconnect.their_stamp,
+            };
+            let redial = reconnect.redial.clone();
+            (*redial)(core, poll, their_ci, reconnect);
+        }
+    }
+
+    fn write(&mut self, _core: &mut Core, _poll: &Poll, data: Vec<u8>, priority: Priority) {
+        if let Some(ref mut reconnect) = self.reconnect {
+            reconnect.hold(data, priority, None);
+        }
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// Messages sent but not yet acknowledged by the peer, oldest dropped first once full.
+///
+/// Tracked messages keep their receipt until it is settled: once written, or once they are given
+/// up on along with the buffer. A receipt the lost connection would have settled as not sent is
+/// withheld instead, since the message is sent again on reconnecting.
+pub struct ReplayBuffer {
+    capacity: usize,
+    next_seq: u64,
+    unacked: BTreeMap<u64, Unacked>,
+}
+
+struct Unacked {
+    data: Arc<Vec<u8>>,
+    priority: Priority,
+    receipt: Option<SendToken>,
+    withheld: bool,
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize) -> Self {
+        ReplayBuffer {
+            capacity: capacity,
+            next_seq: 0,
+            unacked: BTreeMap::new(),
+        }
+    }
+
+    /// Keep `data` until it is acknowledged, returning the sequence number to send it with.
+    /// Returns `None` if nothing is kept.
+    pub fn push(&mut self,
+                data: Arc<Vec<u8>>,
+                priority: Priority,
+                receipt: Option<SendToken>)
+                -> Option<u64> {
+        if self.capacity == 0 {
+            return None;
+        }
+        if self.unacked.len() >= self.capacity {
+            // Still written or dropped by the connection, which settles its receipt
+            let oldest = *unwrap!(self.unacked.keys().next());
+            let _ = self.unacked.remove(&oldest);
+        }
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        let unacked = Unacked {
+            data: data,
+            priority: priority,
+            receipt: receipt,
+            withheld: false,
+        };
+        let _ = self.unacked.insert(seq, unacked);
+        Some(seq)
+    }
+
+    /// The sequence number the next message kept is sent with, or `None` if none is kept.
+    pub fn next_seq(&self) -> Option<u64> {
+        if self.capacity == 0 {
+            None
+        } else {
+            Some(self.next_seq)
+        }
+    }
+
+    /// The message tracked by `receipt` was written, so is not to be reported on again.
+    pub fn settle(&mut self, receipt: SendToken) {
+        for unacked in self.unacked.values_mut() {
+            if unacked.receipt == Some(receipt) {
+                unacked.receipt = None;
+            }
+        }
+    }
+
+    /// Hold back reporting that the message tracked by `receipt` was not sent, returning whether
+    /// it is kept to be sent again.
+    pub fn withhold(&mut self, receipt: SendToken) -> bool {
+        match self.unacked
+                  .values_mut()
+                  .find(|unacked| unacked.receipt == Some(receipt)) {
+            Some(unacked) => {
+                unacked.withheld = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn ack(&mut self, seqs: &[u64]) {
+        for seq in seqs {
+            let _ = self.unacked.remove(seq);
+        }
+    }
+
+    /// Take out everything unacknowledged, oldest first, with the receipts still to be settled.
+    pub fn take_unacked(&mut self) -> Vec<(Arc<Vec<u8>>, Priority, Option<SendToken>)> {
+        mem::replace(&mut self.unacked, BTreeMap::new())
+            .into_iter()
+            .map(|(_, unacked)| (unacked.data, unacked.priority, unacked.receipt))
+            .collect()
+    }
+
+    // The receipts withheld, for messages which are now never going to be sent again.
+    fn take_withheld(&mut self) -> Vec<SendToken> {
+        self.unacked
+            .values_mut()
+            .filter(|unacked| unacked.withheld)
+            .filter_map(|unacked| unacked.receipt.take())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{Core, MSG_DROP_PRIORITY};
+    use main::{Event, OfferStamp, PeerId, PubConnectionInfo};
+    use mio::{Poll, Token};
+    use rust_sodium::crypto::box_::PublicKey;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::Receiver;
+    use std::time::Duration;
+    use tests::get_event_sender;
+
+    const POLICY: ReconnectPolicy = ReconnectPolicy {
+        max_attempts: 3,
+        initial_delay_ms: 100,
+        max_delay_ms: 1000,
+        replay_buffer: 0,
+    };
+
+    fn reconnect(reconnecting: &Reconnecting) -> (Reconnect, Receiver<Event>) {
+        let their_ci = PubConnectionInfo {
+            id: PeerId(PublicKey([1; 32])),
+            for_hole_punch: vec![unwrap!("1.2.3.4:5000".parse())],
+            for_direct: Vec::new(),
+            relay: None,
+            stamp: OfferStamp::new(Duration::from_secs(60)),
+        };
+        let redial = |_: &mut Core, _: &Poll, _: PubConnectionInfo, _: Reconnect| ();
+        let (event_tx, event_rx) = get_event_sender();
+        let reconnect = unwrap!(Reconnect::new(POLICY,
+                                               &their_ci,
+                                               Some(unwrap!("0.0.0.0:6000".parse())),
+                                               reconnecting.clone(),
+                                               Arc::new(Mutex::new(HashMap::new())),
+                                               Rc::new(redial),
+                                               event_tx));
+        (reconnect, event_rx)
+    }
+
+    #[test]
+    fn one_reconnection_per_peer() {
+        let reconnecting = Arc::new(Mutex::new(HashMap::new()));
+        let (mut first, _rx_0) = reconnect(&reconnecting);
+        let (mut second, _rx_1) = reconnect(&reconnecting);
+        assert!(first.claim(Token(1)));
+        assert!(!second.claim(Token(2)));
+        // Handed on from one state to the next
+        assert!(first.claim(Token(3)));
+        assert_eq!(unwrap!(reconnecting.lock()).values().collect::<Vec<_>>(),
+                   vec![&Token(3)]);
+
+        // Superseded by connecting afresh
+        let _ = unwrap!(reconnecting.lock()).remove(&first.their_id);
+        assert!(!first.claim(Token(4)));
+        assert!(second.claim(Token(5)));
+        drop(second);
+        assert!(unwrap!(reconnecting.lock()).is_empty());
+    }
+
+    #[test]
+    fn held_while_reconnecting() {
+        let reconnecting = Arc::new(Mutex::new(HashMap::new()));
+        let (mut reconnect, event_rx) = reconnect(&reconnecting);
+        assert_eq!(reconnect.our_hole_punch(), Some(unwrap!("0.0.0.0:6000".parse())));
+        reconnect.hold(vec![0], 0, Some(0));
+        reconnect.hold(vec![1], MSG_DROP_PRIORITY, Some(1));
+        reconnect.hold(vec![2], 1, None);
+        match unwrap!(event_rx.try_recv()) {
+            Event::MessageNotSent(_, 1) => (),
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert_eq!(reconnect.take_pending(),
+                   vec![(Arc::new(vec![0]), 0, Some(0)), (Arc::new(vec![2]), 1, None)]);
+
+        reconnect.hold(vec![3], 0, Some(3));
+        drop(reconnect);
+        match unwrap!(event_rx.try_recv()) {
+            Event::MessageNotSent(_, 3) => (),
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn replay_buffer() {
+        let mut buffer = ReplayBuffer::new(2);
+        assert_eq!(buffer.next_seq(), Some(0));
+        assert_eq!(buffer.push(Arc::new(vec![0]), 0, None), Some(0));
+        assert_eq!(buffer.push(Arc::new(vec![1]), 1, None), Some(1));
+        assert_eq!(buffer.push(Arc::new(vec![2]), 0, None), Some(2));
+        buffer.ack(&[2, 7]);
+        assert_eq!(buffer.take_unacked(), vec![(Arc::new(vec![1]), 1, None)]);
+        assert!(buffer.take_unacked().is_empty());
+
+        assert_eq!(ReplayBuffer::new(0).next_seq(), None);
+        assert_eq!(ReplayBuffer::new(0).push(Arc::new(vec![0]), 0, None), None);
+    }
+
+    #[test]
+    fn replay_receipts() {
+        let mut buffer = ReplayBuffer::new(4);
+        for receipt in 0..3 {
+            let _ = buffer.push(Arc::new(vec![receipt as u8]), 0, Some(receipt));
+        }
+        // Written, then lost with the connection
+        buffer.settle(0);
+        assert!(!buffer.withhold(0));
+        assert!(buffer.withhold(1));
+        assert!(!buffer.withhold(7));
+        assert_eq!(buffer.take_withheld(), vec![1]);
+        assert!(buffer.take_withheld().is_empty());
+        assert_eq!(buffer.take_unacked(),
+                   vec![(Arc::new(vec![0]), 0, None),
+                        (Arc::new(vec![1]), 0, None),
+                        (Arc::new(vec![2]), 0, Some(2))]);
+    }
+
+    #[test]
+    fn backoff_delay() {
+        let policy = ReconnectPolicy {
+            max_attempts: 10,
+            initial_delay_ms: 100,
+            max_delay_ms: 1000,
+            replay_buffer: 0,
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(3), Duration::from_millis(400));
+        assert_eq!(policy.delay(5), Duration::from_millis(1000));
+        assert_eq!(policy.delay(u32::max_value()), Duration::from_millis(1000));
+    }
+}
@@ -15,48 +15,115 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-use common::{self, Core, CoreMessage, CrustUser, EventLoop, ExternalReachability, NameHash,
-             Priority};
-use main::{ActiveConnection, Bootstrap, Connect, ConnectionId, ConnectionInfoResult,
-           ConnectionListener, ConnectionMap, CrustError, Event, PeerId, PrivConnectionInfo,
-           PubConnectionInfo};
+use common::{self, BandwidthLimits, BufferPoolStats, Capabilities, Core, CoreMessage, CrustUser,
+             Device, EventLoop, ExternalReachability, HttpConnect, Identity, IdentityKeys,
+             MAX_SHARDS, NameHash, Priority, QueueFullPolicy, Shards, Socks5, StateSnapshot,
+             TraceSubscriber, Transport, TraversalHelpers, TunedTcp, lock, shard_of,
+             shard_token_start};
+use main::{ActiveConnection, BanList, Bootstrap, CandidateKind, CandidatePair, CandidateTransport,
+           CandidatesResult, Connect, ConnectionCandidates, ConnectionId, ConnectionInfoResult,
+           ConnectionListener, ConnectionMap, ConnectionStats, CrustError, Event,
+           ExpectedIdentities, Gathering, IpWhitelist, ListenerReachability, LocalCandidates,
+           Metrics, MetricsExporter, MetricsSnapshot, OfferLedger, OfferStamp, Offers, OurRelay,
+           PeerId, PrivConnectionInfo, PubConnectionInfo, RacePolicy, Reconnect, Reconnecting,
+           RelayWatch, SealedConnectionInfo, SendToken, StreamId, UdpHolePunchResult, accept_limits,
+           compression_policy, drop_policy, inactivity_timeout, keep_alive_batch,
+           keep_alive_period, race_policy, reconnect_policy, socket_config};
+use main::candidates;
 use main::config_handler::{self, Config};
+use main::reconnect;
 use mio::{Poll, Token};
+use mio::udp::UdpSocket;
 use nat;
-use nat::{MappedTcpSocket, MappingContext};
+use nat::{CompletionPolicy, DetectNatType, IfWatcher, LeaseRenewal, MappedTcpSocket,
+          MappingConfig, MappingContext, MappingEvent, MappingHandle, MappingResult, NatError,
+          NatStats, PortRange, RendezvousClient, RendezvousResult, UdpHolePunch};
 use rust_sodium;
 use rust_sodium::crypto::box_::{self, PublicKey, SecretKey};
 use rust_sodium::crypto::hash::sha256;
 use service_discovery::ServiceDiscovery;
+use std::cell::Cell;
+use std::cmp;
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::net::SocketAddr;
+use std::iter;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::rc::Rc;
 use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(feature = "test_utils")]
+use test_utils::FakeNat;
 
 const BOOTSTRAP_TOKEN: Token = Token(0);
 const SERVICE_DISCOVERY_TOKEN: Token = Token(1);
 const LISTENER_TOKEN: Token = Token(2);
 
 const SERVICE_DISCOVERY_DEFAULT_PORT: u16 = 5484;
+// How often `shutdown` checks whether the connections have written what was queued to them.
+const SHUTDOWN_FLUSH_POLL_MS: u64 = 20;
+// How long peers are dialed directly before they are also dialed through the HTTP proxy.
+const HTTP_PROXY_FALLBACK_MS: u64 = 2000;
+// How long the connection info we hand out may be connected with for, unless configured.
+const CONNECTION_INFO_TTL_SECS: u64 = 600;
+// How long the external IPs the peers report are reused for under `Config::conserve_network`.
+const CONSERVE_EXT_ADDR_TTL_SECS: u64 = 3600;
+// Most helpers advertised by peers to keep at once.
+const MAX_ADVERTISED_HELPERS: usize = 64;
+// How often a send blocked under `QueueFullPolicy::Block` checks for room, and for how long.
+const QUEUE_BLOCK_POLL_MS: u64 = 20;
+const QUEUE_BLOCK_TIMEOUT_SECS: u64 = 30;
 
 const DISABLE_NAT: bool = true;
 
+type Bootstrapper = Box<FnMut(&mut Core, &Poll, HashSet<SocketAddr>) + Send>;
+
 /// A structure representing all the Crust services. This is the main object through which crust is
 /// used.
 pub struct Service {
     config: Config,
     cm: ConnectionMap,
+    reconnecting: Reconnecting,
     event_tx: ::CrustEventSender,
     mc: Arc<MappingContext>,
     el: EventLoop,
+    // Event loops other than `el` that connections are handed on to.
+    shards: Vec<EventLoop>,
     name_hash: NameHash,
     our_keys: (PublicKey, SecretKey),
+    identity: IdentityKeys,
+    expected_identities: ExpectedIdentities,
+    offers: Offers,
     our_listeners: Arc<Mutex<Vec<SocketAddr>>>,
+    // Tokens of the listeners started last, the first of them being `LISTENER_TOKEN`.
+    listener_tokens: Arc<Mutex<Vec<Token>>>,
+    // The contact we bootstrapped off under `Config::bootstrap_via_relay`, to be reached through.
+    our_relay: OurRelay,
+    // Mappings under way for `prepare_connection_info`, by result token and then by their own id,
+    // as the user may reuse a result token while a mapping for it is still under way.
+    pending_mappings: Arc<Mutex<HashMap<u32, Vec<(usize, MappingHandle)>>>>,
+    next_mapping: AtomicUsize,
+    next_stream: AtomicUsize,
+    next_send: AtomicUsize,
+    // Set between `pause_network` and `resume_network`.
+    paused: AtomicBool,
+    // Shared by connects so that the family each peer was reached over is remembered.
+    race: RacePolicy,
+    helpers: TraversalHelpers,
+    bandwidth: BandwidthLimits,
+    ban_list: BanList,
+    whitelist: IpWhitelist,
+    metrics: Metrics,
+    _metrics_exporter: Option<MetricsExporter>,
 }
 
 impl Service {
     /// Construct a service. `event_tx` is the sending half of the channel which crust will send
-    /// notifications on.
+    /// notifications on. The config is read from the config file, with `CRUST_*` environment
+    /// variables overriding it.
     pub fn new(event_tx: ::CrustEventSender) -> ::Res<Self> {
         Service::with_config(event_tx, config_handler::read_config_file()?)
     }
@@ -65,38 +132,299 @@ impl Service {
     /// and provide the sender half to this method. Receiver will receive all `Event`s from this
     /// library.
     pub fn with_config(event_tx: ::CrustEventSender, config: Config) -> ::Res<Service> {
+        let mc = MappingContext::with_igd(!config.nat_disable_igd.unwrap_or(false))?;
+        Service::start(event_tx, config, mc)
+    }
+
+    /// Construct a service with the given config on the host `nat` describes, rather than
+    /// finding out about the actual host and its routers.
+    #[cfg(feature = "test_utils")]
+    pub fn with_fake_nat(event_tx: ::CrustEventSender,
+                         config: Config,
+                         nat: &FakeNat)
+                         -> ::Res<Service> {
+        Service::start(event_tx, config, MappingContext::scripted(nat))
+    }
+
+    fn start(event_tx: ::CrustEventSender,
+             config: Config,
+             mut mc: MappingContext)
+             -> ::Res<Service> {
         rust_sodium::init();
+        config_handler::check_config(&config)?;
 
         let our_keys = box_::gen_keypair();
+        let identity = match config.identity_file {
+            Some(ref path) => IdentityKeys::load_or_create(Path::new(path))?,
+            None => IdentityKeys::generate(),
+        };
         let our_id = PeerId(our_keys.0);
-        let name_hash = name_hash(&config.network_name);
+        let name_hash = name_hash(&config.network_name, &config.network_genesis);
 
         // Form our initial contact info
         let our_listeners = Arc::new(Mutex::new(Vec::with_capacity(5)));
-        let mut mc = MappingContext::new()?;
         mc.add_peer_stuns(config.hard_coded_contacts.iter().cloned());
+        let mut mapping_config = match config.nat_mapping_timeout_ms {
+            Some(timeout_ms) => MappingConfig::with_timeout(Duration::from_millis(timeout_ms)),
+            None => MappingConfig::default(),
+        };
+        if config.nat_mapping_first_external.unwrap_or(false) {
+            mapping_config.completion = CompletionPolicy::FirstExternal;
+        }
+        if let Some(retries) = config.nat_stun_retries {
+            mapping_config.query_retries = retries;
+        }
+        mapping_config.keep_loopback = config.nat_keep_loopback.unwrap_or(false);
+        mapping_config.discover = !config.nat_external_addrs_only.unwrap_or(false);
+        mapping_config.query_stun = !config.nat_disable_stun.unwrap_or(false);
+        if let Some(ref addrs) = config.nat_external_addrs {
+            mc.set_declared_ext_addrs(addrs.clone());
+        }
+        mapping_config.socket = socket_config(&config);
+        if config.conserve_network.unwrap_or(false) {
+            mapping_config.ext_addr_ttl = Duration::from_secs(CONSERVE_EXT_ADDR_TTL_SECS);
+            mc.gateway_cache().set_background_refresh(false);
+        }
+        mc.set_mapping_config(mapping_config);
+
+        let bandwidth = BandwidthLimits::new(config.max_conn_upload_bytes_per_sec,
+                                             config.max_conn_download_bytes_per_sec,
+                                             config.max_upload_bytes_per_sec,
+                                             config.max_download_bytes_per_sec)
+                .with_peer_quota(config.peer_max_msgs_per_sec,
+                                 config.peer_max_bytes_per_sec,
+                                 config.peer_quota_policy.unwrap_or_default())
+                .with_queue_limits(config.max_queued_bytes_per_peer,
+                                   config.max_queued_bytes,
+                                   config.queue_full_policy.unwrap_or_default())
+                .with_slow_peers(config.slow_peer_ms.map(Duration::from_millis),
+                                 config.disconnect_slow_peers.unwrap_or(false));
+
+        let race = race_policy(&config);
+        let helpers = traversal_helpers(&config, &mc);
+        let ban_list = BanList::new(&config);
+        let whitelist = IpWhitelist::new(&config)?;
+        if let Some(ref name) = config.bind_device {
+            if Device::new(name).is_none() {
+                return Err(CrustError::InvalidDeviceName(name.clone()));
+            }
+        }
+        let metrics = Metrics::new(config.metrics.unwrap_or(false) ||
+                                   config.metrics_listen_addr.is_some());
+        let metrics_exporter = match config.metrics_listen_addr {
+            Some(addr) => Some(MetricsExporter::start(&addr, metrics.clone())?),
+            None => None,
+        };
 
         let el = common::spawn_event_loop(3, Some(&format!("{:?}", our_id)))?;
         trace!("Event loop started");
+        let shards = start_shards(&el, &our_id, config.event_loops.unwrap_or(1))?;
+
+        let service = Service {
+            cm: Arc::new(Mutex::new(HashMap::new())),
+            reconnecting: Arc::new(Mutex::new(HashMap::new())),
+            config: config,
+            event_tx: event_tx,
+            mc: Arc::new(mc),
+            el: el,
+            shards: shards,
+            name_hash: name_hash,
+            our_keys: our_keys,
+            identity: identity,
+            expected_identities: Arc::new(Mutex::new(HashMap::new())),
+            offers: Arc::new(Mutex::new(OfferLedger::default())),
+            our_listeners: our_listeners,
+            listener_tokens: Arc::new(Mutex::new(Vec::new())),
+            our_relay: Arc::new(Mutex::new(None)),
+            pending_mappings: Arc::new(Mutex::new(HashMap::new())),
+            next_mapping: AtomicUsize::new(0),
+            next_stream: AtomicUsize::new(0),
+            next_send: AtomicUsize::new(0),
+            paused: AtomicBool::new(false),
+            race: race,
+            helpers: helpers,
+            bandwidth: bandwidth,
+            ban_list: ban_list,
+            whitelist: whitelist,
+            metrics: metrics,
+            _metrics_exporter: metrics_exporter,
+        };
+        service.start_tcp()?;
+        service.start_proxy()?;
+        service.start_crash_reports()?;
+        service.start_capabilities()?;
+        service.start_helpers()?;
+        service.start_keep_alive_batch()?;
+        service.start_lease_renewal()?;
+        service.start_if_watcher()?;
+        service.start_relay_watch()?;
+
+        Ok(service)
+    }
+
+    // Dial over TCP tuned by the socket options which only take before connecting, if any are
+    // set.
+    fn start_tcp(&self) -> ::Res<()> {
+        let config = self.mc.mapping_config().socket;
+        if config.device.is_none() && config.recv_buffer_size.is_none() {
+            return Ok(());
+        }
+        let mut tcp = TunedTcp::default();
+        if let Some(device) = config.device {
+            tcp = tcp.device(device);
+        }
+        if let Some(size) = config.recv_buffer_size {
+            tcp = tcp.recv_buffer_size(size);
+        }
+        self.set_transport(tcp)
+    }
+
+    fn start_proxy(&self) -> ::Res<()> {
+        if let Some(proxy) = self.config.socks5_proxy {
+            let socks5 = match (self.config.socks5_username.clone(),
+                                self.config.socks5_password.clone()) {
+                (Some(username), Some(password)) => {
+                    Socks5::new(proxy).with_auth(username, password)
+                }
+                _ => Socks5::new(proxy),
+            };
+            self.set_transport(socks5)?;
+        }
+
+        if let Some(proxy) = self.config.http_proxy {
+            let http = match (self.config.http_proxy_username.clone(),
+                              self.config.http_proxy_password.clone()) {
+                (Some(username), Some(password)) => {
+                    HttpConnect::new(proxy).with_auth(username, password)
+                }
+                _ => HttpConnect::new(proxy),
+            };
+            if self.config.http_proxy_only.unwrap_or(false) {
+                self.set_transport(http)?;
+            } else {
+                let delay = self.config
+                    .http_proxy_fallback_ms
+                    .unwrap_or(HTTP_PROXY_FALLBACK_MS);
+                self.add_fallback_transport(http, Duration::from_millis(delay))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn start_crash_reports(&self) -> ::Res<()> {
+        for el in self.event_loops() {
+            let event_tx = self.event_tx.clone();
+            self.post_to(el, move |core, _| {
+                let on_crash = move |crash| {
+                    let _ = event_tx.send(Event::StateCrashed(crash));
+                };
+                core.set_crash_handler(Box::new(on_crash));
+            })?;
+        }
+        Ok(())
+    }
+
+    fn start_capabilities(&self) -> ::Res<()> {
+        let capabilities =
+            Capabilities::new(self.config.max_message_size,
+                              self.config.stream_oversized_messages.unwrap_or(false));
+        let negotiate = self.config.negotiate_capabilities.unwrap_or(false);
+        for el in self.event_loops() {
+            let capabilities = capabilities.clone();
+            let secret_key = self.our_keys.1.clone();
+            self.post_to(el, move |core, _| {
+                core.set_capabilities(capabilities, negotiate);
+                core.set_secret_key(secret_key);
+            })?;
+        }
+        Ok(())
+    }
+
+    fn start_helpers(&self) -> ::Res<()> {
+        for el in self.event_loops() {
+            let helpers = self.helpers.clone();
+            self.post_to(el, move |core, _| core.set_helpers(helpers))?;
+        }
+        Ok(())
+    }
+
+    fn start_keep_alive_batch(&self) -> ::Res<()> {
+        let batch = keep_alive_batch(&self.config);
+        for el in self.event_loops() {
+            self.post_to(el, move |core, _| core.set_keep_alive_batch(batch))?;
+        }
+        Ok(())
+    }
+
+    fn start_lease_renewal(&self) -> ::Res<()> {
+        let event_tx = self.event_tx.clone();
+        let mc = self.mc.clone();
+        self.post(move |core, _| {
+            let on_lost = move |_: &mut Core, _: &Poll, ext_addr| {
+                let _ = event_tx.send(Event::MappingLost(ext_addr));
+            };
+            if let Err(e) = LeaseRenewal::start(core, &mc, Box::new(on_lost)) {
+                debug!("Could not start IGD lease renewal: {:?}", e);
+            }
+        })
+    }
+
+    fn start_if_watcher(&self) -> ::Res<()> {
+        let event_tx = self.event_tx.clone();
+        let listener_tokens = self.listener_tokens.clone();
+        let mc = self.mc.clone();
+        let mc_0 = self.mc.clone();
+        self.post(move |core, _| {
+            // Listeners are mapped afresh to be found at wherever the new network has us
+            let on_change = move |core: &mut Core, poll: &Poll| {
+                let tokens = lock(&listener_tokens).clone();
+                for token in tokens {
+                    let state = match core.get_state(token) {
+                        Some(state) => state,
+                        None => continue,
+                    };
+                    let mut state = state.borrow_mut();
+                    if let Some(listener) = state.as_any().downcast_mut::<ConnectionListener>() {
+                        listener.remap(core, poll, &mc_0);
+                    }
+                }
+                let _ = event_tx.send(Event::NetworkChanged);
+            };
+            if let Err(e) = IfWatcher::start(core, mc, Box::new(on_change)) {
+                debug!("Could not start watching network interfaces: {:?}", e);
+            }
+        })
+    }
 
-        Ok(Service {
-               cm: Arc::new(Mutex::new(HashMap::new())),
-               config: config,
-               event_tx: event_tx,
-               mc: Arc::new(mc),
-               el: el,
-               name_hash: name_hash,
-               our_keys: our_keys,
-               our_listeners: our_listeners,
-           })
+    // Bootstraps off another relay once the one peers are told to reach us through is lost.
+    fn start_relay_watch(&self) -> ::Res<()> {
+        if !self.config.bootstrap_via_relay.unwrap_or(false) {
+            return Ok(());
+        }
+        let mut bootstrap = self.bootstrapper(CrustUser::Node);
+        let our_relay = self.our_relay.clone();
+        let cm = self.cm.clone();
+        self.post(move |core, _| {
+            let on_lost = move |core: &mut Core, poll: &Poll| {
+                bootstrap(core, poll, HashSet::new())
+            };
+            if let Err(e) = RelayWatch::start(core, our_relay, cm, Box::new(on_lost)) {
+                debug!("Could not start watching our relay: {:?}", e);
+            }
+        })
     }
 
-    /// Starts listening for beacon broadcasts.
+    /// Starts listening for beacon broadcasts, and for the IPv6 link-local multicast beacon where
+    /// the host has IPv6.
     pub fn start_service_discovery(&mut self) {
         let our_listeners = self.our_listeners.clone();
         let port = self.config
             .service_discovery_port
             .unwrap_or(SERVICE_DISCOVERY_DEFAULT_PORT);
+        let name_hash = self.name_hash;
+        // Nodes predating genesis values can only be of a network without one
+        let compatible = self.config.network_genesis.is_none();
 
         let _ = self.post(move |core, poll| {
             if core.get_state(SERVICE_DISCOVERY_TOKEN).is_none() {
@@ -104,7 +432,9 @@ impl Service {
                                                         poll,
                                                         our_listeners,
                                                         SERVICE_DISCOVERY_TOKEN,
-                                                        port) {
+                                                        port,
+                                                        name_hash,
+                                                        compatible) {
                     debug!("Could not start ServiceDiscovery: {:?}", e);
                 }
             }
@@ -133,14 +463,14 @@ impl Service {
     }
 
     fn get_peer_socket_addr(&self, peer_id: &PeerId) -> ::Res<SocketAddr> {
-        let token = match unwrap!(self.cm.lock()).get(peer_id) {
+        let token = match lock(&self.cm).get(peer_id) {
             Some(&ConnectionId { active_connection: Some(token), .. }) => token,
             _ => return Err(CrustError::PeerNotFound(*peer_id)),
         };
 
         let (tx, rx) = mpsc::channel();
 
-        let _ = self.post(move |core, _| {
+        let _ = self.post_on(token, move |core, _| {
             let state = match core.get_state(token) {
                 Some(state) => state,
                 None => {
@@ -214,9 +544,6 @@ impl Service {
     // TODO temp remove
     /// Check if we have peers on LAN
     pub fn has_peers_on_lan(&self) -> bool {
-        use std::time::Duration;
-        use std::thread;
-
         let (obs, rx) = mpsc::channel();
         let _ = self.post(move |core, _| {
             let state = match core.get_state(SERVICE_DISCOVERY_TOKEN) {
@@ -245,39 +572,63 @@ impl Service {
                            blacklist: HashSet<SocketAddr>,
                            crust_user: CrustUser)
                            -> ::Res<()> {
+        self.check_paused()?;
+        let mut bootstrap = self.bootstrapper(crust_user);
+        self.post(move |core, poll| bootstrap(core, poll, blacklist))
+    }
+
+    // Starts a bootstrap on the event loop it is run on, unless one is already running there.
+    fn bootstrapper(&self, crust_user: CrustUser) -> Bootstrapper {
         let config = self.config.clone();
         let our_pk = self.our_keys.0;
+        let identity = self.identity.clone();
         let name_hash = self.name_hash;
         let cm = self.cm.clone();
         let event_tx = self.event_tx.clone();
-        let ext_reachability = match crust_user {
-            CrustUser::Node => {
-                ExternalReachability::Required {
-                    direct_listeners: unwrap!(self.our_listeners.lock())
-                        .iter()
-                        .cloned()
-                        .collect(),
+        let bandwidth = self.bandwidth.clone();
+        let ban_list = self.ban_list.clone();
+        let whitelist = self.whitelist.clone();
+        let metrics = self.metrics.clone();
+        let our_relay = self.our_relay.clone();
+        let our_listeners = self.our_listeners.clone();
+        let via_relay = self.config.bootstrap_via_relay.unwrap_or(false);
+
+        Box::new(move |core, poll, blacklist| {
+            if core.get_state(BOOTSTRAP_TOKEN).is_some() {
+                return;
+            }
+            let ext_reachability = match crust_user {
+                CrustUser::Node => {
+                    let direct_listeners = lock(&our_listeners).clone();
+                    if via_relay {
+                        ExternalReachability::Relayed { direct_listeners: direct_listeners }
+                    } else {
+                        ExternalReachability::Required { direct_listeners: direct_listeners }
+                    }
                 }
+                CrustUser::Client => ExternalReachability::NotRequired,
+            };
+            if let Err(e) = Bootstrap::start(core,
+                                             poll,
+                                             name_hash,
+                                             ext_reachability,
+                                             our_relay.clone(),
+                                             our_pk,
+                                             identity.clone(),
+                                             cm.clone(),
+                                             &config,
+                                             bandwidth.clone(),
+                                             blacklist,
+                                             ban_list.clone(),
+                                             whitelist.clone(),
+                                             metrics.clone(),
+                                             BOOTSTRAP_TOKEN,
+                                             SERVICE_DISCOVERY_TOKEN,
+                                             event_tx.clone()) {
+                error!("Could not bootstrap: {:?}", e);
+                let _ = event_tx.send(Event::BootstrapFailed(Vec::new()));
             }
-            CrustUser::Client => ExternalReachability::NotRequired,
-        };
-
-        self.post(move |core, poll| if core.get_state(BOOTSTRAP_TOKEN).is_none() {
-                      if let Err(e) = Bootstrap::start(core,
-                                                       poll,
-                                                       name_hash,
-                                                       ext_reachability,
-                                                       our_pk,
-                                                       cm,
-                                                       &config,
-                                                       blacklist,
-                                                       BOOTSTRAP_TOKEN,
-                                                       SERVICE_DISCOVERY_TOKEN,
-                                                       event_tx.clone()) {
-                          error!("Could not bootstrap: {:?}", e);
-                          let _ = event_tx.send(Event::BootstrapFailed);
-                      }
-                  })
+        })
     }
 
     /// Stop the bootstraping procedure explicitly
@@ -288,38 +639,121 @@ impl Service {
     }
 
     /// Starts accepting TCP connections. This is persistant until it errors out or is stopped
-    /// explicitly.
+    /// explicitly. With `Config::tcp_listen_addrs`, a listener is started on each of them, each
+    /// reporting `Event::ListenerStarted` or `Event::ListenerFailed` on its own.
     pub fn start_listening_tcp(&mut self) -> ::Res<()> {
         let cm = self.cm.clone();
         let mc = self.mc.clone();
-        let port = self.config.tcp_acceptor_port.unwrap_or(0);
+        let ports = match (self.config.tcp_acceptor_port, self.config.tcp_acceptor_port_range) {
+            (Some(port), _) => PortRange::from(port),
+            (None, Some((first, last))) => {
+                PortRange {
+                    first: first,
+                    last: last,
+                }
+            }
+            (None, None) => PortRange::from(0),
+        };
+        let binds: Vec<Option<SocketAddr>> = match self.config.tcp_listen_addrs {
+            Some(ref addrs) => addrs.iter().cloned().map(Some).collect(),
+            None => vec![None],
+        };
+        let listener_tokens = self.listener_tokens.clone();
         let force_include_port = self.config.force_acceptor_port_in_ext_ep;
+        let handshake_timeout_sec = self.config.handshake_timeout_secs;
+        let limits = accept_limits(&self.config);
+        let act_as_relay = self.config.act_as_relay.unwrap_or(false);
+        // Only Linux spreads connections over the sockets sharing a port, see
+        // `Config::reuse_port_listeners`
+        let reuse_port = cfg!(target_os = "linux") && !act_as_relay &&
+                         self.config.reuse_port_listeners.unwrap_or(false);
+        let keep_alive = keep_alive_period(&self.config);
+        let inactivity_timeout = inactivity_timeout(&self.config);
+        let drop_policy = drop_policy(&self.config);
+        let bandwidth = self.bandwidth.clone();
+        let metrics = self.metrics.clone();
+        let compression = compression_policy(&self.config);
         let our_pk = self.our_keys.0;
+        let identity = self.identity.clone();
+        let expected_identities = self.expected_identities.clone();
+        let offers = self.offers.clone();
+        let whitelist = self.whitelist.clone();
         let name_hash = self.name_hash;
         let our_listeners = self.our_listeners.clone();
         let event_tx = self.event_tx.clone();
 
-        self.post(move |core, poll| if core.get_state(LISTENER_TOKEN).is_none() {
-                      ConnectionListener::start(core,
-                                                poll,
-                                                None,
-                                                port,
-                                                force_include_port,
-                                                our_pk,
-                                                name_hash,
-                                                cm,
-                                                mc,
-                                                our_listeners,
-                                                LISTENER_TOKEN,
-                                                event_tx);
-                  })
+        self.post(move |core, poll| {
+            let mut tokens = lock(&listener_tokens);
+            if tokens.iter().any(|token| core.get_state(*token).is_some()) {
+                return;
+            }
+            tokens.clear();
+            for bind in binds {
+                let token = if tokens.is_empty() {
+                    LISTENER_TOKEN
+                } else {
+                    core.get_new_token()
+                };
+                tokens.push(token);
+                ConnectionListener::start(core,
+                                          poll,
+                                          handshake_timeout_sec,
+                                          limits,
+                                          keep_alive,
+                                          inactivity_timeout,
+                                          drop_policy,
+                                          bandwidth.clone(),
+                                          metrics.clone(),
+                                          compression,
+                                          ports,
+                                          bind,
+                                          force_include_port,
+                                          act_as_relay,
+                                          reuse_port,
+                                          our_pk,
+                                          identity.clone(),
+                                          expected_identities.clone(),
+                                          offers.clone(),
+                                          whitelist.clone(),
+                                          name_hash,
+                                          cm.clone(),
+                                          mc.clone(),
+                                          our_listeners.clone(),
+                                          token,
+                                          event_tx.clone());
+            }
+        })
     }
 
     /// Stops Listener explicitly and stops accepting TCP connections.
     pub fn stop_tcp_listener(&mut self) -> ::Res<()> {
-        self.post(move |core, poll| if let Some(state) = core.get_state(LISTENER_TOKEN) {
-                      state.borrow_mut().terminate(core, poll);
-                  })
+        let listener_tokens = self.listener_tokens.clone();
+        self.post(move |core, poll| stop_listeners(core, poll, &listener_tokens))
+    }
+
+    /// How each of our running listeners can be reached from outside our network: thanks to a
+    /// port our router forwarded over UPnP, one forwarded by hand, a NAT which lets anyone reach
+    /// the address peers see us at, or not at all, as checked by having a helper peer connect back
+    /// to it. Should none be reachable, peers behind NATs cannot connect to us and the user may
+    /// need to forward a port on their router.
+    pub fn external_reachability(&self) -> Vec<ListenerReachability> {
+        let listener_tokens = self.listener_tokens.clone();
+        let (tx, rx) = mpsc::channel();
+        let _ = self.post(move |core, _| {
+            let mut reachability = Vec::new();
+            for token in lock(&listener_tokens).iter() {
+                let state = match core.get_state(*token) {
+                    Some(state) => state,
+                    None => continue,
+                };
+                let mut state = state.borrow_mut();
+                if let Some(listener) = state.as_any().downcast_mut::<ConnectionListener>() {
+                    reachability.push(listener.reachability());
+                }
+            }
+            let _ = tx.send(reachability);
+        });
+        rx.recv().unwrap_or_default()
     }
 
     /// Connect to a peer. To call this method you must follow these steps:
@@ -328,52 +762,517 @@ impl Service {
     ///  * Swap `PubConnectionInfo`s out-of-band with the peer you are connecting to.
     ///  * Call `Service::connect` using your `PrivConnectionInfo` and the `PubConnectionInfo`
     ///    obtained from the peer
+    ///
+    /// The peer's info may be connected with only once and before it expires, see
+    /// `Config::connection_info_ttl_secs`, failing with `CrustError::ConnectionInfoExpired` or
+    /// `CrustError::ConnectionInfoReplayed` otherwise.
     pub fn connect(&self, our_ci: PrivConnectionInfo, their_ci: PubConnectionInfo) -> ::Res<()> {
+        self.connect_with(our_ci, their_ci, None)
+    }
+
+    /// Connect to a peer with our candidates from `gather_candidates` and the offer it handed us,
+    /// returning its candidates paired with ours in the priority order of ICE.
+    ///
+    /// This is not an ICE agent: the pairs are not checked one at a time and none is nominated.
+    /// Its TCP candidates are connected to as `connect` connects to the equivalent connection
+    /// info - its listeners dialed `Config::connect_stagger_ms` apart and then its relay, while
+    /// all its mapped sockets are opened to at once from our one hole punch socket - and the first
+    /// connection to handshake is kept, whichever pair it is over. Our side of each pair is only
+    /// there to rank it. UDP pairs are not checked at all but left for the caller to punch a hole
+    /// over with `punch_udp_hole`, crust connections running over TCP only.
+    pub fn connect_candidates(&self,
+                              ours: LocalCandidates,
+                              theirs: &ConnectionCandidates)
+                              -> ::Res<Vec<CandidatePair>> {
+        let pairs = ours.offer().pair_with(theirs);
+        let mut their_ci = PubConnectionInfo {
+            id: theirs.id,
+            for_direct: Vec::new(),
+            for_hole_punch: Vec::new(),
+            relay: None,
+            stamp: theirs.stamp,
+        };
+        for pair in &pairs {
+            let addr = pair.remote.addr;
+            match (pair.remote.transport, pair.remote.kind) {
+                (CandidateTransport::TcpPassive, CandidateKind::Relayed) => {
+                    their_ci.relay = their_ci.relay.or(Some(addr));
+                }
+                (CandidateTransport::TcpPassive, _) => their_ci.for_direct.push(addr),
+                (CandidateTransport::TcpSimultaneousOpen, _) => {
+                    if !their_ci.for_hole_punch.contains(&addr) {
+                        their_ci.for_hole_punch.push(addr);
+                    }
+                }
+                (CandidateTransport::Udp, _) => (),
+            }
+        }
+        self.connect_with(ours.into_connection_info(), their_ci, None)?;
+        Ok(pairs)
+    }
+
+    /// Punch a hole from our UDP `socket`, as taken from the candidates gathered with
+    /// `gather_candidates`, to the UDP candidates of the peer in `theirs`. The peer is to punch
+    /// one towards ours at the same time with the same `secret`, which proves each side to the
+    /// other. The result arrives as an `Event::UdpHolePunched` carrying `result_token`.
+    pub fn punch_udp_hole(&self,
+                          socket: UdpSocket,
+                          theirs: &ConnectionCandidates,
+                          secret: Vec<u8>,
+                          result_token: u32) {
+        let peer_addrs: Vec<SocketAddr> = theirs
+            .candidates
+            .iter()
+            .filter(|candidate| candidate.transport == CandidateTransport::Udp)
+            .map(|candidate| candidate.addr)
+            .collect();
+        let event_tx = self.event_tx.clone();
+        let send_result = move |result| {
+            let _ = event_tx.send(Event::UdpHolePunched(UdpHolePunchResult {
+                                                            result_token: result_token,
+                                                            result: result,
+                                                        }));
+        };
+        if peer_addrs.is_empty() {
+            return send_result(Err(CrustError::InvalidConnectionInfo("no UDP candidates")));
+        }
+        let send_error = send_result.clone();
+        if let Err(e) = self.post(move |core, poll| {
+            let send_error = send_result.clone();
+            let finish = move |_: &mut Core, _: &Poll, res: Option<(UdpSocket, SocketAddr)>| {
+                send_result(res.ok_or_else(|| CrustError::Nat(NatError::UdpHolePunchFailed)))
+            };
+            if let Err(e) = UdpHolePunch::start(core, poll, socket, peer_addrs, secret, finish) {
+                send_error(Err(From::from(e)));
+            }
+        }) {
+            send_error(Err(e));
+        }
+    }
+
+    /// Connect to a peer as `connect` does, but only if it proves to have `identity`, whether we
+    /// dial it or it dials us. Should it prove any other, the connection is refused and
+    /// `Event::ConnectFailure` reported. A connection already made or under way when this is
+    /// called is left as it is, its identity having been reported in `Event::ConnectSuccess`.
+    pub fn connect_to_identity(&self,
+                               our_ci: PrivConnectionInfo,
+                               their_ci: PubConnectionInfo,
+                               identity: Identity)
+                               -> ::Res<()> {
+        self.connect_with(our_ci, their_ci, Some(identity))
+    }
+
+    /// Connect to the peer that sealed `their_ci` as `connect_to_identity` does, once it opens
+    /// and is found to be signed by `identity`. Nothing is dialled if it fails to open or was
+    /// signed by anyone else, as anyone can seal info of their own.
+    pub fn connect_sealed(&self,
+                          our_ci: PrivConnectionInfo,
+                          their_ci: &SealedConnectionInfo,
+                          identity: Identity)
+                          -> ::Res<()> {
+        let (their_ci, signer) = self.open_connection_info(their_ci)?;
+        if signer != identity {
+            debug!("Connection info of {:?} is signed by {:?} rather than {:?}",
+                   their_ci.id,
+                   signer,
+                   identity);
+            return Err(CrustError::InvalidConnectionInfo("signed by another identity"));
+        }
+        self.connect_with(our_ci, their_ci, Some(identity))
+    }
+
+    /// Sign `info` with our identity so that the peer can tell it was not tampered with on the
+    /// way. Given a `recipient` it is also encrypted, so only that peer can read it.
+    pub fn seal_connection_info(&self,
+                                info: &PubConnectionInfo,
+                                recipient: Option<PeerId>)
+                                -> ::Res<SealedConnectionInfo> {
+        SealedConnectionInfo::seal(info, &self.identity, &self.our_keys.1, recipient.as_ref())
+    }
+
+    /// Check and, if need be, decrypt connection info sealed by a peer, returning it along with
+    /// the identity that signed it.
+    pub fn open_connection_info(&self,
+                                sealed: &SealedConnectionInfo)
+                                -> ::Res<(PubConnectionInfo, Identity)> {
+        sealed.open(&self.our_keys.1)
+    }
+
+    fn connect_with(&self,
+                    our_ci: PrivConnectionInfo,
+                    their_ci: PubConnectionInfo,
+                    expected_identity: Option<Identity>)
+                    -> ::Res<()> {
+        self.check_paused()?;
         if their_ci.id == PeerId(self.our_keys.0) {
             debug!("Requested connect to {:?}, which is our peer ID",
                    their_ci.id);
             return Err(CrustError::RequestedConnectToSelf);
         }
 
-        if unwrap!(self.cm.lock()).contains_key(&their_ci.id) {
+        if lock(&self.cm).contains_key(&their_ci.id) {
             debug!("Already connected OR already in process of connecting to {:?}",
                    their_ci.id);
             return Ok(());
         }
 
+        lock(&self.offers).check(their_ci.id, &their_ci.stamp)?;
+
+        // Connecting afresh takes over from reconnecting to the peer
+        if let Some(token) = lock(&self.reconnecting).remove(&their_ci.id) {
+            let _ = self.post_on(token, move |core, poll| if let Some(state) =
+                core.get_state(token) {
+                                     state.borrow_mut().terminate(core, poll);
+                                 });
+        }
+
+        // Before the peer can dial our listener in turn
+        if let Some(identity) = expected_identity {
+            let _ = lock(&self.expected_identities).insert(their_ci.id, identity);
+        }
+
         let event_tx = self.event_tx.clone();
         let cm = self.cm.clone();
         let our_nh = self.name_hash;
+        let our_relay = self.our_relay.clone();
+        let config_relay = self.config.relay;
+        let relay = relay_between(our_ci.id, our_ci.relay, &their_ci, config_relay);
+        let migrate = self.config.migrate_relayed.unwrap_or(false);
+        let race = self.race.clone();
+        let stats = self.mc.stats();
+        let socket_config = self.mc.mapping_config().socket;
+        let keep_alive = keep_alive_period(&self.config);
+        let inactivity_timeout = inactivity_timeout(&self.config);
+        let drop_policy = drop_policy(&self.config);
+        let bandwidth = self.bandwidth.clone();
+        let compression = compression_policy(&self.config);
+        let reconnect_policy = reconnect_policy(&self.config);
+        let ban_list = self.ban_list.clone();
+        let whitelist = self.whitelist.clone();
+        let metrics = self.metrics.clone();
+        let identity = self.identity.clone();
+        let expected_identities = self.expected_identities.clone();
+        let offers = self.offers.clone();
+        let info_ttl = connection_info_ttl(&self.config);
+        let reconnecting = self.reconnecting.clone();
 
         Ok(self.post(move |core, poll| {
-                         let _ = Connect::start(core, poll, our_ci, their_ci, cm, our_nh, event_tx);
-                     })?)
+            let our_id = our_ci.id;
+            let our_hole_punch = our_ci
+                .hole_punch_socket
+                .as_ref()
+                .and_then(|socket| socket.local_addr().ok());
+            let reconnect = reconnect_policy.and_then(|policy| {
+                let cm_of_reconnect = cm.clone();
+                let cm = cm.clone();
+                let stats = stats.clone();
+                let bandwidth = bandwidth.clone();
+                let redial_tx = event_tx.clone();
+                let ban_list = ban_list.clone();
+                let whitelist = whitelist.clone();
+                let metrics = metrics.clone();
+                let identity = identity.clone();
+                let expected_identities = expected_identities.clone();
+                let offers = offers.clone();
+                let our_relay = our_relay.clone();
+                let race = race.clone();
+                let redial = move |core: &mut Core,
+                                   poll: &Poll,
+                                   their_ci: PubConnectionInfo,
+                                   reconnect: Reconnect| {
+                    // Bound where it was before, so the peer finds us at the same mapping
+                    let hole_punch_socket = reconnect
+                        .our_hole_punch()
+                        .and_then(|addr| match nat::new_reusably_bound_tcp_socket(&addr,
+                                                                                   &socket_config) {
+                                      Ok(socket) => Some(socket),
+                                      Err(e) => {
+                                          debug!("Could not rebind {} to punch a hole from: {:?}",
+                                                 addr,
+                                                 e);
+                                          None
+                                      }
+                                  });
+                    let our_ci = PrivConnectionInfo {
+                        id: our_id,
+                        for_direct: Vec::new(),
+                        for_hole_punch: Vec::new(),
+                        hole_punch_socket: hole_punch_socket,
+                        relay: lock(&our_relay).map(|(_, addr)| addr),
+                        stamp: OfferStamp::new(info_ttl),
+                    };
+                    let relay = relay_between(our_id, our_ci.relay, &their_ci, config_relay);
+                    let _ = Connect::start(core,
+                                           poll,
+                                           our_ci,
+                                           their_ci,
+                                           cm.clone(),
+                                           our_nh,
+                                           identity.clone(),
+                                           None,
+                                           expected_identities.clone(),
+                                           offers.clone(),
+                                           relay,
+                                           migrate,
+                                           race.clone(),
+                                           stats.clone(),
+                                           socket_config,
+                                           keep_alive,
+                                           inactivity_timeout,
+                                           drop_policy,
+                                           bandwidth.clone(),
+                                           compression,
+                                           Some(reconnect),
+                                           ban_list.clone(),
+                                           whitelist.clone(),
+                                           metrics.clone(),
+                                           redial_tx.clone());
+                };
+                Reconnect::new(policy,
+                               &their_ci,
+                               our_hole_punch,
+                               reconnecting,
+                               cm_of_reconnect,
+                               Rc::new(redial),
+                               event_tx.clone())
+            });
+            let _ = Connect::start(core,
+                                   poll,
+                                   our_ci,
+                                   their_ci,
+                                   cm,
+                                   our_nh,
+                                   identity,
+                                   expected_identity,
+                                   expected_identities,
+                                   offers,
+                                   relay,
+                                   migrate,
+                                   race,
+                                   stats,
+                                   socket_config,
+                                   keep_alive,
+                                   inactivity_timeout,
+                                   drop_policy,
+                                   bandwidth,
+                                   compression,
+                                   reconnect,
+                                   ban_list,
+                                   whitelist,
+                                   metrics,
+                                   event_tx);
+        })?)
     }
 
     /// Disconnect from the given peer and returns whether there was a connection at all.
     pub fn disconnect(&self, peer_id: PeerId) -> bool {
-        let token = match unwrap!(self.cm.lock()).get(&peer_id) {
+        let token = match lock(&self.cm).get(&peer_id) {
             Some(&ConnectionId { active_connection: Some(token), .. }) => token,
             _ => return false,
         };
 
-        let _ = self.post(move |core, poll| if let Some(state) = core.get_state(token) {
-                              state.borrow_mut().terminate(core, poll);
-                          });
+        let _ = self.post_on(token, move |core, poll| if let Some(state) = core.get_state(token) {
+                                        let mut state = state.borrow_mut();
+                                        if let Some(conn) = state
+                                               .as_any()
+                                               .downcast_mut::<ActiveConnection>() {
+                                            conn.cancel_reconnect();
+                                        }
+                                        state.terminate(core, poll);
+                                    });
 
         true
     }
 
-    /// Send data to a peer.
+    /// Send data to a peer. Fails with `CrustError::EventLoopBusy` if too much data is already
+    /// waiting to be sent, in which case the caller should back off and try again later, and
+    /// likewise with `CrustError::QueueFull` while `Config::max_queued_bytes` is reached under
+    /// `QueueFullPolicy::Refuse`, or `CrustError::NetworkPaused` while paused. Under
+    /// `QueueFullPolicy::Block` it waits for the queues to drain instead. Messages to a peer being
+    /// reconnected to are held until it is, see `Config::reconnect_attempts`.
     pub fn send(&self, peer_id: PeerId, msg: Vec<u8>, priority: Priority) -> ::Res<()> {
-        let token = match unwrap!(self.cm.lock()).get(&peer_id) {
+        let token = self.sending_token(&peer_id)?;
+        self.check_paused()?;
+        self.check_queue()?;
+
+        self.post_data_on(token,
+                          msg.len(),
+                          move |core, poll| if let Some(state) = core.get_state(token) {
+                              state.borrow_mut().write(core, poll, msg, priority);
+                          })
+    }
+
+    /// Send data to a peer as `send` does, returning a token that `Event::MessageSent` or
+    /// `Event::MessageNotSent` is later reported with to tell whether it was written out. A
+    /// message kept to be sent again after reconnecting is only reported on once it is, or once
+    /// the peer is given up on.
+    pub fn send_tracked(&self,
+                        peer_id: PeerId,
+                        msg: Vec<u8>,
+                        priority: Priority)
+                        -> ::Res<SendToken> {
+        let token = self.sending_token(&peer_id)?;
+        self.check_paused()?;
+        self.check_queue()?;
+        let send_token = self.next_send.fetch_add(1, Ordering::Relaxed) as SendToken;
+        let event_tx = self.event_tx.clone();
+
+        self.post_data_on(token, msg.len(), move |core, poll| {
+            if let Some(state) = core.get_state(token) {
+                let mut state = state.borrow_mut();
+                if state.as_any().is::<ActiveConnection>() {
+                    if let Some(conn) = state.as_any().downcast_mut::<ActiveConnection>() {
+                        return conn.send_tracked(core, poll, msg, priority, send_token);
+                    }
+                } else if reconnect::hold(&mut *state, msg, priority, Some(send_token)) {
+                    return;
+                }
+            }
+            // The connection was lost before the message got to it
+            let _ = event_tx.send(Event::MessageNotSent(peer_id, send_token));
+        })?;
+        Ok(send_token)
+    }
+
+    /// Ask a connected peer which address it sees us as, over the connection to it rather than a
+    /// fresh socket as traversal does. It is reported via `Event::ExternalAddrReported`, and
+    /// remembered so that the next mappings can skip asking the peers for our external IP.
+    pub fn query_ext_addr(&self, peer_id: PeerId) -> ::Res<()> {
+        let token = match lock(&self.cm).get(&peer_id) {
             Some(&ConnectionId { active_connection: Some(token), .. }) => token,
             _ => return Err(CrustError::PeerNotFound(peer_id)),
         };
+        let event_tx = self.event_tx.clone();
+        let mc = self.mc.clone();
+        self.post_on(token, move |core, poll| {
+            if let Some(state) = core.get_state(token) {
+                let mut state = state.borrow_mut();
+                if let Some(conn) = state.as_any().downcast_mut::<ActiveConnection>() {
+                    return conn.query_ext_addr(core, poll, mc);
+                }
+            }
+            let _ = event_tx.send(Event::ExternalAddrReported(peer_id, None));
+        })
+    }
 
-        self.post(move |core, poll| if let Some(state) = core.get_state(token) {
-                      state.borrow_mut().write(core, poll, msg, priority);
-                  })
+    /// Meet another client sending the same `key` to the echo service of the node listening at
+    /// `server`, swapping `info` with it, e.g. a serialised `PubConnectionInfo` so that the two
+    /// can connect without an out-of-band channel of their own. It is reported via
+    /// `Event::RendezvousMet`. The info may be at most 1 KiB.
+    pub fn rendezvous(&self, server: SocketAddr, key: Vec<u8>, info: Vec<u8>) -> ::Res<()> {
+        let event_tx = self.event_tx.clone();
+        self.post(move |core, poll| {
+            let event_tx_0 = event_tx.clone();
+            let key_0 = key.clone();
+            let finish = move |_: &mut Core, _: &Poll, res: RendezvousResult| {
+                let met = match res {
+                    Ok(met) => Some(met),
+                    Err(e) => {
+                        debug!("Rendezvous via {} failed: {:?}", server, e);
+                        None
+                    }
+                };
+                let _ = event_tx_0.send(Event::RendezvousMet(key_0, met));
+            };
+            if let Err(e) = RendezvousClient::start(core, poll, server, key.clone(), info, finish) {
+                debug!("Could not start a rendezvous via {}: {:?}", server, e);
+                let _ = event_tx.send(Event::RendezvousMet(key, None));
+            }
+        })
+    }
+
+    /// Open a substream of the connection to a peer, returning its id. Substreams share the
+    /// connection but are flow controlled separately, so e.g. a bulk transfer on one does not hold
+    /// up messages on the others. The peer is told via `Event::StreamOpened`. Fails with
+    /// `CrustError::EventLoopBusy` as `send` does, since it is queued along with the data.
+    pub fn open_stream(&self, peer_id: PeerId) -> ::Res<StreamId> {
+        let token = match lock(&self.cm).get(&peer_id) {
+            Some(&ConnectionId { active_connection: Some(token), .. }) => token,
+            _ => return Err(CrustError::PeerNotFound(peer_id)),
+        };
+
+        // The peer with the lower id opens even substreams, the other odd ones.
+        let parity = if PeerId(self.our_keys.0) < peer_id { 0 } else { 1 };
+        let n = self.next_stream.fetch_add(1, Ordering::Relaxed) as StreamId;
+        let id = n.wrapping_mul(2) + parity;
+
+        self.post_data_on(token,
+                          0,
+                          move |core, poll| if let Some(state) = core.get_state(token) {
+                              let mut state = state.borrow_mut();
+                              if let Some(conn) = state
+                                     .as_any()
+                                     .downcast_mut::<ActiveConnection>() {
+                                  conn.open_stream(core, poll, id);
+                              }
+                          })?;
+        Ok(id)
+    }
+
+    /// Send data on a substream opened by either peer. It is held back while the peer is still
+    /// busy with earlier data on the same substream. Since such data is never dropped, `priority`
+    /// is capped below `MSG_DROP_PRIORITY`. Fails with `CrustError::EventLoopBusy` as `send` does.
+    pub fn send_on_stream(&self,
+                          peer_id: PeerId,
+                          stream: StreamId,
+                          msg: Vec<u8>,
+                          priority: Priority)
+                          -> ::Res<()> {
+        let token = match lock(&self.cm).get(&peer_id) {
+            Some(&ConnectionId { active_connection: Some(token), .. }) => token,
+            _ => return Err(CrustError::PeerNotFound(peer_id)),
+        };
+        self.check_paused()?;
+
+        self.post_data_on(token,
+                          msg.len(),
+                          move |core, poll| if let Some(state) = core.get_state(token) {
+                              let mut state = state.borrow_mut();
+                              if let Some(conn) = state
+                                     .as_any()
+                                     .downcast_mut::<ActiveConnection>() {
+                                  conn.write_stream(core, poll, stream, msg, priority);
+                              }
+                          })
+    }
+
+    /// Tell the peer that `len` bytes delivered on a substream by `Event::NewStreamMessage` have
+    /// been dealt with, so it may send more. A peer only sends so much on a substream before
+    /// waiting for this, so that data piles up with it rather than with us.
+    pub fn consume_stream(&self, peer_id: PeerId, stream: StreamId, len: usize) -> ::Res<()> {
+        let token = match lock(&self.cm).get(&peer_id) {
+            Some(&ConnectionId { active_connection: Some(token), .. }) => token,
+            _ => return Err(CrustError::PeerNotFound(peer_id)),
+        };
+
+        self.post_on(token, move |core, poll| {
+            if let Some(state) = core.get_state(token) {
+                let mut state = state.borrow_mut();
+                if let Some(conn) = state.as_any().downcast_mut::<ActiveConnection>() {
+                    conn.consume_stream(core, poll, stream, len);
+                }
+            }
+        })
+    }
+
+    /// Close a substream once the data already sent on it has gone out. Fails with
+    /// `CrustError::EventLoopBusy` as `send` does, since it is queued behind that data.
+    pub fn close_stream(&self, peer_id: PeerId, stream: StreamId) -> ::Res<()> {
+        let token = match lock(&self.cm).get(&peer_id) {
+            Some(&ConnectionId { active_connection: Some(token), .. }) => token,
+            _ => return Err(CrustError::PeerNotFound(peer_id)),
+        };
+
+        self.post_data_on(token,
+                          0,
+                          move |core, poll| if let Some(state) = core.get_state(token) {
+                              let mut state = state.borrow_mut();
+                              if let Some(conn) = state
+                                     .as_any()
+                                     .downcast_mut::<ActiveConnection>() {
+                                  conn.close_stream(core, poll, stream);
+                              }
+                          })
     }
 
     /// Generate connection info. The connection info is returned via the `ConnectionInfoPrepared`
@@ -381,10 +1280,12 @@ impl Service {
     /// peer, see `Service::connect` for more info.
     // TODO: immediate return in case of sender.send() returned with NotificationError
     pub fn prepare_connection_info(&self, result_token: u32) {
-        let our_listeners = unwrap!(self.our_listeners.lock())
+        let our_listeners = lock(&self.our_listeners)
             .iter()
             .cloned()
             .collect();
+        let our_relay = lock(&self.our_relay).map(|(_, addr)| addr);
+        let stamp = lock(&self.offers).issue(connection_info_ttl(&self.config));
         if DISABLE_NAT {
             let event =
                 Event::ConnectionInfoPrepared(ConnectionInfoResult {
@@ -394,6 +1295,8 @@ impl Service {
                                                                  for_direct: our_listeners,
                                                                  for_hole_punch: Default::default(),
                                                                  hole_punch_socket: None,
+                                                                 relay: our_relay,
+                                                                 stamp: stamp,
                                                              }),
                                               });
             let _ = self.event_tx.send(event);
@@ -401,29 +1304,48 @@ impl Service {
             let event_tx = self.event_tx.clone();
             let our_pub_key = self.our_keys.0;
             let mc = self.mc.clone();
+            let pending_mappings = self.pending_mappings.clone();
+            let mapping_id = self.next_mapping.fetch_add(1, Ordering::Relaxed);
             if let Err(e) = self.post(move |mut core, poll| {
                 let event_tx_clone = event_tx.clone();
-                match MappedTcpSocket::start(core, poll, 0, &mc, move |_, _, socket, addrs| {
-                    let hole_punch_addrs = addrs
-                        .into_iter()
-                        .filter(|elt| nat::ip_addr_is_global(&elt.ip()))
-                        .collect();
-                    let event_tx = event_tx_clone;
-                    let event =
-                        Event::ConnectionInfoPrepared(ConnectionInfoResult {
-                                                          result_token: result_token,
-                                                          result: Ok(PrivConnectionInfo {
-                                                                         id: PeerId(our_pub_key),
-                                                                         for_direct: our_listeners,
-                                                                         for_hole_punch:
-                                                                             hole_punch_addrs,
-                                                                         hole_punch_socket:
-                                                                             Some(socket),
-                                                                     }),
-                                                      });
-                    let _ = event_tx.send(event);
-                }) {
-                    Ok(()) => (),
+                let pending_mappings_clone = pending_mappings.clone();
+                // The mapping may be over before it has even started
+                let finished = Rc::new(Cell::new(false));
+                let finished_clone = finished.clone();
+                let finish = move |_: &mut Core, _: &Poll, res: MappingResult| {
+                    finished_clone.set(true);
+                    forget_mapping(&pending_mappings_clone, result_token, mapping_id);
+                    let result = res.map(|(socket, addrs)| {
+                        let hole_punch_addrs = addrs
+                            .into_iter()
+                            .map(|mapped| mapped.addr)
+                            .filter(|elt| nat::ip_addr_is_global(&elt.ip()))
+                            .collect();
+                        PrivConnectionInfo {
+                            id: PeerId(our_pub_key),
+                            for_direct: our_listeners,
+                            for_hole_punch: hole_punch_addrs,
+                            hole_punch_socket: Some(socket),
+                            relay: our_relay,
+                            stamp: stamp,
+                        }
+                    });
+                    let event = Event::ConnectionInfoPrepared(ConnectionInfoResult {
+                                                                  result_token: result_token,
+                                                                  result: result
+                                                                      .map_err(From::from),
+                                                              });
+                    let _ = event_tx_clone.send(event);
+                };
+                match MappedTcpSocket::start(core, poll, 0, &mc, finish) {
+                    Ok(handle) => {
+                        if !finished.get() {
+                            lock(&pending_mappings)
+                                .entry(result_token)
+                                .or_insert_with(Vec::new)
+                                .push((mapping_id, handle));
+                        }
+                    }
                     Err(e) => {
                         debug!("Error mapping tcp socket: {}", e);
                         let _ = event_tx
@@ -443,78 +1365,890 @@ impl Service {
         }
     }
 
-    /// Check if we are connected to the given peer
-    pub fn is_connected(&self, peer_id: &PeerId) -> bool {
-        match unwrap!(self.cm.lock()).get(peer_id) {
-            Some(&ConnectionId { active_connection: Some(_), .. }) => true,
-            _ => false,
+    /// Gather every way we may be reached at - our listeners, a mapped TCP socket to hole punch
+    /// with, a mapped UDP socket and the relay we are reached through - all at once, along with
+    /// our NAT type. The result arrives as an `Event::CandidatesGathered` carrying
+    /// `result_token`, whose offer is to be handed out of band to the peers that are to connect to
+    /// us. Whatever cannot be mapped is just left out.
+    pub fn gather_candidates(&self, result_token: u32) {
+        let gathering = Gathering {
+            id: PeerId(self.our_keys.0),
+            listeners: lock(&self.our_listeners).clone(),
+            relay: lock(&self.our_relay).map(|(_, addr)| addr),
+            local_ips: Gathering::local_ips(&self.mc),
+            stamp: lock(&self.offers).issue(connection_info_ttl(&self.config)),
+        };
+        if DISABLE_NAT {
+            let result = CandidatesResult {
+                result_token: result_token,
+                result: Ok(gathering.unmapped()),
+            };
+            let _ = self.event_tx.send(Event::CandidatesGathered(result));
+            return;
+        }
+        let event_tx = self.event_tx.clone();
+        let mc = self.mc.clone();
+        if let Err(e) = self.post(move |core, poll| {
+            let finish = move |_: &mut Core, _: &Poll, candidates| {
+                let result = CandidatesResult {
+                    result_token: result_token,
+                    result: Ok(candidates),
+                };
+                let _ = event_tx.send(Event::CandidatesGathered(result));
+            };
+            candidates::gather(core, poll, &mc, gathering, finish);
+        }) {
+            let result = CandidatesResult {
+                result_token: result_token,
+                result: Err(e),
+            };
+            let _ = self.event_tx.send(Event::CandidatesGathered(result));
         }
     }
 
-    /// Returns our ID.
-    pub fn id(&self) -> PeerId {
-        PeerId(self.our_keys.0)
+    /// Abort preparing the connection info requested with `result_token`, in which case its
+    /// `ConnectionInfoPrepared` event carries an error. Does nothing if it is ready already.
+    pub fn cancel_connection_info(&self, result_token: u32) {
+        let handles = lock(&self.pending_mappings).remove(&result_token);
+        for (_, handle) in handles.into_iter().flat_map(|handles| handles) {
+            handle.cancel();
+        }
     }
 
-    /// Returns our config.
-    pub fn config(&self) -> Config {
-        self.config.clone()
+    /// Find out what kind of NAT we are behind with the help of the hard-coded contacts. The result
+    /// is reported as an `Event::NatTypeDetected` on the event channel, `None` meaning it could
+    /// not be determined. Knowing it lets the user decide whether connecting directly or hole
+    /// punching is worth attempting at all.
+    pub fn detect_nat_type(&self) {
+        let event_tx = self.event_tx.clone();
+        let mc = self.mc.clone();
+        if let Err(e) = self.post(move |core, poll| {
+            let event_tx_clone = event_tx.clone();
+            let finish = move |_: &mut Core, _: &Poll, nat_type| {
+                let _ = event_tx_clone.send(Event::NatTypeDetected(nat_type));
+            };
+            if let Err(e) = DetectNatType::start(core, poll, &mc, finish) {
+                debug!("Could not start detecting NAT type: {}", e);
+                let _ = event_tx.send(Event::NatTypeDetected(None));
+            }
+        }) {
+            debug!("Could not post NAT type detection: {:?}", e);
+            let _ = self.event_tx.send(Event::NatTypeDetected(None));
+        }
     }
 
-    fn post<F>(&self, f: F) -> ::Res<()>
-        where F: FnOnce(&mut Core, &Poll) + Send + 'static
-    {
-        Ok(self.el.send(CoreMessage::new(f))?)
+    /// Find out as much as we can about how our network treats peer-to-peer traffic: whether there
+    /// is a working gateway, our external IPs, the NAT type and more. The report arrives as an
+    /// `Event::NatDiagnosed` on the event channel after a few seconds and is meant to be attached
+    /// to connectivity bug reports.
+    pub fn diagnose_nat(&self) {
+        let event_tx = self.event_tx.clone();
+        let mc = self.mc.clone();
+        if let Err(e) = self.post(move |core, poll| {
+            let event_tx_clone = event_tx.clone();
+            let finish = move |_: &mut Core, _: &Poll, report| {
+                let _ = event_tx_clone.send(Event::NatDiagnosed(Some(report)));
+            };
+            if let Err(e) = nat::diagnose(core, poll, &mc, finish) {
+                debug!("Could not start NAT diagnosis: {}", e);
+                let _ = event_tx.send(Event::NatDiagnosed(None));
+            }
+        }) {
+            debug!("Could not post NAT diagnosis: {:?}", e);
+            let _ = self.event_tx.send(Event::NatDiagnosed(None));
+        }
     }
-}
 
-/// Returns a hash of the network name.
-fn name_hash(network_name: &Option<String>) -> NameHash {
-    trace!("Network name: {:?}", network_name);
-    match *network_name {
-        Some(ref name) => sha256::hash(name.as_bytes()).0,
-        None => [0; sha256::DIGESTBYTES],
+    /// Start using the echo service of a peer at `addr` for NAT traversal, e.g. a listener of a
+    /// peer learnt from the network. Returns whether it was new and globally reachable.
+    pub fn add_peer_stun(&self, addr: SocketAddr) -> bool {
+        self.mc.add_peer_stun(addr)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use CrustError;
-    use maidsafe_utilities;
-    use maidsafe_utilities::thread::Joiner;
-    use main::{Event, PrivConnectionInfo, PubConnectionInfo};
-    use std::collections::{HashMap, HashSet, hash_map};
-    use std::net::IpAddr;
-    use std::str::FromStr;
-    use std::sync::{Arc, Barrier, mpsc};
-    use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
-    use std::sync::mpsc::Receiver;
-    use std::thread;
-    use std::time::Duration;
-    use tests::{get_event_sender, timebomb};
+    /// Stop using the echo service of a peer for NAT traversal. Returns whether it was in use.
+    pub fn remove_peer_stun(&self, addr: &SocketAddr) -> bool {
+        self.mc.remove_peer_stun(addr)
+    }
 
-    #[test]
-    fn connect_self() {
-        timebomb(Duration::from_secs(30), || {
-            let (event_tx, event_rx) = get_event_sender();
-            let mut service = unwrap!(Service::new(event_tx));
+    /// The external address we serve peers as a traversal helper on, if one of our listeners was
+    /// found reachable from outside our network, see `Config::act_as_helper`.
+    pub fn traversal_helper_addr(&self) -> Option<SocketAddr> {
+        self.helpers.ours()
+    }
 
-            unwrap!(service.start_listening_tcp());
-            expect_event!(event_rx, Event::ListenerStarted(_));
+    /// Have every socket mapping report its progress to `observer`, e.g. to show what a connection
+    /// attempt is waiting for, or stop reporting it if `None`.
+    pub fn set_mapping_observer(&self, observer: Option<mpsc::Sender<MappingEvent>>) {
+        self.mc.observer().set(observer);
+    }
+
+    /// How NAT traversal has fared since this service started: gateway and peer echo service
+    /// success rates, echo round trip times, mapping times and hole punch outcomes.
+    pub fn nat_stats(&self) -> NatStats {
+        self.mc.stats().snapshot()
+    }
+
+    /// How well connections have been reusing receive buffers rather than allocating them.
+    pub fn buffer_pool_stats(&self) -> BufferPoolStats {
+        let (tx, rx) = mpsc::channel();
+        let _ = self.post(move |core, _| {
+            let _ = tx.send(core.buffer_pool().stats());
+        });
+        rx.recv().unwrap_or_default()
+    }
+
+    /// The state machines alive on the service's event loops, by token. For tracking down those
+    /// that linger long after they should have finished, e.g. mappings that never terminated.
+    pub fn debug_snapshot(&self) -> Vec<StateSnapshot> {
+        let (tx, rx) = mpsc::channel();
+        for el in self.event_loops() {
+            let tx = tx.clone();
+            let _ = self.post_to(el, move |core, _| {
+                let _ = tx.send(core.debug_snapshot());
+            });
+        }
+        drop(tx);
+        let mut snapshot: Vec<_> = rx.iter().flat_map(|states| states).collect();
+        snapshot.sort_by_key(|state| state.token);
+        snapshot
+    }
+
+    /// Look for the IGD, NAT-PMP and PCP gateways on our network again and forget the external IP
+    /// peers last told us about, e.g. after a network change. Otherwise both are reused until
+    /// they go stale or fail us. This blocks for up to a second.
+    pub fn refresh_gateways(&self) {
+        self.mc.force_ext_addr_refresh();
+        self.mc.refresh_gateways();
+    }
+
+    /// Hold off on the network activity we start ourselves, e.g. while a mobile app is in the
+    /// background: sending, connecting and bootstrapping fail with `CrustError::NetworkPaused`,
+    /// connections send no heartbeats nor give up on peers that send none, nor migrate, lost peers
+    /// are not redialed until we resume, peers seeking us on the LAN are not answered, and neither
+    /// router leases are renewed nor gateways looked for. Connections are kept, and traffic the
+    /// peers send is still handled. Connecting and bootstrapping already under way carry on, and
+    /// listeners keep accepting peers, which is left to the peers to start.
+    pub fn pause_network(&self) -> ::Res<()> {
+        self.set_paused(true)
+    }
+
+    /// Carry on after `pause_network`.
+    pub fn resume_network(&self) -> ::Res<()> {
+        self.set_paused(false)
+    }
+
+    /// Whether the service is paused by `pause_network`.
+    pub fn is_network_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Never bootstrap off or dial `addr` again, until unblacklisted. Attempts already under way
+    /// carry on.
+    pub fn blacklist(&self, addr: SocketAddr) {
+        self.ban_list.blacklist(addr);
+    }
+
+    /// Allow bootstrapping off and dialing `addr` again. Returns whether it was blacklisted.
+    pub fn unblacklist(&self, addr: &SocketAddr) -> bool {
+        self.ban_list.unblacklist(addr)
+    }
+
+    /// Endpoints blacklisted, in the config or with `blacklist`.
+    pub fn blacklisted_contacts(&self) -> Vec<SocketAddr> {
+        self.ban_list.blacklisted()
+    }
+
+    /// Allow connecting to `peer_id` again, having been banned for repeatedly failing handshakes
+    /// or misbehaving. Returns whether it was banned.
+    pub fn unban(&self, peer_id: &PeerId) -> bool {
+        self.ban_list.unban(peer_id)
+    }
+
+    /// Peers not connected to for now, after repeatedly failing handshakes or misbehaving.
+    pub fn banned_peers(&self) -> Vec<PeerId> {
+        self.ban_list.banned()
+    }
+
+    /// How many times each IP outside `Config::whitelisted_node_ips` and
+    /// `Config::whitelisted_client_ips` was turned away when connecting to us. Only the most
+    /// rejected IPs are kept track of.
+    pub fn whitelist_rejections(&self) -> HashMap<IpAddr, u64> {
+        self.whitelist.rejections()
+    }
+
+    /// What has been recorded since the service started, if `Config::metrics` or
+    /// `Config::metrics_listen_addr` is set.
+    pub fn metrics(&self) -> Option<MetricsSnapshot> {
+        self.metrics.snapshot()
+    }
+
+    /// Have `subscriber` receive the lifecycle events of this service's mappings, handshakes and
+    /// connections, rather than them being logged under `TRACE_TARGET`. Every event loop gets a
+    /// clone of `subscriber`, so those of connections handed on to other event loops under
+    /// `Config::event_loops` reach it too.
+    pub fn set_trace_subscriber<S>(&self, subscriber: S) -> ::Res<()>
+        where S: TraceSubscriber + Clone + 'static
+    {
+        for el in self.event_loops() {
+            let subscriber = subscriber.clone();
+            self.post_to(el, move |core, _| core.set_trace_subscriber(Box::new(subscriber)))?;
+        }
+        Ok(())
+    }
+
+    /// Dial and listen over `transport` instead of TCP, on every event loop. Connections and
+    /// listeners already started keep the transport they were started with, so this is best
+    /// called before `start_listening` and `start_bootstrap`.
+    pub fn set_transport<T>(&self, transport: T) -> ::Res<()>
+        where T: Transport + Clone + Send + 'static
+    {
+        for el in self.event_loops() {
+            let transport = transport.clone();
+            self.post_to(el, move |core, _| core.set_transport(Rc::new(transport)))?;
+        }
+        Ok(())
+    }
+
+    /// Dial peers over `transport` as well should they not be reached over the main transport,
+    /// nor over the fallbacks added before, within `delay`, or should dialing them fail outright.
+    /// Applies to every event loop.
+    pub fn add_fallback_transport<T>(&self, transport: T, delay: Duration) -> ::Res<()>
+        where T: Transport + Clone + Send + 'static
+    {
+        for el in self.event_loops() {
+            let transport = transport.clone();
+            self.post_to(el,
+                         move |core, _| core.add_fallback_transport(Rc::new(transport), delay))?;
+        }
+        Ok(())
+    }
+
+    /// Register `transport` alongside the main one: listen over it on the port of every
+    /// listener started from now on, and fall back on dialing peers over it as
+    /// `add_fallback_transport` does. Applies to every event loop.
+    pub fn register_transport<T>(&self, transport: T, delay: Duration) -> ::Res<()>
+        where T: Transport + Clone + Send + 'static
+    {
+        for el in self.event_loops() {
+            let transport = transport.clone();
+            self.post_to(el, move |core, _| {
+                let transport: Rc<Transport> = Rc::new(transport);
+                core.add_listen_transport(transport.clone());
+                core.add_fallback_transport(transport, delay);
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Live statistics of the connection to `peer_id`, if there is one.
+    pub fn connection_info_of(&self, peer_id: &PeerId) -> Option<ConnectionStats> {
+        let token = match lock(&self.cm).get(peer_id) {
+            Some(&ConnectionId { active_connection: Some(token), .. }) => token,
+            _ => return None,
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let _ = self.post_on(token, move |core, _| {
+                               let _ = tx.send(with_active_connection(core,
+                                                                      token,
+                                                                      |conn| conn.stats()));
+                           });
+        rx.recv().ok().and_then(|stats| stats)
+    }
+
+    /// Check if we are connected to the given peer
+    pub fn is_connected(&self, peer_id: &PeerId) -> bool {
+        match lock(&self.cm).get(peer_id) {
+            Some(&ConnectionId { active_connection: Some(_), .. }) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns our ID.
+    pub fn id(&self) -> PeerId {
+        PeerId(self.our_keys.0)
+    }
+
+    /// Returns the identity we prove to peers, which lasts across runs if
+    /// `Config::identity_file` is set.
+    pub fn identity(&self) -> Identity {
+        self.identity.identity()
+    }
+
+    /// Returns our config.
+    pub fn config(&self) -> Config {
+        self.config.clone()
+    }
+
+    /// Stop the service gracefully rather than abruptly as dropping it does. Listening,
+    /// bootstrapping and service discovery stop first, then messages already queued to peers are
+    /// given until `deadline` to be written. Every connection is then closed without reconnecting,
+    /// `Event::LostPeer` being reported for each, along with everything else under way, before
+    /// the event loop stops and the ports forwarded to us are released.
+    pub fn shutdown(self, deadline: Duration) -> ::Res<()> {
+        let give_up = Instant::now() + deadline;
+
+        let listener_tokens = self.listener_tokens.clone();
+        self.post(move |core, poll| {
+            stop_listeners(core, poll, &listener_tokens);
+            for &token in &[BOOTSTRAP_TOKEN, SERVICE_DISCOVERY_TOKEN] {
+                if let Some(state) = core.get_state(token) {
+                    state.borrow_mut().terminate(core, poll);
+                }
+            }
+        })?;
+        for (index, el) in self.event_loops().into_iter().enumerate() {
+            let cm = self.cm.clone();
+            self.post_to(el, move |core, _| {
+                for token in active_connections(&cm, index) {
+                    let _ = with_active_connection(core, token, |conn| conn.cancel_reconnect());
+                }
+            })?;
+        }
+
+        while Instant::now() < give_up {
+            let (tx, rx) = mpsc::channel();
+            for (index, el) in self.event_loops().into_iter().enumerate() {
+                let tx = tx.clone();
+                let cm = self.cm.clone();
+                self.post_to(el, move |core, _| {
+                    let flushing = active_connections(&cm, index)
+                        .into_iter()
+                        .filter_map(|token| {
+                                        with_active_connection(core,
+                                                               token,
+                                                               |conn| conn.is_flushing())
+                                    })
+                        .any(|flushing| flushing);
+                    let _ = tx.send(flushing);
+                })?;
+            }
+            drop(tx);
+            if !rx.iter().any(|flushing| flushing) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(SHUTDOWN_FLUSH_POLL_MS));
+        }
+
+        for el in self.event_loops() {
+            let (tx, rx) = mpsc::channel();
+            self.post_to(el, move |core, poll| {
+                core.terminate_all(poll);
+                let _ = tx.send(());
+            })?;
+            rx.recv()?;
+        }
+        Ok(())
+    }
+
+    // Every event loop of the service, starting with the one running everything other than the
+    // connections handed on to the rest.
+    fn event_loops(&self) -> Vec<&EventLoop> {
+        iter::once(&self.el).chain(self.shards.iter()).collect()
+    }
+
+    fn set_paused(&self, paused: bool) -> ::Res<()> {
+        self.paused.store(paused, Ordering::SeqCst);
+        let background_refresh = !paused && !self.config.conserve_network.unwrap_or(false);
+        self.mc.gateway_cache().set_background_refresh(background_refresh);
+        for el in self.event_loops() {
+            self.post_to(el, move |core, _| core.set_paused(paused))?;
+        }
+        Ok(())
+    }
+
+    // The token of the connection to the peer, or of whatever is reconnecting to it.
+    fn sending_token(&self, peer_id: &PeerId) -> ::Res<Token> {
+        if let Some(&ConnectionId { active_connection: Some(token), .. }) =
+            lock(&self.cm).get(peer_id) {
+            return Ok(token);
+        }
+        lock(&self.reconnecting)
+            .get(peer_id)
+            .cloned()
+            .ok_or(CrustError::PeerNotFound(*peer_id))
+    }
+
+    fn check_paused(&self) -> ::Res<()> {
+        if self.paused.load(Ordering::SeqCst) {
+            return Err(CrustError::NetworkPaused);
+        }
+        Ok(())
+    }
+
+    fn check_queue(&self) -> ::Res<()> {
+        let limits = self.bandwidth.queue_limits();
+        match limits.policy {
+            QueueFullPolicy::Refuse if limits.is_total_full() => Err(CrustError::QueueFull),
+            QueueFullPolicy::Block => {
+                let deadline = Instant::now() + Duration::from_secs(QUEUE_BLOCK_TIMEOUT_SECS);
+                while limits.is_total_full() {
+                    if Instant::now() >= deadline {
+                        return Err(CrustError::QueueFull);
+                    }
+                    thread::sleep(Duration::from_millis(QUEUE_BLOCK_POLL_MS));
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn post<F>(&self, f: F) -> ::Res<()>
+        where F: FnOnce(&mut Core, &Poll) + Send + 'static
+    {
+        self.post_to(&self.el, f)
+    }
+
+    // Run `f` on the event loop the state of `token` belongs to.
+    fn post_on<F>(&self, token: Token, f: F) -> ::Res<()>
+        where F: FnOnce(&mut Core, &Poll) + Send + 'static
+    {
+        match shard_of(token) {
+            0 => self.post(f),
+            index => self.post_to(&self.shards[index - 1], f),
+        }
+    }
+
+    // Run `f`, carrying `len` bytes of data, on the event loop the state of `token` belongs to
+    // once any control messages queued there have been, unless too much data is queued there
+    // already.
+    fn post_data_on<F>(&self, token: Token, len: usize, f: F) -> ::Res<()>
+        where F: FnOnce(&mut Core, &Poll) + Send + 'static
+    {
+        let el = match shard_of(token) {
+            0 => &self.el,
+            index => &self.shards[index - 1],
+        };
+        let metrics = self.metrics.clone();
+        self.metrics.request_queued();
+        let res = el.try_send_data(CoreMessage::new(move |core, poll| {
+                                                        metrics.request_handled();
+                                                        f(core, poll)
+                                                    }),
+                                   len);
+        if res.is_err() {
+            self.metrics.request_handled();
+        }
+        match res {
+            Ok(()) => Ok(()),
+            Err(common::CommonError::EventLoopBusy) => Err(CrustError::EventLoopBusy),
+            Err(e) => Err(From::from(e)),
+        }
+    }
+
+    fn post_to<F>(&self, el: &EventLoop, f: F) -> ::Res<()>
+        where F: FnOnce(&mut Core, &Poll) + Send + 'static
+    {
+        let metrics = self.metrics.clone();
+        self.metrics.request_queued();
+        let res = el.send(CoreMessage::new(move |core, poll| {
+                                                 metrics.request_handled();
+                                                 f(core, poll)
+                                             }));
+        if res.is_err() {
+            self.metrics.request_handled();
+        }
+        Ok(res?)
+    }
+}
+
+impl Drop for Service {
+    fn drop(&mut self) {
+        // Don't leave ports forwarded to us on the routers once we are gone.
+        self.mc.cleanup();
+    }
+}
+
+// Stop whichever of the listeners with `listener_tokens` are still going.
+fn stop_listeners(core: &mut Core, poll: &Poll, listener_tokens: &Mutex<Vec<Token>>) {
+    let tokens = mem::replace(&mut *lock(&listener_tokens), Vec::new());
+    for token in tokens {
+        if let Some(state) = core.get_state(token) {
+            state.borrow_mut().terminate(core, poll);
+        }
+    }
+}
+
+// The tokens of our established connections on the event loop at `shard`.
+fn active_connections(cm: &ConnectionMap, shard: usize) -> Vec<Token> {
+    lock(&cm)
+        .values()
+        .filter_map(|id| id.active_connection)
+        .filter(|&token| shard_of(token) == shard)
+        .collect()
+}
+
+// Spawn the event loops beyond `el` that connections are spread over, `count` in all, and tell
+// each of them about the others.
+fn start_shards(el: &EventLoop, our_id: &PeerId, count: usize) -> ::Res<Vec<EventLoop>> {
+    let count = cmp::min(cmp::max(count, 1), MAX_SHARDS);
+    let mut shards = Vec::with_capacity(count - 1);
+    for index in 1..count {
+        shards.push(common::spawn_event_loop(shard_token_start(index),
+                                             Some(&format!("{:?} {}", our_id, index)))?);
+    }
+    if shards.is_empty() {
+        return Ok(shards);
+    }
+
+    let els: Vec<_> = iter::once(el).chain(shards.iter()).collect();
+    let views = Shards::new(els.iter().map(|el| el.sender()).collect());
+    for (el, view) in els.into_iter().zip(views) {
+        el.send(CoreMessage::new(move |core, _| core.set_shards(view)))?;
+    }
+    Ok(shards)
+}
+
+fn with_active_connection<F, T>(core: &Core, token: Token, f: F) -> Option<T>
+    where F: FnOnce(&mut ActiveConnection) -> T
+{
+    let state = match core.get_state(token) {
+        Some(state) => state,
+        None => return None,
+    };
+    let mut state = state.borrow_mut();
+    state.as_any().downcast_mut::<ActiveConnection>().map(f)
+}
+
+// The relay both ends of a connection dial should neither reach the other directly: the one a
+// peer only reachable through a relay handed out, else the one configured. Should both peers have
+// handed one out, both go with that of the lesser peer.
+fn relay_between(our_id: PeerId,
+                 our_relay: Option<SocketAddr>,
+                 their_ci: &PubConnectionInfo,
+                 config_relay: Option<SocketAddr>)
+                 -> Option<SocketAddr> {
+    match (our_relay, their_ci.relay) {
+        (Some(ours), Some(theirs)) => Some(if our_id < their_ci.id { ours } else { theirs }),
+        (ours, theirs) => ours.or(theirs).or(config_relay),
+    }
+}
+
+// Peers advertising themselves as helpers join the helpers in `mc`, and leave again once they
+// withdraw or disconnect - unless we had them as helpers already, e.g. from our hard-coded
+// contacts. Only so many are taken, so peers cannot grow the pool without bound.
+fn traversal_helpers(config: &Config, mc: &MappingContext) -> TraversalHelpers {
+    let peer_stuns = mc.peer_stun_health();
+    let advertised = Mutex::new(HashSet::new());
+    TraversalHelpers::new(config.act_as_helper.unwrap_or(false),
+                          move |addr, serving| if serving {
+                              let mut advertised = lock(&advertised);
+                              if advertised.len() < MAX_ADVERTISED_HELPERS &&
+                                 peer_stuns.add(addr) {
+                                  let _ = advertised.insert(addr);
+                              }
+                          } else if lock(&advertised).remove(&addr) {
+                              let _ = peer_stuns.remove(&addr);
+                          })
+}
+
+// Drop the mapping `id` for `result_token` from those that can be cancelled, once it is over.
+fn forget_mapping(pending: &Mutex<HashMap<u32, Vec<(usize, MappingHandle)>>>,
+                  result_token: u32,
+                  id: usize) {
+    let mut pending = lock(pending);
+    let empty = match pending.get_mut(&result_token) {
+        Some(handles) => {
+            handles.retain(|&(mapping_id, _)| mapping_id != id);
+            handles.is_empty()
+        }
+        None => false,
+    };
+    if empty {
+        let _ = pending.remove(&result_token);
+    }
+}
 
+fn connection_info_ttl(config: &Config) -> Duration {
+    Duration::from_secs(config.connection_info_ttl_secs.unwrap_or(CONNECTION_INFO_TTL_SECS))
+}
+
+/// Returns a hash of the network name, and of its genesis value if there is one.
+fn name_hash(network_name: &Option<String>, network_genesis: &Option<String>) -> NameHash {
+    trace!("Network name: {:?}, genesis: {:?}", network_name, network_genesis);
+    let name_hash = match *network_name {
+        Some(ref name) => sha256::hash(name.as_bytes()).0,
+        None => [0; sha256::DIGESTBYTES],
+    };
+    match *network_genesis {
+        Some(ref genesis) => {
+            let mut bytes = name_hash.to_vec();
+            bytes.extend_from_slice(genesis.as_bytes());
+            sha256::hash(&bytes).0
+        }
+        None => name_hash,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use CrustError;
+    use common::{Compression, QuotaPolicy, TraceEvent, TraceState, TraceSubscriber};
+    use maidsafe_utilities;
+    use maidsafe_utilities::thread::Joiner;
+    use main::{Event, HEARTBEAT_PERIOD_MS, INACTIVITY_TIMEOUT_MS, PrivConnectionInfo,
+               PubConnectionInfo};
+    use std::collections::{HashMap, HashSet, hash_map};
+    use std::net::IpAddr;
+    use std::str::FromStr;
+    use std::sync::{Arc, Barrier, mpsc};
+    use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+    use std::sync::mpsc::Receiver;
+    use std::thread;
+    use std::time::Duration;
+    use tests::{gen_config, get_event_sender, timebomb};
+
+    #[test]
+    fn name_hash_genesis() {
+        let name = Some("test".to_owned());
+        assert_eq!(name_hash(&name, &None), sha256::hash(b"test").0);
+        assert!(name_hash(&name, &Some("1".to_owned())) != name_hash(&name, &None));
+        assert!(name_hash(&name, &Some("1".to_owned())) !=
+                name_hash(&name, &Some("2".to_owned())));
+    }
+
+    #[test]
+    fn relay_both_ends_dial() {
+        let ours: SocketAddr = unwrap!("1.2.3.4:5483".parse());
+        let theirs: SocketAddr = unwrap!("5.6.7.8:5483".parse());
+        let configured: SocketAddr = unwrap!("9.9.9.9:5483".parse());
+        let mut ids = vec![PeerId(box_::gen_keypair().0), PeerId(box_::gen_keypair().0)];
+        ids.sort();
+        let their_ci = |id, relay| {
+            PubConnectionInfo {
+                id: id,
+                for_hole_punch: Vec::new(),
+                for_direct: Vec::new(),
+                relay: relay,
+                stamp: OfferStamp::new(Duration::from_secs(60)),
+            }
+        };
+
+        assert_eq!(relay_between(ids[0], Some(ours), &their_ci(ids[1], None), Some(configured)),
+                   Some(ours));
+        assert_eq!(relay_between(ids[0], None, &their_ci(ids[1], Some(theirs)), None),
+                   Some(theirs));
+        assert_eq!(relay_between(ids[0], None, &their_ci(ids[1], None), Some(configured)),
+                   Some(configured));
+        // Whichever end does the choosing, it is the lesser peer's
+        assert_eq!(relay_between(ids[0], Some(ours), &their_ci(ids[1], Some(theirs)), None),
+                   Some(ours));
+        assert_eq!(relay_between(ids[1], Some(theirs), &their_ci(ids[0], Some(ours)), None),
+                   Some(ours));
+    }
+
+    #[cfg(feature = "test_utils")]
+    #[test]
+    fn listen_behind_fake_nat() {
+        use std::net::Ipv4Addr;
+
+        timebomb(Duration::from_secs(30), || {
+            let router_ip = Ipv4Addr::new(1, 2, 3, 4);
+            let stun_ip = Ipv4Addr::new(5, 6, 7, 8);
+            let nat = FakeNat::new()
+                .router(router_ip)
+                .router_latency(Duration::from_millis(200))
+                .external_ip(IpAddr::V4(stun_ip))
+                .stun_fails();
+            let (event_tx, event_rx) = get_event_sender();
+            let mut service = unwrap!(Service::with_fake_nat(event_tx, gen_config(), &nat));
+
+            unwrap!(service.start_listening_tcp());
+            expect_event!(event_rx, Event::ListenerStarted(_));
+
+            // The router was waited for, while the peers' failure left their IP out
+            let ips: Vec<_> = lock(&service.our_listeners)
+                .iter()
+                .map(|addr| addr.ip())
+                .collect();
+            assert!(ips.contains(&IpAddr::V4(router_ip)));
+            assert!(!ips.contains(&IpAddr::V4(stun_ip)));
+        })
+    }
+
+    #[test]
+    fn connect_self() {
+        timebomb(Duration::from_secs(30), || {
+            let (event_tx, event_rx) = get_event_sender();
+            let mut service = unwrap!(Service::new(event_tx));
+
+            unwrap!(service.start_listening_tcp());
+            expect_event!(event_rx, Event::ListenerStarted(_));
+
+            service.prepare_connection_info(0);
+
+            let conn_info_result =
+                expect_event!(event_rx, Event::ConnectionInfoPrepared(result) => result);
+
+            let priv_info = unwrap!(conn_info_result.result);
+            let pub_info = priv_info.to_pub_connection_info();
+
+            match service.connect(priv_info, pub_info) {
+                Err(CrustError::RequestedConnectToSelf) => (),
+                Ok(()) | Err(..) => panic!("Expected CrustError::RequestedConnectedToSelf"),
+            }
+        })
+    }
+
+    #[test]
+    fn connect_stale_info() {
+        timebomb(Duration::from_secs(30), || {
+            let (event_tx, event_rx) = get_event_sender();
+            let service = unwrap!(Service::new(event_tx));
+
+            service.prepare_connection_info(0);
+            let conn_info_result =
+                expect_event!(event_rx, Event::ConnectionInfoPrepared(result) => result);
+            let priv_info = unwrap!(conn_info_result.result);
+
+            let mut stamp = OfferStamp::new(Duration::from_secs(60));
+            stamp.expires_at = stamp.created_at - 1;
+            let their_info = PubConnectionInfo {
+                id: PeerId(box_::gen_keypair().0),
+                for_hole_punch: Vec::new(),
+                for_direct: vec![unwrap!("127.0.0.1:5483".parse())],
+                relay: None,
+                stamp: stamp,
+            };
+            match service.connect(priv_info, their_info) {
+                Err(CrustError::ConnectionInfoExpired) => (),
+                res => panic!("Expected CrustError::ConnectionInfoExpired, got {:?}", res),
+            }
+        })
+    }
+
+    #[test]
+    fn invalid_bind_device() {
+        let (event_tx, _event_rx) = get_event_sender();
+        let mut config = gen_config();
+        config.bind_device = Some("not an interface".to_owned());
+        match Service::with_config(event_tx, config) {
+            Err(CrustError::InvalidDeviceName(_)) => (),
+            Err(e) => panic!("Expected CrustError::InvalidDeviceName, got {:?}", e),
+            Ok(_) => panic!("Expected CrustError::InvalidDeviceName"),
+        }
+    }
+
+    #[test]
+    fn gather_candidates() {
+        timebomb(Duration::from_secs(30), || {
+            let (event_tx, event_rx) = get_event_sender();
+            let mut service = unwrap!(Service::new(event_tx));
+
+            unwrap!(service.start_listening_tcp());
+            expect_event!(event_rx, Event::ListenerStarted(_));
+
+            service.gather_candidates(7);
+            let result = expect_event!(event_rx, Event::CandidatesGathered(result) => result);
+            assert_eq!(result.result_token, 7);
+            let candidates = unwrap!(result.result);
+            let offer = candidates.offer().clone();
+            assert_eq!(offer.id, service.id());
+            let listeners = unwrap!(service.our_listeners.lock()).clone();
+            assert!(!listeners.is_empty());
+            assert_eq!(offer.to_pub_connection_info().for_direct, listeners);
+            assert!(offer
+                        .candidates
+                        .windows(2)
+                        .all(|pair| pair[0].priority >= pair[1].priority));
+
+            let priv_info = candidates.into_connection_info();
+            let pub_info = offer.to_pub_connection_info();
+            match service.connect(priv_info, pub_info) {
+                Err(CrustError::RequestedConnectToSelf) => (),
+                Ok(()) | Err(..) => panic!("Expected CrustError::RequestedConnectedToSelf"),
+            }
+        })
+    }
+
+    #[test]
+    fn listen_on_explicit_addrs() {
+        timebomb(Duration::from_secs(30), || {
+            let (event_tx, event_rx) = get_event_sender();
+            let mut config = gen_config();
+            // The second address is not ours, so only its listener fails
+            config.tcp_listen_addrs = Some(vec![unwrap!("127.0.0.1:0".parse()),
+                                                unwrap!("192.0.2.1:0".parse())]);
+            let mut service = unwrap!(Service::with_config(event_tx, config));
+            unwrap!(service.start_listening_tcp());
+
+            let mut port = None;
+            let mut failed = false;
+            while port.is_none() || !failed {
+                match unwrap!(event_rx.recv()) {
+                    Event::ListenerStarted(addr) => {
+                        assert_eq!(addr.ip(), unwrap!(IpAddr::from_str("127.0.0.1")));
+                        port = Some(addr.port());
+                    }
+                    Event::ListenerFailed(addr) => {
+                        assert_eq!(addr, Some(unwrap!("192.0.2.1:0".parse())));
+                        failed = true;
+                    }
+                    event => panic!("Unexpected event {:?}", event),
+                }
+            }
+
+            service.prepare_connection_info(0);
+            let conn_info_result =
+                expect_event!(event_rx, Event::ConnectionInfoPrepared(result) => result);
+            let pub_info = unwrap!(conn_info_result.result).to_pub_connection_info();
+            let listener = SocketAddr::new(unwrap!(IpAddr::from_str("127.0.0.1")),
+                                           unwrap!(port));
+            assert_eq!(pub_info.for_direct, vec![listener]);
+
+            unwrap!(service.stop_tcp_listener());
+            // Only returns once the event loop has got round to it, and so stopped the listener
+            let _ = service.debug_snapshot();
+            service.prepare_connection_info(1);
+            let conn_info_result =
+                expect_event!(event_rx, Event::ConnectionInfoPrepared(result) => result);
+            let pub_info = unwrap!(conn_info_result.result).to_pub_connection_info();
+            assert!(pub_info.for_direct.is_empty());
+        })
+    }
+
+    #[test]
+    fn listen_on_declared_addrs() {
+        timebomb(Duration::from_secs(30), || {
+            let (event_tx, event_rx) = get_event_sender();
+            let mut config = gen_config();
+            config.nat_external_addrs = Some(vec![unwrap!("203.0.113.1:0".parse()),
+                                                  unwrap!("203.0.113.2:6000".parse())]);
+            config.nat_external_addrs_only = Some(true);
+            let mut service = unwrap!(Service::with_config(event_tx, config));
+            unwrap!(service.start_listening_tcp());
+            let port = expect_event!(event_rx, Event::ListenerStarted(addr) => addr.port());
+
+            service.prepare_connection_info(0);
+            let conn_info_result =
+                expect_event!(event_rx, Event::ConnectionInfoPrepared(result) => result);
+            let pub_info = unwrap!(conn_info_result.result).to_pub_connection_info();
+            let one_to_one = SocketAddr::new(unwrap!(IpAddr::from_str("203.0.113.1")), port);
+            assert_eq!(pub_info.for_direct[..2],
+                       [one_to_one, unwrap!("203.0.113.2:6000".parse())]);
+        })
+    }
+
+    #[test]
+    fn map_without_igd_or_stun() {
+        timebomb(Duration::from_secs(30), || {
+            let (event_tx, event_rx) = get_event_sender();
+            let mut config = gen_config();
+            config.nat_disable_igd = Some(true);
+            config.nat_disable_stun = Some(true);
+            let mut service = unwrap!(Service::with_config(event_tx, config));
+            assert!(service.mc.gateways().igd.is_empty());
+            assert!(!service.mc.mapping_config().query_stun);
+
+            // The mapping completes from our interfaces and whatever NAT-PMP and PCP turn up
+            unwrap!(service.start_listening_tcp());
+            expect_event!(event_rx, Event::ListenerStarted(_));
             service.prepare_connection_info(0);
-
             let conn_info_result =
                 expect_event!(event_rx, Event::ConnectionInfoPrepared(result) => result);
-
-            let priv_info = unwrap!(conn_info_result.result);
-            let pub_info = priv_info.to_pub_connection_info();
-
-            match service.connect(priv_info, pub_info) {
-                Err(CrustError::RequestedConnectToSelf) => (),
-                Ok(()) | Err(..) => panic!("Expected CrustError::RequestedConnectedToSelf"),
-            }
+            let _ = unwrap!(conn_info_result.result);
         })
     }
 
@@ -556,6 +2290,120 @@ mod tests {
         thread::sleep(Duration::from_secs(1));
     }
 
+    // When each path of a connect was started and how, and when it connected.
+    #[cfg(feature = "test_utils")]
+    #[derive(Clone)]
+    struct PathRecorder(mpsc::Sender<(Instant, &'static str, String)>);
+
+    #[cfg(feature = "test_utils")]
+    impl TraceSubscriber for PathRecorder {
+        fn on_event(&self, event: &TraceEvent) {
+            if event.state != TraceState::Connect {
+                return;
+            }
+            let via = event
+                .fields
+                .iter()
+                .find(|&&(key, _)| key == "via")
+                .map_or_else(String::new, |&(_, value)| value.to_string());
+            let _ = self.0.send((Instant::now(), event.name, via));
+        }
+    }
+
+    #[cfg(feature = "test_utils")]
+    #[test]
+    fn connect_races_paths() {
+        use common::{Listener, Transport};
+        use std::io::ErrorKind;
+        use std::net::Ipv4Addr;
+        use test_utils::MemoryNetwork;
+
+        timebomb(Duration::from_secs(30), || {
+            let stagger = Duration::from_millis(200);
+            let net = MemoryNetwork::new();
+            // Long enough for the handshake to outlast the stagger twice over
+            net.set_latency(Duration::from_millis(250));
+            let addr_of = |listener: &Listener| {
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                                unwrap!(listener.local_addr()).port())
+            };
+            // Accepted but never answered, and refused outright
+            let silent = unwrap!(net.transport().listen(&unwrap!("0.0.0.0:0".parse())));
+            let silent_relay = unwrap!(net.transport().listen(&unwrap!("0.0.0.0:0".parse())));
+            let refused = unwrap!(net.transport().listen(&unwrap!("0.0.0.0:0".parse())));
+            net.refuse(addr_of(&*refused).port());
+
+            let nat = FakeNat::new();
+            let mut config = gen_config();
+            config.connect_stagger_ms = Some(200);
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let service_0 = unwrap!(Service::with_fake_nat(event_tx_0, config, &nat));
+            unwrap!(service_0.set_transport(net.transport()));
+            let (trace_tx, trace_rx) = mpsc::channel();
+            unwrap!(service_0.set_trace_subscriber(PathRecorder(trace_tx)));
+
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let mut service_1 = unwrap!(Service::with_fake_nat(event_tx_1, gen_config(), &nat));
+            unwrap!(service_1.set_transport(net.transport()));
+            unwrap!(service_1.start_listening_tcp());
+            let port = expect_event!(event_rx_1, Event::ListenerStarted(addr) => addr.port());
+
+            service_0.prepare_connection_info(0);
+            service_1.prepare_connection_info(0);
+            let our_ci = unwrap!(expect_event!(event_rx_0,
+                                               Event::ConnectionInfoPrepared(res) => res.result));
+            let their_ci = unwrap!(expect_event!(event_rx_1,
+                                                 Event::ConnectionInfoPrepared(res) => res.result));
+            let mut their_ci = their_ci.to_pub_connection_info();
+            their_ci.for_direct = vec![addr_of(&*silent),
+                                       addr_of(&*refused),
+                                       SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                                                       port)];
+            their_ci.for_hole_punch.clear();
+            their_ci.relay = Some(addr_of(&*silent_relay));
+
+            unwrap!(service_0.connect(our_ci, their_ci));
+            expect_event!(event_rx_0, Event::ConnectSuccess(id, _) => {
+                assert_eq!(id, service_1.id());
+            });
+
+            let events: Vec<_> = trace_rx.try_iter().collect();
+            let started = unwrap!(events.iter().find(|&&(_, name, _)| name == "started")).0;
+            let paths: Vec<_> = events
+                .iter()
+                .filter(|&&(_, name, _)| name == "path_started")
+                .map(|&(at, _, ref via)| (at - started, via.as_str()))
+                .collect();
+            assert_eq!(paths.iter().map(|&(_, via)| via).collect::<Vec<_>>(),
+                       vec!["direct", "direct", "relay"]);
+            // The listener is dialed a stagger in, as the endpoint before it is refused straight
+            // away, and the relay joins the race a stagger later while the listener handshakes
+            assert!(paths[0].0 < stagger / 2);
+            assert!(paths[1].0 >= stagger * 3 / 4 && paths[1].0 < stagger * 3 / 2);
+            assert!(paths[2].0 >= stagger * 7 / 4);
+            assert!(events
+                        .iter()
+                        .any(|&(_, name, ref via)| name == "connected" && via == "direct"));
+
+            // The paths which lost the race are closed
+            for listener in &[silent, silent_relay] {
+                let (mut stream, _) = unwrap!(listener.accept());
+                let mut buf = [0; 1024];
+                loop {
+                    match stream.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(_) => (),
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                            thread::sleep(Duration::from_millis(10))
+                        }
+                        Err(e) => panic!("Unexpected read: {:?}", e),
+                    }
+                }
+            }
+            expect_event!(event_rx_1, Event::ConnectSuccess(..));
+        })
+    }
+
     fn connect(service_0: &Service,
                event_rx_0: &Receiver<Event>,
                service_1: &Service,
@@ -576,8 +2424,460 @@ mod tests {
         unwrap!(service_0.connect(priv_info_0, pub_info_1));
         unwrap!(service_1.connect(priv_info_1, pub_info_0));
 
-        expect_event!(event_rx_0, Event::ConnectSuccess(id) => assert_eq!(id, service_1.id()));
-        expect_event!(event_rx_1, Event::ConnectSuccess(id) => assert_eq!(id, service_0.id()));
+        expect_event!(event_rx_0, Event::ConnectSuccess(id, identity) => {
+            assert_eq!(id, service_1.id());
+            assert_eq!(identity, service_1.identity());
+        });
+        expect_event!(event_rx_1, Event::ConnectSuccess(id, identity) => {
+            assert_eq!(id, service_0.id());
+            assert_eq!(identity, service_0.identity());
+        });
+    }
+
+    #[test]
+    fn connect_candidates() {
+        timebomb(Duration::from_secs(30), || {
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let mut service_0 = unwrap!(Service::new(event_tx_0));
+            unwrap!(service_0.start_listening_tcp());
+            expect_event!(event_rx_0, Event::ListenerStarted(_));
+
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let mut service_1 = unwrap!(Service::new(event_tx_1));
+            unwrap!(service_1.start_listening_tcp());
+            expect_event!(event_rx_1, Event::ListenerStarted(_));
+
+            service_0.gather_candidates(0);
+            service_1.gather_candidates(0);
+            let ours_0 =
+                unwrap!(expect_event!(event_rx_0, Event::CandidatesGathered(res) => res.result));
+            let ours_1 =
+                unwrap!(expect_event!(event_rx_1, Event::CandidatesGathered(res) => res.result));
+            let offer_0 = ours_0.offer().clone();
+            let offer_1 = ours_1.offer().clone();
+
+            let pairs = unwrap!(service_0.connect_candidates(ours_0, &offer_1));
+            assert!(!pairs.is_empty());
+            let _ = unwrap!(service_1.connect_candidates(ours_1, &offer_0));
+
+            expect_event!(event_rx_0, Event::ConnectSuccess(id, _) => {
+                assert_eq!(id, service_1.id());
+            });
+            expect_event!(event_rx_1, Event::ConnectSuccess(id, _) => {
+                assert_eq!(id, service_0.id());
+            });
+        })
+    }
+
+    #[test]
+    fn punch_udp_hole() {
+        timebomb(Duration::from_secs(30), || {
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let service_0 = unwrap!(Service::new(event_tx_0));
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let service_1 = unwrap!(Service::new(event_tx_1));
+
+            service_0.gather_candidates(0);
+            service_1.gather_candidates(0);
+            let mut ours_0 =
+                unwrap!(expect_event!(event_rx_0, Event::CandidatesGathered(res) => res.result));
+            let mut ours_1 =
+                unwrap!(expect_event!(event_rx_1, Event::CandidatesGathered(res) => res.result));
+            let socket_0 = unwrap!(ours_0.take_udp_socket());
+            let socket_1 = unwrap!(ours_1.take_udp_socket());
+            let addr_0 = unwrap!(socket_0.local_addr());
+            let addr_1 = unwrap!(socket_1.local_addr());
+
+            service_0.punch_udp_hole(socket_0, ours_1.offer(), b"secret".to_vec(), 3);
+            service_1.punch_udp_hole(socket_1, ours_0.offer(), b"secret".to_vec(), 4);
+            expect_event!(event_rx_0, Event::UdpHolePunched(res) => {
+                assert_eq!(res.result_token, 3);
+                assert_eq!(unwrap!(res.result).1.port(), addr_1.port());
+            });
+            expect_event!(event_rx_1, Event::UdpHolePunched(res) => {
+                assert_eq!(res.result_token, 4);
+                assert_eq!(unwrap!(res.result).1.port(), addr_0.port());
+            });
+        })
+    }
+
+    #[test]
+    fn connect_to_wrong_identity() {
+        timebomb(Duration::from_secs(30), || {
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let mut service_0 = unwrap!(Service::new(event_tx_0));
+            unwrap!(service_0.start_listening_tcp());
+            expect_event!(event_rx_0, Event::ListenerStarted(_));
+
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let mut service_1 = unwrap!(Service::new(event_tx_1));
+            unwrap!(service_1.start_listening_tcp());
+            expect_event!(event_rx_1, Event::ListenerStarted(_));
+
+            service_0.prepare_connection_info(0);
+            service_1.prepare_connection_info(0);
+            let conn_info_result_0 =
+                expect_event!(event_rx_0, Event::ConnectionInfoPrepared(result) => result);
+            let conn_info_result_1 =
+                expect_event!(event_rx_1, Event::ConnectionInfoPrepared(result) => result);
+            let priv_info_0 = unwrap!(conn_info_result_0.result);
+            let priv_info_1 = unwrap!(conn_info_result_1.result);
+            let pub_info_0 = priv_info_0.to_pub_connection_info();
+            let pub_info_1 = priv_info_1.to_pub_connection_info();
+
+            // Service 1 is not who service 0 is after
+            let wrong_identity = service_0.identity();
+            unwrap!(service_0.connect_to_identity(priv_info_0, pub_info_1, wrong_identity));
+            unwrap!(service_1.connect(priv_info_1, pub_info_0));
+
+            expect_event!(event_rx_0, Event::ConnectFailure(id) => assert_eq!(id, service_1.id()));
+        })
+    }
+
+    #[test]
+    fn connect_sealed() {
+        timebomb(Duration::from_secs(30), || {
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let mut service_0 = unwrap!(Service::new(event_tx_0));
+            unwrap!(service_0.start_listening_tcp());
+            expect_event!(event_rx_0, Event::ListenerStarted(_));
+
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let mut service_1 = unwrap!(Service::new(event_tx_1));
+            unwrap!(service_1.start_listening_tcp());
+            expect_event!(event_rx_1, Event::ListenerStarted(_));
+
+            service_0.prepare_connection_info(0);
+            service_1.prepare_connection_info(0);
+            let conn_info_result_0 =
+                expect_event!(event_rx_0, Event::ConnectionInfoPrepared(result) => result);
+            let conn_info_result_1 =
+                expect_event!(event_rx_1, Event::ConnectionInfoPrepared(result) => result);
+            let priv_info_0 = unwrap!(conn_info_result_0.result);
+            let priv_info_1 = unwrap!(conn_info_result_1.result);
+            let sealed_0 = unwrap!(service_0.seal_connection_info(
+                    &priv_info_0.to_pub_connection_info(), Some(service_1.id())));
+            let sealed_1 = unwrap!(service_1.seal_connection_info(
+                    &priv_info_1.to_pub_connection_info(), Some(service_0.id())));
+
+            // Sealed by another identity than the one expected
+            let identity_0 = service_0.identity();
+            let identity_1 = service_1.identity();
+            service_0.prepare_connection_info(1);
+            let spare_info_0 = expect_event!(event_rx_0,
+                                             Event::ConnectionInfoPrepared(result) => {
+                                                 unwrap!(result.result)
+                                             });
+            match service_0.connect_sealed(spare_info_0, &sealed_1, identity_0) {
+                Err(CrustError::InvalidConnectionInfo(_)) => (),
+                res => panic!("Unexpected {:?}", res),
+            }
+
+            unwrap!(service_0.connect_sealed(priv_info_0, &sealed_1, identity_1));
+            unwrap!(service_1.connect_sealed(priv_info_1, &sealed_0, identity_0));
+
+            expect_event!(event_rx_0, Event::ConnectSuccess(id, identity) => {
+                assert_eq!(id, service_1.id());
+                assert_eq!(identity, identity_1);
+            });
+            expect_event!(event_rx_1, Event::ConnectSuccess(id, _) => {
+                assert_eq!(id, service_0.id());
+            });
+        })
+    }
+
+    #[test]
+    fn peer_quota_exceeded() {
+        timebomb(Duration::from_secs(30), || {
+            let mut config = ::tests::utils::gen_config();
+            config.peer_max_msgs_per_sec = Some(2);
+            config.peer_quota_policy = Some(QuotaPolicy::Disconnect);
+
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let mut service_0 = unwrap!(Service::with_config(event_tx_0, config));
+            unwrap!(service_0.start_listening_tcp());
+            expect_event!(event_rx_0, Event::ListenerStarted(_));
+
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let mut service_1 = unwrap!(Service::new(event_tx_1));
+            unwrap!(service_1.start_listening_tcp());
+            expect_event!(event_rx_1, Event::ListenerStarted(_));
+
+            connect(&service_0, &event_rx_0, &service_1, &event_rx_1);
+
+            let id_1 = service_1.id();
+            for _ in 0..5 {
+                unwrap!(service_1.send(service_0.id(), vec![1, 2, 3], 0));
+            }
+
+            let mut received = 0;
+            let mut exceeded = false;
+            for event in event_rx_0.iter() {
+                match event {
+                    Event::NewMessage(id, _) if id == id_1 => received += 1,
+                    Event::PeerQuotaExceeded(id) if id == id_1 => exceeded = true,
+                    Event::LostPeer(id) if id == id_1 => break,
+                    event => panic!("unexpected event {:?}", event),
+                }
+            }
+            assert!(exceeded);
+            assert!(received < 5);
+        })
+    }
+
+    #[test]
+    fn metrics() {
+        timebomb(Duration::from_secs(30), || {
+            let mut config = ::tests::utils::gen_config();
+            config.metrics = Some(true);
+
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let mut service_0 = unwrap!(Service::with_config(event_tx_0, config));
+            unwrap!(service_0.start_listening_tcp());
+            expect_event!(event_rx_0, Event::ListenerStarted(_));
+
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let mut service_1 = unwrap!(Service::new(event_tx_1));
+            unwrap!(service_1.start_listening_tcp());
+            expect_event!(event_rx_1, Event::ListenerStarted(_));
+
+            connect(&service_0, &event_rx_0, &service_1, &event_rx_1);
+            exchange_messages(&service_0, &event_rx_0, &service_1, &event_rx_1);
+
+            let snapshot = unwrap!(service_0.metrics());
+            assert_eq!(snapshot.connections, 1);
+            assert!(!snapshot.handshakes.is_empty());
+            let traffic = unwrap!(snapshot.peers.get(&service_1.id()));
+            assert!(traffic.bytes_in >= 32);
+            assert!(traffic.bytes_out >= 32);
+            assert!(service_1.metrics().is_none());
+        })
+    }
+
+    #[derive(Clone)]
+    struct TraceRecorder(mpsc::Sender<(TraceState, &'static str, usize)>);
+
+    impl TraceSubscriber for TraceRecorder {
+        fn on_event(&self, event: &TraceEvent) {
+            let _ = self.0.send((event.state, event.name, event.conn));
+        }
+    }
+
+    #[test]
+    fn trace_subscriber() {
+        timebomb(Duration::from_secs(30), || {
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let mut service_0 = unwrap!(Service::new(event_tx_0));
+            let (trace_tx, trace_rx) = mpsc::channel();
+            unwrap!(service_0.set_trace_subscriber(TraceRecorder(trace_tx)));
+            unwrap!(service_0.start_listening_tcp());
+            expect_event!(event_rx_0, Event::ListenerStarted(_));
+
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let mut service_1 = unwrap!(Service::new(event_tx_1));
+            unwrap!(service_1.start_listening_tcp());
+            expect_event!(event_rx_1, Event::ListenerStarted(_));
+
+            connect(&service_0, &event_rx_0, &service_1, &event_rx_1);
+
+            let events: Vec<_> = trace_rx.try_iter().collect();
+            let started = |wanted| {
+                events
+                    .iter()
+                    .find(|&&(state, name, _)| state == wanted && name == "started")
+                    .map(|&(_, _, conn)| conn)
+            };
+            // The connection keeps its id from the handshake on
+            let conn = unwrap!(started(TraceState::ActiveConnection));
+            assert!(events.contains(&(TraceState::Handshake, "completed", conn)));
+            assert!(started(TraceState::Mapping).is_some());
+        })
+    }
+
+    #[test]
+    fn connection_info_of() {
+        timebomb(Duration::from_secs(30), || {
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let mut service_0 = unwrap!(Service::new(event_tx_0));
+            unwrap!(service_0.start_listening_tcp());
+            expect_event!(event_rx_0, Event::ListenerStarted(_));
+
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let mut service_1 = unwrap!(Service::new(event_tx_1));
+            unwrap!(service_1.start_listening_tcp());
+            expect_event!(event_rx_1, Event::ListenerStarted(_));
+
+            assert!(service_0.connection_info_of(&service_1.id()).is_none());
+            connect(&service_0, &event_rx_0, &service_1, &event_rx_1);
+            exchange_messages(&service_0, &event_rx_0, &service_1, &event_rx_1);
+            // Long enough for a heartbeat to be answered
+            thread::sleep(Duration::from_millis(3 * HEARTBEAT_PERIOD_MS));
+
+            let stats = unwrap!(service_0.connection_info_of(&service_1.id()));
+            assert_eq!(stats.transport, Some("tcp"));
+            assert!(!stats.relayed);
+            assert!(stats.local_addr.is_some() && stats.remote_addr.is_some());
+            assert!(stats.rtt.is_some());
+            assert_eq!((stats.msgs_sent, stats.msgs_received), (1, 1));
+            assert_eq!((stats.bytes_sent, stats.bytes_received), (32, 32));
+            assert!(stats.age >= Duration::from_millis(3 * HEARTBEAT_PERIOD_MS));
+        })
+    }
+
+    #[test]
+    fn compression_negotiated() {
+        timebomb(Duration::from_secs(30), || {
+            let mut config = gen_config();
+            config.compression = Some(Compression::Zstd);
+            config.compression_threshold = Some(1024);
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let mut service_0 = unwrap!(Service::with_config(event_tx_0, config));
+            unwrap!(service_0.start_listening_tcp());
+            expect_event!(event_rx_0, Event::ListenerStarted(_));
+
+            // Does not compress, but still tells of the codecs it can decompress
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let mut service_1 = unwrap!(Service::new(event_tx_1));
+            unwrap!(service_1.start_listening_tcp());
+            expect_event!(event_rx_1, Event::ListenerStarted(_));
+
+            connect(&service_0, &event_rx_0, &service_1, &event_rx_1);
+            // Long enough for the codecs to be told
+            thread::sleep(Duration::from_millis(HEARTBEAT_PERIOD_MS));
+
+            let large = vec![7; 100_000];
+            let small = b"after the large one".to_vec();
+            unwrap!(service_0.send(service_1.id(), large.clone(), 0));
+            unwrap!(service_0.send(service_1.id(), small.clone(), 0));
+            // Sent in order, although the large one is compressed off the event loop first
+            expect_event!(event_rx_1, Event::NewMessage(_, data) => assert_eq!(data, large));
+            expect_event!(event_rx_1, Event::NewMessage(_, data) => assert_eq!(data, small));
+            let stats = unwrap!(service_0.connection_info_of(&service_1.id()));
+            assert!(stats.bytes_sent < (large.len() + small.len()) as u64 / 10);
+
+            unwrap!(service_1.send(service_0.id(), large.clone(), 0));
+            expect_event!(event_rx_0, Event::NewMessage(_, data) => assert_eq!(data, large));
+            let stats = unwrap!(service_1.connection_info_of(&service_0.id()));
+            assert!(stats.bytes_sent >= large.len() as u64);
+        })
+    }
+
+    #[test]
+    fn query_ext_addr() {
+        timebomb(Duration::from_secs(30), || {
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let mut service_0 = unwrap!(Service::new(event_tx_0));
+            unwrap!(service_0.start_listening_tcp());
+            expect_event!(event_rx_0, Event::ListenerStarted(_));
+
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let mut service_1 = unwrap!(Service::new(event_tx_1));
+            unwrap!(service_1.start_listening_tcp());
+            expect_event!(event_rx_1, Event::ListenerStarted(_));
+
+            assert!(service_0.query_ext_addr(service_1.id()).is_err());
+            connect(&service_0, &event_rx_0, &service_1, &event_rx_1);
+
+            let stats = unwrap!(service_0.connection_info_of(&service_1.id()));
+            unwrap!(service_0.query_ext_addr(service_1.id()));
+            let (peer_id, addr) = expect_event!(event_rx_0,
+                                                Event::ExternalAddrReported(peer_id, addr) => {
+                                                    (peer_id, addr)
+                                                });
+            assert_eq!(peer_id, service_1.id());
+            assert_eq!(addr, stats.local_addr);
+        })
+    }
+
+    #[test]
+    fn pause_network() {
+        timebomb(Duration::from_secs(30), || {
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let mut service_0 = unwrap!(Service::new(event_tx_0));
+            unwrap!(service_0.start_listening_tcp());
+            expect_event!(event_rx_0, Event::ListenerStarted(_));
+
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let mut service_1 = unwrap!(Service::new(event_tx_1));
+            unwrap!(service_1.start_listening_tcp());
+            expect_event!(event_rx_1, Event::ListenerStarted(_));
+
+            connect(&service_0, &event_rx_0, &service_1, &event_rx_1);
+
+            unwrap!(service_0.pause_network());
+            unwrap!(service_1.pause_network());
+            assert!(service_0.is_network_paused());
+            match service_0.send(service_1.id(), vec![1], 0) {
+                Err(CrustError::NetworkPaused) => (),
+                res => panic!("Expected CrustError::NetworkPaused, got {:?}", res),
+            }
+
+            // Well past the inactivity timeout, without either peer giving up on the other
+            thread::sleep(Duration::from_millis(3 * INACTIVITY_TIMEOUT_MS));
+            assert!(service_0.is_connected(&service_1.id()));
+            assert!(service_1.is_connected(&service_0.id()));
+
+            unwrap!(service_0.resume_network());
+            unwrap!(service_1.resume_network());
+            exchange_messages(&service_0, &event_rx_0, &service_1, &event_rx_1);
+        })
+    }
+
+    #[test]
+    fn shutdown() {
+        timebomb(Duration::from_secs(30), || {
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let mut service_0 = unwrap!(Service::new(event_tx_0));
+            unwrap!(service_0.start_listening_tcp());
+            expect_event!(event_rx_0, Event::ListenerStarted(_));
+
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let mut service_1 = unwrap!(Service::new(event_tx_1));
+            unwrap!(service_1.start_listening_tcp());
+            expect_event!(event_rx_1, Event::ListenerStarted(_));
+
+            connect(&service_0, &event_rx_0, &service_1, &event_rx_1);
+
+            // Too much to have been written by the time `shutdown` is called
+            let data = vec![7; 256 * 1024];
+            let (id_0, id_1) = (service_0.id(), service_1.id());
+            unwrap!(service_0.send(id_1, data.clone(), 0));
+            unwrap!(service_0.shutdown(Duration::from_secs(10)));
+
+            expect_event!(event_rx_0, Event::LostPeer(id) => assert_eq!(id, id_1));
+            expect_event!(event_rx_1, Event::NewMessage(id, msg) => {
+                assert_eq!(id, id_0);
+                assert!(msg == data);
+            });
+            expect_event!(event_rx_1, Event::LostPeer(id) => assert_eq!(id, id_0));
+        })
+    }
+
+    #[test]
+    fn event_loops() {
+        timebomb(Duration::from_secs(30), || {
+            let mut config = gen_config();
+            config.event_loops = Some(2);
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let mut service_0 = unwrap!(Service::with_config(event_tx_0, config));
+            unwrap!(service_0.start_listening_tcp());
+            expect_event!(event_rx_0, Event::ListenerStarted(_));
+
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let mut service_1 = unwrap!(Service::new(event_tx_1));
+            unwrap!(service_1.start_listening_tcp());
+            expect_event!(event_rx_1, Event::ListenerStarted(_));
+
+            connect(&service_0, &event_rx_0, &service_1, &event_rx_1);
+            // Handed on from the first event loop, which is the busier with the listener
+            assert_eq!(active_connections(&service_0.cm, 1).len(), 1);
+            exchange_messages(&service_0, &event_rx_0, &service_1, &event_rx_1);
+            assert!(service_0.connection_info_of(&service_1.id()).is_some());
+
+            let id_0 = service_0.id();
+            unwrap!(service_0.shutdown(Duration::from_secs(1)));
+            expect_event!(event_rx_1, Event::LostPeer(id) => assert_eq!(id, id_0));
+        })
     }
 
     fn exchange_messages(service_0: &Service,
@@ -690,7 +2990,7 @@ mod tests {
                     let mut their_ids = HashMap::new();
                     for _ in 0..NUM_SERVICES - 1 {
                         let their_id = match unwrap!(self.event_rx.recv()) {
-                            Event::ConnectSuccess(their_id) => their_id,
+                            Event::ConnectSuccess(their_id, _) => their_id,
                             m => panic!("Expected ConnectSuccess message. Got message {:?}", m),
                         };
                         if their_ids.insert(their_id, 0u32).is_some() {
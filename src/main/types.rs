@@ -15,12 +15,18 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
+use common::{Identity, IdentityKeys};
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use main::CrustError;
 use mio::Token;
 use net2::TcpBuilder;
-use rand::{Rand, Rng};
-use rust_sodium::crypto::box_::{self, PublicKey};
+use rand::{self, Rand, Rng};
+use rust_sodium::crypto::box_::{self, Nonce, PublicKey, SecretKey};
+use rust_sodium::crypto::sign::{self, Signature};
+use std::collections::HashMap;
 use std::fmt;
 use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 // ========================================================================================
 //                                     PeerId
@@ -66,6 +72,128 @@ pub struct ConnectionId {
     pub currently_handshaking: usize,
 }
 
+// ========================================================================================
+//                                     OfferStamp
+// ========================================================================================
+/// When connection info was made, until when it may be connected with and the nonce that keeps
+/// it from being connected with twice. Only sealed info (see `SealedConnectionInfo`) keeps a
+/// peer from forging it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct OfferStamp {
+    /// Seconds since the Unix epoch the info was made at.
+    pub created_at: u64,
+    /// Seconds since the Unix epoch after which the info is refused.
+    pub expires_at: u64,
+    /// Random, for the info to be connected with once only.
+    pub nonce: u64,
+}
+
+impl OfferStamp {
+    /// A stamp for info made now, to be connected with within `ttl`.
+    pub fn new(ttl: Duration) -> Self {
+        let now = unix_time();
+        OfferStamp {
+            created_at: now,
+            expires_at: now.saturating_add(ttl.as_secs()),
+            nonce: rand::random(),
+        }
+    }
+
+    /// Whether the info may no longer be connected with, going by our clock.
+    pub fn is_expired(&self) -> bool {
+        unix_time() > self.expires_at
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+// Past this many, the nonces of our info that peers connected with are forgotten, those expiring
+// first going first.
+const MAX_ISSUED_OFFERS: usize = 1024;
+
+/// The nonces of the info we handed out and of the peers' info we connected with.
+///
+/// Each peer connecting sends the nonce of our info it connects with, which we must have handed
+/// out. Once the handshake succeeds the nonce is the peer's, and anyone else sending it is refused,
+/// while the peer itself may send it again on reconnecting.
+#[derive(Debug, Default)]
+pub struct OfferLedger {
+    // Nonces of our info, along with when it expires and the peer that connected with it, if any.
+    issued: HashMap<u64, (u64, Option<PeerId>)>,
+    // Nonces of the peers' info we connected with, kept until the info expires.
+    used: HashMap<(PeerId, u64), u64>,
+}
+
+impl OfferLedger {
+    /// A stamp for info made now, to be connected with within `ttl`, remembered as handed out.
+    pub fn issue(&mut self, ttl: Duration) -> OfferStamp {
+        let stamp = OfferStamp::new(ttl);
+        self.forget_issued();
+        let _ = self.issued.insert(stamp.nonce, (stamp.expires_at, None));
+        stamp
+    }
+
+    /// Whether `peer` may connect with the info of ours carrying `nonce`.
+    pub fn admits(&self, peer: &PeerId, nonce: u64) -> bool {
+        match self.issued.get(&nonce) {
+            Some(&(_, Some(ref redeemed_by))) => redeemed_by == peer,
+            Some(&(expires_at, None)) => unix_time() <= expires_at,
+            None => false,
+        }
+    }
+
+    /// Hand the nonce of our info over to `peer`, which completed a handshake with it.
+    pub fn redeem(&mut self, peer: PeerId, nonce: u64) {
+        if let Some(&mut (_, ref mut redeemed_by)) = self.issued.get_mut(&nonce) {
+            *redeemed_by = Some(peer);
+        }
+    }
+
+    /// Check that the info of `peer` stamped with `stamp` may be connected with.
+    pub fn check(&self, peer: PeerId, stamp: &OfferStamp) -> ::Res<()> {
+        if stamp.is_expired() {
+            return Err(CrustError::ConnectionInfoExpired);
+        }
+        if self.used.contains_key(&(peer, stamp.nonce)) {
+            return Err(CrustError::ConnectionInfoReplayed);
+        }
+        Ok(())
+    }
+
+    /// Record having connected to `peer` with its info stamped with `stamp`, for it not to be
+    /// connected with again.
+    pub fn use_offer(&mut self, peer: PeerId, stamp: &OfferStamp) {
+        let now = unix_time();
+        self.used.retain(|_, expires_at| *expires_at >= now);
+        let _ = self.used.insert((peer, stamp.nonce), stamp.expires_at);
+    }
+
+    // Forget the nonces of ours that expired before anyone connected with them, and the oldest of
+    // those connected with once there are too many.
+    fn forget_issued(&mut self) {
+        let now = unix_time();
+        self.issued
+            .retain(|_, &mut (expires_at, redeemed_by)| redeemed_by.is_some() || expires_at >= now);
+        if self.issued.len() < MAX_ISSUED_OFFERS {
+            return;
+        }
+        let mut by_expiry: Vec<_> = self.issued
+            .iter()
+            .map(|(&nonce, &(expires_at, _))| (expires_at, nonce))
+            .collect();
+        by_expiry.sort();
+        let excess = self.issued.len() + 1 - MAX_ISSUED_OFFERS;
+        for &(_, nonce) in by_expiry.iter().take(excess) {
+            let _ = self.issued.remove(&nonce);
+        }
+    }
+}
+
 // ========================================================================================
 //                                   ConnectionInfoResult
 // ========================================================================================
@@ -92,6 +220,10 @@ pub struct PrivConnectionInfo {
     pub for_hole_punch: Vec<SocketAddr>,
     #[doc(hidden)]
     pub hole_punch_socket: Option<TcpBuilder>,
+    #[doc(hidden)]
+    pub relay: Option<SocketAddr>,
+    #[doc(hidden)]
+    pub stamp: OfferStamp,
 }
 
 impl PrivConnectionInfo {
@@ -101,7 +233,9 @@ impl PrivConnectionInfo {
         PubConnectionInfo {
             for_hole_punch: self.for_hole_punch.clone(),
             for_direct: self.for_direct.clone(),
+            relay: self.relay,
             id: self.id,
+            stamp: self.stamp,
         }
     }
 }
@@ -110,7 +244,7 @@ impl PrivConnectionInfo {
 //                                     PubConnectionInfo
 // ========================================================================================
 /// Contact info used to connect to another peer.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PubConnectionInfo {
     #[doc(hidden)]
     pub id: PeerId,
@@ -118,6 +252,13 @@ pub struct PubConnectionInfo {
     pub for_hole_punch: Vec<SocketAddr>,
     #[doc(hidden)]
     pub for_direct: Vec<SocketAddr>,
+    // Absent from the info of peers predating relays. Only self-describing encodings can leave it
+    // out; binary ones need the info exchanged out of band to be versioned.
+    #[doc(hidden)]
+    #[serde(default)]
+    pub relay: Option<SocketAddr>,
+    #[doc(hidden)]
+    pub stamp: OfferStamp,
 }
 
 impl PubConnectionInfo {
@@ -125,4 +266,216 @@ impl PubConnectionInfo {
     pub fn id(&self) -> PeerId {
         self.id
     }
+
+    /// When the info was made, when it expires and its nonce.
+    pub fn stamp(&self) -> &OfferStamp {
+        &self.stamp
+    }
+}
+
+// ========================================================================================
+//                                   SealedConnectionInfo
+// ========================================================================================
+// Keeps signatures over connection info from being good for anything else signed with the key.
+const SEAL_CONTEXT: &'static [u8] = b"crust-connection-info";
+
+/// `PubConnectionInfo` signed with the sender's identity key and, if it was sealed for a given
+/// peer, encrypted so that only that peer can read it. Created by
+/// `Service::seal_connection_info` and checked by `Service::open_connection_info`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedConnectionInfo {
+    sender: PeerId,
+    body: SealedBody,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum SealedBody {
+    Signed(Vec<u8>),
+    Encrypted(Nonce, Vec<u8>),
+}
+
+#[derive(Serialize, Deserialize)]
+struct SignedConnectionInfo {
+    info: Vec<u8>,
+    key: sign::PublicKey,
+    sig: Signature,
+}
+
+impl SealedConnectionInfo {
+    /// Sign `info` with `identity` and, given a `recipient`, encrypt it to that peer with our
+    /// `PeerId` key, whose secret half is `our_sk`.
+    pub fn seal(info: &PubConnectionInfo,
+                identity: &IdentityKeys,
+                our_sk: &SecretKey,
+                recipient: Option<&PeerId>)
+                -> ::Res<Self> {
+        let sender = info.id;
+        let info = serialise(info)?;
+        let signed = serialise(&SignedConnectionInfo {
+                                   sig: identity.sign(&seal_data(&info)),
+                                   key: identity.identity().0,
+                                   info: info,
+                               })?;
+        let body = match recipient {
+            Some(&PeerId(ref their_pk)) => {
+                let nonce = box_::gen_nonce();
+                let bytes = box_::seal(&signed, &nonce, their_pk, our_sk);
+                SealedBody::Encrypted(nonce, bytes)
+            }
+            None => SealedBody::Signed(signed),
+        };
+        Ok(SealedConnectionInfo {
+               sender: sender,
+               body: body,
+           })
+    }
+
+    /// The `PeerId` of the node claiming to have sealed this.
+    pub fn sender(&self) -> PeerId {
+        self.sender
+    }
+
+    /// Whether only the peer it was sealed for can open this.
+    pub fn is_encrypted(&self) -> bool {
+        match self.body {
+            SealedBody::Encrypted(..) => true,
+            SealedBody::Signed(_) => false,
+        }
+    }
+
+    /// Decrypt, if need be, with `our_sk` and check the signature, returning the connection info
+    /// and the identity of whoever sealed it. Fails if the info was tampered with, was sealed for
+    /// someone else, or names a peer other than its sender.
+    pub fn open(&self, our_sk: &SecretKey) -> ::Res<(PubConnectionInfo, Identity)> {
+        let signed = match self.body {
+            SealedBody::Signed(ref bytes) => bytes.clone(),
+            SealedBody::Encrypted(ref nonce, ref bytes) => {
+                box_::open(bytes, nonce, &(self.sender.0), our_sk)
+                    .map_err(|()| CrustError::InvalidConnectionInfo("cannot decrypt"))?
+            }
+        };
+        let signed: SignedConnectionInfo = deserialise(&signed)?;
+        if !sign::verify_detached(&signed.sig, &seal_data(&signed.info), &signed.key) {
+            return Err(CrustError::InvalidConnectionInfo("bad signature"));
+        }
+        let info: PubConnectionInfo = deserialise(&signed.info)?;
+        if info.id != self.sender {
+            return Err(CrustError::InvalidConnectionInfo("sender mismatch"));
+        }
+        Ok((info, Identity(signed.key)))
+    }
+}
+
+fn seal_data(info: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(SEAL_CONTEXT.len() + info.len());
+    data.extend_from_slice(SEAL_CONTEXT);
+    data.extend_from_slice(info);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::IdentityKeys;
+    use rust_sodium::crypto::box_;
+
+    fn info(id: PeerId) -> PubConnectionInfo {
+        PubConnectionInfo {
+            id: id,
+            for_hole_punch: Vec::new(),
+            for_direct: vec![unwrap!("127.0.0.1:5483".parse())],
+            relay: None,
+            stamp: OfferStamp::new(Duration::from_secs(60)),
+        }
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let identity = IdentityKeys::generate();
+        let (our_pk, our_sk) = box_::gen_keypair();
+        let (their_pk, their_sk) = box_::gen_keypair();
+        let our_info = info(PeerId(our_pk));
+
+        let signed = unwrap!(SealedConnectionInfo::seal(&our_info, &identity, &our_sk, None));
+        assert!(!signed.is_encrypted());
+        let (opened, id) = unwrap!(signed.open(&their_sk));
+        assert_eq!(opened, our_info);
+        assert_eq!(id, identity.identity());
+
+        let sealed = unwrap!(SealedConnectionInfo::seal(&our_info,
+                                                        &identity,
+                                                        &our_sk,
+                                                        Some(&PeerId(their_pk))));
+        assert!(sealed.is_encrypted());
+        let (opened, id) = unwrap!(sealed.open(&their_sk));
+        assert_eq!(opened, our_info);
+        assert_eq!(id, identity.identity());
+
+        // Only the recipient can read it
+        let (_, other_sk) = box_::gen_keypair();
+        assert!(sealed.open(&other_sk).is_err());
+    }
+
+    #[test]
+    fn tampered() {
+        let identity = IdentityKeys::generate();
+        let (our_pk, our_sk) = box_::gen_keypair();
+        let (_, their_sk) = box_::gen_keypair();
+
+        let mut sealed = unwrap!(SealedConnectionInfo::seal(&info(PeerId(our_pk)),
+                                                            &identity,
+                                                            &our_sk,
+                                                            None));
+        if let SealedBody::Signed(ref mut bytes) = sealed.body {
+            let last = bytes.len() - 1;
+            bytes[last] ^= 1;
+        }
+        assert!(sealed.open(&their_sk).is_err());
+
+        // Info signed by its maker but passed off as someone else's
+        let mut sealed = unwrap!(SealedConnectionInfo::seal(&info(PeerId(our_pk)),
+                                                            &identity,
+                                                            &our_sk,
+                                                            None));
+        sealed.sender = PeerId(box_::gen_keypair().0);
+        assert!(sealed.open(&their_sk).is_err());
+    }
+
+    #[test]
+    fn offers_used_once() {
+        let mut ledger = OfferLedger::default();
+        let ours = PeerId(box_::gen_keypair().0);
+        let theirs = PeerId(box_::gen_keypair().0);
+        let stamp = OfferStamp::new(Duration::from_secs(60));
+        unwrap!(ledger.check(ours, &stamp));
+        ledger.use_offer(ours, &stamp);
+        unwrap!(ledger.check(theirs, &stamp));
+        match ledger.check(ours, &stamp) {
+            Err(CrustError::ConnectionInfoReplayed) => (),
+            res => panic!("Unexpected {:?}", res),
+        }
+        unwrap!(ledger.check(ours, &OfferStamp::new(Duration::from_secs(60))));
+
+        let mut stale = OfferStamp::new(Duration::from_secs(60));
+        stale.expires_at = stale.created_at - 1;
+        match ledger.check(ours, &stale) {
+            Err(CrustError::ConnectionInfoExpired) => (),
+            res => panic!("Unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn offers_redeemed_once() {
+        let mut ledger = OfferLedger::default();
+        let first = PeerId(box_::gen_keypair().0);
+        let second = PeerId(box_::gen_keypair().0);
+        let stamp = ledger.issue(Duration::from_secs(60));
+        assert!(!ledger.admits(&first, stamp.nonce.wrapping_add(1)));
+        assert!(ledger.admits(&first, stamp.nonce));
+        assert!(ledger.admits(&second, stamp.nonce));
+
+        ledger.redeem(first, stamp.nonce);
+        assert!(ledger.admits(&first, stamp.nonce));
+        assert!(!ledger.admits(&second, stamp.nonce));
+    }
 }
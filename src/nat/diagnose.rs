@@ -0,0 +1,247 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{Core, CoreTimer, State, Timeout};
+use mio::{Poll, PollOpt, Ready, Token};
+use mio::tcp::{TcpListener, TcpStream};
+use nat::{DetectNatType, MappedTcpSocket, MappingContext, MappingResult, NatError, NatType, util};
+use nat::mapped_addr::MappedAddrSource;
+use net2::TcpBuilder;
+use std::any::Any;
+use std::cell::RefCell;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::time::Duration;
+
+const HAIRPIN_TIMEOUT_SEC: u64 = 3;
+
+/// What `diagnose` found out about our network - worth attaching to any "I can't connect" report.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NatDiagnostics {
+    /// Whether an IGD, NAT-PMP or PCP gateway was found on any of our interfaces.
+    pub gateway_found: bool,
+    /// Whether a gateway actually forwarded a port for us.
+    pub upnp_working: bool,
+    /// Our external IPs as reported by the peers and gateways. Empty if nobody answered.
+    pub external_ips: Vec<IpAddr>,
+    /// How the NAT treats udp traffic, if that could be determined.
+    pub nat_type: Option<NatType>,
+    /// Whether the NAT kept our port number when mapping a tcp socket, if any peer told us.
+    pub port_preserved: Option<bool>,
+    /// Whether we could connect to our own external address from inside the network, if there
+    /// was one to try. Without this, peers behind the same NAT must use our local addresses.
+    pub hairpin: Option<bool>,
+}
+
+struct Progress<F> {
+    report: NatDiagnostics,
+    pending: usize,
+    finish: Option<F>,
+}
+
+impl<F> Progress<F>
+    where F: FnOnce(&mut Core, &Poll, NatDiagnostics) + Any
+{
+    fn done(progress: &Rc<RefCell<Progress<F>>>, core: &mut Core, poll: &Poll) {
+        let (finish, report) = {
+            let mut progress = progress.borrow_mut();
+            progress.pending -= 1;
+            if progress.pending > 0 {
+                return;
+            }
+            (progress.finish.take(), progress.report.clone())
+        };
+        if let Some(finish) = finish {
+            finish(core, poll, report);
+        }
+    }
+}
+
+/// Map a tcp socket, detect our NAT type and check for hairpinning, all at once, and report what
+/// was found to `finish`.
+pub fn diagnose<F>(core: &mut Core,
+                   poll: &Poll,
+                   mc: &MappingContext,
+                   finish: F)
+                   -> Result<(), NatError>
+    where F: FnOnce(&mut Core, &Poll, NatDiagnostics) + Any
+{
+    let gateways = mc.gateways();
+    let report = NatDiagnostics {
+        gateway_found: !(gateways.igd.is_empty() && gateways.nat_pmp.is_empty() &&
+                         gateways.pcp.is_empty()),
+        ..Default::default()
+    };
+    let progress = Rc::new(RefCell::new(Progress {
+                                            report: report,
+                                            pending: 2,
+                                            finish: Some(finish),
+                                        }));
+
+    let progress_0 = progress.clone();
+    let mapping_finish = move |core: &mut Core, poll: &Poll, res: MappingResult| {
+        let (socket, mapped_addrs) = match res {
+            Ok(res) => res,
+            Err(e) => {
+                debug!("Could not map a tcp socket for diagnosis: {:?}", e);
+                return Progress::done(&progress_0, core, poll);
+            }
+        };
+
+        let local_port = socket.local_addr().ok().map(|addr| addr.port());
+        {
+            let mut progress = progress_0.borrow_mut();
+            let report = &mut progress.report;
+            for mapped in &mapped_addrs {
+                // Only what the network told us counts, not what we were told to assume
+                if mapped.source == MappedAddrSource::Local ||
+                   mapped.source == MappedAddrSource::Configured ||
+                   !util::ip_addr_is_global(&mapped.addr.ip()) {
+                    continue;
+                }
+                if !report.external_ips.contains(&mapped.addr.ip()) {
+                    report.external_ips.push(mapped.addr.ip());
+                }
+                if mapped.source == MappedAddrSource::Router {
+                    report.upnp_working = true;
+                } else {
+                    let preserved = Some(mapped.addr.port()) == local_port;
+                    report.port_preserved = Some(report.port_preserved.unwrap_or(true) &&
+                                                 preserved);
+                }
+            }
+        }
+
+        let target = mapped_addrs
+            .iter()
+            .find(|mapped| {
+                      mapped.source != MappedAddrSource::Local &&
+                      util::ip_addr_is_global(&mapped.addr.ip())
+                  })
+            .map(|mapped| mapped.addr);
+        if let Some(target) = target {
+            let progress_1 = progress_0.clone();
+            let hairpin_finish = move |core: &mut Core, poll: &Poll, hairpin: bool| {
+                progress_1.borrow_mut().report.hairpin = Some(hairpin);
+                Progress::done(&progress_1, core, poll);
+            };
+            match CheckHairpin::start(core, poll, socket, target, hairpin_finish) {
+                Ok(()) => return,
+                Err(e) => debug!("Could not check for hairpinning: {:?}", e),
+            }
+        }
+        Progress::done(&progress_0, core, poll);
+    };
+    // What the peers told us of other sockets says nothing of how this one is mapped
+    let mut fresh_mc = mc.clone();
+    let mut config = *mc.mapping_config();
+    config.ext_addr_ttl = Duration::from_secs(0);
+    fresh_mc.set_mapping_config(config);
+    let _ = MappedTcpSocket::start(core, poll, 0, &fresh_mc, mapping_finish)?;
+
+    let progress_2 = progress.clone();
+    let nat_type_finish = move |core: &mut Core, poll: &Poll, nat_type: Option<NatType>| {
+        progress_2.borrow_mut().report.nat_type = nat_type;
+        Progress::done(&progress_2, core, poll);
+    };
+    if let Err(e) = DetectNatType::start(core, poll, mc, nat_type_finish) {
+        debug!("Could not detect NAT type for diagnosis: {}", e);
+        Progress::done(&progress, core, poll);
+    }
+
+    Ok(())
+}
+
+// Connects to our own external address while listening on the mapped socket.
+struct CheckHairpin<F> {
+    token: Token,
+    // Kept so that the connection has something to reach
+    _listener: TcpListener,
+    stream: TcpStream,
+    timeout: Timeout,
+    finish: Option<F>,
+}
+
+impl<F> CheckHairpin<F>
+    where F: FnOnce(&mut Core, &Poll, bool) + Any
+{
+    fn start(core: &mut Core,
+             poll: &Poll,
+             socket: TcpBuilder,
+             target: SocketAddr,
+             finish: F)
+             -> Result<(), NatError> {
+        let listener = socket.listen(1)?;
+        let local_addr = listener.local_addr()?;
+        let listener = TcpListener::from_listener(listener, &local_addr)?;
+        let stream = TcpStream::connect(&target)?;
+
+        let token = core.get_new_token();
+        poll.register(&stream,
+                      token,
+                      Ready::error() | Ready::hup() | Ready::writable(),
+                      PollOpt::edge())?;
+        let timeout = core.set_timeout(Duration::from_secs(HAIRPIN_TIMEOUT_SEC),
+                                       CoreTimer::new(token, 0))?;
+
+        let state = CheckHairpin {
+            token: token,
+            _listener: listener,
+            stream: stream,
+            timeout: timeout,
+            finish: Some(finish),
+        };
+
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+
+        Ok(())
+    }
+
+    fn handle_result(&mut self, core: &mut Core, poll: &Poll, hairpin: bool) {
+        self.terminate(core, poll);
+        if let Some(finish) = self.finish.take() {
+            finish(core, poll, hairpin);
+        }
+    }
+}
+
+impl<F> State for CheckHairpin<F>
+    where F: FnOnce(&mut Core, &Poll, bool) + Any
+{
+    fn name(&self) -> &'static str {
+        "CheckHairpin"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        let hairpin = !kind.is_error() && !kind.is_hup() && kind.is_writable();
+        self.handle_result(core, poll, hairpin);
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u8) {
+        self.handle_result(core, poll, false);
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        let _ = core.cancel_timeout(&self.timeout);
+        let _ = core.remove_state(self.token);
+        let _ = poll.deregister(&self.stream);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
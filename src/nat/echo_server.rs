@@ -0,0 +1,390 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{Core, Message, State};
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use mio::{Poll, PollOpt, Ready, Token};
+use mio::udp::UdpSocket;
+use nat::{NatError, util};
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const MAX_PENDING_RESPONSES: usize = 64;
+/// Requests any one IP may make per `RATE_WINDOW_SEC` before being ignored.
+const MAX_REQUESTS_PER_WINDOW: u32 = 20;
+const RATE_WINDOW_SEC: u64 = 10;
+/// Beyond this many requesters the oldest are forgotten, so a flood of addresses cannot exhaust
+/// our memory.
+const MAX_TRACKED_IPS: usize = 1024;
+/// Rendezvous waiting for their second client, and how long they wait. Those met are remembered
+/// as long, for answering a client whose answer was lost.
+const MAX_PENDING_RENDEZVOUS: usize = 256;
+pub const RENDEZVOUS_TIMEOUT_SEC: u64 = 30;
+/// Largest connection info brokered.
+pub const MAX_INFO_LEN: usize = 1024;
+
+/// A state which serves other nodes' NAT traversal over udp: it tells them what the world sees
+/// their address as and brokers connection info between two clients meeting at a rendezvous.
+///
+/// A client sends `RendezvousReq(key, info)`. Once a second client sends the same key, each is
+/// sent `RendezvousResp(addr, info)` with the other's external address and info, after which they
+/// can hole punch to each other. A client asking again after that is sent the same answer, as
+/// the first may have been lost. Every request counts against a per IP rate limit.
+pub struct EchoServer {
+    token: Token,
+    socket: UdpSocket,
+    read_buf: [u8; 2048],
+    replies: VecDeque<(SocketAddr, Vec<u8>)>,
+    limiter: RateLimiter,
+    rendezvous: HashMap<Vec<u8>, PendingRendezvous>,
+    met: HashMap<Vec<u8>, (PendingRendezvous, PendingRendezvous)>,
+}
+
+struct PendingRendezvous {
+    addr: SocketAddr,
+    info: Vec<u8>,
+    since: Instant,
+}
+
+impl EchoServer {
+    /// Start serving on `local_addr`, usually the address of our tcp listener.
+    pub fn start(core: &mut Core, poll: &Poll, local_addr: &SocketAddr) -> Result<Token, NatError> {
+        let socket = util::new_reusably_bound_udp_socket(local_addr)?;
+        let socket = UdpSocket::from_socket(socket)?;
+        let token = core.get_new_token();
+
+        poll.register(&socket,
+                      token,
+                      Ready::error() | Ready::hup() | Ready::readable(),
+                      PollOpt::edge())?;
+
+        let state = EchoServer {
+            token: token,
+            socket: socket,
+            read_buf: [0; 2048],
+            replies: VecDeque::with_capacity(MAX_PENDING_RESPONSES),
+            limiter: RateLimiter::new(MAX_REQUESTS_PER_WINDOW,
+                                      Duration::from_secs(RATE_WINDOW_SEC)),
+            rendezvous: HashMap::new(),
+            met: HashMap::new(),
+        };
+
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+
+        Ok(token)
+    }
+
+    fn read(&mut self, core: &mut Core, poll: &Poll) {
+        loop {
+            let (bytes_rxd, peer_addr) = match self.socket.recv_from(&mut self.read_buf) {
+                Ok(Some(res)) => res,
+                Ok(None) => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    debug!("EchoServer error in read: {:?}", e);
+                    return self.terminate(core, poll);
+                }
+            };
+
+            if !self.limiter.allow(peer_addr.ip()) {
+                trace!("EchoServer rate limiting {}", peer_addr);
+                continue;
+            }
+
+            match deserialise(&self.read_buf[..bytes_rxd]) {
+                Ok(Message::EchoAddrReq) => {
+                    self.queue_reply(peer_addr, &Message::EchoAddrResp(peer_addr))
+                }
+                Ok(Message::EchoAddrReqTo(target)) => {
+                    // Only ever redirect to another port of the requester so we can't be used to
+                    // spray traffic at third parties.
+                    let target = SocketAddr::new(peer_addr.ip(), target.port());
+                    self.queue_reply(target, &Message::EchoAddrResp(target));
+                }
+                Ok(Message::EchoAddrReqFromOtherPort) => self.reply_from_other_port(peer_addr),
+                Ok(Message::RendezvousReq(key, info)) => {
+                    self.handle_rendezvous_req(peer_addr, key, info)
+                }
+                Ok(msg) => trace!("Unexpected udp message from {}: {:?}", peer_addr, msg),
+                Err(e) => trace!("Bogus udp message from {}: {:?}", peer_addr, e),
+            }
+        }
+
+        self.write(core, poll);
+    }
+
+    fn handle_rendezvous_req(&mut self, peer_addr: SocketAddr, key: Vec<u8>, info: Vec<u8>) {
+        if info.len() > MAX_INFO_LEN {
+            trace!("Refusing to broker {} bytes of info for {}", info.len(), peer_addr);
+            return;
+        }
+
+        let timeout = Duration::from_secs(RENDEZVOUS_TIMEOUT_SEC);
+        self.rendezvous.retain(|_, pending| pending.since.elapsed() < timeout);
+        self.met.retain(|_, &mut (_, ref second)| second.since.elapsed() < timeout);
+
+        // One of a pair which met already, whose answer was lost
+        let answer = self.met
+            .get(&key)
+            .and_then(|&(ref first, ref second)| if first.addr == peer_addr {
+                          Some(Message::RendezvousResp(second.addr, second.info.clone()))
+                      } else if second.addr == peer_addr {
+                          Some(Message::RendezvousResp(first.addr, first.info.clone()))
+                      } else {
+                          None
+                      });
+        if let Some(answer) = answer {
+            return self.queue_reply(peer_addr, &answer);
+        }
+
+        let other = match self.rendezvous.remove(&key) {
+            // The same client asking again, e.g. because a packet was lost
+            Some(ref pending) if pending.addr == peer_addr => None,
+            other => other,
+        };
+        match other {
+            Some(other) => {
+                self.queue_reply(peer_addr,
+                                 &Message::RendezvousResp(other.addr, other.info.clone()));
+                self.queue_reply(other.addr, &Message::RendezvousResp(peer_addr, info.clone()));
+                if self.met.len() < MAX_PENDING_RENDEZVOUS {
+                    let second = PendingRendezvous {
+                        addr: peer_addr,
+                        info: info,
+                        since: Instant::now(),
+                    };
+                    let _ = self.met.insert(key, (other, second));
+                }
+            }
+            None => {
+                if self.rendezvous.len() >= MAX_PENDING_RENDEZVOUS {
+                    trace!("Too many pending rendezvous, ignoring {}", peer_addr);
+                    return;
+                }
+                let _ = self.rendezvous.insert(key,
+                                               PendingRendezvous {
+                                                   addr: peer_addr,
+                                                   info: info,
+                                                   since: Instant::now(),
+                                               });
+            }
+        }
+    }
+
+    fn queue_reply(&mut self, peer_addr: SocketAddr, msg: &Message) {
+        if self.replies.len() >= MAX_PENDING_RESPONSES {
+            return;
+        }
+        match serialise(msg) {
+            Ok(resp) => self.replies.push_back((peer_addr, resp)),
+            Err(e) => debug!("EchoServer could not serialise response: {:?}", e),
+        }
+    }
+
+    // Lets the peer find out whether its NAT filters on port as well as on address.
+    fn reply_from_other_port(&self, peer_addr: SocketAddr) {
+        let res = self.socket
+            .local_addr()
+            .and_then(|local_addr| {
+                          let unspecified = if local_addr.is_ipv4() {
+                              IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))
+                          } else {
+                              IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0))
+                          };
+                          net::UdpSocket::bind(SocketAddr::new(unspecified, 0))
+                      });
+        let socket = match res {
+            Ok(socket) => socket,
+            Err(e) => {
+                debug!("EchoServer could not bind other port: {:?}", e);
+                return;
+            }
+        };
+        match serialise(&Message::EchoAddrResp(peer_addr)) {
+            Ok(resp) => {
+                if let Err(e) = socket.send_to(&resp, &peer_addr) {
+                    trace!("EchoServer could not reply from other port: {:?}", e);
+                }
+            }
+            Err(e) => debug!("EchoServer could not serialise response: {:?}", e),
+        }
+    }
+
+    fn write(&mut self, core: &mut Core, poll: &Poll) {
+        while let Some((peer_addr, resp)) = self.replies.pop_front() {
+            match self.socket.send_to(&resp, &peer_addr) {
+                // UDP is all or none so if anything is written we consider it written
+                Ok(Some(_)) => (),
+                Ok(None) => {
+                    self.replies.push_front((peer_addr, resp));
+                    break;
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted ||
+                              e.kind() == ErrorKind::WouldBlock => {
+                    self.replies.push_front((peer_addr, resp));
+                    break;
+                }
+                Err(e) => {
+                    debug!("EchoServer error in write: {:?}", e);
+                    return self.terminate(core, poll);
+                }
+            }
+        }
+
+        let kind = if self.replies.is_empty() {
+            Ready::error() | Ready::hup() | Ready::readable()
+        } else {
+            Ready::error() | Ready::hup() | Ready::readable() | Ready::writable()
+        };
+
+        if let Err(e) = poll.reregister(&self.socket, self.token, kind, PollOpt::edge()) {
+            debug!("EchoServer error in re-registeration: {:?}", e);
+            self.terminate(core, poll);
+        }
+    }
+}
+
+impl State for EchoServer {
+    fn name(&self) -> &'static str {
+        "EchoServer"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        if kind.is_error() || kind.is_hup() {
+            self.terminate(core, poll);
+        } else {
+            if kind.is_readable() {
+                self.read(core, poll);
+            }
+            if kind.is_writable() {
+                self.write(core, poll);
+            }
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        let _ = poll.deregister(&self.socket);
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+// Allows each IP a fixed number of requests per window.
+struct RateLimiter {
+    max: u32,
+    window: Duration,
+    requests: HashMap<IpAddr, (Instant, u32)>,
+}
+
+impl RateLimiter {
+    fn new(max: u32, window: Duration) -> RateLimiter {
+        RateLimiter {
+            max: max,
+            window: window,
+            requests: HashMap::new(),
+        }
+    }
+
+    fn allow(&mut self, ip: IpAddr) -> bool {
+        if self.requests.len() >= MAX_TRACKED_IPS && !self.requests.contains_key(&ip) {
+            let window = self.window;
+            self.requests.retain(|_, &mut (since, _)| since.elapsed() < window);
+            if self.requests.len() >= MAX_TRACKED_IPS {
+                return false;
+            }
+        }
+
+        let window = self.window;
+        let entry = self.requests.entry(ip).or_insert((Instant::now(), 0));
+        if entry.0.elapsed() >= window {
+            *entry = (Instant::now(), 0);
+        }
+        entry.1 += 1;
+        entry.1 <= self.max
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{self, CoreMessage, Message};
+    use maidsafe_utilities::serialisation::{deserialise, serialise};
+    use nat::util;
+    use std::net::{self, IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn rate_limit() {
+        let mut limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let ip = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+        let other_ip = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 5));
+
+        assert!(limiter.allow(ip));
+        assert!(limiter.allow(ip));
+        assert!(!limiter.allow(ip));
+        assert!(limiter.allow(other_ip));
+    }
+
+    #[test]
+    fn rendezvous() {
+        let el = unwrap!(common::spawn_event_loop(0, Some("EchoServer")));
+        let (tx, rx) = mpsc::channel();
+        unwrap!(el.send(CoreMessage::new(move |core, poll| {
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0);
+            let socket = unwrap!(util::new_reusably_bound_udp_socket(&addr));
+            let addr = unwrap!(socket.local_addr());
+            drop(socket);
+            let _ = unwrap!(EchoServer::start(core, poll, &addr));
+            unwrap!(tx.send(addr));
+        })));
+        let server_addr = unwrap!(rx.recv());
+
+        let client_0 = unwrap!(net::UdpSocket::bind("127.0.0.1:0"));
+        let client_1 = unwrap!(net::UdpSocket::bind("127.0.0.1:0"));
+        unwrap!(client_0.set_read_timeout(Some(Duration::from_secs(5))));
+        unwrap!(client_1.set_read_timeout(Some(Duration::from_secs(5))));
+
+        let key = b"meet here".to_vec();
+        for &(client, info) in &[(&client_0, b"info 0"), (&client_1, b"info 1")] {
+            let req = unwrap!(serialise(&Message::RendezvousReq(key.clone(), info.to_vec())));
+            let _ = unwrap!(client.send_to(&req, server_addr));
+        }
+
+        let mut buf = [0; 2048];
+        for &(client, other, info) in &[(&client_0, &client_1, b"info 1"),
+                                        (&client_1, &client_0, b"info 0")] {
+            let (len, _) = unwrap!(client.recv_from(&mut buf));
+            match unwrap!(deserialise(&buf[..len])) {
+                Message::RendezvousResp(addr, their_info) => {
+                    assert_eq!(addr, unwrap!(other.local_addr()));
+                    assert_eq!(their_info, info.to_vec());
+                }
+                msg => panic!("Unexpected message: {:?}", msg),
+            }
+        }
+    }
+}
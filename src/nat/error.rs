@@ -16,6 +16,7 @@
 // relating to use of the SAFE Network Software.
 
 use common::CommonError;
+use maidsafe_utilities::serialisation::SerialisationError;
 use std::io;
 
 quick_error! {
@@ -36,5 +37,32 @@ quick_error! {
             cause(e)
             from()
         }
+        /// Serialisation error
+        Serialisation(e: SerialisationError) {
+            description("Serialisation error during nat traversal")
+            display("Serialisation error during nat traversal: {}", e)
+            cause(e)
+            from()
+        }
+        /// No peers with an echo service are known
+        NoPeerStuns {
+            description("No peers known to query our external address from")
+            display("No peers known to query our external address from")
+        }
+        /// The operation was cancelled by the caller
+        Cancelled {
+            description("Cancelled")
+            display("Cancelled by the caller")
+        }
+        /// Asking peers for our external address is turned off in the mapping config
+        StunDisabled {
+            description("Querying peers is disabled")
+            display("Querying peers for our external address is disabled")
+        }
+        /// No authenticated answer came from any of the peer's endpoints in time
+        UdpHolePunchFailed {
+            description("Udp hole punching failed")
+            display("Udp hole punching failed: the peer never answered")
+        }
     }
 }
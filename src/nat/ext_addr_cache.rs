@@ -0,0 +1,173 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{Core, CoreMessage, lock};
+use mio::{Poll, Token};
+use mio::channel::Sender;
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+#[cfg(feature = "test_utils")]
+use test_utils::Answer;
+
+/// Tells the mapping behind `Token` what the resolving mapping found: the external IPs, or
+/// `None` if it has to ask the peers itself.
+pub type Notify = fn(&mut Core, &Poll, Token, Option<Vec<IpAddr>>);
+
+/// What a mapping should do about its external address.
+pub enum Lookup {
+    /// Use these IPs, which are fresh.
+    Cached(Vec<IpAddr>),
+    /// Another mapping is asking the peers already - wait to be notified.
+    Pending,
+    /// Ask the peers, then call `ExtAddrCache::resolved` to notify the others.
+    Resolve,
+    /// Ask the peers, nobody else waiting on the answers.
+    Query,
+}
+
+#[derive(Debug)]
+struct Entry {
+    found_at: Instant,
+    port_preserved: bool,
+}
+
+#[derive(Default)]
+struct Inner {
+    // By the external IP reported and the port of the socket it was reported for, so that what
+    // one socket showed of the NAT is not overwritten by what another one did.
+    entries: HashMap<(IpAddr, u16), Entry>,
+    resolving: bool,
+    subscribers: Vec<(Sender<CoreMessage>, Token, Notify)>,
+}
+
+impl Inner {
+    fn fresh(&self, ttl: Duration) -> Option<Vec<IpAddr>> {
+        let fresh: Vec<_> = self.entries
+            .iter()
+            .filter(|&(_, entry)| entry.found_at.elapsed() <= ttl)
+            .collect();
+        if fresh.is_empty() || fresh.iter().any(|&(_, entry)| !entry.port_preserved) {
+            return None;
+        }
+        let mut ips: Vec<_> = fresh.into_iter().map(|(&(ip, _), _)| ip).collect();
+        ips.sort();
+        ips.dedup();
+        Some(ips)
+    }
+}
+
+/// The external IPs peers have recently reported for our tcp sockets, shared by every clone.
+///
+/// Our public IP rarely changes between mappings, so while it is fresh a new socket can be
+/// assumed to be reachable on it without asking the peers again - but only if the NAT has been
+/// seen to keep our port numbers, since the port is not something which can be cached. Mappings
+/// running at the same time share a single round of queries: the first one asks the peers and the
+/// rest subscribe to its outcome, which reaches each of them on its own event loop.
+#[derive(Clone, Default)]
+pub struct ExtAddrCache {
+    inner: Arc<Mutex<Inner>>,
+    // What the peers of a `FakeNat` host report, in place of asking any
+    #[cfg(feature = "test_utils")]
+    script: Option<(Vec<IpAddr>, Answer)>,
+}
+
+impl ExtAddrCache {
+    /// A cache which has every mapping wait for the scripted peers to report `ext_ips` as
+    /// `answer` says, however recently they last did.
+    #[cfg(feature = "test_utils")]
+    pub fn scripted(ext_ips: Vec<IpAddr>, answer: Answer) -> ExtAddrCache {
+        ExtAddrCache {
+            inner: Default::default(),
+            script: Some((ext_ips, answer)),
+        }
+    }
+
+    /// Find out how the mapping behind `token` is to learn our external IPs. If it is told to
+    /// wait, `notify` is called on the event loop of `core` once the mapping asking the peers is
+    /// done. A zero `ttl` has it ask the peers itself whatever is cached.
+    pub fn lookup(&self, core: &mut Core, ttl: Duration, token: Token, notify: Notify) -> Lookup {
+        #[cfg(feature = "test_utils")]
+        {
+            if let Some((ref ext_ips, answer)) = self.script {
+                return ::nat::scripted::lookup(core, ext_ips, answer, token, notify);
+            }
+        }
+
+        if ttl == Duration::from_secs(0) {
+            return Lookup::Query;
+        }
+        let mut inner = lock(&self.inner);
+        if let Some(ips) = inner.fresh(ttl) {
+            Lookup::Cached(ips)
+        } else if inner.resolving {
+            inner.subscribers.push((core.sender().clone(), token, notify));
+            Lookup::Pending
+        } else {
+            inner.resolving = true;
+            Lookup::Resolve
+        }
+    }
+
+    /// Record that a peer saw our socket bound to `local_port` as `ext_addr`.
+    pub fn record(&self, local_port: u16, ext_addr: &SocketAddr) {
+        let entry = Entry {
+            found_at: Instant::now(),
+            port_preserved: ext_addr.port() == local_port,
+        };
+        let _ = lock(&self.inner)
+            .entries
+            .insert((ext_addr.ip(), local_port), entry);
+    }
+
+    /// Called by the mapping told to `Resolve` once it is done with the peers, successfully or
+    /// not, to pass on what it found to the mappings waiting on it.
+    pub fn resolved(&self, ttl: Duration) {
+        let (subscribers, ips) = {
+            let mut inner = lock(&self.inner);
+            inner.resolving = false;
+            (mem::replace(&mut inner.subscribers, Vec::new()), inner.fresh(ttl))
+        };
+        for (tx, token, notify) in subscribers {
+            let ips = ips.clone();
+            let msg = CoreMessage::new(move |core, poll| notify(core, poll, token, ips));
+            if let Err(e) = tx.send(msg) {
+                debug!("Could not notify mapping {:?} of our external IPs: {:?}", token, e);
+            }
+        }
+    }
+
+    /// Have the peers asked again by the next mapping, e.g. after a network change.
+    pub fn force_refresh(&self) {
+        lock(&self.inner).entries.clear();
+    }
+}
+
+impl fmt::Debug for ExtAddrCache {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let inner = lock(&self.inner);
+        let res = write!(formatter,
+                         "ExtAddrCache {{ entries: {:?}, resolving: {}, subscribers: {} }}",
+                         inner.entries,
+                         inner.resolving,
+                         inner.subscribers.len());
+        res
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use super::nat_pmp::{self, NatPmpGateway};
+use super::pcp::{self, PcpGateway};
+use common::lock;
+use crossbeam;
+use igd::{self, Gateway};
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long discovered gateways are trusted before being looked for again.
+pub const GATEWAY_TTL_SEC: u64 = 600;
+
+/// The IGD, NAT-PMP and PCP gateways found, each along with the v4 interface it serves.
+#[derive(Debug, Clone, Default)]
+pub struct Gateways {
+    pub igd: Vec<(Ipv4Addr, Gateway)>,
+    pub nat_pmp: Vec<(Ipv4Addr, NatPmpGateway)>,
+    pub pcp: Vec<(Ipv4Addr, PcpGateway)>,
+}
+
+impl Gateways {
+    /// Search for gateways on each of the v4 interfaces (address and netmask) given, IGD ones
+    /// only if `search_igd`. Blocks for up to a second.
+    pub fn discover(ifv4s: &[(Ipv4Addr, Ipv4Addr)], search_igd: bool) -> Gateways {
+        let mut igd_gateways: Vec<Option<Gateway>> = vec![None; ifv4s.len()];
+        let mut nat_pmp_gateways: Vec<Option<NatPmpGateway>> = vec![None; ifv4s.len()];
+        let mut pcp_gateways: Vec<Option<PcpGateway>> = vec![None; ifv4s.len()];
+
+        crossbeam::scope(|scope| {
+            let mut guards = Vec::with_capacity(3 * ifv4s.len());
+            for (((&(ip, netmask), igd_gateway), nat_pmp_gateway), pcp_gateway) in
+                ifv4s
+                    .iter()
+                    .zip(&mut igd_gateways)
+                    .zip(&mut nat_pmp_gateways)
+                    .zip(&mut pcp_gateways) {
+                if !ip.is_loopback() {
+                    if search_igd {
+                        guards.push(scope.spawn(move || {
+                            *igd_gateway =
+                                igd::search_gateway_from_timeout(ip, Duration::from_secs(1)).ok();
+                        }));
+                    }
+                    if ip.is_private() {
+                        guards.push(scope.spawn(move || {
+                            *nat_pmp_gateway =
+                                nat_pmp::search_gateway_from_timeout(ip,
+                                                                     netmask,
+                                                                     Duration::from_millis(250))
+                                    .ok();
+                        }));
+                        guards.push(scope.spawn(move || {
+                            *pcp_gateway =
+                                pcp::search_gateway_from_timeout(ip,
+                                                                 netmask,
+                                                                 Duration::from_millis(250))
+                                    .ok();
+                        }));
+                    }
+                }
+            }
+        });
+
+        // A PCP server will usually speak NAT-PMP too - only ask it once, in the newer protocol.
+        let nat_pmp = ifv4s
+            .iter()
+            .zip(nat_pmp_gateways)
+            .zip(&pcp_gateways)
+            .filter(|&(_, pcp_gateway)| pcp_gateway.is_none())
+            .filter_map(|((&(ip, _), gateway), _)| gateway.map(|gateway| (ip, gateway)))
+            .collect();
+        let pcp = ifv4s
+            .iter()
+            .zip(pcp_gateways)
+            .filter_map(|(&(ip, _), gateway)| gateway.map(|gateway| (ip, gateway)))
+            .collect();
+        let igd = ifv4s
+            .iter()
+            .zip(igd_gateways)
+            .filter_map(|(&(ip, _), gateway)| gateway.map(|gateway| (ip, gateway)))
+            .collect();
+
+        Gateways {
+            igd: igd,
+            nat_pmp: nat_pmp,
+            pcp: pcp,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    gateways: Gateways,
+    found_at: Instant,
+    expired: bool,
+    refreshing: bool,
+    // Whether gateways are looked for again merely for having grown old
+    background_refresh: bool,
+    // Whether IGD gateways are looked for at all
+    igd: bool,
+}
+
+/// Gateways found on our interfaces, shared by every clone. Once they are older than the TTL, or
+/// one of them has failed to answer, they are due to be looked for again - see
+/// `MappingContext::gateways` - while the old ones keep being handed out.
+#[derive(Debug, Clone)]
+pub struct GatewayCache {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl GatewayCache {
+    /// Discover the gateways serving `ifv4s` (address and netmask), blocking until done. IGD
+    /// gateways are left alone unless `igd`, as some routers misbehave when sent UPnP requests.
+    pub fn new(ifv4s: &[(Ipv4Addr, Ipv4Addr)], igd: bool) -> GatewayCache {
+        let gateways = Gateways::discover(ifv4s, igd);
+        GatewayCache {
+            inner: Arc::new(Mutex::new(Inner {
+                                           gateways: gateways,
+                                           found_at: Instant::now(),
+                                           expired: false,
+                                           refreshing: false,
+                                           background_refresh: true,
+                                           igd: igd,
+                                       })),
+        }
+    }
+
+    /// The gateways currently known.
+    pub fn gateways(&self) -> Gateways {
+        lock(&self.inner).gateways.clone()
+    }
+
+    /// Whether the gateways are stale and no one is looking for them yet, in which case the
+    /// caller is to, by `set_interfaces`.
+    pub fn start_refresh(&self) -> bool {
+        let mut inner = lock(&self.inner);
+        let ttl = Duration::from_secs(GATEWAY_TTL_SEC);
+        let aged = inner.background_refresh && inner.found_at.elapsed() > ttl;
+        if !inner.refreshing && (inner.expired || aged) {
+            inner.refreshing = true;
+            return true;
+        }
+        false
+    }
+
+    /// Look for the gateways serving `ifv4s` (address and netmask), which may have changed since,
+    /// blocking until done.
+    pub fn set_interfaces(&self, ifv4s: &[(Ipv4Addr, Ipv4Addr)]) {
+        let igd = lock(&self.inner).igd;
+        self.replace(Gateways::discover(ifv4s, igd));
+    }
+
+    /// Whether to look for the gateways again once they are older than the TTL. Off e.g. to save
+    /// the battery of a mobile device, in which case they are only looked for again once one has
+    /// failed us.
+    pub fn set_background_refresh(&self, enabled: bool) {
+        lock(&self.inner).background_refresh = enabled;
+    }
+
+    /// Have the gateways looked for again on next use, because one did not answer.
+    pub fn expire(&self) {
+        lock(&self.inner).expired = true;
+    }
+
+    fn replace(&self, gateways: Gateways) {
+        let mut inner = lock(&self.inner);
+        inner.gateways = gateways;
+        inner.found_at = Instant::now();
+        inner.expired = false;
+        inner.refreshing = false;
+    }
+}
@@ -0,0 +1,355 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{Core, CoreTimer, State, Timeout};
+use igd::{Gateway, PortMappingProtocol};
+use mio::{Poll, PollOpt, Ready, Token};
+use mio::tcp::TcpStream;
+use nat::NatError;
+use nat::lease_renewal::{IGD_DESCRIPTION, LEASE_SEC};
+use nat::port_mapping::MappingFailure;
+use std::any::Any;
+use std::cell::RefCell;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::rc::Rc;
+use std::time::Duration;
+
+const SERVICE_URN: &'static str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+// Nothing a gateway should send in answer to our requests comes anywhere near this.
+const MAX_RESPONSE_LEN: usize = 64 * 1024;
+
+pub type Finish = Box<FnMut(&mut Core, &Poll, Token, Result<SocketAddrV4, MappingFailure>)>;
+
+#[derive(Clone, Copy)]
+enum Phase {
+    ExternalIp,
+    AddAnyPort(Ipv4Addr),
+    // For gateways which predate `AddAnyPortMapping` - ask for the local port only.
+    AddPort(Ipv4Addr),
+}
+
+/// Obtains an IGD port mapping by talking SOAP over HTTP to the gateway, driven by the event loop
+/// so that the parent can abort it at any point by terminating it.
+pub struct GetIgdAddr {
+    token: Token,
+    gateway: Gateway,
+    protocol: PortMappingProtocol,
+    local_addr: SocketAddrV4,
+    phase: Phase,
+    stream: TcpStream,
+    request: Vec<u8>,
+    written: usize,
+    response: Vec<u8>,
+    timeout: Timeout,
+    finish: Finish,
+}
+
+impl GetIgdAddr {
+    pub fn start(core: &mut Core,
+                 poll: &Poll,
+                 gateway: Gateway,
+                 protocol: PortMappingProtocol,
+                 local_addr: SocketAddrV4,
+                 timeout: Duration,
+                 finish: Finish)
+                 -> Result<Token, NatError> {
+        let token = core.get_new_token();
+        let request = soap_request(&gateway, "GetExternalIPAddress", "");
+        let stream = TcpStream::connect(&SocketAddr::V4(gateway.addr))?;
+        let timeout = core.set_timeout(timeout, CoreTimer::new(token, 0))?;
+
+        let state = GetIgdAddr {
+            token: token,
+            gateway: gateway,
+            protocol: protocol,
+            local_addr: local_addr,
+            phase: Phase::ExternalIp,
+            stream: stream,
+            request: request,
+            written: 0,
+            response: Vec::new(),
+            timeout: timeout,
+            finish: finish,
+        };
+
+        poll.register(&state.stream,
+                      token,
+                      Ready::error() | Ready::hup() | Ready::readable() | Ready::writable(),
+                      PollOpt::edge())?;
+
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+
+        Ok(token)
+    }
+
+    // The gateway closes the connection once it has answered, so each request gets a fresh one.
+    fn send(&mut self, core: &mut Core, poll: &Poll, phase: Phase) {
+        let args = match phase {
+            Phase::ExternalIp => String::new(),
+            Phase::AddAnyPort(_) | Phase::AddPort(_) => {
+                mapping_args(self.protocol, self.local_addr.port(), &self.local_addr)
+            }
+        };
+        let action = match phase {
+            Phase::ExternalIp => "GetExternalIPAddress",
+            Phase::AddAnyPort(_) => "AddAnyPortMapping",
+            Phase::AddPort(_) => "AddPortMapping",
+        };
+
+        let _ = poll.deregister(&self.stream);
+        let stream = match TcpStream::connect(&SocketAddr::V4(self.gateway.addr)) {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("Could not connect to IGD gateway {}: {:?}", self.gateway.addr, e);
+                return self.handle_error(core, poll, MappingFailure::Unanswered);
+            }
+        };
+        if let Err(e) = poll.register(&stream,
+                                      self.token,
+                                      Ready::error() | Ready::hup() | Ready::readable() |
+                                      Ready::writable(),
+                                      PollOpt::edge()) {
+            debug!("Could not register connection to IGD gateway: {:?}", e);
+            return self.handle_error(core, poll, MappingFailure::Unanswered);
+        }
+
+        self.stream = stream;
+        self.phase = phase;
+        self.request = soap_request(&self.gateway, action, &args);
+        self.written = 0;
+        self.response.clear();
+    }
+
+    fn write(&mut self, core: &mut Core, poll: &Poll) {
+        while self.written < self.request.len() {
+            match self.stream.write(&self.request[self.written..]) {
+                Ok(0) => return self.handle_error(core, poll, MappingFailure::Unanswered),
+                Ok(bytes_txd) => self.written += bytes_txd,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => {
+                    debug!("Error writing to IGD gateway: {:?}", e);
+                    return self.handle_error(core, poll, MappingFailure::Unanswered);
+                }
+            }
+        }
+    }
+
+    fn read(&mut self, core: &mut Core, poll: &Poll) {
+        let mut buf = [0; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return self.handle_response(core, poll),
+                Ok(bytes_rxd) => self.response.extend_from_slice(&buf[..bytes_rxd]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => {
+                    debug!("Error reading from IGD gateway: {:?}", e);
+                    return self.handle_error(core, poll, MappingFailure::Unanswered);
+                }
+            }
+            if self.response.len() > MAX_RESPONSE_LEN {
+                debug!("IGD gateway sent an oversized response");
+                return self.handle_error(core, poll, MappingFailure::Refused);
+            }
+        }
+    }
+
+    fn handle_response(&mut self, core: &mut Core, poll: &Poll) {
+        // Hanging up without a word is no answer
+        if self.response.is_empty() {
+            return self.handle_error(core, poll, MappingFailure::Unanswered);
+        }
+        let body = match parse_response(&self.response) {
+            Ok(body) => Some(body),
+            Err(e) => {
+                trace!("IGD gateway refused request: {:?}", e);
+                None
+            }
+        };
+
+        let phase = self.phase;
+        match phase {
+            Phase::ExternalIp => {
+                let ext_ip = body.and_then(|body| xml_value(&body, "NewExternalIPAddress"))
+                    .and_then(|ip| ip.parse().ok());
+                match ext_ip {
+                    Some(ext_ip) => self.send(core, poll, Phase::AddAnyPort(ext_ip)),
+                    None => self.handle_error(core, poll, MappingFailure::Refused),
+                }
+            }
+            Phase::AddAnyPort(ext_ip) => {
+                let body = match body {
+                    Some(body) => body,
+                    None => return self.send(core, poll, Phase::AddPort(ext_ip)),
+                };
+                match xml_value(&body, "NewReservedPort").and_then(|port| port.parse().ok()) {
+                    Some(port) => self.done(core, poll, SocketAddrV4::new(ext_ip, port)),
+                    None => self.handle_error(core, poll, MappingFailure::Refused),
+                }
+            }
+            Phase::AddPort(ext_ip) => {
+                if body.is_some() {
+                    let port = self.local_addr.port();
+                    self.done(core, poll, SocketAddrV4::new(ext_ip, port));
+                } else {
+                    self.handle_error(core, poll, MappingFailure::Refused);
+                }
+            }
+        }
+    }
+
+    fn done(&mut self, core: &mut Core, poll: &Poll, ext_addr: SocketAddrV4) {
+        self.terminate(core, poll);
+        let token = self.token;
+        (*self.finish)(core, poll, token, Ok(ext_addr));
+    }
+
+    fn handle_error(&mut self, core: &mut Core, poll: &Poll, failure: MappingFailure) {
+        self.terminate(core, poll);
+        let token = self.token;
+        (*self.finish)(core, poll, token, Err(failure));
+    }
+}
+
+impl State for GetIgdAddr {
+    fn name(&self) -> &'static str {
+        "GetIgdAddr"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        if kind.is_error() {
+            return self.handle_error(core, poll, MappingFailure::Unanswered);
+        }
+        if kind.is_writable() {
+            self.write(core, poll);
+            if core.get_state(self.token).is_none() {
+                return;
+            }
+        }
+        // The gateway hanging up is how the end of its answer is marked, so read whatever is left.
+        if kind.is_readable() || kind.is_hup() {
+            self.read(core, poll);
+        }
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u8) {
+        debug!("IGD gateway {} did not answer in time", self.gateway.addr);
+        self.handle_error(core, poll, MappingFailure::Unanswered);
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        let _ = core.remove_state(self.token);
+        let _ = core.cancel_timeout(&self.timeout);
+        let _ = poll.deregister(&self.stream);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+fn soap_request(gateway: &Gateway, action: &str, args: &str) -> Vec<u8> {
+    let body = format!("<?xml version=\"1.0\"?>\r\n\
+                        <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+                        s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+                        <s:Body><u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>",
+                       action,
+                       SERVICE_URN,
+                       args);
+    format!("POST {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Content-Type: text/xml; charset=\"utf-8\"\r\n\
+             Content-Length: {}\r\n\
+             SOAPAction: \"{}#{}\"\r\n\
+             Connection: close\r\n\r\n{}",
+            gateway.control_url,
+            gateway.addr,
+            body.len(),
+            SERVICE_URN,
+            action,
+            body)
+            .into_bytes()
+}
+
+fn mapping_args(protocol: PortMappingProtocol, ext_port: u16, local_addr: &SocketAddrV4) -> String {
+    let protocol = match protocol {
+        PortMappingProtocol::TCP => "TCP",
+        PortMappingProtocol::UDP => "UDP",
+    };
+    format!("<NewRemoteHost></NewRemoteHost>\
+             <NewExternalPort>{}</NewExternalPort>\
+             <NewProtocol>{}</NewProtocol>\
+             <NewInternalPort>{}</NewInternalPort>\
+             <NewInternalClient>{}</NewInternalClient>\
+             <NewEnabled>1</NewEnabled>\
+             <NewPortMappingDescription>{}</NewPortMappingDescription>\
+             <NewLeaseDuration>{}</NewLeaseDuration>",
+            ext_port,
+            protocol,
+            local_addr.port(),
+            local_addr.ip(),
+            IGD_DESCRIPTION,
+            LEASE_SEC)
+}
+
+// Returns the body of a successful HTTP response.
+fn parse_response(response: &[u8]) -> io::Result<String> {
+    let response = String::from_utf8_lossy(response);
+    let (head, body) = match response.find("\r\n\r\n") {
+        Some(pos) => (&response[..pos], &response[pos + 4..]),
+        None => return Err(io::Error::new(ErrorKind::InvalidData, "truncated response")),
+    };
+    let status = head.lines()
+        .next()
+        .and_then(|status_line| status_line.split_whitespace().nth(1));
+    match status {
+        Some("200") => Ok(body.to_owned()),
+        Some(status) => Err(io::Error::new(ErrorKind::Other, format!("status {}", status))),
+        None => Err(io::Error::new(ErrorKind::InvalidData, "no status line")),
+    }
+}
+
+fn xml_value(body: &str, tag: &str) -> Option<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    body.find(&open).and_then(|pos| {
+        let value = &body[pos + open.len()..];
+        value.find(&close).map(|len| value[..len].trim().to_owned())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_soap_response() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\n\r\n\
+                         <s:Envelope><s:Body><u:AddAnyPortMappingResponse>\
+                         <NewReservedPort> 5483 </NewReservedPort>\
+                         </u:AddAnyPortMappingResponse></s:Body></s:Envelope>";
+        let body = unwrap!(parse_response(response));
+        assert_eq!(xml_value(&body, "NewReservedPort"), Some("5483".to_owned()));
+        assert_eq!(xml_value(&body, "NewExternalIPAddress"), None);
+
+        let refused = b"HTTP/1.1 500 Internal Server Error\r\n\r\n<errorCode>718</errorCode>";
+        assert!(parse_response(refused).is_err());
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\nContent-").is_err());
+    }
+}
@@ -0,0 +1,287 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use self::notifier::Notifier;
+use common::{Core, CoreMessage, State};
+use maidsafe_utilities::thread;
+use mio::{Poll, Token};
+use nat::{MappingContext, NatError};
+use std::any::Any;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::Duration;
+
+/// How long to block waiting for the OS before checking whether to stop.
+const WAIT_MS: u64 = 1000;
+/// How often the interfaces are read where the OS cannot tell us about changes.
+const POLL_INTERVAL_SEC: u64 = 5;
+/// Changes tend to come in bursts (link up, then addresses, then routes), so let them settle.
+const SETTLE_MS: u64 = 500;
+
+/// Invoked on the event loop once our interfaces have changed and `MappingContext` has been
+/// refreshed to match.
+pub type ChangeHandler = Box<FnMut(&mut Core, &Poll)>;
+
+/// A state which watches our network interfaces for changes, e.g. a laptop moving from Wi-Fi to
+/// ethernet, which invalidate every mapping made so far.
+///
+/// The OS is asked to notify us (netlink on Linux, a routing socket on macOS), falling back to
+/// reading the interfaces periodically elsewhere. On a change the `MappingContext` is refreshed
+/// and the handler told, so traversal can be redone.
+pub struct IfWatcher {
+    token: Token,
+    stop_flag: Arc<AtomicBool>,
+    on_change: ChangeHandler,
+}
+
+impl IfWatcher {
+    /// Start watching the interfaces of `mc` until terminated.
+    pub fn start(core: &mut Core,
+                 mc: Arc<MappingContext>,
+                 on_change: ChangeHandler)
+                 -> Result<Token, NatError> {
+        let token = core.get_new_token();
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let mut notifier = match Notifier::new(Duration::from_millis(WAIT_MS)) {
+            Ok(notifier) => Some(notifier),
+            Err(e) => {
+                debug!("No interface change notifications, polling instead: {:?}", e);
+                None
+            }
+        };
+
+        let stop_flag_0 = stop_flag.clone();
+        let tx = core.sender().clone();
+        // Changes may also be found on looking for the gateways again, so one is told by their
+        // count rather than by whether this very read found it
+        let mut changes = mc.interface_changes();
+        let _ = thread::named("Interface-Watcher", move || {
+            while !stop_flag_0.load(Ordering::SeqCst) {
+                let res = notifier.as_ref().map(|notifier| notifier.wait());
+                let hint = match res {
+                    Some(Ok(hint)) => hint,
+                    Some(Err(e)) => {
+                        debug!("Interface change notifications failed, polling instead: {:?}", e);
+                        notifier = None;
+                        continue;
+                    }
+                    None => {
+                        sleep(Duration::from_secs(POLL_INTERVAL_SEC));
+                        true
+                    }
+                };
+                if !hint {
+                    continue;
+                }
+
+                sleep(Duration::from_millis(SETTLE_MS));
+                if let Err(e) = mc.refresh_interfaces() {
+                    debug!("Could not read network interfaces: {:?}", e);
+                    continue;
+                }
+                if mc.interface_changes() != changes {
+                    changes = mc.interface_changes();
+                    let _ = tx.send(CoreMessage::new(move |core, poll| {
+                        let state = match core.get_state(token) {
+                            Some(state) => state,
+                            None => return,
+                        };
+                        let mut state = state.borrow_mut();
+                        if let Some(watcher) = state.as_any().downcast_mut::<IfWatcher>() {
+                            info!("Network interfaces changed");
+                            (*watcher.on_change)(core, poll);
+                        }
+                    }));
+                }
+            }
+        });
+
+        let state = IfWatcher {
+            token: token,
+            stop_flag: stop_flag,
+            on_change: on_change,
+        };
+
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+
+        Ok(token)
+    }
+}
+
+impl State for IfWatcher {
+    fn name(&self) -> &'static str {
+        "IfWatcher"
+    }
+
+    fn terminate(&mut self, core: &mut Core, _poll: &Poll) {
+        self.stop_flag.store(true, Ordering::SeqCst);
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod notifier {
+    use libc;
+    use std::io;
+    use std::mem;
+    use std::time::Duration;
+
+    /// A socket the OS writes to whenever our links or addresses change.
+    pub struct Notifier {
+        fd: libc::c_int,
+    }
+
+    impl Notifier {
+        /// Open the socket, each `wait` on it blocking for up to `timeout`.
+        #[allow(unsafe_code)]
+        #[allow(trivial_casts)]
+        pub fn new(timeout: Duration) -> io::Result<Notifier> {
+            let notifier = Notifier { fd: open()? };
+
+            let tv = libc::timeval {
+                tv_sec: timeout.as_secs() as libc::time_t,
+                tv_usec: (timeout.subsec_nanos() / 1000) as libc::suseconds_t,
+            };
+            let tv_ptr: *const libc::timeval = &tv;
+            let res = unsafe {
+                libc::setsockopt(notifier.fd,
+                                 libc::SOL_SOCKET,
+                                 libc::SO_RCVTIMEO,
+                                 tv_ptr as *const libc::c_void,
+                                 mem::size_of::<libc::timeval>() as libc::socklen_t)
+            };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(notifier)
+        }
+
+        /// Whether anything happened before the timeout. The message itself is not looked into -
+        /// it is cheaper to just read the interfaces again.
+        #[allow(unsafe_code)]
+        pub fn wait(&self) -> io::Result<bool> {
+            let mut buf = [0u8; 4096];
+            let n = unsafe {
+                libc::recv(self.fd,
+                           buf.as_mut_ptr() as *mut libc::c_void,
+                           buf.len(),
+                           0)
+            };
+            if n >= 0 {
+                return Ok(n > 0);
+            }
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                io::ErrorKind::WouldBlock |
+                io::ErrorKind::TimedOut |
+                io::ErrorKind::Interrupted => Ok(false),
+                _ => Err(e),
+            }
+        }
+    }
+
+    impl Drop for Notifier {
+        #[allow(unsafe_code)]
+        fn drop(&mut self) {
+            let _ = unsafe { libc::close(self.fd) };
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[allow(unsafe_code)]
+    #[allow(trivial_casts)]
+    fn open() -> io::Result<libc::c_int> {
+        const NETLINK_ROUTE: libc::c_int = 0;
+        const RTMGRP_LINK: u32 = 0x1;
+        const RTMGRP_IPV4_IFADDR: u32 = 0x10;
+        const RTMGRP_IPV6_IFADDR: u32 = 0x100;
+
+        #[repr(C)]
+        struct SockaddrNl {
+            nl_family: libc::sa_family_t,
+            nl_pad: libc::c_ushort,
+            nl_pid: u32,
+            nl_groups: u32,
+        }
+
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW, NETLINK_ROUTE) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let addr = SockaddrNl {
+            nl_family: libc::AF_NETLINK as libc::sa_family_t,
+            nl_pad: 0,
+            nl_pid: 0,
+            nl_groups: RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV6_IFADDR,
+        };
+        let addr_ptr: *const SockaddrNl = &addr;
+        let res = unsafe {
+            libc::bind(fd,
+                       addr_ptr as *const libc::sockaddr,
+                       mem::size_of::<SockaddrNl>() as libc::socklen_t)
+        };
+        if res < 0 {
+            let e = io::Error::last_os_error();
+            let _ = unsafe { libc::close(fd) };
+            return Err(e);
+        }
+
+        Ok(fd)
+    }
+
+    // A routing socket receives every change to routes, links and addresses unasked.
+    #[cfg(target_os = "macos")]
+    #[allow(unsafe_code)]
+    fn open() -> io::Result<libc::c_int> {
+        const PF_ROUTE: libc::c_int = 17;
+
+        let fd = unsafe { libc::socket(PF_ROUTE, libc::SOCK_RAW, libc::AF_UNSPEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(fd)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod notifier {
+    use std::io;
+    use std::time::Duration;
+
+    /// The OS cannot notify us here, so the interfaces are polled instead.
+    pub struct Notifier;
+
+    impl Notifier {
+        pub fn new(_timeout: Duration) -> io::Result<Notifier> {
+            Err(io::Error::new(io::ErrorKind::Other,
+                               "Interface change notifications not supported on this platform"))
+        }
+
+        pub fn wait(&self) -> io::Result<bool> {
+            Ok(true)
+        }
+    }
+}
@@ -0,0 +1,285 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{Core, CoreMessage, CoreTimer, State, Timeout, lock};
+use igd::{Gateway, PortMappingProtocol};
+use maidsafe_utilities::thread;
+use mio::{Poll, Token};
+use nat::{MappingContext, NatError, util};
+use nat::nat_pmp::NatPmpGateway;
+use nat::pcp::{Nonce, PcpGateway};
+use std::any::Any;
+use std::cell::RefCell;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Lease requested for router port mappings. Routers have been seen to silently drop mappings
+/// asked for indefinitely, so a finite lease is asked for and renewed well before it runs out.
+pub const LEASE_SEC: u32 = 3600;
+
+/// Description given to our IGD port mappings.
+pub const IGD_DESCRIPTION: &'static str = "MaidSafeNat";
+
+const RENEWAL_TIMER_ID: u8 = 0;
+const RELEASE_TIMER_ID: u8 = 1;
+// How often the leases are checked for sockets which have since been closed.
+const RELEASE_CHECK_SEC: u64 = 60;
+
+/// The router a port mapping was obtained from.
+#[derive(Debug, Clone)]
+pub enum LeaseGateway {
+    /// Mapped via UPnP IGD
+    Igd(Gateway),
+    /// Mapped via NAT-PMP
+    NatPmp(NatPmpGateway),
+    /// Mapped via PCP, along with the nonce the mapping was made with
+    Pcp(PcpGateway, Nonce),
+}
+
+impl LeaseGateway {
+    /// Address of the router's mapping service.
+    pub fn addr(&self) -> SocketAddrV4 {
+        match *self {
+            LeaseGateway::Igd(ref gateway) => gateway.addr,
+            LeaseGateway::NatPmp(ref gateway) => gateway.addr,
+            LeaseGateway::Pcp(ref gateway, _) => gateway.addr,
+        }
+    }
+}
+
+/// A router port mapping held on our behalf.
+#[derive(Debug, Clone)]
+pub struct Lease {
+    /// Router which forwards the port
+    pub gateway: LeaseGateway,
+    /// Protocol forwarded
+    pub protocol: PortMappingProtocol,
+    /// Where the traffic is forwarded to
+    pub local_addr: SocketAddrV4,
+    /// What the world sees `local_addr` as
+    pub ext_addr: SocketAddr,
+}
+
+impl Lease {
+    /// Extend the lease, returning whether the router still forwards the same external address.
+    pub fn renew(&self) -> bool {
+        match (&self.gateway, self.ext_addr) {
+            (&LeaseGateway::Igd(ref gateway), SocketAddr::V4(ext_addr)) => {
+                gateway
+                    .add_port(self.protocol,
+                              ext_addr.port(),
+                              self.local_addr,
+                              LEASE_SEC,
+                              IGD_DESCRIPTION)
+                    .is_ok()
+            }
+            (&LeaseGateway::NatPmp(ref gateway), SocketAddr::V4(ext_addr)) => {
+                gateway
+                    .renew(self.protocol, self.local_addr, ext_addr, LEASE_SEC)
+                    .ok() == Some(ext_addr)
+            }
+            (&LeaseGateway::Pcp(ref gateway, ref nonce), ext_addr) => {
+                gateway
+                    .get_any_address(self.protocol, self.local_addr, LEASE_SEC, nonce)
+                    .ok() == Some(ext_addr)
+            }
+            (_, SocketAddr::V6(_)) => false,
+        }
+    }
+
+    /// Whether a socket of ours still holds the local port, else the router forwards it in vain.
+    pub fn is_held(&self) -> bool {
+        match self.protocol {
+            PortMappingProtocol::TCP => !util::tcp_port_is_free(self.local_addr.port()),
+            PortMappingProtocol::UDP => !util::udp_port_is_free(self.local_addr.port()),
+        }
+    }
+
+    /// Ask the router to stop forwarding.
+    pub fn delete(&self) {
+        let res = match self.gateway {
+            LeaseGateway::Igd(ref gateway) => {
+                gateway
+                    .remove_port(self.protocol, self.ext_addr.port())
+                    .map_err(|e| format!("{:?}", e))
+            }
+            LeaseGateway::NatPmp(ref gateway) => {
+                gateway
+                    .delete_mapping(self.protocol, self.local_addr)
+                    .map_err(|e| format!("{:?}", e))
+            }
+            LeaseGateway::Pcp(ref gateway, ref nonce) => {
+                gateway
+                    .delete_mapping(self.protocol, self.local_addr, nonce)
+                    .map_err(|e| format!("{:?}", e))
+            }
+        };
+        if let Err(e) = res {
+            debug!("Could not delete the port mapping for {}: {}", self.ext_addr, e);
+        }
+    }
+}
+
+/// The router port mappings currently held, shared by every user of a `MappingContext`.
+pub type Leases = Arc<Mutex<Vec<Lease>>>;
+
+/// Called with the external address of each mapping that could not be renewed.
+pub type LostHandler = Box<FnMut(&mut Core, &Poll, SocketAddr)>;
+
+/// A state which periodically renews every lease recorded in a `MappingContext`, forgetting and
+/// reporting those the router would not renew. Leases whose socket has been closed are released
+/// within `RELEASE_CHECK_SEC`.
+pub struct LeaseRenewal {
+    token: Token,
+    leases: Leases,
+    timeout: Timeout,
+    release_timeout: Timeout,
+    on_lost: LostHandler,
+}
+
+impl LeaseRenewal {
+    /// Start renewing the leases of `mc` until terminated.
+    pub fn start(core: &mut Core,
+                 mc: &MappingContext,
+                 on_lost: LostHandler)
+                 -> Result<Token, NatError> {
+        let token = core.get_new_token();
+        let state = LeaseRenewal {
+            token: token,
+            leases: mc.leases(),
+            timeout: core.set_timeout(renewal_interval(),
+                                      CoreTimer::new(token, RENEWAL_TIMER_ID))?,
+            release_timeout: core.set_timeout(Duration::from_secs(RELEASE_CHECK_SEC),
+                                              CoreTimer::new(token, RELEASE_TIMER_ID))?,
+            on_lost: on_lost,
+        };
+
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+
+        Ok(token)
+    }
+
+    fn renew(&self, core: &Core) {
+        let leases = lock(&self.leases).clone();
+        if leases.is_empty() {
+            return;
+        }
+
+        let token = self.token;
+        let tx = core.sender().clone();
+        let _ = thread::named("Lease-Renewal", move || {
+            let lost: Vec<_> = leases
+                .into_iter()
+                .filter(|lease| !lease.renew())
+                .map(|lease| lease.ext_addr)
+                .collect();
+            if lost.is_empty() {
+                return;
+            }
+            let _ = tx.send(CoreMessage::new(move |core, poll| {
+                let state = match core.get_state(token) {
+                    Some(state) => state,
+                    None => return,
+                };
+                let mut state = state.borrow_mut();
+                if let Some(renewal) = state.as_any().downcast_mut::<LeaseRenewal>() {
+                    renewal.handle_lost(core, poll, lost);
+                }
+            }));
+        });
+    }
+
+    // Have the routers stop forwarding the ports of the sockets closed since the last check.
+    fn release(&self) {
+        let released: Vec<_> = {
+            let mut leases = lock(&self.leases);
+            let (held, released) = leases.drain(..).partition(|lease| lease.is_held());
+            *leases = held;
+            released
+        };
+        if released.is_empty() {
+            return;
+        }
+
+        let _ = thread::named("Lease-Release", move || for lease in released {
+            debug!("Releasing the mapping for {} as its socket is closed", lease.ext_addr);
+            lease.delete();
+        });
+    }
+
+    fn handle_lost(&mut self, core: &mut Core, poll: &Poll, lost: Vec<SocketAddr>) {
+        lock(&self.leases).retain(|lease| !lost.contains(&lease.ext_addr));
+        for ext_addr in lost {
+            debug!("Router would not renew the mapping for {}", ext_addr);
+            (*self.on_lost)(core, poll, ext_addr);
+        }
+    }
+}
+
+impl State for LeaseRenewal {
+    fn name(&self) -> &'static str {
+        "LeaseRenewal"
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, timer_id: u8) {
+        if timer_id == RELEASE_TIMER_ID {
+            let timer = CoreTimer::new(self.token, RELEASE_TIMER_ID);
+            match core.set_timeout(Duration::from_secs(RELEASE_CHECK_SEC), timer) {
+                Ok(timeout) => self.release_timeout = timeout,
+                Err(e) => {
+                    debug!("Could not schedule lease release: {:?}", e);
+                    return self.terminate(core, poll);
+                }
+            }
+            if !core.is_paused() {
+                self.release();
+            }
+            return;
+        }
+
+        match core.set_timeout(renewal_interval(),
+                               CoreTimer::new(self.token, RENEWAL_TIMER_ID)) {
+            Ok(timeout) => self.timeout = timeout,
+            Err(e) => {
+                debug!("Could not schedule lease renewal: {:?}", e);
+                return self.terminate(core, poll);
+            }
+        }
+        // Leases that run out meanwhile are simply asked for again by the next renewal
+        if !core.is_paused() {
+            self.release();
+            self.renew(core);
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core, _poll: &Poll) {
+        let _ = core.cancel_timeout(&self.timeout);
+        let _ = core.cancel_timeout(&self.release_timeout);
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+// Renew at half time so a lost or slow request still leaves time for the next attempt.
+fn renewal_interval() -> Duration {
+    Duration::from_secs(LEASE_SEC as u64 / 2)
+}
@@ -0,0 +1,181 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use nat::util;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+
+/// Where a candidate address of a mapped socket was learnt from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MappedAddrSource {
+    /// One of our own interfaces
+    Local,
+    /// Forwarded to us by an IGD, NAT-PMP or PCP gateway
+    Router,
+    /// Reported by a peer's echo service
+    Stun,
+    /// Declared in the config, e.g. behind a static 1:1 NAT or a port forwarded by hand
+    Configured,
+}
+
+/// A candidate address at which peers may be able to reach a mapped socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MappedAddr {
+    /// The address itself
+    pub addr: SocketAddr,
+    /// Where it was learnt from
+    pub source: MappedAddrSource,
+    /// Whether a peer has managed to connect to us on it. Only listening sockets can be verified,
+    /// so this is otherwise always false.
+    pub verified: bool,
+    /// Whether only peers we have sent to first can reach us on it, as through a NAT filtering
+    /// what comes in by where we sent to, so that it takes hole punching. Addresses reported by a
+    /// peer's echo service are taken to be until verified, while those forwarded to us by a router
+    /// - over IGD, NAT-PMP or PCP alike - or declared in the config are open to anyone.
+    pub nat_restricted: bool,
+}
+
+impl MappedAddr {
+    /// Candidate `addr` learnt from `source`.
+    pub fn new(addr: SocketAddr, source: MappedAddrSource) -> Self {
+        MappedAddr {
+            addr: addr,
+            source: source,
+            verified: false,
+            nat_restricted: source == MappedAddrSource::Stun,
+        }
+    }
+
+    // Lower is better: declared in the config, then global, then forwarded by a router (e.g.
+    // behind a second NAT), then private, then loopback.
+    fn rank(&self) -> u8 {
+        let ip = self.addr.ip();
+        if self.source == MappedAddrSource::Configured {
+            0
+        } else if util::ip_addr_is_global(&ip) {
+            1
+        } else if self.source == MappedAddrSource::Router {
+            2
+        } else if !ip.is_loopback() {
+            3
+        } else {
+            4
+        }
+    }
+}
+
+/// The addresses of `declared` a socket bound to `port` can be reached at, taken as verified.
+/// Those with a port of 0 stand for `port` itself, as behind a static 1:1 NAT. Those with a port
+/// of their own are only taken if `forwarded`, as only a listener has ports forwarded to it.
+pub fn declared_addrs(declared: &[SocketAddr], port: u16, forwarded: bool) -> Vec<MappedAddr> {
+    declared
+        .iter()
+        .filter(|addr| addr.port() == 0 || forwarded)
+        .map(|addr| {
+                 let mut addr = *addr;
+                 if addr.port() == 0 {
+                     addr.set_port(port);
+                 }
+                 MappedAddr {
+                     addr: addr,
+                     source: MappedAddrSource::Configured,
+                     verified: true,
+                     nat_restricted: false,
+                 }
+             })
+        .collect()
+}
+
+/// Order `addrs` best candidate first, dropping repeats of the same address. Loopback addresses
+/// are dropped too unless `keep_loopback` is set or there is nothing else to offer.
+pub fn rank(mut addrs: Vec<MappedAddr>, keep_loopback: bool) -> Vec<MappedAddr> {
+    // The sort is stable, so equally ranked addresses keep the order they were found in.
+    addrs.sort_by_key(MappedAddr::rank);
+    let mut seen = HashSet::with_capacity(addrs.len());
+    addrs.retain(|mapped| seen.insert(mapped.addr));
+    if !keep_loopback && addrs.iter().any(|mapped| !mapped.addr.ip().is_loopback()) {
+        addrs.retain(|mapped| !mapped.addr.ip().is_loopback());
+    }
+    addrs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranks_and_dedups() {
+        let loopback = unwrap!("127.0.0.1:5483".parse());
+        let private = unwrap!("192.168.0.2:5483".parse());
+        let double_nat = unwrap!("10.0.0.2:5483".parse());
+        let global = unwrap!("8.8.8.8:5483".parse());
+
+        let addrs = vec![MappedAddr::new(loopback, MappedAddrSource::Local),
+                         MappedAddr::new(private, MappedAddrSource::Local),
+                         MappedAddr::new(double_nat, MappedAddrSource::Router),
+                         MappedAddr::new(global, MappedAddrSource::Stun),
+                         MappedAddr::new(global, MappedAddrSource::Router),
+                         MappedAddr::new(global, MappedAddrSource::Stun)];
+
+        let ranked = |addrs: Vec<MappedAddr>, keep_loopback: bool| -> Vec<SocketAddr> {
+            rank(addrs, keep_loopback)
+                .into_iter()
+                .map(|mapped| mapped.addr)
+                .collect()
+        };
+
+        assert_eq!(ranked(addrs.clone(), true),
+                   vec![global, double_nat, private, loopback]);
+        assert_eq!(ranked(addrs, false), vec![global, double_nat, private]);
+
+        let only_loopback = vec![MappedAddr::new(loopback, MappedAddrSource::Local)];
+        assert_eq!(ranked(only_loopback, false), vec![loopback]);
+
+        let static_nat = unwrap!("203.0.113.1:5483".parse());
+        let mut addrs = vec![MappedAddr::new(global, MappedAddrSource::Stun)];
+        addrs.extend(declared_addrs(&[unwrap!("203.0.113.1:0".parse()), global], 5483, false));
+        assert_eq!(ranked(addrs, false), vec![static_nat, global]);
+    }
+
+    #[test]
+    fn nat_restricted() {
+        let global = unwrap!("8.8.8.8:5483".parse());
+        assert!(MappedAddr::new(global, MappedAddrSource::Stun).nat_restricted);
+        assert!(!MappedAddr::new(global, MappedAddrSource::Router).nat_restricted);
+        assert!(!MappedAddr::new(global, MappedAddrSource::Configured).nat_restricted);
+        assert!(!MappedAddr::new(global, MappedAddrSource::Local).nat_restricted);
+    }
+
+    #[test]
+    fn declared() {
+        let one_to_one = unwrap!("203.0.113.1:0".parse());
+        let forwarded = unwrap!("203.0.113.2:6000".parse());
+
+        let for_socket = declared_addrs(&[one_to_one, forwarded], 5483, false);
+        assert_eq!(for_socket.len(), 1);
+        assert_eq!(for_socket[0].addr, unwrap!("203.0.113.1:5483".parse()));
+        assert_eq!(for_socket[0].source, MappedAddrSource::Configured);
+        assert!(for_socket[0].verified);
+        assert!(!for_socket[0].nat_restricted);
+
+        let for_listener: Vec<_> = declared_addrs(&[one_to_one, forwarded], 5483, true)
+            .into_iter()
+            .map(|mapped| mapped.addr)
+            .collect();
+        assert_eq!(for_listener, vec![unwrap!("203.0.113.1:5483".parse()), forwarded]);
+    }
+}
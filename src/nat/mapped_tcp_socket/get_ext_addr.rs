@@ -15,21 +15,38 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-use common::{Core, Message, Priority, Socket, State};
+use common::{Core, CoreTimer, Message, Priority, Socket, SocketConfig, State, Timeout};
 use mio::{Poll, PollOpt, Ready, Token};
 use mio::tcp::TcpStream;
-use nat::{NatError, util};
+use nat::{MappingConfig, NatError, util};
+use nat::peer_stuns::PeerStuns;
+use nat::stats::StatsRecorder;
+use net2::TcpStreamExt;
 use std::any::Any;
 use std::cell::RefCell;
 use std::net::SocketAddr;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const QUERY_TIMER_ID: u8 = 0;
+const RETRY_TIMER_ID: u8 = QUERY_TIMER_ID + 1;
 
 pub type Finish = Box<FnMut(&mut Core, &Poll, Token, Result<SocketAddr, ()>)>;
 
 pub struct GetExtAddr {
     token: Token,
-    socket: Socket,
+    local_addr: SocketAddr,
+    socket_config: SocketConfig,
+    peer_stun: SocketAddr,
+    socket: Option<Socket>,
     request: Option<(Message, Priority)>,
+    query_timeout: Duration,
+    retries_left: u32,
+    backoff: Duration,
+    timeout: Option<Timeout>,
+    sent_at: Instant,
+    stats: StatsRecorder,
+    health: PeerStuns,
     finish: Finish,
 }
 
@@ -38,58 +55,147 @@ impl GetExtAddr {
                  poll: &Poll,
                  local_addr: SocketAddr,
                  peer_stun: &SocketAddr,
+                 config: &MappingConfig,
+                 stats: StatsRecorder,
+                 health: PeerStuns,
                  finish: Finish)
                  -> Result<Token, NatError> {
-        let query_socket = util::new_reusably_bound_tcp_socket(&local_addr)?;
-        let query_socket = query_socket.to_tcp_stream()?;
-        let socket = TcpStream::connect_stream(query_socket, peer_stun)?;
-
-        let socket = Socket::wrap(socket);
         let token = core.get_new_token();
-
-        let state = GetExtAddr {
+        let mut state = GetExtAddr {
             token: token,
-            socket: socket,
-            request: Some((Message::EchoAddrReq, 0)),
+            local_addr: local_addr,
+            socket_config: config.socket,
+            peer_stun: util::to_family_of(&local_addr, peer_stun),
+            socket: None,
+            request: None,
+            query_timeout: config.stun_attempt_timeout(),
+            retries_left: config.query_retries,
+            backoff: config.retry_backoff,
+            timeout: None,
+            sent_at: Instant::now(),
+            stats: stats,
+            health: health,
             finish: finish,
         };
-
-        poll.register(&state.socket,
-                      token,
-                      Ready::error() | Ready::hup() | Ready::writable(),
-                      PollOpt::edge())?;
+        state.query(core, poll)?;
 
         let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
 
         Ok(token)
     }
 
+    fn query(&mut self, core: &mut Core, poll: &Poll) -> Result<(), NatError> {
+        let query_socket = util::new_reusably_bound_tcp_socket(&self.local_addr,
+                                                                 &self.socket_config)?;
+        let query_socket = query_socket.to_tcp_stream()?;
+        // Closed with a reset rather than left in TIME_WAIT, which would keep a retry from the
+        // same port to the same peer from connecting.
+        query_socket.set_linger(Some(Duration::from_secs(0)))?;
+        let socket = Socket::wrap(TcpStream::connect_stream(query_socket, &self.peer_stun)?);
+
+        poll.register(&socket,
+                      self.token,
+                      Ready::error() | Ready::hup() | Ready::writable(),
+                      PollOpt::edge())?;
+        // A peer which does not answer in time is given up on without holding up the others.
+        self.timeout = Some(core.set_timeout(self.query_timeout,
+                                             CoreTimer::new(self.token, QUERY_TIMER_ID))?);
+        self.socket = Some(socket);
+        self.request = Some((Message::EchoAddrReq, 0));
+        self.sent_at = Instant::now();
+
+        Ok(())
+    }
+
     fn write(&mut self, core: &mut Core, poll: &Poll, msg: Option<(Message, Priority)>) {
-        if self.socket.write(poll, self.token, msg).is_err() {
+        let res = match self.socket {
+            Some(ref mut socket) => socket.write(poll, self.token, msg),
+            None => return,
+        };
+        if res.is_err() {
             self.handle_error(core, poll);
         }
     }
 
     fn receive_response(&mut self, core: &mut Core, poll: &Poll) {
-        match self.socket.read::<Message>() {
+        let res = match self.socket {
+            Some(ref mut socket) => socket.read::<Message>(),
+            None => return,
+        };
+        match res {
             Ok(Some(Message::EchoAddrResp(ext_addr))) => {
-                self.terminate(core, poll);
+                self.stats.record_stun(self.peer_stun, Some(self.sent_at.elapsed()));
+                self.health.record(&self.peer_stun, true);
+                self.stop(core, poll);
                 let token = self.token;
-                (*self.finish)(core, poll, token, Ok(ext_addr))
+                (*self.finish)(core, poll, token, Ok(util::unmap_ipv4(&ext_addr)))
             }
             Ok(None) => (),
             Ok(Some(_)) | Err(_) => self.handle_error(core, poll),
         }
     }
 
+    // Drop the failed attempt and, unless out of retries, schedule another with double the wait
+    // of the previous one - a helper which is merely busy or lost a packet is worth asking again.
     fn handle_error(&mut self, core: &mut Core, poll: &Poll) {
-        self.terminate(core, poll);
+        self.close(core, poll);
+        self.stats.record_stun(self.peer_stun, None);
+
+        if self.retries_left > 0 {
+            self.retries_left -= 1;
+            match core.set_timeout(self.backoff, CoreTimer::new(self.token, RETRY_TIMER_ID)) {
+                Ok(timeout) => {
+                    self.timeout = Some(timeout);
+                    self.backoff = self.backoff * 2;
+                    return;
+                }
+                Err(e) => debug!("Could not schedule a retry of peer stun query: {:?}", e),
+            }
+        }
+
+        self.health.record(&self.peer_stun, false);
+        self.stop(core, poll);
         let token = self.token;
         (*self.finish)(core, poll, token, Err(()));
     }
+
+    fn retry(&mut self, core: &mut Core, poll: &Poll) {
+        trace!("Retrying peer stun query to {}", self.peer_stun);
+        if let Err(e) = self.query(core, poll) {
+            debug!("Could not retry peer stun query: {:?}", e);
+            self.handle_error(core, poll);
+        }
+    }
+
+    /// Count the peer as having failed to answer in time, as it was given up on while in flight.
+    pub fn give_up(&mut self, core: &mut Core, poll: &Poll) {
+        if self.socket.is_some() {
+            self.stats.record_stun(self.peer_stun, None);
+        }
+        self.health.record(&self.peer_stun, false);
+        self.stop(core, poll);
+    }
+
+    fn stop(&mut self, core: &mut Core, poll: &Poll) {
+        self.close(core, poll);
+        let _ = core.remove_state(self.token);
+    }
+
+    fn close(&mut self, core: &mut Core, poll: &Poll) {
+        if let Some(timeout) = self.timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
+        if let Some(socket) = self.socket.take() {
+            let _ = poll.deregister(&socket);
+        }
+    }
 }
 
 impl State for GetExtAddr {
+    fn name(&self) -> &'static str {
+        "GetExtAddr"
+    }
+
     fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
         if kind.is_error() || kind.is_hup() {
             self.handle_error(core, poll);
@@ -104,9 +210,21 @@ impl State for GetExtAddr {
         }
     }
 
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, timer_id: u8) {
+        self.timeout = None;
+        match timer_id {
+            RETRY_TIMER_ID => self.retry(core, poll),
+            _ => {
+                trace!("Peer stun {} did not answer in time", self.peer_stun);
+                self.handle_error(core, poll);
+            }
+        }
+    }
+
+    // Only called from outside, i.e. when the query is no longer needed, which says nothing of
+    // the peer. See `give_up` for when it took too long.
     fn terminate(&mut self, core: &mut Core, poll: &Poll) {
-        let _ = core.remove_state(self.token);
-        let _ = poll.deregister(&self.socket);
+        self.stop(core, poll);
     }
 
     fn as_any(&mut self) -> &mut Any {
@@ -16,127 +16,388 @@
 // relating to use of the SAFE Network Software.
 
 use self::get_ext_addr::GetExtAddr;
-use common::{Core, CoreMessage, CoreTimer, State};
+use common::{Core, CoreMessage, CoreTimer, SocketConfig, State, Timeout, TraceState};
 use igd::PortMappingProtocol;
-use maidsafe_utilities::thread;
 use mio::{Poll, Token};
-use mio::timer::Timeout;
-use nat::{MappingContext, NatError, util};
+use mio::channel::Sender;
+use nat::{MappingConfig, MappingContext, MappingEvent, MappingId, MappingObserver, NatError,
+          Router, mapped_addr, port_mapping, util};
+use nat::ext_addr_cache::{ExtAddrCache, Lookup};
+use nat::mapped_addr::{MappedAddr, MappedAddrSource};
+use nat::peer_stuns::PeerStuns;
+use nat::stats::StatsRecorder;
 use net2::TcpBuilder;
 use std::any::Any;
 use std::cell::RefCell;
 use std::collections::HashSet;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::Instant;
 
 mod get_ext_addr;
 
-const TIMEOUT_SEC: u64 = 3;
+const TIMEOUT_TIMER_ID: u8 = 0;
+const ROUTER_TIMER_ID: u8 = TIMEOUT_TIMER_ID + 1;
+const STUN_TIMER_ID: u8 = ROUTER_TIMER_ID + 1;
+
+/// Result of mapping a tcp socket: the socket along with the addresses it may be reachable at,
+/// best first. None of them are verified yet - see `VerifyReachability` - other than those
+/// declared rather than discovered.
+pub type MappingResult = Result<(TcpBuilder, Vec<MappedAddr>), NatError>;
+
+/// The local ports a socket may be bound to, tried in order until one is free. Port 0 lets the OS
+/// pick an ephemeral port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    /// First port to try
+    pub first: u16,
+    /// Last port to try, inclusive
+    pub last: u16,
+}
+
+impl PortRange {
+    /// Whether the OS is left to pick the port.
+    pub fn is_ephemeral(&self) -> bool {
+        self.first == 0 && self.last == 0
+    }
+
+    fn bind(&self, dual_stack: bool, config: &SocketConfig) -> io::Result<TcpBuilder> {
+        let mut port = self.first;
+        loop {
+            // Our sockets allow reuse, so binding one to a port another holds would succeed
+            if self.first < self.last && !util::tcp_port_is_free(port) {
+                if port == self.last {
+                    return Err(io::Error::new(io::ErrorKind::AddrInUse, "Every port is taken"));
+                }
+                trace!("Port {} is taken, trying the next one", port);
+                port += 1;
+                continue;
+            }
+            let res = if dual_stack {
+                util::new_reusably_bound_dual_stack_tcp_socket(port, config)
+            } else {
+                let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+                util::new_reusably_bound_tcp_socket(&addr, config)
+            };
+            match res {
+                Err(ref e) if e.kind() == io::ErrorKind::AddrInUse && port < self.last => {
+                    trace!("Port {} is taken, trying the next one", port);
+                    port += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl From<u16> for PortRange {
+    fn from(port: u16) -> PortRange {
+        PortRange {
+            first: port,
+            last: port,
+        }
+    }
+}
+
+/// Lets an in-progress `MappedTcpSocket` be aborted from any thread.
+#[derive(Clone)]
+pub struct MappingHandle {
+    token: Token,
+    tx: Sender<CoreMessage>,
+    cancel: fn(&mut Core, &Poll, Token),
+}
+
+impl MappingHandle {
+    /// Stop the mapping, its finish callback being called with `NatError::Cancelled`. Does
+    /// nothing if the mapping has finished already.
+    pub fn cancel(&self) {
+        let token = self.token;
+        let cancel = self.cancel;
+        let _ = self.tx.send(CoreMessage::new(move |core, poll| cancel(core, poll, token)));
+    }
+}
 
 /// A state which represents the in-progress mapping of a tcp socket.
 pub struct MappedTcpSocket<F> {
     token: Token,
     socket: Option<TcpBuilder>,
-    igd_children: usize,
+    router_children: usize,
+    igd_children: Vec<Token>,
     stun_children: HashSet<Token>,
-    mapped_addrs: Vec<SocketAddr>,
+    mapped_addrs: Vec<MappedAddr>,
     timeout: Timeout,
+    router_timeout: Option<Timeout>,
+    stun_timeout: Option<Timeout>,
+    config: MappingConfig,
+    local_addr: SocketAddr,
+    peer_stuns: Vec<SocketAddr>,
+    ext_addr_cache: ExtAddrCache,
+    resolving: bool,
+    awaiting_ext_addr: bool,
+    started_at: Instant,
+    stats: StatsRecorder,
+    health: PeerStuns,
+    observer: MappingObserver,
     finish: Option<F>,
 }
 
 impl<F> MappedTcpSocket<F>
-    where F: FnOnce(&mut Core, &Poll, TcpBuilder, Vec<SocketAddr>) + Any
+    where F: FnOnce(&mut Core, &Poll, MappingResult) + Any
 {
-    /// Start mapping a tcp socket
-    pub fn start(core: &mut Core,
-                 poll: &Poll,
-                 port: u16,
-                 mc: &MappingContext,
-                 finish: F)
-                 -> Result<(), NatError> {
+    /// Start mapping a tcp socket bound to the first free port of `ports`
+    pub fn start<P: Into<PortRange>>(core: &mut Core,
+                                     poll: &Poll,
+                                     ports: P,
+                                     mc: &MappingContext,
+                                     finish: F)
+                                     -> Result<MappingHandle, NatError> {
         let token = core.get_new_token();
+        let handle = MappingHandle {
+            token: token,
+            tx: core.sender().clone(),
+            cancel: MappedTcpSocket::<F>::cancel,
+        };
 
-        // TODO(Spandan) Ipv6 is not supported in Listener so dealing only with ipv4 right now
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
-
-        let socket = util::new_reusably_bound_tcp_socket(&addr)?;
+        let socket = ports
+            .into()
+            .bind(!mc.ifv6s().is_empty(), &mc.mapping_config().socket)?;
         let addr = socket.local_addr()?;
+        core.trace(token, TraceState::Mapping, "started", &[("local_addr", &addr)]);
 
-        // Ask IGD
-        let mut igd_children = 0;
-        for &(ref ip, ref gateway) in mc.ifv4s() {
-            let gateway = match *gateway {
-                Some(ref gateway) => gateway.clone(),
-                None => continue,
+        // Ask IGD, NAT-PMP and PCP
+        let router_handler = move |core: &mut Core, poll: &Poll, router, ext_addr| {
+            let state = match core.get_state(token) {
+                Some(state) => state,
+                None => return,
             };
-            let tx = core.sender().clone();
-            let addr_igd = SocketAddrV4::new(*ip, addr.port());
-            let _ = thread::named("IGD-Address-Mapping", move || {
-                let res =
-                    gateway.get_any_address(PortMappingProtocol::TCP, addr_igd, 0, "MaidSafeNat");
-                let ext_addr = match res {
-                    Ok(ext_addr) => ext_addr,
-                    Err(_) => return,
-                };
-                let _ = tx.send(CoreMessage::new(move |core, poll| {
-                    let state = match core.get_state(token) {
-                        Some(state) => state,
-                        None => return,
-                    };
-
-                    let mut state = state.borrow_mut();
-                    let mapping_tcp_sock =
-                        match state.as_any().downcast_mut::<MappedTcpSocket<F>>() {
-                            Some(mapping_sock) => mapping_sock,
-                            None => return,
-                        };
-                    mapping_tcp_sock.handle_igd_resp(core, poll, SocketAddr::V4(ext_addr));
-                }));
-            });
-            igd_children += 1;
-        }
-
-        let mapped_addrs = mc.ifv4s()
+
+            let mut state = state.borrow_mut();
+            let mapping_sock = match state.as_any().downcast_mut::<MappedTcpSocket<F>>() {
+                Some(mapping_sock) => mapping_sock,
+                None => return,
+            };
+            mapping_sock.handle_router_resp(core, poll, router, ext_addr);
+        };
+        let config = *mc.mapping_config();
+        let (router_children, igd_children) = if config.discover {
+            port_mapping::request_router_mappings(core,
+                                                  poll,
+                                                  mc,
+                                                  PortMappingProtocol::TCP,
+                                                  addr.port(),
+                                                  router_handler)
+        } else {
+            (0, Vec::new())
+        };
+
+        let ext_addr_cache = mc.ext_addr_cache();
+
+        let mut mapped_addrs: Vec<_> = mc.ifv4s()
             .iter()
-            .map(|&(ip, _)| SocketAddr::new(IpAddr::V4(ip), addr.port()))
+            .map(|&ip| SocketAddr::new(IpAddr::V4(ip), addr.port()))
+            .map(|addr| MappedAddr::new(addr, MappedAddrSource::Local))
             .collect();
+        if addr.is_ipv6() {
+            mapped_addrs.extend(mc.ifv6s()
+                                    .iter()
+                                    .map(|&ip| SocketAddr::new(IpAddr::V6(ip), addr.port()))
+                                    .map(|addr| MappedAddr::new(addr, MappedAddrSource::Local)));
+        }
+        mapped_addrs.extend(mapped_addr::declared_addrs(mc.declared_ext_addrs(),
+                                                        addr.port(),
+                                                        false));
+
+        let timeout = core.set_timeout(config.timeout, CoreTimer::new(token, TIMEOUT_TIMER_ID))?;
+        let router_timeout = if router_children > 0 {
+            Some(core.set_timeout(config.router_timeout,
+                                  CoreTimer::new(token, ROUTER_TIMER_ID))?)
+        } else {
+            None
+        };
+
+        // Don't bother the peers if they have told us our external IP recently, or are about to
+        // tell another mapping, or if we are not to ask them at all.
+        let lookup = if config.discover && config.query_stun {
+            ext_addr_cache.lookup(core,
+                                  config.ext_addr_ttl,
+                                  token,
+                                  MappedTcpSocket::<F>::notify_ext_addr)
+        } else {
+            Lookup::Cached(Vec::new())
+        };
+        let (mut resolving, mut awaiting_ext_addr) = (false, false);
+        let all_peer_stuns = mc.peer_stuns();
+        let peer_stuns: &[SocketAddr] = match lookup {
+            Lookup::Cached(ext_ips) => {
+                mapped_addrs.extend(ext_ips
+                                        .into_iter()
+                                        .map(|ip| SocketAddr::new(ip, addr.port()))
+                                        .map(|addr| MappedAddr::new(addr, MappedAddrSource::Stun)));
+                &[]
+            }
+            Lookup::Pending => {
+                awaiting_ext_addr = true;
+                &[]
+            }
+            Lookup::Resolve => {
+                resolving = true;
+                &all_peer_stuns
+            }
+            Lookup::Query => &all_peer_stuns,
+        };
+
+        let stun_timeout = if !peer_stuns.is_empty() || awaiting_ext_addr {
+            match core.set_timeout(config.stun_timeout, CoreTimer::new(token, STUN_TIMER_ID)) {
+                Ok(stun_timeout) => Some(stun_timeout),
+                Err(e) => {
+                    // Don't leave the other mappings waiting on us
+                    if resolving {
+                        ext_addr_cache.resolved(config.ext_addr_ttl);
+                    }
+                    return Err(From::from(e));
+                }
+            }
+        } else {
+            None
+        };
 
         let state =
             Rc::new(RefCell::new(MappedTcpSocket {
                                      token: token,
                                      socket: Some(socket),
+                                     router_children: router_children,
                                      igd_children: igd_children,
-                                     stun_children: HashSet::with_capacity(mc.peer_stuns().len()),
+                                     stun_children: HashSet::with_capacity(peer_stuns.len()),
                                      mapped_addrs: mapped_addrs,
-                                     timeout: core.set_timeout(Duration::from_secs(TIMEOUT_SEC),
-                                                               CoreTimer::new(token, 0))?,
+                                     timeout: timeout,
+                                     router_timeout: router_timeout,
+                                     stun_timeout: stun_timeout,
+                                     config: config,
+                                     local_addr: addr,
+                                     peer_stuns: all_peer_stuns.clone(),
+                                     ext_addr_cache: ext_addr_cache,
+                                     resolving: resolving,
+                                     awaiting_ext_addr: awaiting_ext_addr,
+                                     started_at: Instant::now(),
+                                     stats: mc.stats(),
+                                     health: mc.peer_stun_health(),
+                                     observer: mc.observer(),
                                      finish: Some(finish),
                                  }));
 
-        // Ask Stuns
-        for stun in mc.peer_stuns() {
-            let self_weak = Rc::downgrade(&state);
+        state.borrow_mut().query_stuns(core, poll, peer_stuns);
+
+        if state.borrow().is_done() {
+            state.borrow_mut().terminate(core, poll);
+            return Ok(handle);
+        }
+
+        let _ = core.insert_state(token, state);
+
+        Ok(handle)
+    }
+
+    fn cancel(core: &mut Core, poll: &Poll, token: Token) {
+        let state = match core.get_state(token) {
+            Some(state) => state,
+            None => return,
+        };
+        let mut state = state.borrow_mut();
+        if let Some(mapping_sock) = state.as_any().downcast_mut::<MappedTcpSocket<F>>() {
+            mapping_sock.stop(core, poll);
+            core.trace(token, TraceState::Mapping, "cancelled", &[]);
+            let _ = mapping_sock.socket.take();
+            (unwrap!(mapping_sock.finish.take()))(core, poll, Err(NatError::Cancelled));
+        }
+    }
+
+    fn query_stuns(&mut self, core: &mut Core, poll: &Poll, peer_stuns: &[SocketAddr]) {
+        let token = self.token;
+        for stun in peer_stuns {
             let handler = move |core: &mut Core, poll: &Poll, child_token, res| {
-                if let Some(self_rc) = self_weak.upgrade() {
-                    self_rc
-                        .borrow_mut()
-                        .handle_stun_resp(core, poll, child_token, res)
+                let state = match core.get_state(token) {
+                    Some(state) => state,
+                    None => return,
+                };
+
+                let mut state = state.borrow_mut();
+                if let Some(mapping_sock) = state.as_any().downcast_mut::<MappedTcpSocket<F>>() {
+                    mapping_sock.handle_stun_resp(core, poll, child_token, res);
                 }
             };
 
-            if let Ok(child) = GetExtAddr::start(core, poll, addr, stun, Box::new(handler)) {
-                let _ = state.borrow_mut().stun_children.insert(child);
+            if let Ok(child) = GetExtAddr::start(core,
+                                                 poll,
+                                                 self.local_addr,
+                                                 stun,
+                                                 &self.config,
+                                                 self.stats.clone(),
+                                                 self.health.clone(),
+                                                 Box::new(handler)) {
+                let _ = self.stun_children.insert(child);
             }
         }
+        // Nobody to ask, so nothing for any other mapping to wait for
+        if self.stun_children.is_empty() {
+            self.finish_resolving();
+        }
+    }
 
-        if state.borrow().stun_children.is_empty() && state.borrow().igd_children == 0 {
-            return Ok(state.borrow_mut().terminate(core, poll));
+    fn notify_ext_addr(core: &mut Core, poll: &Poll, token: Token, ext_ips: Option<Vec<IpAddr>>) {
+        let state = match core.get_state(token) {
+            Some(state) => state,
+            None => return,
+        };
+        let mut state = state.borrow_mut();
+        if let Some(mapping_sock) = state.as_any().downcast_mut::<MappedTcpSocket<F>>() {
+            mapping_sock.handle_ext_addr(core, poll, ext_ips);
         }
+    }
 
-        let _ = core.insert_state(token, state);
+    // The mapping which asked the peers is done. Either reuse the IPs it learnt or, if the NAT
+    // does not keep our port or nothing was learnt, ask the peers about this socket ourselves.
+    fn handle_ext_addr(&mut self, core: &mut Core, poll: &Poll, ext_ips: Option<Vec<IpAddr>>) {
+        if !self.awaiting_ext_addr {
+            return;
+        }
+        self.awaiting_ext_addr = false;
+        match ext_ips {
+            Some(ext_ips) => {
+                let port = self.local_addr.port();
+                self.mapped_addrs
+                    .extend(ext_ips
+                                .into_iter()
+                                .map(|ip| SocketAddr::new(ip, port))
+                                .map(|addr| MappedAddr::new(addr, MappedAddrSource::Stun)));
+            }
+            None => {
+                let peer_stuns = self.peer_stuns.clone();
+                self.query_stuns(core, poll, &peer_stuns);
+            }
+        }
+        self.maybe_terminate(core, poll);
+    }
 
-        Ok(())
+    fn finish_resolving(&mut self) {
+        if self.resolving {
+            self.resolving = false;
+            self.ext_addr_cache.resolved(self.config.ext_addr_ttl);
+        }
+    }
+
+    fn stop(&mut self, core: &mut Core, poll: &Poll) {
+        self.terminate_children(core, poll, false);
+        self.terminate_igd_children(core, poll);
+        let _ = core.remove_state(self.token);
+        let _ = core.cancel_timeout(&self.timeout);
+        if let Some(router_timeout) = self.router_timeout.take() {
+            let _ = core.cancel_timeout(&router_timeout);
+        }
+        if let Some(stun_timeout) = self.stun_timeout.take() {
+            let _ = core.cancel_timeout(&stun_timeout);
+        }
+        self.finish_resolving();
     }
 
     fn handle_stun_resp(&mut self,
@@ -146,23 +407,45 @@ impl<F> MappedTcpSocket<F>
                         res: Result<SocketAddr, ()>) {
         let _ = self.stun_children.remove(&child);
         if let Ok(our_ext_addr) = res {
-            self.mapped_addrs.push(our_ext_addr);
+            self.observer.notify(MappingEvent::StunReplied(self.token.into(), our_ext_addr));
+            self.ext_addr_cache.record(self.local_addr.port(), &our_ext_addr);
+            self.mapped_addrs.push(MappedAddr::new(our_ext_addr, MappedAddrSource::Stun));
         }
-        if self.stun_children.is_empty() && self.igd_children == 0 {
-            self.terminate(core, poll);
+        if self.stun_children.is_empty() {
+            self.finish_resolving();
+        }
+        self.maybe_terminate(core, poll);
+    }
+
+    fn handle_router_resp(&mut self,
+                          core: &mut Core,
+                          poll: &Poll,
+                          router: Router,
+                          our_ext_addr: Option<SocketAddr>) {
+        // Responses arriving after the router timeout are still welcome, but are no longer
+        // being waited for.
+        self.router_children = self.router_children.saturating_sub(1);
+        if let Some(our_ext_addr) = our_ext_addr {
+            let id = MappingId::from(self.token);
+            self.observer.notify(MappingEvent::RouterMapped(id, router, our_ext_addr));
+            self.mapped_addrs.push(MappedAddr::new(our_ext_addr, MappedAddrSource::Router));
         }
+        self.maybe_terminate(core, poll);
     }
 
-    fn handle_igd_resp(&mut self, core: &mut Core, poll: &Poll, our_ext_addr: SocketAddr) {
-        self.igd_children -= 1;
-        self.mapped_addrs.push(our_ext_addr);
-        if self.stun_children.is_empty() && self.igd_children == 0 {
+    fn is_done(&self) -> bool {
+        (self.stun_children.is_empty() && !self.awaiting_ext_addr && self.router_children == 0) ||
+        self.config.completion.is_satisfied(&self.mapped_addrs)
+    }
+
+    fn maybe_terminate(&mut self, core: &mut Core, poll: &Poll) {
+        if self.is_done() {
             self.terminate(core, poll);
         }
     }
 
-    fn terminate_children(&mut self, core: &mut Core, poll: &Poll) {
-        for token in self.stun_children.drain() {
+    fn terminate_igd_children(&mut self, core: &mut Core, poll: &Poll) {
+        for token in self.igd_children.drain(..) {
             let child = match core.get_state(token) {
                 Some(state) => state,
                 None => continue,
@@ -171,26 +454,95 @@ impl<F> MappedTcpSocket<F>
             child.borrow_mut().terminate(core, poll);
         }
     }
+
+    // `timed_out` counts the peers still being asked as having failed to answer.
+    fn terminate_children(&mut self, core: &mut Core, poll: &Poll, timed_out: bool) {
+        for token in self.stun_children.drain() {
+            let child = match core.get_state(token) {
+                Some(state) => state,
+                None => continue,
+            };
+
+            let mut child = child.borrow_mut();
+            match child.as_any().downcast_mut::<GetExtAddr>() {
+                Some(query) if timed_out => query.give_up(core, poll),
+                _ => child.terminate(core, poll),
+            }
+        }
+    }
 }
 
 impl<F> State for MappedTcpSocket<F>
-    where F: FnOnce(&mut Core, &Poll, TcpBuilder, Vec<SocketAddr>) + Any
+    where F: FnOnce(&mut Core, &Poll, MappingResult) + Any
 {
-    fn timeout(&mut self, core: &mut Core, poll: &Poll, _: u8) {
-        self.terminate(core, poll)
+    fn name(&self) -> &'static str {
+        "MappedTcpSocket"
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, timer_id: u8) {
+        match timer_id {
+            ROUTER_TIMER_ID => {
+                trace!("Gave up waiting for {} router mapping(s)", self.router_children);
+                let id = MappingId::from(self.token);
+                self.observer.notify(MappingEvent::ChildTimedOut(id, self.router_children));
+                self.router_timeout = None;
+                self.router_children = 0;
+                self.terminate_igd_children(core, poll);
+                self.maybe_terminate(core, poll);
+            }
+            STUN_TIMER_ID => {
+                trace!("Gave up waiting for {} stun(s)", self.stun_children.len());
+                let id = MappingId::from(self.token);
+                self.observer.notify(MappingEvent::ChildTimedOut(id, self.stun_children.len()));
+                self.stun_timeout = None;
+                self.awaiting_ext_addr = false;
+                self.terminate_children(core, poll, true);
+                self.finish_resolving();
+                self.maybe_terminate(core, poll);
+            }
+            _ => self.terminate(core, poll),
+        }
     }
 
     fn terminate(&mut self, core: &mut Core, poll: &Poll) {
-        self.terminate_children(core, poll);
-        let _ = core.remove_state(self.token);
-        let _ = core.cancel_timeout(&self.timeout);
+        self.stop(core, poll);
 
         let socket = unwrap!(self.socket.take());
-        let mapped_addrs = self.mapped_addrs.drain(..).collect();
-        (unwrap!(self.finish.take()))(core, poll, socket, mapped_addrs);
+        let mapped_addrs = mapped_addr::rank(self.mapped_addrs.drain(..).collect(),
+                                             self.config.keep_loopback);
+        self.stats.record_mapping(self.started_at.elapsed());
+        let id = MappingId::from(self.token);
+        self.observer.notify(MappingEvent::Finished(id, mapped_addrs.len()));
+        core.trace(self.token,
+                   TraceState::Mapping,
+                   "finished",
+                   &[("mapped_addrs", &mapped_addrs.len())]);
+        (unwrap!(self.finish.take()))(core, poll, Ok((socket, mapped_addrs)));
     }
 
     fn as_any(&mut self) -> &mut Any {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn port_range_skips_held_ports() {
+        let config = SocketConfig::default();
+        let held = unwrap!(util::new_reusably_bound_dual_stack_tcp_socket(0, &config));
+        let port = unwrap!(held.local_addr()).port();
+        let range = PortRange {
+            first: port,
+            last: port.saturating_add(1),
+        };
+        let socket = unwrap!(range.bind(true, &config));
+        assert_eq!(unwrap!(socket.local_addr()).port(), port + 1);
+
+        // A port asked for on its own is shared as it was before
+        let socket = unwrap!(PortRange::from(port).bind(true, &config));
+        assert_eq!(unwrap!(socket.local_addr()).port(), port);
+    }
+}
@@ -0,0 +1,356 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{Core, CoreTimer, Message, State, Timeout, bind_to_device, mark_dscp};
+use igd::PortMappingProtocol;
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use mio::{Poll, PollOpt, Ready, Token};
+use mio::udp::UdpSocket;
+use nat::{CompletionPolicy, MappingContext, MappingEvent, MappingId, MappingObserver, NatError,
+          Router, mapped_addr, port_mapping, util};
+use nat::mapped_addr::{MappedAddr, MappedAddrSource};
+use nat::peer_stuns::PeerStuns;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::rc::Rc;
+use std::time::Duration;
+
+const TIMEOUT_TIMER_ID: u8 = 0;
+const RESEND_TIMER_ID: u8 = TIMEOUT_TIMER_ID + 1;
+const ROUTER_TIMER_ID: u8 = RESEND_TIMER_ID + 1;
+const STUN_TIMER_ID: u8 = ROUTER_TIMER_ID + 1;
+
+/// A state which represents the in-progress mapping of a udp socket.
+///
+/// Unlike `MappedTcpSocket` the STUN-like queries are all sent from the very socket being mapped,
+/// so no child states are needed - responses are matched to the peer stun they came from.
+pub struct MappedUdpSocket<F> {
+    token: Token,
+    socket: Option<UdpSocket>,
+    router_children: usize,
+    igd_children: Vec<Token>,
+    stun_pending: HashSet<SocketAddr>,
+    request: Vec<u8>,
+    read_buf: [u8; 1024],
+    mapped_addrs: Vec<MappedAddr>,
+    timeout: Timeout,
+    resend_timeout: Option<Timeout>,
+    // Resends left, and the wait for an answer before each, as for queries over TCP.
+    resends_left: u32,
+    attempt_timeout: Duration,
+    backoff: Duration,
+    router_timeout: Option<Timeout>,
+    stun_timeout: Option<Timeout>,
+    completion: CompletionPolicy,
+    keep_loopback: bool,
+    health: PeerStuns,
+    observer: MappingObserver,
+    finish: Option<F>,
+}
+
+impl<F> MappedUdpSocket<F>
+    where F: FnOnce(&mut Core, &Poll, UdpSocket, Vec<SocketAddr>) + Any
+{
+    /// Start mapping a udp socket
+    pub fn start(core: &mut Core,
+                 poll: &Poll,
+                 port: u16,
+                 mc: &MappingContext,
+                 finish: F)
+                 -> Result<(), NatError> {
+        let token = core.get_new_token();
+
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+
+        let socket = util::new_reusably_bound_udp_socket(&addr)?;
+        let addr = socket.local_addr()?;
+        if let Some(dscp) = mc.mapping_config().socket.dscp {
+            let _ = mark_dscp(&socket, addr.is_ipv6(), dscp);
+        }
+        if let Some(ref device) = mc.mapping_config().socket.device {
+            bind_to_device(&socket, device)?;
+        }
+        let socket = UdpSocket::from_socket(socket)?;
+
+        // Ask IGD, NAT-PMP and PCP
+        let router_handler = move |core: &mut Core, poll: &Poll, router, ext_addr| {
+            let state = match core.get_state(token) {
+                Some(state) => state,
+                None => return,
+            };
+
+            let mut state = state.borrow_mut();
+            let mapping_sock = match state.as_any().downcast_mut::<MappedUdpSocket<F>>() {
+                Some(mapping_sock) => mapping_sock,
+                None => return,
+            };
+            mapping_sock.handle_router_resp(core, poll, router, ext_addr);
+        };
+        let config = *mc.mapping_config();
+        let (router_children, igd_children) = if config.discover {
+            port_mapping::request_router_mappings(core,
+                                                  poll,
+                                                  mc,
+                                                  PortMappingProtocol::UDP,
+                                                  addr.port(),
+                                                  router_handler)
+        } else {
+            (0, Vec::new())
+        };
+
+        let mut mapped_addrs: Vec<_> = mc.ifv4s()
+            .iter()
+            .map(|&ip| SocketAddr::new(IpAddr::V4(ip), addr.port()))
+            .map(|addr| MappedAddr::new(addr, MappedAddrSource::Local))
+            .collect();
+        mapped_addrs.extend(mapped_addr::declared_addrs(mc.declared_ext_addrs(),
+                                                        addr.port(),
+                                                        false));
+
+        let stun_pending: HashSet<SocketAddr> = if config.discover && config.query_stun {
+            mc.peer_stuns().iter().cloned().collect()
+        } else {
+            HashSet::new()
+        };
+
+        let timeout = core.set_timeout(config.timeout, CoreTimer::new(token, TIMEOUT_TIMER_ID))?;
+        let router_timeout = if router_children > 0 {
+            Some(core.set_timeout(config.router_timeout,
+                                  CoreTimer::new(token, ROUTER_TIMER_ID))?)
+        } else {
+            None
+        };
+        let stun_timeout = if !stun_pending.is_empty() {
+            Some(core.set_timeout(config.stun_timeout, CoreTimer::new(token, STUN_TIMER_ID))?)
+        } else {
+            None
+        };
+
+        let mut state = MappedUdpSocket {
+            token: token,
+            socket: Some(socket),
+            router_children: router_children,
+            igd_children: igd_children,
+            stun_pending: stun_pending,
+            request: serialise(&Message::EchoAddrReq)?,
+            read_buf: [0; 1024],
+            mapped_addrs: mapped_addrs,
+            timeout: timeout,
+            resend_timeout: None,
+            resends_left: config.query_retries,
+            attempt_timeout: config.stun_attempt_timeout(),
+            backoff: config.retry_backoff,
+            router_timeout: router_timeout,
+            stun_timeout: stun_timeout,
+            completion: config.completion,
+            keep_loopback: config.keep_loopback,
+            health: mc.peer_stun_health(),
+            observer: mc.observer(),
+            finish: Some(finish),
+        };
+
+        if state.is_done() {
+            return Ok(state.terminate(core, poll));
+        }
+
+        if !state.stun_pending.is_empty() {
+            poll.register(unwrap!(state.socket.as_ref()),
+                          token,
+                          Ready::error() | Ready::hup() | Ready::readable(),
+                          PollOpt::edge())?;
+            state.send_requests(core);
+        }
+
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+
+        Ok(())
+    }
+
+    fn send_requests(&mut self, core: &mut Core) {
+        if self.stun_pending.is_empty() {
+            return;
+        }
+        {
+            let socket = unwrap!(self.socket.as_ref());
+            for stun in &self.stun_pending {
+                // UDP is all or none and lossy anyway - anything not sent now will be retried
+                // when the resend timer fires, if any retries are left.
+                if let Err(e) = socket.send_to(&self.request, stun) {
+                    trace!("Could not send echo request to {}: {:?}", stun, e);
+                }
+            }
+        }
+        if self.resends_left == 0 {
+            return;
+        }
+        self.resends_left -= 1;
+        let delay = self.attempt_timeout + self.backoff;
+        self.backoff = self.backoff * 2;
+        self.resend_timeout = core.set_timeout(delay, CoreTimer::new(self.token, RESEND_TIMER_ID))
+            .ok();
+    }
+
+    fn read(&mut self, core: &mut Core, poll: &Poll) {
+        loop {
+            let (bytes_rxd, peer_addr) = match unwrap!(self.socket.as_ref())
+                      .recv_from(&mut self.read_buf) {
+                Ok(Some(res)) => res,
+                Ok(None) => return,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    debug!("Error reading from udp socket being mapped: {:?}", e);
+                    return self.terminate(core, poll);
+                }
+            };
+
+            if !self.stun_pending.contains(&peer_addr) {
+                continue;
+            }
+
+            match deserialise(&self.read_buf[..bytes_rxd]) {
+                Ok(Message::EchoAddrResp(our_ext_addr)) => {
+                    let _ = self.stun_pending.remove(&peer_addr);
+                    self.health.record(&peer_addr, true);
+                    let id = MappingId::from(self.token);
+                    self.observer.notify(MappingEvent::StunReplied(id, our_ext_addr));
+                    self.mapped_addrs
+                        .push(MappedAddr::new(our_ext_addr, MappedAddrSource::Stun));
+                }
+                Ok(msg) => trace!("Unexpected message from {}: {:?}", peer_addr, msg),
+                Err(e) => trace!("Bogus message from {}: {:?}", peer_addr, e),
+            }
+
+            if self.is_done() {
+                return self.terminate(core, poll);
+            }
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        (self.stun_pending.is_empty() && self.router_children == 0) ||
+        self.completion.is_satisfied(&self.mapped_addrs)
+    }
+
+    fn maybe_terminate(&mut self, core: &mut Core, poll: &Poll) {
+        if self.is_done() {
+            self.terminate(core, poll);
+        }
+    }
+
+    fn handle_router_resp(&mut self,
+                          core: &mut Core,
+                          poll: &Poll,
+                          router: Router,
+                          our_ext_addr: Option<SocketAddr>) {
+        self.router_children = self.router_children.saturating_sub(1);
+        if let Some(our_ext_addr) = our_ext_addr {
+            let id = MappingId::from(self.token);
+            self.observer.notify(MappingEvent::RouterMapped(id, router, our_ext_addr));
+            self.mapped_addrs.push(MappedAddr::new(our_ext_addr, MappedAddrSource::Router));
+        }
+        self.maybe_terminate(core, poll);
+    }
+
+    fn terminate_igd_children(&mut self, core: &mut Core, poll: &Poll) {
+        for token in self.igd_children.drain(..) {
+            let child = match core.get_state(token) {
+                Some(state) => state,
+                None => continue,
+            };
+
+            child.borrow_mut().terminate(core, poll);
+        }
+    }
+}
+
+impl<F> State for MappedUdpSocket<F>
+    where F: FnOnce(&mut Core, &Poll, UdpSocket, Vec<SocketAddr>) + Any
+{
+    fn name(&self) -> &'static str {
+        "MappedUdpSocket"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        if kind.is_error() || kind.is_hup() {
+            self.terminate(core, poll);
+        } else if kind.is_readable() {
+            self.read(core, poll);
+        }
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, timer_id: u8) {
+        match timer_id {
+            RESEND_TIMER_ID => {
+                self.resend_timeout = None;
+                self.send_requests(core);
+            }
+            ROUTER_TIMER_ID => {
+                trace!("Gave up waiting for {} router mapping(s)", self.router_children);
+                let id = MappingId::from(self.token);
+                self.observer.notify(MappingEvent::ChildTimedOut(id, self.router_children));
+                self.router_timeout = None;
+                self.router_children = 0;
+                self.terminate_igd_children(core, poll);
+                self.maybe_terminate(core, poll);
+            }
+            STUN_TIMER_ID => {
+                trace!("Gave up waiting for {} stun(s)", self.stun_pending.len());
+                let id = MappingId::from(self.token);
+                self.observer.notify(MappingEvent::ChildTimedOut(id, self.stun_pending.len()));
+                self.stun_timeout = None;
+                for stun in self.stun_pending.drain() {
+                    self.health.record(&stun, false);
+                }
+                self.maybe_terminate(core, poll);
+            }
+            _ => self.terminate(core, poll),
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        self.terminate_igd_children(core, poll);
+        let _ = core.remove_state(self.token);
+        let _ = core.cancel_timeout(&self.timeout);
+        for timeout in self.resend_timeout
+                .take()
+                .into_iter()
+                .chain(self.router_timeout.take())
+                .chain(self.stun_timeout.take()) {
+            let _ = core.cancel_timeout(&timeout);
+        }
+
+        let socket = unwrap!(self.socket.take());
+        let _ = poll.deregister(&socket);
+        // Only a listening socket can be connected back to, so udp addresses stay unverified.
+        let mapped_addrs: Vec<_> = mapped_addr::rank(self.mapped_addrs.drain(..).collect(),
+                                                      self.keep_loopback)
+                .into_iter()
+                .map(|mapped| mapped.addr)
+                .collect();
+        let id = MappingId::from(self.token);
+        self.observer.notify(MappingEvent::Finished(id, mapped_addrs.len()));
+        (unwrap!(self.finish.take()))(core, poll, socket, mapped_addrs);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
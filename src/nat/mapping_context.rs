@@ -19,69 +19,432 @@
 
 
 use super::NatError;
+use super::ext_addr_cache::ExtAddrCache;
+use super::gateway_cache::{GatewayCache, Gateways};
+use super::lease_renewal::Leases;
+use super::mapped_addr::MappedAddr;
+use super::mapping_event::MappingObserver;
+use super::peer_stuns::PeerStuns;
+use super::stats::StatsRecorder;
+use common::{SocketConfig, lock};
 use common::get_if_addrs::{self, IfAddr};
 use crossbeam;
-use igd::{self, Gateway};
+use maidsafe_utilities::thread;
 use nat;
+use std::cmp;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
 
-/// Keeps track of information about external mapping servers
-#[derive(Debug, Clone)]
-pub struct MappingContext {
-    our_ifv4s: Vec<(Ipv4Addr, Option<Gateway>)>,
-    our_ifv6s: Vec<Ipv6Addr>,
-    peer_stuns: Vec<SocketAddr>,
+/// When a socket mapping should hand the socket back to its caller. Either way the mapping never
+/// outlives `MappingConfig::timeout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompletionPolicy {
+    /// Wait for every router and STUN query to answer or time out.
+    AllResults,
+    /// Finish as soon as one globally routable address is known, cancelling the rest.
+    FirstExternal,
 }
 
-impl MappingContext {
-    /// Create a new `MappingContext`
-    pub fn new() -> Result<MappingContext, NatError> {
+impl CompletionPolicy {
+    /// Whether the mapping can finish early having found `mapped_addrs` so far.
+    pub fn is_satisfied(&self, mapped_addrs: &[MappedAddr]) -> bool {
+        match *self {
+            CompletionPolicy::FirstExternal => {
+                mapped_addrs
+                    .iter()
+                    .any(|mapped| nat::ip_addr_is_global(&mapped.addr.ip()))
+            }
+            CompletionPolicy::AllResults => false,
+        }
+    }
+}
+
+/// Deadlines governing each socket mapping started with a `MappingContext`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappingConfig {
+    /// Deadline for the whole mapping, after which it completes with whatever has been found.
+    pub timeout: Duration,
+    /// How long to wait for IGD, NAT-PMP and PCP gateways to forward the port.
+    pub router_timeout: Duration,
+    /// How long to wait for the STUN-like peers to tell us our external address.
+    pub stun_timeout: Duration,
+    /// How long each individual STUN or IGD query may take before that one helper is given up on,
+    /// letting the mapping finish with the others that did answer.
+    pub query_timeout: Duration,
+    /// How many times a failed or unanswered STUN query is retried, over TCP or UDP, see
+    /// `stun_attempt_timeout`.
+    pub query_retries: u32,
+    /// Wait before the first retry of a STUN query, doubled for each one after.
+    pub retry_backoff: Duration,
+    /// Hand out loopback addresses as well, e.g. for testing several peers on one machine.
+    /// Otherwise they are only kept when there is nothing else to offer.
+    pub keep_loopback: bool,
+    /// How long an external IP reported by the peers is reused for new sockets before asking
+    /// them again. Zero asks them about every socket.
+    pub ext_addr_ttl: Duration,
+    /// How long a peer is given to connect back to one of our external addresses, proving it
+    /// reachable.
+    pub verify_timeout: Duration,
+    /// When to finish the mapping.
+    pub completion: CompletionPolicy,
+    /// Options for the tcp sockets bound for mappings and for the connections made from them.
+    pub socket: SocketConfig,
+    /// Ask routers and peers for our external addresses at all. Off when they are all declared
+    /// up front, see `MappingContext::set_declared_ext_addrs`.
+    pub discover: bool,
+    /// Ask the STUN-like peers for our external address. Off to rely on the routers (and any
+    /// declared addresses) alone.
+    pub query_stun: bool,
+}
+
+impl MappingConfig {
+    /// Config with an overall deadline of `timeout`, the sub-timeouts being capped by it.
+    pub fn with_timeout(timeout: Duration) -> Self {
+        let default = MappingConfig::default();
+        MappingConfig {
+            timeout: timeout,
+            router_timeout: cmp::min(default.router_timeout, timeout),
+            stun_timeout: cmp::min(default.stun_timeout, timeout),
+            query_timeout: cmp::min(default.query_timeout, timeout),
+            query_retries: default.query_retries,
+            retry_backoff: default.retry_backoff,
+            keep_loopback: default.keep_loopback,
+            ext_addr_ttl: default.ext_addr_ttl,
+            verify_timeout: default.verify_timeout,
+            completion: default.completion,
+            socket: default.socket,
+            discover: default.discover,
+            query_stun: default.query_stun,
+        }
+    }
+
+    /// How long each attempt at a STUN query waits for an answer: at most `query_timeout`, and
+    /// short enough that the retries, with their backoff, fit within `stun_timeout` as well.
+    pub fn stun_attempt_timeout(&self) -> Duration {
+        let attempts = self.query_retries.saturating_add(1);
+        let backoff = (0..cmp::min(self.query_retries, 31))
+            .fold(Some(Duration::from_millis(0)), |total, retry| {
+                total.and_then(|total| {
+                                   self.retry_backoff
+                                       .checked_mul(1 << retry)
+                                       .and_then(|backoff| total.checked_add(backoff))
+                               })
+            });
+        let budget = backoff
+            .and_then(|backoff| self.stun_timeout.checked_sub(backoff))
+            .unwrap_or(self.stun_timeout);
+        cmp::min(self.query_timeout, budget / attempts)
+    }
+}
+
+impl Default for MappingConfig {
+    fn default() -> Self {
+        MappingConfig {
+            timeout: Duration::from_secs(3),
+            router_timeout: Duration::from_secs(2),
+            stun_timeout: Duration::from_secs(2),
+            query_timeout: Duration::from_millis(1500),
+            query_retries: 2,
+            retry_backoff: Duration::from_millis(100),
+            keep_loopback: false,
+            ext_addr_ttl: Duration::from_secs(300),
+            verify_timeout: Duration::from_secs(5),
+            completion: CompletionPolicy::AllResults,
+            socket: SocketConfig::default(),
+            discover: true,
+            query_stun: true,
+        }
+    }
+}
+
+// Our v4 interfaces (address and netmask) and v6 ones
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Interfaces {
+    v4: Vec<(Ipv4Addr, Ipv4Addr)>,
+    v6: Vec<Ipv6Addr>,
+}
+
+impl Interfaces {
+    fn read() -> Result<Interfaces, NatError> {
         let ifs = get_if_addrs::get_if_addrs()?;
         let (mut ifv4s, mut ifv6s) = (Vec::with_capacity(5), Vec::with_capacity(5));
+        // Link-local addresses are of no use to anyone off our link and have no gateways.
         for interface in ifs {
             match interface.addr {
-                IfAddr::V4(v4_addr) => ifv4s.push((v4_addr.ip, None)),
-                IfAddr::V6(v6_addr) => ifv6s.push(v6_addr.ip),
+                IfAddr::V4(v4_addr) => {
+                    if !v4_addr.ip.is_link_local() {
+                        ifv4s.push((v4_addr.ip, v4_addr.netmask));
+                    }
+                }
+                IfAddr::V6(v6_addr) => {
+                    if !nat::util::ipv6_addr_is_unicast_link_local(&v6_addr.ip) {
+                        ifv6s.push(v6_addr.ip);
+                    }
+                }
             }
         }
 
-        crossbeam::scope(|scope| {
-            let mut guards = Vec::with_capacity(ifv4s.len());
-            for ifv4 in &mut ifv4s {
-                if !ifv4.0.is_loopback() {
-                    guards.push(scope.spawn(move || {
-                        ifv4.1 = igd::search_gateway_from_timeout(ifv4.0, Duration::from_secs(1))
-                            .ok();
-                    }));
-                }
-            }
-        });
+        Ok(Interfaces {
+               v4: ifv4s,
+               v6: ifv6s,
+           })
+    }
+}
+
+/// Keeps track of information about external mapping servers
+#[derive(Debug, Clone)]
+pub struct MappingContext {
+    interfaces: Arc<Mutex<Interfaces>>,
+    // Bumped whenever the interfaces are found to have changed
+    interface_changes: Arc<AtomicUsize>,
+    gateways: GatewayCache,
+    ext_addrs: ExtAddrCache,
+    peer_stuns: PeerStuns,
+    config: MappingConfig,
+    leases: Leases,
+    stats: StatsRecorder,
+    observer: MappingObserver,
+    // External addresses declared rather than discovered
+    declared_ext_addrs: Vec<SocketAddr>,
+    // Keep the interfaces we were given rather than reading them from the host
+    fixed_interfaces: bool,
+    // The router of a `FakeNat` host, with its external IP
+    #[cfg(feature = "test_utils")]
+    scripted_router: Option<(Ipv4Addr, ::test_utils::Answer)>,
+}
+
+impl MappingContext {
+    /// Create a new `MappingContext`. This looks for the gateways serving our interfaces, which
+    /// are then reused by every socket mapping until they go stale or fail to answer.
+    pub fn new() -> Result<MappingContext, NatError> {
+        MappingContext::with_igd(true)
+    }
+
+    /// Create a new `MappingContext`, which only looks for and asks IGD gateways to forward ports
+    /// if `igd`. Some routers crash or flood their logs when sent UPnP requests, in which case
+    /// NAT-PMP, PCP and the peers are relied upon instead.
+    pub fn with_igd(igd: bool) -> Result<MappingContext, NatError> {
+        let interfaces = Interfaces::read()?;
 
         Ok(MappingContext {
-               our_ifv4s: ifv4s,
-               our_ifv6s: ifv6s,
-               peer_stuns: Vec::with_capacity(10),
+               gateways: GatewayCache::new(&interfaces.v4, igd),
+               interfaces: Arc::new(Mutex::new(interfaces)),
+               interface_changes: Arc::new(AtomicUsize::new(0)),
+               ext_addrs: Default::default(),
+               peer_stuns: Default::default(),
+               config: Default::default(),
+               leases: Arc::new(Mutex::new(Vec::new())),
+               stats: Default::default(),
+               observer: Default::default(),
+               declared_ext_addrs: Vec::new(),
+               fixed_interfaces: false,
+               #[cfg(feature = "test_utils")]
+               scripted_router: None,
            })
     }
 
+    /// A context for the host `nat` describes, whose router and peers answer as scripted there
+    /// rather than any real gateway or peer being asked.
+    #[cfg(feature = "test_utils")]
+    pub fn scripted(nat: &::test_utils::FakeNat) -> MappingContext {
+        MappingContext {
+            interfaces: Arc::new(Mutex::new(Interfaces {
+                                                v4: nat.ifv4s().to_vec(),
+                                                v6: nat.ifv6s().to_vec(),
+                                            })),
+            interface_changes: Arc::new(AtomicUsize::new(0)),
+            gateways: GatewayCache::new(&[], true),
+            ext_addrs: ExtAddrCache::scripted(nat.ext_ips().to_vec(), nat.stun_answer()),
+            peer_stuns: Default::default(),
+            config: Default::default(),
+            leases: Arc::new(Mutex::new(Vec::new())),
+            stats: Default::default(),
+            observer: Default::default(),
+            declared_ext_addrs: Vec::new(),
+            fixed_interfaces: true,
+            scripted_router: nat.router_answer(),
+        }
+    }
+
     /// Inform the context about external "STUN" servers. Note that crust does not actually use
     /// STUN but a custom STUN-like protocol.
-    pub fn add_peer_stuns<A: IntoIterator<Item = SocketAddr>>(&mut self, stun_addrs: A) {
-        let listeners = stun_addrs
-            .into_iter()
-            .filter(|elt| nat::ip_addr_is_global(&elt.ip()));
-        self.peer_stuns.extend(listeners);
+    pub fn add_peer_stuns<A: IntoIterator<Item = SocketAddr>>(&self, stun_addrs: A) {
+        for stun_addr in stun_addrs {
+            let _ = self.peer_stuns.add(stun_addr);
+        }
+    }
+
+    /// Inform the context about a single "STUN" server, e.g. one learnt from the network while
+    /// running. Returns whether it was new and globally reachable.
+    pub fn add_peer_stun(&self, stun_addr: SocketAddr) -> bool {
+        self.peer_stuns.add(stun_addr)
+    }
+
+    /// Stop using a "STUN" server. Returns whether it was known.
+    pub fn remove_peer_stun(&self, stun_addr: &SocketAddr) -> bool {
+        self.peer_stuns.remove(stun_addr)
+    }
+
+    /// Set the deadlines used by mappings started from now on
+    pub fn set_mapping_config(&mut self, config: MappingConfig) {
+        self.config = config;
+    }
+
+    /// Get the deadlines for socket mappings
+    pub fn mapping_config(&self) -> &MappingConfig {
+        &self.config
+    }
+
+    /// Have every socket mapped from now on taken to be reachable at `addrs` as well, e.g. behind
+    /// a static 1:1 NAT, which are then put ahead of whatever is discovered. A port of 0 stands
+    /// for the port of the socket itself, while addresses with a port of their own are left to
+    /// listeners to use.
+    pub fn set_declared_ext_addrs(&mut self, addrs: Vec<SocketAddr>) {
+        self.declared_ext_addrs = addrs;
+    }
+
+    /// The external addresses declared rather than discovered.
+    pub fn declared_ext_addrs(&self) -> &[SocketAddr] {
+        &self.declared_ext_addrs
     }
 
     /// Get v4 interfaces
-    pub fn ifv4s(&self) -> &Vec<(Ipv4Addr, Option<Gateway>)> {
-        &self.our_ifv4s
+    pub fn ifv4s(&self) -> Vec<Ipv4Addr> {
+        lock(&self.interfaces)
+            .v4
+            .iter()
+            .map(|&(ip, _)| ip)
+            .collect()
+    }
+
+    /// Get v6 interfaces
+    pub fn ifv6s(&self) -> Vec<Ipv6Addr> {
+        lock(&self.interfaces).v6.clone()
+    }
+
+    /// Read our interfaces again and, if they have changed, look for the gateways serving them
+    /// and forget our external IP. Returns whether anything changed, in which case this blocks
+    /// for up to a second.
+    pub fn refresh_interfaces(&self) -> Result<bool, NatError> {
+        if !self.reread_interfaces()? {
+            return Ok(false);
+        }
+        let ifv4s = lock(&self.interfaces).v4.clone();
+        self.gateways.set_interfaces(&ifv4s);
+        Ok(true)
+    }
+
+    /// How many times our interfaces have been found to have changed, by `refresh_interfaces` or
+    /// on looking for the gateways again.
+    pub fn interface_changes(&self) -> usize {
+        self.interface_changes.load(Ordering::SeqCst)
+    }
+
+    // Read our interfaces again, forgetting our external IP if they have changed. Returns whether
+    // they have.
+    fn reread_interfaces(&self) -> Result<bool, NatError> {
+        if self.fixed_interfaces {
+            return Ok(false);
+        }
+        let interfaces = Interfaces::read()?;
+        {
+            let mut current = lock(&self.interfaces);
+            if *current == interfaces {
+                return Ok(false);
+            }
+            *current = interfaces;
+        }
+        let _ = self.interface_changes.fetch_add(1, Ordering::SeqCst);
+        self.ext_addrs.force_refresh();
+        Ok(true)
+    }
+
+    // Look for the gateways again on our interfaces as they are now, blocking until done.
+    fn rediscover_gateways(&self) {
+        if let Err(e) = self.reread_interfaces() {
+            debug!("Could not read network interfaces: {:?}", e);
+        }
+        let ifv4s = lock(&self.interfaces).v4.clone();
+        self.gateways.set_interfaces(&ifv4s);
+    }
+
+    /// Get the IGD, NAT-PMP and PCP gateways known, along with the v4 interface each serves. If
+    /// these have gone stale they are looked for again in the background, on our interfaces as
+    /// they are by then.
+    pub fn gateways(&self) -> Gateways {
+        if self.gateways.start_refresh() {
+            let mc = self.clone();
+            let _ = thread::named("Gateway-Discovery", move || mc.rediscover_gateways());
+        }
+        self.gateways.gateways()
+    }
+
+    /// The scripted router of a `FakeNat` host, with its external IP and how it answers.
+    #[cfg(feature = "test_utils")]
+    pub fn scripted_router(&self) -> Option<(Ipv4Addr, ::test_utils::Answer)> {
+        self.scripted_router
+    }
+
+    /// Read our interfaces and look for the gateways serving them again, blocking until done.
+    pub fn refresh_gateways(&self) {
+        self.rediscover_gateways();
     }
 
-    /// Iterate over the known servers
-    pub fn peer_stuns(&self) -> &Vec<SocketAddr> {
-        &self.peer_stuns
+    /// Handle to the gateways, for marking them stale once one of them fails to answer.
+    pub fn gateway_cache(&self) -> GatewayCache {
+        self.gateways.clone()
+    }
+
+    /// The external IPs recently learnt from the peers.
+    pub fn ext_addr_cache(&self) -> ExtAddrCache {
+        self.ext_addrs.clone()
+    }
+
+    /// Record that a peer saw our socket bound to `local_port` as `ext_addr`, e.g. over a
+    /// connection we already had to it, so that the next mapping need not ask.
+    pub fn record_ext_addr(&self, local_port: u16, ext_addr: &SocketAddr) {
+        self.ext_addrs.record(local_port, ext_addr);
+    }
+
+    /// Have the peers asked for our external IP again by the next mapping.
+    pub fn force_ext_addr_refresh(&self) {
+        self.ext_addrs.force_refresh();
+    }
+
+    /// Get the known servers, leaving out those which have repeatedly failed to answer lately
+    pub fn peer_stuns(&self) -> Vec<SocketAddr> {
+        self.peer_stuns.get()
+    }
+
+    /// Handle to the known servers, for reporting whether they answered.
+    pub fn peer_stun_health(&self) -> PeerStuns {
+        self.peer_stuns.clone()
+    }
+
+    /// Get the router port mappings currently held
+    pub fn leases(&self) -> Leases {
+        self.leases.clone()
+    }
+
+    /// Where mappings report their progress.
+    pub fn observer(&self) -> MappingObserver {
+        self.observer.clone()
+    }
+
+    /// Where traversal outcomes are counted.
+    pub fn stats(&self) -> StatsRecorder {
+        self.stats.clone()
+    }
+
+    /// Ask the routers to delete every port mapping currently held. Blocks until each router has
+    /// answered or timed out.
+    pub fn cleanup(&self) {
+        let leases: Vec<_> = lock(&self.leases).drain(..).collect();
+        crossbeam::scope(|scope| for lease in &leases {
+            let _ = scope.spawn(move || lease.delete());
+        });
     }
 }
 
@@ -94,22 +457,30 @@ mod tests {
     #[ignore]
     fn igd_gateway_available() {
         let mc = unwrap!(MappingContext::new(), "Could not instantiate MC");
-        assert!(!mc.our_ifv4s.is_empty());
-
-        let mut loopback_found = false;
-        let mut non_loopback_found = false;
-
-        for ifv4 in mc.our_ifv4s {
-            if ifv4.0.is_loopback() {
-                loopback_found = true;
-                assert!(ifv4.1.is_none());
-            } else {
-                non_loopback_found = true;
-                assert!(ifv4.1.is_some());
-            }
-        }
+        assert!(!mc.ifv4s().is_empty());
+
+        let igd_gateways = mc.gateways().igd;
+        assert!(!igd_gateways.is_empty());
+        assert!(igd_gateways.iter().all(|&(ip, _)| !ip.is_loopback()));
+    }
+
+    #[test]
+    fn stun_retries_fit_the_stun_timeout() {
+        let config = MappingConfig::default();
+        let attempt = config.stun_attempt_timeout();
+        let last_retry = attempt * 3 + config.retry_backoff * 3;
+        assert!(last_retry <= config.stun_timeout);
+
+        let config = MappingConfig {
+            query_retries: 0,
+            ..MappingConfig::default()
+        };
+        assert_eq!(config.stun_attempt_timeout(), config.query_timeout);
 
-        assert!(loopback_found);
-        assert!(non_loopback_found);
+        let config = MappingConfig {
+            query_retries: u32::max_value(),
+            ..MappingConfig::default()
+        };
+        assert!(config.stun_attempt_timeout() < config.stun_timeout);
     }
 }
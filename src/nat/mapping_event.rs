@@ -0,0 +1,83 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::lock;
+use mio::Token;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+
+/// Tells apart the mappings reporting their progress, as several may be in progress at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MappingId(usize);
+
+impl From<Token> for MappingId {
+    fn from(token: Token) -> MappingId {
+        MappingId(token.0)
+    }
+}
+
+/// The kind of router a port mapping was granted by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Router {
+    /// A UPnP Internet Gateway Device
+    Igd,
+    /// A NAT-PMP gateway
+    NatPmp,
+    /// A PCP server
+    Pcp,
+}
+
+/// What an in-progress socket mapping just learnt, for showing connection progress or debugging
+/// a mapping which seems stuck. Every event names the mapping it is about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MappingEvent {
+    /// A router of the given kind mapped our port to this external address.
+    RouterMapped(MappingId, Router, SocketAddr),
+    /// A peer's echo service told us this is our external address.
+    StunReplied(MappingId, SocketAddr),
+    /// This many routers or peers had not answered in time and were given up on.
+    ChildTimedOut(MappingId, usize),
+    /// The mapping finished with this many addresses.
+    Finished(MappingId, usize),
+}
+
+/// Where mappings report their progress, shared by every clone. Reports go nowhere until an
+/// observer is set.
+#[derive(Debug, Clone, Default)]
+pub struct MappingObserver {
+    tx: Arc<Mutex<Option<Sender<MappingEvent>>>>,
+}
+
+impl MappingObserver {
+    /// Send the progress of every mapping to `tx`, or stop reporting it if `None`.
+    pub fn set(&self, tx: Option<Sender<MappingEvent>>) {
+        *lock(&self.tx) = tx;
+    }
+
+    /// Report `event`, forgetting the observer if it has hung up.
+    pub fn notify(&self, event: MappingEvent) {
+        let mut tx = lock(&self.tx);
+        let hung_up = match *tx {
+            Some(ref sender) => sender.send(event).is_err(),
+            None => false,
+        };
+        if hung_up {
+            *tx = None;
+        }
+    }
+}
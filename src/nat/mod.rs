@@ -15,14 +15,49 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
+pub use self::diagnose::{NatDiagnostics, diagnose};
+pub use self::echo_server::EchoServer;
 pub use self::error::NatError;
-pub use self::mapped_tcp_socket::MappedTcpSocket;
-pub use self::mapping_context::MappingContext;
-pub use self::punch_hole::get_sockets;
-pub use self::util::ip_addr_is_global;
+pub use self::if_watcher::IfWatcher;
+pub use self::lease_renewal::LeaseRenewal;
+pub use self::mapped_addr::{MappedAddr, MappedAddrSource, declared_addrs};
+pub use self::mapped_tcp_socket::{MappedTcpSocket, MappingHandle, MappingResult, PortRange};
+pub use self::mapped_udp_socket::MappedUdpSocket;
+pub use self::mapping_context::{CompletionPolicy, MappingConfig, MappingContext};
+pub use self::mapping_event::{MappingEvent, MappingId, MappingObserver, Router};
+pub use self::nat_type::{DetectNatType, NatType};
+pub use self::rendezvous_client::{RendezvousClient, RendezvousResult};
+pub use self::stats::{GatewayStats, NatStats, StatsRecorder, StunStats};
+pub use self::tcp_rendezvous_connect::TcpRendezvousConnect;
+pub use self::udp_hole_punch::UdpHolePunch;
+pub use self::util::{ip_addr_is_global, new_reusably_bound_tcp_socket,
+                     new_reusably_bound_udp_socket, unmap_ipv4};
+pub use self::verify_reachability::VerifyReachability;
 
+mod diagnose;
+mod echo_server;
 mod error;
+mod ext_addr_cache;
+mod gateway_cache;
+mod get_igd_addr;
+mod if_watcher;
+mod lease_renewal;
+mod mapped_addr;
 mod mapped_tcp_socket;
+mod mapped_udp_socket;
 mod mapping_context;
+mod mapping_event;
+mod nat_pmp;
+mod nat_type;
+mod pcp;
+mod peer_stuns;
+mod port_mapping;
 mod punch_hole;
+mod rendezvous_client;
+#[cfg(feature = "test_utils")]
+mod scripted;
+mod stats;
+mod tcp_rendezvous_connect;
+mod udp_hole_punch;
 mod util;
+mod verify_reachability;
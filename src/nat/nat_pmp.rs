@@ -0,0 +1,229 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! A minimal, blocking NAT-PMP (RFC 6886) client. Its API mirrors that of the `igd` crate so the
+//! two mechanisms can be driven the same way from the mapping states.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use igd::PortMappingProtocol;
+use std::io::{self, Cursor, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::Duration;
+
+pub const NAT_PMP_PORT: u16 = 5351;
+const NAT_PMP_VERSION: u8 = 0;
+const OPCODE_EXTERNAL_ADDR: u8 = 0;
+const OPCODE_MAP_UDP: u8 = 1;
+const OPCODE_MAP_TCP: u8 = 2;
+const RESPONSE_OPCODE_OFFSET: u8 = 128;
+const INITIAL_RETRANSMIT_MS: u64 = 250;
+const MAX_ATTEMPTS: u32 = 3;
+
+quick_error! {
+    /// NAT-PMP specific error
+    #[derive(Debug)]
+    pub enum NatPmpError {
+        /// IO error
+        Io(e: io::Error) {
+            description("Io error during NAT-PMP request")
+            display("Io error during NAT-PMP request: {}", e)
+            cause(e)
+            from()
+        }
+        /// The gateway did not answer in time
+        NoResponse {
+            description("NAT-PMP gateway did not respond")
+        }
+        /// The gateway sent something which is not a valid response to our request
+        InvalidResponse {
+            description("Invalid NAT-PMP response")
+        }
+        /// The gateway answered with a non-zero result code
+        ResultCode(code: u16) {
+            description("NAT-PMP gateway returned an error")
+            display("NAT-PMP gateway returned result code {}", code)
+        }
+    }
+}
+
+/// A NAT-PMP capable gateway on the local network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NatPmpGateway {
+    /// Address of the gateway's NAT-PMP server.
+    pub addr: SocketAddrV4,
+    /// The external address the gateway reported during discovery.
+    pub external_ip: Ipv4Addr,
+}
+
+/// Look for a NAT-PMP gateway serving the interface `local_ip`.
+///
+/// NAT-PMP servers sit on the default gateway, which we can't portably read from the routing
+/// table, so we try the first host address of the interface's subnet - by far the most common
+/// router configuration.
+pub fn search_gateway_from_timeout(local_ip: Ipv4Addr,
+                                   netmask: Ipv4Addr,
+                                   timeout: Duration)
+                                   -> Result<NatPmpGateway, NatPmpError> {
+    let gateway_ip = guess_gateway(local_ip, netmask);
+    let addr = SocketAddrV4::new(gateway_ip, NAT_PMP_PORT);
+
+    let resp = request(local_ip, addr, &[NAT_PMP_VERSION, OPCODE_EXTERNAL_ADDR], 12, timeout)?;
+    let mut rdr = Cursor::new(&resp[8..]);
+    let external_ip = Ipv4Addr::from(rdr.read_u32::<BigEndian>()?);
+
+    Ok(NatPmpGateway {
+           addr: addr,
+           external_ip: external_ip,
+       })
+}
+
+impl NatPmpGateway {
+    /// Map `local_addr` to an external port of the gateway's choosing (our own port is suggested)
+    /// for `lease_duration` seconds.
+    pub fn get_any_address(&self,
+                           protocol: PortMappingProtocol,
+                           local_addr: SocketAddrV4,
+                           lease_duration: u32)
+                           -> Result<SocketAddrV4, NatPmpError> {
+        let (external_port, _) = self.map(protocol, local_addr, local_addr.port(), lease_duration)?;
+        Ok(SocketAddrV4::new(self.external_ip, external_port))
+    }
+
+    /// Ask for `ext_addr` to keep being forwarded to `local_addr` for another `lease_duration`
+    /// seconds, returning what the gateway actually settled on.
+    pub fn renew(&self,
+                 protocol: PortMappingProtocol,
+                 local_addr: SocketAddrV4,
+                 ext_addr: SocketAddrV4,
+                 lease_duration: u32)
+                 -> Result<SocketAddrV4, NatPmpError> {
+        let (external_port, _) = self.map(protocol, local_addr, ext_addr.port(), lease_duration)?;
+        Ok(SocketAddrV4::new(self.external_ip, external_port))
+    }
+
+    /// Stop forwarding to `local_addr`.
+    pub fn delete_mapping(&self,
+                          protocol: PortMappingProtocol,
+                          local_addr: SocketAddrV4)
+                          -> Result<(), NatPmpError> {
+        // A zero lifetime (and suggested port) deletes the mapping for the internal port
+        self.map(protocol, local_addr, 0, 0).map(|_| ())
+    }
+
+    fn map(&self,
+           protocol: PortMappingProtocol,
+           local_addr: SocketAddrV4,
+           suggested_port: u16,
+           lease_duration: u32)
+           -> Result<(u16, u32), NatPmpError> {
+        let opcode = match protocol {
+            PortMappingProtocol::UDP => OPCODE_MAP_UDP,
+            PortMappingProtocol::TCP => OPCODE_MAP_TCP,
+        };
+
+        let mut req = Vec::with_capacity(12);
+        req.push(NAT_PMP_VERSION);
+        req.push(opcode);
+        req.write_u16::<BigEndian>(0)?;
+        req.write_u16::<BigEndian>(local_addr.port())?;
+        req.write_u16::<BigEndian>(suggested_port)?;
+        req.write_u32::<BigEndian>(lease_duration)?;
+
+        let timeout = Duration::from_millis(INITIAL_RETRANSMIT_MS);
+        let resp = request(*local_addr.ip(), self.addr, &req, 16, timeout)?;
+        let mut rdr = Cursor::new(&resp[8..]);
+        let _internal_port = rdr.read_u16::<BigEndian>()?;
+        let external_port = rdr.read_u16::<BigEndian>()?;
+        let lifetime = rdr.read_u32::<BigEndian>()?;
+
+        Ok((external_port, lifetime))
+    }
+}
+
+/// Best guess at the default gateway of an interface: the first host address of its subnet.
+pub fn guess_gateway(local_ip: Ipv4Addr, netmask: Ipv4Addr) -> Ipv4Addr {
+    let network = u32::from(local_ip) & u32::from(netmask);
+    Ipv4Addr::from(network + 1)
+}
+
+/// Send `req` from `local_ip` to `gateway` and wait for a datagram in response, retransmitting
+/// with a doubling timeout as both NAT-PMP and PCP ask of clients. Returns the number of bytes
+/// received into `buf`.
+pub fn exchange(local_ip: Ipv4Addr,
+                gateway: SocketAddrV4,
+                req: &[u8],
+                buf: &mut [u8],
+                initial_timeout: Duration)
+                -> io::Result<usize> {
+    let socket = UdpSocket::bind(SocketAddr::V4(SocketAddrV4::new(local_ip, 0)))?;
+    socket.connect(SocketAddr::V4(gateway))?;
+
+    let mut timeout = initial_timeout;
+    for _ in 0..MAX_ATTEMPTS {
+        let _ = socket.send(req)?;
+        socket.set_read_timeout(Some(timeout))?;
+        match socket.recv(buf) {
+            Ok(bytes_rxd) => return Ok(bytes_rxd),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                timeout = timeout * 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(io::Error::new(ErrorKind::TimedOut, "No response from gateway"))
+}
+
+// Send `req` and wait for a response of `resp_len` bytes. The common part of the NAT-PMP
+// response header is validated here.
+fn request(local_ip: Ipv4Addr,
+           gateway: SocketAddrV4,
+           req: &[u8],
+           resp_len: usize,
+           initial_timeout: Duration)
+           -> Result<Vec<u8>, NatPmpError> {
+    let mut buf = [0; 16];
+    let bytes_rxd = match exchange(local_ip, gateway, req, &mut buf, initial_timeout) {
+        Ok(bytes_rxd) => bytes_rxd,
+        Err(ref e) if e.kind() == ErrorKind::TimedOut => return Err(NatPmpError::NoResponse),
+        Err(e) => return Err(From::from(e)),
+    };
+
+    if bytes_rxd < resp_len || buf[0] != NAT_PMP_VERSION ||
+       buf[1] != req[1] + RESPONSE_OPCODE_OFFSET {
+        return Err(NatPmpError::InvalidResponse);
+    }
+    let result_code = Cursor::new(&buf[2..4]).read_u16::<BigEndian>()?;
+    if result_code != 0 {
+        return Err(NatPmpError::ResultCode(result_code));
+    }
+
+    Ok(buf[..resp_len].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gateway_guess() {
+        assert_eq!(guess_gateway(Ipv4Addr::new(192, 168, 1, 42), Ipv4Addr::new(255, 255, 255, 0)),
+                   Ipv4Addr::new(192, 168, 1, 1));
+        assert_eq!(guess_gateway(Ipv4Addr::new(10, 1, 2, 3), Ipv4Addr::new(255, 0, 0, 0)),
+                   Ipv4Addr::new(10, 0, 0, 1));
+    }
+}
@@ -0,0 +1,484 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{Core, CoreTimer, Message, State, Timeout};
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use mio::{Poll, PollOpt, Ready, Token};
+use mio::udp::UdpSocket;
+use nat::{MappingContext, NatError, util};
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{self, IpAddr, Ipv4Addr, SocketAddr};
+use std::rc::Rc;
+use std::time::Duration;
+
+const PHASE_TIMEOUT_SEC: u64 = 2;
+const RESEND_MS: u64 = 500;
+const PHASE_TIMER_ID: u8 = 0;
+const RESEND_TIMER_ID: u8 = PHASE_TIMER_ID + 1;
+
+/// How the NAT (if any) between us and the internet treats udp traffic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NatType {
+    /// No NAT - the world sees our local endpoint.
+    Open,
+    /// Endpoint independent mapping, and anyone can reach us on it.
+    FullCone,
+    /// Endpoint independent mapping, reachable by hosts we have sent to.
+    AddressRestricted,
+    /// Endpoint independent mapping, reachable by the exact endpoints we have sent to.
+    PortRestricted,
+    /// A different mapping per destination - hole punching is unlikely to work.
+    Symmetric,
+}
+
+enum Phase {
+    // Ask every peer what our endpoint looks like to it
+    Mapping,
+    // Ask the first responder to reply from another port and the host held back from the mapping
+    // phase to send to the endpoint it has never heard from
+    Filtering {
+        other_port: SocketAddr,
+        other_host: Option<SocketAddr>,
+        other_port_seen: bool,
+    },
+}
+
+/// A state which classifies our NAT with the help of the echo service of several peers.
+///
+/// The mapping is determined by comparing the endpoints reported for the same socket by different
+/// peers. For cone NATs, filtering is then probed by asking one peer to reply from a port we have
+/// not sent to and another, held back from the mapping phase, to send to our mapped endpoint from
+/// an address we have not sent to. At least two peers with distinct IPs are needed to tell
+/// symmetric NATs apart from the rest, and a third on yet another IP to tell full cone ones.
+pub struct DetectNatType<F> {
+    token: Token,
+    socket: UdpSocket,
+    // Only sends, so the other host's echo reaches `socket` from an address it has not sent to
+    aux_socket: UdpSocket,
+    local_addr: SocketAddr,
+    our_ips: Vec<IpAddr>,
+    // Not sent to from `socket`, so that its echo only gets through a full cone NAT
+    held_back: Option<SocketAddr>,
+    phase: Phase,
+    responses: HashMap<SocketAddr, SocketAddr>,
+    pending: Vec<SocketAddr>,
+    read_buf: [u8; 1024],
+    phase_timeout: Timeout,
+    resend_timeout: Option<Timeout>,
+    finish: Option<F>,
+}
+
+impl<F> DetectNatType<F>
+    where F: FnOnce(&mut Core, &Poll, Option<NatType>) + Any
+{
+    /// Start detecting our NAT type using the peer stuns of `mc`, unless its mapping config has
+    /// us not ask them.
+    pub fn start(core: &mut Core,
+                 poll: &Poll,
+                 mc: &MappingContext,
+                 finish: F)
+                 -> Result<(), NatError> {
+        let config = mc.mapping_config();
+        if !config.discover || !config.query_stun {
+            return Err(NatError::StunDisabled);
+        }
+        let mut peers: Vec<_> = mc.peer_stuns()
+            .iter()
+            .filter(|addr| addr.is_ipv4())
+            .cloned()
+            .collect();
+        if peers.is_empty() {
+            return Err(NatError::NoPeerStuns);
+        }
+        let held_back = hold_back(&mut peers);
+
+        let unspecified = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
+        let socket = util::new_reusably_bound_udp_socket(&unspecified)?;
+        let local_addr = socket.local_addr()?;
+        let socket = UdpSocket::from_socket(socket)?;
+        let aux_socket = UdpSocket::from_socket(net::UdpSocket::bind(unspecified)?)?;
+
+        let token = core.get_new_token();
+        let phase_timeout = core.set_timeout(Duration::from_secs(PHASE_TIMEOUT_SEC),
+                                             CoreTimer::new(token, PHASE_TIMER_ID))?;
+
+        poll.register(&socket,
+                      token,
+                      Ready::error() | Ready::hup() | Ready::readable(),
+                      PollOpt::edge())?;
+
+        let mut state = DetectNatType {
+            token: token,
+            socket: socket,
+            aux_socket: aux_socket,
+            local_addr: local_addr,
+            our_ips: mc.ifv4s().iter().map(|&ip| IpAddr::V4(ip)).collect(),
+            held_back: held_back,
+            phase: Phase::Mapping,
+            responses: HashMap::with_capacity(peers.len()),
+            pending: peers,
+            read_buf: [0; 1024],
+            phase_timeout: phase_timeout,
+            resend_timeout: None,
+            finish: Some(finish),
+        };
+        state.send_requests(core);
+
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+
+        Ok(())
+    }
+
+    fn send_requests(&mut self, core: &mut Core) {
+        match self.phase {
+            Phase::Mapping => {
+                for peer in &self.pending {
+                    send(&self.socket, &Message::EchoAddrReq, peer);
+                }
+            }
+            Phase::Filtering { other_port, other_host, .. } => {
+                send(&self.socket, &Message::EchoAddrReqFromOtherPort, &other_port);
+                if let Some(other_host) = other_host {
+                    if let Some(&our_ext_addr) = self.responses.values().next() {
+                        send(&self.aux_socket,
+                             &Message::EchoAddrReqTo(our_ext_addr),
+                             &other_host);
+                    }
+                }
+            }
+        }
+        self.resend_timeout = core.set_timeout(Duration::from_millis(RESEND_MS),
+                                               CoreTimer::new(self.token, RESEND_TIMER_ID))
+            .ok();
+    }
+
+    fn read(&mut self, core: &mut Core, poll: &Poll) {
+        loop {
+            let (bytes_rxd, peer_addr) = match self.socket.recv_from(&mut self.read_buf) {
+                Ok(Some(res)) => res,
+                Ok(None) => return,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    debug!("Error reading from udp socket detecting NAT type: {:?}", e);
+                    return self.done(core, poll, None);
+                }
+            };
+
+            let our_ext_addr = match deserialise(&self.read_buf[..bytes_rxd]) {
+                Ok(Message::EchoAddrResp(our_ext_addr)) => util::unmap_ipv4(&our_ext_addr),
+                Ok(msg) => {
+                    trace!("Unexpected message from {}: {:?}", peer_addr, msg);
+                    continue;
+                }
+                Err(e) => {
+                    trace!("Bogus message from {}: {:?}", peer_addr, e);
+                    continue;
+                }
+            };
+
+            if let Some(nat_type) = self.handle_resp(peer_addr, our_ext_addr) {
+                return self.done(core, poll, Some(nat_type));
+            }
+            if self.is_mapping() && self.pending.is_empty() {
+                if let Some(res) = self.end_mapping_phase(core) {
+                    return self.done(core, poll, res);
+                }
+            }
+        }
+    }
+
+    fn handle_resp(&mut self, peer_addr: SocketAddr, our_ext_addr: SocketAddr) -> Option<NatType> {
+        match self.phase {
+            Phase::Mapping => {
+                if let Some(pos) = self.pending.iter().position(|addr| *addr == peer_addr) {
+                    let _ = self.pending.swap_remove(pos);
+                    let _ = self.responses.insert(peer_addr, our_ext_addr);
+                }
+                None
+            }
+            Phase::Filtering { other_port, other_host, ref mut other_port_seen } => {
+                match filtering_resp(peer_addr, other_port, other_host) {
+                    Some(FilteringResp::OtherHost) => Some(NatType::FullCone),
+                    Some(FilteringResp::OtherPort) => {
+                        *other_port_seen = true;
+                        None
+                    }
+                    None => None,
+                }
+            }
+        }
+    }
+
+    fn is_mapping(&self) -> bool {
+        match self.phase {
+            Phase::Mapping => true,
+            Phase::Filtering { .. } => false,
+        }
+    }
+
+    // `None` to carry on with the filtering phase, otherwise what to finish with.
+    fn end_mapping_phase(&mut self, core: &mut Core) -> Option<Option<NatType>> {
+        let other_port = match self.responses.keys().next() {
+            Some(&peer) => peer,
+            None => return Some(None),
+        };
+
+        if let Some(nat_type) = classify_mapping(self.local_addr, &self.our_ips, &self.responses) {
+            return Some(Some(nat_type));
+        }
+
+        self.phase = Phase::Filtering {
+            other_port: other_port,
+            other_host: self.held_back,
+            other_port_seen: false,
+        };
+
+        let _ = core.cancel_timeout(&self.phase_timeout);
+        match core.set_timeout(Duration::from_secs(PHASE_TIMEOUT_SEC),
+                               CoreTimer::new(self.token, PHASE_TIMER_ID)) {
+            Ok(timeout) => self.phase_timeout = timeout,
+            Err(_) => return Some(None),
+        }
+        if let Some(resend_timeout) = self.resend_timeout.take() {
+            let _ = core.cancel_timeout(&resend_timeout);
+        }
+        self.send_requests(core);
+
+        None
+    }
+
+    fn done(&mut self, core: &mut Core, poll: &Poll, res: Option<NatType>) {
+        self.terminate(core, poll);
+        if let Some(finish) = self.finish.take() {
+            finish(core, poll, res);
+        }
+    }
+}
+
+impl<F> State for DetectNatType<F>
+    where F: FnOnce(&mut Core, &Poll, Option<NatType>) + Any
+{
+    fn name(&self) -> &'static str {
+        "DetectNatType"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        if kind.is_error() || kind.is_hup() {
+            self.done(core, poll, None);
+        } else if kind.is_readable() {
+            self.read(core, poll);
+        }
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, timer_id: u8) {
+        if timer_id == RESEND_TIMER_ID {
+            return self.send_requests(core);
+        }
+
+        let res = match self.phase {
+            Phase::Mapping => {
+                match self.end_mapping_phase(core) {
+                    Some(res) => res,
+                    None => return,
+                }
+            }
+            Phase::Filtering { other_port_seen, .. } => Some(restricted(other_port_seen)),
+        };
+        self.done(core, poll, res);
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        let _ = poll.deregister(&self.socket);
+        let _ = core.cancel_timeout(&self.phase_timeout);
+        if let Some(resend_timeout) = self.resend_timeout.take() {
+            let _ = core.cancel_timeout(&resend_timeout);
+        }
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+// Takes a peer whose IP no other peer has out of `peers`, as long as two are left for the mapping
+// phase.
+fn hold_back(peers: &mut Vec<SocketAddr>) -> Option<SocketAddr> {
+    if peers.len() < 3 {
+        return None;
+    }
+    let pos = match peers
+              .iter()
+              .rposition(|addr| peers.iter().filter(|other| other.ip() == addr.ip()).count() == 1) {
+        Some(pos) => pos,
+        None => return None,
+    };
+    let held_back = peers.remove(pos);
+    if peers.iter().any(|addr| addr.ip() != peers[0].ip()) {
+        Some(held_back)
+    } else {
+        peers.push(held_back);
+        None
+    }
+}
+
+// The NAT type told by the mapping phase alone, or `None` for a cone NAT whose filtering is yet
+// to be probed.
+fn classify_mapping(local_addr: SocketAddr,
+                    our_ips: &[IpAddr],
+                    responses: &HashMap<SocketAddr, SocketAddr>)
+                    -> Option<NatType> {
+    let our_ext_addr = match responses.values().next() {
+        Some(&addr) => addr,
+        None => return None,
+    };
+    if responses.values().any(|addr| *addr != our_ext_addr) {
+        Some(NatType::Symmetric)
+    } else if our_ext_addr.port() == local_addr.port() && our_ips.contains(&our_ext_addr.ip()) {
+        Some(NatType::Open)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum FilteringResp {
+    // From the held back host, which we have never sent to
+    OtherHost,
+    // From another port of the host we have sent to
+    OtherPort,
+}
+
+fn filtering_resp(peer_addr: SocketAddr,
+                  other_port: SocketAddr,
+                  other_host: Option<SocketAddr>)
+                  -> Option<FilteringResp> {
+    if Some(peer_addr) == other_host {
+        Some(FilteringResp::OtherHost)
+    } else if peer_addr.ip() == other_port.ip() && peer_addr.port() != other_port.port() {
+        Some(FilteringResp::OtherPort)
+    } else {
+        None
+    }
+}
+
+// The NAT type of a cone NAT the held back host could not reach us through.
+fn restricted(other_port_seen: bool) -> NatType {
+    if other_port_seen {
+        NatType::AddressRestricted
+    } else {
+        NatType::PortRestricted
+    }
+}
+
+fn send(socket: &UdpSocket, msg: &Message, peer: &SocketAddr) {
+    let res = serialise(msg).map_err(NatError::from).and_then(|data| {
+        socket.send_to(&data, peer).map_err(NatError::from)
+    });
+    if let Err(e) = res {
+        trace!("Could not send {:?} to {}: {:?}", msg, peer, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        unwrap!(s.parse())
+    }
+
+    fn responses(pairs: &[(&str, &str)]) -> HashMap<SocketAddr, SocketAddr> {
+        pairs.iter().map(|&(peer, ext)| (addr(peer), addr(ext))).collect()
+    }
+
+    #[test]
+    fn held_back_host_has_an_ip_of_its_own() {
+        let mut peers = vec![addr("1.1.1.1:1"), addr("2.2.2.2:1"), addr("3.3.3.3:1")];
+        assert_eq!(hold_back(&mut peers), Some(addr("3.3.3.3:1")));
+        assert_eq!(peers, vec![addr("1.1.1.1:1"), addr("2.2.2.2:1")]);
+
+        // Its IP would have been sent to from another port
+        let mut peers = vec![addr("1.1.1.1:1"), addr("2.2.2.2:1"), addr("2.2.2.2:2")];
+        assert_eq!(hold_back(&mut peers), Some(addr("1.1.1.1:1")));
+
+        // The mapping phase needs two IPs of its own
+        let mut peers = vec![addr("1.1.1.1:1"), addr("1.1.1.1:2"), addr("2.2.2.2:1")];
+        assert_eq!(hold_back(&mut peers), None);
+        assert_eq!(peers.len(), 3);
+
+        let mut peers = vec![addr("1.1.1.1:1"), addr("2.2.2.2:1")];
+        assert_eq!(hold_back(&mut peers), None);
+    }
+
+    #[test]
+    fn open() {
+        let local_addr = addr("0.0.0.0:5000");
+        let our_ips = [unwrap!("9.9.9.9".parse())];
+        let responses = responses(&[("1.1.1.1:1", "9.9.9.9:5000"), ("2.2.2.2:1", "9.9.9.9:5000")]);
+        assert_eq!(classify_mapping(local_addr, &our_ips, &responses),
+                   Some(NatType::Open));
+    }
+
+    #[test]
+    fn symmetric() {
+        let local_addr = addr("0.0.0.0:5000");
+        let responses = responses(&[("1.1.1.1:1", "9.9.9.9:6000"), ("2.2.2.2:1", "9.9.9.9:6001")]);
+        assert_eq!(classify_mapping(local_addr, &[], &responses),
+                   Some(NatType::Symmetric));
+    }
+
+    #[test]
+    fn cone_is_probed_for_filtering() {
+        let local_addr = addr("0.0.0.0:5000");
+        let our_ips = [unwrap!("192.168.0.2".parse())];
+        let responses = responses(&[("1.1.1.1:1", "9.9.9.9:6000"), ("2.2.2.2:1", "9.9.9.9:6000")]);
+        assert_eq!(classify_mapping(local_addr, &our_ips, &responses), None);
+        assert_eq!(classify_mapping(local_addr, &our_ips, &HashMap::new()), None);
+    }
+
+    #[test]
+    fn full_cone() {
+        let other_port = addr("1.1.1.1:1");
+        let other_host = addr("3.3.3.3:1");
+        assert_eq!(filtering_resp(other_host, other_port, Some(other_host)),
+                   Some(FilteringResp::OtherHost));
+        // Only the exact endpoint held back proves we can be reached by anyone
+        assert_eq!(filtering_resp(addr("3.3.3.3:2"), other_port, Some(other_host)), None);
+        assert_eq!(filtering_resp(addr("2.2.2.2:1"), other_port, None), None);
+    }
+
+    #[test]
+    fn address_restricted() {
+        let other_port = addr("1.1.1.1:1");
+        assert_eq!(filtering_resp(addr("1.1.1.1:2"), other_port, Some(addr("3.3.3.3:1"))),
+                   Some(FilteringResp::OtherPort));
+        assert_eq!(restricted(true), NatType::AddressRestricted);
+    }
+
+    #[test]
+    fn port_restricted() {
+        let other_port = addr("1.1.1.1:1");
+        // A late echo of the mapping phase
+        assert_eq!(filtering_resp(other_port, other_port, Some(addr("3.3.3.3:1"))), None);
+        assert_eq!(restricted(false), NatType::PortRestricted);
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! A minimal, blocking Port Control Protocol (RFC 6887) client supporting the `MAP` opcode. PCP
+//! shares its server port with NAT-PMP, which it succeeds, and is what carrier-grade NATs and
+//! IPv6 firewalls speak.
+
+use super::nat_pmp::{self, NAT_PMP_PORT};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use igd::PortMappingProtocol;
+use std::io::{self, Cursor, ErrorKind, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+const PCP_VERSION: u8 = 2;
+const OPCODE_ANNOUNCE: u8 = 0;
+const OPCODE_MAP: u8 = 1;
+const RESPONSE_BIT: u8 = 0x80;
+const HEADER_LEN: usize = 24;
+const MAP_LEN: usize = 36;
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+const INITIAL_RETRANSMIT_MS: u64 = 250;
+
+quick_error! {
+    /// PCP specific error
+    #[derive(Debug)]
+    pub enum PcpError {
+        /// IO error
+        Io(e: io::Error) {
+            description("Io error during PCP request")
+            display("Io error during PCP request: {}", e)
+            cause(e)
+            from()
+        }
+        /// The server did not answer in time
+        NoResponse {
+            description("PCP server did not respond")
+        }
+        /// The server sent something which is not a valid response to our request
+        InvalidResponse {
+            description("Invalid PCP response")
+        }
+        /// The server answered with a non-zero result code
+        ResultCode(code: u8) {
+            description("PCP server returned an error")
+            display("PCP server returned result code {}", code)
+        }
+    }
+}
+
+/// Identifies the mappings we ask a PCP server for - it must be repeated to renew or delete them.
+pub type Nonce = [u8; 12];
+
+/// A PCP server on the local network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PcpGateway {
+    /// Address of the PCP server.
+    pub addr: SocketAddrV4,
+}
+
+/// Look for a PCP server on the default gateway of the interface `local_ip`, found the same way
+/// as for NAT-PMP.
+pub fn search_gateway_from_timeout(local_ip: Ipv4Addr,
+                                   netmask: Ipv4Addr,
+                                   timeout: Duration)
+                                   -> Result<PcpGateway, PcpError> {
+    let gateway_ip = nat_pmp::guess_gateway(local_ip, netmask);
+    let gateway = PcpGateway { addr: SocketAddrV4::new(gateway_ip, NAT_PMP_PORT) };
+
+    let req = header(OPCODE_ANNOUNCE, 0, local_ip)?;
+    let _ = gateway.request(local_ip, &req, HEADER_LEN, timeout)?;
+
+    Ok(gateway)
+}
+
+impl PcpGateway {
+    /// Map `local_addr` to an external address of the server's choosing (our own port is
+    /// suggested) for `lease_duration` seconds. Unlike IGD and NAT-PMP, the external address may
+    /// well be IPv6. Asking again with the same `nonce` renews the mapping.
+    pub fn get_any_address(&self,
+                           protocol: PortMappingProtocol,
+                           local_addr: SocketAddrV4,
+                           lease_duration: u32,
+                           nonce: &Nonce)
+                           -> Result<SocketAddr, PcpError> {
+        let protocol = match protocol {
+            PortMappingProtocol::TCP => PROTOCOL_TCP,
+            PortMappingProtocol::UDP => PROTOCOL_UDP,
+        };
+
+        let mut req = header(OPCODE_MAP, lease_duration, *local_addr.ip())?;
+        req.extend_from_slice(nonce);
+        req.push(protocol);
+        req.extend_from_slice(&[0; 3]);
+        req.write_u16::<BigEndian>(local_addr.port())?;
+        req.write_u16::<BigEndian>(local_addr.port())?;
+        req.extend_from_slice(&[0; 16]);
+
+        let timeout = Duration::from_millis(INITIAL_RETRANSMIT_MS);
+        let resp = self.request(*local_addr.ip(), &req, HEADER_LEN + MAP_LEN, timeout)?;
+
+        let mut rdr = Cursor::new(&resp[HEADER_LEN..]);
+        let mut resp_nonce = [0; 12];
+        rdr.read_exact(&mut resp_nonce)?;
+        let resp_protocol = rdr.read_u8()?;
+        if resp_nonce != *nonce || resp_protocol != protocol {
+            return Err(PcpError::InvalidResponse);
+        }
+        let mut reserved = [0; 3];
+        rdr.read_exact(&mut reserved)?;
+        let _internal_port = rdr.read_u16::<BigEndian>()?;
+        let external_port = rdr.read_u16::<BigEndian>()?;
+        let mut external_ip = [0; 16];
+        rdr.read_exact(&mut external_ip)?;
+
+        Ok(SocketAddr::new(from_pcp_addr(external_ip), external_port))
+    }
+
+    /// Delete the mapping of `local_addr` previously made with `nonce`.
+    pub fn delete_mapping(&self,
+                          protocol: PortMappingProtocol,
+                          local_addr: SocketAddrV4,
+                          nonce: &Nonce)
+                          -> Result<(), PcpError> {
+        self.get_any_address(protocol, local_addr, 0, nonce).map(|_| ())
+    }
+
+    fn request(&self,
+               local_ip: Ipv4Addr,
+               req: &[u8],
+               resp_len: usize,
+               initial_timeout: Duration)
+               -> Result<Vec<u8>, PcpError> {
+        let mut buf = [0; 1100];
+        let res = nat_pmp::exchange(local_ip, self.addr, req, &mut buf, initial_timeout);
+        let bytes_rxd = match res {
+            Ok(bytes_rxd) => bytes_rxd,
+            Err(ref e) if e.kind() == ErrorKind::TimedOut => return Err(PcpError::NoResponse),
+            Err(e) => return Err(From::from(e)),
+        };
+
+        // A NAT-PMP-only server answers with a version 0 response, which fails the check below.
+        if bytes_rxd < resp_len || buf[0] != PCP_VERSION || buf[1] != (req[1] | RESPONSE_BIT) {
+            return Err(PcpError::InvalidResponse);
+        }
+        let result_code = buf[3];
+        if result_code != 0 {
+            return Err(PcpError::ResultCode(result_code));
+        }
+
+        Ok(buf[..resp_len].to_vec())
+    }
+}
+
+fn header(opcode: u8, lifetime: u32, client_ip: Ipv4Addr) -> io::Result<Vec<u8>> {
+    let mut req = Vec::with_capacity(HEADER_LEN + MAP_LEN);
+    req.push(PCP_VERSION);
+    req.push(opcode);
+    req.extend_from_slice(&[0; 2]);
+    req.write_u32::<BigEndian>(lifetime)?;
+    for segment in &client_ip.to_ipv6_mapped().segments() {
+        req.write_u16::<BigEndian>(*segment)?;
+    }
+    Ok(req)
+}
+
+// PCP carries all addresses as 128 bit, with v4 addresses in their v4-mapped form.
+fn from_pcp_addr(octets: [u8; 16]) -> IpAddr {
+    if octets[..10] == [0; 10] && octets[10] == 0xff && octets[11] == 0xff {
+        return IpAddr::V4(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]));
+    }
+    let mut segments = [0u16; 8];
+    for (i, segment) in segments.iter_mut().enumerate() {
+        *segment = ((octets[2 * i] as u16) << 8) | octets[2 * i + 1] as u16;
+    }
+    IpAddr::V6(Ipv6Addr::new(segments[0],
+                             segments[1],
+                             segments[2],
+                             segments[3],
+                             segments[4],
+                             segments[5],
+                             segments[6],
+                             segments[7]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_request_layout() {
+        let req = unwrap!(header(OPCODE_MAP, 7200, Ipv4Addr::new(192, 168, 1, 42)));
+        assert_eq!(req.len(), HEADER_LEN);
+        assert_eq!(req[0], PCP_VERSION);
+        assert_eq!(req[1], OPCODE_MAP);
+        assert_eq!(&req[4..8], &[0, 0, 0x1c, 0x20]);
+        assert_eq!(&req[18..], &[0xff, 0xff, 192, 168, 1, 42]);
+    }
+
+    #[test]
+    fn pcp_addresses() {
+        let mut v4 = [0; 16];
+        v4[10] = 0xff;
+        v4[11] = 0xff;
+        v4[12..].copy_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(from_pcp_addr(v4), IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+
+        let mut v6 = [0; 16];
+        v6[0] = 0x20;
+        v6[1] = 0x01;
+        v6[15] = 1;
+        assert_eq!(from_pcp_addr(v6),
+                   IpAddr::V6(Ipv6Addr::new(0x2001, 0, 0, 0, 0, 0, 0, 1)));
+    }
+}
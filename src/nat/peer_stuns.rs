@@ -0,0 +1,150 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::lock;
+use nat::util;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Failures in a row after which a helper is demoted.
+const MAX_STRIKES: u32 = 3;
+/// How long a demoted helper is left alone before it is given another chance.
+const PROBATION_SEC: u64 = 10 * 60;
+
+#[derive(Debug)]
+struct Helper {
+    addr: SocketAddr,
+    strikes: u32,
+    demoted_at: Option<Instant>,
+}
+
+impl Helper {
+    fn is_demoted(&self) -> bool {
+        match self.demoted_at {
+            Some(demoted_at) => demoted_at.elapsed() < Duration::from_secs(PROBATION_SEC),
+            None => false,
+        }
+    }
+}
+
+/// The peers whose echo services help us with traversal, shared by every clone. Helpers may come
+/// and go while we run, and those which keep failing to answer are left out until they have
+/// served their probation.
+#[derive(Debug, Clone, Default)]
+pub struct PeerStuns {
+    helpers: Arc<Mutex<Vec<Helper>>>,
+}
+
+impl PeerStuns {
+    /// Add a helper unless it is known already or not globally reachable. Returns whether it was
+    /// added.
+    pub fn add(&self, addr: SocketAddr) -> bool {
+        if !util::ip_addr_is_global(&addr.ip()) {
+            return false;
+        }
+        let mut helpers = lock(&self.helpers);
+        if helpers.iter().any(|helper| helper.addr == addr) {
+            return false;
+        }
+        helpers.push(Helper {
+                         addr: addr,
+                         strikes: 0,
+                         demoted_at: None,
+                     });
+        true
+    }
+
+    /// Forget a helper. Returns whether it was known.
+    pub fn remove(&self, addr: &SocketAddr) -> bool {
+        let mut helpers = lock(&self.helpers);
+        let len = helpers.len();
+        helpers.retain(|helper| helper.addr != *addr);
+        helpers.len() != len
+    }
+
+    /// The helpers worth asking, in the order they were added. Should every one of them be
+    /// demoted, they are all returned anyway - a poor helper beats none.
+    pub fn get(&self) -> Vec<SocketAddr> {
+        let helpers = lock(&self.helpers);
+        let healthy: Vec<_> = helpers
+            .iter()
+            .filter(|helper| !helper.is_demoted())
+            .map(|helper| helper.addr)
+            .collect();
+        if healthy.is_empty() {
+            helpers.iter().map(|helper| helper.addr).collect()
+        } else {
+            healthy
+        }
+    }
+
+    /// Note whether the helper at `addr` answered a query.
+    pub fn record(&self, addr: &SocketAddr, answered: bool) {
+        let addr = util::unmap_ipv4(addr);
+        let mut helpers = lock(&self.helpers);
+        let helper = match helpers.iter_mut().find(|helper| helper.addr == addr) {
+            Some(helper) => helper,
+            None => return,
+        };
+        if answered {
+            helper.strikes = 0;
+            helper.demoted_at = None;
+            return;
+        }
+        helper.strikes += 1;
+        if helper.strikes >= MAX_STRIKES {
+            if helper.demoted_at.is_none() {
+                debug!("Demoting helper {} after {} failures", addr, helper.strikes);
+            }
+            // A helper back from probation gets one chance only
+            helper.demoted_at = Some(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn demotion() {
+        let stuns = PeerStuns::default();
+        let good = unwrap!("8.8.8.8:5483".parse());
+        let bad = unwrap!("8.8.4.4:5483".parse());
+        let local = unwrap!("192.168.0.1:5483".parse());
+
+        assert!(stuns.add(good));
+        assert!(stuns.add(bad));
+        assert!(!stuns.add(good));
+        assert!(!stuns.add(local));
+
+        for _ in 0..MAX_STRIKES - 1 {
+            stuns.record(&bad, false);
+        }
+        stuns.record(&good, false);
+        stuns.record(&good, true);
+        assert_eq!(stuns.get(), vec![good, bad]);
+
+        stuns.record(&bad, false);
+        assert_eq!(stuns.get(), vec![good]);
+
+        assert!(stuns.remove(&good));
+        assert!(!stuns.remove(&good));
+        assert_eq!(stuns.get(), vec![bad]);
+    }
+}
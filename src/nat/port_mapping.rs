@@ -0,0 +1,217 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{Core, CoreMessage, lock};
+use igd::PortMappingProtocol;
+use maidsafe_utilities::thread;
+use mio::{Poll, Token};
+use nat::{MappingContext, Router};
+use nat::gateway_cache::GatewayCache;
+use nat::get_igd_addr::GetIgdAddr;
+use nat::lease_renewal::{LEASE_SEC, Lease, LeaseGateway, Leases};
+use nat::nat_pmp::NatPmpError;
+use nat::pcp::PcpError;
+use nat::stats::StatsRecorder;
+use rand;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+
+/// Why a gateway did not forward a port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingFailure {
+    /// It answered but would not or could not, e.g. as the port is taken.
+    Refused,
+    /// It could not be reached or did not answer in time, so may be gone.
+    Unanswered,
+}
+
+impl From<NatPmpError> for MappingFailure {
+    fn from(e: NatPmpError) -> Self {
+        match e {
+            NatPmpError::Io(_) |
+            NatPmpError::NoResponse => MappingFailure::Unanswered,
+            NatPmpError::InvalidResponse |
+            NatPmpError::ResultCode(_) => MappingFailure::Refused,
+        }
+    }
+}
+
+impl From<PcpError> for MappingFailure {
+    fn from(e: PcpError) -> Self {
+        match e {
+            PcpError::Io(_) |
+            PcpError::NoResponse => MappingFailure::Unanswered,
+            PcpError::InvalidResponse |
+            PcpError::ResultCode(_) => MappingFailure::Refused,
+        }
+    }
+}
+
+/// Ask every IGD, NAT-PMP and PCP gateway known to `mc` to forward `port` to us. The outcome of
+/// each request is handed to `handler`, which will hence be called at most as many times as the
+/// returned count. IGD requests are driven by the event loop and their tokens are returned too -
+/// terminating those aborts the requests without `handler` being called for them.
+pub fn request_router_mappings<H>(core: &mut Core,
+                                  poll: &Poll,
+                                  mc: &MappingContext,
+                                  protocol: PortMappingProtocol,
+                                  port: u16,
+                                  handler: H)
+                                  -> (usize, Vec<Token>)
+    where H: Fn(&mut Core, &Poll, Router, Option<SocketAddr>) + Send + Sync + 'static
+{
+    let handler = Arc::new(handler);
+    let mut children = 0;
+    let mut igd_children = Vec::new();
+
+    #[cfg(feature = "test_utils")]
+    {
+        if let Some((ext_ip, answer)) = mc.scripted_router() {
+            let handler = handler.clone();
+            let finish = move |core: &mut Core, poll: &Poll, ext_addr| {
+                (*handler)(core, poll, Router::Igd, ext_addr)
+            };
+            match ::nat::scripted::request_mapping(core, ext_ip, answer, protocol, port, finish) {
+                Ok(child) => {
+                    igd_children.push(child);
+                    children += 1;
+                }
+                Err(e) => debug!("Could not ask the scripted router for a mapping: {:?}", e),
+            }
+        }
+    }
+
+    let gateways = mc.gateways();
+    let query_timeout = mc.mapping_config().query_timeout;
+
+    for (ip, gateway) in gateways.igd {
+        let handler = handler.clone();
+        let leases = mc.leases();
+        let cache = mc.gateway_cache();
+        let stats = mc.stats();
+        let addr_igd = SocketAddrV4::new(ip, port);
+        let lease_gateway = LeaseGateway::Igd(gateway.clone());
+        let finish = move |core: &mut Core,
+                           poll: &Poll,
+                           _: Token,
+                           res: Result<SocketAddrV4, MappingFailure>| {
+            let res = res.map(SocketAddr::V4);
+            record_outcome(&leases,
+                           &cache,
+                           &stats,
+                           lease_gateway.clone(),
+                           protocol,
+                           addr_igd,
+                           res);
+            (*handler)(core, poll, Router::Igd, res.ok())
+        };
+        match GetIgdAddr::start(core,
+                                poll,
+                                gateway,
+                                protocol,
+                                addr_igd,
+                                query_timeout,
+                                Box::new(finish)) {
+            Ok(child) => {
+                igd_children.push(child);
+                children += 1;
+            }
+            Err(e) => debug!("Could not ask IGD gateway for a mapping: {:?}", e),
+        }
+    }
+
+    for (ip, gateway) in gateways.nat_pmp {
+        let tx = core.sender().clone();
+        let handler = handler.clone();
+        let leases = mc.leases();
+        let cache = mc.gateway_cache();
+        let stats = mc.stats();
+        let addr_nat_pmp = SocketAddrV4::new(ip, port);
+        let _ = thread::named("NAT-PMP-Address-Mapping", move || {
+            let res = gateway
+                .get_any_address(protocol, addr_nat_pmp, LEASE_SEC)
+                .map(SocketAddr::V4)
+                .map_err(MappingFailure::from);
+            record_outcome(&leases,
+                           &cache,
+                           &stats,
+                           LeaseGateway::NatPmp(gateway),
+                           protocol,
+                           addr_nat_pmp,
+                           res);
+            let ext_addr = res.ok();
+            let _ = tx.send(CoreMessage::new(move |core, poll| {
+                                                 (*handler)(core, poll, Router::NatPmp, ext_addr)
+                                             }));
+        });
+        children += 1;
+    }
+
+    for (ip, gateway) in gateways.pcp {
+        let tx = core.sender().clone();
+        let handler = handler.clone();
+        let leases = mc.leases();
+        let cache = mc.gateway_cache();
+        let stats = mc.stats();
+        let addr_pcp = SocketAddrV4::new(ip, port);
+        let _ = thread::named("PCP-Address-Mapping", move || {
+            // The nonce has to be presented again to renew or delete the mapping.
+            let nonce = rand::random();
+            let res = gateway
+                .get_any_address(protocol, addr_pcp, LEASE_SEC, &nonce)
+                .map_err(MappingFailure::from);
+            record_outcome(&leases,
+                           &cache,
+                           &stats,
+                           LeaseGateway::Pcp(gateway, nonce),
+                           protocol,
+                           addr_pcp,
+                           res);
+            let ext_addr = res.ok();
+            let _ = tx.send(CoreMessage::new(move |core, poll| {
+                                                 (*handler)(core, poll, Router::Pcp, ext_addr)
+                                             }));
+        });
+        children += 1;
+    }
+
+    (children, igd_children)
+}
+
+// Hold on to a granted mapping, or have the gateways looked for again if one did not answer. One
+// that refused is still there, so is asked again next time.
+fn record_outcome(leases: &Leases,
+                  cache: &GatewayCache,
+                  stats: &StatsRecorder,
+                  gateway: LeaseGateway,
+                  protocol: PortMappingProtocol,
+                  local_addr: SocketAddrV4,
+                  res: Result<SocketAddr, MappingFailure>) {
+    stats.record_gateway(SocketAddr::V4(gateway.addr()), res.is_ok());
+    match res {
+        Ok(ext_addr) => {
+            lock(&leases).push(Lease {
+                                            gateway: gateway,
+                                            protocol: protocol,
+                                            local_addr: local_addr,
+                                            ext_addr: ext_addr,
+                                        })
+        }
+        Err(MappingFailure::Unanswered) => cache.expire(),
+        Err(MappingFailure::Refused) => (),
+    }
+}
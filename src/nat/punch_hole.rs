@@ -15,18 +15,20 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
+use common::SocketConfig;
 use mio::tcp::TcpListener;
 use nat::{NatError, util};
 use net2::TcpBuilder;
 use std::net::TcpStream;
 
 pub fn get_sockets(mapped_socket: &TcpBuilder,
-                   required: usize)
+                   required: usize,
+                   config: &SocketConfig)
                    -> Result<(TcpListener, Vec<TcpStream>), NatError> {
     let local_addr = mapped_socket.local_addr()?;
     let mut unconnected_sockets = Vec::with_capacity(required);
     for _ in 0..required {
-        let socket = util::new_reusably_bound_tcp_socket(&local_addr)?;
+        let socket = util::new_reusably_bound_tcp_socket(&local_addr, config)?;
         let socket = socket.to_tcp_stream()?;
         unconnected_sockets.push(socket);
     }
@@ -36,3 +38,57 @@ pub fn get_sockets(mapped_socket: &TcpBuilder,
 
     Ok((listener, unconnected_sockets))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio::tcp::TcpStream as MioTcpStream;
+    use std::io::ErrorKind;
+    use std::net::SocketAddr;
+    use std::thread;
+    use std::time::Duration;
+    use tests::timebomb;
+
+    fn accept(listener: &TcpListener) -> SocketAddr {
+        loop {
+            match listener.accept() {
+                Ok((_, addr)) => return addr,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                Err(e) => panic!("Could not accept: {}", e),
+            }
+        }
+    }
+
+    // What a rendezvous needs of the OS, which Windows is the odd one out at: the sockets dialing
+    // out share their port with the listener, and both dialing out and accepting work.
+    #[test]
+    fn sockets_share_port() {
+        timebomb(Duration::from_secs(10), || {
+            let config = SocketConfig::default();
+            let any_port = unwrap!("127.0.0.1:0".parse());
+            let ours = unwrap!(util::new_reusably_bound_tcp_socket(&any_port, &config));
+            let theirs = unwrap!(util::new_reusably_bound_tcp_socket(&any_port, &config));
+            let our_addr = unwrap!(ours.local_addr());
+            let their_addr = unwrap!(theirs.local_addr());
+
+            let (our_listener, our_sockets) = unwrap!(get_sockets(&ours, 2, &config));
+            let (their_listener, _) = unwrap!(get_sockets(&theirs, 1, &config));
+            assert_eq!(unwrap!(our_listener.local_addr()), our_addr);
+            for socket in &our_sockets {
+                assert_eq!(unwrap!(socket.local_addr()), our_addr);
+            }
+
+            let mut our_sockets = our_sockets.into_iter();
+            let _ours = unwrap!(MioTcpStream::connect_stream(unwrap!(our_sockets.next()),
+                                                             &their_addr));
+            assert_eq!(accept(&their_listener), our_addr);
+
+            // Dialing the same address from our other socket would make a second connection
+            // between the same two ports, so the listener is dialed from elsewhere
+            let other = unwrap!(MioTcpStream::connect(&our_addr));
+            assert_eq!(accept(&our_listener), unwrap!(other.local_addr()));
+        })
+    }
+}
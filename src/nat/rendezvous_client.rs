@@ -0,0 +1,225 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{Core, CoreTimer, Message, State, Timeout};
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use mio::{Poll, PollOpt, Ready, Token};
+use mio::udp::UdpSocket;
+use nat::NatError;
+use nat::echo_server::{MAX_INFO_LEN, RENDEZVOUS_TIMEOUT_SEC};
+use std::any::Any;
+use std::cell::RefCell;
+use std::io::{self, ErrorKind};
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::rc::Rc;
+use std::time::Duration;
+
+const RESEND_TIMER_ID: u8 = 0;
+const TIMEOUT_TIMER_ID: u8 = 1;
+// Well within the rate limit of the server.
+const RESEND_MS: u64 = 1000;
+
+/// What a rendezvous ends in: the other client's external address and the info it brought.
+pub type RendezvousResult = Result<(SocketAddr, Vec<u8>), NatError>;
+
+/// A state which meets another client at an `EchoServer`, swapping connection info with it.
+///
+/// The request is sent again every second, as udp is lossy and the other client may come along
+/// any time until the server forgets about us. Should it not have come by then, the rendezvous
+/// finishes with an `io::ErrorKind::TimedOut` error.
+pub struct RendezvousClient<F> {
+    token: Token,
+    socket: UdpSocket,
+    server: SocketAddr,
+    request: Vec<u8>,
+    read_buf: [u8; 2048],
+    resend_timeout: Option<Timeout>,
+    timeout: Timeout,
+    finish: Option<F>,
+}
+
+impl<F> RendezvousClient<F>
+    where F: FnOnce(&mut Core, &Poll, RendezvousResult) + Any
+{
+    /// Meet the client sending the same `key` to the echo server at `server`, bringing `info`.
+    pub fn start(core: &mut Core,
+                 poll: &Poll,
+                 server: SocketAddr,
+                 key: Vec<u8>,
+                 info: Vec<u8>,
+                 finish: F)
+                 -> Result<Token, NatError> {
+        if info.len() > MAX_INFO_LEN {
+            return Err(NatError::Io(io::Error::new(ErrorKind::InvalidInput,
+                                                   "Too much info for a rendezvous")));
+        }
+
+        let unspecified = if server.is_ipv4() {
+            IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))
+        } else {
+            IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0))
+        };
+        let socket = net::UdpSocket::bind(SocketAddr::new(unspecified, 0))?;
+        let socket = UdpSocket::from_socket(socket)?;
+        let token = core.get_new_token();
+
+        poll.register(&socket,
+                      token,
+                      Ready::error() | Ready::hup() | Ready::readable(),
+                      PollOpt::edge())?;
+
+        let timeout = core.set_timeout(Duration::from_secs(RENDEZVOUS_TIMEOUT_SEC),
+                                       CoreTimer::new(token, TIMEOUT_TIMER_ID))?;
+
+        let mut state = RendezvousClient {
+            token: token,
+            socket: socket,
+            server: server,
+            request: serialise(&Message::RendezvousReq(key, info))?,
+            read_buf: [0; 2048],
+            resend_timeout: None,
+            timeout: timeout,
+            finish: Some(finish),
+        };
+        state.send_request(core);
+
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+
+        Ok(token)
+    }
+
+    fn send_request(&mut self, core: &mut Core) {
+        // UDP is all or none and lossy anyway - anything not sent now is sent again shortly.
+        if let Err(e) = self.socket.send_to(&self.request, &self.server) {
+            trace!("Could not send rendezvous request to {}: {:?}", self.server, e);
+        }
+        self.resend_timeout = core.set_timeout(Duration::from_millis(RESEND_MS),
+                                               CoreTimer::new(self.token, RESEND_TIMER_ID))
+            .ok();
+    }
+
+    fn read(&mut self, core: &mut Core, poll: &Poll) {
+        loop {
+            let (bytes_rxd, peer_addr) = match self.socket.recv_from(&mut self.read_buf) {
+                Ok(Some(res)) => res,
+                Ok(None) => return,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => return self.finish(core, poll, Err(From::from(e))),
+            };
+
+            if peer_addr != self.server {
+                continue;
+            }
+
+            match deserialise(&self.read_buf[..bytes_rxd]) {
+                Ok(Message::RendezvousResp(addr, info)) => {
+                    return self.finish(core, poll, Ok((addr, info)));
+                }
+                Ok(msg) => trace!("Unexpected message from {}: {:?}", peer_addr, msg),
+                Err(e) => trace!("Bogus message from {}: {:?}", peer_addr, e),
+            }
+        }
+    }
+
+    fn finish(&mut self, core: &mut Core, poll: &Poll, res: RendezvousResult) {
+        let _ = poll.deregister(&self.socket);
+        let _ = core.remove_state(self.token);
+        let _ = core.cancel_timeout(&self.timeout);
+        if let Some(resend_timeout) = self.resend_timeout.take() {
+            let _ = core.cancel_timeout(&resend_timeout);
+        }
+        if let Some(finish) = self.finish.take() {
+            finish(core, poll, res);
+        }
+    }
+}
+
+impl<F> State for RendezvousClient<F>
+    where F: FnOnce(&mut Core, &Poll, RendezvousResult) + Any
+{
+    fn name(&self) -> &'static str {
+        "RendezvousClient"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        if kind.is_error() || kind.is_hup() {
+            let e = io::Error::new(ErrorKind::Other, "Rendezvous socket errored");
+            self.finish(core, poll, Err(From::from(e)));
+        } else if kind.is_readable() {
+            self.read(core, poll);
+        }
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, timer_id: u8) {
+        if timer_id == RESEND_TIMER_ID {
+            self.resend_timeout = None;
+            return self.send_request(core);
+        }
+        let e = io::Error::new(ErrorKind::TimedOut, "No other client came to the rendezvous");
+        self.finish(core, poll, Err(From::from(e)));
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        self.finish(core, poll, Err(NatError::Cancelled));
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{self, CoreMessage};
+    use nat::{EchoServer, util};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn meet_at_echo_server() {
+        let el = unwrap!(common::spawn_event_loop(0, Some("RendezvousClient")));
+        let (tx, rx) = mpsc::channel();
+        unwrap!(el.send(CoreMessage::new(move |core, poll| {
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0);
+            let socket = unwrap!(util::new_reusably_bound_udp_socket(&addr));
+            let server = unwrap!(socket.local_addr());
+            drop(socket);
+            let _ = unwrap!(EchoServer::start(core, poll, &server));
+
+            for &(info, their_info) in &[(b"info 0", b"info 1"), (b"info 1", b"info 0")] {
+                let tx = tx.clone();
+                let finish = move |_: &mut Core, _: &Poll, res: RendezvousResult| {
+                    let (_, info) = unwrap!(res);
+                    assert_eq!(info, their_info.to_vec());
+                    unwrap!(tx.send(()));
+                };
+                let _ = unwrap!(RendezvousClient::start(core,
+                                                        poll,
+                                                        server,
+                                                        b"meet here".to_vec(),
+                                                        info.to_vec(),
+                                                        finish));
+            }
+        })));
+        for _ in 0..2 {
+            unwrap!(rx.recv_timeout(Duration::from_secs(5)));
+        }
+    }
+}
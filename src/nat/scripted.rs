@@ -0,0 +1,125 @@
+// Copyright 2017 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Answers for the host a `FakeNat` describes, given on the event loop after the scripted latency
+//! as the routers and peers they stand in for would give them.
+
+use common::{Core, CoreTimer, State, Timeout};
+use igd::PortMappingProtocol;
+use mio::{Poll, Token};
+use nat::NatError;
+use nat::ext_addr_cache::{Lookup, Notify};
+use std::any::Any;
+use std::cell::RefCell;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::rc::Rc;
+use std::time::Duration;
+use test_utils::Answer;
+
+/// Calls `reply` once `latency` is over, unless terminated first.
+pub struct ScriptedReply<R> {
+    token: Token,
+    timeout: Timeout,
+    reply: Option<R>,
+}
+
+impl<R> ScriptedReply<R>
+    where R: FnOnce(&mut Core, &Poll) + 'static
+{
+    pub fn start(core: &mut Core, latency: Duration, reply: R) -> Result<Token, NatError> {
+        let token = core.get_new_token();
+        let state = ScriptedReply {
+            token: token,
+            timeout: core.set_timeout(latency, CoreTimer::new(token, 0))?,
+            reply: Some(reply),
+        };
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+        Ok(token)
+    }
+}
+
+impl<R> State for ScriptedReply<R>
+    where R: FnOnce(&mut Core, &Poll) + 'static
+{
+    fn name(&self) -> &'static str {
+        "ScriptedReply"
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u8) {
+        let _ = core.remove_state(self.token);
+        if let Some(reply) = self.reply.take() {
+            reply(core, poll);
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core, _poll: &Poll) {
+        let _ = core.cancel_timeout(&self.timeout);
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// Have the scripted router forward `port` to the same port on `ext_ip`, handing the outcome to
+/// `handler` as `request_router_mappings` would. Returns the token of the pending answer.
+pub fn request_mapping<H>(core: &mut Core,
+                          ext_ip: Ipv4Addr,
+                          answer: Answer,
+                          protocol: PortMappingProtocol,
+                          port: u16,
+                          handler: H)
+                          -> Result<Token, NatError>
+    where H: FnOnce(&mut Core, &Poll, Option<SocketAddr>) + 'static
+{
+    let ext_addr = if answer.fails {
+        None
+    } else {
+        Some(SocketAddr::new(IpAddr::V4(ext_ip), port))
+    };
+    debug!("Scripted router answering {:?} for {:?} port {}", ext_addr, protocol, port);
+    ScriptedReply::start(core,
+                         answer.latency,
+                         move |core: &mut Core, poll: &Poll| handler(core, poll, ext_addr))
+}
+
+/// Have the scripted peers report `ext_ips` to the mapping behind `token`, or nothing if they
+/// fail or there are none to report.
+pub fn lookup(core: &mut Core,
+              ext_ips: &[IpAddr],
+              answer: Answer,
+              token: Token,
+              notify: Notify)
+              -> Lookup {
+    let ext_ips = if answer.fails || ext_ips.is_empty() {
+        None
+    } else {
+        Some(ext_ips.to_vec())
+    };
+    match ScriptedReply::start(core,
+                               answer.latency,
+                               move |core: &mut Core, poll: &Poll| {
+                                   notify(core, poll, token, ext_ips)
+                               }) {
+        Ok(_) => Lookup::Pending,
+        Err(e) => {
+            debug!("Could not schedule the scripted peers' answer: {:?}", e);
+            Lookup::Cached(Vec::new())
+        }
+    }
+}
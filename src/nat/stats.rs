@@ -0,0 +1,176 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::lock;
+use std::cmp;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Port mapping requests answered by one IGD, NAT-PMP or PCP gateway.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GatewayStats {
+    /// Mappings granted
+    pub successes: u64,
+    /// Mappings refused or not answered
+    pub failures: u64,
+}
+
+/// Queries made to one peer's echo service.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StunStats {
+    /// Queries answered
+    pub answered: u64,
+    /// Queries given up on, retries included
+    pub unanswered: u64,
+    /// Sum of the round trip times of the answered queries
+    pub total_rtt: Duration,
+    /// Slowest round trip of the answered queries
+    pub max_rtt: Duration,
+}
+
+impl StunStats {
+    /// Average round trip time, if any query was answered.
+    pub fn mean_rtt(&self) -> Option<Duration> {
+        mean(self.total_rtt, self.answered)
+    }
+}
+
+/// A snapshot of how NAT traversal has fared, for monitoring its health in deployments.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NatStats {
+    /// Per gateway, keyed by its address
+    pub gateways: HashMap<SocketAddr, GatewayStats>,
+    /// Per helper peer, keyed by the address of its echo service
+    pub stuns: HashMap<SocketAddr, StunStats>,
+    /// Tcp socket mappings completed
+    pub mappings: u64,
+    /// Sum of the time taken by the completed mappings
+    pub total_mapping_time: Duration,
+    /// Slowest of the completed mappings
+    pub max_mapping_time: Duration,
+    /// Tcp hole punches attempted
+    pub hole_punch_attempts: u64,
+    /// Tcp hole punches which got us a connection
+    pub hole_punch_successes: u64,
+}
+
+impl NatStats {
+    /// Average time taken by a mapping, if any has completed.
+    pub fn mean_mapping_time(&self) -> Option<Duration> {
+        mean(self.total_mapping_time, self.mappings)
+    }
+
+    /// Fraction of hole punches which succeeded, if any was attempted.
+    pub fn hole_punch_success_rate(&self) -> Option<f64> {
+        if self.hole_punch_attempts == 0 {
+            None
+        } else {
+            Some(self.hole_punch_successes as f64 / self.hole_punch_attempts as f64)
+        }
+    }
+}
+
+fn mean(total: Duration, count: u64) -> Option<Duration> {
+    if count == 0 {
+        None
+    } else {
+        Some(total / cmp::min(count, u32::max_value() as u64) as u32)
+    }
+}
+
+/// Where the nat module counts its outcomes, shared by every clone.
+#[derive(Debug, Clone, Default)]
+pub struct StatsRecorder {
+    stats: Arc<Mutex<NatStats>>,
+}
+
+impl StatsRecorder {
+    /// A copy of the counters as they are now.
+    pub fn snapshot(&self) -> NatStats {
+        lock(&self.stats).clone()
+    }
+
+    /// Count a port mapping request to the gateway at `addr`.
+    pub fn record_gateway(&self, addr: SocketAddr, success: bool) {
+        let mut stats = lock(&self.stats);
+        let gateway = stats.gateways.entry(addr).or_insert_with(GatewayStats::default);
+        if success {
+            gateway.successes += 1;
+        } else {
+            gateway.failures += 1;
+        }
+    }
+
+    /// Count a query to the echo service at `addr`, answered after `rtt` or not at all.
+    pub fn record_stun(&self, addr: SocketAddr, rtt: Option<Duration>) {
+        let mut stats = lock(&self.stats);
+        let stun = stats.stuns.entry(addr).or_insert_with(StunStats::default);
+        match rtt {
+            Some(rtt) => {
+                stun.answered += 1;
+                stun.total_rtt += rtt;
+                stun.max_rtt = cmp::max(stun.max_rtt, rtt);
+            }
+            None => stun.unanswered += 1,
+        }
+    }
+
+    /// Count a completed tcp socket mapping which took `duration`.
+    pub fn record_mapping(&self, duration: Duration) {
+        let mut stats = lock(&self.stats);
+        stats.mappings += 1;
+        stats.total_mapping_time += duration;
+        stats.max_mapping_time = cmp::max(stats.max_mapping_time, duration);
+    }
+
+    /// Count a tcp hole punch.
+    pub fn record_hole_punch(&self, success: bool) {
+        let mut stats = lock(&self.stats);
+        stats.hole_punch_attempts += 1;
+        if success {
+            stats.hole_punch_successes += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts() {
+        let recorder = StatsRecorder::default();
+        let stun = unwrap!("8.8.8.8:5483".parse());
+
+        recorder.record_stun(stun, Some(Duration::from_millis(100)));
+        recorder.record_stun(stun, Some(Duration::from_millis(300)));
+        recorder.record_stun(stun, None);
+        recorder.record_hole_punch(true);
+        recorder.record_hole_punch(false);
+
+        let stats = recorder.snapshot();
+        let stun_stats = stats.stuns[&stun];
+        assert_eq!(stun_stats.answered, 2);
+        assert_eq!(stun_stats.unanswered, 1);
+        assert_eq!(stun_stats.mean_rtt(), Some(Duration::from_millis(200)));
+        assert_eq!(stun_stats.max_rtt, Duration::from_millis(300));
+        assert_eq!(stats.hole_punch_success_rate(), Some(0.5));
+        assert_eq!(stats.mean_mapping_time(), None);
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{Core, CoreTimer, SocketConfig, State, Timeout};
+use mio::{Poll, PollOpt, Ready, Token};
+use mio::tcp::TcpStream;
+use nat::{NatError, util};
+use std::any::Any;
+use std::cell::RefCell;
+use std::io;
+use std::net::{self, SocketAddr};
+use std::rc::Rc;
+use std::time::Duration;
+
+const RETRY_MS: u64 = 500;
+
+pub type Finish = Box<FnMut(&mut Core, &Poll, Token, Option<TcpStream>)>;
+
+/// The outgoing half of a rendezvous with one of the peer's addresses. Until the peer's own
+/// connect has opened a hole in its NAT our SYNs are likely to be dropped or reset, so failed
+/// attempts are retried from the same local port until the parent gives up on us.
+pub struct ConnectAttempt {
+    token: Token,
+    local_addr: SocketAddr,
+    socket_config: SocketConfig,
+    peer_addr: SocketAddr,
+    socket: Option<TcpStream>,
+    retry_timeout: Option<Timeout>,
+    finish: Finish,
+}
+
+impl ConnectAttempt {
+    pub fn start(core: &mut Core,
+                 poll: &Poll,
+                 socket: net::TcpStream,
+                 peer_addr: &SocketAddr,
+                 socket_config: SocketConfig,
+                 finish: Finish)
+                 -> Result<Token, NatError> {
+        let token = core.get_new_token();
+        let local_addr = socket.local_addr()?;
+
+        let mut state = ConnectAttempt {
+            token: token,
+            local_addr: local_addr,
+            socket_config: socket_config,
+            peer_addr: util::to_family_of(&local_addr, peer_addr),
+            socket: None,
+            retry_timeout: None,
+            finish: finish,
+        };
+        if let Err(e) = state.connect(poll, socket) {
+            if !util::connect_is_transient(&e) {
+                return Err(From::from(e));
+            }
+            debug!("Could not connect to {} from {} yet: {:?}",
+                   state.peer_addr,
+                   local_addr,
+                   e);
+            state.schedule_retry(core, poll);
+        }
+
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+
+        Ok(token)
+    }
+
+    fn connect(&mut self, poll: &Poll, socket: net::TcpStream) -> io::Result<()> {
+        let socket = TcpStream::connect_stream(socket, &self.peer_addr)?;
+        poll.register(&socket,
+                      self.token,
+                      Ready::error() | Ready::hup() | Ready::writable(),
+                      PollOpt::edge())?;
+        self.socket = Some(socket);
+        Ok(())
+    }
+
+    fn reconnect(&mut self, core: &mut Core, poll: &Poll) {
+        self.retry_timeout = None;
+        let res = util::new_reusably_bound_tcp_socket(&self.local_addr, &self.socket_config)
+            .and_then(|socket| socket.to_tcp_stream())
+            .and_then(|socket| self.connect(poll, socket));
+        match res {
+            Ok(()) => (),
+            Err(ref e) if util::connect_is_transient(e) => {
+                debug!("Could not reconnect to {} from {} yet: {:?}",
+                       self.peer_addr,
+                       self.local_addr,
+                       e);
+                self.schedule_retry(core, poll);
+            }
+            Err(e) => {
+                debug!("Could not reconnect to {} from {}: {:?}",
+                       self.peer_addr,
+                       self.local_addr,
+                       e);
+                self.handle_error(core, poll);
+            }
+        }
+    }
+
+    fn schedule_retry(&mut self, core: &mut Core, poll: &Poll) {
+        if let Some(socket) = self.socket.take() {
+            let _ = poll.deregister(&socket);
+        }
+        match core.set_timeout(Duration::from_millis(RETRY_MS),
+                               CoreTimer::new(self.token, 0)) {
+            Ok(timeout) => self.retry_timeout = Some(timeout),
+            Err(_) => self.handle_error(core, poll),
+        }
+    }
+
+    fn handle_connected(&mut self, core: &mut Core, poll: &Poll) {
+        let connected = match self.socket.as_ref() {
+            Some(socket) => {
+                match socket.take_error() {
+                    Ok(None) => socket.peer_addr().is_ok(),
+                    Ok(Some(_)) | Err(_) => false,
+                }
+            }
+            None => false,
+        };
+
+        if !connected {
+            return self.schedule_retry(core, poll);
+        }
+
+        let socket = self.socket.take();
+        if let Some(ref socket) = socket {
+            let _ = poll.deregister(socket);
+        }
+        self.terminate(core, poll);
+        let token = self.token;
+        (*self.finish)(core, poll, token, socket);
+    }
+
+    fn handle_error(&mut self, core: &mut Core, poll: &Poll) {
+        self.terminate(core, poll);
+        let token = self.token;
+        (*self.finish)(core, poll, token, None);
+    }
+}
+
+impl State for ConnectAttempt {
+    fn name(&self) -> &'static str {
+        "ConnectAttempt"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        if kind.is_error() || kind.is_hup() {
+            self.schedule_retry(core, poll);
+        } else if kind.is_writable() {
+            self.handle_connected(core, poll);
+        }
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u8) {
+        self.reconnect(core, poll);
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        if let Some(socket) = self.socket.take() {
+            let _ = poll.deregister(&socket);
+        }
+        if let Some(retry_timeout) = self.retry_timeout.take() {
+            let _ = core.cancel_timeout(&retry_timeout);
+        }
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
@@ -0,0 +1,236 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use self::connect_attempt::ConnectAttempt;
+use common::{Core, CoreTimer, SocketConfig, State, Timeout};
+use mio::{Poll, PollOpt, Ready, Token};
+use mio::tcp::{TcpListener, TcpStream};
+use nat::{NatError, StatsRecorder, punch_hole};
+use net2::TcpBuilder;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+
+mod connect_attempt;
+
+const TIMEOUT_SEC: u64 = 30;
+
+/// Called with each stream established with the peer, and then with `None` once the rendezvous
+/// times out.
+pub type Finish = Box<FnMut(&mut Core, &Poll, Token, Option<TcpStream>)>;
+
+/// A state which races simultaneous `connect`s to every one of the peer's mapped addresses against
+/// `accept`s on our own mapped socket, which is what TCP hole punching boils down to. The peer is
+/// expected to be doing the same to our addresses at about the same time.
+///
+/// Every stream is handed on, not only the first: which one comes first can differ between the
+/// two ends, so it is left to the handshake to settle on the same one, see `ConnectionCandidate`.
+/// The rendezvous carries on until it is terminated or times out.
+pub struct TcpRendezvousConnect {
+    token: Token,
+    listener: TcpListener,
+    children: HashSet<Token>,
+    timeout: Timeout,
+    self_weak: Weak<RefCell<TcpRendezvousConnect>>,
+    stats: StatsRecorder,
+    delivered: bool,
+    finish: Finish,
+}
+
+impl TcpRendezvousConnect {
+    /// Start the rendezvous from the reusably bound `socket` we previously got mapped.
+    pub fn start(core: &mut Core,
+                 poll: &Poll,
+                 socket: &TcpBuilder,
+                 peer_addrs: Vec<SocketAddr>,
+                 stats: StatsRecorder,
+                 socket_config: SocketConfig,
+                 finish: Finish)
+                 -> Result<Token, NatError> {
+        let token = core.get_new_token();
+        let (listener, sockets) =
+            punch_hole::get_sockets(socket, peer_addrs.len(), &socket_config)?;
+        let timeout = core.set_timeout(Duration::from_secs(TIMEOUT_SEC),
+                                       CoreTimer::new(token, 0))?;
+
+        poll.register(&listener,
+                      token,
+                      Ready::readable() | Ready::error() | Ready::hup(),
+                      PollOpt::edge())?;
+
+        let state = Rc::new(RefCell::new(TcpRendezvousConnect {
+                                             token: token,
+                                             listener: listener,
+                                             children: HashSet::with_capacity(sockets.len()),
+                                             timeout: timeout,
+                                             self_weak: Weak::new(),
+                                             stats: stats,
+                                             delivered: false,
+                                             finish: finish,
+                                         }));
+        state.borrow_mut().self_weak = Rc::downgrade(&state);
+
+        for (socket, peer_addr) in sockets.into_iter().zip(peer_addrs) {
+            let self_weak = state.borrow().self_weak.clone();
+            let handler = move |core: &mut Core, poll: &Poll, child, res| {
+                if let Some(self_rc) = self_weak.upgrade() {
+                    self_rc.borrow_mut().handle_attempt(core, poll, child, res);
+                }
+            };
+
+            match ConnectAttempt::start(core,
+                                        poll,
+                                        socket,
+                                        &peer_addr,
+                                        socket_config,
+                                        Box::new(handler)) {
+                Ok(child) => {
+                    let _ = state.borrow_mut().children.insert(child);
+                }
+                Err(e) => debug!("Could not start connecting to {}: {:?}", peer_addr, e),
+            }
+        }
+
+        let _ = core.insert_state(token, state);
+
+        Ok(token)
+    }
+
+    fn handle_attempt(&mut self,
+                      core: &mut Core,
+                      poll: &Poll,
+                      child: Token,
+                      res: Option<TcpStream>) {
+        let _ = self.children.remove(&child);
+        if let Some(stream) = res {
+            self.deliver(core, poll, stream);
+        }
+    }
+
+    fn accept(&mut self, core: &mut Core, poll: &Poll) {
+        while let Ok((stream, _)) = self.listener.accept() {
+            self.deliver(core, poll, stream);
+        }
+    }
+
+    fn deliver(&mut self, core: &mut Core, poll: &Poll, stream: TcpStream) {
+        if !self.delivered {
+            self.delivered = true;
+            self.stats.record_hole_punch(true);
+        }
+        let token = self.token;
+        (*self.finish)(core, poll, token, Some(stream));
+    }
+
+    fn terminate_children(&mut self, core: &mut Core, poll: &Poll) {
+        for child in self.children.drain() {
+            let child = match core.get_state(child) {
+                Some(state) => state,
+                None => continue,
+            };
+
+            child.borrow_mut().terminate(core, poll);
+        }
+    }
+}
+
+impl State for TcpRendezvousConnect {
+    fn name(&self) -> &'static str {
+        "TcpRendezvousConnect"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        if !kind.is_error() && !kind.is_hup() && kind.is_readable() {
+            self.accept(core, poll);
+        }
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u8) {
+        debug!("Tcp rendezvous connect timed out");
+        self.terminate(core, poll);
+        if !self.delivered {
+            self.stats.record_hole_punch(false);
+        }
+        let token = self.token;
+        (*self.finish)(core, poll, token, None);
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        self.terminate_children(core, poll);
+        let _ = poll.deregister(&self.listener);
+        let _ = core.cancel_timeout(&self.timeout);
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{CoreMessage, spawn_event_loop};
+    use nat::util;
+    use std::net::{TcpListener as StdTcpListener, TcpStream as StdTcpStream};
+    use std::sync::mpsc;
+    use tests::timebomb;
+
+    // Whichever stream the peer takes, we must have it too.
+    #[test]
+    fn delivers_every_stream() {
+        timebomb(Duration::from_secs(10), || {
+            let el = unwrap!(spawn_event_loop(0, None));
+            let peer = unwrap!(StdTcpListener::bind("127.0.0.1:0"));
+            let peer_addr = unwrap!(peer.local_addr());
+            let (addr_tx, addr_rx) = mpsc::channel();
+            let (stream_tx, stream_rx) = mpsc::channel();
+
+            unwrap!(el.send(CoreMessage::new(move |core, poll| {
+                let config = SocketConfig::default();
+                let any_port = unwrap!("127.0.0.1:0".parse());
+                let socket = unwrap!(util::new_reusably_bound_tcp_socket(&any_port, &config));
+                unwrap!(addr_tx.send(unwrap!(socket.local_addr())));
+                let finish = move |_: &mut Core, _: &Poll, _, res: Option<TcpStream>| {
+                    let _ = stream_tx.send(res.map(|stream| unwrap!(stream.peer_addr())));
+                };
+                let _ = unwrap!(TcpRendezvousConnect::start(core,
+                                                             poll,
+                                                             &socket,
+                                                             vec![peer_addr],
+                                                             StatsRecorder::default(),
+                                                             config,
+                                                             Box::new(finish)));
+            })));
+            let our_addr = unwrap!(addr_rx.recv());
+
+            let (_dialed_us, _) = unwrap!(peer.accept());
+            let dialing = unwrap!(StdTcpStream::connect(our_addr));
+            let dialing_addr = unwrap!(dialing.local_addr());
+
+            let mut delivered = vec![unwrap!(unwrap!(stream_rx.recv())),
+                                     unwrap!(unwrap!(stream_rx.recv()))];
+            delivered.sort();
+            let mut expected = vec![peer_addr, dialing_addr];
+            expected.sort();
+            assert_eq!(delivered, expected);
+        })
+    }
+}
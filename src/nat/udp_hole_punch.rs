@@ -0,0 +1,317 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use byteorder::{BigEndian, WriteBytesExt};
+use common::{Core, CoreTimer, State, Timeout};
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use mio::{Poll, PollOpt, Ready, Token};
+use mio::udp::UdpSocket;
+use nat::{NatError, util};
+use rand;
+use rust_sodium::crypto::auth::hmacsha256;
+use rust_sodium::crypto::hash::sha256;
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
+
+const TIMEOUT_SEC: u64 = 10;
+const INITIAL_RESEND_MS: u64 = 100;
+const MAX_RESEND_MS: u64 = 1600;
+// How long we keep answering the peer once acked ourselves, in case our acks to it were lost
+const LINGER_MS: u64 = 2 * MAX_RESEND_MS;
+const TIMEOUT_TIMER_ID: u8 = 0;
+const RESEND_TIMER_ID: u8 = TIMEOUT_TIMER_ID + 1;
+
+const SYN_TAG: u8 = 0;
+const ACK_TAG: u8 = 1;
+const ACKED_SYN_TAG: u8 = 2;
+
+type Mac = [u8; hmacsha256::TAGBYTES];
+
+#[derive(Debug, Serialize, Deserialize)]
+enum PunchMsg {
+    /// Our random nonce and whether it has been acked yet, authenticated with the shared secret.
+    Syn(u64, bool, Mac),
+    /// Echoes the nonce of a valid `Syn`, again authenticated with the shared secret.
+    Ack(u64, Mac),
+}
+
+/// A state which punches a hole for a mapped udp socket towards a peer doing the same for its own.
+///
+/// Authenticated `Syn`s are sent to every one of the peer's endpoints with exponential backoff and
+/// each valid `Syn` received is answered with an `Ack`. Once acked for our nonce, traffic is known
+/// to flow both ways and our `Syn`s say so. The socket is handed back along with the peer endpoint
+/// that worked as soon as the peer says it has been acked too, or else after lingering a while to
+/// answer it in case our `Ack`s were lost.
+pub struct UdpHolePunch<F> {
+    token: Token,
+    socket: Option<UdpSocket>,
+    key: hmacsha256::Key,
+    our_nonce: u64,
+    peer_addrs: Vec<SocketAddr>,
+    syn: Vec<u8>,
+    peer_acked: bool,
+    acked_by: Option<SocketAddr>,
+    read_buf: [u8; 1024],
+    resend_ms: u64,
+    timeout: Timeout,
+    resend_timeout: Option<Timeout>,
+    finish: Option<F>,
+}
+
+impl<F> UdpHolePunch<F>
+    where F: FnOnce(&mut Core, &Poll, Option<(UdpSocket, SocketAddr)>) + Any
+{
+    /// Start punching a hole from `socket` to any of the peer's mapped `peer_addrs`. Messages are
+    /// authenticated with an HMAC keyed on the `secret` shared with the peer.
+    pub fn start(core: &mut Core,
+                 poll: &Poll,
+                 socket: UdpSocket,
+                 peer_addrs: Vec<SocketAddr>,
+                 secret: Vec<u8>,
+                 finish: F)
+                 -> Result<(), NatError> {
+        let token = core.get_new_token();
+        let local_addr = socket.local_addr()?;
+        let peer_addrs = peer_addrs
+            .iter()
+            .map(|addr| util::to_family_of(&local_addr, addr))
+            .collect();
+
+        let key = hmacsha256::Key(sha256::hash(&secret).0);
+        let our_nonce = rand::random();
+        let syn = serialise(&PunchMsg::Syn(our_nonce, false, auth(&key, SYN_TAG, our_nonce)))?;
+
+        let timeout = core.set_timeout(Duration::from_secs(TIMEOUT_SEC),
+                                       CoreTimer::new(token, TIMEOUT_TIMER_ID))?;
+
+        poll.register(&socket,
+                      token,
+                      Ready::error() | Ready::hup() | Ready::readable(),
+                      PollOpt::edge())?;
+
+        let mut state = UdpHolePunch {
+            token: token,
+            socket: Some(socket),
+            key: key,
+            our_nonce: our_nonce,
+            peer_addrs: peer_addrs,
+            syn: syn,
+            peer_acked: false,
+            acked_by: None,
+            read_buf: [0; 1024],
+            resend_ms: INITIAL_RESEND_MS,
+            timeout: timeout,
+            resend_timeout: None,
+            finish: Some(finish),
+        };
+        state.send_syns(core);
+
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+
+        Ok(())
+    }
+
+    fn send_syns(&mut self, core: &mut Core) {
+        {
+            let socket = unwrap!(self.socket.as_ref());
+            for peer_addr in &self.peer_addrs {
+                if let Err(e) = socket.send_to(&self.syn, peer_addr) {
+                    trace!("Could not send hole punch syn to {}: {:?}", peer_addr, e);
+                }
+            }
+        }
+        self.resend_timeout = core.set_timeout(Duration::from_millis(self.resend_ms),
+                                               CoreTimer::new(self.token, RESEND_TIMER_ID))
+            .ok();
+        self.resend_ms = cmp::min(self.resend_ms * 2, MAX_RESEND_MS);
+    }
+
+    fn read(&mut self, core: &mut Core, poll: &Poll) {
+        loop {
+            let (bytes_rxd, peer_addr) = match unwrap!(self.socket.as_ref())
+                      .recv_from(&mut self.read_buf) {
+                Ok(Some(res)) => res,
+                Ok(None) => return,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    debug!("Error reading from udp socket being hole punched: {:?}", e);
+                    return self.terminate(core, poll);
+                }
+            };
+
+            match deserialise(&self.read_buf[..bytes_rxd]) {
+                Ok(PunchMsg::Syn(nonce, acked, mac)) => {
+                    self.handle_syn(peer_addr, nonce, acked, &mac)
+                }
+                Ok(PunchMsg::Ack(nonce, mac)) => {
+                    if nonce == self.our_nonce && verify(&self.key, ACK_TAG, nonce, &mac) {
+                        self.handle_ack(core, peer_addr);
+                    }
+                }
+                Err(e) => trace!("Bogus message from {}: {:?}", peer_addr, e),
+            }
+
+            if self.peer_acked && self.acked_by.is_some() {
+                return self.done(core, poll);
+            }
+        }
+    }
+
+    fn handle_syn(&mut self, peer_addr: SocketAddr, nonce: u64, acked: bool, mac: &Mac) {
+        let tag = if acked { ACKED_SYN_TAG } else { SYN_TAG };
+        // Our own syn reflected back at us must not pass for the peer's
+        if nonce == self.our_nonce || !verify(&self.key, tag, nonce, mac) {
+            trace!("Ignoring unauthenticated hole punch syn from {}", peer_addr);
+            return;
+        }
+        if acked {
+            self.peer_acked = true;
+        }
+
+        let ack = match serialise(&PunchMsg::Ack(nonce, auth(&self.key, ACK_TAG, nonce))) {
+            Ok(ack) => ack,
+            Err(e) => {
+                debug!("Could not serialise hole punch ack: {:?}", e);
+                return;
+            }
+        };
+        // A lost ack is made up for by the peer resending its syn
+        if let Err(e) = unwrap!(self.socket.as_ref()).send_to(&ack, &peer_addr) {
+            trace!("Could not send hole punch ack to {}: {:?}", peer_addr, e);
+        }
+    }
+
+    fn handle_ack(&mut self, core: &mut Core, peer_addr: SocketAddr) {
+        if self.acked_by.is_some() {
+            return;
+        }
+        self.acked_by = Some(peer_addr);
+
+        // Tell the peer straight away, and only stay on for as long as it may need our acks
+        let mac = auth(&self.key, ACKED_SYN_TAG, self.our_nonce);
+        match serialise(&PunchMsg::Syn(self.our_nonce, true, mac)) {
+            Ok(syn) => self.syn = syn,
+            Err(e) => debug!("Could not serialise hole punch syn: {:?}", e),
+        }
+        let _ = core.cancel_timeout(&self.timeout);
+        if let Ok(timeout) = core.set_timeout(Duration::from_millis(LINGER_MS),
+                                              CoreTimer::new(self.token, TIMEOUT_TIMER_ID)) {
+            self.timeout = timeout;
+        }
+        if let Some(resend_timeout) = self.resend_timeout.take() {
+            let _ = core.cancel_timeout(&resend_timeout);
+        }
+        self.resend_ms = INITIAL_RESEND_MS;
+        self.send_syns(core);
+    }
+
+    fn done(&mut self, core: &mut Core, poll: &Poll) {
+        let _ = core.remove_state(self.token);
+        self.cancel_timeouts(core);
+
+        let socket = unwrap!(self.socket.take());
+        let _ = poll.deregister(&socket);
+        let peer_addr = unwrap!(self.acked_by);
+        (unwrap!(self.finish.take()))(core, poll, Some((socket, peer_addr)));
+    }
+
+    fn cancel_timeouts(&mut self, core: &mut Core) {
+        let _ = core.cancel_timeout(&self.timeout);
+        if let Some(resend_timeout) = self.resend_timeout.take() {
+            let _ = core.cancel_timeout(&resend_timeout);
+        }
+    }
+}
+
+impl<F> State for UdpHolePunch<F>
+    where F: FnOnce(&mut Core, &Poll, Option<(UdpSocket, SocketAddr)>) + Any
+{
+    fn name(&self) -> &'static str {
+        "UdpHolePunch"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        if kind.is_error() || kind.is_hup() {
+            self.terminate(core, poll);
+        } else if kind.is_readable() {
+            self.read(core, poll);
+        }
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, timer_id: u8) {
+        match timer_id {
+            RESEND_TIMER_ID => self.send_syns(core),
+            // Acked ourselves, so the hole is punched even if the peer never said it was acked
+            _ if self.acked_by.is_some() => self.done(core, poll),
+            _ => {
+                debug!("Udp hole punch to {:?} timed out", self.peer_addrs);
+                self.terminate(core, poll);
+            }
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        let _ = core.remove_state(self.token);
+        self.cancel_timeouts(core);
+
+        if let Some(socket) = self.socket.take() {
+            let _ = poll.deregister(&socket);
+        }
+        (unwrap!(self.finish.take()))(core, poll, None);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+fn auth(key: &hmacsha256::Key, tag: u8, nonce: u64) -> Mac {
+    hmacsha256::authenticate(&auth_data(tag, nonce), key).0
+}
+
+fn verify(key: &hmacsha256::Key, tag: u8, nonce: u64, mac: &Mac) -> bool {
+    hmacsha256::verify(&hmacsha256::Tag(*mac), &auth_data(tag, nonce), key)
+}
+
+fn auth_data(tag: u8, nonce: u64) -> Vec<u8> {
+    let mut data = Vec::with_capacity(9);
+    data.push(tag);
+    unwrap!(data.write_u64::<BigEndian>(nonce));
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auth_binds_key_tag_and_nonce() {
+        let key = hmacsha256::Key([1; hmacsha256::KEYBYTES]);
+        let mac = auth(&key, SYN_TAG, 7);
+        assert!(verify(&key, SYN_TAG, 7, &mac));
+        assert!(!verify(&hmacsha256::Key([2; hmacsha256::KEYBYTES]), SYN_TAG, 7, &mac));
+        assert!(!verify(&key, ACKED_SYN_TAG, 7, &mac));
+        assert!(!verify(&key, ACK_TAG, 7, &mac));
+        assert!(!verify(&key, SYN_TAG, 8, &mac));
+    }
+}
@@ -15,34 +15,233 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-use net2::TcpBuilder;
+use common::{SocketConfig, bind_to_device, mark_dscp, set_hop_limit, set_recv_buffer_size};
+use net2::{TcpBuilder, UdpBuilder};
 use std::io;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 
-pub fn new_reusably_bound_tcp_socket(local_addr: &SocketAddr) -> io::Result<TcpBuilder> {
+pub fn new_reusably_bound_tcp_socket(local_addr: &SocketAddr,
+                                     config: &SocketConfig)
+                                     -> io::Result<TcpBuilder> {
     let socket = match local_addr.ip() {
         IpAddr::V4(..) => TcpBuilder::new_v4()?,
-        IpAddr::V6(..) => TcpBuilder::new_v6()?,
+        IpAddr::V6(ref ip) => {
+            let socket = TcpBuilder::new_v6()?;
+            // A socket bound to the unspecified v6 address is our dual-stack socket. Windows
+            // defaults to v6-only so be explicit about it.
+            if ip.is_unspecified() {
+                let _ = socket.only_v6(false)?;
+            }
+            socket
+        }
     };
-    let _ = socket.reuse_address(true)?;
-    enable_so_reuseport(&socket)?;
+    enable_reuse(&socket)?;
+    // Everything else can only be set once the socket is connected, see `SocketConfig::apply`.
+    // The receive buffer is too, should the OS not let us now.
+    if let Some(size) = config.recv_buffer_size {
+        let _ = set_recv_buffer_size(&socket, size);
+    }
+    if let Some(ttl) = config.ttl {
+        if local_addr.is_ipv6() {
+            if let Err(e) = set_hop_limit(&socket, ttl) {
+                debug!("Could not set the hop limit to {}: {}", ttl, e);
+            }
+            // Only means something to the IPv4 traffic of a dual-stack socket
+            let _ = socket.ttl(ttl);
+        } else {
+            let _ = socket.ttl(ttl)?;
+        }
+    }
+    if let Some(dscp) = config.dscp {
+        let _ = mark_dscp(&socket, local_addr.is_ipv6(), dscp);
+    }
+    if let Some(ref device) = config.device {
+        bind_to_device(&socket, device)?;
+    }
     let _ = socket.bind(local_addr)?;
 
     Ok(socket)
 }
 
+pub fn new_reusably_bound_udp_socket(local_addr: &SocketAddr) -> io::Result<UdpSocket> {
+    let socket = match local_addr.ip() {
+        IpAddr::V4(..) => UdpBuilder::new_v4()?,
+        IpAddr::V6(ref ip) => {
+            let socket = UdpBuilder::new_v6()?;
+            if ip.is_unspecified() {
+                let _ = socket.only_v6(false)?;
+            }
+            socket
+        }
+    };
+    let _ = socket.reuse_address(true)?;
+    enable_so_reuseport_udp(&socket)?;
+    let socket = socket.bind(local_addr)?;
+
+    Ok(socket)
+}
+
+/// Whether no TCP socket holds `port`, found by binding it without address reuse - which fails
+/// even when the sockets holding it allow reuse.
+pub fn tcp_port_is_free(port: u16) -> bool {
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+    TcpBuilder::new_v4()
+        .and_then(|socket| socket.bind(&addr).map(|_| ()))
+        .is_ok()
+}
+
+/// Whether no UDP socket holds `port`, as `tcp_port_is_free` finds out.
+pub fn udp_port_is_free(port: u16) -> bool {
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+    UdpBuilder::new_v4()
+        .and_then(|socket| socket.bind(&addr))
+        .is_ok()
+}
+
+/// Let `sock` share its port with the other sockets of a rendezvous: the listener and every
+/// socket dialing the peer.
 #[cfg(target_family = "unix")]
-pub fn enable_so_reuseport(sock: &TcpBuilder) -> io::Result<()> {
+pub fn enable_reuse(sock: &TcpBuilder) -> io::Result<()> {
     use net2::unix::UnixTcpBuilderExt;
+    let _ = sock.reuse_address(true)?;
+    let _ = sock.reuse_port(true)?;
+    Ok(())
+}
+
+/// Let `sock` share its port with the other sockets of a rendezvous: the listener and every
+/// socket dialing the peer. `SO_REUSEADDR` alone does it on Windows, where there is no
+/// `SO_REUSEPORT`, but only as long as no socket on the port was given `SO_EXCLUSIVEADDRUSE`,
+/// which some libraries set by default. It is cleared first since the two cannot be combined.
+#[cfg(target_family = "windows")]
+#[allow(unsafe_code)]
+#[allow(trivial_casts, trivial_numeric_casts)]
+pub fn enable_reuse(sock: &TcpBuilder) -> io::Result<()> {
+    use libc::{c_char, c_int};
+    use std::mem;
+    use std::os::windows::io::AsRawSocket;
+    use winapi::SOCKET;
+
+    const SOL_SOCKET: c_int = 0xffff;
+    const SO_EXCLUSIVEADDRUSE: c_int = !0x0004;
+
+    #[link(name="ws2_32")]
+    extern "system" {
+        fn setsockopt(socket: SOCKET,
+                      level: c_int,
+                      name: c_int,
+                      value: *const c_char,
+                      len: c_int)
+                      -> c_int;
+    }
+
+    let off: c_int = 0;
+    let off_ptr: *const c_int = &off;
+    let res = unsafe {
+        setsockopt(sock.as_raw_socket() as SOCKET,
+                   SOL_SOCKET,
+                   SO_EXCLUSIVEADDRUSE,
+                   off_ptr as *const c_char,
+                   mem::size_of::<c_int>() as c_int)
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let _ = sock.reuse_address(true)?;
+    Ok(())
+}
+
+/// Whether connecting a socket sharing its port failed for reasons which may pass, so that the
+/// connect is worth retrying from the same port. Until the peer's NAT lets us in, our SYNs are
+/// refused or go unanswered. Windows also refuses to connect from a port whose last connection to
+/// the peer has not been torn down yet, and may report a connect still under way on it.
+pub fn connect_is_transient(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused |
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::TimedOut |
+        io::ErrorKind::AddrInUse |
+        io::ErrorKind::AddrNotAvailable => true,
+        _ => is_transient_os_error(e),
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn is_transient_os_error(_e: &io::Error) -> bool {
+    false
+}
+
+#[cfg(target_family = "windows")]
+fn is_transient_os_error(e: &io::Error) -> bool {
+    const WSAEINVAL: i32 = 10022;
+    const WSAEALREADY: i32 = 10037;
+    match e.raw_os_error() {
+        Some(WSAEINVAL) | Some(WSAEALREADY) => true,
+        _ => false,
+    }
+}
+
+#[cfg(target_family = "unix")]
+pub fn enable_so_reuseport_udp(sock: &UdpBuilder) -> io::Result<()> {
+    use net2::unix::UnixUdpBuilderExt;
     let _ = sock.reuse_port(true)?;
     Ok(())
 }
 
 #[cfg(target_family = "windows")]
-pub fn enable_so_reuseport(_sock: &TcpBuilder) -> io::Result<()> {
+pub fn enable_so_reuseport_udp(_sock: &UdpBuilder) -> io::Result<()> {
     Ok(())
 }
 
+/// Binds a reusable tcp socket to `port` which accepts both v4 and v6 traffic if the host has
+/// IPv6, falling back to a v4-only socket otherwise.
+pub fn new_reusably_bound_dual_stack_tcp_socket(port: u16,
+                                                config: &SocketConfig)
+                                                -> io::Result<TcpBuilder> {
+    let addr_v6 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)), port);
+    match new_reusably_bound_tcp_socket(&addr_v6, config) {
+        Ok(socket) => Ok(socket),
+        Err(e) => {
+            trace!("Could not bind dual-stack socket ({:?}), falling back to v4.", e);
+            let addr_v4 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+            new_reusably_bound_tcp_socket(&addr_v4, config)
+        }
+    }
+}
+
+/// Converts `addr` to the address family of a socket bound to `local_addr`, so a dual-stack
+/// socket can reach v4 peers via v4-mapped addresses.
+pub fn to_family_of(local_addr: &SocketAddr, addr: &SocketAddr) -> SocketAddr {
+    match (*local_addr, *addr) {
+        (SocketAddr::V6(..), SocketAddr::V4(ref addr_v4)) => {
+            SocketAddr::new(IpAddr::V6(addr_v4.ip().to_ipv6_mapped()), addr_v4.port())
+        }
+        _ => *addr,
+    }
+}
+
+/// Strips v4-mapped v6 addresses (`::ffff:a.b.c.d`), as seen by dual-stack sockets, down to the
+/// plain v4 address.
+pub fn unmap_ipv4(addr: &SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(ref addr_v6) = *addr {
+        let segments = addr_v6.ip().segments();
+        if segments[..5] == [0, 0, 0, 0, 0] && segments[5] == 0xffff {
+            let ip = Ipv4Addr::new((segments[6] >> 8) as u8,
+                                   segments[6] as u8,
+                                   (segments[7] >> 8) as u8,
+                                   segments[7] as u8);
+            return SocketAddr::new(IpAddr::V4(ip), addr_v6.port());
+        }
+    }
+    *addr
+}
+
+/// Returns true for v6 link-local (`fe80::/10`) addresses, which are useless to peers without a
+/// scope id.
+pub fn ipv6_addr_is_unicast_link_local(ipv6: &Ipv6Addr) -> bool {
+    (ipv6.segments()[0] & 0xffc0) == 0xfe80
+}
+
 /// A replacement for `IpAddr::is_global` while we wait for that to enter stable.
 pub fn ip_addr_is_global(ip: &IpAddr) -> bool {
     match *ip {
@@ -61,5 +260,48 @@ pub fn ipv4_addr_is_global(ipv4: &Ipv4Addr) -> bool {
 /// A replacement for `Ipv6Addr::is_global` while we wait for that to enter stable.
 pub fn ipv6_addr_is_global(ipv6: &Ipv6Addr) -> bool {
     // TODO(canndrew): This function is incomplete and may return false-positives.
-    !(ipv6.is_loopback() || ipv6.is_unspecified())
+    !(ipv6.is_loopback() || ipv6.is_unspecified() || ipv6_addr_is_unicast_link_local(ipv6))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn transient_connect_errors() {
+        assert!(connect_is_transient(&io::Error::from(io::ErrorKind::AddrInUse)));
+        assert!(connect_is_transient(&io::Error::from(io::ErrorKind::ConnectionRefused)));
+        assert!(!connect_is_transient(&io::Error::from(io::ErrorKind::PermissionDenied)));
+    }
+
+    #[test]
+    fn v4_mapped_addresses() {
+        let v4 = unwrap!(SocketAddr::from_str("1.2.3.4:5678"));
+        let v6 = unwrap!(SocketAddr::from_str("[::]:0"));
+        let mapped = to_family_of(&v6, &v4);
+        assert_eq!(mapped, unwrap!(SocketAddr::from_str("[::ffff:1.2.3.4]:5678")));
+        assert_eq!(unmap_ipv4(&mapped), v4);
+        assert_eq!(to_family_of(&v4, &v4), v4);
+
+        let global_v6 = unwrap!(SocketAddr::from_str("[2001:db8::1]:5678"));
+        assert_eq!(unmap_ipv4(&global_v6), global_v6);
+    }
+
+    #[test]
+    fn ports_held_reusably() {
+        let config = SocketConfig::default();
+        let tcp = unwrap!(new_reusably_bound_dual_stack_tcp_socket(0, &config));
+        let tcp_port = unwrap!(tcp.local_addr()).port();
+        let any = unwrap!(SocketAddr::from_str("0.0.0.0:0"));
+        let udp = unwrap!(new_reusably_bound_udp_socket(&any));
+        let udp_port = unwrap!(udp.local_addr()).port();
+        assert!(!tcp_port_is_free(tcp_port));
+        assert!(!udp_port_is_free(udp_port));
+
+        drop(tcp);
+        drop(udp);
+        assert!(tcp_port_is_free(tcp_port));
+        assert!(udp_port_is_free(udp_port));
+    }
 }
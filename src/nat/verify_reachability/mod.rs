@@ -0,0 +1,169 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use self::request_connect_back::RequestConnectBack;
+use common::{Core, State};
+use mio::{Poll, Token};
+use nat::{MappingContext, util};
+use nat::mapped_addr::MappedAddr;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+mod request_connect_back;
+
+/// A state which checks which of the external addresses of a listening socket peers can actually
+/// connect to - some ISPs block inbound connections whatever the NAT in front of us allows.
+///
+/// A helper peer is asked to connect back to each global address. Addresses it reached are marked
+/// `verified` and those it could not reach are told apart, but all are kept in the order they came
+/// in, as the helper's own network may be what kept it out. Addresses verified already, as those
+/// declared in the config, are taken at our word.
+pub struct VerifyReachability<F> {
+    token: Token,
+    children: HashMap<Token, usize>,
+    mapped_addrs: Vec<MappedAddr>,
+    unreachable: Vec<SocketAddr>,
+    finish: Option<F>,
+}
+
+impl<F> VerifyReachability<F>
+    where F: FnOnce(&mut Core, &Poll, Vec<MappedAddr>, Vec<SocketAddr>) + Any
+{
+    /// Start verifying `mapped_addrs`, which must belong to a socket which is listening already.
+    pub fn start(core: &mut Core,
+                 poll: &Poll,
+                 mapped_addrs: Vec<MappedAddr>,
+                 mc: &MappingContext,
+                 finish: F) {
+        let token = core.get_new_token();
+        let timeout = mc.mapping_config().verify_timeout;
+
+        let state = Rc::new(RefCell::new(VerifyReachability {
+                                             token: token,
+                                             children: HashMap::with_capacity(mapped_addrs.len()),
+                                             mapped_addrs: mapped_addrs,
+                                             unreachable: Vec::new(),
+                                             finish: Some(finish),
+                                         }));
+
+        let candidates: Vec<_> = state
+            .borrow()
+            .mapped_addrs
+            .iter()
+            .enumerate()
+            .filter(|&(_, mapped)| !mapped.verified && util::ip_addr_is_global(&mapped.addr.ip()))
+            .map(|(index, mapped)| (index, mapped.addr))
+            .collect();
+
+        // Spread the work over the helpers, each address going to one of its own family.
+        let peer_stuns = mc.peer_stuns();
+        for (nth, (index, addr)) in candidates.into_iter().enumerate() {
+            let helpers: Vec<_> = peer_stuns
+                .iter()
+                .filter(|stun| stun.is_ipv4() == addr.is_ipv4())
+                .collect();
+            if helpers.is_empty() {
+                continue;
+            }
+
+            let self_weak = Rc::downgrade(&state);
+            let handler = move |core: &mut Core, poll: &Poll, child_token, res| {
+                if let Some(self_rc) = self_weak.upgrade() {
+                    self_rc
+                        .borrow_mut()
+                        .handle_resp(core, poll, child_token, res)
+                }
+            };
+
+            match RequestConnectBack::start(core,
+                                            poll,
+                                            helpers[nth % helpers.len()],
+                                            addr,
+                                            timeout,
+                                            Box::new(handler)) {
+                Ok(child) => {
+                    let _ = state.borrow_mut().children.insert(child, index);
+                }
+                Err(e) => debug!("Could not ask a peer to connect back to {}: {:?}", addr, e),
+            }
+        }
+
+        if state.borrow().children.is_empty() {
+            state.borrow_mut().terminate(core, poll);
+            return;
+        }
+
+        let _ = core.insert_state(token, state);
+    }
+
+    fn handle_resp(&mut self, core: &mut Core, poll: &Poll, child: Token, res: Result<bool, ()>) {
+        let index = match self.children.remove(&child) {
+            Some(index) => index,
+            None => return,
+        };
+        match res {
+            Ok(true) => {
+                // The peer got through without us sending to it first, so nothing filters it out
+                let mapped = &mut self.mapped_addrs[index];
+                mapped.verified = true;
+                mapped.nat_restricted = false;
+            }
+            Ok(false) => {
+                let addr = self.mapped_addrs[index].addr;
+                trace!("{} could not be reached by our peer", addr);
+                self.unreachable.push(addr);
+            }
+            Err(()) => (),
+        }
+        if self.children.is_empty() {
+            self.terminate(core, poll);
+        }
+    }
+}
+
+impl<F> State for VerifyReachability<F>
+    where F: FnOnce(&mut Core, &Poll, Vec<MappedAddr>, Vec<SocketAddr>) + Any
+{
+    fn name(&self) -> &'static str {
+        "VerifyReachability"
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        for (token, _) in self.children.drain() {
+            let child = match core.get_state(token) {
+                Some(state) => state,
+                None => continue,
+            };
+
+            child.borrow_mut().terminate(core, poll);
+        }
+        let _ = core.remove_state(self.token);
+
+        let mapped_addrs = self.mapped_addrs.drain(..).collect();
+        let unreachable = self.unreachable.drain(..).collect();
+        if let Some(finish) = self.finish.take() {
+            finish(core, poll, mapped_addrs, unreachable);
+        }
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
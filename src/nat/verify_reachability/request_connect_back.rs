@@ -0,0 +1,126 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::{Core, CoreTimer, Message, Priority, Socket, State, Timeout};
+use mio::{Poll, PollOpt, Ready, Token};
+use nat::NatError;
+use std::any::Any;
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Called with whether the helper could connect to the address, or `Err` if it did not say.
+pub type Finish = Box<FnMut(&mut Core, &Poll, Token, Result<bool, ()>)>;
+
+/// Asks a peer's echo service to connect to one of our external addresses and report back.
+pub struct RequestConnectBack {
+    token: Token,
+    socket: Socket,
+    request: Option<(Message, Priority)>,
+    timeout: Timeout,
+    finish: Finish,
+}
+
+impl RequestConnectBack {
+    pub fn start(core: &mut Core,
+                 poll: &Poll,
+                 peer_stun: &SocketAddr,
+                 our_ext_addr: SocketAddr,
+                 timeout: Duration,
+                 finish: Finish)
+                 -> Result<Token, NatError> {
+        let socket = Socket::dial(&*core.transport(), peer_stun)?;
+        let token = core.get_new_token();
+
+        poll.register(&socket,
+                      token,
+                      Ready::error() | Ready::hup() | Ready::writable(),
+                      PollOpt::edge())?;
+
+        let timeout = core.set_timeout(timeout, CoreTimer::new(token, 0))?;
+
+        let state = RequestConnectBack {
+            token: token,
+            socket: socket,
+            request: Some((Message::ReachabilityReq(our_ext_addr), 0)),
+            timeout: timeout,
+            finish: finish,
+        };
+
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+
+        Ok(token)
+    }
+
+    fn write(&mut self, core: &mut Core, poll: &Poll, msg: Option<(Message, Priority)>) {
+        if self.socket.write(poll, self.token, msg).is_err() {
+            self.handle_result(core, poll, Err(()));
+        }
+    }
+
+    fn receive_response(&mut self, core: &mut Core, poll: &Poll) {
+        match self.socket.read::<Message>() {
+            Ok(Some(Message::ReachabilityResp(reachable))) => {
+                self.handle_result(core, poll, Ok(reachable))
+            }
+            Ok(None) => (),
+            Ok(Some(_)) | Err(_) => self.handle_result(core, poll, Err(())),
+        }
+    }
+
+    fn handle_result(&mut self, core: &mut Core, poll: &Poll, res: Result<bool, ()>) {
+        self.terminate(core, poll);
+        let token = self.token;
+        (*self.finish)(core, poll, token, res);
+    }
+}
+
+impl State for RequestConnectBack {
+    fn name(&self) -> &'static str {
+        "RequestConnectBack"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        if kind.is_error() || kind.is_hup() {
+            self.handle_result(core, poll, Err(()));
+        } else {
+            if kind.is_writable() {
+                let req = self.request.take();
+                self.write(core, poll, req);
+            }
+            if kind.is_readable() && core.get_state(self.token).is_some() {
+                self.receive_response(core, poll)
+            }
+        }
+    }
+
+    fn timeout(&mut self, core: &mut Core, poll: &Poll, _timer_id: u8) {
+        trace!("Peer stun did not report back on our reachability in time");
+        self.handle_result(core, poll, Err(()));
+    }
+
+    fn terminate(&mut self, core: &mut Core, poll: &Poll) {
+        let _ = core.cancel_timeout(&self.timeout);
+        let _ = core.remove_state(self.token);
+        let _ = poll.deregister(&self.socket);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
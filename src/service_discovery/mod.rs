@@ -19,39 +19,49 @@ pub use self::errors::ServiceDiscoveryError;
 
 mod errors;
 
-use common::{Core, State};
+use common::{Core, NameHash, State, lock};
+use common::get_if_addrs::{IfAddr, get_if_addrs};
 use maidsafe_utilities::serialisation::{deserialise, serialise};
 use mio::{Poll, PollOpt, Ready, Token};
 use mio::udp::UdpSocket;
+use net2::UdpBuilder;
 use rand;
 use std::any::Any;
 use std::cell::RefCell;
 use std::collections::VecDeque;
-use std::io::ErrorKind;
-use std::net::SocketAddr;
+use std::io::{self, ErrorKind};
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
 use std::rc::Rc;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::Sender;
 use std::u16;
 
+// Variants are only ever appended, so that nodes predating them still understand the rest.
 #[derive(Serialize, Deserialize)]
 enum DiscoveryMsg {
+    // As sent by nodes predating network genesis values, which cannot tell networks apart.
     Request { guid: u64 },
     Response(Vec<SocketAddr>),
+    NetworkRequest { guid: u64, name_hash: NameHash },
 }
 
 pub struct ServiceDiscovery {
     token: Token,
     socket: UdpSocket,
     remote_addr: SocketAddr,
+    v6: Option<V6>,
     listen: bool,
     read_buf: [u8; 1024],
     our_listeners: Arc<Mutex<Vec<SocketAddr>>>,
-    seek_peers_req: Vec<u8>,
+    seek_peers_reqs: Vec<Vec<u8>>,
     reply_to: VecDeque<SocketAddr>,
     observers: Vec<Sender<Vec<SocketAddr>>>,
     guid: u64,
+    name_hash: NameHash,
+    // Whether nodes predating genesis values can be of our network, as they are unless it has
+    // one, in which case they are sought and answered with the request they know too.
+    compatible: bool,
 }
 
 impl ServiceDiscovery {
@@ -59,25 +69,46 @@ impl ServiceDiscovery {
                  poll: &Poll,
                  our_listeners: Arc<Mutex<Vec<SocketAddr>>>,
                  token: Token,
-                 port: u16)
+                 port: u16,
+                 name_hash: NameHash,
+                 compatible: bool)
                  -> Result<(), ServiceDiscoveryError> {
         let udp_socket = get_socket(port)?;
         udp_socket.set_broadcast(true)?;
 
         let guid = rand::random();
+        let mut seek_peers_reqs = vec![serialise(&DiscoveryMsg::NetworkRequest {
+                                                     guid: guid,
+                                                     name_hash: name_hash,
+                                                 })?];
+        if compatible {
+            seek_peers_reqs.push(serialise(&DiscoveryMsg::Request { guid: guid })?);
+        }
         let remote_addr = SocketAddr::from_str(&format!("255.255.255.255:{}", port))?;
 
+        // Peers on an IPv6-only LAN can only be reached over IPv6, which not every host has
+        let v6 = match V6::start(core, poll, token, udp_socket.local_addr()?.port(), port) {
+            Ok(v6) => Some(v6),
+            Err(e) => {
+                debug!("ServiceDiscovery running without IPv6: {:?}", e);
+                None
+            }
+        };
+
         let service_discovery = ServiceDiscovery {
             token: token,
             socket: udp_socket,
             remote_addr: remote_addr,
+            v6: v6,
             listen: false,
             read_buf: [0; 1024],
             our_listeners: our_listeners,
-            seek_peers_req: serialise(&DiscoveryMsg::Request { guid: guid })?,
+            seek_peers_reqs: seek_peers_reqs,
             reply_to: VecDeque::new(),
             observers: Vec::new(),
             guid: guid,
+            name_hash: name_hash,
+            compatible: compatible,
         };
 
         poll.register(&service_discovery.socket,
@@ -96,11 +127,30 @@ impl ServiceDiscovery {
         self.listen = listen;
     }
 
-    /// Interrogate the network to find peers.
+    /// Interrogate the network to find peers, over IPv4 and IPv6 if available. Succeeds if
+    /// either could be sent out.
     pub fn seek_peers(&mut self) -> Result<(), ServiceDiscoveryError> {
-        let _ = self.socket
-            .send_to(&self.seek_peers_req, &self.remote_addr)?;
-        Ok(())
+        let mut res = Ok(());
+        let mut sent_v6 = false;
+        for req in &self.seek_peers_reqs {
+            if let Err(e) = self.socket.send_to(req, &self.remote_addr) {
+                res = Err(e);
+            }
+            if let Some(ref v6) = self.v6 {
+                for remote_addr in &v6.remote_addrs {
+                    match v6.socket.send_to(req, remote_addr) {
+                        Ok(_) => sent_v6 = true,
+                        Err(e) => {
+                            debug!("Could not seek peers over IPv6 at {}: {:?}", remote_addr, e)
+                        }
+                    }
+                }
+            }
+        }
+        if sent_v6 {
+            return Ok(());
+        }
+        Ok(res?)
     }
 
     /// Register service discovery observer
@@ -108,8 +158,16 @@ impl ServiceDiscovery {
         self.observers.push(obs);
     }
 
-    fn read(&mut self, core: &mut Core, poll: &Poll) {
-        let (bytes_rxd, peer_addr) = match self.socket.recv_from(&mut self.read_buf) {
+    fn read(&mut self, core: &mut Core, poll: &Poll, v6: bool) {
+        let res = {
+            let socket = match (v6, self.v6.as_ref()) {
+                (false, _) => &self.socket,
+                (true, Some(v6)) => &v6.socket,
+                (true, None) => return,
+            };
+            socket.recv_from(&mut self.read_buf)
+        };
+        let (bytes_rxd, peer_addr) = match res {
             Ok(Some((bytes_rxd, peer_addr))) => (bytes_rxd, peer_addr),
             Ok(None) => return,
             Err(ref e) if e.kind() == ErrorKind::Interrupted => return,
@@ -128,20 +186,26 @@ impl ServiceDiscovery {
             }
         };
 
-        match msg {
-            DiscoveryMsg::Request { guid } => {
-                if self.listen && self.guid != guid {
-                    self.reply_to.push_back(peer_addr);
-                    self.write(core, poll)
-                }
+        // Peers seeking another network are left to find their own, and nobody is answered while
+        // the network is paused
+        let answer = match msg {
+            DiscoveryMsg::Request { guid } => self.compatible && self.guid != guid,
+            DiscoveryMsg::NetworkRequest { guid, name_hash } => {
+                self.guid != guid && self.name_hash == name_hash
             }
             DiscoveryMsg::Response(peer_listeners) => {
                 self.observers
                     .retain(|obs| obs.send(peer_listeners.clone()).is_ok());
+                false
             }
+        };
+        if answer && self.listen && !core.is_paused() {
+            self.reply_to.push_back(peer_addr);
+            self.write(core, poll)
         }
     }
 
+
     fn write(&mut self, core: &mut Core, poll: &Poll) {
         if let Err(e) = self.write_impl(poll) {
             debug!("Error in ServiceDiscovery write: {:?}", e);
@@ -150,7 +214,7 @@ impl ServiceDiscovery {
     }
 
     fn write_impl(&mut self, poll: &Poll) -> Result<(), ServiceDiscoveryError> {
-        let our_current_listeners = unwrap!(self.our_listeners.lock())
+        let our_current_listeners = lock(&self.our_listeners)
             .iter()
             .cloned()
             .collect();
@@ -159,7 +223,13 @@ impl ServiceDiscovery {
         let serialised_resp = serialise(&resp)?;
 
         if let Some(peer_addr) = self.reply_to.pop_front() {
-            match self.socket.send_to(&serialised_resp[..], &peer_addr) {
+            // Requests that came in over IPv6 are answered over it too
+            let socket = match (peer_addr, self.v6.as_ref()) {
+                (SocketAddr::V4(..), _) => &self.socket,
+                (SocketAddr::V6(..), Some(v6)) => &v6.socket,
+                (SocketAddr::V6(..), None) => return Ok(()),
+            };
+            match socket.send_to(&serialised_resp[..], &peer_addr) {
                 // UDP is all or none so if anything is written we consider it written
                 Ok(Some(_)) => (),
                 Ok(None) => self.reply_to.push_front(peer_addr),
@@ -177,17 +247,40 @@ impl ServiceDiscovery {
             Ready::error() | Ready::hup() | Ready::readable() | Ready::writable()
         };
 
+        if let Some(ref v6) = self.v6 {
+            poll.reregister(&v6.socket, v6.token, kind, PollOpt::edge())?;
+        }
         Ok(poll.reregister(&self.socket, self.token, kind, PollOpt::edge())?)
     }
+
+    fn ready_v6(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        if kind.is_error() || kind.is_hup() {
+            debug!("ServiceDiscovery lost its IPv6 socket");
+            if let Some(v6) = self.v6.take() {
+                v6.terminate(core, poll);
+            }
+        } else {
+            if kind.is_readable() {
+                self.read(core, poll, true);
+            }
+            if kind.is_writable() {
+                self.write(core, poll);
+            }
+        }
+    }
 }
 
 impl State for ServiceDiscovery {
+    fn name(&self) -> &'static str {
+        "ServiceDiscovery"
+    }
+
     fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
         if kind.is_error() || kind.is_hup() {
             self.terminate(core, poll);
         } else {
             if kind.is_readable() {
-                self.read(core, poll);
+                self.read(core, poll, false);
             }
             if kind.is_writable() {
                 self.write(core, poll);
@@ -198,6 +291,100 @@ impl State for ServiceDiscovery {
     fn terminate(&mut self, core: &mut Core, poll: &Poll) {
         let _ = poll.deregister(&self.socket);
         let _ = core.remove_state(self.token);
+        if let Some(v6) = self.v6.take() {
+            v6.terminate(core, poll);
+        }
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+// The IPv6 socket, which needs a token of its own. The group is link-local, so it is joined and
+// sent to on every interface with IPv6.
+struct V6 {
+    token: Token,
+    socket: UdpSocket,
+    remote_addrs: Vec<SocketAddr>,
+}
+
+impl V6 {
+    fn start(core: &mut Core,
+             poll: &Poll,
+             discovery: Token,
+             local_port: u16,
+             remote_port: u16)
+             -> Result<Self, ServiceDiscoveryError> {
+        // Link-local multicast group the beacon is sent to, IPv6 having no broadcast
+        let group = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0x5afe, 0xc457);
+        let socket = get_socket_v6(local_port)?;
+        let mut indices: Vec<u32> = get_if_addrs()?
+            .into_iter()
+            .filter_map(|interface| match interface.addr {
+                            IfAddr::V6(ref addr) if !addr.ip.is_loopback() => Some(interface.index),
+                            _ => None,
+                        })
+            .filter(|&index| index != 0)
+            .collect();
+        indices.sort();
+        indices.dedup();
+        indices.retain(|&index| match socket.join_multicast_v6(&group, index) {
+                           Ok(()) => true,
+                           Err(e) => {
+                               debug!("Could not join the IPv6 beacon group on interface {}: {:?}",
+                                      index,
+                                      e);
+                               false
+                           }
+                       });
+        if indices.is_empty() {
+            return Err(From::from(io::Error::new(ErrorKind::NotFound,
+                                                 "No interface to join the IPv6 beacon group on")));
+        }
+
+        let token = core.get_new_token();
+        poll.register(&socket,
+                      token,
+                      Ready::error() | Ready::hup() | Ready::readable(),
+                      PollOpt::edge())?;
+        let _ = core.insert_state(token, Rc::new(RefCell::new(V6Events { discovery: discovery })));
+
+        Ok(V6 {
+               token: token,
+               socket: socket,
+               remote_addrs: indices
+                   .into_iter()
+                   .map(|index| SocketAddr::V6(SocketAddrV6::new(group, remote_port, 0, index)))
+                   .collect(),
+           })
+    }
+
+    fn terminate(self, core: &mut Core, poll: &Poll) {
+        let _ = poll.deregister(&self.socket);
+        let _ = core.remove_state(self.token);
+    }
+}
+
+// Hands the events of the IPv6 socket on to the `ServiceDiscovery` it belongs to.
+struct V6Events {
+    discovery: Token,
+}
+
+impl State for V6Events {
+    fn name(&self) -> &'static str {
+        "V6Events"
+    }
+
+    fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
+        let state = match core.get_state(self.discovery) {
+            Some(state) => state,
+            None => return,
+        };
+        let mut state = state.borrow_mut();
+        if let Some(service_discovery) = state.as_any().downcast_mut::<ServiceDiscovery>() {
+            service_discovery.ready_v6(core, poll, kind);
+        }
     }
 
     fn as_any(&mut self) -> &mut Any {
@@ -219,10 +406,19 @@ fn get_socket(mut port: u16) -> Result<UdpSocket, ServiceDiscoveryError> {
     res
 }
 
+// Only v6, or it would clash with the IPv4 socket on the same port.
+fn get_socket_v6(port: u16) -> io::Result<UdpSocket> {
+    let socket = UdpBuilder::new_v6()?;
+    let _ = socket.only_v6(true)?;
+    let socket = socket.bind(("::", port))?;
+    UdpSocket::from_socket(socket)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use common::{self, CoreMessage};
+    use maidsafe_utilities::serialisation::serialise;
     use mio::Token;
     use std::{net, thread};
     use std::str::FromStr;
@@ -230,76 +426,109 @@ mod tests {
     use std::sync::mpsc;
     use std::time::Duration;
 
-    #[test]
-    fn service_discovery() {
-        const SERVICE_DISCOVERY_TOKEN: usize = 0;
+    const SERVICE_DISCOVERY_TOKEN: usize = 0;
 
-        // Poll-0
-        let el0 = unwrap!(common::spawn_event_loop(SERVICE_DISCOVERY_TOKEN + 1, Some("EL0")),
-                          "Could not run el0");
+    // Start a listening `ServiceDiscovery` on `port` with `name_hash`, returning the event loop
+    // it runs on along with the listeners it answers with.
+    fn listen(port: u16,
+              name_hash: NameHash,
+              compatible: bool)
+              -> (common::EventLoop, Vec<net::SocketAddr>) {
+        let el = unwrap!(common::spawn_event_loop(SERVICE_DISCOVERY_TOKEN + 1, Some("EL0")),
+                         "Could not run el0");
 
         let addr = unwrap!(net::SocketAddr::from_str("138.139.140.150:54321"));
-        let listeners_0 = Arc::new(Mutex::new(vec![addr]));
-        let listeners_0_clone = listeners_0.clone();
-
-        // ServiceDiscovery-0
-        {
-            let token_0 = Token(SERVICE_DISCOVERY_TOKEN);
-            unwrap!(el0.send(CoreMessage::new(move |core, poll| {
-                unwrap!(ServiceDiscovery::start(core, poll, listeners_0_clone, token_0, 65530),
-                        "Could not spawn ServiceDiscovery_0");
-            })),
-                    "Could not send to el0");
-
-            // Start listening for peers
-            unwrap!(el0.send(CoreMessage::new(move |core, _| {
-                let state = unwrap!(core.get_state(token_0));
-                let mut inner = state.borrow_mut();
-                unwrap!(inner.as_any().downcast_mut::<ServiceDiscovery>()).set_listen(true);
-            })));
-        }
+        let listeners = Arc::new(Mutex::new(vec![addr]));
+        let token = Token(SERVICE_DISCOVERY_TOKEN);
+        unwrap!(el.send(CoreMessage::new(move |core, poll| {
+            unwrap!(ServiceDiscovery::start(core,
+                                            poll,
+                                            listeners,
+                                            token,
+                                            port,
+                                            name_hash,
+                                            compatible),
+                    "Could not spawn ServiceDiscovery_0");
+        })),
+                "Could not send to el0");
+
+        // Start listening for peers
+        unwrap!(el.send(CoreMessage::new(move |core, _| {
+            let state = unwrap!(core.get_state(token));
+            let mut inner = state.borrow_mut();
+            unwrap!(inner.as_any().downcast_mut::<ServiceDiscovery>()).set_listen(true);
+        })));
 
         thread::sleep(Duration::from_millis(100));
+        (el, vec![addr])
+    }
 
-        // Poll-1
-        let el1 = unwrap!(common::spawn_event_loop(SERVICE_DISCOVERY_TOKEN + 1, Some("EL1")),
-                          "Could not run el1");
+    // Seek peers on `port` with `name_hash`, returning the listeners of the first to answer.
+    fn seek(port: u16, name_hash: NameHash, compatible: bool) -> Option<Vec<net::SocketAddr>> {
+        let el = unwrap!(common::spawn_event_loop(SERVICE_DISCOVERY_TOKEN + 1, Some("EL1")),
+                         "Could not run el1");
 
         let (tx, rx) = mpsc::channel();
+        let listeners = Arc::new(Mutex::new(vec![]));
+        let token = Token(SERVICE_DISCOVERY_TOKEN);
+        unwrap!(el.send(CoreMessage::new(move |core, poll| {
+            unwrap!(ServiceDiscovery::start(core,
+                                            poll,
+                                            listeners,
+                                            token,
+                                            port,
+                                            name_hash,
+                                            compatible),
+                    "Could not spawn ServiceDiscovery_1");
+        })),
+                "Could not send to el1");
+
+        // Register observer and seek peers
+        unwrap!(el.send(CoreMessage::new(move |core, _| {
+            let state = unwrap!(core.get_state(token));
+            let mut inner = state.borrow_mut();
+            let sd = unwrap!(inner.as_any().downcast_mut::<ServiceDiscovery>());
+            sd.register_observer(tx);
+            unwrap!(sd.seek_peers());
+        })),
+                "Could not send to el1");
+
+        rx.recv_timeout(Duration::from_secs(2)).ok()
+    }
+
+    #[test]
+    fn service_discovery() {
+        let (_el0, listeners) = listen(65530, [0; 32], true);
+        assert_eq!(seek(65530, [0; 32], true), Some(listeners));
+    }
 
-        // ServiceDiscovery-1
-        {
-            let listeners_1 = Arc::new(Mutex::new(vec![]));
-            let token_1 = Token(SERVICE_DISCOVERY_TOKEN);
-            unwrap!(el1.send(CoreMessage::new(move |core, poll| {
-                                                  unwrap!(ServiceDiscovery::start(core,
-                                                                                  poll,
-                                                                                  listeners_1,
-                                                                                  token_1,
-                                                                                  65530),
-                                                          "Could not spawn ServiceDiscovery_1");
-                                              })),
-                    "Could not send to el1");
-
-            // Register observer
-            unwrap!(el1.send(CoreMessage::new(move |core, _| {
-                let state = unwrap!(core.get_state(token_1));
-                let mut inner = state.borrow_mut();
-                unwrap!(inner.as_any().downcast_mut::<ServiceDiscovery>()).register_observer(tx);
-            })));
-
-            // Seek peers
-            unwrap!(el1.send(CoreMessage::new(move |core, _| {
-                        let state = unwrap!(core.get_state(token_1));
-                        let mut inner = state.borrow_mut();
-                        let sd = unwrap!(inner.as_any().downcast_mut::<ServiceDiscovery>());
-                        unwrap!(sd.seek_peers());
-                    })),
-                    "Could not send to el1");
+    #[test]
+    fn network_isolation() {
+        // Networks told apart by their genesis values
+        let (_el0, listeners) = listen(65520, [1; 32], false);
+        assert_eq!(seek(65520, [2; 32], false), None);
+        assert_eq!(seek(65520, [1; 32], false), Some(listeners));
+    }
+
+    #[test]
+    fn older_nodes() {
+        let (_el0, listeners) = listen(65510, [0; 32], true);
+
+        // A request as nodes predating genesis values send it
+        let socket = unwrap!(net::UdpSocket::bind("127.0.0.1:0"));
+        unwrap!(socket.set_read_timeout(Some(Duration::from_secs(2))));
+        let req = unwrap!(serialise(&DiscoveryMsg::Request { guid: 1 }));
+        let _ = unwrap!(socket.send_to(&req, "127.0.0.1:65510"));
+        let mut buf = [0; 1024];
+        let len = unwrap!(socket.recv(&mut buf));
+        match unwrap!(deserialise(&buf[..len])) {
+            DiscoveryMsg::Response(peer_listeners) => assert_eq!(peer_listeners, listeners),
+            _ => panic!("Unexpected message"),
         }
 
-        let peer_listeners = unwrap!(rx.recv());
-        assert_eq!(peer_listeners.into_iter().collect::<Vec<_>>(),
-                   *unwrap!(listeners_0.lock()));
+        // Which a network with a genesis value leaves unanswered
+        let (_el1, _) = listen(65500, [1; 32], false);
+        let _ = unwrap!(socket.send_to(&req, "127.0.0.1:65500"));
+        assert!(socket.recv(&mut buf).is_err());
     }
 }
@@ -0,0 +1,134 @@
+// Copyright 2017 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use common::lock;
+use maidsafe_utilities::thread::{self, Joiner};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+/// Something with data held up for it, to be woken once that is due. Woken with its lock held,
+/// so whoever checks what is due under the same lock cannot miss the wakeup.
+pub trait Wake: Send {
+    /// Have the reader look for what has arrived.
+    fn wake(&self);
+}
+
+/// Wakes each scheduled target once its time comes. By the real clock that is done on a thread of
+/// its own which ends once this is dropped. By a manual clock time stands still until `advance`
+/// moves it on, so that what arrives when does not depend on how fast the test runs.
+pub enum Deliveries {
+    Real {
+        tx: Sender<Delivery>,
+        _joiner: Joiner,
+    },
+    Manual {
+        now: Instant,
+        pending: Vec<Delivery>,
+    },
+}
+
+impl Deliveries {
+    /// Go by the real clock, on a thread named `name`.
+    pub fn new(name: &'static str) -> Self {
+        let (tx, rx) = mpsc::channel();
+        Deliveries::Real {
+            tx: tx,
+            _joiner: thread::named(name, move || run(rx)),
+        }
+    }
+
+    /// Go by a clock which only moves on `advance`.
+    pub fn manual() -> Self {
+        Deliveries::Manual {
+            now: Instant::now(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// The time by the clock gone by.
+    pub fn now(&self) -> Instant {
+        match *self {
+            Deliveries::Real { .. } => Instant::now(),
+            Deliveries::Manual { now, .. } => now,
+        }
+    }
+
+    /// Wake `target` once `at` has passed.
+    pub fn schedule(&mut self, at: Instant, target: Arc<Mutex<Wake>>) {
+        let delivery = Delivery {
+            at: at,
+            target: target,
+        };
+        match *self {
+            Deliveries::Real { ref tx, .. } => {
+                let _ = tx.send(delivery);
+            }
+            Deliveries::Manual { now, ref mut pending } => {
+                if at <= now {
+                    lock(&delivery.target).wake();
+                } else {
+                    pending.push(delivery);
+                }
+            }
+        }
+    }
+
+    /// Move a manual clock on by `by`, waking whatever has come due. Does nothing by the real
+    /// clock.
+    pub fn advance(&mut self, by: Duration) {
+        if let Deliveries::Manual { ref mut now, ref mut pending } = *self {
+            *now += by;
+            wake_due(pending, *now);
+        }
+    }
+}
+
+pub struct Delivery {
+    at: Instant,
+    target: Arc<Mutex<Wake>>,
+}
+
+fn wake_due(pending: &mut Vec<Delivery>, now: Instant) {
+    let mut index = 0;
+    while index < pending.len() {
+        if pending[index].at <= now {
+            let delivery = pending.swap_remove(index);
+            lock(&delivery.target).wake();
+        } else {
+            index += 1;
+        }
+    }
+}
+
+fn run(rx: Receiver<Delivery>) {
+    let mut pending: Vec<Delivery> = Vec::new();
+    loop {
+        let now = Instant::now();
+        wake_due(&mut pending, now);
+
+        let res = match pending.iter().map(|delivery| delivery.at).min() {
+            Some(at) => rx.recv_timeout(at - now),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match res {
+            Ok(delivery) => pending.push(delivery),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
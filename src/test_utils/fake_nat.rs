@@ -0,0 +1,131 @@
+// Copyright 2017 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+/// How a scripted router or STUN-like peer answers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Answer {
+    /// How long it takes to answer, as timed by the event loop.
+    pub latency: Duration,
+    /// Whether it answers that it cannot help rather than with our external address.
+    pub fails: bool,
+}
+
+/// What a service started with `Service::with_fake_nat` finds out about its network: the
+/// interfaces it has, the router forwarding its ports, and the external IPs the STUN-like peers
+/// would report, each answering after the latency or with the failure scripted here. Real
+/// gateways and peers are never asked, and the scripted IPs do not go stale.
+///
+/// Only the answers are scripted: the sockets being mapped, and those hole punched or connected
+/// through the addresses found, are still real ones bound on the host.
+#[derive(Clone, Debug, Default)]
+pub struct FakeNat {
+    ifv4s: Vec<(Ipv4Addr, Ipv4Addr)>,
+    ifv6s: Vec<Ipv6Addr>,
+    ext_ips: Vec<IpAddr>,
+    stun: Answer,
+    router: Option<Ipv4Addr>,
+    router_answer: Answer,
+}
+
+impl FakeNat {
+    /// A host with just the v4 loopback interface, no router and nobody to tell it its external
+    /// IP.
+    pub fn new() -> Self {
+        FakeNat {
+            ifv4s: vec![(Ipv4Addr::new(127, 0, 0, 1), Ipv4Addr::new(255, 0, 0, 0))],
+            ..Default::default()
+        }
+    }
+
+    /// Add a v4 interface with its netmask.
+    pub fn interface_v4(mut self, ip: Ipv4Addr, netmask: Ipv4Addr) -> Self {
+        self.ifv4s.push((ip, netmask));
+        self
+    }
+
+    /// Add a v6 interface.
+    pub fn interface_v6(mut self, ip: Ipv6Addr) -> Self {
+        self.ifv6s.push(ip);
+        self
+    }
+
+    /// Have the peers report `ip` as our external IP, with the NAT keeping our port numbers.
+    pub fn external_ip(mut self, ip: IpAddr) -> Self {
+        self.ext_ips.push(ip);
+        self
+    }
+
+    /// Have the peers take `latency` to answer.
+    pub fn stun_latency(mut self, latency: Duration) -> Self {
+        self.stun.latency = latency;
+        self
+    }
+
+    /// Have the peers fail to tell us our external IP, leaving the mapping to ask any real peers
+    /// it was given.
+    pub fn stun_fails(mut self) -> Self {
+        self.stun.fails = true;
+        self
+    }
+
+    /// Put us behind an IGD-like router which forwards whichever port it is asked for to the
+    /// same port on `ext_ip`.
+    pub fn router(mut self, ext_ip: Ipv4Addr) -> Self {
+        self.router = Some(ext_ip);
+        self
+    }
+
+    /// Have the router take `latency` to answer.
+    pub fn router_latency(mut self, latency: Duration) -> Self {
+        self.router_answer.latency = latency;
+        self
+    }
+
+    /// Have the router refuse to forward ports.
+    pub fn router_fails(mut self) -> Self {
+        self.router_answer.fails = true;
+        self
+    }
+
+    /// The v4 interfaces, with their netmasks.
+    pub fn ifv4s(&self) -> &[(Ipv4Addr, Ipv4Addr)] {
+        &self.ifv4s
+    }
+
+    /// The v6 interfaces.
+    pub fn ifv6s(&self) -> &[Ipv6Addr] {
+        &self.ifv6s
+    }
+
+    /// The external IPs the peers report.
+    pub fn ext_ips(&self) -> &[IpAddr] {
+        &self.ext_ips
+    }
+
+    /// How the peers answer.
+    pub fn stun_answer(&self) -> Answer {
+        self.stun
+    }
+
+    /// The router's external IP and how it answers, if there is one.
+    pub fn router_answer(&self) -> Option<(Ipv4Addr, Answer)> {
+        self.router.map(|ext_ip| (ext_ip, self.router_answer))
+    }
+}
@@ -0,0 +1,517 @@
+// Copyright 2017 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use super::delivery::{Deliveries, Wake};
+use common::{Listener, Stream, Transport, lock};
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+// Ports handed out to listeners bound to port 0 and to the near end of streams.
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+/// An in-memory network which `MemoryTransport`s listen and dial on, with latency and failures
+/// injected at will. Every address on it is one host's, so only the port tells them apart. Clones
+/// share the network. Latency goes by the real clock, or by one that only moves on `advance` for
+/// runs which do not depend on timing.
+///
+/// Streams cannot be moved between event loops, so services on it should run a single one.
+#[derive(Clone)]
+pub struct MemoryNetwork {
+    inner: Arc<Mutex<Network>>,
+}
+
+struct Network {
+    listeners: HashMap<u16, Arc<Mutex<Backlog>>>,
+    next_port: u16,
+    latency: Duration,
+    refused: HashSet<u16>,
+    pipes: Vec<Weak<Mutex<Pipe>>>,
+    deliveries: Deliveries,
+}
+
+impl MemoryNetwork {
+    /// A network without latency which refuses nobody.
+    pub fn new() -> Self {
+        MemoryNetwork::with_deliveries(Deliveries::new("Crust-Memory-Network"))
+    }
+
+    /// A network as `new` makes, whose latency goes by a clock that only moves on `advance`.
+    pub fn with_manual_clock() -> Self {
+        MemoryNetwork::with_deliveries(Deliveries::manual())
+    }
+
+    fn with_deliveries(deliveries: Deliveries) -> Self {
+        MemoryNetwork {
+            inner: Arc::new(Mutex::new(Network {
+                                           listeners: HashMap::new(),
+                                           next_port: FIRST_EPHEMERAL_PORT,
+                                           latency: Duration::from_secs(0),
+                                           refused: HashSet::new(),
+                                           pipes: Vec::new(),
+                                           deliveries: deliveries,
+                                       })),
+        }
+    }
+
+    /// Move the clock of a network made by `with_manual_clock` on by `by`, letting through
+    /// whatever has arrived by then.
+    pub fn advance(&self, by: Duration) {
+        lock(&self.inner).deliveries.advance(by);
+    }
+
+    /// A transport dialing and listening on this network.
+    pub fn transport(&self) -> MemoryTransport {
+        MemoryTransport { net: self.clone() }
+    }
+
+    /// Hold up the data written from now on by `latency` before it can be read.
+    pub fn set_latency(&self, latency: Duration) {
+        lock(&self.inner).latency = latency;
+    }
+
+    /// Refuse connections to `port`, whether something listens on it or not.
+    pub fn refuse(&self, port: u16) {
+        let _ = lock(&self.inner).refused.insert(port);
+    }
+
+    /// Accept connections to `port` again.
+    pub fn allow(&self, port: u16) {
+        let _ = lock(&self.inner).refused.remove(&port);
+    }
+
+    /// Reset every stream established so far, as a network outage would.
+    pub fn break_links(&self) {
+        let pipes: Vec<_> = {
+            let mut net = lock(&self.inner);
+            net.pipes.retain(|pipe| pipe.upgrade().is_some());
+            net.pipes.iter().filter_map(|pipe| pipe.upgrade()).collect()
+        };
+        for pipe in pipes {
+            let mut pipe = lock(&pipe);
+            pipe.broken = true;
+            pipe.wake();
+        }
+    }
+
+    fn listen(&self, addr: &SocketAddr) -> io::Result<MemoryListener> {
+        let mut net = lock(&self.inner);
+        let port = if addr.port() == 0 {
+            net.take_port()
+        } else if net.listeners.contains_key(&addr.port()) {
+            return Err(io::Error::new(ErrorKind::AddrInUse, "Port already listened on"));
+        } else {
+            addr.port()
+        };
+
+        let (registration, readiness) = Registration::new2();
+        let backlog = Arc::new(Mutex::new(Backlog {
+                                              streams: VecDeque::new(),
+                                              readiness: readiness,
+                                          }));
+        let _ = net.listeners.insert(port, backlog.clone());
+        Ok(MemoryListener {
+               addr: SocketAddr::new(addr.ip(), port),
+               backlog: backlog,
+               registration: registration,
+               net: self.clone(),
+           })
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<MemoryStream> {
+        let (ours, theirs, backlog) = {
+            let mut net = lock(&self.inner);
+            let backlog = match net.listeners.get(&addr.port()) {
+                Some(backlog) if !net.refused.contains(&addr.port()) => backlog.clone(),
+                _ => return Err(io::Error::new(ErrorKind::ConnectionRefused, "Refused")),
+            };
+            let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                                             net.take_port());
+
+            let (our_registration, our_readiness) = Registration::new2();
+            let (their_registration, their_readiness) = Registration::new2();
+            let to_them = Arc::new(Mutex::new(Pipe::new(their_readiness)));
+            let to_us = Arc::new(Mutex::new(Pipe::new(our_readiness)));
+            net.pipes.push(Arc::downgrade(&to_them));
+            net.pipes.push(Arc::downgrade(&to_us));
+
+            let ours = MemoryStream {
+                local_addr: local_addr,
+                peer_addr: *addr,
+                incoming: to_us.clone(),
+                outgoing: to_them.clone(),
+                registration: our_registration,
+                net: self.clone(),
+            };
+            let theirs = MemoryStream {
+                local_addr: *addr,
+                peer_addr: local_addr,
+                incoming: to_them,
+                outgoing: to_us,
+                registration: their_registration,
+                net: self.clone(),
+            };
+            (ours, theirs, backlog)
+        };
+
+        let mut backlog = lock(&backlog);
+        backlog.streams.push_back((theirs, ours.local_addr));
+        let _ = backlog.readiness.set_readiness(Ready::readable());
+        Ok(ours)
+    }
+
+    fn unbind(&self, port: u16) {
+        let _ = lock(&self.inner).listeners.remove(&port);
+    }
+
+    fn now(&self) -> Instant {
+        lock(&self.inner).deliveries.now()
+    }
+
+    // Queue `data` for the reader of `pipe` once the latency has passed.
+    fn send(&self, pipe: &Arc<Mutex<Pipe>>, data: Vec<u8>) {
+        let mut net = lock(&self.inner);
+        let at = net.deliveries.now() + net.latency;
+        let mut locked = lock(&pipe);
+        locked.chunks.push_back((at, data));
+        if net.latency == Duration::from_secs(0) {
+            locked.wake();
+        } else {
+            net.deliveries.schedule(at, pipe.clone());
+        }
+    }
+}
+
+impl Network {
+    fn take_port(&mut self) -> u16 {
+        while self.listeners.contains_key(&self.next_port) {
+            self.next_port = self.next_port.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+        }
+        let port = self.next_port;
+        self.next_port = self.next_port.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+        port
+    }
+}
+
+/// Dials and listens on a `MemoryNetwork`, without NAT traversal.
+#[derive(Clone)]
+pub struct MemoryTransport {
+    net: MemoryNetwork,
+}
+
+impl Transport for MemoryTransport {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Box<Stream>> {
+        Ok(Box::new(self.net.connect(addr)?))
+    }
+
+    fn listen(&self, addr: &SocketAddr) -> io::Result<Box<Listener>> {
+        Ok(Box::new(self.net.listen(addr)?))
+    }
+}
+
+// Bytes on their way one way between two streams, each chunk along with when it arrives.
+struct Pipe {
+    chunks: VecDeque<(Instant, Vec<u8>)>,
+    closed: bool,
+    broken: bool,
+    reader: SetReadiness,
+}
+
+impl Pipe {
+    fn new(reader: SetReadiness) -> Self {
+        let _ = reader.set_readiness(Ready::writable());
+        Pipe {
+            chunks: VecDeque::new(),
+            closed: false,
+            broken: false,
+            reader: reader,
+        }
+    }
+}
+
+impl Wake for Pipe {
+    fn wake(&self) {
+        let _ = self.reader.set_readiness(Ready::readable() | Ready::writable());
+    }
+}
+
+/// A stream over a `MemoryNetwork`.
+pub struct MemoryStream {
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    incoming: Arc<Mutex<Pipe>>,
+    outgoing: Arc<Mutex<Pipe>>,
+    registration: Registration,
+    net: MemoryNetwork,
+}
+
+impl Stream for MemoryStream {
+    fn transport(&self) -> &'static str {
+        "memory"
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Before the pipe is locked, as the network is locked ahead of pipes
+        let now = self.net.now();
+        let mut pipe = lock(&self.incoming);
+        if pipe.broken {
+            return Err(io::Error::new(ErrorKind::ConnectionReset, "Link broken"));
+        }
+
+        let mut read = 0;
+        while read < buf.len() {
+            let (at, chunk) = match pipe.chunks.pop_front() {
+                Some(entry) => entry,
+                None => break,
+            };
+            if at > now {
+                pipe.chunks.push_front((at, chunk));
+                break;
+            }
+            let len = cmp::min(chunk.len(), buf.len() - read);
+            buf[read..read + len].copy_from_slice(&chunk[..len]);
+            read += len;
+            if len < chunk.len() {
+                pipe.chunks.push_front((at, chunk[len..].to_vec()));
+            }
+        }
+
+        if read > 0 || buf.is_empty() {
+            Ok(read)
+        } else if pipe.closed && pipe.chunks.is_empty() {
+            Ok(0)
+        } else {
+            let _ = pipe.reader.set_readiness(Ready::writable());
+            Err(io::Error::new(ErrorKind::WouldBlock, "Nothing has arrived"))
+        }
+    }
+
+    fn write_bufs(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        {
+            let pipe = lock(&self.outgoing);
+            if pipe.broken {
+                return Err(io::Error::new(ErrorKind::ConnectionReset, "Link broken"));
+            }
+            if pipe.closed {
+                return Err(io::Error::new(ErrorKind::BrokenPipe, "Stream closed"));
+            }
+        }
+
+        let mut data = Vec::new();
+        for buf in bufs {
+            data.extend_from_slice(buf);
+        }
+        let len = data.len();
+        if len > 0 {
+            self.net.send(&self.outgoing, data);
+        }
+        Ok(len)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer_addr)
+    }
+
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        Ok(None)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        for pipe in &[&self.incoming, &self.outgoing] {
+            let mut pipe = lock(&pipe);
+            pipe.closed = true;
+            pipe.wake();
+        }
+        Ok(())
+    }
+}
+
+impl Evented for MemoryStream {
+    fn register(&self,
+                poll: &Poll,
+                token: Token,
+                interest: Ready,
+                opts: PollOpt)
+                -> io::Result<()> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self,
+                  poll: &Poll,
+                  token: Token,
+                  interest: Ready,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.registration.deregister(poll)
+    }
+}
+
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        let _ = Stream::shutdown(self);
+    }
+}
+
+// Streams opened to a listener which it has yet to accept.
+struct Backlog {
+    streams: VecDeque<(MemoryStream, SocketAddr)>,
+    readiness: SetReadiness,
+}
+
+/// Accepts streams over a `MemoryNetwork`.
+pub struct MemoryListener {
+    addr: SocketAddr,
+    backlog: Arc<Mutex<Backlog>>,
+    registration: Registration,
+    net: MemoryNetwork,
+}
+
+impl Listener for MemoryListener {
+    fn accept(&self) -> io::Result<(Box<Stream>, SocketAddr)> {
+        let mut backlog = lock(&self.backlog);
+        match backlog.streams.pop_front() {
+            Some((stream, addr)) => Ok((Box::new(stream), addr)),
+            None => {
+                let _ = backlog.readiness.set_readiness(Ready::empty());
+                Err(io::Error::new(ErrorKind::WouldBlock, "No stream to accept"))
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Evented for MemoryListener {
+    fn register(&self,
+                poll: &Poll,
+                token: Token,
+                interest: Ready,
+                opts: PollOpt)
+                -> io::Result<()> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self,
+                  poll: &Poll,
+                  token: Token,
+                  interest: Ready,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.registration.deregister(poll)
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        self.net.unbind(self.addr.port());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio::Events;
+
+    #[test]
+    fn latency_and_failures() {
+        let net = MemoryNetwork::new();
+        let transport = net.transport();
+        let listener = unwrap!(transport.listen(&unwrap!("0.0.0.0:0".parse())));
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                                   unwrap!(listener.local_addr()).port());
+        let mut stream = unwrap!(transport.connect(&addr));
+        let (mut accepted, peer_addr) = unwrap!(listener.accept());
+        assert_eq!(peer_addr, unwrap!(stream.local_addr()));
+
+        let poll = unwrap!(Poll::new());
+        unwrap!(poll.register(&*accepted, Token(0), Ready::readable(), PollOpt::edge()));
+        net.set_latency(Duration::from_millis(50));
+        let hello = &b"hello"[..];
+        assert_eq!(unwrap!(stream.write_bufs(&[hello])), 5);
+
+        let mut buf = [0; 8];
+        match accepted.read(&mut buf) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+            res => panic!("Unexpected read: {:?}", res),
+        }
+        let mut events = Events::with_capacity(4);
+        let _ = unwrap!(poll.poll(&mut events, Some(Duration::from_secs(5))));
+        assert!(events.iter().any(|event| event.kind().is_readable()));
+        assert_eq!(unwrap!(accepted.read(&mut buf)), 5);
+        assert_eq!(&buf[..5], b"hello");
+
+        net.break_links();
+        match accepted.read(&mut buf) {
+            Err(ref e) if e.kind() == ErrorKind::ConnectionReset => (),
+            res => panic!("Unexpected read: {:?}", res),
+        }
+
+        net.refuse(addr.port());
+        match transport.connect(&addr) {
+            Err(ref e) if e.kind() == ErrorKind::ConnectionRefused => (),
+            _ => panic!("Connected to a refused port"),
+        }
+    }
+
+    #[test]
+    fn manual_clock() {
+        let net = MemoryNetwork::with_manual_clock();
+        let transport = net.transport();
+        let listener = unwrap!(transport.listen(&unwrap!("0.0.0.0:0".parse())));
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                                   unwrap!(listener.local_addr()).port());
+        let mut stream = unwrap!(transport.connect(&addr));
+        let (mut accepted, _) = unwrap!(listener.accept());
+
+        net.set_latency(Duration::from_secs(60));
+        let hello = &b"hello"[..];
+        assert_eq!(unwrap!(stream.write_bufs(&[hello])), 5);
+
+        // However long the test takes, nothing arrives until the clock is moved on
+        let mut buf = [0; 8];
+        net.advance(Duration::from_secs(59));
+        match accepted.read(&mut buf) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+            res => panic!("Unexpected read: {:?}", res),
+        }
+        net.advance(Duration::from_secs(1));
+        assert_eq!(unwrap!(accepted.read(&mut buf)), 5);
+        assert_eq!(&buf[..5], b"hello");
+    }
+}
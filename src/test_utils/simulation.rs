@@ -0,0 +1,652 @@
+// Copyright 2017 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use super::delivery::{Deliveries, Wake};
+use common::{Listener, SocketConfig, Stream, Transport, lock};
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use mio::tcp::{TcpListener, TcpStream};
+use rand::{self, Rng, SeedableRng, XorShiftRng};
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Most read from the wrapped stream at a time.
+const READ_CHUNK: usize = 16 * 1024;
+
+/// Conditions on the path from a peer to us, applied to what we receive from it, one read of the
+/// wrapped stream at a time. Lost data is sent again unless `drop` has it lost for good, and data
+/// arrives in order unless `reorder` lets it overtake what was held up before it - those two
+/// break the guarantees of a stream on purpose, as a path tampering with it would, so that the
+/// handling of corrupt streams can be exercised.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Conditions {
+    /// How long all data takes to arrive.
+    pub latency: Duration,
+    /// Up to how much longer, picked at random for each read.
+    pub jitter: Duration,
+    /// Chance, from 0 to 1, that what a read got was lost and had to be sent again.
+    pub loss: f64,
+    /// How much later lost data arrives.
+    pub retransmit_delay: Duration,
+    /// Chance, from 0 to 1, that what a read got is lost and never sent again.
+    pub drop: f64,
+    /// Chance, from 0 to 1, that what a read got is not held up behind earlier data, so that it
+    /// arrives first if its latency and jitter are less.
+    pub reorder: f64,
+    /// Most bytes arriving per second, if capped.
+    pub bandwidth: Option<u64>,
+}
+
+impl Default for Conditions {
+    fn default() -> Self {
+        Conditions {
+            latency: Duration::from_secs(0),
+            jitter: Duration::from_secs(0),
+            loss: 0.0,
+            retransmit_delay: Duration::from_millis(200),
+            drop: 0.0,
+            reorder: 0.0,
+            bandwidth: None,
+        }
+    }
+}
+
+/// Network conditions to wrap transports in, set for all peers, for each by address or for every
+/// stream to and from a listener. Changes apply to streams already open as well. Clones share
+/// the conditions.
+///
+/// Streams of the wrapped transport are affected, and so are the TCP streams hole punched and the
+/// listeners on mapped sockets when the transport is TCP with NAT traversal. Streams cannot be
+/// moved between event loops, so services using it should run a single one.
+///
+/// Random choices are made by an RNG which can be seeded, and time can be made to stand still
+/// until `advance` moves it on, so that a run can be repeated exactly.
+#[derive(Clone)]
+pub struct Simulation {
+    shared: Arc<Mutex<Shared>>,
+}
+
+struct Shared {
+    default: Conditions,
+    peers: HashMap<SocketAddr, Conditions>,
+    listeners: HashMap<u16, Conditions>,
+    rng: XorShiftRng,
+    deliveries: Deliveries,
+}
+
+// What becomes of data read from the wrapped stream.
+struct Fate {
+    delay: Duration,
+    dropped: bool,
+    reordered: bool,
+    bandwidth: Option<u64>,
+}
+
+impl Simulation {
+    /// A simulation of a perfect network, by the real clock and with a random seed.
+    pub fn new() -> Self {
+        Simulation::start(rand::random(), Deliveries::new("Crust-Simulation"))
+    }
+
+    /// A simulation by the real clock, making its random choices from `seed`.
+    pub fn with_seed(seed: u64) -> Self {
+        Simulation::start(seed, Deliveries::new("Crust-Simulation"))
+    }
+
+    /// A simulation making its random choices from `seed`, whose clock only moves on `advance`.
+    pub fn with_manual_clock(seed: u64) -> Self {
+        Simulation::start(seed, Deliveries::manual())
+    }
+
+    fn start(seed: u64, deliveries: Deliveries) -> Self {
+        // Any words will do as long as not all are zero
+        let rng = XorShiftRng::from_seed([seed as u32, (seed >> 32) as u32, 0x9e37_79b9, 1]);
+        Simulation {
+            shared: Arc::new(Mutex::new(Shared {
+                                            default: Conditions::default(),
+                                            peers: HashMap::new(),
+                                            listeners: HashMap::new(),
+                                            rng: rng,
+                                            deliveries: deliveries,
+                                        })),
+        }
+    }
+
+    /// Apply `conditions` to peers without conditions of their own.
+    pub fn set_default(&self, conditions: Conditions) {
+        lock(&self.shared).default = conditions;
+    }
+
+    /// Apply `conditions` to the peer at `addr`.
+    pub fn set_peer(&self, addr: SocketAddr, conditions: Conditions) {
+        let _ = lock(&self.shared).peers.insert(addr, conditions);
+    }
+
+    /// Have the peer at `addr` go by the default conditions again.
+    pub fn clear_peer(&self, addr: &SocketAddr) {
+        let _ = lock(&self.shared).peers.remove(addr);
+    }
+
+    /// Apply `conditions` to every stream dialed to or accepted by the listener on `port`, whose
+    /// peer has no conditions of its own. This reaches peers which dialed in from ports not known
+    /// beforehand.
+    pub fn set_listener(&self, port: u16, conditions: Conditions) {
+        let _ = lock(&self.shared).listeners.insert(port, conditions);
+    }
+
+    /// Have the streams of the listener on `port` go by the default conditions again.
+    pub fn clear_listener(&self, port: u16) {
+        let _ = lock(&self.shared).listeners.remove(&port);
+    }
+
+    /// Move a manual clock on by `by`, delivering whatever has come due. Does nothing by the
+    /// real clock.
+    pub fn advance(&self, by: Duration) {
+        lock(&self.shared).deliveries.advance(by);
+    }
+
+    /// `transport` with streams subject to the conditions.
+    pub fn wrap<T: Transport>(&self, transport: T) -> SimulatedTransport<T> {
+        SimulatedTransport {
+            inner: transport,
+            simulation: self.clone(),
+        }
+    }
+
+    fn now(&self) -> Instant {
+        lock(&self.shared).deliveries.now()
+    }
+
+    fn fate(&self, peer: &SocketAddr, listener: &SocketAddr) -> Fate {
+        let mut shared = lock(&self.shared);
+        let conditions = shared
+            .peers
+            .get(peer)
+            .or_else(|| shared.listeners.get(&listener.port()))
+            .cloned()
+            .unwrap_or(shared.default);
+        let rng = &mut shared.rng;
+        let mut delay = conditions.latency + scale(conditions.jitter, rng.next_f64());
+        if rng.next_f64() < conditions.loss {
+            delay += conditions.retransmit_delay;
+        }
+        Fate {
+            delay: delay,
+            dropped: rng.next_f64() < conditions.drop,
+            reordered: rng.next_f64() < conditions.reorder,
+            bandwidth: conditions.bandwidth,
+        }
+    }
+
+    fn schedule(&self, at: Instant, held: &Arc<Mutex<Held>>) {
+        lock(&self.shared).deliveries.schedule(at, held.clone());
+    }
+
+    fn simulate(&self,
+                stream: Box<Stream>,
+                peer: SocketAddr,
+                listener: SocketAddr)
+                -> SimulatedStream {
+        let (registration, readiness) = Registration::new2();
+        SimulatedStream {
+            inner: stream,
+            peer: peer,
+            listener: listener,
+            held: Arc::new(Mutex::new(Held {
+                                          chunks: VecDeque::new(),
+                                          eof_at: None,
+                                          readiness: readiness,
+                                      })),
+            registration: registration,
+            last_due: self.now(),
+            simulation: self.clone(),
+        }
+    }
+}
+
+/// A transport with streams subject to the conditions of a `Simulation`.
+pub struct SimulatedTransport<T> {
+    inner: T,
+    simulation: Simulation,
+}
+
+impl<T: Transport> Transport for SimulatedTransport<T> {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Box<Stream>> {
+        let stream = self.inner.connect(addr)?;
+        Ok(Box::new(self.simulation.simulate(stream, *addr, *addr)))
+    }
+
+    fn listen(&self, addr: &SocketAddr) -> io::Result<Box<Listener>> {
+        Ok(Box::new(SimulatedListener {
+                        inner: self.inner.listen(addr)?,
+                        simulation: self.simulation.clone(),
+                    }))
+    }
+
+    fn nat_traversal(&self) -> bool {
+        self.inner.nat_traversal()
+    }
+
+    fn adopt(&self, stream: TcpStream) -> io::Result<Box<Stream>> {
+        // Hole punching has both ends dial, so the peer is taken to be the listening end
+        let peer = stream.peer_addr()?;
+        let stream = self.inner.adopt(stream)?;
+        Ok(Box::new(self.simulation.simulate(stream, peer, peer)))
+    }
+
+    fn adopt_listener(&self, listener: TcpListener) -> io::Result<Box<Listener>> {
+        Ok(Box::new(SimulatedListener {
+                        inner: self.inner.adopt_listener(listener)?,
+                        simulation: self.simulation.clone(),
+                    }))
+    }
+}
+
+// What has been read from the wrapped stream, each chunk along with when it is due.
+struct Held {
+    chunks: VecDeque<(Instant, Vec<u8>)>,
+    eof_at: Option<Instant>,
+    readiness: SetReadiness,
+}
+
+impl Wake for Held {
+    fn wake(&self) {
+        let _ = self.readiness.set_readiness(Ready::readable());
+    }
+}
+
+/// A stream of a `SimulatedTransport`.
+pub struct SimulatedStream {
+    inner: Box<Stream>,
+    peer: SocketAddr,
+    // The listening end, which is the peer's unless we accepted the stream
+    listener: SocketAddr,
+    held: Arc<Mutex<Held>>,
+    registration: Registration,
+    // When the last data held up is due, which later data may not overtake
+    last_due: Instant,
+    simulation: Simulation,
+}
+
+impl SimulatedStream {
+    // Hold up `chunk` read at `now` as the current conditions have it, unless it is lost.
+    fn hold(&mut self, now: Instant, chunk: Vec<u8>) {
+        let fate = self.simulation.fate(&self.peer, &self.listener);
+        if fate.dropped {
+            return;
+        }
+        let mut due = now + fate.delay;
+        if !fate.reordered {
+            due = cmp::max(due, self.last_due);
+        }
+        if let Some(bandwidth) = fate.bandwidth {
+            due += scale(Duration::from_secs(1),
+                         chunk.len() as f64 / cmp::max(bandwidth, 1) as f64);
+        }
+        self.last_due = cmp::max(due, self.last_due);
+
+        {
+            let mut held = lock(&self.held);
+            let index = held
+                .chunks
+                .iter()
+                .position(|&(at, _)| at > due)
+                .unwrap_or(held.chunks.len());
+            held.chunks.insert(index, (due, chunk));
+        }
+        self.simulation.schedule(due, &self.held);
+    }
+}
+
+impl Stream for SimulatedStream {
+    fn transport(&self) -> &'static str {
+        self.inner.transport()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Take in whatever has arrived, to be held up as the conditions have it
+        let now = self.simulation.now();
+        let mut chunk = [0; READ_CHUNK];
+        while lock(&self.held).eof_at.is_none() {
+            match self.inner.read(&mut chunk) {
+                Ok(0) => {
+                    let at = cmp::max(now, self.last_due);
+                    lock(&self.held).eof_at = Some(at);
+                    self.simulation.schedule(at, &self.held);
+                }
+                Ok(len) => self.hold(now, chunk[..len].to_vec()),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut held = lock(&self.held);
+        let mut read = 0;
+        while read < buf.len() {
+            let (at, chunk) = match held.chunks.pop_front() {
+                Some(entry) => entry,
+                None => break,
+            };
+            if at > now {
+                held.chunks.push_front((at, chunk));
+                break;
+            }
+            let len = cmp::min(chunk.len(), buf.len() - read);
+            buf[read..read + len].copy_from_slice(&chunk[..len]);
+            read += len;
+            if len < chunk.len() {
+                held.chunks.push_front((at, chunk[len..].to_vec()));
+            }
+        }
+
+        let eof = held.chunks.is_empty() && held.eof_at.map_or(false, |at| at <= now);
+        if read > 0 || buf.is_empty() || eof {
+            Ok(read)
+        } else {
+            let _ = held.readiness.set_readiness(Ready::empty());
+            Err(io::Error::new(ErrorKind::WouldBlock, "Nothing is due yet"))
+        }
+    }
+
+    fn write_bufs(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        self.inner.write_bufs(bufs)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.inner.take_error()
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.inner.shutdown()
+    }
+
+    fn configure(&self, config: &SocketConfig) -> io::Result<()> {
+        self.inner.configure(config)
+    }
+}
+
+impl Evented for SimulatedStream {
+    fn register(&self,
+                poll: &Poll,
+                token: Token,
+                interest: Ready,
+                opts: PollOpt)
+                -> io::Result<()> {
+        self.inner.register(poll, token, interest, opts)?;
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self,
+                  poll: &Poll,
+                  token: Token,
+                  interest: Ready,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        self.inner.reregister(poll, token, interest, opts)?;
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.inner.deregister(poll)?;
+        self.registration.deregister(poll)
+    }
+}
+
+/// A listener of a `SimulatedTransport`.
+pub struct SimulatedListener {
+    inner: Box<Listener>,
+    simulation: Simulation,
+}
+
+impl Listener for SimulatedListener {
+    fn accept(&self) -> io::Result<(Box<Stream>, SocketAddr)> {
+        let (stream, addr) = self.inner.accept()?;
+        let listener = self.inner.local_addr()?;
+        Ok((Box::new(self.simulation.simulate(stream, addr, listener)), addr))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+impl Evented for SimulatedListener {
+    fn register(&self,
+                poll: &Poll,
+                token: Token,
+                interest: Ready,
+                opts: PollOpt)
+                -> io::Result<()> {
+        self.inner.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self,
+                  poll: &Poll,
+                  token: Token,
+                  interest: Ready,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        self.inner.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.inner.deregister(poll)
+    }
+}
+
+// `duration` times `factor`.
+fn scale(duration: Duration, factor: f64) -> Duration {
+    let nanos = (duration.as_secs() as f64 * 1e9 + duration.subsec_nanos() as f64) * factor;
+    Duration::new((nanos / 1e9) as u64, (nanos % 1e9) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::Tcp;
+    use mio::Events;
+    use std::io::Write;
+    use std::net::{self, IpAddr, Ipv4Addr};
+    use test_utils::MemoryNetwork;
+
+    #[test]
+    fn latency_and_bandwidth() {
+        let net = MemoryNetwork::new();
+        let simulation = Simulation::new();
+        let transport = simulation.wrap(net.transport());
+        let listener = unwrap!(transport.listen(&unwrap!("0.0.0.0:0".parse())));
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                                   unwrap!(listener.local_addr()).port());
+        let mut stream = unwrap!(transport.connect(&addr));
+        let (mut accepted, _) = unwrap!(listener.accept());
+
+        let poll = unwrap!(Poll::new());
+        unwrap!(poll.register(&*accepted, Token(0), Ready::readable(), PollOpt::edge()));
+        simulation.set_default(Conditions {
+                                   latency: Duration::from_millis(50),
+                                   bandwidth: Some(1000),
+                                   ..Conditions::default()
+                               });
+
+        let sent = [7; 100];
+        let start = Instant::now();
+        let buf = &sent[..];
+        assert_eq!(unwrap!(stream.write_bufs(&[buf])), sent.len());
+
+        let mut received = Vec::new();
+        let mut events = Events::with_capacity(4);
+        while received.len() < sent.len() {
+            let _ = unwrap!(poll.poll(&mut events, Some(Duration::from_secs(5))));
+            let mut buf = [0; 128];
+            match accepted.read(&mut buf) {
+                Ok(len) => received.extend_from_slice(&buf[..len]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(e) => panic!("Read failed: {:?}", e),
+            }
+        }
+        assert_eq!(&received[..], &sent[..]);
+        // Latency plus 100 bytes at 1000 bytes per second
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+
+    // Connect over a memory network without latency, returning the dialing and accepted streams
+    // and the port listened on.
+    fn connect(simulation: &Simulation) -> (Box<Stream>, Box<Stream>, u16) {
+        let net = MemoryNetwork::with_manual_clock();
+        let transport = simulation.wrap(net.transport());
+        let listener = unwrap!(transport.listen(&unwrap!("0.0.0.0:0".parse())));
+        let port = unwrap!(listener.local_addr()).port();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+        let stream = unwrap!(transport.connect(&addr));
+        let (accepted, _) = unwrap!(listener.accept());
+        (stream, accepted, port)
+    }
+
+    // Send the bytes 0 to 15 apart under `conditions`, returning what arrives in what order.
+    fn run(seed: u64, conditions: Conditions) -> Vec<u8> {
+        let simulation = Simulation::with_manual_clock(seed);
+        simulation.set_default(conditions);
+        let (mut stream, mut accepted, _) = connect(&simulation);
+
+        let mut received = Vec::new();
+        for byte in 0..16u8 {
+            let sent = [byte];
+            let sent = &sent[..];
+            assert_eq!(unwrap!(stream.write_bufs(&[sent])), 1);
+            // Take it in on its own, so it is held up on its own
+            read_due(&mut accepted, &mut received);
+        }
+        simulation.advance(Duration::from_secs(10));
+        read_due(&mut accepted, &mut received);
+        received
+    }
+
+    fn read_due(stream: &mut Box<Stream>, received: &mut Vec<u8>) {
+        let mut buf = [0; 16];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(len) => received.extend_from_slice(&buf[..len]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => panic!("Read failed: {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn seeded_runs_repeat() {
+        let conditions = Conditions {
+            latency: Duration::from_millis(10),
+            jitter: Duration::from_millis(500),
+            reorder: 0.5,
+            drop: 0.2,
+            ..Conditions::default()
+        };
+        let first = run(7, conditions);
+        assert_eq!(run(7, conditions), first);
+
+        // Nothing is duplicated, but some is lost and some arrives out of order
+        let mut sorted = first.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted.len(), first.len());
+        assert!(first.len() < 16);
+        assert!(sorted != first);
+
+        let perfect = run(7, Conditions::default());
+        assert_eq!(perfect, (0..16).collect::<Vec<u8>>());
+        let lossy = Conditions {
+            drop: 1.0,
+            ..Conditions::default()
+        };
+        assert!(run(7, lossy).is_empty());
+    }
+
+    #[test]
+    fn listener_conditions() {
+        let simulation = Simulation::with_manual_clock(0);
+        let (mut stream, mut accepted, port) = connect(&simulation);
+        simulation.set_listener(port,
+                                Conditions {
+                                    latency: Duration::from_secs(60),
+                                    ..Conditions::default()
+                                });
+
+        // Both ends go by the listener's conditions, however long the test takes
+        let mut buf = [0; 8];
+        let hello = &b"hello"[..];
+        assert_eq!(unwrap!(stream.write_bufs(&[hello])), 5);
+        assert_eq!(unwrap!(accepted.write_bufs(&[hello])), 5);
+        for _ in 0..2 {
+            for stream in &mut [&mut stream, &mut accepted] {
+                match stream.read(&mut buf) {
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+                    res => panic!("Unexpected read: {:?}", res),
+                }
+            }
+            simulation.advance(Duration::from_secs(30));
+        }
+        for stream in &mut [&mut stream, &mut accepted] {
+            assert_eq!(unwrap!(stream.read(&mut buf)), 5);
+            assert_eq!(&buf[..5], b"hello");
+        }
+    }
+
+    #[test]
+    fn adopted_streams() {
+        // As a hole punched stream would be
+        let listener = unwrap!(net::TcpListener::bind("127.0.0.1:0"));
+        let addr = unwrap!(listener.local_addr());
+        let stream = unwrap!(TcpStream::connect(&addr));
+        let (mut far_end, _) = unwrap!(listener.accept());
+
+        let simulation = Simulation::with_seed(0);
+        simulation.set_peer(addr,
+                            Conditions {
+                                latency: Duration::from_millis(100),
+                                ..Conditions::default()
+                            });
+        let mut stream = unwrap!(simulation.wrap(Tcp).adopt(stream));
+        let poll = unwrap!(Poll::new());
+        unwrap!(poll.register(&*stream, Token(0), Ready::readable(), PollOpt::edge()));
+
+        let start = Instant::now();
+        unwrap!(far_end.write_all(b"hello"));
+        let mut received = Vec::new();
+        let mut events = Events::with_capacity(4);
+        while received.len() < 5 {
+            let _ = unwrap!(poll.poll(&mut events, Some(Duration::from_secs(5))));
+            read_due(&mut stream, &mut received);
+        }
+        assert_eq!(&received[..], b"hello");
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
@@ -20,7 +20,7 @@ pub mod utils;
 pub use self::utils::{gen_config, get_event_sender, timebomb};
 
 use common::CrustUser;
-use main::{Config, Event, Service};
+use main::{BootstrapFailure, Config, Event, Service};
 use mio;
 use std::collections::HashSet;
 use std::net::SocketAddr;
@@ -53,7 +53,7 @@ fn bootstrap_two_services_and_exchange_messages() {
 
     unwrap!(service0.start_listening_tcp());
 
-    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(addr) => addr.port());
 
     let mut config1 = gen_config();
     config1.hard_coded_contacts = vec![localhost_contact_info(port0)];
@@ -63,10 +63,10 @@ fn bootstrap_two_services_and_exchange_messages() {
 
     unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
 
-    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
     assert_eq!(peer_id0, service0.id());
 
-    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
     assert_eq!(peer_id1, service1.id());
 
     let message0 = b"hello from 0".to_vec();
@@ -86,6 +86,50 @@ fn bootstrap_two_services_and_exchange_messages() {
     });
 }
 
+#[test]
+fn bootstrap_via_relay() {
+    let mut config0 = gen_config();
+    config0.act_as_relay = Some(true);
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0));
+
+    unwrap!(service0.start_listening_tcp());
+
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(addr) => addr.port());
+
+    // Without listeners of its own, service 1 can only be reached through service 0.
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0)];
+    config1.bootstrap_via_relay = Some(true);
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Node));
+
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    assert_eq!(peer_id0, service0.id());
+
+    expect_event!(event_rx0, Event::BootstrapAccept(peer_id, CrustUser::Node, _) => {
+        assert_eq!(peer_id, service1.id());
+    });
+
+    service1.prepare_connection_info(0);
+    expect_event!(event_rx1, Event::ConnectionInfoPrepared(cir) => {
+        assert_eq!(unwrap!(cir.result).relay, Some(localhost(port0)));
+    });
+
+    // Once the relay is gone it is no longer handed out, and another is looked for.
+    drop(service0);
+    expect_event!(event_rx1, Event::LostPeer(peer_id) => assert_eq!(peer_id, peer_id0));
+    expect_event!(event_rx1, Event::BootstrapFailed(..));
+
+    service1.prepare_connection_info(1);
+    expect_event!(event_rx1, Event::ConnectionInfoPrepared(cir) => {
+        assert_eq!(unwrap!(cir.result).relay, None);
+    });
+}
+
 #[test]
 fn bootstrap_two_services_using_service_discovery() {
     let service_discovery_port = gen_service_discovery_port();
@@ -100,21 +144,21 @@ fn bootstrap_two_services_using_service_discovery() {
     let mut service1 = unwrap!(Service::with_config(event_tx1, config));
 
     unwrap!(service1.start_listening_tcp());
-    let _ = expect_event!(event_rx1, Event::ListenerStarted(port) => port);
+    let _ = expect_event!(event_rx1, Event::ListenerStarted(addr) => addr.port());
 
     service0.start_service_discovery();
     service0.set_service_discovery_listen(true);
     unwrap!(service0.start_listening_tcp());
 
-    expect_event!(event_rx0, Event::ListenerStarted(_port));
+    expect_event!(event_rx0, Event::ListenerStarted(_addr));
 
     service1.start_service_discovery();
     unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
 
-    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
     assert_eq!(peer_id0, service0.id());
 
-    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
     assert_eq!(peer_id1, service1.id());
 }
 
@@ -125,7 +169,7 @@ fn bootstrap_with_multiple_contact_endpoints() {
     let (event_tx0, event_rx0) = get_event_sender();
     let mut service0 = unwrap!(Service::with_config(event_tx0, Config::default()));
     unwrap!(service0.start_listening_tcp());
-    let port = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    let port = expect_event!(event_rx0, Event::ListenerStarted(addr) => addr.port());
     let valid_address = localhost(port);
 
     let deaf_listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
@@ -139,12 +183,12 @@ fn bootstrap_with_multiple_contact_endpoints() {
     unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
 
     unwrap!(service1.start_listening_tcp());
-    let _ = expect_event!(event_rx1, Event::ListenerStarted(port) => port);
+    let _ = expect_event!(event_rx1, Event::ListenerStarted(addr) => addr.port());
 
-    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
     assert_eq!(peer_id0, service0.id());
 
-    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
     assert_eq!(peer_id1, service1.id());
 }
 
@@ -155,7 +199,7 @@ fn bootstrap_with_blacklist() {
     let (event_tx0, event_rx0) = get_event_sender();
     let mut service0 = unwrap!(Service::with_config(event_tx0, Config::default()));
     unwrap!(service0.start_listening_tcp());
-    let port = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    let port = expect_event!(event_rx0, Event::ListenerStarted(addr) => addr.port());
     let valid_address = localhost(port);
 
     let blacklisted_listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
@@ -171,12 +215,12 @@ fn bootstrap_with_blacklist() {
     unwrap!(service1.start_bootstrap(blacklist, CrustUser::Client));
 
     unwrap!(service1.start_listening_tcp());
-    let _ = expect_event!(event_rx1, Event::ListenerStarted(port) => port);
+    let _ = expect_event!(event_rx1, Event::ListenerStarted(addr) => addr.port());
 
-    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
     assert_eq!(peer_id0, service0.id());
 
-    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
     assert_eq!(peer_id1, service1.id());
 
     let blacklisted_listener = unwrap!(
@@ -205,7 +249,7 @@ fn bootstrap_fails_only_blacklisted_contact() {
     blacklist.insert(blacklisted_address);
     unwrap!(service.start_bootstrap(blacklist, CrustUser::Client));
 
-    expect_event!(event_rx, Event::BootstrapFailed);
+    expect_event!(event_rx, Event::BootstrapFailed(_));
 
     let blacklisted_listener = unwrap!(
             mio::tcp::TcpListener::from_listener(blacklisted_listener, &blacklisted_address)
@@ -222,7 +266,7 @@ fn bootstrap_fails_if_there_are_no_contacts() {
     let mut service = unwrap!(Service::with_config(event_tx, config));
 
     unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
-    expect_event!(event_rx, Event::BootstrapFailed);
+    expect_event!(event_rx, Event::BootstrapFailed(_));
 }
 
 #[test]
@@ -234,12 +278,15 @@ fn bootstrap_timeouts_if_there_are_only_invalid_contacts() {
 
     let mut config = gen_config();
     config.hard_coded_contacts = vec![address];
+    config.bootstrap_timeout_secs = Some(2);
 
     let (event_tx, event_rx) = get_event_sender();
     let mut service = unwrap!(Service::with_config(event_tx, config));
 
     unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
-    expect_event!(event_rx, Event::BootstrapFailed);
+    expect_event!(event_rx, Event::BootstrapFailed(failures) => {
+        assert_eq!(failures, vec![(address, BootstrapFailure::TimedOut)]);
+    });
 }
 
 #[test]
@@ -249,7 +296,7 @@ fn drop_disconnects() {
     let mut service_0 = unwrap!(Service::with_config(event_tx_0, config_0));
 
     unwrap!(service_0.start_listening_tcp());
-    let port = expect_event!(event_rx_0, Event::ListenerStarted(port) => port);
+    let port = expect_event!(event_rx_0, Event::ListenerStarted(addr) => addr.port());
 
     let mut config_1 = gen_config();
     config_1.hard_coded_contacts = vec![localhost_contact_info(port)];
@@ -259,8 +306,8 @@ fn drop_disconnects() {
 
     unwrap!(service_1.start_bootstrap(HashSet::new(), CrustUser::Client));
 
-    let peer_id_0 = expect_event!(event_rx_1, Event::BootstrapConnect(peer_id, _) => peer_id);
-    expect_event!(event_rx_0, Event::BootstrapAccept(_peer_id, _));
+    let peer_id_0 = expect_event!(event_rx_1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    expect_event!(event_rx_0, Event::BootstrapAccept(_peer_id, _, _));
 
     // Dropping service_0 should make service_1 receive a LostPeer event.
     drop(service_0);
@@ -273,7 +320,7 @@ fn drop_disconnects() {
 // connections but then does nothing. It's purpose is to test that we detect
 // and handle non-responsive peers correctly.
 mod broken_peer {
-    use common::{Core, Message, Socket, State};
+    use common::{Challenge, Core, IdentityKeys, Message, PROTOCOL_VERSION, Socket, State};
     use mio::{Poll, PollOpt, Ready, Token};
     use mio::tcp::TcpListener;
     use rust_sodium::crypto::box_;
@@ -295,6 +342,10 @@ mod broken_peer {
     }
 
     impl State for Listen {
+        fn name(&self) -> &'static str {
+            "Listen"
+        }
+
         fn ready(&mut self, core: &mut Core, poll: &Poll, _: Ready) {
             let (socket, _) = unwrap!(self.0.accept());
             unwrap!(poll.deregister(&self.0));
@@ -320,18 +371,30 @@ mod broken_peer {
     }
 
     impl State for Connection {
+        fn name(&self) -> &'static str {
+            "Connection"
+        }
+
         fn ready(&mut self, core: &mut Core, poll: &Poll, kind: Ready) {
             if kind.is_error() || kind.is_hup() {
                 return self.terminate(core, poll);
             }
             if kind.is_readable() {
                 match self.0.read::<Message>() {
-                    Ok(Some(Message::BootstrapRequest(..))) => {
+                    Ok(Some(Message::IdentifiedBootstrapRequest(their_public_key,
+                                                                _,
+                                                                _,
+                                                                their_challenge,
+                                                                _))) => {
                         let public_key = box_::gen_keypair().0;
-                        unwrap!(self.0.write(poll,
-                                             self.1,
-                                             Some((Message::BootstrapGranted(public_key),
-                                                   0))));
+                        let challenge = Challenge::new();
+                        let proof = IdentityKeys::generate()
+                            .prove(&public_key, &their_public_key, &challenge, &their_challenge);
+                        let granted = Message::IdentifiedBootstrapGranted(public_key,
+                                                                          challenge,
+                                                                          proof,
+                                                                          PROTOCOL_VERSION);
+                        unwrap!(self.0.write(poll, self.1, Some((granted, 0))));
                     }
                     Ok(Some(_)) | Ok(None) => (),
                     Err(_) => self.terminate(core, poll),
@@ -382,7 +445,7 @@ fn drop_peer_when_no_message_received_within_inactivity_period() {
     let mut service = unwrap!(Service::with_config(event_tx, config));
 
     unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
-    let peer_id = expect_event!(event_rx, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let peer_id = expect_event!(event_rx, Event::BootstrapConnect(peer_id, _, _) => peer_id);
 
     // The peer should drop after inactivity.
     expect_event!(event_rx, Event::LostPeer(lost_peer_id) => {
@@ -401,7 +464,7 @@ fn do_not_drop_peer_even_when_no_data_messages_are_exchanged_within_inactivity_p
     let mut service0 = unwrap!(Service::with_config(event_tx0, config0));
 
     unwrap!(service0.start_listening_tcp());
-    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(addr) => addr.port());
 
     let mut config1 = gen_config();
     config1.hard_coded_contacts = vec![localhost_contact_info(port0)];
@@ -410,8 +473,8 @@ fn do_not_drop_peer_even_when_no_data_messages_are_exchanged_within_inactivity_p
     let mut service1 = unwrap!(Service::with_config(event_tx1, config1));
 
     unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
-    expect_event!(event_rx1, Event::BootstrapConnect(_peer_id, _));
-    expect_event!(event_rx0, Event::BootstrapAccept(_peer_id, _));
+    expect_event!(event_rx1, Event::BootstrapConnect(_peer_id, _, _));
+    expect_event!(event_rx0, Event::BootstrapAccept(_peer_id, _, _));
 
     thread::sleep(Duration::from_millis(2 * INACTIVITY_TIMEOUT_MS));
 
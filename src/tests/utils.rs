@@ -16,7 +16,6 @@
 // relating to use of the SAFE Network Software.
 
 use crossbeam;
-use maidsafe_utilities::event_sender::{MaidSafeEventCategory, MaidSafeObserver};
 use main::{Config, Event};
 use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
 use std::sync::mpsc::{self, Receiver};
@@ -42,10 +41,7 @@ macro_rules! expect_event {
 }
 
 pub fn get_event_sender() -> (::CrustEventSender, Receiver<Event>) {
-    let (category_tx, _) = mpsc::channel();
-    let (event_tx, event_rx) = mpsc::channel();
-
-    (MaidSafeObserver::new(event_tx, MaidSafeEventCategory::Crust, category_tx), event_rx)
+    ::event_channel()
 }
 
 // Generate config with unique bootstrap cache name.